@@ -0,0 +1,107 @@
+use clap::{Parser, Subcommand};
+
+use crate::commands::RootOpts;
+use crate::error::{NudgeError, Result};
+use crate::utils::contacts::{Contact, ContactBook};
+use crate::utils::identity::PublicKey;
+use crate::utils::network_profile::NetworkProfile;
+use crate::utils::ui::{style, success_mark};
+
+#[derive(Parser, Debug)]
+pub struct ContactsOpts {
+    #[clap(subcommand)]
+    action: ContactsAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ContactsAction {
+    /// Add a contact, or replace the one with the same name
+    Add {
+        /// Name to address the contact by, e.g. `send --to alice`
+        #[clap(value_parser = Contact::validate_name)]
+        name: String,
+
+        /// Public key of the contact, as printed by `nudge identity export`
+        #[clap(value_parser = PublicKey::parse)]
+        public_key: PublicKey,
+
+        /// Relay to use for this contact, as "host:port"
+        #[clap(long)]
+        relay: Option<String>,
+
+        /// Network profile to use for this contact
+        #[clap(long, value_enum)]
+        profile: Option<NetworkProfile>,
+
+        /// Name to show to this contact instead of the hostname
+        #[clap(long)]
+        display_name: Option<String>,
+    },
+    /// List the contacts
+    List,
+    /// Remove a contact
+    Remove {
+        name: String,
+    },
+}
+
+/// Run the `contacts` command to manage the named peers.
+pub fn run(_: &RootOpts, contacts_opts: &ContactsOpts) -> Result<()> {
+    let path = ContactBook::default_path()
+        .ok_or(NudgeError::InvalidContact("no configuration directory (no home directory found)".to_string()))?;
+    let mut book = ContactBook::load(&path)?;
+
+    match &contacts_opts.action {
+        ContactsAction::Add { name, public_key, relay, profile, display_name } => {
+            let replaced = book.insert(Contact {
+                name: name.clone(),
+                public_key: *public_key,
+                relay: relay.clone(),
+                profile: *profile,
+                display_name: display_name.clone(),
+            });
+            book.save(&path)?;
+            status!(
+                "{} {} contact {} ({})",
+                success_mark(),
+                if replaced.is_some() { "Replaced" } else { "Added" },
+                style(name).cyan(),
+                style(public_key.fingerprint()).dim()
+            );
+        }
+        ContactsAction::List => {
+            if book.contacts().is_empty() {
+                status!("{} No contacts yet, see `nudge contacts add`", style("[~]").bold().yellow());
+            }
+            for contact in book.contacts() {
+                let mut details = Vec::new();
+                if let Some(relay) = &contact.relay {
+                    details.push(format!("relay {}", relay));
+                }
+                if let Some(profile) = contact.profile {
+                    details.push(format!("profile {:?}", profile).to_lowercase());
+                }
+                if let Some(display_name) = &contact.display_name {
+                    details.push(format!("shown as {}", display_name));
+                }
+                println!(
+                    "{:<16} {}  {}",
+                    style(&contact.name).cyan(),
+                    contact.public_key.fingerprint(),
+                    style(details.join(", ")).dim()
+                );
+            }
+        }
+        ContactsAction::Remove { name } => {
+            let contact = book.remove(name).ok_or_else(|| NudgeError::UnknownContact(name.clone()))?;
+            book.save(&path)?;
+            status!(
+                "{} Removed contact {} ({})",
+                success_mark(),
+                style(&contact.name).cyan(),
+                style(contact.public_key.fingerprint()).dim()
+            );
+        }
+    }
+    Ok(())
+}
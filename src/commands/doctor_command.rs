@@ -0,0 +1,73 @@
+use clap::Parser;
+
+use crate::commands::RootOpts;
+use crate::error::Result;
+use crate::utils::get_hostname;
+use crate::utils::memory::MemoryProfile;
+use crate::utils::platform::config_dir;
+use crate::utils::resolver::{resolve_address, resolver};
+use crate::utils::ui::{failure_mark, style, success_mark};
+use crate::utils::units::format_bytes;
+use crate::utils::DEFAULT_CHUNK_SIZE;
+
+#[derive(Parser, Debug)]
+pub struct DoctorOpts {
+    /// Chunk size used to estimate the peak memory of a transfer
+    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE)]
+    chunk_size: u32,
+}
+
+/// Run the `doctor` command to print diagnostics about the environment.
+pub fn run(root_opts: &RootOpts, doctor_opts: &DoctorOpts) -> Result<()> {
+    println!(
+        "{} nudge {} ({}/{})",
+        style("[~]").bold().yellow(),
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+    );
+
+    match get_hostname() {
+        Ok(hostname) => println!("{} Hostname: {}", success_mark(), style(hostname).cyan()),
+        Err(e) => println!("{} Hostname: {}", failure_mark(), e),
+    }
+
+    match config_dir() {
+        Some(dir) => println!("{} Config directory: {}", success_mark(), style(dir.display()).cyan()),
+        None => println!("{} Config directory: cannot determine home directory", failure_mark()),
+    }
+
+    let relay_address = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
+    let via = if root_opts.doh.is_some() { ", via DNS-over-HTTPS" } else { "" };
+    match resolver(root_opts.doh.as_deref()).and_then(|resolver| resolve_address(resolver.as_ref(), &relay_address)) {
+        Ok(addr) => println!(
+            "{} Relay: {} ({}{})",
+            success_mark(),
+            style(&relay_address).cyan(),
+            style(addr).dim(),
+            via
+        ),
+        Err(e) => println!(
+            "{} Relay: {}",
+            failure_mark(),
+            e
+        ),
+    }
+
+    let memory_profile = MemoryProfile::select(root_opts.low_memory);
+    println!(
+        "{} Memory profile: {} (hash buffer: {}, max. in-flight packets: {})",
+        style("[~]").bold().yellow(),
+        style(if root_opts.low_memory { "low" } else { "default" }).cyan(),
+        format_bytes(memory_profile.hash_buffer_size as u64),
+        memory_profile.max_in_flight,
+    );
+    println!(
+        "{} Peak transfer memory: ~{} (chunk-size: {})",
+        style("[~]").bold().yellow(),
+        style(format_bytes(memory_profile.peak_transfer_memory(doctor_opts.chunk_size))).cyan(),
+        style(format_bytes(u64::from(doctor_opts.chunk_size))).dim()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,380 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::Parser;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::commands::RootOpts;
+use crate::commands::send_command::{bind_socket, compute_file_hash, connect_to_relay_server, encrypt_pairing};
+use crate::error::{NudgeError, Result};
+use crate::models::*;
+use crate::utils::capture::PacketCapture;
+use crate::utils::memory::MemoryProfile;
+use crate::utils::reliable_udp::ReliableUdpSocket;
+use crate::utils::rendezvous::{code_from_args, pair, Pairing};
+use crate::utils::socket::init_socket;
+use crate::utils::summary::TransferSummary;
+use crate::utils::ui::{complete_message, confirm, failure_mark, is_quiet, new_downloader_progressbar, style, success_mark};
+use crate::utils::{AnonymousString, DEFAULT_CHUNK_SIZE, hash_file_and_seek, peer_identity};
+use crate::utils::network_profile::MAX_CHUNK_SIZE;
+use crate::utils::sanitize::sanitize;
+use crate::utils::platform::{lock_file, preallocate};
+use crate::utils::units::format_bytes;
+
+#[derive(Parser, Debug)]
+pub struct ExchangeOpts {
+    /// Files to offer to the peer (optional, to only receive files)
+    files: Vec<String>,
+
+    /// Passphrase shared with the peer.
+    /// The side starting the exchange can omit it to get a generated one
+    #[clap(long, env = "NUDGE_PASSPHRASE", hide_env_values = true)]
+    code: Option<String>,
+
+    /// Use the passphrase stored in this file
+    #[clap(long, conflicts_with = "code")]
+    code_from_file: Option<String>,
+
+    /// Directory to store the received files in
+    #[clap(long, default_value = ".")]
+    out_dir: PathBuf,
+
+    #[clap(short, long, default_value = "500")]
+    delay: u64,
+
+    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE, value_parser = clap::value_parser!(u32).range(1..=MAX_CHUNK_SIZE as i64))]
+    chunk_size: u32,
+
+    /// If enabled, accepts all files offered by the peer without asking
+    #[clap(short, long, default_value = "false")]
+    force: bool,
+
+    /// If enabled, sends the hostname to the peer (and the relay)
+    #[clap(long, default_value = "false")]
+    share_hostname: bool,
+
+    /// Name shown to the peer instead of the hostname, e.g. "Alice's laptop"
+    #[clap(long, env = "NUDGE_DISPLAY_NAME", conflicts_with = "share_hostname")]
+    display_name: Option<String>,
+
+    /// If enabled, will overwrite existing files without asking
+    #[clap(long, default_value = "false")]
+    overwrite_file: bool,
+
+    /// If enabled, won't display any prompts and skip files that would need one
+    ///
+    /// (useful for scripting)
+    #[clap(long, default_value = "false")]
+    no_prompt: bool,
+
+    /// If enabled, won't hash the offered files or check the received ones
+    #[clap(long, default_value = "false")]
+    skip_hash: bool,
+}
+
+/// A file offered to the peer
+struct OfferedFile {
+    file: File,
+    meta: ExchangeFile,
+}
+
+/// Run the `exchange` command to swap files with a peer running the same command.
+///
+/// The side that reaches the relay first registers the passphrase (the host),
+/// the other side joins it. Over the peer-to-peer connection, both sides offer their files,
+/// pick the ones they want, and the host sends its files before the joining side sends its files.
+pub fn run(root_opts: &RootOpts, exchange_opts: &ExchangeOpts) -> Result<()> {
+    let memory_profile = MemoryProfile::select(root_opts.low_memory);
+    debug!("Memory profile: {:?}", memory_profile);
+    let capture = root_opts.capture.as_deref().map(PacketCapture::create).transpose()?;
+
+    let mut offered = open_offered_files(exchange_opts, &memory_profile)?;
+
+    let socket = bind_socket(None)?;
+    connect_to_relay_server(&socket, root_opts)?;
+
+    let identity = peer_identity(exchange_opts.share_hostname, exchange_opts.display_name.as_deref());
+    debug!("Identity: {}", identity);
+
+    let code = code_from_args(exchange_opts.code.as_deref(), exchange_opts.code_from_file.as_deref())?;
+
+    let total_size = offered.iter().map(|offered_file| offered_file.meta.file_size).sum();
+    let relay_token = root_opts.relay_token.as_deref();
+    let pairing = pair(&socket, code, &identity, SessionKind::Exchange, total_size, relay_token, root_opts.blind)?;
+
+    debug!("Initializing socket connection...");
+    init_socket(&socket)?;
+    debug!("Ready to exchange files!");
+
+    let connection = ReliableUdpSocket::new(socket)
+        .with_max_in_flight(memory_profile.max_in_flight)
+        .with_capture(capture);
+    let mut connection = encrypt_pairing(connection, &pairing, root_opts.allow_unencrypted, exchange_opts.delay)?;
+    let Pairing { peer_host, is_host, .. } = pairing;
+
+    let our_offer = ExchangeOfferMessage {
+        files: offered.iter().map(|offered_file| offered_file.meta.clone()).collect(),
+    };
+
+    if is_host {
+        write_message(&mut connection, &our_offer, exchange_opts.delay)?;
+        let peer_offer: ExchangeOfferMessage = read_message(&mut connection)?;
+        let peer_accept: ExchangeAcceptMessage = read_message(&mut connection)?;
+        let our_accept = choose_files(&peer_offer, &peer_host, exchange_opts)?;
+        write_message(&mut connection, &our_accept, exchange_opts.delay)?;
+
+        send_files(&mut connection, &mut offered, &peer_accept, exchange_opts)?;
+        receive_files(&mut connection, &peer_offer, &our_accept, exchange_opts, &memory_profile)?;
+
+        // Wait for the peer to end the session, so it doesn't wait for our acknowledgment
+        if connection.read(&[0; 1])?.1 != 0 {
+            warn!("Peer sent more data than expected");
+        }
+    } else {
+        let peer_offer: ExchangeOfferMessage = read_message(&mut connection)?;
+        let our_accept = choose_files(&peer_offer, &peer_host, exchange_opts)?;
+        write_message(&mut connection, &our_offer, exchange_opts.delay)?;
+        write_message(&mut connection, &our_accept, exchange_opts.delay)?;
+        let peer_accept: ExchangeAcceptMessage = read_message(&mut connection)?;
+
+        receive_files(&mut connection, &peer_offer, &our_accept, exchange_opts, &memory_profile)?;
+        send_files(&mut connection, &mut offered, &peer_accept, exchange_opts)?;
+        connection.end();
+    }
+
+    status!("{} Exchange complete!", success_mark());
+    Ok(())
+}
+
+/// Opens and hashes the files to offer to the peer
+fn open_offered_files(exchange_opts: &ExchangeOpts, memory_profile: &MemoryProfile) -> Result<Vec<OfferedFile>> {
+    let mut offered = Vec::with_capacity(exchange_opts.files.len());
+    for path in &exchange_opts.files {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let file_name = path.split('/').next_back().unwrap_or_default().to_string();
+        let file_hash = compute_file_hash(exchange_opts.skip_hash, &mut file, memory_profile)?;
+        offered.push(OfferedFile {
+            file,
+            meta: ExchangeFile { file_name, file_size, file_hash },
+        });
+    }
+    Ok(offered)
+}
+
+/// Serializes a message and sends it as a single packet, waiting for the peer to receive it
+pub(crate) fn write_message(connection: &mut ReliableUdpSocket, message: &impl Serialize, delay: u64) -> Result<()> {
+    connection.write_and_flush(&serde_json::to_vec(message)?, true, delay)
+}
+
+/// Reads a single packet and parses it as message
+pub(crate) fn read_message<T: DeserializeOwned>(connection: &mut ReliableUdpSocket) -> Result<T> {
+    let (data, bytes_read) = connection.read(&[0; 1])?;
+    if bytes_read == 0 {
+        return Err(NudgeError::ExchangeProtocolError("peer ended the session early".to_string()));
+    }
+    Ok(serde_json::from_slice(&data[..bytes_read])?)
+}
+
+/// Returns where a file offered by the peer is stored.
+/// Only the last component of the name is used, so the peer can't write outside of the output directory.
+fn output_path(out_dir: &Path, file_name: &str) -> Option<PathBuf> {
+    Path::new(file_name).file_name().map(|name| out_dir.join(name))
+}
+
+/// Asks which of the files offered by the peer should be received
+fn choose_files(
+    peer_offer: &ExchangeOfferMessage,
+    peer_host: &AnonymousString,
+    exchange_opts: &ExchangeOpts,
+) -> Result<ExchangeAcceptMessage> {
+    let mut accepted = Vec::new();
+    for (index, file) in peer_offer.files.iter().enumerate() {
+        status!(
+            "{} Offered: {} by {} [{}]",
+            style("[~]").bold().yellow(),
+            style(sanitize(&file.file_name)).yellow(),
+            style(peer_host).cyan(),
+            format_bytes(file.file_size)
+        );
+
+        let Some(path) = output_path(&exchange_opts.out_dir, &file.file_name) else {
+            status!("{} Skipping file without a usable name", failure_mark());
+            continue;
+        };
+
+        if !exchange_opts.overwrite_file && path.exists() {
+            if exchange_opts.no_prompt {
+                status!("File {} already exists, skipping. Pass --overwrite-file to replace it.", sanitize(&path.display().to_string()));
+                continue;
+            }
+            if !confirm(&format!("File {} already exists. Overwrite?", sanitize(&path.display().to_string())))? {
+                continue;
+            }
+        }
+
+        if !exchange_opts.force {
+            if exchange_opts.no_prompt {
+                status!("Skipping {}. Pass -f to receive files without asking.", sanitize(&file.file_name));
+                continue;
+            }
+            if !confirm(&format!("Do you want to receive {}?", sanitize(&file.file_name)))? {
+                continue;
+            }
+        }
+
+        accepted.push(index);
+    }
+    Ok(ExchangeAcceptMessage { accepted })
+}
+
+/// Sends the files the peer accepted
+fn send_files(
+    connection: &mut ReliableUdpSocket,
+    offered: &mut [OfferedFile],
+    peer_accept: &ExchangeAcceptMessage,
+    exchange_opts: &ExchangeOpts,
+) -> Result<()> {
+    let mut buffer: Vec<u8> = vec![0; exchange_opts.chunk_size as usize];
+
+    for &index in &peer_accept.accepted {
+        let offered_file = offered.get_mut(index).ok_or_else(|| {
+            NudgeError::ExchangeProtocolError(format!("peer accepted unknown file #{}", index))
+        })?;
+        let file_size = offered_file.meta.file_size;
+
+        status!(
+            "{} Sending {} (chunk-size: {})...",
+            style("[~]").bold().yellow(),
+            style(&offered_file.meta.file_name).yellow(),
+            style(format_bytes(u64::from(exchange_opts.chunk_size))).dim()
+        );
+        let progress_bar = new_downloader_progressbar(file_size);
+        offered_file.file.seek(SeekFrom::Start(0))?;
+
+        // The peer reads exactly the offered size, so the file must not grow or shrink meanwhile
+        let mut bytes_sent: u64 = 0;
+        while bytes_sent < file_size {
+            let max_read = buffer.len().min((file_size - bytes_sent) as usize);
+            let bytes_read = offered_file.file.read(&mut buffer[..max_read])?;
+            if bytes_read == 0 {
+                return Err(NudgeError::ExchangeProtocolError(format!(
+                    "{} shrank while sending it", offered_file.meta.file_name
+                )));
+            }
+            bytes_sent += bytes_read as u64;
+
+            // wait for the peer before it's our turn to receive
+            connection.write_and_flush(&buffer[..bytes_read], bytes_sent == file_size, exchange_opts.delay)?;
+            progress_bar.set_position(bytes_sent);
+        }
+        progress_bar.finish_with_message(complete_message());
+    }
+    Ok(())
+}
+
+/// Receives the files we accepted
+fn receive_files(
+    connection: &mut ReliableUdpSocket,
+    peer_offer: &ExchangeOfferMessage,
+    our_accept: &ExchangeAcceptMessage,
+    exchange_opts: &ExchangeOpts,
+    memory_profile: &MemoryProfile,
+) -> Result<()> {
+    let buffer: Vec<u8> = vec![0; exchange_opts.chunk_size as usize];
+
+    for &index in &our_accept.accepted {
+        let offered_file = &peer_offer.files[index];
+        let path = output_path(&exchange_opts.out_dir, &offered_file.file_name)
+            .expect("accepted files have a usable name");
+
+        status!(
+            "{} Receiving {} ({})...",
+            style("[~]").bold().yellow(),
+            style(sanitize(&offered_file.file_name)).yellow(),
+            format_bytes(offered_file.file_size)
+        );
+
+        // Truncate only after locking, another receiver might be writing to the same file
+        let mut file = OpenOptions::new()
+            .truncate(false)
+            .write(true)
+            .create(true)
+            .read(true)
+            .open(&path)?;
+        lock_file(&file, &path)?;
+        file.set_len(0)?;
+        preallocate(&file, offered_file.file_size)?;
+
+        let progress_bar = new_downloader_progressbar(offered_file.file_size);
+        let start_time = Instant::now();
+
+        let mut bytes_received: u64 = 0;
+        while bytes_received < offered_file.file_size {
+            let (read_buffer, bytes_read) = connection.read(&buffer)?;
+            if bytes_read == 0 {
+                return Err(NudgeError::ExchangeProtocolError("peer ended the session early".to_string()));
+            }
+            file.write_all(&read_buffer[..bytes_read])?;
+            bytes_received += bytes_read as u64;
+            progress_bar.set_position(bytes_received);
+        }
+        file.flush()?;
+        progress_bar.finish_with_message(complete_message());
+        let duration_millis = start_time.elapsed().as_millis() as u64;
+
+        let hash = verify_file_hash(&mut file, offered_file, exchange_opts, memory_profile)?;
+        if is_quiet() {
+            let summary = TransferSummary {
+                path: std::fs::canonicalize(&path).unwrap_or(path),
+                size: bytes_received,
+                hash,
+                duration_millis,
+                phases: Vec::new(),
+            };
+            println!("{}", summary.to_line());
+        } else {
+            status!(
+                "{} Received {} in {}s",
+                success_mark(),
+                style(sanitize(&offered_file.file_name)).yellow(),
+                duration_millis as f64 / 1000.0
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks the hash of a received file against the hash offered by the peer.
+///
+/// # Returns
+///
+/// `Result<Option<String>>` - The hash of the received file, or `None` if the check was skipped.
+fn verify_file_hash(
+    file: &mut File,
+    offered_file: &ExchangeFile,
+    exchange_opts: &ExchangeOpts,
+    memory_profile: &MemoryProfile,
+) -> Result<Option<String>> {
+    if exchange_opts.skip_hash {
+        return Ok(None);
+    }
+    let Some(expected_hash) = offered_file.file_hash.0.clone() else {
+        status!(
+            "{} Peer did not send a hash for {}! Skipping hash check...",
+            failure_mark(),
+            offered_file.file_name
+        );
+        return Ok(None);
+    };
+
+    file.seek(SeekFrom::Start(0))?;
+    let actual_hash = hash_file_and_seek(file, memory_profile.hash_buffer_size)?;
+    if expected_hash != actual_hash {
+        return Err(NudgeError::HashMismatch(expected_hash, actual_hash));
+    }
+    Ok(Some(actual_hash))
+}
@@ -0,0 +1,41 @@
+use clap::Parser;
+
+use crate::commands::RootOpts;
+use crate::error::{NudgeError, Result};
+use crate::utils::error_catalog::{explanation, EXPLANATIONS};
+use crate::utils::ui::style;
+
+#[derive(Parser, Debug)]
+pub struct ExplainOpts {
+    /// Code of the error, as printed below it, e.g. E101
+    #[clap(required_unless_present = "list")]
+    code: Option<String>,
+
+    /// List the codes with their titles and exit
+    #[clap(long, default_value = "false", conflicts_with = "code")]
+    list: bool,
+}
+
+pub fn run(_: &RootOpts, explain_opts: &ExplainOpts) -> Result<()> {
+    if explain_opts.list {
+        for explanation in &EXPLANATIONS {
+            println!("{}  {}", explanation.code, explanation.title);
+        }
+        return Ok(());
+    }
+
+    let code = explain_opts.code.as_deref().unwrap_or_default();
+    let explanation = explanation(code).ok_or_else(|| NudgeError::UnknownErrorCode(code.to_string()))?;
+    println!("{} {}", style(explanation.code).bold(), explanation.title);
+    println!();
+    println!("{}", style("Causes:").bold());
+    for cause in explanation.causes {
+        println!("  * {}", cause);
+    }
+    println!();
+    println!("{}", style("Fixes:").bold());
+    for fix in explanation.fixes {
+        println!("  * {}", fix);
+    }
+    Ok(())
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use clap::{ArgGroup, Parser};
+
+use crate::commands::RootOpts;
+use crate::commands::send_command::{bind_socket, connect_to_relay_server, encrypt_pairing};
+use crate::error::Result;
+use crate::models::SessionKind;
+use crate::utils::capture::PacketCapture;
+use crate::utils::memory::MemoryProfile;
+use crate::utils::mux::{MUX_HEADER_SIZE, MuxFrame};
+use crate::utils::peer_identity;
+use crate::utils::reliable_udp::ReliableUdpSocket;
+use crate::utils::rendezvous::{code_from_args, pair, Pairing};
+use crate::utils::socket::init_socket;
+use crate::utils::ui::{style, success_mark};
+use crate::utils::DEFAULT_CHUNK_SIZE;
+
+/// How long to wait for data from the peer before checking the local connections again
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum number of packets forwarded in one direction before switching to the other one
+const MAX_PACKETS_PER_ROUND: usize = 64;
+
+#[derive(Parser, Debug)]
+#[clap(group(ArgGroup::new("side").required(true).args(&["local", "remote"])))]
+pub struct ForwardOpts {
+    /// Listen on this local port and forward connections to the peer
+    #[clap(long)]
+    local: Option<u16>,
+
+    /// Address the local port is bound to
+    #[clap(long, default_value = "127.0.0.1", requires = "local")]
+    bind: String,
+
+    /// Connect forwarded connections to this port (or host:port) on this machine
+    #[clap(long)]
+    remote: Option<String>,
+
+    /// Passphrase shared with the peer.
+    /// The side starting the forward can omit it to get a generated one
+    #[clap(long, env = "NUDGE_PASSPHRASE", hide_env_values = true)]
+    code: Option<String>,
+
+    /// Use the passphrase stored in this file
+    #[clap(long, conflicts_with = "code")]
+    code_from_file: Option<String>,
+
+    #[clap(short, long, default_value = "500")]
+    delay: u64,
+
+    /// Maximum number of bytes read from a connection per packet
+    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE)]
+    chunk_size: u32,
+
+    /// If enabled, sends the hostname to the peer (and the relay)
+    #[clap(long, default_value = "false")]
+    share_hostname: bool,
+
+    /// Name shown to the peer instead of the hostname, e.g. "Alice's laptop"
+    #[clap(long, env = "NUDGE_DISPLAY_NAME", conflicts_with = "share_hostname")]
+    display_name: Option<String>,
+}
+
+/// Something that happened on a local TCP connection
+enum Event {
+    /// A client connected to the local port
+    Accepted(u32, TcpStream),
+    /// Data was read from a connection
+    Data(u32, Vec<u8>),
+    /// A connection was closed for reading
+    Eof(u32),
+}
+
+/// A local TCP connection forwarded over the channel
+struct ForwardedConnection {
+    stream: TcpStream,
+    /// Whether we read everything from the local side
+    local_closed: bool,
+    /// Whether the peer read everything from its side
+    peer_closed: bool,
+}
+
+/// Run the `forward` command to make a TCP port of one machine reachable from the other.
+///
+/// The side with `--local` listens for connections and sends them over the channel,
+/// the side with `--remote` connects them to its target. Runs until interrupted.
+pub fn run(root_opts: &RootOpts, forward_opts: &ForwardOpts) -> Result<()> {
+    let memory_profile = MemoryProfile::select(root_opts.low_memory);
+    debug!("Memory profile: {:?}", memory_profile);
+    let capture = root_opts.capture.as_deref().map(PacketCapture::create).transpose()?;
+
+    // Bind first, so a port that's already in use fails before pairing
+    let listener = match forward_opts.local {
+        Some(port) => Some(TcpListener::bind((forward_opts.bind.as_str(), port))?),
+        None => None,
+    };
+
+    let socket = bind_socket(None)?;
+    connect_to_relay_server(&socket, root_opts)?;
+
+    let identity = peer_identity(forward_opts.share_hostname, forward_opts.display_name.as_deref());
+    let code = code_from_args(forward_opts.code.as_deref(), forward_opts.code_from_file.as_deref())?;
+    let relay_token = root_opts.relay_token.as_deref();
+    let pairing = pair(&socket, code, &identity, SessionKind::Forward, 0, relay_token, root_opts.blind)?;
+
+    debug!("Initializing socket connection...");
+    init_socket(&socket)?;
+
+    let connection = ReliableUdpSocket::new(socket)
+        .with_max_in_flight(memory_profile.max_in_flight)
+        .with_capture(capture);
+    let mut connection = encrypt_pairing(connection, &pairing, root_opts.allow_unencrypted, forward_opts.delay)?;
+    let Pairing { peer_host, .. } = pairing;
+    let (events, event_receiver) = mpsc::sync_channel(memory_profile.max_in_flight);
+
+    match (&listener, &forward_opts.remote) {
+        (Some(listener), _) => {
+            status!(
+                "{} Forwarding {} to {}",
+                success_mark(),
+                style(listener.local_addr()?).cyan(),
+                style(&peer_host).cyan()
+            );
+            spawn_acceptor(listener.try_clone()?, events.clone());
+        }
+        (None, Some(remote)) => status!(
+            "{} Forwarding connections from {} to {}",
+            success_mark(),
+            style(&peer_host).cyan(),
+            style(remote).cyan()
+        ),
+        (None, None) => unreachable!("clap requires --local or --remote"),
+    }
+
+    forward(&mut connection, &events, &event_receiver, forward_opts)?;
+
+    status!("{} Peer ended the session", success_mark());
+    Ok(())
+}
+
+/// Accepts clients on the local port on a separate thread
+fn spawn_acceptor(listener: TcpListener, events: SyncSender<Event>) {
+    thread::spawn(move || {
+        for (connection_id, stream) in (0u32..).zip(listener.incoming()) {
+            match stream {
+                Ok(stream) => {
+                    if events.send(Event::Accepted(connection_id, stream)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("Cannot accept connection: {}", e),
+            }
+        }
+    });
+}
+
+/// Reads a local connection on a separate thread, since reading blocks
+fn spawn_reader(connection_id: u32, mut stream: TcpStream, events: SyncSender<Event>, chunk_size: usize) {
+    thread::spawn(move || {
+        let mut buffer = vec![0; chunk_size];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => {
+                    let _ = events.send(Event::Eof(connection_id));
+                    break;
+                }
+                Ok(bytes_read) => {
+                    if events.send(Event::Data(connection_id, buffer[..bytes_read].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Moves data between the local connections and the peer, until the peer ends the session.
+fn forward(
+    connection: &mut ReliableUdpSocket,
+    events: &SyncSender<Event>,
+    event_receiver: &Receiver<Event>,
+    forward_opts: &ForwardOpts,
+) -> Result<()> {
+    // leave room for the frame header, packets can't exceed 0xfffc bytes
+    let chunk_size = (forward_opts.chunk_size as usize).min(0xfffc - MUX_HEADER_SIZE);
+    let buffer: Vec<u8> = vec![0; chunk_size];
+    let mut connections: HashMap<u32, ForwardedConnection> = HashMap::new();
+
+    loop {
+        // forward what the local connections produced in the meantime
+        let mut handled = 0;
+        while handled < MAX_PACKETS_PER_ROUND {
+            let event = match event_receiver.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            };
+            handle_event(connection, &mut connections, event, events, chunk_size, forward_opts.delay)?;
+            handled += 1;
+        }
+
+        // receive what the peer sent, without waiting long if there is more to send
+        let mut timeout = if handled > 0 { Duration::ZERO } else { POLL_INTERVAL };
+        let mut received = 0;
+        while received < MAX_PACKETS_PER_ROUND {
+            let Some((data, bytes_read)) = connection.try_read(&buffer, timeout)? else {
+                break;
+            };
+            if bytes_read == 0 {
+                return Ok(());
+            }
+            match MuxFrame::decode(&data[..bytes_read]) {
+                Some(frame) => handle_frame(connection, &mut connections, frame, events, chunk_size, forward_opts)?,
+                None => warn!("Received invalid frame from the peer"),
+            }
+            timeout = Duration::ZERO;
+            received += 1;
+        }
+    }
+}
+
+/// Forwards an event of a local connection to the peer
+fn handle_event(
+    connection: &mut ReliableUdpSocket,
+    connections: &mut HashMap<u32, ForwardedConnection>,
+    event: Event,
+    events: &SyncSender<Event>,
+    chunk_size: usize,
+    delay: u64,
+) -> Result<()> {
+    match event {
+        Event::Accepted(connection_id, stream) => {
+            status!(
+                "{} Connection #{} from {}",
+                style("[~]").bold().yellow(),
+                connection_id,
+                style(stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default()).dim()
+            );
+            spawn_reader(connection_id, stream.try_clone()?, events.clone(), chunk_size);
+            connections.insert(connection_id, ForwardedConnection { stream, local_closed: false, peer_closed: false });
+            connection.write_and_flush(&MuxFrame::Open(connection_id).encode(), false, delay)?;
+        }
+        Event::Data(connection_id, data) => {
+            connection.write_and_flush(&MuxFrame::Data(connection_id, data).encode(), false, delay)?;
+        }
+        Event::Eof(connection_id) => {
+            // the reader of a connection we removed ends, too
+            let Some(forwarded) = connections.get_mut(&connection_id) else {
+                return Ok(());
+            };
+            forwarded.local_closed = true;
+            connection.write_and_flush(&MuxFrame::Close(connection_id).encode(), false, delay)?;
+            if forwarded.peer_closed {
+                remove_connection(connections, connection_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies a frame sent by the peer to the local connections
+fn handle_frame(
+    connection: &mut ReliableUdpSocket,
+    connections: &mut HashMap<u32, ForwardedConnection>,
+    frame: MuxFrame,
+    events: &SyncSender<Event>,
+    chunk_size: usize,
+    forward_opts: &ForwardOpts,
+) -> Result<()> {
+    match frame {
+        MuxFrame::Open(connection_id) => {
+            let Some(remote) = &forward_opts.remote else {
+                warn!("Peer opened connection #{}, but this side has no --remote", connection_id);
+                return connection.write_and_flush(&MuxFrame::Close(connection_id).encode(), false, forward_opts.delay);
+            };
+            match TcpStream::connect(remote_address(remote)) {
+                Ok(stream) => {
+                    debug!("Connection #{} connected to {}", connection_id, remote);
+                    spawn_reader(connection_id, stream.try_clone()?, events.clone(), chunk_size);
+                    connections.insert(connection_id, ForwardedConnection { stream, local_closed: false, peer_closed: false });
+                }
+                Err(e) => {
+                    warn!("Cannot connect #{} to {}: {}", connection_id, remote, e);
+                    connection.write_and_flush(&MuxFrame::Close(connection_id).encode(), false, forward_opts.delay)?;
+                }
+            }
+        }
+        MuxFrame::Data(connection_id, data) => {
+            let Some(forwarded) = connections.get_mut(&connection_id) else {
+                return Ok(());
+            };
+            if let Err(e) = forwarded.stream.write_all(&data) {
+                debug!("Cannot write to connection #{}: {}", connection_id, e);
+                remove_connection(connections, connection_id);
+                connection.write_and_flush(&MuxFrame::Close(connection_id).encode(), false, forward_opts.delay)?;
+            }
+        }
+        MuxFrame::Close(connection_id) => {
+            let Some(forwarded) = connections.get_mut(&connection_id) else {
+                return Ok(());
+            };
+            // the peer won't send more, but we might still have something to say
+            let _ = forwarded.stream.shutdown(Shutdown::Write);
+            forwarded.peer_closed = true;
+            if forwarded.local_closed {
+                remove_connection(connections, connection_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Closes and forgets a local connection
+fn remove_connection(connections: &mut HashMap<u32, ForwardedConnection>, connection_id: u32) {
+    if let Some(forwarded) = connections.remove(&connection_id) {
+        let _ = forwarded.stream.shutdown(Shutdown::Both);
+        debug!("Connection #{} closed", connection_id);
+    }
+}
+
+/// Returns the address to connect to for `--remote`, which may be just a port.
+fn remote_address(remote: &str) -> String {
+    if remote.parse::<u16>().is_ok() {
+        format!("127.0.0.1:{}", remote)
+    } else {
+        remote.to_string()
+    }
+}
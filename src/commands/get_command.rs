@@ -1,52 +1,132 @@
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::{Seek, Write};
-use std::net::{Ipv4Addr, UdpSocket};
-use std::path::Path;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use console::style;
-use dialoguer::Confirm;
-use humansize::{DECIMAL, format_size};
 use crate::commands::RootOpts;
+use crate::commands::send_command::{bind_socket, connect_to_relay, connect_to_relay_server, exchange_keys, map_port};
 
 use crate::error::NudgeError;
-use crate::models::FileInfo;
-use crate::models::R2XRequestSenderConnectionMessage;
-use crate::models::R2XRequestFileInfoMessage;
-use crate::utils::passphrase::Passphrase;
-use crate::utils::reliable_udp::ReliableUdpSocket;
-use crate::utils::{current_unix_millis, hash_file_and_seek};
-use crate::utils::hide_or_get_hostname;
-use crate::utils::new_downloader_progressbar;
-use crate::utils::question_theme;
-use crate::utils::DEFAULT_CHUNK_SIZE;
-use crate::utils::serialize::{receive_and_parse_and_expect, serialize_and_send};
-use crate::utils::socket::init_socket;
+use crate::commands::exchange_command::{read_message, write_message};
+use crate::models::{CompressionMessage, Extent, ExtendedAttribute, ExtendedAttributesMessage, FileInfo, FileManifestMessage, PreviewDecisionMessage, PreviewRequestMessage, SparseMapMessage};
+use crate::models::{EncryptionMessage, IdentityChallengeMessage, IdentityProofMessage};
+use crate::models::{R2XRequestSenderConnectionMessage, ResumeMessage};
+use crate::models::{R2XRequestFileInfoMessage, R2XWakeSenderMessage};
+use crate::utils::partial::PartialDownload;
+use crate::utils::passphrase::{Passphrase, PassphraseLink};
+use crate::utils::batch::read_batch_file;
+use crate::utils::blind::{is_blind, relay_passphrase};
+use crate::utils::bundle::BundleWriter;
+use crate::utils::capture::PacketCapture;
+use crate::utils::compression::{self, Decompressor};
+use crate::utils::encryption::{self, generate_salt, Role};
+use crate::utils::reliable_udp::{ReceiveState, ReliableUdpSocket, DEFAULT_ACK_EVERY, DEFAULT_DEDUP_WINDOW, DEFAULT_PEER_TIMEOUT, DEFAULT_REORDER_BUFFER, MAX_DEDUP_WINDOW};
+use crate::utils::duration::{format_duration, parse_duration};
+use crate::utils::{current_unix_millis, encode_hex, is_known_hash_algorithm, sender_file_name, HashingWriter};
+use crate::utils::identity::{identity_proof_message, Identity, IdentityFile};
+use crate::utils::interface::print_interfaces;
+use crate::utils::manifest::{ManifestEntry, TransferManifest};
+use crate::utils::memory::MemoryProfile;
+use crate::utils::network_profile::{NetworkProfile, TransferTuning, MAX_CHUNK_SIZE};
+use crate::utils::out_template::OutTemplate;
+use crate::utils::port_mapping::{mapped_addr, PortMapping};
+use crate::utils::peer_identity;
+use crate::utils::preview::{format_preview, DEFAULT_PREVIEW_BYTES};
+use crate::utils::profiling::{self, span, Stage};
+use crate::utils::receiver_policy::ReceiverPolicy;
+use crate::utils::sandbox;
+use crate::utils::sanitize::sanitize;
+use crate::utils::session_lock::SessionLock;
+use crate::utils::rename::{RenamePattern, DEFAULT_RENAME_PATTERN};
+use crate::utils::platform::{is_block_device, lock_file, parse_file_mode, preallocate, set_file_mode};
+use crate::utils::ui::{complete_message, confirm, failure_mark, is_quiet, new_downloader_progressbar, new_stream_progressbar, style, success_mark, Phase, PhaseProgress, RECEIVER_PHASES};
+use crate::utils::summary::{format_phase_durations, TransferSummary};
+use crate::utils::serialize::request;
+use crate::utils::rendezvous::{request_sender_connection, wake_sender};
+use crate::utils::socket::{connect_to_peer, init_socket};
+use crate::utils::sparse::{data_size, SparseWriter};
+use crate::utils::tar::ArchiveUnpacker;
+use crate::utils::ticket::{decode_ticket, TicketPayload};
+use crate::utils::units::format_bytes;
+use crate::utils::watchdog::{StallWatchdog, DEFAULT_STALL_REPORT};
+use crate::utils::xattr::{unsupported_reason, write_attributes};
 
 #[derive(Parser, Debug)]
 pub struct GetOpts {
-    /// Passphrase to access the file (required)
-    passphrase: String,
+    /// Passphrase to access the file, or a link (see `send --link`) which also names the relay
+    /// (required, unless passed via NUDGE_PASSPHRASE or --passphrase-stdin)
+    #[clap(env = "NUDGE_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
 
-    /// Override the output file (optional)
+    /// Read the passphrase from the first line of stdin
+    #[clap(long, default_value = "false", conflicts_with = "passphrase")]
+    passphrase_stdin: bool,
+
+    /// Pick up the file with a session ticket (see `send --ticket-file`) instead of a passphrase.
+    /// The ticket names the relay, so --relay-host and --relay-port are ignored
+    #[clap(long, env = "NUDGE_TICKET", hide_env_values = true, conflicts_with_all = ["passphrase", "passphrase_stdin"])]
+    ticket: Option<String>,
+
+    /// Read the session ticket from this file
+    #[clap(long, conflicts_with_all = ["passphrase", "passphrase_stdin", "ticket"])]
+    ticket_file: Option<PathBuf>,
+
+    /// Download the files of all codes (passphrases or links) in this file, one per line.
+    /// All files are looked up and confirmed at once before the first one is received
+    #[clap(long, value_name = "FILE", conflicts_with_all = [
+        "passphrase", "passphrase_stdin", "ticket", "ticket_file", "out_file", "preview", "policy", "manifest", "sandbox",
+    ])]
+    batch: Option<PathBuf>,
+
+    /// Override the output file (optional), `-` writes the file to stdout, e.g. to pipe it into `tar x`
     #[clap(short = 'o', long)]
     out_file: Option<String>,
 
-    #[clap(short, long, default_value = "500")]
-    delay: u64,
+    /// Allow writing to a block device given with -o (e.g. /dev/sdb), to flash a disk image as it arrives.
+    /// Everything on the device is overwritten
+    #[clap(long, default_value = "false", requires = "out_file", conflicts_with = "policy")]
+    allow_block_device: bool,
+
+    /// Delay between two packets in microseconds [default: 500]
+    #[clap(short, long)]
+    delay: Option<u64>,
 
     /// If enabled, won't ask for confirmation before downloading the file
     #[clap(short, long, default_value = "false")]
     force: bool,
 
-    /// If enabled, won't send the hostname to the sender
+    /// If enabled, sends the hostname to the sender (and the relay)
     #[clap(long, default_value = "false")]
+    share_hostname: bool,
+
+    /// Name shown to the sender instead of the hostname, e.g. "Alice's laptop"
+    #[clap(long, env = "NUDGE_DISPLAY_NAME", conflicts_with = "share_hostname")]
+    display_name: Option<String>,
+
+    /// Deprecated, the hostname is hidden unless --share-hostname is given
+    #[clap(long, hide = true, alias = "no-hostname", default_value = "false", conflicts_with = "share_hostname")]
     hide_hostname: bool,
 
-    /// If enabled, will overwrite the output file if it already exists without asking
+    /// If enabled, will overwrite the output file if it already exists instead of renaming the download
     #[clap(long, default_value = "false")]
     overwrite_file: bool,
 
+    /// Name of the download if the output file already exists, e.g. "{stem}-{n}.{ext}".
+    /// Variables: name, stem, ext and the counter n
+    #[clap(long, default_value = DEFAULT_RENAME_PATTERN, value_parser = RenamePattern::parse, conflicts_with = "overwrite_file")]
+    rename_pattern: RenamePattern,
+
+    /// Append the download to the output file if it already exists, e.g. to collect logs shipped one after another.
+    /// The file is neither truncated nor preallocated, and the hash is checked on the appended data
+    #[clap(long, default_value = "false", conflicts_with_all = ["overwrite_file", "rename_pattern", "allow_block_device"])]
+    append: bool,
+
+    /// Start over instead of resuming an interrupted download of the output file (recorded in `<FILE>.nudge-part`)
+    #[clap(long, default_value = "false")]
+    no_resume: bool,
+
     /// If enabled, won't display any prompts and always quit
     ///
     /// (useful for scripting)
@@ -57,204 +137,1500 @@ pub struct GetOpts {
     #[clap(long, default_value = "false")]
     skip_hash: bool,
 
-    /// Chunk size to read from the socket
-    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE)]
-    chunk_size: u32,
+    /// Largest chunks the sender may send, it lowers its chunk size if needed [default: 4096]
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..=MAX_CHUNK_SIZE as i64))]
+    chunk_size: Option<u32>,
+
+    /// Use chunk size, delay and window suited for the network, explicit --chunk-size and --delay take precedence
+    #[clap(long, value_enum)]
+    profile: Option<NetworkProfile>,
+
+    /// Ask the sender to compress the data stream, for compressible files on slow links
+    #[clap(long, default_value = "false")]
+    compress: bool,
+
+    /// Number of packet IDs before the next expected one recognized as duplicates, the ones after it are taken
+    /// as packets overtaking a missing one [default: 32768]
+    #[clap(long, value_name = "PACKETS", value_parser = clap::value_parser!(u16).range(1..=MAX_DEDUP_WINDOW as i64))]
+    dedup_window: Option<u16>,
+
+    /// Acknowledge up to this many packets at once (and any packet not followed by another within 2ms),
+    /// saving upload bandwidth on asymmetric links. 1 acknowledges every packet right away
+    #[clap(long, value_name = "PACKETS", default_value_t = DEFAULT_ACK_EVERY, value_parser = clap::value_parser!(u16).range(1..))]
+    ack_every: u16,
+
+    /// Keep up to this many packets arriving ahead of a missing one until it arrives, instead of having them sent
+    /// again. Raise it for links with a high bandwidth-delay product which reorder packets, 0 turns it off
+    #[clap(long, value_name = "PACKETS", default_value_t = DEFAULT_REORDER_BUFFER)]
+    reorder_buffer: u16,
+
+    /// Give up if the sender sent nothing within this time after connecting, e.g. because the NATs block
+    /// the direct connection
+    #[clap(long, default_value = DEFAULT_PEER_TIMEOUT, value_parser = parse_duration)]
+    peer_timeout: Duration,
+
+    /// Print where the transfer stands (last packet, resend requests) whenever no data arrived for this long,
+    /// 0 turns it off
+    #[clap(long, value_name = "DURATION", default_value = DEFAULT_STALL_REPORT, value_parser = parse_duration)]
+    stall_report: Duration,
+
+    /// Bind to this IP address or interface (e.g. eth0, wg0) instead of relying on the default route
+    #[clap(long)]
+    bind: Option<String>,
+
+    /// List the network interfaces usable with --bind and exit
+    #[clap(long, default_value = "false")]
+    list_interfaces: bool,
+
+    /// Ask the router to forward a port via NAT-PMP or UPnP, so the sender can reach us directly
+    #[clap(long, default_value = "false")]
+    port_mapping: bool,
+
+    /// Show the beginning of a text file (up to this many bytes) before asking to download it
+    #[clap(long, num_args = 0..=1, default_missing_value = DEFAULT_PREVIEW_BYTES, conflicts_with_all = ["force", "no_prompt"])]
+    preview: Option<u32>,
+
+    /// Accept files according to this policy file instead of asking (e.g. for an unattended drop box)
+    #[clap(long, conflicts_with_all = ["out_file", "force", "preview"])]
+    policy: Option<PathBuf>,
+
+    /// Organize received files into directories, e.g. "{date}/{sender}/{name}".
+    /// Variables: name, stem, ext, sender, date, year, month, day
+    #[clap(long, value_parser = OutTemplate::parse, conflicts_with = "out_file")]
+    out_template: Option<OutTemplate>,
+
+    /// Permissions of the downloaded file (octal), restricted by the umask
+    #[clap(long, default_value = "0600", value_parser = parse_file_mode)]
+    mode: u32,
+
+    /// Apply the permissions of the sender's file instead of --mode (if the sender shares them)
+    #[clap(long, default_value = "false")]
+    preserve: bool,
+
+    /// Restore the extended attributes of the sender's file (if the sender shares them with --xattrs)
+    #[clap(long, default_value = "false")]
+    xattrs: bool,
+
+    /// Write a manifest (JSON) of the received file with its name, size, hash, timestamps and sender
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// Once connected to the sender, restrict the process (Linux only): no network access except to the sender,
+    /// and no file access except below the output directory (Landlock, Linux 5.13+)
+    #[clap(long, default_value = "false", conflicts_with = "port_mapping")]
+    sandbox: bool,
+
+    /// Prove owning the identity (see `nudge identity`) to senders pinning it with `send --to`
+    #[clap(long, default_value = "false")]
+    identify: bool,
 }
 
+/// Output file name writing the file to stdout
+const STDOUT_PATH: &str = "-";
+
+/// How long a read waits for data before the stall watchdog is checked
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a sender which doesn't answer is given to restart and take over its session at the relay,
+/// as long as the relay keeps an accepted session
+const SENDER_RESTART_GRACE: Duration = Duration::from_secs(30);
+
+/// Pause between two questions to the relay whether a sender which doesn't answer came back
+const SENDER_RESTART_POLL: Duration = Duration::from_secs(1);
+
+impl GetOpts {
+    /// Returns whether the file is written to stdout (`-o -`) instead of a file.
+    pub(crate) fn writes_to_stdout(&self) -> bool {
+        self.out_file.as_deref() == Some(STDOUT_PATH)
+    }
+
+    /// Returns whether the file is written to a block device (`-o /dev/sdb`) instead of a file.
+    fn writes_to_block_device(&self) -> bool {
+        self.out_file.as_deref().is_some_and(|path| is_block_device(Path::new(path)))
+    }
+}
+
+/// Where the received file is written to
+enum Output {
+    File(File),
+
+    /// stdout (`-o -`), which is hashed while writing as it can't be read again
+    Stdout(Box<blake3::Hasher>),
+
+    /// A file the download is appended to (`--append`), with the offset the download starts at
+    Append(File, u64),
+
+    /// A directory the archive of the sender is unpacked into, hashed while unpacking
+    Directory(ArchiveUnpacker, Box<blake3::Hasher>),
+
+    /// A directory several files shared at once are stored in, their data stream hashed while splitting it
+    Bundle(Box<BundleWriter>, Box<blake3::Hasher>),
+}
+
+/// Returns the passphrase from the arguments, the environment or stdin,
+/// together with the relay if it was given as a link (optional).
+fn resolve_passphrase(get_opts: &GetOpts) -> Result<(Passphrase<'static>, Option<String>), NudgeError> {
+    let passphrase = if get_opts.passphrase_stdin {
+        Passphrase::read_from(std::io::stdin().lock())?
+    } else {
+        get_opts.passphrase.clone()
+            .map(Passphrase::from)
+            .ok_or(NudgeError::PassphraseMissing)?
+    };
+    parse_code(passphrase)
+}
+
+/// Splits a link into the passphrase and the relay it names, passphrases are returned as they are.
+fn parse_code(passphrase: Passphrase<'static>) -> Result<(Passphrase<'static>, Option<String>), NudgeError> {
+    if PassphraseLink::is_link(&passphrase.0) {
+        let link = PassphraseLink::parse(&passphrase.0)?;
+        return Ok((link.passphrase, Some(link.relay)));
+    }
+    Ok((passphrase, None))
+}
+
+/// Returns the session ticket from the arguments, the environment or the ticket file (optional).
+fn resolve_ticket(get_opts: &GetOpts) -> Result<Option<(String, TicketPayload)>, NudgeError> {
+    let ticket = match (&get_opts.ticket, &get_opts.ticket_file) {
+        (Some(ticket), _) => ticket.trim().to_string(),
+        (None, Some(path)) => std::fs::read_to_string(path)?.trim().to_string(),
+        (None, None) => return Ok(None),
+    };
+    let payload = decode_ticket(&ticket)?;
+    Ok(Some((ticket, payload)))
+}
+
+/// Settings shared by all files received in one run
+struct Receiver<'a> {
+    get_opts: &'a GetOpts,
+    identity: Option<Identity>,
+    policy: Option<ReceiverPolicy>,
+    memory_profile: MemoryProfile,
+    tuning: TransferTuning,
+
+    /// Whether the data stream may be received unencrypted (`--allow-unencrypted`)
+    allow_unencrypted: bool,
+
+    /// Records the datagrams exchanged with the senders (`--capture`, optional)
+    capture: Option<PacketCapture>,
+}
+
+/// A file looked up at the relay, which can be received from its sender
+struct Lookup {
+    socket: UdpSocket,
+    port_mapping: Option<PortMapping>,
+    passphrase: Passphrase<'static>,
+    ticket: Option<String>,
+    file_info: FileInfo,
+
+    /// The passphrase as given, the key of the data stream is derived from it (`None` if it's already hashed,
+    /// e.g. in a ticket of a blind relay)
+    secret: Option<Passphrase<'static>>,
+}
 
 /// Run the `get` command to download a file using the provided options.
 pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
-    let local_bind_address = (Ipv4Addr::from(0u32), 0);
-    debug!("Binding UDP socket to local address: {:?}", local_bind_address);
-    let socket = UdpSocket::bind(local_bind_address)?;
-
-    let relay_address = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
-    debug!("Connecting to relay-server: {}...", relay_address);
-    socket.connect(relay_address)?;
-
-    // Send request for file information
-    let passphrase = Passphrase::from(get_opts.passphrase.clone());
-    debug!("Sending R2XRequestFileInfoMessage with passphrase: {}...", passphrase.0);
-    serialize_and_send(&socket, "R2X_RFI", &R2XRequestFileInfoMessage {
+    if get_opts.list_interfaces {
+        return print_interfaces();
+    }
+    if get_opts.hide_hostname {
+        status!(
+            "{} --hide-hostname is deprecated, the hostname is hidden unless --share-hostname is given",
+            style("[~]").bold().yellow()
+        );
+    }
+    if get_opts.compress && !compression::is_supported() {
+        return Err(NudgeError::CompressionUnsupported);
+    }
+    // flashing the wrong disk is too easy a mistake to make without saying so
+    if get_opts.writes_to_block_device() && !get_opts.allow_block_device {
+        return Err(NudgeError::BlockDeviceNotAllowed(get_opts.out_file.clone().unwrap_or_default()));
+    }
+
+    let policy = get_opts.policy.as_deref().map(ReceiverPolicy::load).transpose()?;
+    // unlock the identity before anything else, so a passphrase prompt doesn't keep the sender waiting
+    let identity = if get_opts.identify {
+        let path = IdentityFile::default_path()
+            .ok_or(NudgeError::IdentityMissing("the configuration directory (no home directory found)".to_string()))?;
+        Some(IdentityFile::load(&path)?.unseal_interactive()?)
+    } else {
+        None
+    };
+
+    let memory_profile = MemoryProfile::select(root_opts.low_memory);
+    debug!("Memory profile: {:?}", memory_profile);
+    let tuning = TransferTuning::resolve(get_opts.profile, get_opts.chunk_size, get_opts.delay, &memory_profile);
+    debug!("Transfer tuning: {:?}", tuning);
+    let capture = root_opts.capture.as_deref().map(PacketCapture::create).transpose()?;
+    let allow_unencrypted = root_opts.allow_unencrypted;
+    let receiver = Receiver { get_opts, identity, policy, memory_profile, tuning, allow_unencrypted, capture };
+
+    if let Some(batch_file) = &get_opts.batch {
+        return receive_batch(root_opts, &receiver, batch_file);
+    }
+
+    // A ticket names the session and the relay it's registered at, a link names the relay as well
+    let (passphrase, relay, ticket) = match resolve_ticket(get_opts)? {
+        Some((ticket, payload)) => {
+            debug!(target: "relay_client", "Using the relay-server from the ticket: {}", payload.relay);
+            (payload.passphrase, Some(payload.relay), Some(ticket))
+        }
+        None => {
+            let (passphrase, relay) = resolve_passphrase(get_opts)?;
+            if let Some(relay) = &relay {
+                debug!(target: "relay_client", "Using the relay-server from the link: {}", relay);
+            }
+            (passphrase, relay, None)
+        }
+    };
+
+    let phases = PhaseProgress::new(RECEIVER_PHASES);
+    phases.enter(Phase::LookingUp);
+    let lookup = look_up(root_opts, get_opts, passphrase, relay.as_deref(), ticket)?;
+    receive(&receiver, lookup, phases, false)
+}
+
+/// Looks up the files of all codes of a batch file (`--batch`), shows them and asks once whether to download
+/// them all. Only then the files are received one after another, so an invalid code fails before any data moves.
+fn receive_batch(root_opts: &RootOpts, receiver: &Receiver, batch_file: &Path) -> Result<(), NudgeError> {
+    let get_opts = receiver.get_opts;
+    let codes = read_batch_file(batch_file)?;
+
+    let phases = PhaseProgress::new(RECEIVER_PHASES);
+    phases.enter(Phase::LookingUp);
+    let mut lookups = Vec::with_capacity(codes.len());
+    for code in &codes {
+        let lookup = parse_code(Passphrase::from(code.code.clone()))
+            .and_then(|(passphrase, relay)| look_up(root_opts, get_opts, passphrase, relay.as_deref(), None))
+            .map_err(|e| NudgeError::InvalidBatchCode(code.line, Box::new(e)))?;
+        lookups.push(lookup);
+    }
+    drop(phases);
+
+    let total_size: u64 = lookups.iter().map(|lookup| lookup.file_info.file_size).sum();
+    status!(
+        "{} Found {} files [{}]:",
+        success_mark(),
+        lookups.len(),
+        format_bytes(total_size)
+    );
+    let names: Vec<String> = lookups.iter().map(|lookup| sanitize(&lookup.file_info.file_name)).collect();
+    let name_width = names.iter().map(|name| name.chars().count()).max().unwrap_or(0);
+    for (index, (lookup, name)) in lookups.iter().zip(&names).enumerate() {
+        let file_info = &lookup.file_info;
+        status!(
+            "    {:>3}  {:<width$}  {:>10}  by {}",
+            index + 1,
+            name,
+            format_file_size(file_info),
+            style(&file_info.sender_host).cyan(),
+            width = name_width
+        );
+    }
+
+    if !get_opts.force {
+        if get_opts.no_prompt {
+            status!("Do you want to download the files? Pass -f to download without asking.");
+            return Err(NudgeError::NoPromptExit);
+        }
+        if !confirm("Do you want to download the files?")? {
+            status!("Cancelled by user.");
+            return Ok(());
+        }
+    }
+
+    let count = lookups.len();
+    for (index, lookup) in lookups.into_iter().enumerate() {
+        status!("{} File {}/{}", style("[~]").bold().yellow(), index + 1, count);
+        receive(receiver, lookup, PhaseProgress::new(RECEIVER_PHASES), true)?;
+    }
+    status!("{} Received {} files", success_mark(), count);
+    Ok(())
+}
+
+/// Looks up a file at the relay with a fresh socket, which is later used to receive it.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase of the file.
+/// * `relay` - The relay named by a link or ticket, or `None` for the relay of the options.
+/// * `ticket` - The session ticket, if the file is picked up with one.
+///
+/// # Errors
+///
+/// Returns the error of the relay, e.g. `NudgeError::ServerError` for an unknown passphrase,
+/// or `NudgeError::WrongSessionKind` if the passphrase isn't meant for `get`.
+fn look_up(
+    root_opts: &RootOpts,
+    get_opts: &GetOpts,
+    passphrase: Passphrase<'static>,
+    relay: Option<&str>,
+    ticket: Option<String>,
+) -> Result<Lookup, NudgeError> {
+    let socket = bind_socket(get_opts.bind.as_deref())?;
+    // map before contacting the relay, so it likely sees the mapped port already
+    let port_mapping = map_port(&socket, get_opts.port_mapping);
+    match relay {
+        Some(relay) => connect_to_relay(&socket, relay, root_opts)?,
+        None => connect_to_relay_server(&socket, root_opts)?,
+    }
+
+    // Send request for file information, from here on a blind relay and the sender only know the hash
+    let secret = (!is_blind(&passphrase)).then(|| passphrase.clone());
+    let passphrase = relay_passphrase(&passphrase, root_opts.blind);
+    debug!(target: "relay_client", "Sending R2XRequestFileInfoMessage with passphrase: {}...", passphrase.0);
+    let file_info: FileInfo = request(&socket, "R2X_RFI", &R2XRequestFileInfoMessage {
         passphrase: passphrase.clone(),
-    })?;
+        ticket: ticket.clone(),
+    }, "X2R_AFI")?;
+    debug!(target: "relay_client", "Received FileInfo: {:?}", file_info);
+    if !file_info.kind.is_file() {
+        return Err(NudgeError::WrongSessionKind(file_info.kind));
+    }
+    Ok(Lookup { socket, port_mapping, passphrase, ticket, file_info, secret })
+}
+
+/// States of receiving a looked up file, each one holds what the next one needs.
+/// `receive` drives them, so every transition enters the phase of the next state in one place.
+enum ReceiverState {
+    /// Decides where the file is stored and asks to download it
+    Preparing(Lookup),
+    /// Asks the sender to connect and punches through the NATs
+    Connecting(Lookup, Destination),
+    /// Receives the data stream into the output
+    Transferring(Box<Transfer>),
+    /// Checks the hash of the received file
+    Verifying(Received),
+    /// The file was received, or the download was cancelled
+    Done,
+}
+
+impl ReceiverState {
+    /// Returns the phase of the state, or `None` if it's part of the phase before (e.g. preparing after looking up).
+    fn phase(&self) -> Option<Phase> {
+        match self {
+            ReceiverState::Preparing(_) | ReceiverState::Done => None,
+            ReceiverState::Connecting(..) => Some(Phase::Connecting),
+            ReceiverState::Transferring(_) => Some(Phase::Transferring),
+            ReceiverState::Verifying(_) => Some(Phase::Verifying),
+        }
+    }
+}
+
+/// Where a looked up file is stored, decided before connecting to the sender
+struct Destination {
+    out_file_name: PathBuf,
 
-    debug!("Waiting for FileInfo...");
-    let file_info: FileInfo = receive_and_parse_and_expect(&socket, "X2R_AFI")?;
-    debug!("Received FileInfo: {:?}", file_info);
+    /// Bytes of the file shown before asking to download it (optional)
+    preview_bytes: Option<u32>,
 
-    println!(
+    /// The opened output, or `None` until the download is confirmed after the preview
+    output: Option<Output>,
+
+    /// Permissions of the output file
+    mode: u32,
+
+    /// Whether the extended attributes of the file are restored
+    xattrs: bool,
+
+    /// Claims the output file for this session, `None` for stdout and block devices
+    lock: Option<SessionLock>,
+
+    /// Whether the progress is recorded, so an interrupted download can be resumed
+    resumable: bool,
+
+    /// Bytes kept from an interrupted download, which the sender is asked to skip (optional)
+    resume_offset: Option<u64>,
+}
+
+/// A connection to the sender, over which the data stream is received next
+struct Transfer {
+    file_info: FileInfo,
+    out_file_name: PathBuf,
+    connection: ReliableUdpSocket,
+    output: Output,
+
+    /// Extended attributes sent by the sender (optional)
+    attributes: Option<Vec<ExtendedAttribute>>,
+
+    /// Data regions of a sparse file, only these are received (optional)
+    extents: Option<Vec<Extent>>,
+    decompressor: Option<Decompressor>,
+    lock: Option<SessionLock>,
+
+    /// Where the data stream starts in the output, if the progress is recorded to resume the download (optional)
+    resume_from: Option<u64>,
+}
+
+/// A received file, whose hash is checked next
+struct Received {
+    file_info: FileInfo,
+    out_file_name: PathBuf,
+    output: Output,
+
+    /// Hash of the data written, computed while writing it
+    written_hash: String,
+    attributes: Option<Vec<ExtendedAttribute>>,
+    duration_millis: u64,
+    lock: Option<SessionLock>,
+}
+
+/// Receives a looked up file from its sender.
+///
+/// # Arguments
+///
+/// * `phases` - The phases of the transfer, entering `Phase::Connecting` next.
+/// * `confirmed` - Whether the download was already confirmed, e.g. for all files of a batch.
+fn receive(receiver: &Receiver, lookup: Lookup, phases: PhaseProgress, confirmed: bool) -> Result<(), NudgeError> {
+    let mut state = ReceiverState::Preparing(lookup);
+    loop {
+        if let Some(phase) = state.phase() {
+            phases.enter(phase);
+        }
+        state = match state {
+            ReceiverState::Preparing(lookup) => match prepare(receiver, &lookup, confirmed)? {
+                Some(destination) => ReceiverState::Connecting(lookup, destination),
+                None => ReceiverState::Done,
+            },
+            ReceiverState::Connecting(lookup, destination) => match connect(receiver, lookup, destination)? {
+                Some(transfer) => ReceiverState::Transferring(Box::new(transfer)),
+                None => ReceiverState::Done,
+            },
+            ReceiverState::Transferring(transfer) => ReceiverState::Verifying(receive_file(receiver, *transfer)?),
+            ReceiverState::Verifying(received) => {
+                verify(receiver, received, &phases)?;
+                ReceiverState::Done
+            }
+            ReceiverState::Done => return Ok(()),
+        };
+    }
+}
+
+/// Shows the looked up file, decides where it's stored and asks whether to download it.
+///
+/// # Returns
+///
+/// `Option<Destination>` - Where the file is stored, or `None` if the user cancelled the download.
+///
+/// # Errors
+///
+/// Returns `NudgeError::SenderOffline` if the sender only offered the file and isn't online yet,
+/// `NudgeError::OutputUnsupported` if a directory or several files can't be stored where asked,
+/// `NudgeError::PolicyRejected` if the policy doesn't accept the file, `NudgeError::NoPromptExit` if the
+/// download isn't confirmed with --no-prompt, `NudgeError::DuplicateSession` if another receiver of this session
+/// writes the same file, or `NudgeError::Io` if the output can't be opened
+fn prepare(receiver: &Receiver, lookup: &Lookup, confirmed: bool) -> Result<Option<Destination>, NudgeError> {
+    let Receiver { get_opts, policy, .. } = receiver;
+    let Lookup { socket, passphrase, ticket, file_info, .. } = lookup;
+    let to_stdout = get_opts.writes_to_stdout();
+    let to_block_device = get_opts.writes_to_block_device();
+
+    status!(
         "{} Meta: {} by {} [{}]",
-        style("[✔]").bold().green(),
-        style(&file_info.file_name).yellow(),
+        success_mark(),
+        style(sanitize(&file_info.file_name)).yellow(),
         style(&file_info.sender_host).cyan(),
-        format_size(file_info.file_size, DECIMAL)
+        format_file_size(file_info)
     );
+    check_shared_output(get_opts, file_info)?;
+    // fails before downloading a file whose hash can't be checked
+    check_hash_algorithm(file_info, get_opts)?;
+    if file_info.directory {
+        status!(
+            "{} The sender shares a directory, {}",
+            style("[~]").bold().yellow(),
+            if to_stdout { "writing it to stdout as a tar archive" } else { "unpacking it while it's received" }
+        );
+    }
+    if let Some(files) = file_info.file_count {
+        status!(
+            "{} The sender shares {} files, {}",
+            style("[~]").bold().yellow(),
+            files,
+            if to_stdout { "writing them to stdout one after another" } else { "storing them in the output directory" }
+        );
+    }
+    if let Some(sender_geo) = &file_info.sender_geo {
+        status!(
+            "{} Origin: {}",
+            style("[~]").bold().yellow(),
+            style(sanitize(&sender_geo.to_string())).cyan()
+        );
+    }
 
-    let out_file_name = get_opts.out_file.as_deref().unwrap_or_else(|| {
-        // Use the file name from the sender if output file is not specified
-        file_info.file_name.split("/").last().expect("File name is empty")
-    });
+    // the sender of an offer only comes online once asked, there's nothing to confirm until then
+    if file_info.offer_only {
+        status!("{} The sender only offered the file, asking them to come online...", style("[~]").bold().yellow());
+        wake_sender(socket, &R2XWakeSenderMessage {
+            passphrase: passphrase.clone(),
+            receiver_host: peer_identity(get_opts.share_hostname, get_opts.display_name.as_deref()),
+            ticket: ticket.clone(),
+        })?;
+    }
 
-    // Check if the file already exists and ask for confirmation to overwrite
-    if !get_opts.overwrite_file && Path::new(out_file_name).exists() {
-        if get_opts.no_prompt {
-            println!("File {} already exists. Use -o <file> to specify a different output file.", out_file_name);
-            return Err(NudgeError::NoPromptExit);
+    // several files shared at once are stored in the output directory, the current one by default
+    let bundle = file_info.file_count.filter(|_| !to_stdout);
+    let file_name = match (get_opts.out_file.as_deref(), bundle) {
+        (Some(out_file), _) => out_file,
+        (None, Some(_)) => ".",
+        // Use the file name from the sender if output file is not specified, it must not lead out of the directory
+        (None, None) => sender_file_name(&file_info.file_name)?,
+    };
+
+    // The template sorts the file into directories, below the directory chosen by the policy (if any)
+    let relative_path = match &get_opts.out_template {
+        Some(template) => template.expand(file_info),
+        None => PathBuf::from(file_name),
+    };
+
+    // The policy decides instead of the user whether (and where) the file is stored
+    let out_file_name = match &policy {
+        Some(policy) => {
+            let rule = policy.evaluate(file_name, (!file_info.streaming).then_some(file_info.file_size), &file_info.sender_host)?;
+            let out_path = rule.out_path(&relative_path);
+            status!(
+                "{} Accepted by the receiver policy, storing at {}",
+                success_mark(),
+                style(sanitize(&out_path.display().to_string())).yellow()
+            );
+            out_path
         }
+        None => relative_path,
+    };
 
-        // Ask for confirmation to overwrite the file
-        if !Confirm::with_theme(&question_theme())
-            .with_prompt(format!("File {} already exists. Overwrite?", out_file_name))
-            .interact()
-            .unwrap()
-        {
-            println!("Cancelled by user. You can specify a different output file with -o <file>.");
-            return Ok(());
+    // only a plain file is written in order, sparse files are written around their holes
+    let resumable = !to_stdout && !to_block_device && !get_opts.append && !file_info.directory && bundle.is_none()
+        && !file_info.sparse && file_info.file_hash.0.is_some();
+    let resume_offset = match resumable && !get_opts.no_resume {
+        true => PartialDownload::find(&out_file_name, file_info),
+        false => None,
+    };
+    if let Some(offset) = resume_offset {
+        status!(
+            "{} {} was interrupted at {} of {}, resuming it (pass --no-resume to start over)",
+            style("[~]").bold().yellow(),
+            sanitize(&out_file_name.display().to_string()),
+            format_bytes(offset),
+            format_bytes(file_info.file_size)
+        );
+    }
+
+    // Keep an existing file and store the download next to it, unless asked to overwrite or append to it
+    let out_file_name = if get_opts.overwrite_file || get_opts.append || to_stdout || to_block_device || bundle.is_some()
+        || resume_offset.is_some() {
+        out_file_name
+    } else {
+        let unique_name = get_opts.rename_pattern.unique_path(&out_file_name)?;
+        if unique_name != out_file_name {
+            status!(
+                "{} File {} already exists, saving as {}",
+                style("[~]").bold().yellow(),
+                sanitize(&out_file_name.display().to_string()),
+                style(sanitize(&unique_name.display().to_string())).yellow()
+            );
         }
+        unique_name
+    };
+
+    // A preview needs the connection to the sender, so the download is confirmed after connecting
+    let preview_bytes = get_opts.preview.filter(|_| file_info.previewable);
+    if get_opts.preview.is_some() && preview_bytes.is_none() {
+        status!("{} The sender doesn't offer a preview of this file", style("[~]").bold().yellow());
     }
 
     // Ask for confirmation to download the file
-    if !get_opts.force {
+    if preview_bytes.is_none() && policy.is_none() && !get_opts.force && !confirmed {
         // never download if not -f and --no-prompt passed
         if get_opts.no_prompt {
-            println!("Do you want to download the file? Pass -f to download without asking.");
+            status!("Do you want to download the file? Pass -f to download without asking.");
             return Err(NudgeError::NoPromptExit);
         }
 
         // ask for confirmation
-        if !Confirm::with_theme(&question_theme())
-            .with_prompt("Do you want to download the file?")
-            .interact()
-            .unwrap()
-        {
-            println!("Cancelled by user.");
-            return Ok(());
+        let prompt = if to_block_device {
+            format!("Do you want to overwrite the block device {} with the file?", out_file_name.display())
+        } else if resume_offset.is_some() {
+            "Do you want to resume the download?".to_string()
+        } else if get_opts.append && !to_stdout && out_file_name.exists() {
+            format!("Do you want to append the file to {}?", sanitize(&out_file_name.display().to_string()))
+        } else if file_info.directory {
+            "Do you want to download the directory?".to_string()
+        } else if let Some(files) = file_info.file_count {
+            format!("Do you want to download the {} files?", files)
+        } else {
+            "Do you want to download the file?".to_string()
+        };
+        if !confirm(&prompt)? {
+            status!("Cancelled by user.");
+            return Ok(None);
         }
     }
 
-    let mut file = OpenOptions::new()
-        .truncate(false)
-        .write(true)
-        .create(true)
-        .read(true)
-        .open(out_file_name)?;
-    file.set_len(file_info.file_size)?;
+    // Claim the file before the session, so a second receiver of a multi-use passphrase leaves it to the next one
+    // the files shared at once are claimed one by one, by not overwriting them
+    let lock = if to_stdout || to_block_device || bundle.is_some() {
+        None
+    } else {
+        Some(SessionLock::acquire(&out_file_name, passphrase, current_unix_millis())?)
+    };
+    let mode = file_info.file_mode.filter(|_| get_opts.preserve).unwrap_or(get_opts.mode);
+    // several files are only stored once the sender listed them
+    let output = match preview_bytes.is_some() || bundle.is_some() {
+        true => None,
+        false => Some(open_output(get_opts, &out_file_name, file_info, mode, resume_offset.is_some())?),
+    };
+
+    let stdout_reason = to_stdout.then_some("Extended attributes can't be restored when writing to stdout")
+        .or(to_block_device.then_some("Extended attributes can't be restored on a block device"))
+        .or(get_opts.append.then_some("Extended attributes aren't restored when appending to a file"))
+        .or(file_info.directory.then_some("Extended attributes aren't restored for directories"))
+        .or(bundle.map(|_| "Extended attributes aren't restored for several files"));
+    let xattrs = get_opts.xattrs && match unsupported_reason().or(stdout_reason) {
+        Some(reason) => {
+            status!("{} {}", style("[~]").bold().yellow(), reason);
+            false
+        }
+        None => true,
+    };
+    Ok(Some(Destination { out_file_name, preview_bytes, output, mode, xattrs, lock, resumable, resume_offset }))
+}
+
+/// Asks the relay where a sender which doesn't answer is, until it restarted and took over its session from another
+/// address (`send --registration-id`), or `SENDER_RESTART_GRACE` passed.
+///
+/// # Arguments
+///
+/// * `socket` - The socket, connected to the relay again while asking.
+/// * `relay_addr` - The address of the relay.
+/// * `connection_request` - The request sent to the relay before, repeated to ask it.
+/// * `sender_addr` - The address the sender doesn't answer at.
+///
+/// # Returns
+///
+/// `Option<SocketAddr>` - The new address of the sender, or `None` if it didn't move (or the relay doesn't tell).
+fn find_restarted_sender(
+    socket: &UdpSocket,
+    relay_addr: SocketAddr,
+    connection_request: &R2XRequestSenderConnectionMessage,
+    sender_addr: SocketAddr,
+) -> Result<Option<SocketAddr>, NudgeError> {
+    debug!("The sender doesn't answer, asking the relay whether it restarted...");
+    let deadline = Instant::now() + SENDER_RESTART_GRACE;
+    socket.connect(relay_addr)?;
+    while Instant::now() < deadline {
+        match request_sender_connection(socket, connection_request) {
+            Ok(Some(current_addr)) if current_addr != sender_addr => return Ok(Some(current_addr)),
+            Ok(Some(_)) => std::thread::sleep(SENDER_RESTART_POLL),
+            Ok(None) => return Ok(None),
+            // the session expired, the sender didn't come back in time
+            Err(e) => {
+                debug!("The relay doesn't know the sender anymore: {}", e);
+                return Ok(None);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Asks the sender to connect, punches through the NATs and reads what the sender sends before the data stream
+/// (identity challenge, preview, extended attributes, sparse map and compression).
+///
+/// # Returns
+///
+/// `Option<Transfer>` - The connection ready for the data stream, or `None` if the user cancelled after the preview.
+///
+/// # Errors
+///
+/// Returns `NudgeError` if the relay doesn't connect us, the sender aborts, or the output can't be opened
+fn connect(receiver: &Receiver, lookup: Lookup, destination: Destination) -> Result<Option<Transfer>, NudgeError> {
+    let Receiver { get_opts, identity, tuning, .. } = receiver;
+    let Lookup { socket, port_mapping, passphrase, ticket, file_info, secret } = lookup;
+    let Destination { out_file_name, preview_bytes, output, mode, xattrs, lock, resumable, resume_offset } = destination;
+    let to_stdout = get_opts.writes_to_stdout();
+    let to_block_device = get_opts.writes_to_block_device();
+    // stdout can't skip the holes, and neither a device nor the end of an appended file is zeroed,
+    // so the sender has to send them as zeros
+    let sparse = file_info.sparse && !to_stdout && !to_block_device && !get_opts.append;
+    // several files written to stdout are simply concatenated
+    let manifest = file_info.file_count.is_some() && !to_stdout;
 
     // Request sender to connect
-    let hostname = hide_or_get_hostname(get_opts.hide_hostname)?;
+    let mut sender_addr = mapped_addr(file_info.sender_addr, file_info.sender_mapped_port);
+    let hostname = peer_identity(get_opts.share_hostname, get_opts.display_name.as_deref());
     debug!(
+        target: "relay_client",
         "Requesting sender to connect to us ({})...",
         hostname
     );
-    serialize_and_send(&socket, "R2X_RSC", &R2XRequestSenderConnectionMessage {
-        passphrase,
+    // the key is derived from the passphrase, which tickets of blind relays only carry hashed
+    let encryption_salt = secret.as_ref().filter(|_| encryption::is_supported()).map(|_| generate_salt());
+    if secret.is_none() {
+        if !receiver.allow_unencrypted {
+            return Err(NudgeError::TicketWithoutPassphrase);
+        }
+        status!("{} The data stream isn't encrypted, the ticket doesn't carry the passphrase", style("[~]").bold().yellow());
+    }
+    let connection_request = R2XRequestSenderConnectionMessage {
+        passphrase: passphrase.clone(),
         file_hash: file_info.file_hash.clone(),
         receiver_host: hostname,
-    })?;
+        mapped_port: port_mapping.as_ref().map(|mapping| mapping.external_port),
+        preview: preview_bytes.is_some(),
+        xattrs,
+        sparse,
+        manifest,
+        compress: get_opts.compress,
+        zstd: compression::is_supported() && (get_opts.compress || file_info.compress),
+        resume_offset,
+        encryption_salt: encryption_salt.map(|salt| encode_hex(&salt)),
+        key_exchange: encryption_salt.is_some(),
+        // the sender lowers its chunk size to ours, if we chose one for the network
+        max_chunk_size: (get_opts.chunk_size.is_some() || get_opts.profile.is_some()).then_some(tuning.chunk_size),
+        ticket,
+        identity: identity.as_ref().map(Identity::public_key),
+    };
+    // the sender may have registered again from another address since we looked it up
+    if let Some(current_addr) = request_sender_connection(&socket, &connection_request)? {
+        sender_addr = current_addr;
+    }
+    let relay_addr = socket.peer_addr()?;
 
-    println!(
+    status!(
         "{} Connecting to {} ({})...",
         style("[~]").bold().yellow(),
         style(&file_info.sender_host).cyan(),
-        style(&file_info.sender_addr).dim()
+        style(&sender_addr).dim()
     );
-    socket.connect(file_info.sender_addr)?;
+    connect_to_peer(&socket, sender_addr)?;
 
     debug!("Initializing socket connection...");
-    init_socket(&socket)?;
+    let answered = init_socket(&socket);
+    if !matches!(answered, Ok(true)) {
+        match find_restarted_sender(&socket, relay_addr, &connection_request, sender_addr)? {
+            Some(current_addr) => {
+                status!("{} The sender restarted, connecting to {}...", style("[~]").bold().yellow(), style(&current_addr).dim());
+                connect_to_peer(&socket, current_addr)?;
+                init_socket(&socket)?;
+            }
+            None => {
+                connect_to_peer(&socket, sender_addr)?;
+                answered?;
+            }
+        }
+    }
+    // only entered now, the sandbox forbids connecting the socket to a sender which restarted
+    if get_opts.sandbox {
+        enter_sandbox((!to_stdout).then_some(out_file_name.as_path()), get_opts.manifest.as_deref())?;
+    }
     debug!("Ready to receive data!");
 
     // Wrap the socket in a "reliable udp socket"
-    let mut safe_connection = ReliableUdpSocket::new(socket);
+    let mut connection = ReliableUdpSocket::new(socket)
+        .with_max_in_flight(tuning.max_in_flight)
+        .with_dedup_window(get_opts.dedup_window.unwrap_or(DEFAULT_DEDUP_WINDOW))
+        .with_ack_every(get_opts.ack_every)
+        .with_reorder_buffer(get_opts.reorder_buffer)
+        .with_peer_timeout(get_opts.peer_timeout)
+        .with_capture(receiver.capture.clone());
+    // the packets are kept with their header, the window bounds the buffer
+    let reorder_buffer = connection.reorder_buffer();
+    debug!(
+        target: "transport",
+        "Reorder buffer: {} packets, up to {}",
+        reorder_buffer,
+        format_bytes(reorder_buffer as u64 * (u64::from(tuning.chunk_size) + 3))
+    );
+
+    if let (Some(salt), Some(secret)) = (encryption_salt, &secret) {
+        start_encryption(&mut connection, secret, &salt, receiver.allow_unencrypted, tuning.delay)?;
+    }
+    if let Some(identity) = &identity {
+        prove_identity(&mut connection, identity, &passphrase, tuning.delay)?;
+    }
+    if let Some(max_bytes) = preview_bytes {
+        if !preview_and_confirm(&mut connection, max_bytes, tuning.delay)? {
+            status!("Cancelled by user.");
+            return Ok(None);
+        }
+    }
+    let attributes = if xattrs {
+        read_message::<ExtendedAttributesMessage>(&mut connection)?.attributes
+    } else {
+        None
+    };
+    if xattrs && attributes.is_none() {
+        status!("{} The sender doesn't share extended attributes", style("[~]").bold().yellow());
+    }
+    let mut output = match output {
+        Some(output) => output,
+        None if manifest => {
+            let manifest = read_message::<FileManifestMessage>(&mut connection)?;
+            open_bundle(get_opts, &out_file_name, &file_info, manifest, mode)?
+        }
+        None => open_output(get_opts, &out_file_name, &file_info, mode, resume_offset.is_some())?,
+    };
+    let extents = if sparse {
+        Some(read_message::<SparseMapMessage>(&mut connection)?.extents)
+    } else {
+        None
+    };
+    let compression = if connection_request.compress || connection_request.zstd {
+        Some(read_message::<CompressionMessage>(&mut connection)?).filter(|message| message.compressed)
+    } else {
+        None
+    };
+    let decompressor = compression.map(|message| {
+        debug!(target: "io", "The sender compresses the data stream ({})", message.algorithm);
+        Decompressor::new(message.algorithm)
+    }).transpose()?;
+    if get_opts.compress && decompressor.is_none() {
+        status!("{} The sender doesn't compress the data stream", style("[~]").bold().yellow());
+    }
+    let resume_from = match resume_offset {
+        Some(requested) => {
+            let offset = read_message::<ResumeMessage>(&mut connection)?.offset;
+            // the data before the offset would be missing
+            if offset > requested {
+                connection.abort("Sender skips data the receiver doesn't have");
+                return Err(NudgeError::InvalidResumeOffset(offset, requested));
+            }
+            if offset < requested {
+                status!("{} The sender can't resume the download, receiving the whole file", style("[~]").bold().yellow());
+            }
+            if let Output::File(file) = &mut output {
+                file.seek(std::io::SeekFrom::Start(offset))?;
+            }
+            Some(offset)
+        }
+        None => resumable.then_some(0),
+    };
+    Ok(Some(Transfer { file_info, out_file_name, connection, output, attributes, extents, decompressor, lock, resume_from }))
+}
 
-    println!(
+/// Receives the data stream of the sender into the output.
+///
+/// # Errors
+///
+/// Returns `NudgeError::TransferAborted` if the sender aborts, or `NudgeError::Io` if the output can't be written
+fn receive_file(receiver: &Receiver, transfer: Transfer) -> Result<Received, NudgeError> {
+    let Receiver { get_opts, tuning, memory_profile, .. } = receiver;
+    let Transfer {
+        mut file_info, out_file_name, mut connection, mut output, attributes, extents, mut decompressor, lock, resume_from,
+    } = transfer;
+    let to_stdout = get_opts.writes_to_stdout();
+    let resumed_bytes = resume_from.unwrap_or(0);
+    let data_size = extents.as_deref().map(data_size).unwrap_or(file_info.file_size) - resumed_bytes;
+
+    status!(
         "{} Receiving {} (chunk-size: {})...",
         style("[~]").bold().yellow(),
-        format_size(file_info.file_size, DECIMAL),
-        style(format_size(get_opts.chunk_size, DECIMAL)).dim()
+        if file_info.streaming { "the stream".to_string() } else { format_bytes(file_info.file_size) },
+        style(format_bytes(u64::from(tuning.chunk_size))).dim()
     );
+    if resumed_bytes > 0 {
+        status!(
+            "{} Resuming after {}, {} left",
+            style("[~]").bold().yellow(),
+            format_bytes(resumed_bytes),
+            format_bytes(data_size)
+        );
+    }
+    // an interrupted download continues where it stopped with the next `get` of the file
+    let mut partial = match resume_from {
+        Some(offset) => PartialDownload::start(&out_file_name, &file_info, offset, current_unix_millis())?,
+        None => None,
+    };
+    if extents.is_some() {
+        status!(
+            "{} Sparse file, only {} of data are transferred",
+            style("[~]").bold().yellow(),
+            format_bytes(data_size)
+        );
+    }
 
-    let progress_bar = new_downloader_progressbar(file_info.file_size);
+    let progress_bar = match file_info.streaming {
+        true => new_stream_progressbar(),
+        false => new_downloader_progressbar(data_size),
+    };
 
-    // Used for calculating the total time taken
-    let start_time = current_unix_millis();
+    // Used for calculating the total time taken, with the monotonic clock in case the wall clock changes meanwhile
+    let start_time = Instant::now();
 
-    // Used for updating the progress bar
+    // Used for updating the progress bar, counts the data written (after inflating it)
     let mut bytes_received: u64 = 0;
 
-    // Update progress every 25 KiB
-    let update_progress_rate = (1024 * 25) / get_opts.chunk_size;
-    let mut current_progress = 0;
+    let buffer: Vec<u8> = vec![0; tuning.chunk_size as usize];
+    // the data stops for hours outside of the sender's schedule, which isn't worth reporting
+    let stall_report = match &file_info.schedule {
+        Some(schedule) => {
+            status!("{} The sender sends {} (its local time)", style("[~]").bold().yellow(), schedule);
+            Duration::ZERO
+        }
+        None => get_opts.stall_report,
+    };
+    let mut watchdog = StallWatchdog::new(stall_report, current_unix_millis());
 
-    let mut buffer: Vec<u8> = vec![0; get_opts.chunk_size as usize];
+    // the data of sparse files is written to its regions, the holes in between are left untouched.
+    // Writing to stdout blocks while a slow reader on the other end of the pipe catches up.
+    // Files are hashed while writing them, including the data of a resumed download, so they aren't read again
+    let mut file_hash = None;
+    let buffer_size = memory_profile.hash_buffer_size;
+    let (mut writer, mut hasher): (Box<dyn Write>, Option<&mut blake3::Hasher>) = match (&mut output, extents) {
+        (Output::File(file), Some(extents)) => {
+            let file_hash = file_hash.insert(HashingWriter::start(file, 0, 0, buffer_size)?);
+            (Box::new(SparseWriter::new(file_hash, extents)), None)
+        }
+        (Output::File(file), None) => (Box::new(file_hash.insert(HashingWriter::start(file, 0, resumed_bytes, buffer_size)?)), None),
+        (Output::Append(file, offset), _) => (Box::new(file_hash.insert(HashingWriter::start(file, *offset, 0, buffer_size)?)), None),
+        (Output::Stdout(hasher), _) => (Box::new(std::io::stdout().lock()), Some(hasher)),
+        (Output::Directory(unpacker, hasher), _) => (Box::new(unpacker), Some(hasher)),
+        (Output::Bundle(writer, hasher), _) => (Box::new(writer), Some(hasher)),
+    };
 
     loop {
-        let (read_buffer, bytes_read) = safe_connection.read(&mut buffer)?;
+        let receive_span = span(Stage::Receive);
+        let (read_buffer, bytes_read) = match read_chunk(&mut connection, &buffer, &mut watchdog, bytes_received, data_size) {
+            Err(NudgeError::TransferAborted(reason)) => {
+                status!(
+                    "{} Transfer aborted, {} is incomplete ({} of {})",
+                    failure_mark(),
+                    if to_stdout { "stdout".into() } else { sanitize(&out_file_name.display().to_string()) },
+                    format_bytes(bytes_received),
+                    format_bytes(data_size)
+                );
+                return Err(NudgeError::TransferAborted(reason));
+            }
+            result => result?,
+        };
+        drop(receive_span);
         if bytes_read == 0 {
-            progress_bar.finish_with_message("Transfer complete! 🎉");
+            progress_bar.finish_with_message(complete_message());
             break;
         }
 
-        let buffer = &read_buffer[..bytes_read];
-        file.write_all(buffer)?;
-        file.flush()?;
+        let write_span = span(Stage::Write);
+        let chunk = &read_buffer[..bytes_read];
+        let written = match &mut decompressor {
+            Some(decompressor) => decompressor.decompress(chunk, |data| {
+                bytes_received += data.len() as u64;
+                write_output(&mut writer, hasher.as_deref_mut(), data)
+            }),
+            None => {
+                bytes_received += bytes_read as u64;
+                write_output(&mut writer, hasher.as_deref_mut(), chunk)
+            }
+        };
+        if let Err(e) = written.and_then(|_| Ok(writer.flush()?)) {
+            // e.g. the reader of the pipe exited, let the sender know instead of keeping it waiting
+            let reason = match &e {
+                NudgeError::Io(e) => e.to_string(),
+                e => e.to_string(),
+            };
+            connection.abort(&format!("Receiver cannot write the file: {}", reason));
+            return Err(e);
+        }
+        // the time blocked writing isn't the system sleeping
+        connection.mark_busy();
+        watchdog.progressed(current_unix_millis());
+        if let Some(partial) = &mut partial {
+            partial.record(resumed_bytes + bytes_received, current_unix_millis());
+        }
+        drop(write_span);
+        progress_bar.set_position(bytes_received);
+    }
+
+    drop(writer);
+    // the size of a stream is known once it ended
+    if file_info.streaming {
+        file_info.file_size = bytes_received;
+    }
+    let written_hash = match file_hash {
+        Some(file_hash) => file_hash.finalize(file_info.file_size),
+        None => match &output {
+            Output::Stdout(hasher) | Output::Directory(_, hasher) | Output::Bundle(_, hasher) => hasher.finalize().to_hex().to_string(),
+            Output::File(_) | Output::Append(..) => unreachable!("files are hashed while writing"),
+        },
+    };
+    if let Some(partial) = partial {
+        partial.complete();
+    }
+    // the data is only on the device once the caches are flushed, e.g. before the stick is pulled
+    if let (true, Output::File(file)) = (get_opts.writes_to_block_device(), &output) {
+        file.sync_all()?;
+    }
+    if let Output::Directory(unpacker, _) = &output {
+        let files = unpacker.finish()?;
+        status!(
+            "{} Unpacked {} files into {}",
+            success_mark(),
+            files,
+            style(sanitize(&out_file_name.display().to_string())).yellow()
+        );
+    }
+    if let Output::Bundle(writer, _) = &mut output {
+        let files = writer.finish()?;
+        status!(
+            "{} Stored {} files in {}",
+            success_mark(),
+            files,
+            style(sanitize(&out_file_name.display().to_string())).yellow()
+        );
+    }
 
-        bytes_received += bytes_read as u64;
+    let duration_millis = start_time.elapsed().as_millis() as u64;
+    status!(
+        "{} File received successfully in {}s!",
+        success_mark(),
+        duration_millis as f64 / 1000.0
+    );
+    status!("{} Datagrams: {}", style("[~]").bold().yellow(), connection.statistics());
+    profiling::dump();
+    Ok(Received { file_info, out_file_name, output, written_hash, attributes, duration_millis, lock })
+}
 
-        current_progress += 1;
-        if current_progress % update_progress_rate == 0 {
-            progress_bar.set_position(bytes_received);
+/// Reads the next chunk of the sender like `ReliableUdpSocket::read`, reporting where the transfer stands
+/// whenever the watchdog finds it stalled.
+///
+/// # Arguments
+///
+/// * `bytes_received` - Bytes received so far, for the report.
+/// * `data_size` - Bytes expected in total, for the report.
+fn read_chunk(
+    connection: &mut ReliableUdpSocket,
+    buffer: &[u8],
+    watchdog: &mut StallWatchdog,
+    bytes_received: u64,
+    data_size: u64,
+) -> Result<(Vec<u8>, usize), NudgeError> {
+    loop {
+        if let Some(received) = connection.try_read(buffer, STALL_CHECK_INTERVAL)? {
+            return Ok(received);
+        }
+        if let Some(stalled) = watchdog.check(current_unix_millis()) {
+            report_stall(&connection.receive_state(), stalled, bytes_received, data_size);
         }
     }
+}
 
-    println!(
-        "{} File received successfully in {}s!",
-        style("[✔]").bold().green(),
-        (current_unix_millis() - start_time) as f64 / 1000.0
+/// Prints where a stalled transfer stands, e.g.
+/// "No data for 20s at 43% (8.6 MB of 20 MB): expecting packet #2101, the last packet arrived 20s ago, ..."
+fn report_stall(state: &ReceiveState, stalled: Duration, bytes_received: u64, data_size: u64) {
+    let percent = (bytes_received * 100).checked_div(data_size).unwrap_or(100);
+    let last_packet = match state.last_packet_at {
+        Some(last_packet_at) => format!(
+            "the last packet arrived {} ago",
+            format_duration(Duration::from_millis(current_unix_millis().saturating_sub(last_packet_at)))
+        ),
+        None => "no packet arrived yet".to_string(),
+    };
+    status!(
+        "{} No data for {} at {}% ({} of {}): expecting packet #{}, {}, {} resend requests, {}",
+        style("[~]").bold().yellow(),
+        format_duration(stalled),
+        percent,
+        format_bytes(bytes_received),
+        format_bytes(data_size),
+        state.received_packets,
+        last_packet,
+        state.resend_requests,
+        state.statistics
     );
+}
+
+/// Checks the hash of the received file, restores its extended attributes and reports where it landed.
+///
+/// # Errors
+///
+/// Returns `NudgeError::HashMismatch` if the file doesn't match the hash of the sender,
+/// or `NudgeError::Io` if the attributes or the manifest can't be written
+fn verify(receiver: &Receiver, received: Received, phases: &PhaseProgress) -> Result<(), NudgeError> {
+    let Receiver { get_opts, .. } = receiver;
+    let Received { file_info, out_file_name, output, written_hash, attributes, duration_millis, lock } = received;
+
+    let hash = verify_file_hash(written_hash, &file_info, get_opts)?;
+
+    if let (Some(attributes), Output::File(file)) = (attributes, &output) {
+        let restored = write_attributes(file, &attributes)?;
+        status!(
+            "{} Restored {} of {} extended attributes",
+            success_mark(),
+            restored,
+            attributes.len()
+        );
+    }
+    // the file is complete, other receivers may write it again
+    drop(lock);
+
+    let summary = TransferSummary {
+        path: match output {
+            Output::File(_) | Output::Append(..) | Output::Directory(..) | Output::Bundle(..) => {
+                std::fs::canonicalize(&out_file_name).unwrap_or(out_file_name)
+            }
+            Output::Stdout(_) => PathBuf::from(STDOUT_PATH),
+        },
+        size: file_info.file_size,
+        hash,
+        duration_millis,
+        phases: phases.durations(),
+    };
+    status!("{} Phases: {}", style("[~]").bold().yellow(), format_phase_durations(&summary.phases));
+
+    if let Some(manifest_path) = &get_opts.manifest {
+        let entry = ManifestEntry::new(&file_info, &summary, current_unix_millis());
+        TransferManifest::new(file_info.sender_host.0.clone(), vec![entry]).write(manifest_path)?;
+        status!(
+            "{} Manifest written to {}",
+            success_mark(),
+            style(manifest_path.display()).yellow()
+        );
+    }
+
+    if is_quiet() {
+        // print a single line so scripts can pick up where the file landed (on stderr if the file went to stdout)
+        report!("{}", summary.to_line());
+    }
 
+    Ok(())
+}
+
+/// Opens where the file is written to: stdout (`-o -`), a block device, the end of a file (`--append`), a new file
+/// (or the one of an interrupted download, if `resume`) or, if the sender shares a directory, the directory its
+/// archive is unpacked into.
+///
+/// # Errors
+///
+/// Returns `NudgeError::BlockDeviceTooSmall` if the file doesn't fit on the device,
+/// or `NudgeError::Io` if the file can't be created
+fn open_output(get_opts: &GetOpts, out_file_name: &Path, file_info: &FileInfo, mode: u32, resume: bool) -> Result<Output, NudgeError> {
+    let file_size = file_info.file_size;
+    Ok(if get_opts.writes_to_stdout() {
+        Output::Stdout(Box::default())
+    } else if file_info.directory {
+        // the files keep the permissions of the sender's files only with --preserve
+        let mode = (!get_opts.preserve).then_some(mode);
+        Output::Directory(ArchiveUnpacker::new(out_file_name, mode), Box::default())
+    } else if get_opts.writes_to_block_device() {
+        Output::File(open_block_device(out_file_name, file_size)?)
+    } else if get_opts.append {
+        let (file, offset) = open_appended_file(out_file_name, mode)?;
+        Output::Append(file, offset)
+    } else if resume {
+        Output::File(open_resumed_file(out_file_name)?)
+    } else {
+        Output::File(open_output_file(out_file_name, file_size, mode)?)
+    })
+}
+
+/// Opens the directory several files shared at once are stored in, once the sender listed them.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ManifestMismatch` if the files don't add up to what the sender announced,
+/// `NudgeError::UnsafePath` if a name isn't a plain file name, or `NudgeError::Io` if the directory can't be created
+fn open_bundle(
+    get_opts: &GetOpts,
+    directory: &Path,
+    file_info: &FileInfo,
+    manifest: FileManifestMessage,
+    mode: u32,
+) -> Result<Output, NudgeError> {
+    let size: u64 = manifest.files.iter().map(|file| file.size).sum();
+    if Some(manifest.files.len() as u32) != file_info.file_count || size != file_info.file_size {
+        return Err(NudgeError::ManifestMismatch);
+    }
+    // existing files are kept, like a single file
+    let rename = (!get_opts.overwrite_file).then(|| get_opts.rename_pattern.clone());
+    Ok(Output::Bundle(Box::new(BundleWriter::new(directory, manifest, rename, mode)?), Box::default()))
+}
+
+/// Checks a directory or several files shared by the sender can be stored where the file would be.
+///
+/// # Errors
+///
+/// Returns `NudgeError::OutputUnsupported` for a block device or `--append`
+fn check_shared_output(get_opts: &GetOpts, file_info: &FileInfo) -> Result<(), NudgeError> {
+    let shared = match (file_info.directory, file_info.file_count) {
+        (true, _) => "a directory",
+        (false, Some(_)) => "several files",
+        (false, None) => return Ok(()),
+    };
+    if get_opts.writes_to_stdout() {
+        Ok(())
+    } else if get_opts.writes_to_block_device() {
+        Err(NudgeError::OutputUnsupported(shared, "written to a block device"))
+    } else if get_opts.append {
+        Err(NudgeError::OutputUnsupported(shared, "appended to a file"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Restricts the process to the connection to the sender and the directories of the output file and the manifest.
+fn enter_sandbox(out_file_name: Option<&Path>, manifest: Option<&Path>) -> Result<(), NudgeError> {
+    let writable_dirs: Vec<&Path> = [out_file_name, manifest]
+        .into_iter()
+        .flatten()
+        .map(|path| path.parent().unwrap_or(Path::new("")))
+        .collect();
+    let status = sandbox::enter(&writable_dirs, &[])?;
+    match status.landlock_abi {
+        Some(abi) => status!(
+            "{} Sandboxed: network limited to the sender, files to the output directory (Landlock ABI {})",
+            success_mark(),
+            abi
+        ),
+        None => status!(
+            "{} Sandboxed: network limited to the sender, but the kernel doesn't support Landlock to limit file access",
+            style("[~]").bold().yellow()
+        ),
+    }
+    Ok(())
+}
+
+/// Returns the size of the file for display, streams (`send -`) don't tell theirs.
+fn format_file_size(file_info: &FileInfo) -> String {
+    match file_info.streaming {
+        true => "unknown size, streamed".to_string(),
+        false => format_bytes(file_info.file_size),
+    }
+}
+
+/// Writes received data to the output, updating the hash of data written to stdout.
+fn write_output(writer: &mut dyn Write, hasher: Option<&mut blake3::Hasher>, data: &[u8]) -> Result<(), NudgeError> {
+    if let Some(hasher) = hasher {
+        hasher.update(data);
+    }
+    Ok(writer.write_all(data)?)
+}
+
+/// Opens (or creates) the output file and its directories, sets its permissions and reserves space for the download.
+fn open_output_file(out_file_name: &Path, file_size: u64, mode: u32) -> Result<File, NudgeError> {
+    if let Some(dir) = out_file_name.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new()
+        .truncate(false)
+        .write(true)
+        .create(true)
+        .read(true)
+        .open(out_file_name)?;
+    // Lock before touching the content, another receiver might be writing to the same file
+    lock_file(&file, out_file_name)?;
+    set_file_mode(&file, mode)?;
+    // Truncate first, holes skipped by sparse transfers have to read as zeros
+    preallocate(&file, 0)?;
+    preallocate(&file, file_size)?;
+    Ok(file)
+}
+
+/// Opens the file of an interrupted download, leaving its content untouched. It's written from the offset the sender
+/// continues at, once it told.
+fn open_resumed_file(out_file_name: &Path) -> Result<File, NudgeError> {
+    let file = OpenOptions::new().write(true).read(true).open(out_file_name)?;
+    // Lock before touching the content, another receiver might be writing to the same file
+    lock_file(&file, out_file_name)?;
+    Ok(file)
+}
+
+/// Opens (or creates) the file the download is appended to, leaving its content and permissions untouched.
+///
+/// # Returns
+///
+/// `(File, u64)` - The file, which is written at its end, and its length before appending.
+fn open_appended_file(out_file_name: &Path, mode: u32) -> Result<(File, u64), NudgeError> {
+    if let Some(dir) = out_file_name.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let existed = out_file_name.exists();
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .read(true)
+        .open(out_file_name)?;
+    lock_file(&file, out_file_name)?;
+    if !existed {
+        set_file_mode(&file, mode)?;
+    }
+    let offset = file.metadata()?.len();
+    if offset > 0 {
+        status!(
+            "{} Appending to {} after its {}",
+            style("[~]").bold().yellow(),
+            style(sanitize(&out_file_name.display().to_string())).yellow(),
+            format_bytes(offset)
+        );
+    }
+    Ok((file, offset))
+}
+
+/// Opens a block device to write the file to, without truncating (or resizing) it.
+///
+/// # Errors
+///
+/// Returns `NudgeError::BlockDeviceTooSmall` if the file doesn't fit on the device.
+fn open_block_device(out_file_name: &Path, file_size: u64) -> Result<File, NudgeError> {
+    let mut file = OpenOptions::new().write(true).read(true).open(out_file_name)?;
+    lock_file(&file, out_file_name)?;
+    let capacity = file.seek(std::io::SeekFrom::End(0))?;
+    file.seek(std::io::SeekFrom::Start(0))?;
+    if capacity < file_size {
+        return Err(NudgeError::BlockDeviceTooSmall(out_file_name.display().to_string(), file_size, capacity));
+    }
+    status!(
+        "{} Writing to block device {} ({} of {})",
+        style("[~]").bold().yellow(),
+        style(out_file_name.display()).yellow(),
+        format_bytes(file_size),
+        format_bytes(capacity)
+    );
+    Ok(file)
+}
+
+/// Starts encrypting the data stream, which the sender agrees to first thing after connecting.
+/// The answer isn't authenticated, so a sender declining is refused rather than trusted.
+///
+/// # Arguments
+///
+/// * `safe_connection` - The connection to the sender.
+/// * `secret` - The passphrase as given, not the hash a blind relay knows.
+/// * `salt` - The salt sent with the connection request.
+/// * `allow_unencrypted` - Whether the data stream may be received unencrypted (`--allow-unencrypted`).
+///
+/// # Errors
+///
+/// Returns `NudgeError::EncryptionDeclined` if the sender doesn't agree on a key with a key exchange,
+/// or `NudgeError::KeyExchangeFailed` if the sender doesn't know the passphrase
+pub(crate) fn start_encryption(
+    safe_connection: &mut ReliableUdpSocket,
+    secret: &Passphrase<'static>,
+    salt: &[u8],
+    allow_unencrypted: bool,
+    delay: u64,
+) -> Result<(), NudgeError> {
+    let encryption: EncryptionMessage = read_message(safe_connection)?;
+    if !encryption.encrypted || !encryption.key_exchange {
+        if !allow_unencrypted {
+            return Err(NudgeError::EncryptionDeclined);
+        }
+        status!("{} The data stream isn't encrypted, the peer can't encrypt", style("[~]").bold().yellow());
+        return Ok(());
+    }
+    let cipher = exchange_keys(safe_connection, secret, salt, Role::Receiver, delay)?;
+    safe_connection.start_encryption(cipher)?;
+    Ok(())
+}
+
+/// Answers the sender's identity challenge with a signature of the nonce and the passphrase.
+fn prove_identity(
+    safe_connection: &mut ReliableUdpSocket,
+    identity: &Identity,
+    passphrase: &Passphrase<'static>,
+    delay: u64,
+) -> Result<(), NudgeError> {
+    let challenge: IdentityChallengeMessage = read_message(safe_connection)?;
+    debug!(target: "crypto", "Sender challenged the identity with nonce {}", challenge.nonce);
+    let signature = identity.sign(&identity_proof_message(&challenge.nonce, passphrase));
+    write_message(safe_connection, &IdentityProofMessage { signature }, delay)
+}
+
+/// Asks the sender for a preview of the file, shows it and asks whether to download the file.
+/// The decision is sent to the sender, which only starts sending the file if it's positive.
+///
+/// # Returns
+///
+/// `Result<bool>` - Whether the file should be downloaded.
+fn preview_and_confirm(safe_connection: &mut ReliableUdpSocket, max_bytes: u32, delay: u64) -> Result<bool, NudgeError> {
+    write_message(safe_connection, &PreviewRequestMessage { max_bytes }, delay)?;
+    let (preview, bytes_read) = safe_connection.read(&[0; 1])?;
+    status!(
+        "{} Preview ({}):",
+        style("[~]").bold().yellow(),
+        format_bytes(bytes_read as u64)
+    );
+    status!("{}", style(format_preview(&preview[..bytes_read])).dim());
+
+    let download = confirm("Do you want to download the file?")?;
+    write_message(safe_connection, &PreviewDecisionMessage { download }, delay)?;
+    Ok(download)
+}
+
+/// Checks the hash of the received file against the hash sent by the sender.
+/// The data was hashed while writing it, so the file isn't read again.
+///
+/// # Arguments
+///
+/// * `written_hash` - The hash of the data written.
+///
+/// # Returns
+///
+/// `Result<Option<String>>` - The hash of the received file, or `None` if the check was skipped.
+fn verify_file_hash(written_hash: String, file_info: &FileInfo, get_opts: &GetOpts) -> Result<Option<String>, NudgeError> {
     if get_opts.skip_hash {
         // if the hash is skipped, we don't need to check it
-        return Ok(());
+        return Ok(None);
+    }
+
+    // streams are sent as they're read, there's nothing to hash beforehand
+    if file_info.streaming && file_info.file_hash.0.is_none() {
+        status!("{} The sender streamed the data, skipping hash check...", style("[~]").bold().yellow());
+        return Ok(None);
     }
 
     // If no hash was sent, display warning to the user
     // we only treat this case as a warning, not an error
     if file_info.file_hash.0.is_none() {
-        println!(
+        status!(
             "{} Sender did not send a hash! Skipping hash check...",
-            style("[✗]").bold().red()
+            failure_mark()
         );
-        return Ok(());
+        return Ok(None);
     }
+    check_hash_algorithm(file_info, get_opts)?;
 
-    println!(
+    status!(
         "{} Checking file hash...",
         style("[~]").bold().yellow(),
     );
+    check_hash(file_info, written_hash)
+}
 
-    file.seek(std::io::SeekFrom::Start(0))?;
-    let actual_hash = hash_file_and_seek(&mut file)?;
-    file.seek(std::io::SeekFrom::Start(0))?;
+/// Checks that the hash of the sender can be checked. A newer sender may hash with an algorithm this version
+/// doesn't know, the file is only accepted unchecked with `--skip-hash` rather than trusting it silently.
+///
+/// # Errors
+///
+/// Returns `NudgeError::UnknownHashAlgorithm` if the algorithm isn't known and the check isn't skipped
+fn check_hash_algorithm(file_info: &FileInfo, get_opts: &GetOpts) -> Result<(), NudgeError> {
+    match file_info.hash_algorithm.as_deref() {
+        Some(algorithm) if !get_opts.skip_hash && !is_known_hash_algorithm(Some(algorithm)) => {
+            Err(NudgeError::UnknownHashAlgorithm(sanitize(algorithm)))
+        }
+        _ => Ok(()),
+    }
+}
 
-    let expected_hash = file_info.file_hash.0.unwrap();
+/// Compares the hash of the received data with the one of the sender.
+///
+/// # Errors
+///
+/// Returns `NudgeError::HashMismatch` if the hashes differ
+fn check_hash(file_info: &FileInfo, actual_hash: String) -> Result<Option<String>, NudgeError> {
+    let expected_hash = file_info.file_hash.0.clone().unwrap();
 
     if expected_hash != actual_hash {
-        println!(
+        status!(
             "{} Hash mismatch!\n\t\tExpected: {},\n\t\tReceived: {}",
-            style("[✗]").bold().red(),
+            failure_mark(),
             expected_hash,
             actual_hash
         );
         return Err(NudgeError::HashMismatch(expected_hash, actual_hash));
     }
 
-    println!(
+    status!(
         "{} Hash check successful!",
-        style("[✔]").bold().green(),
+        success_mark(),
     );
 
-    Ok(())
+    Ok(Some(actual_hash))
 }
\ No newline at end of file
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use time::OffsetDateTime;
+
+use crate::commands::RootOpts;
+use crate::error::{NudgeError, Result};
+use crate::utils::{current_unix_millis, hash_file_and_seek};
+use crate::utils::identity::{Identity, IdentityFile, PublicKey, IDENTITY_PASSPHRASE_ENV};
+use crate::utils::memory::MemoryProfile;
+use crate::utils::ui::{password, style, success_mark};
+
+/// Prefix of the signed message, so file signatures can't be mistaken for signatures of anything else
+const FILE_SIGNATURE_CONTEXT: &str = "nudge-ngx file signature v1\n";
+
+#[derive(Parser, Debug)]
+pub struct IdentityOpts {
+    /// Identity file to use instead of identity.json in the configuration directory
+    #[clap(long, env = "NUDGE_IDENTITY_FILE")]
+    identity_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    action: IdentityAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IdentityAction {
+    /// Create a new identity keypair
+    Create {
+        /// Encrypt the secret key with a passphrase (asked for, or taken from $NUDGE_IDENTITY_PASSPHRASE)
+        #[clap(long, default_value = "false")]
+        protect: bool,
+
+        /// Replace an existing identity. Peers pinning the old public key won't recognize you anymore
+        #[clap(long, default_value = "false")]
+        force: bool,
+    },
+    /// Show the public key and fingerprint of the identity
+    Show,
+    /// Print the public key for sharing with peers
+    Export {
+        /// Print the whole identity file (including the secret key) for moving it to another device
+        #[clap(long, default_value = "false")]
+        secret: bool,
+    },
+    /// Sign a file, printing the signature
+    Sign {
+        file: PathBuf,
+    },
+    /// Check the signature of a file made with `nudge identity sign`
+    Verify {
+        file: PathBuf,
+
+        /// The signature printed by `nudge identity sign`
+        #[clap(long)]
+        signature: String,
+
+        /// Public key of the signer (defaults to the own identity)
+        #[clap(long, value_parser = PublicKey::parse)]
+        key: Option<PublicKey>,
+    },
+}
+
+/// Run the `identity` command to manage the identity keypair.
+pub fn run(_: &RootOpts, identity_opts: &IdentityOpts) -> Result<()> {
+    let path = match &identity_opts.identity_file {
+        Some(path) => path.clone(),
+        None => IdentityFile::default_path().ok_or(NudgeError::IdentityMissing("the configuration directory (no home directory found)".to_string()))?,
+    };
+
+    match &identity_opts.action {
+        IdentityAction::Create { protect, force } => {
+            let passphrase = if *protect {
+                Some(match std::env::var(IDENTITY_PASSPHRASE_ENV) {
+                    Ok(passphrase) => passphrase,
+                    Err(_) => password("Passphrase for the identity", true)?,
+                })
+            } else {
+                None
+            };
+            let file = Identity::generate()?.seal(passphrase.as_deref(), current_unix_millis())?;
+            file.save(&path, *force)?;
+            status!("{} Created identity at {}", success_mark(), style(path.display()).cyan());
+            print_identity(&file);
+        }
+        IdentityAction::Show => {
+            let file = IdentityFile::load(&path)?;
+            status!("{} Identity at {}", style("[~]").bold().yellow(), style(path.display()).cyan());
+            print_identity(&file);
+        }
+        IdentityAction::Export { secret: false } => {
+            println!("{}", IdentityFile::load(&path)?.public_key());
+        }
+        IdentityAction::Export { secret: true } => {
+            let file = IdentityFile::load(&path)?;
+            if !file.is_protected() {
+                status!(
+                    "{} The secret key isn't protected by a passphrase, keep the export private",
+                    style("[!]").bold().yellow()
+                );
+            }
+            println!("{}", serde_json::to_string_pretty(&file)?);
+        }
+        IdentityAction::Sign { file } => {
+            let identity = IdentityFile::load(&path)?.unseal_interactive()?;
+            println!("{}", identity.sign(&file_signature_message(file)?));
+        }
+        IdentityAction::Verify { file, signature, key } => {
+            let key = match key {
+                Some(key) => *key,
+                None => IdentityFile::load(&path)?.public_key(),
+            };
+            if !key.verify(&file_signature_message(file)?, signature.trim())? {
+                return Err(NudgeError::InvalidIdentity(format!("the signature of {} wasn't made by {}", file.display(), key)));
+            }
+            status!(
+                "{} Valid signature by {} ({})",
+                success_mark(),
+                style(key.fingerprint()).cyan(),
+                style(key).dim()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the message signed for a file: the context and the BLAKE3 hash of the file.
+fn file_signature_message(path: &Path) -> Result<Vec<u8>> {
+    let hash = hash_file_and_seek(&mut File::open(path)?, MemoryProfile::DEFAULT.hash_buffer_size)?;
+    Ok(format!("{}{}", FILE_SIGNATURE_CONTEXT, hash).into_bytes())
+}
+
+fn print_identity(file: &IdentityFile) {
+    println!("{} Public key:  {}", style("[~]").bold().yellow(), style(file.public_key()).cyan());
+    println!("{} Fingerprint: {}", style("[~]").bold().yellow(), style(file.public_key().fingerprint()).cyan());
+    println!(
+        "{} Protected:   {}",
+        style("[~]").bold().yellow(),
+        if file.is_protected() { "yes (passphrase)" } else { "no" }
+    );
+    let created = OffsetDateTime::from_unix_timestamp_nanos(file.created_at() as i128 * 1_000_000)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    println!(
+        "{} Created:     {:04}-{:02}-{:02}",
+        style("[~]").bold().yellow(),
+        created.year(),
+        created.month() as u8,
+        created.day()
+    );
+}
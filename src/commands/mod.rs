@@ -1,10 +1,30 @@
-use clap::{Parser, Subcommand};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+
+use crate::utils::duration::parse_duration;
+use crate::utils::glob;
+use crate::utils::logging::LogTarget;
+use crate::utils::passphrase::{Passphrase, PassphraseLink};
+use crate::utils::resolver::DEFAULT_RELAY_RECHECK;
+use crate::utils::units::Units;
 use crate::utils::{DEFAULT_RELAY_HOST, DEFAULT_RELAY_PORT};
 
 pub mod send_command;
 pub mod get_command;
 pub mod server_command;
+pub mod doctor_command;
+pub mod exchange_command;
+pub mod pipe_command;
+pub mod forward_command;
+pub mod identity_command;
+pub mod contacts_command;
+pub mod protocol_schema_command;
+pub mod relay_stats_command;
+pub mod explain_command;
 
 #[derive(Parser, Debug)]
 #[clap(name = "nudge")]
@@ -16,16 +36,156 @@ pub struct RootOpts {
     #[clap(short = 'y', long, env = "NUDGE_RELAY_PORT", default_value = DEFAULT_RELAY_PORT)]
     pub(crate) relay_port: u16,
 
-    #[clap(short, long, default_value = "false")]
-    pub(crate) verbose: bool,
+    /// Token to register passphrases at relays which require one
+    #[clap(long, env = "NUDGE_RELAY_TOKEN", hide_env_values = true)]
+    pub(crate) relay_token: Option<String>,
+
+    /// Only send a hash of the passphrase to the relay, required by relays running `serve --blind`.
+    /// Senders generate the passphrase themselves. Both peers need to pass it
+    #[clap(long, env = "NUDGE_BLIND", default_value = "false")]
+    pub(crate) blind: bool,
+
+    /// Send and receive the data stream unencrypted if the peer can't encrypt it (e.g. built without the crypto
+    /// feature). Without it, nudge refuses with E305, since someone in between could strip the encryption
+    #[clap(long, env = "NUDGE_ALLOW_UNENCRYPTED", default_value = "false")]
+    pub(crate) allow_unencrypted: bool,
+
+    /// Resolve relay host names via DNS-over-HTTPS at this URL, e.g. https://1.1.1.1/dns-query (needs curl)
+    #[clap(long, value_name = "URL", env = "NUDGE_DOH")]
+    pub(crate) doh: Option<String>,
+
+    /// How long to wait for the relay to answer a request (sent again meanwhile), e.g. 30s or 2m.
+    /// Waiting for the peer to show up is bounded by the lifetime of the passphrase instead
+    #[clap(long, env = "NUDGE_RELAY_TIMEOUT", default_value = "30s", value_parser = parse_duration)]
+    pub(crate) relay_timeout: Duration,
+
+    /// Reach the relay, and the peer through it, over TCP on the relay port, for networks which block UDP
+    /// (e.g. guest Wi-Fi). Slower, needs a relay running `serve --tunnel`
+    #[clap(long, env = "NUDGE_TUNNEL", default_value = "false")]
+    pub(crate) tunnel: bool,
+
+    /// How often a sender waiting for receivers looks up the relay host name again, following the relay
+    /// to a new address (dynamic DNS), e.g. 1m. 0 turns it off
+    #[clap(long, env = "NUDGE_RELAY_RECHECK", default_value = DEFAULT_RELAY_RECHECK, value_parser = parse_duration)]
+    pub(crate) relay_recheck: Duration,
+
+    /// Write the datagrams exchanged with the peer to a pcap file, e.g. to analyze the transfer in Wireshark
+    #[clap(long, value_name = "FILE", env = "NUDGE_CAPTURE", global = true)]
+    pub(crate) capture: Option<PathBuf>,
+
+    /// Show debug output (-vv for trace output)
+    #[clap(short, long, action = ArgAction::Count, global = true)]
+    pub(crate) verbose: u8,
+
+    /// Show debug output (trace output with -vv) of these subsystems only, e.g. --debug transport,io
+    #[clap(long, value_enum, value_name = "TARGET", value_delimiter = ',', global = true, conflicts_with = "quiet")]
+    pub(crate) debug: Vec<LogTarget>,
+
+    /// Only print errors and results (passphrase, output path)
+    #[clap(short, long, default_value = "false", global = true, conflicts_with = "verbose")]
+    pub(crate) quiet: bool,
+
+    /// Show sizes in powers of 1000 (kB, MB) or 1024 (KiB, MiB)
+    #[clap(long, env = "NUDGE_UNITS", value_enum, default_value = "decimal", global = true)]
+    pub(crate) units: Units,
+
+    /// Reproducible behavior for golden-output tests: seeded passphrases and IDs, a clock starting at
+    /// 2024-01-01, no colors, spinners or progress bars. Never use it for real transfers
+    #[clap(long, env = "NUDGE_DETERMINISTIC", default_value = "false", global = true)]
+    pub(crate) deterministic: bool,
+
+    /// Print without colors and other styling, also selected by a non-empty NO_COLOR variable
+    #[clap(long, default_value = "false", global = true)]
+    pub(crate) no_color: bool,
+
+    /// Print ASCII markers, spinners and progress bars instead of emoji and other symbols
+    #[clap(long, env = "NUDGE_NO_EMOJI", default_value = "false", global = true)]
+    pub(crate) no_emoji: bool,
+
+    /// Cap buffer counts and sizes for memory constrained devices (routers, Raspberry Pis, ...)
+    #[clap(long, env = "NUDGE_LOW_MEMORY", default_value = "false")]
+    pub(crate) low_memory: bool,
 
     #[clap(subcommand)]
     pub(crate) subcmd: SubCommand,
 }
 
+impl RootOpts {
+    /// Parses the command line like `parse`, where a bare path instead of a command implies `send`
+    /// (`nudge report.pdf`) and a bare passphrase or link implies `get` (`nudge correct-horse-battery`).
+    /// Exits with a usage error if the argument is neither.
+    pub fn parse_with_implied_command() -> Self {
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        let opts = RootOpts::parse_from(&args);
+        let SubCommand::Implied(implied) = &opts.subcmd else {
+            return opts;
+        };
+        // the implied command takes all remaining arguments, starting with the path or passphrase
+        let position = args.len() - implied.len();
+        let Some(command) = implied_command(&implied[0]) else {
+            RootOpts::command()
+                .error(
+                    ErrorKind::InvalidSubcommand,
+                    format!(
+                        "unrecognized subcommand '{}', and it's neither a file to send nor a passphrase",
+                        implied[0].to_string_lossy()
+                    ),
+                )
+                .exit();
+        };
+        args.insert(position, OsString::from(command));
+        RootOpts::parse_from(args)
+    }
+}
+
+/// Returns the command a bare argument implies: `send` for an existing path or a glob pattern, `get` for anything
+/// shaped like a passphrase (a link, words joined by dashes, or as long as a chosen one), `None` for anything else,
+/// e.g. a typo.
+fn implied_command(arg: &OsStr) -> Option<&'static str> {
+    if Path::new(arg).exists() {
+        return Some("send");
+    }
+    let arg = arg.to_str()?;
+    // passphrases never contain wildcards, the pattern is expanded by `send`
+    if !PassphraseLink::is_link(arg) && glob::is_pattern(arg) {
+        return Some("send");
+    }
+    let is_passphrase = PassphraseLink::is_link(arg)
+        || arg.contains('-')
+        || arg.chars().count() >= Passphrase::MIN_CHOSEN_LEN;
+    is_passphrase.then_some("get")
+}
+
 #[derive(Subcommand, Debug)]
 pub enum SubCommand {
+    /// Run a relay which introduces senders and receivers
+    #[clap(visible_alias = "relay")]
     Serve(server_command::RelayServerOpts),
+    /// Send a file, also implied by passing just its path (`nudge <FILE>`)
+    #[clap(visible_alias = "put")]
     Send(send_command::SendOpts),
+    /// Receive a file, also implied by passing just the passphrase (`nudge <PASSPHRASE>`)
+    #[clap(visible_aliases = ["receive", "recv"])]
     Get(get_command::GetOpts),
+    /// Swap files with a peer running `nudge exchange` with the same passphrase
+    Exchange(exchange_command::ExchangeOpts),
+    /// Bridge stdin/stdout to a peer running `nudge pipe` with the same passphrase
+    Pipe(pipe_command::PipeOpts),
+    /// Forward a TCP port to a peer running `nudge forward` with the same passphrase
+    Forward(forward_command::ForwardOpts),
+    /// Print diagnostics about the environment nudge is running in
+    Doctor(doctor_command::DoctorOpts),
+    /// Manage the identity keypair peers recognize you by
+    Identity(identity_command::IdentityOpts),
+    /// Manage named peers to send to with `send --to`
+    Contacts(contacts_command::ContactsOpts),
+    /// Print the JSON Schemas of the messages exchanged with the relay and the peer
+    ProtocolSchema(protocol_schema_command::ProtocolSchemaOpts),
+    /// Print the statistics of the sessions of a relay, given one of its admin tokens
+    RelayStats(relay_stats_command::RelayStatsOpts),
+    /// Explain the causes and fixes of an error by the code printed below it
+    Explain(explain_command::ExplainOpts),
+    /// A path or passphrase instead of a command, see `RootOpts::parse_with_implied_command`
+    #[clap(external_subcommand)]
+    Implied(Vec<OsString>),
 }
\ No newline at end of file
@@ -0,0 +1,180 @@
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::commands::RootOpts;
+use crate::commands::send_command::{bind_socket, connect_to_relay_server, encrypt_pairing};
+use crate::error::Result;
+use crate::models::SessionKind;
+use crate::utils::capture::PacketCapture;
+use crate::utils::memory::MemoryProfile;
+use crate::utils::network_profile::MAX_CHUNK_SIZE;
+use crate::utils::peer_identity;
+use crate::utils::reliable_udp::ReliableUdpSocket;
+use crate::utils::rendezvous::{code_from_args, pair};
+use crate::utils::socket::init_socket;
+use crate::utils::ui::{style, success_mark};
+use crate::utils::DEFAULT_CHUNK_SIZE;
+
+/// How long to wait for data from the peer before checking stdin again
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum number of packets forwarded in one direction before switching to the other one
+const MAX_PACKETS_PER_ROUND: usize = 64;
+
+#[derive(Parser, Debug)]
+pub struct PipeOpts {
+    /// Passphrase shared with the peer.
+    /// The side starting the pipe can omit it to get a generated one
+    #[clap(env = "NUDGE_PASSPHRASE", hide_env_values = true)]
+    code: Option<String>,
+
+    /// Use the passphrase stored in this file
+    #[clap(long, conflicts_with = "code")]
+    code_from_file: Option<String>,
+
+    #[clap(short, long, default_value = "500")]
+    delay: u64,
+
+    /// Maximum number of bytes read from stdin per packet
+    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE, value_parser = clap::value_parser!(u32).range(1..=MAX_CHUNK_SIZE as i64))]
+    chunk_size: u32,
+
+    /// If enabled, sends the hostname to the peer (and the relay)
+    #[clap(long, default_value = "false")]
+    share_hostname: bool,
+
+    /// Name shown to the peer instead of the hostname, e.g. "Alice's laptop"
+    #[clap(long, env = "NUDGE_DISPLAY_NAME", conflicts_with = "share_hostname")]
+    display_name: Option<String>,
+}
+
+/// Run the `pipe` command to bridge stdin/stdout to a peer running the same command.
+///
+/// Everything read from stdin is sent to the peer, and everything the peer sends is written to stdout.
+/// Once stdin is closed, the peer's stdout is closed too; the pipe ends when both sides closed stdin.
+pub fn run(root_opts: &RootOpts, pipe_opts: &PipeOpts) -> Result<()> {
+    let memory_profile = MemoryProfile::select(root_opts.low_memory);
+    debug!("Memory profile: {:?}", memory_profile);
+    let capture = root_opts.capture.as_deref().map(PacketCapture::create).transpose()?;
+
+    let socket = bind_socket(None)?;
+    connect_to_relay_server(&socket, root_opts)?;
+
+    let identity = peer_identity(pipe_opts.share_hostname, pipe_opts.display_name.as_deref());
+    let code = code_from_args(pipe_opts.code.as_deref(), pipe_opts.code_from_file.as_deref())?;
+
+    let relay_token = root_opts.relay_token.as_deref();
+    let pairing = pair(&socket, code, &identity, SessionKind::Pipe, 0, relay_token, root_opts.blind)?;
+
+    debug!("Initializing socket connection...");
+    init_socket(&socket)?;
+
+    let connection = ReliableUdpSocket::new(socket)
+        .with_max_in_flight(memory_profile.max_in_flight)
+        .with_capture(capture);
+    let mut connection = encrypt_pairing(connection, &pairing, root_opts.allow_unencrypted, pipe_opts.delay)?;
+    status!(
+        "{} Connected to {}, bridging stdin/stdout",
+        success_mark(),
+        style(&pairing.peer_host).cyan()
+    );
+    let stdin_chunks = spawn_stdin_reader(pipe_opts.chunk_size as usize, memory_profile.max_in_flight);
+    bridge(&mut connection, &stdin_chunks, pipe_opts)?;
+
+    // Make sure the peer received everything before leaving
+    connection.end();
+    status!("{} Pipe closed", success_mark());
+    Ok(())
+}
+
+/// Reads stdin on a separate thread, since reading blocks.
+/// An empty chunk signals that stdin was closed.
+///
+/// # Arguments
+///
+/// * `chunk_size` - Maximum size of a chunk.
+/// * `capacity` - Number of chunks to buffer before reading pauses.
+fn spawn_stdin_reader(chunk_size: usize, capacity: usize) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buffer = vec![0; chunk_size];
+        loop {
+            let bytes_read = match stdin.read(&mut buffer) {
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Cannot read stdin: {}", e);
+                    0
+                }
+            };
+            if sender.send(buffer[..bytes_read].to_vec()).is_err() || bytes_read == 0 {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Forwards stdin to the peer and the peer's data to stdout, until both sides are done.
+/// An empty packet tells the peer that our stdin was closed.
+fn bridge(
+    connection: &mut ReliableUdpSocket,
+    stdin_chunks: &Receiver<Vec<u8>>,
+    pipe_opts: &PipeOpts,
+) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    let buffer: Vec<u8> = vec![0; pipe_opts.chunk_size as usize];
+
+    let mut stdin_open = true;
+    let mut peer_open = true;
+
+    while stdin_open || peer_open {
+        if !peer_open {
+            // nothing to receive anymore, so wait for stdin instead of polling the socket
+            let chunk = stdin_chunks.recv().unwrap_or_default();
+            stdin_open = !chunk.is_empty();
+            connection.write_and_flush(&chunk, !stdin_open, pipe_opts.delay)?;
+            continue;
+        }
+
+        // forward what stdin produced in the meantime
+        let mut forwarded = 0;
+        while stdin_open && forwarded < MAX_PACKETS_PER_ROUND {
+            let chunk = match stdin_chunks.try_recv() {
+                Ok(chunk) => chunk,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => Vec::new(),
+            };
+            if chunk.is_empty() {
+                debug!("stdin closed, telling the peer");
+                stdin_open = false;
+            }
+            connection.write_and_flush(&chunk, !stdin_open, pipe_opts.delay)?;
+            forwarded += 1;
+        }
+
+        // receive what the peer sent, without waiting long if stdin has more to send
+        let mut timeout = if forwarded > 0 { Duration::ZERO } else { POLL_INTERVAL };
+        let mut received = 0;
+        while received < MAX_PACKETS_PER_ROUND {
+            let Some((data, bytes_read)) = connection.try_read(&buffer, timeout)? else {
+                break;
+            };
+            if bytes_read == 0 {
+                debug!("Peer closed its stdin");
+                peer_open = false;
+                break;
+            }
+            stdout.write_all(&data[..bytes_read])?;
+            timeout = Duration::ZERO;
+            received += 1;
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
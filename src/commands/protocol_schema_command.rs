@@ -0,0 +1,342 @@
+use clap::Parser;
+use serde_json::{json, Value};
+
+use crate::commands::RootOpts;
+use crate::error::{NudgeError, Result};
+use crate::models::*;
+use crate::utils::schema::schema_of;
+
+/// Dialect of the emitted schemas
+const JSON_SCHEMA_DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+#[derive(Parser, Debug)]
+pub struct ProtocolSchemaOpts {
+    /// Only print the schema of this message, e.g. S2X_RP or SparseMapMessage
+    message: Option<String>,
+
+    /// List the messages with their direction and exit
+    #[clap(long, default_value = "false", conflicts_with = "message")]
+    list: bool,
+}
+
+/// A message of the wire protocol
+struct WireMessage {
+    /// Name of the model
+    name: &'static str,
+
+    /// Prefix the message is sent to or by the relay with, as `<PREFIX> <JSON>\n`.
+    /// Messages without one are sent between the peers as plain JSON
+    prefix: Option<&'static str>,
+
+    from: &'static str,
+    to: &'static str,
+    description: &'static str,
+    schema: fn() -> Result<Value>,
+}
+
+const WIRE_MESSAGES: [WireMessage; 32] = [
+    WireMessage {
+        name: "S2XRequestPassphraseMessage",
+        prefix: Some("S2X_RP"),
+        from: "sender",
+        to: "relay",
+        description: "Registers a session and asks for its passphrase",
+        schema: schema_of::<S2XRequestPassphraseMessage>,
+    },
+    WireMessage {
+        name: "X2SPassphraseProvidedMessage",
+        prefix: Some("X2S_PPM"),
+        from: "relay",
+        to: "sender",
+        description: "The passphrase of the registered session",
+        schema: schema_of::<X2SPassphraseProvidedMessage>,
+    },
+    WireMessage {
+        name: "RelayBusyMessage",
+        prefix: Some("BUSY"),
+        from: "relay",
+        to: "sender",
+        description: "The relay is saturated, the sender should register again later",
+        schema: schema_of::<RelayBusyMessage>,
+    },
+    WireMessage {
+        name: "S2XRequestTicketMessage",
+        prefix: Some("S2X_RT"),
+        from: "sender",
+        to: "relay",
+        description: "Asks the relay to sign a ticket for the session",
+        schema: schema_of::<S2XRequestTicketMessage>,
+    },
+    WireMessage {
+        name: "X2STicketMessage",
+        prefix: Some("X2S_TKT"),
+        from: "relay",
+        to: "sender",
+        description: "The signed session ticket",
+        schema: schema_of::<X2STicketMessage>,
+    },
+    WireMessage {
+        name: "R2XRequestFileInfoMessage",
+        prefix: Some("R2X_RFI"),
+        from: "receiver",
+        to: "relay",
+        description: "Asks for the session of a passphrase",
+        schema: schema_of::<R2XRequestFileInfoMessage>,
+    },
+    WireMessage {
+        name: "FileInfo",
+        prefix: Some("X2R_AFI"),
+        from: "relay",
+        to: "receiver",
+        description: "The session of the passphrase",
+        schema: schema_of::<FileInfo>,
+    },
+    WireMessage {
+        name: "R2XRequestSenderConnectionMessage",
+        prefix: Some("R2X_RSC"),
+        from: "receiver",
+        to: "relay",
+        description: "Accepts the session, so the relay introduces the peers",
+        schema: schema_of::<R2XRequestSenderConnectionMessage>,
+    },
+    WireMessage {
+        name: "X2RSenderConnectionAcceptedMessage",
+        prefix: Some("X2R_ASC"),
+        from: "relay",
+        to: "receiver",
+        description: "The relay told the sender to connect",
+        schema: schema_of::<X2RSenderConnectionAcceptedMessage>,
+    },
+    WireMessage {
+        name: "R2XWakeSenderMessage",
+        prefix: Some("R2X_WAKE"),
+        from: "receiver",
+        to: "relay",
+        description: "Asks the relay to wake the sender of an offer (send --offer-only)",
+        schema: schema_of::<R2XWakeSenderMessage>,
+    },
+    WireMessage {
+        name: "X2RSenderOnlineMessage",
+        prefix: Some("X2R_ONL"),
+        from: "relay",
+        to: "receiver",
+        description: "The sender of the offer is online and can be connected to",
+        schema: schema_of::<X2RSenderOnlineMessage>,
+    },
+    WireMessage {
+        name: "X2SSenderConnectToReceiverMessage",
+        prefix: Some("X2S_SCON"),
+        from: "relay",
+        to: "sender",
+        description: "Tells the sender which receiver to connect to",
+        schema: schema_of::<X2SSenderConnectToReceiverMessage>,
+    },
+    WireMessage {
+        name: "S2XHeartbeatMessage",
+        prefix: Some("S2X_HB"),
+        from: "sender",
+        to: "relay",
+        description: "Tells the relay the sender still waits, to notice a relay which stopped responding",
+        schema: schema_of::<S2XHeartbeatMessage>,
+    },
+    WireMessage {
+        name: "X2SHeartbeatMessage",
+        prefix: Some("X2S_HB"),
+        from: "relay",
+        to: "sender",
+        description: "Confirms the sender that the relay still holds its session, or wakes the sender of an offer",
+        schema: schema_of::<X2SHeartbeatMessage>,
+    },
+    WireMessage {
+        name: "S2XReadyMessage",
+        prefix: Some("S2X_RDY"),
+        from: "sender",
+        to: "relay",
+        description: "Reopens a session kept open with --stay-open for the next receiver, or opens an offer",
+        schema: schema_of::<S2XReadyMessage>,
+    },
+    WireMessage {
+        name: "X2SReadyMessage",
+        prefix: Some("X2S_RDY"),
+        from: "relay",
+        to: "sender",
+        description: "Confirms the sender that the session is open for the next receiver",
+        schema: schema_of::<X2SReadyMessage>,
+    },
+    WireMessage {
+        name: "A2XRequestStatisticsMessage",
+        prefix: Some("A2X_STATS"),
+        from: "admin",
+        to: "relay",
+        description: "Asks the relay for the statistics of its sessions with an admin token",
+        schema: schema_of::<A2XRequestStatisticsMessage>,
+    },
+    WireMessage {
+        name: "X2AStatisticsMessage",
+        prefix: Some("X2A_STATS"),
+        from: "relay",
+        to: "admin",
+        description: "Statistics of the sessions of the relay, never naming their passphrases",
+        schema: schema_of::<X2AStatisticsMessage>,
+    },
+    WireMessage {
+        name: "EncryptionMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Whether the data stream is encrypted from the next packet on, sent first if the receiver asks for it",
+        schema: schema_of::<EncryptionMessage>,
+    },
+    WireMessage {
+        name: "KeyExchangeMessage",
+        prefix: None,
+        from: "peer",
+        to: "peer",
+        description: "Element of the key exchange authenticated by the passphrase, keying the encrypted data stream",
+        schema: schema_of::<KeyExchangeMessage>,
+    },
+    WireMessage {
+        name: "KeyConfirmationMessage",
+        prefix: None,
+        from: "peer",
+        to: "peer",
+        description: "Proves having derived the same key in the key exchange, i.e. knowing the passphrase",
+        schema: schema_of::<KeyConfirmationMessage>,
+    },
+    WireMessage {
+        name: "IdentityChallengeMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Asks the receiver to prove owning the identity it claimed",
+        schema: schema_of::<IdentityChallengeMessage>,
+    },
+    WireMessage {
+        name: "IdentityProofMessage",
+        prefix: None,
+        from: "receiver",
+        to: "sender",
+        description: "Answers the identity challenge",
+        schema: schema_of::<IdentityProofMessage>,
+    },
+    WireMessage {
+        name: "PreviewRequestMessage",
+        prefix: None,
+        from: "receiver",
+        to: "sender",
+        description: "Asks for the beginning of the file, answered with the raw bytes",
+        schema: schema_of::<PreviewRequestMessage>,
+    },
+    WireMessage {
+        name: "PreviewDecisionMessage",
+        prefix: None,
+        from: "receiver",
+        to: "sender",
+        description: "Whether the receiver wants the file after the preview",
+        schema: schema_of::<PreviewDecisionMessage>,
+    },
+    WireMessage {
+        name: "ExtendedAttributesMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Extended attributes of the file",
+        schema: schema_of::<ExtendedAttributesMessage>,
+    },
+    WireMessage {
+        name: "SparseMapMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Regions of a sparse file containing data, sent before them",
+        schema: schema_of::<SparseMapMessage>,
+    },
+    WireMessage {
+        name: "FileManifestMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Files shared at once with their size and hash, sent before their contents",
+        schema: schema_of::<FileManifestMessage>,
+    },
+    WireMessage {
+        name: "CompressionMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Whether and how the data stream is compressed, sent before it if the receiver asks for compression or takes zstd",
+        schema: schema_of::<CompressionMessage>,
+    },
+    WireMessage {
+        name: "ResumeMessage",
+        prefix: None,
+        from: "sender",
+        to: "receiver",
+        description: "Where the data stream starts, sent before it if the receiver asks to resume a download",
+        schema: schema_of::<ResumeMessage>,
+    },
+    WireMessage {
+        name: "ExchangeOfferMessage",
+        prefix: None,
+        from: "peer",
+        to: "peer",
+        description: "Files offered in a `nudge exchange` session",
+        schema: schema_of::<ExchangeOfferMessage>,
+    },
+    WireMessage {
+        name: "ExchangeAcceptMessage",
+        prefix: None,
+        from: "peer",
+        to: "peer",
+        description: "Files wanted in a `nudge exchange` session",
+        schema: schema_of::<ExchangeAcceptMessage>,
+    },
+];
+
+/// Run the `protocol-schema` command to print the JSON Schemas of the wire messages.
+pub fn run(_: &RootOpts, protocol_schema_opts: &ProtocolSchemaOpts) -> Result<()> {
+    if protocol_schema_opts.list {
+        for message in &WIRE_MESSAGES {
+            println!(
+                "{:<36} {:<9} {:>8} -> {:<8} {}",
+                message.name, message.prefix.unwrap_or("-"), message.from, message.to, message.description
+            );
+        }
+        return Ok(());
+    }
+
+    let document = match &protocol_schema_opts.message {
+        Some(name) => {
+            let message = WIRE_MESSAGES.iter()
+                .find(|message| message.name == name || message.prefix == Some(name.as_str()))
+                .ok_or_else(|| NudgeError::UnknownMessage(name.clone()))?;
+            let mut schema = (message.schema)()?;
+            schema["$schema"] = json!(JSON_SCHEMA_DIALECT);
+            schema["description"] = json!(message.description);
+            schema
+        }
+        None => json!({
+            "$schema": JSON_SCHEMA_DIALECT,
+            "title": "nudge wire protocol",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Messages to and from the relay are sent as `<PREFIX> <JSON>\\n` in a UDP datagram, \
+                errors of the relay as `ERROR <text>\\n`. Messages between the peers are sent as JSON \
+                over their reliable UDP connection",
+            "messages": WIRE_MESSAGES.iter().map(describe).collect::<Result<Vec<_>>>()?,
+        }),
+    };
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
+
+/// Describes a message with its schema.
+fn describe(message: &WireMessage) -> Result<Value> {
+    Ok(json!({
+        "name": message.name,
+        "prefix": message.prefix,
+        "from": message.from,
+        "to": message.to,
+        "description": message.description,
+        "schema": (message.schema)()?,
+    }))
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+
+use crate::commands::send_command::{bind_socket, connect_to_relay_server};
+use crate::commands::RootOpts;
+use crate::error::Result;
+use crate::models::{A2XRequestStatisticsMessage, X2AStatisticsMessage};
+use crate::utils::duration::format_duration;
+use crate::utils::serialize::request;
+use crate::utils::ui::style;
+
+#[derive(Parser, Debug)]
+pub struct RelayStatsOpts {
+    /// One of the `admin_tokens` in the config of the relay
+    #[clap(long, env = "NUDGE_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: String,
+
+    /// How to print the statistics
+    #[clap(long, value_enum, default_value = "text")]
+    format: StatsFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// Lines for humans
+    Text,
+    /// The message of the relay as is
+    Json,
+    /// Prometheus text exposition format, e.g. for the textfile collector of the node exporter
+    Prometheus,
+}
+
+/// Run the `relay-stats` command to print the statistics of the sessions of the relay.
+pub fn run(root_opts: &RootOpts, relay_stats_opts: &RelayStatsOpts) -> Result<()> {
+    let socket = bind_socket(None)?;
+    connect_to_relay_server(&socket, root_opts)?;
+    let statistics: X2AStatisticsMessage = request(&socket, "A2X_STATS", &A2XRequestStatisticsMessage {
+        admin_token: relay_stats_opts.admin_token.clone(),
+    }, "X2A_STATS")?;
+
+    match relay_stats_opts.format {
+        StatsFormat::Text => print_text(&statistics),
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&statistics)?),
+        StatsFormat::Prometheus => print!("{}", prometheus(&statistics)),
+    }
+    Ok(())
+}
+
+/// Prints the statistics as lines for humans.
+fn print_text(statistics: &X2AStatisticsMessage) {
+    let waits = |millis: u64| format_duration(Duration::from_millis(millis));
+    println!("{} Uptime: {}", style("[~]").bold().yellow(), format_duration(Duration::from_secs(statistics.uptime_secs)));
+    println!(
+        "{} Sessions: {} registered, {} claimed, {} expired unclaimed, {} lookups",
+        style("[~]").bold().yellow(),
+        statistics.registered, statistics.matched, statistics.expired, statistics.lookups
+    );
+    println!(
+        "{} Active: {} ({} waiting for a receiver{})",
+        style("[~]").bold().yellow(),
+        statistics.active,
+        statistics.waiting,
+        statistics.oldest_waiting_secs
+            .map(|secs| format!(", the oldest for {}", format_duration(Duration::from_secs(secs))))
+            .unwrap_or_default()
+    );
+    if let Some(wait) = statistics.wait_millis {
+        println!(
+            "{} Wait until claimed: {} median, {} p90, {} max",
+            style("[~]").bold().yellow(),
+            waits(wait.p50), waits(wait.p90), waits(wait.max)
+        );
+    }
+}
+
+/// Returns the statistics in the Prometheus text exposition format.
+fn prometheus(statistics: &X2AStatisticsMessage) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+        out.push_str(&format!("# HELP nudge_relay_{} {}\n# TYPE nudge_relay_{} {}\n", name, help, name, kind));
+        for (labels, value) in samples {
+            out.push_str(&format!("nudge_relay_{}{} {}\n", name, labels, value));
+        }
+    };
+    metric("uptime_seconds", "gauge", "Seconds since the relay started", &[("", statistics.uptime_secs as f64)]);
+    metric("sessions_registered_total", "counter", "Sessions registered", &[("", statistics.registered as f64)]);
+    metric("sessions_matched_total", "counter", "Sessions claimed by a receiver", &[("", statistics.matched as f64)]);
+    metric("sessions_expired_total", "counter", "Sessions expired unclaimed", &[("", statistics.expired as f64)]);
+    metric("lookups_total", "counter", "Lookups of registered passphrases", &[("", statistics.lookups as f64)]);
+    metric("sessions_active", "gauge", "Sessions registered right now", &[("", statistics.active as f64)]);
+    metric("sessions_waiting", "gauge", "Sessions waiting for a receiver", &[("", statistics.waiting as f64)]);
+    if let Some(secs) = statistics.oldest_waiting_secs {
+        metric("oldest_waiting_seconds", "gauge", "Seconds the oldest session waits", &[("", secs as f64)]);
+    }
+    if let Some(wait) = statistics.wait_millis {
+        metric("claim_wait_seconds", "gauge", "Seconds recently claimed sessions waited for their receiver", &[
+            ("{quantile=\"0.5\"}", wait.p50 as f64 / 1000.0),
+            ("{quantile=\"0.9\"}", wait.p90 as f64 / 1000.0),
+            ("{quantile=\"1\"}", wait.max as f64 / 1000.0),
+        ]);
+    }
+    out
+}
@@ -1,112 +1,1938 @@
-use std::fs::File;
-use std::io::{Read, Seek};
-use std::net::{Ipv4Addr, UdpSocket};
+use std::fs::{File, Metadata};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use console::style;
-use humansize::{DECIMAL, format_size};
+use rand::{thread_rng, Rng};
+use time::{OffsetDateTime, UtcOffset};
 
 use crate::commands::RootOpts;
-use crate::error::Result;
-use crate::models::X2SPassphraseProvidedMessage;
-use crate::models::S2XRequestPassphraseMessage;
-use crate::models::X2SSenderConnectToReceiverMessage;
-use crate::utils::reliable_udp::ReliableUdpSocket;
+use crate::commands::exchange_command::{read_message, write_message};
+use crate::commands::get_command;
+use crate::error::{NudgeError, Result};
+use crate::models::{CompressionMessage, Extent, ExtendedAttributesMessage, PreviewDecisionMessage, PreviewRequestMessage, SparseMapMessage};
+use crate::models::{EncryptionMessage, IdentityChallengeMessage, IdentityProofMessage, KeyConfirmationMessage, KeyExchangeMessage};
+use crate::models::{S2XRequestTicketMessage, X2SPassphraseProvidedMessage, X2SSenderConnectToReceiverMessage, X2STicketMessage};
+use crate::models::{ResumeMessage, S2XRequestPassphraseMessage};
+use crate::models::{S2XReadyMessage, X2SReadyMessage};
+use crate::models::{MatchPolicy, SessionKind};
+use crate::utils::bandwidth::{BandwidthShare, PeerPacer, PeerTransfer, TransferTotals};
+use crate::utils::batch::read_send_manifest;
+use crate::utils::blind::{generate_passphrase, relay_passphrase};
+use crate::utils::bundle::FileBundle;
+use crate::utils::capture::PacketCapture;
+use crate::utils::reliable_udp::{ReliableUdpSocket, DEFAULT_PEER_TIMEOUT};
+use crate::utils::sender_session::SenderSession;
 use crate::utils::AnonymousString;
 use crate::utils::current_unix_millis;
-use crate::utils::hash_file_and_seek;
-use crate::utils::hide_or_get_hostname;
-use crate::utils::new_downloader_progressbar;
-use crate::utils::DEFAULT_CHUNK_SIZE;
-use crate::utils::serialize::{receive_and_parse_and_expect, serialize_and_send};
-use crate::utils::socket::init_socket;
-
-#[derive(Parser, Debug)]
+use crate::utils::duration::{format_duration, parse_duration};
+use crate::utils::glob;
+use crate::utils::hash_cache::{FileIdentity, HashCache};
+use crate::utils::{decode_hex, encode_hex, hash_file_and_seek, new_registration_id, read_with_retry, HASH_ALGORITHM};
+use crate::utils::contacts::{Contact, ContactBook};
+use crate::utils::identity::{identity_proof_message, PublicKey};
+use crate::utils::interface::{list_interfaces, print_interfaces, resolve_bind_address};
+use crate::utils::memory::MemoryProfile;
+use crate::utils::network_profile::{negotiate_chunk_size, NetworkProfile, TransferTuning, MAX_CHUNK_SIZE, UNFRAGMENTED_CHUNK_SIZE};
+use crate::utils::port_mapping::{mapped_addr, PortMapping};
+use crate::utils::passphrase::{Passphrase, PassphraseLink};
+use crate::utils::peer_identity;
+use crate::utils::platform::{background_toggles, device_kind, file_mode, notify_desktop, set_io_priority, stdin_file, watch_background_signal, DeviceKind};
+use crate::utils::profiling::{self, span, Stage};
+use crate::utils::preview::{looks_like_text, MAX_PREVIEW_BYTES, PREVIEW_MAX_FILE_SIZE};
+use crate::utils::compression::{self, Algorithm};
+use crate::utils::encryption::{self, FrameCipher, KeyExchange, Role};
+use crate::utils::rendezvous::{wait_for_connection_request, wait_for_offer_request, watch_for_connection_request, Pairing};
+use crate::utils::resolver::{resolve_address, resolver, RelayBinding};
+use crate::utils::sandbox;
+use crate::utils::schedule::{parse_time_of_day, parse_window, Schedule, TimeOfDay, Window};
+use crate::utils::ui::{complete_message, failure_mark, is_quiet, new_downloader_progressbar, new_stream_progressbar, ring_bell, style, success_mark, Phase, PhaseProgress, SENDER_PHASES};
+use crate::utils::serialize::{relay_timeout, request};
+use crate::utils::summary::format_phase_durations;
+use crate::utils::socket::{connect_to_peer, init_socket};
+use crate::utils::sparse::{data_extents, data_size, SparseReader};
+use crate::utils::units::{format_bytes, parse_size};
+use crate::utils::tar::DirectoryArchive;
+use crate::utils::tunnel;
+use crate::utils::xattr::{read_attributes, unsupported_reason};
+
+/// Most passphrases registered for a file with `--copies`, each one takes a socket and a thread
+const MAX_COPIES: u32 = 64;
+
+/// Shortest registration ID chosen with `--registration-id`, as long as a random one in hex
+const MIN_REGISTRATION_ID_LEN: usize = 16;
+
+/// Pause before retrying a failed read of the file, doubled for every further retry
+const READ_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Path standing for stdin (`send -`), streamed with an unknown size
+const STDIN_PATH: &str = "-";
+
+/// Name receivers save the data streamed from stdin as
+const STDIN_FILE_NAME: &str = "stdin";
+
+/// How often the connection to the receiver is kept alive while the schedule holds the data back,
+/// well below the time NATs keep the mapping of an idle UDP flow
+const SCHEDULE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Parser, Debug, Clone)]
 pub struct SendOpts {
-    file: String,
+    /// The file (or directory) to send, `-` streams stdin
+    #[clap(required_unless_present_any = ["list_interfaces", "from_manifest"])]
+    file: Option<String>,
 
-    #[clap(short, long, default_value = "500")]
-    delay: u64,
+    /// More files, shared together with the first one and stored by the receiver in one directory
+    #[clap(value_name = "FILE")]
+    more_files: Vec<String>,
 
-    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE)]
-    chunk_size: u32,
+    /// Delay between two packets in microseconds [default: 500]
+    #[clap(short, long)]
+    delay: Option<u64>,
+
+    /// Size of the data in a packet in bytes, never lowered for receivers taking smaller chunks [default: 4096]
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..=MAX_CHUNK_SIZE as i64))]
+    chunk_size: Option<u32>,
 
-    /// If enabled, won't send the hostname to the receiver
+    /// Use chunk size, delay and window suited for the network, explicit --chunk-size and --delay take precedence
+    #[clap(long, value_enum)]
+    profile: Option<NetworkProfile>,
+
+    /// Hand every packet to the kernel with its departure (SO_TXTIME, Linux), for qdiscs pacing by it (fq, etf)
     #[clap(long, default_value = "false")]
+    txtime: bool,
+
+    /// If enabled, sends the hostname to the receiver (and the relay)
+    #[clap(long, default_value = "false")]
+    share_hostname: bool,
+
+    /// Name shown to the receiver instead of the hostname, e.g. "Alice's laptop"
+    #[clap(long, env = "NUDGE_DISPLAY_NAME", conflicts_with = "share_hostname")]
+    display_name: Option<String>,
+
+    /// Deprecated, the hostname is hidden unless --share-hostname is given
+    #[clap(long, hide = true, alias = "no-hostname", default_value = "false", conflicts_with = "share_hostname")]
     hide_hostname: bool,
 
     /// If enabled, won't create a hash of the file
     #[clap(long, default_value = "false")]
     skip_hash: bool,
+
+    /// Compress the data stream with zstd, for compressible files (text, logs, uncompressed archives) on slow links.
+    /// Receivers which can't decompress zstd get the file uncompressed
+    #[clap(long, default_value = "false")]
+    compress: bool,
+
+    /// Remember the hash of the file, so sending it again unchanged doesn't need to hash it
+    #[clap(long, default_value = "false", conflicts_with = "skip_hash")]
+    hash_cache: bool,
+
+    /// Use the passphrase stored in this file instead of a generated one
+    #[clap(long)]
+    code_from_file: Option<String>,
+
+    /// How often a failed read of the file is retried before the transfer is aborted
+    #[clap(long, default_value = "5")]
+    read_retries: u32,
+
+    /// Let the passphrase expire after this time if no receiver connected, e.g. 15m.
+    /// Can only shorten the lifetime configured on the relay
+    #[clap(long, value_parser = parse_duration)]
+    expire: Option<Duration>,
+
+    /// Give up if the receiver sent nothing within this time after connecting, e.g. because the NATs block
+    /// the direct connection. Waiting for a receiver to claim the passphrase is bounded by --expire instead
+    #[clap(long, default_value = DEFAULT_PEER_TIMEOUT, value_parser = parse_duration)]
+    peer_timeout: Duration,
+
+    /// Bind to this IP address or interface (e.g. eth0, wg0) instead of relying on the default route
+    #[clap(long)]
+    bind: Option<String>,
+
+    /// List the network interfaces usable with --bind and exit
+    #[clap(long, default_value = "false")]
+    list_interfaces: bool,
+
+    /// Ask the router to forward a port via NAT-PMP or UPnP, so the receiver can reach us directly
+    #[clap(long, default_value = "false")]
+    port_mapping: bool,
+
+    /// Share the extended attributes of the file (user namespace) with receivers asking for them
+    #[clap(long, default_value = "false", conflicts_with = "allow_device")]
+    xattrs: bool,
+
+    /// Allow sending the contents of a block device (e.g. /dev/sdb) or character device (e.g. /dev/ttyUSB0)
+    #[clap(long, default_value = "false")]
+    allow_device: bool,
+
+    /// Number of bytes sent from a device, e.g. 32GiB. Required for character devices, which don't tell their size,
+    /// block devices are sent up to their capacity without it
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, requires = "allow_device")]
+    size_hint: Option<u64>,
+
+    /// Ring the terminal bell and show a desktop notification when a receiver claims the passphrase
+    #[clap(long, default_value = "false")]
+    alert: bool,
+
+    /// Let the relay announce the file and the passphrase to its webhook, e.g. in a team chat
+    #[clap(long, default_value = "false")]
+    announce: bool,
+
+    /// Also print a link (nudge://<relay>/<passphrase>), which receivers can pass to `get` without matching relay flags
+    #[clap(long, default_value = "false")]
+    link: bool,
+
+    /// Write a session ticket signed by the relay to this file, receivers pick up the file with `get --ticket-file`
+    #[clap(long)]
+    ticket_file: Option<PathBuf>,
+
+    /// Once connected to the receiver, restrict the process (Linux only): no network access except to the receiver,
+    /// and no file access except reading the file being sent (Landlock, Linux 5.13+)
+    #[clap(long, default_value = "false", conflicts_with_all = ["port_mapping", "txtime"])]
+    sandbox: bool,
+
+    /// Send to a contact (see `nudge contacts`): uses its relay, profile and display name,
+    /// and only accepts a receiver proving to own its identity (`get --identify`)
+    #[clap(long, value_name = "CONTACT")]
+    to: Option<String>,
+
+    /// Register this many passphrases for the file, each can be used by one receiver.
+    /// The copies are sent at the same time, to whoever claims them first
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=MAX_COPIES as i64),
+        conflicts_with_all = ["code_from_file", "ticket_file", "sandbox", "to"])]
+    copies: u32,
+
+    /// With --copies, wait until all receivers connected and send them the file together,
+    /// reading every chunk once and fanning it out to all of them
+    #[clap(long, default_value = "false", requires = "copies")]
+    group: bool,
+
+    /// Register at this relay instead if the relay stops responding while waiting for the receiver, as "host:port".
+    /// Can be repeated, the relays are tried in order. The passphrase is kept unless the backup relay has it in use
+    #[clap(long = "backup-relay", value_name = "HOST:PORT", conflicts_with_all = ["copies", "ticket_file"])]
+    backup_relays: Vec<String>,
+
+    /// Keep the passphrase registered after a receiver got the file, and serve the next receivers one at a time
+    /// until the passphrase expires (see --expire) or nudge is quit
+    #[clap(long, default_value = "false", conflicts_with_all = ["copies", "sandbox", "size_hint"])]
+    stay_open: bool,
+
+    /// Only offer the file: the relay keeps the passphrase without the receiver connecting right away. Once a receiver
+    /// asks for the file, a desktop notification tells and the file is sent as soon as the receiver tries again
+    #[clap(long, default_value = "false", conflicts_with_all = ["copies", "backup_relays"])]
+    offer_only: bool,
+
+    /// Limit the bandwidth to this many bytes per second, e.g. 10MB. Receivers served at the same time
+    /// (--copies) share it equally, so a single receiver can't take up all of it
+    #[clap(long, value_name = "RATE", value_parser = parse_size)]
+    max_rate: Option<u64>,
+
+    /// Send in the background: read the file with idle I/O priority and limit the bandwidth to --background-rate,
+    /// so long transfers don't slow down the machine. SIGUSR1 (`kill -USR1 <pid>`) toggles it while sending
+    #[clap(long, default_value = "false")]
+    background: bool,
+
+    /// Bandwidth limit of the background mode per second, --max-rate applies if it's lower
+    #[clap(long, value_name = "RATE", value_parser = parse_size, default_value = "2MB")]
+    background_rate: u64,
+
+    /// Send the files listed in this file, one path per line, optionally followed by a tab and a label.
+    /// Relative paths are relative to the file
+    #[clap(long, value_name = "PATH", conflicts_with = "file")]
+    from_manifest: Option<PathBuf>,
+
+    /// Register every file of --from-manifest with a passphrase of its own, instead of one for all files
+    #[clap(long, default_value = "false", requires = "from_manifest", conflicts_with_all = ["code_from_file", "ticket_file"])]
+    separate: bool,
+
+    /// Register with this ID (at least 16 characters, kept secret) instead of a random one. Restarted with the same ID,
+    /// nudge takes over the session at the relay, including a receiver which accepted it in the meantime
+    #[clap(long, value_name = "ID", value_parser = parse_registration_id, conflicts_with_all = ["copies", "separate"])]
+    registration_id: Option<String>,
+
+    /// Record the session under this name: restarted with the same name, nudge takes over the session at the relay
+    /// like with --registration-id, reuses the hash of the unchanged file and tells how much the receiver acknowledged
+    #[clap(long, value_name = "NAME", conflicts_with_all = ["copies", "separate", "registration_id", "stay_open", "sandbox"])]
+    resume_session: Option<String>,
+
+    /// Start sending at this time of day (local time, e.g. 02:00), the receiver connects before and waits
+    #[clap(long, value_name = "HH:MM", value_parser = parse_time_of_day, conflicts_with = "group")]
+    start_at: Option<TimeOfDay>,
+
+    /// Only send within these hours of the day (local time, e.g. 01:00-06:00 or 22:00-06:00), pausing outside of them
+    #[clap(long, value_name = "HH:MM-HH:MM", value_parser = parse_window, conflicts_with = "group")]
+    window: Option<Window>,
+}
+
+/// Checks a registration ID chosen with `--registration-id`. Whoever knows it can take over the session,
+/// so it has to be as hard to guess as a random one.
+fn parse_registration_id(id: &str) -> std::result::Result<String, String> {
+    match id.chars().count() >= MIN_REGISTRATION_ID_LEN {
+        true => Ok(id.to_string()),
+        false => Err(format!("must be at least {} characters long", MIN_REGISTRATION_ID_LEN)),
+    }
+}
+
+/// Checks the options of streaming stdin (`send -`), which can only be read once.
+///
+/// # Errors
+///
+/// Returns `NudgeError::StdinNotRepeatable` if stdin would be sent more than once, or with other files
+fn check_streamable(send_opts: &SendOpts) -> Result<()> {
+    let repeating = [
+        (send_opts.copies > 1, "--copies"),
+        (send_opts.stay_open, "--stay-open"),
+        (send_opts.resume_session.is_some(), "--resume-session"),
+        (!send_opts.more_files.is_empty(), "other files"),
+    ];
+    match repeating.into_iter().find(|(repeats, _)| *repeats) {
+        Some((_, option)) => Err(NudgeError::StdinNotRepeatable(option)),
+        None => Ok(()),
+    }
+}
+
+/// The files of a session started by `--from-manifest`
+#[derive(Debug, Default)]
+struct ListedSession {
+    /// Labels of the files, in the order of the paths (empty without a manifest file)
+    labels: Vec<Option<String>>,
+
+    /// Whether the session is one of several sent at the same time (`--separate`), named after its file
+    separate: bool,
 }
 
 pub fn run(root_opts: &RootOpts, send_opts: &SendOpts) -> Result<()> {
+    if send_opts.list_interfaces {
+        return print_interfaces();
+    }
+    if send_opts.hide_hostname {
+        status!(
+            "{} --hide-hostname is deprecated, the hostname is hidden unless --share-hostname is given",
+            style("[~]").bold().yellow()
+        );
+    }
+    let schedule = local_schedule(send_opts);
+    watch_background_signal();
+    if let Some(schedule) = schedule {
+        status!("{} Sending {} (local time)", style("[~]").bold().yellow(), schedule);
+    }
+    if send_opts.background {
+        status!(
+            "{} Sending in the background, at most {}/s{}",
+            style("[~]").bold().yellow(),
+            format_bytes(send_opts.max_rate.map_or(send_opts.background_rate, |rate| rate.min(send_opts.background_rate))),
+            if cfg!(unix) { format!(" (toggle with kill -USR1 {})", std::process::id()) } else { String::new() }
+        );
+    }
+    let Some(manifest_path) = &send_opts.from_manifest else {
+        let mut paths = expand_patterns(send_opts)?;
+        let send_opts = SendOpts { file: Some(paths.remove(0)), more_files: paths, ..send_opts.clone() };
+        return serve(root_opts, &send_opts, &ListedSession::default(), schedule);
+    };
+    let listed = read_send_manifest(manifest_path)?;
+    let mut paths: Vec<String> = listed.iter().map(|file| file.path.display().to_string()).collect();
+    let labels: Vec<Option<String>> = listed.iter().map(|file| file.label.clone()).collect();
+    if !send_opts.separate {
+        let send_opts = SendOpts { file: Some(paths.remove(0)), more_files: paths, from_manifest: None, ..send_opts.clone() };
+        return serve(root_opts, &send_opts, &ListedSession { labels, separate: false }, schedule);
+    }
+
+    // every file is a session of its own, served at the same time
+    status!("{} Registering a passphrase for each of the {} files", style("[~]").bold().yellow(), paths.len());
+    let results: Vec<(String, Result<()>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = paths.into_iter().zip(labels).map(|(path, label)| {
+            let send_opts = SendOpts { file: Some(path.clone()), from_manifest: None, separate: false, ..send_opts.clone() };
+            let name = label.clone().unwrap_or(path);
+            let handle = scope.spawn(move || {
+                serve(root_opts, &send_opts, &ListedSession { labels: vec![label], separate: true }, schedule)
+            });
+            (name, handle)
+        }).collect();
+        handles.into_iter()
+            .map(|(name, handle)| (name, handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))))
+            .collect()
+    });
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    let mut first_error = None;
+    for (name, result) in results {
+        if let Err(e) = result {
+            report!("{} {}: {}", failure_mark(), name, e);
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => {
+            report!("{} {} of the files weren't sent", failure_mark(), failed);
+            Err(e)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Returns the schedule of `--start-at` and `--window` in the local time, `None` without them.
+/// The offset of the local time can only be determined safely while the process has a single thread.
+fn local_schedule(send_opts: &SendOpts) -> Option<Schedule> {
+    if send_opts.start_at.is_none() && send_opts.window.is_none() {
+        return None;
+    }
+    let offset = UtcOffset::current_local_offset().unwrap_or_else(|_| {
+        status!("{} Cannot determine the local time zone, the schedule is in UTC", style("[~]").bold().yellow());
+        UtcOffset::UTC
+    });
+    Schedule::new(send_opts.start_at, send_opts.window, offset)
+}
+
+/// Expands the glob patterns among the files given on the command line (`"*.log"`), see `glob::expand`.
+///
+/// # Returns
+///
+/// `Result<Vec<String>>` - The paths of the files to send, at least one.
+fn expand_patterns(send_opts: &SendOpts) -> Result<Vec<String>> {
+    let mut paths: Vec<String> = Vec::new();
+    for arg in send_opts.file.iter().chain(&send_opts.more_files) {
+        // a file may be named like a pattern, e.g. "[draft] notes.txt"
+        if !glob::is_pattern(arg) || Path::new(arg).exists() {
+            paths.push(arg.clone());
+            continue;
+        }
+        let matches = glob::expand(arg)?;
+        status!("{} {} matches {} files", style("[~]").bold().yellow(), arg, matches.len());
+        for path in matches.iter().map(|path| path.display().to_string()) {
+            // patterns may overlap, e.g. `*.log app.log`
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Registers the file (or the files) given on the command line and serves the receivers.
+///
+/// # Arguments
+///
+/// * `listed` - Labels of the files and how the session is shown, from `--from-manifest`.
+/// * `schedule` - When data is sent (`--start-at`, `--window`, optional).
+fn serve(root_opts: &RootOpts, send_opts: &SendOpts, listed: &ListedSession, schedule: Option<Schedule>) -> Result<()> {
+    let file_path = send_opts.file.as_deref().expect("clap requires a file unless --list-interfaces or --from-manifest");
+
+    let contact = match &send_opts.to {
+        Some(name) => Some(ContactBook::open()?.get(name)?.clone()),
+        None => None,
+    };
+    let profile = send_opts.profile.or(contact.as_ref().and_then(|contact| contact.profile));
+
+    let memory_profile = MemoryProfile::select(root_opts.low_memory);
+    debug!("Memory profile: {:?}", memory_profile);
+    let tuning = TransferTuning::resolve(profile, send_opts.chunk_size, send_opts.delay, &memory_profile);
+    debug!("Transfer tuning: {:?}", tuning);
+
+    // several files are sent as one data stream, listed for the receiver to split it up again
+    let mut bundle = match send_opts.more_files.is_empty() {
+        true => None,
+        false => {
+            let paths: Vec<&str> = std::iter::once(file_path).chain(send_opts.more_files.iter().map(String::as_str)).collect();
+            Some(FileBundle::scan(&paths)?.with_labels(&listed.labels))
+        }
+    };
+    let bundle_name = bundle.as_ref().map(|bundle| format!("{} files", bundle.len()));
+
+    // stdin is streamed as it comes, its size is unknown and it can only be read once
+    let streaming = file_path == STDIN_PATH;
+    if streaming {
+        check_streamable(send_opts)?;
+    }
+
     // check if the file exists and open it
-    let mut file = File::open(&send_opts.file)?;
-    let file_name = send_opts.file.split('/').last().unwrap_or_default();
-    let file_size = file.metadata()?.len();
+    let mut file = if streaming { stdin_file()? } else { File::open(file_path)? };
+    let metadata = file.metadata()?;
+    // directories are sent as a tar archive, the handle is only kept for the metadata
+    let archive = metadata.is_dir().then(|| DirectoryArchive::scan(Path::new(file_path))).transpose()?;
+    let file_name = match (&archive, &bundle_name) {
+        (Some(archive), _) => archive.name(),
+        (None, Some(bundle_name)) => bundle_name,
+        (None, None) if streaming => STDIN_FILE_NAME,
+        (None, None) => file_path.split('/').next_back().unwrap_or_default(),
+    };
+    let device = device_kind(Path::new(file_path)).filter(|_| !streaming);
+    let file_size = match (&archive, &bundle, device) {
+        _ if streaming => 0,
+        (Some(archive), _, _) => archive.size(),
+        (None, Some(bundle), _) => bundle.size(),
+        (None, None, Some(kind)) => device_size(&mut file, file_path, kind, send_opts)?,
+        (None, None, None) => metadata.len(),
+    };
+    if let Some(archive) = &archive {
+        status!(
+            "{} Sending the directory {} as an archive of {} files",
+            style("[~]").bold().yellow(),
+            file_name,
+            archive.file_count()
+        );
+    }
+    if let Some(bundle) = &bundle {
+        status!("{} Sending {} files together", style("[~]").bold().yellow(), bundle.len());
+    }
+    if streaming {
+        status!("{} Streaming stdin, its size is unknown until it ends", style("[~]").bold().yellow());
+    }
+    let single_file = archive.is_none() && bundle.is_none() && !streaming;
+    if let Some(reason) = unsupported_reason().filter(|_| send_opts.xattrs) {
+        status!("{} {}", style("[~]").bold().yellow(), reason);
+    } else if send_opts.xattrs && !single_file {
+        status!("{} Extended attributes are only shared for single files", style("[~]").bold().yellow());
+    }
+
+    // every copy is a session of its own, with its own socket
+    let mut relay = contact.as_ref()
+        .and_then(|contact| contact.relay.clone())
+        .unwrap_or_else(|| format!("{}:{}", root_opts.relay_host, root_opts.relay_port));
+    let mut sockets = Vec::with_capacity(send_opts.copies as usize);
+    for _ in 0..send_opts.copies {
+        let socket = bind_socket(send_opts.bind.as_deref())?;
+        // map before contacting the relay, so it likely sees the mapped port already
+        let port_mapping = map_port(&socket, send_opts.port_mapping);
+        connect_to_relay(&socket, &relay, root_opts)?;
+        sockets.push((socket, port_mapping));
+    }
 
-    let socket = bind_socket()?;
-    connect_to_relay_server(&socket, root_opts)?;
+    // Get the identity of the sender
+    let display_name = send_opts.display_name.as_deref()
+        .or(contact.as_ref().and_then(|contact| contact.display_name.as_deref()));
+    let sender_host = peer_identity(send_opts.share_hostname, display_name);
+    debug!(target: "crypto", "Sender identity: {}", sender_host);
 
-    // Get the hostname of the sender
-    let sender_host = hide_or_get_hostname(send_opts.hide_hostname)?;
-    debug!("Sender hostname: {}", sender_host);
+    // sessions served at the same time can't share the display of the phases
+    let phases = match listed.separate {
+        true => PhaseProgress::hidden(SENDER_PHASES),
+        false => PhaseProgress::new(SENDER_PHASES),
+    };
+    // a session of several is named after its file, so the passphrases can be told apart
+    let subject = listed.separate.then(|| listed.labels.first().cloned().flatten().unwrap_or_else(|| file_name.to_string()));
+    // a recorded session only knows the hash and the progress of a single file
+    let mut session = match &send_opts.resume_session {
+        Some(name) => {
+            let identity = FileIdentity::of(Path::new(file_path), &metadata).filter(|_| single_file && device.is_none());
+            let session = SenderSession::open(name, identity);
+            if session.is_none() {
+                status!("{} No configuration directory to record the session in", style("[~]").bold().yellow());
+            }
+            session
+        }
+        None => None,
+    };
+    let recorded_hash = session.as_ref().and_then(|session| session.file_hash().map(str::to_string));
+    phases.enter(Phase::Hashing);
+    let file_hash = match device {
+        _ if streaming && !send_opts.skip_hash => {
+            status!("{} Not hashing stdin, it can only be read once", style("[~]").bold().yellow());
+            AnonymousString(None)
+        }
+        _ if streaming => AnonymousString(None),
+        _ if archive.is_some() && !send_opts.skip_hash => {
+            debug!(target: "crypto", "Creating hash of the archive...");
+            let archive = archive.as_ref().expect("checked above");
+            AnonymousString(Some(hash_file_and_seek(&mut archive.reader(), memory_profile.hash_buffer_size)?))
+        }
+        _ if archive.is_some() => AnonymousString(None),
+        _ if bundle.is_some() && !send_opts.skip_hash => {
+            debug!(target: "crypto", "Creating hashes of the files...");
+            let bundle = bundle.as_mut().expect("checked above");
+            AnonymousString(Some(bundle.hash(memory_profile.hash_buffer_size)?))
+        }
+        _ if bundle.is_some() => AnonymousString(None),
+        Some(DeviceKind::Block) if !send_opts.skip_hash => {
+            debug!(target: "crypto", "Creating hash of the device...");
+            let hash = hash_file_and_seek(&mut (&mut file).take(file_size), memory_profile.hash_buffer_size)?;
+            file.rewind()?;
+            AnonymousString(Some(hash))
+        }
+        Some(DeviceKind::Character) if !send_opts.skip_hash => {
+            status!("{} Not hashing the character device, it can only be read once", style("[~]").bold().yellow());
+            AnonymousString(None)
+        }
+        Some(_) => AnonymousString(None),
+        None if recorded_hash.is_some() => {
+            status!("{} File unchanged since the session was recorded, reusing its hash", style("[~]").bold().yellow());
+            AnonymousString(recorded_hash)
+        }
+        None if send_opts.hash_cache => compute_cached_file_hash(file_path, &mut file, &metadata, &memory_profile)?,
+        None => compute_file_hash(send_opts.skip_hash, &mut file, &memory_profile)?,
+    };
+    debug!(target: "crypto", "File hash: {}", file_hash);
+    if let Some(session) = &mut session {
+        session.start(file_hash.0.clone(), current_unix_millis())?;
+        if session.acknowledged() > 0 {
+            status!(
+                "{} The receiver acknowledged {} of {} before, skipped if it kept them when it reconnects",
+                style("[~]").bold().yellow(),
+                format_bytes(session.acknowledged()),
+                format_bytes(file_size)
+            );
+        }
+    }
+    // only single files are previewed and searched for holes, character devices can't even seek
+    let (previewable, extents) = match device {
+        None if single_file => (is_previewable(&mut file, file_size)?, data_extents(&mut file, file_size)?),
+        _ => (false, None),
+    };
 
-    let file_hash = compute_file_hash(send_opts.skip_hash, &mut file)?;
-    debug!("File hash: {}", file_hash);
+    let passphrase = match &send_opts.code_from_file {
+        Some(path) => Some(Passphrase::read_from(BufReader::new(File::open(path)?))?),
+        None => None,
+    };
 
-    // Request a passphrase from the relay-server
-    serialize_and_send(&socket, "S2X_RP", &S2XRequestPassphraseMessage {
-        sender_host,
+    // Request a passphrase for every copy from the relay-server
+    phases.enter(Phase::Registering);
+    let registration = S2XRequestPassphraseMessage {
+        sender_host: sender_host.clone(),
         file_size,
-        file_hash,
+        file_hash: file_hash.clone(),
         file_name: file_name.to_string(),
-    })?;
-
-    // (Hopefully) receive the passphrase from the relay-server
-    let passphrase_message: X2SPassphraseProvidedMessage = receive_and_parse_and_expect(
-        &socket,
-        "X2S_PPM",
-    )?;
-
-    println!(
-        "{} Passphrase: {}",
-        style("[✔]").bold().green(),
-        style(&passphrase_message.passphrase).cyan()
+        passphrase: None,
+        expire_secs: send_opts.expire.map(|expire| expire.as_secs()),
+        kind: SessionKind::File,
+        relay_token: root_opts.relay_token.clone(),
+        registration_id: send_opts.registration_id.clone()
+            .or_else(|| session.as_ref().map(|session| session.registration_id().to_string())),
+        mapped_port: None,
+        previewable,
+        file_mode: file_mode(&metadata).filter(|_| device.is_none() && single_file),
+        sparse: extents.is_some(),
+        directory: archive.is_some(),
+        file_count: bundle.as_ref().map(|bundle| bundle.len() as u32),
+        announce: send_opts.announce,
+        on_match: if send_opts.stay_open { MatchPolicy::Keep } else { MatchPolicy::Remove },
+        offer_only: send_opts.offer_only,
+        compress: send_opts.compress && compression::is_supported(),
+        hash_algorithm: file_hash.0.as_ref().map(|_| HASH_ALGORITHM.to_string()),
+        resume: send_opts.resume_session.is_some(),
+        streaming,
+        schedule: schedule.map(|schedule| schedule.to_string()),
+    };
+    let mut sessions = Vec::with_capacity(sockets.len());
+    for (socket, port_mapping) in sockets {
+        let registration = S2XRequestPassphraseMessage {
+            mapped_port: port_mapping.as_ref().map(|mapping| mapping.external_port),
+            ..registration.clone()
+        };
+        let passphrase_message = register(&socket, &registration, passphrase.clone(), root_opts.blind)?;
+        sessions.push((socket, port_mapping, passphrase_message));
+    }
+
+    let copies = sessions.len();
+    for (index, (_, _, passphrase_message)) in sessions.iter().enumerate() {
+        let label = if copies > 1 { format!("Passphrase {}/{}", index + 1, copies) } else { "Passphrase".to_string() };
+        print_passphrase(send_opts, &relay, &label, subject.as_deref(), &passphrase_message.passphrase);
+    }
+
+    let mut expires_in = sessions.first()
+        .and_then(|(_, _, passphrase_message)| passphrase_lifetime(passphrase_message, send_opts));
+    if let Some(expires_in) = expires_in {
+        status!(
+            "{} {} in {}",
+            style("[~]").bold().yellow(),
+            if copies > 1 { "Passphrases expire" } else { "Passphrase expires" },
+            style(format_duration(expires_in)).dim()
+        );
+    }
+
+    let offer = Offer {
+        file_path,
+        file_name,
+        file_size,
+        extents,
+        archive: archive.as_ref(),
+        bundle: bundle.as_ref(),
+        contact: contact.as_ref(),
+        tuning: &tuning,
+        send_opts,
+        blind: root_opts.blind,
+        allow_unencrypted: root_opts.allow_unencrypted,
+        bandwidth: BandwidthShare::new(send_opts.max_rate, send_opts.background_rate, send_opts.background),
+        doh: root_opts.doh.as_deref(),
+        // the tunnel stays with the address the relay had when it was opened
+        relay_recheck: if root_opts.tunnel { Duration::ZERO } else { root_opts.relay_recheck },
+        capture: root_opts.capture.as_deref().map(PacketCapture::create).transpose()?,
+        session: session.map(Mutex::new),
+        streaming,
+        schedule,
+    };
+    if copies == 1 {
+        let (socket, port_mapping, mut passphrase_message) = sessions.pop().expect("at least one copy is sent");
+        if let Some(ticket_file) = &send_opts.ticket_file {
+            write_ticket(&socket, &relay, &relay_passphrase(&passphrase_message.passphrase, root_opts.blind), ticket_file)?;
+        }
+        let registration = S2XRequestPassphraseMessage {
+            mapped_port: port_mapping.as_ref().map(|mapping| mapping.external_port),
+            ..registration
+        };
+        if send_opts.offer_only {
+            expires_in = Some(come_online(&socket, &relay, &passphrase_message.passphrase, expires_in, root_opts)?);
+        }
+        let mut backup_relays = send_opts.backup_relays.iter();
+        let mut served = TransferTotals::default();
+        loop {
+            let result = serve_receiver(&offer, &socket, &relay, file.try_clone()?, &passphrase_message.passphrase, expires_in, &phases);
+            // with --stay-open, a receiver which failed doesn't keep the next ones from getting the file
+            if send_opts.stay_open && !result.as_ref().is_err_and(ends_session) {
+                match result {
+                    Ok(transfer) => {
+                        status!("{} Sent to {}: {}", style("[~]").bold().yellow(), style(&transfer.peer).cyan(), transfer);
+                        served.add_sequential(&transfer);
+                    }
+                    Err(e) => {
+                        report!("{} The transfer failed: {}", failure_mark(), e);
+                    }
+                }
+                file.rewind()?;
+                let remaining = reopen_session(&socket, &relay, &passphrase_message.passphrase, root_opts)?;
+                expires_in = Some(remaining);
+                status!(
+                    "{} Served {} receiver{}, waiting for the next one for {}",
+                    style("[~]").bold().yellow(),
+                    served.peers,
+                    if served.peers == 1 { "" } else { "s" },
+                    style(format_duration(remaining)).dim()
+                );
+                continue;
+            }
+            match result {
+                // the receivers were served until the passphrase expired
+                Err(NudgeError::SessionExpired) if served.peers > 0 => {
+                    status!("{} The passphrase expired after serving {} receivers", success_mark(), served.peers);
+                    status!("{} Total: {}", style("[~]").bold().yellow(), served);
+                    break;
+                }
+                // move to the next relay, the receivers only need to learn which one (and the passphrase, if it changed)
+                Err(NudgeError::RelayUnresponsive(timeout)) if backup_relays.len() > 0 => {
+                    report!(
+                        "{} The relay {} stopped responding (no answer within {})",
+                        failure_mark(),
+                        relay,
+                        format_duration(timeout)
+                    );
+                    phases.enter(Phase::Registering);
+                    let previous = passphrase_message.passphrase.clone();
+                    (relay, passphrase_message) = register_at_backup_relay(
+                        &socket, &mut backup_relays, &registration, previous, root_opts,
+                    )?;
+                    print_passphrase(send_opts, &relay, "Passphrase", subject.as_deref(), &passphrase_message.passphrase);
+                    expires_in = passphrase_lifetime(&passphrase_message, send_opts);
+                }
+                result => break result.map(drop)?,
+            }
+        }
+        status!("{} Phases: {}", style("[~]").bold().yellow(), format_phase_durations(&phases.durations()));
+        return Ok(());
+    }
+
+    if send_opts.group {
+        return serve_group(&offer, &sessions, &relay, expires_in, &phases);
+    }
+
+    // the receivers claim their copies in any order, each one is served by a thread of its own
+    phases.enter(Phase::WaitingForPeer);
+    let relay = relay.as_str();
+    let results: Vec<Result<PeerTransfer>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = sessions.iter()
+            .map(|(socket, _port_mapping, passphrase_message)| {
+                let offer = &offer;
+                scope.spawn(move || -> Result<PeerTransfer> {
+                    let file = File::open(offer.file_path)?;
+                    // the spinner shows the phase of all copies, so the copies only time theirs
+                    let phases = PhaseProgress::hidden(SENDER_PHASES);
+                    serve_receiver(offer, socket, relay, file, &passphrase_message.passphrase, expires_in, &phases)
+                })
+            })
+            .collect();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    });
+    drop(phases);
+    report_copies(sessions.iter().map(|(_, _, passphrase_message)| &passphrase_message.passphrase), results)
+}
+
+/// Registers the file at the relay the socket is connected to.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay
+/// * `registration` - The registration, sent with a new registration ID unless it has a chosen one
+/// * `code` - The passphrase to register (optional), generated by the relay otherwise
+/// * `blind` - Whether only a hash of the passphrase is sent to the relay (`--blind`)
+///
+/// # Returns
+///
+/// `Result<X2SPassphraseProvidedMessage>` - The answer of the relay, with the passphrase the receiver needs.
+///
+/// # Errors
+///
+/// Returns `NudgeError::MatchPolicyUnsupported` if the relay doesn't keep the session as asked (`--stay-open`),
+/// or `NudgeError::OfferUnsupported` if it doesn't keep it as an offer (`--offer-only`)
+fn register(
+    socket: &UdpSocket,
+    registration: &S2XRequestPassphraseMessage,
+    code: Option<Passphrase<'static>>,
+    blind: bool,
+) -> Result<X2SPassphraseProvidedMessage> {
+    // blind relays can't generate passphrases, they never see them
+    let code = match code {
+        None if blind => Some(generate_passphrase()?),
+        code => code,
+    };
+    let mut passphrase_message: X2SPassphraseProvidedMessage = request(socket, "S2X_RP", &S2XRequestPassphraseMessage {
+        passphrase: code.as_ref().map(|code| relay_passphrase(code, blind)),
+        registration_id: registration.registration_id.clone().or_else(|| Some(new_registration_id())),
+        ..registration.clone()
+    }, "X2S_PPM")?;
+    // older relays don't tell, they remove the session once claimed
+    if !registration.on_match.is_remove() && passphrase_message.on_match != Some(registration.on_match) {
+        return Err(NudgeError::MatchPolicyUnsupported);
+    }
+    if registration.offer_only && !passphrase_message.offer_only {
+        return Err(NudgeError::OfferUnsupported);
+    }
+    // the relay answers with the hash, the receiver needs the passphrase
+    if let Some(code) = code.filter(|_| blind) {
+        passphrase_message.passphrase = code;
+    }
+    Ok(passphrase_message)
+}
+
+/// Registers the file at the first of the backup relays which accepts it, after the relay stopped responding.
+/// The passphrase is kept, unless the backup relay has it in use already.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket the file was registered with, connected to the backup relay afterward
+/// * `backup_relays` - The backup relays not tried yet, as "host:port"
+/// * `registration` - The registration, sent with a new registration ID
+/// * `passphrase` - The passphrase the file was registered with
+/// * `root_opts` - Root options selecting the resolver
+///
+/// # Returns
+///
+/// `Result<(String, X2SPassphraseProvidedMessage)>` - The backup relay and its answer.
+///
+/// # Errors
+///
+/// Returns the error of the last backup relay if none of them registered the file
+fn register_at_backup_relay<'a>(
+    socket: &UdpSocket,
+    backup_relays: &mut impl Iterator<Item = &'a String>,
+    registration: &S2XRequestPassphraseMessage,
+    passphrase: Passphrase<'static>,
+    root_opts: &RootOpts,
+) -> Result<(String, X2SPassphraseProvidedMessage)> {
+    let mut last_error = None;
+    for backup_relay in backup_relays {
+        status!("{} Registering at {}...", style("[~]").bold().yellow(), style(backup_relay).cyan());
+        let registered = connect_to_relay(socket, backup_relay, root_opts)
+            .and_then(|_| match register(socket, registration, Some(passphrase.clone()), root_opts.blind) {
+                Err(NudgeError::ServerError(e)) if e.contains(&NudgeError::PassphraseInUse.to_string()) => {
+                    status!("{} The passphrase is in use at {}, registering a new one", style("[~]").bold().yellow(), backup_relay);
+                    register(socket, registration, None, root_opts.blind)
+                }
+                result => result,
+            });
+        match registered {
+            Ok(passphrase_message) => return Ok((backup_relay.clone(), passphrase_message)),
+            Err(e) => {
+                status!("{} Registering at {} failed: {}", failure_mark(), backup_relay, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or(NudgeError::RelayUnresponsive(relay_timeout())))
+}
+
+/// Prints a passphrase (and its link with --link) for the receiver, only the passphrase (or link) with -q.
+/// The passphrase of one of several files (`--separate`) is followed by the file, separated by a tab with -q.
+fn print_passphrase(send_opts: &SendOpts, relay: &str, label: &str, subject: Option<&str>, passphrase: &Passphrase<'static>) {
+    let link = send_opts.link.then(|| PassphraseLink {
+        relay: relay.to_string(),
+        passphrase: passphrase.clone(),
+    });
+    if is_quiet() {
+        // print only the passphrases (or links) so scripts can pick them up, one per line
+        let code = match &link {
+            Some(link) => link.to_string(),
+            None => passphrase.to_string(),
+        };
+        match subject {
+            Some(subject) => println!("{}\t{}", code, subject),
+            None => println!("{}", code),
+        }
+        return;
+    }
+    report!(
+        "{} {}{}: {}",
+        success_mark(),
+        label,
+        subject.map(|subject| format!(" for {}", subject)).unwrap_or_default(),
+        style(passphrase).cyan()
     );
+    if let Some(link) = &link {
+        report!("{} Link: {}", success_mark(), style(link).cyan());
+    }
+}
+
+/// Returns whether the session can't serve more receivers after the error (`--stay-open`), e.g. as the passphrase
+/// expired, while other errors only concern the receiver.
+fn ends_session(error: &NudgeError) -> bool {
+    matches!(
+        error,
+        NudgeError::SessionExpired | NudgeError::RelayUnresponsive(_) | NudgeError::RelayTimeout(_) | NudgeError::ServerError(_)
+    )
+}
+
+/// Reconnects the socket to the relay after serving a receiver and reopens the session for the next one (`--stay-open`),
+/// or opens an offer (`--offer-only`) for the receiver which asked for it.
+///
+/// # Returns
+///
+/// `Duration` - Time until the passphrase expires.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ServerError` if the relay forgot the session meanwhile, e.g. as it expired
+fn reopen_session(
+    socket: &UdpSocket,
+    relay: &str,
+    passphrase: &Passphrase<'static>,
+    root_opts: &RootOpts,
+) -> Result<Duration> {
+    connect_to_relay(socket, relay, root_opts)?;
+    let ready: X2SReadyMessage = request(socket, "S2X_RDY", &S2XReadyMessage {
+        passphrase: relay_passphrase(passphrase, root_opts.blind),
+    }, "X2S_RDY")?;
+    Ok(Duration::from_secs(ready.expires_in_secs))
+}
+
+/// Waits for a receiver to ask for the offer (`--offer-only`), then opens the session for it.
+///
+/// # Returns
+///
+/// `Duration` - Time until the passphrase expires.
+///
+/// # Errors
+///
+/// Returns `NudgeError::SessionExpired` if no receiver asked before the passphrase expired
+fn come_online(
+    socket: &UdpSocket,
+    relay: &str,
+    passphrase: &Passphrase<'static>,
+    expires_in: Option<Duration>,
+    root_opts: &RootOpts,
+) -> Result<Duration> {
+    status!("{} Offered the file, waiting for a receiver to ask for it", style("[~]").bold().yellow());
+    let receiver_host = wait_for_offer_request(socket, &relay_passphrase(passphrase, root_opts.blind), expires_in)?;
+    notify_desktop("nudge", &format!("{} asks for the file, coming online", receiver_host));
+    status!("{} {} asks for the file, coming online", style("[~]").bold().yellow(), style(&receiver_host).cyan());
+    reopen_session(socket, relay, passphrase, root_opts)
+}
+
+/// Returns when the passphrase expires, older relays don't tell so only our own limit is known (optional).
+fn passphrase_lifetime(passphrase_message: &X2SPassphraseProvidedMessage, send_opts: &SendOpts) -> Option<Duration> {
+    passphrase_message.expires_in_secs.map(Duration::from_secs).or(send_opts.expire)
+}
+
+/// The file, as offered to every receiver
+struct Offer<'a> {
+    file_path: &'a str,
+    file_name: &'a str,
+    file_size: u64,
+
+    /// Data regions of a sparse file (optional)
+    extents: Option<Vec<Extent>>,
+
+    /// The archive sent instead of the file if a directory is shared (optional)
+    archive: Option<&'a DirectoryArchive>,
 
-    debug!("Waiting for connection request...");
-    let conn_req: X2SSenderConnectToReceiverMessage = receive_and_parse_and_expect(
-        &socket,
-        "X2S_SCON",
-    )?;
+    /// The files sent if several are shared, the first one is `file_path` (optional)
+    bundle: Option<&'a FileBundle>,
 
-    println!(
+    /// Contact whose identity the receiver has to prove (optional)
+    contact: Option<&'a Contact>,
+    tuning: &'a TransferTuning,
+    send_opts: &'a SendOpts,
+
+    /// Whether the relay and the receiver only know the hash of the passphrase (`--blind`)
+    blind: bool,
+
+    /// Whether the file may be sent unencrypted to receivers which can't encrypt (`--allow-unencrypted`)
+    allow_unencrypted: bool,
+
+    /// The bandwidth limit shared by the receivers (`--max-rate`)
+    bandwidth: BandwidthShare,
+
+    /// DNS-over-HTTPS URL relay host names are resolved with (`--doh`, optional)
+    doh: Option<&'a str>,
+
+    /// How often the relay host name is looked up again while waiting for receivers (`--relay-recheck`)
+    relay_recheck: Duration,
+
+    /// Records the datagrams exchanged with the receivers (`--capture`, optional)
+    capture: Option<PacketCapture>,
+
+    /// Records the progress of the receiver (`--resume-session`, optional)
+    session: Option<Mutex<SenderSession>>,
+
+    /// Whether stdin is streamed (`send -`), read until it ends
+    streaming: bool,
+
+    /// When data is sent (`--start-at`, `--window`, optional)
+    schedule: Option<Schedule>,
+}
+
+impl Offer<'_> {
+    /// Returns the data stream of a directory or several files, `None` if the file itself is sent.
+    fn stream(&self) -> Option<Box<dyn Read + '_>> {
+        match (self.archive, self.bundle) {
+            (Some(archive), _) => Some(Box::new(archive.reader())),
+            (None, Some(bundle)) => Some(Box::new(bundle.reader())),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns the paths of the files read while sending.
+    fn paths(&self) -> Vec<&Path> {
+        match self.bundle {
+            Some(bundle) => bundle.paths().iter().map(PathBuf::as_path).collect(),
+            None => vec![Path::new(self.file_path)],
+        }
+    }
+}
+
+/// States of serving a receiver, each one holds what the next one needs.
+/// `connect_receiver` drives them, so every transition enters the phase of the next state in one place.
+enum SenderState {
+    /// Waits for a receiver to claim the passphrase
+    WaitingForPeer,
+    /// A receiver claimed the passphrase, punching through the NATs to it
+    Connecting(X2SSenderConnectToReceiverMessage),
+    /// Connected to the receiver, its requests are answered and then the data stream follows
+    Transferring(Box<ConnectedReceiver>),
+}
+
+impl SenderState {
+    /// Returns the phase of the state.
+    fn phase(&self) -> Phase {
+        match self {
+            SenderState::WaitingForPeer => Phase::WaitingForPeer,
+            SenderState::Connecting(_) => Phase::Connecting,
+            SenderState::Transferring(..) => Phase::Transferring,
+        }
+    }
+}
+
+/// A receiver the sender connected to
+struct ConnectedReceiver {
+    safe_connection: ReliableUdpSocket,
+    conn_req: X2SSenderConnectToReceiverMessage,
+
+    /// The tuning with the chunk size agreed on
+    tuning: TransferTuning,
+
+    /// The contacts naming the receiver proving its identity, read before the sandbox is entered (optional)
+    contact_book: Option<ContactBook>,
+}
+
+/// Waits for a receiver to claim the passphrase, connects to it and sends the file.
+///
+/// # Arguments
+///
+/// * `offer` - The file and how it's sent
+/// * `socket` - The socket the passphrase was registered with
+/// * `relay` - The relay the socket talks to, as "host:port"
+/// * `file` - The file, read from the beginning
+/// * `passphrase` - The passphrase of the session
+/// * `expires_in` - When the passphrase expires (optional)
+/// * `phases` - Shows and times the phases of the transfer
+///
+/// # Errors
+///
+/// Returns `NudgeError` if the passphrase expires, the receiver doesn't pass the checks or the transfer fails
+fn serve_receiver(
+    offer: &Offer,
+    socket: &UdpSocket,
+    relay: &str,
+    mut file: File,
+    passphrase: &Passphrase<'static>,
+    expires_in: Option<Duration>,
+    phases: &PhaseProgress,
+) -> Result<PeerTransfer> {
+    let (mut safe_connection, conn_req, tuning) = connect_receiver(offer, socket, relay, &mut file, passphrase, expires_in, phases)?;
+    let tuning = &tuning;
+    // skip the holes only if the receiver knows how to recreate them
+    let extents = offer.extents.as_deref().filter(|_| conn_req.sparse);
+    if let Some(extents) = extents {
+        write_message(&mut safe_connection, &SparseMapMessage { extents: extents.to_vec() }, tuning.delay)?;
+    }
+    // older receivers don't ask, and builds without the feature tell the receiver they can't compress
+    let compression = compression::negotiate(offer.send_opts.compress, conn_req.compress, conn_req.zstd);
+    if conn_req.compress || conn_req.zstd {
+        let message = CompressionMessage { compressed: compression.is_some(), algorithm: compression.unwrap_or_default() };
+        write_message(&mut safe_connection, &message, tuning.delay)?;
+    } else if offer.send_opts.compress {
+        status!("{} The receiver can't decompress zstd, sending the data stream uncompressed", style("[~]").bold().yellow());
+    }
+    // continue an interrupted download after the bytes the receiver kept, if the file itself is sent
+    let offset = match conn_req.resume_offset {
+        Some(requested) => {
+            let resumable = offer.archive.is_none() && offer.bundle.is_none() && extents.is_none() && !offer.streaming
+                && requested <= offer.file_size;
+            let offset = if resumable { requested } else { 0 };
+            write_message(&mut safe_connection, &ResumeMessage { offset }, tuning.delay)?;
+            offset
+        }
+        None => {
+            let acknowledged = offer.session.as_ref().map_or(0, |session| session.lock().unwrap().acknowledged());
+            if acknowledged > 0 {
+                status!(
+                    "{} The receiver didn't keep the {} it acknowledged before, sending the file from the start",
+                    style("[~]").bold().yellow(),
+                    format_bytes(acknowledged)
+                );
+            }
+            0
+        }
+    };
+    send_file(safe_connection, &mut file, offer, tuning, extents, compression, offset, &conn_req.receiver_host.to_string())
+}
+
+/// Waits for a receiver to claim the passphrase, connects to it, agrees on the chunk size and answers its requests
+/// (identity challenge, preview, extended attributes), so the file can be sent next.
+///
+/// # Returns
+///
+/// `Result<(ReliableUdpSocket, X2SSenderConnectToReceiverMessage, TransferTuning)>` - The connection,
+/// the request of the receiver and the tuning with the chunk size agreed on.
+fn connect_receiver(
+    offer: &Offer,
+    socket: &UdpSocket,
+    relay: &str,
+    file: &mut File,
+    passphrase: &Passphrase<'static>,
+    expires_in: Option<Duration>,
+    phases: &PhaseProgress,
+) -> Result<(ReliableUdpSocket, X2SSenderConnectToReceiverMessage, TransferTuning)> {
+    let mut state = SenderState::WaitingForPeer;
+    loop {
+        phases.enter(state.phase());
+        state = match state {
+            SenderState::WaitingForPeer => SenderState::Connecting(wait_for_receiver(offer, socket, relay, passphrase, expires_in)?),
+            SenderState::Connecting(conn_req) => SenderState::Transferring(Box::new(connect_to_receiver(offer, socket, conn_req)?)),
+            SenderState::Transferring(mut receiver) => {
+                if let Err(e) = answer_requests(offer, &mut receiver, file, passphrase) {
+                    // the receiver learns why instead of waiting for the file
+                    if matches!(e, NudgeError::EncryptionDeclined) {
+                        receiver.safe_connection.abort(&e.to_string());
+                    }
+                    return Err(e);
+                }
+                return Ok((receiver.safe_connection, receiver.conn_req, receiver.tuning));
+            }
+        };
+    }
+}
+
+/// Waits for a receiver to claim the passphrase, refusing receivers other than the contact sent to.
+/// Meanwhile the socket follows the relay to a new address if its host name resolves to another one.
+///
+/// # Errors
+///
+/// Returns `NudgeError::PassphraseExpired` if no receiver claimed the passphrase in time,
+/// `NudgeError::RelayUnresponsive` if the relay stopped responding with --backup-relay,
+/// or `NudgeError::ContactMismatch` if the receiver isn't the contact
+fn wait_for_receiver(
+    offer: &Offer,
+    socket: &UdpSocket,
+    relay: &str,
+    passphrase: &Passphrase<'static>,
+    expires_in: Option<Duration>,
+) -> Result<X2SSenderConnectToReceiverMessage> {
+    debug!(target: "relay_client", "Waiting for connection request...");
+    let mut binding = RelayBinding::new(resolver(offer.doh)?, relay, socket.peer_addr()?, offer.relay_recheck, Instant::now());
+    let fail_over = !offer.send_opts.backup_relays.is_empty();
+    // the relay is only watched if there's another one to move to, or a host name to follow
+    let conn_req = if fail_over || binding.is_some() {
+        watch_for_connection_request(socket, &relay_passphrase(passphrase, offer.blind), expires_in, binding.as_mut(), fail_over)?
+    } else {
+        wait_for_connection_request(socket, expires_in)?
+    };
+    if let Some(contact) = offer.contact {
+        // refuse before connecting, the receiver must claim the pinned key and prove owning it below
+        match &conn_req.receiver_identity {
+            Some(key) if *key == contact.public_key => {}
+            Some(key) => return Err(NudgeError::ContactMismatch(
+                contact.name.clone(), format!("the receiver identified as {}", key.fingerprint())
+            )),
+            None => return Err(NudgeError::ContactMismatch(
+                contact.name.clone(), "the receiver didn't identify (`get --identify`)".to_string()
+            )),
+        }
+    }
+    if offer.send_opts.alert {
+        ring_bell();
+        notify_desktop("nudge", &format!("{} is receiving {}", conn_req.receiver_host, offer.file_name));
+    }
+    Ok(conn_req)
+}
+
+/// Punches through the NATs to the receiver and agrees on the chunk size.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ChunkSizeMismatch` if the receiver can't take the chunks asked for with --chunk-size
+fn connect_to_receiver(
+    offer: &Offer,
+    socket: &UdpSocket,
+    conn_req: X2SSenderConnectToReceiverMessage,
+) -> Result<ConnectedReceiver> {
+    let (send_opts, tuning) = (offer.send_opts, offer.tuning);
+    let receiver_addr = mapped_addr(conn_req.receiver_addr, conn_req.receiver_mapped_port);
+    status!(
         "{} Connecting to peer {} ({})...",
         style("[~]").bold().yellow(),
         style(&conn_req.receiver_host).cyan(),
-        style(&conn_req.receiver_addr).dim()
+        style(&receiver_addr).dim()
     );
-    socket.connect(conn_req.receiver_addr)?;
+    // the contacts name the receiver proving its identity, read them while files can still be opened
+    let contact_book = conn_req.receiver_identity.as_ref().map(|_| ContactBook::open()).transpose()?;
+
+    connect_to_peer(socket, receiver_addr)?;
+    if send_opts.sandbox {
+        enter_sandbox(&offer.paths())?;
+    }
 
     debug!("Initializing socket connection...");
-    init_socket(&socket)?;
+    init_socket(socket)?;
     debug!("Ready to send data!");
 
-    send_file(&socket, &mut file, send_opts, file_size)?;
+    let safe_connection = ReliableUdpSocket::new(socket.try_clone()?)
+        .with_max_in_flight(tuning.max_in_flight)
+        .with_peer_timeout(send_opts.peer_timeout)
+        .with_txtime(send_opts.txtime)
+        .with_capture(offer.capture.clone());
+    // abort instead of sending chunks the receiver's network can't take, so both sides learn why
+    let chunk_size = match negotiate_chunk_size(tuning.chunk_size, send_opts.chunk_size.is_some(), conn_req.max_chunk_size) {
+        Ok(chunk_size) => chunk_size,
+        Err(e) => {
+            safe_connection.abort(&e.to_string());
+            return Err(e);
+        }
+    };
+    if chunk_size < tuning.chunk_size {
+        status!(
+            "{} The receiver takes chunks of at most {}, lowering the chunk size",
+            style("[~]").bold().yellow(),
+            style(format_bytes(u64::from(chunk_size))).dim()
+        );
+    }
+    let tuning = TransferTuning { chunk_size, ..*tuning };
+    Ok(ConnectedReceiver { safe_connection, conn_req, tuning, contact_book })
+}
+
+/// Answers the requests of the receiver sent before the file: the identity challenge, the preview
+/// and the extended attributes.
+///
+/// # Errors
+///
+/// Returns `NudgeError::EncryptionDeclined` if the data stream wouldn't be encrypted (unless `--allow-unencrypted`),
+/// `NudgeError::ContactMismatch` if the receiver can't prove its identity,
+/// or `NudgeError::Io` if the file can't be read
+fn answer_requests(
+    offer: &Offer,
+    receiver: &mut ConnectedReceiver,
+    file: &mut File,
+    passphrase: &Passphrase<'static>,
+) -> Result<()> {
+    let ConnectedReceiver { safe_connection, conn_req, tuning, contact_book } = receiver;
+    // a relay (or someone spoofing it) could drop the salt from the connection request to strip the encryption
+    match &conn_req.encryption_salt {
+        Some(salt) => {
+            start_encryption(safe_connection, passphrase, salt, conn_req.key_exchange, offer.allow_unencrypted, tuning.delay)?
+        }
+        None if offer.allow_unencrypted => status!(
+            "{} The data stream isn't encrypted, the receiver didn't ask for encryption",
+            style("[~]").bold().yellow()
+        ),
+        None => return Err(NudgeError::EncryptionDeclined),
+    }
+    if let (Some(key), Some(contact_book)) = (&conn_req.receiver_identity, &contact_book) {
+        let passphrase = relay_passphrase(passphrase, offer.blind);
+        challenge_identity(safe_connection, key, contact_book, &passphrase, tuning.delay)?;
+    }
+    if conn_req.preview {
+        send_preview(safe_connection, file, tuning.delay)?;
+    }
+    if conn_req.xattrs {
+        let share = offer.send_opts.xattrs && offer.archive.is_none() && offer.bundle.is_none() && !offer.streaming;
+        send_attributes(safe_connection, file, share, tuning.delay)?;
+    }
+    match offer.bundle {
+        Some(bundle) if conn_req.manifest => write_message(safe_connection, bundle.manifest(), tuning.delay)?,
+        Some(_) => status!(
+            "{} The receiver doesn't split several files, it gets them as one",
+            style("[~]").bold().yellow()
+        ),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Chunks buffered for each receiver of a group, once the buffer of the slowest receiver is full
+/// it holds up reading the file
+const GROUP_BUFFER_CHUNKS: usize = 256;
+
+/// Handed from the thread reading the file to the threads sending it to the receivers of a group
+enum GroupChunk {
+    /// All receivers are connected, with how the data stream is sent to all of them
+    Start { sparse: bool, compression: Option<Algorithm> },
+
+    /// The next chunk of the data stream
+    Data(Arc<[u8]>),
+
+    /// The file can't be read, the receivers are told why
+    Abort(String),
+}
+
+/// A receiver of a group which connected and waits for the data stream
+struct GroupMember {
+    /// Index of the copy the receiver claimed
+    index: usize,
+
+    /// Whether the receiver accepts a sparse transfer
+    sparse: bool,
+
+    /// Whether the receiver asks for compression
+    compress: bool,
+
+    /// Whether the receiver takes zstd
+    zstd: bool,
+
+    /// Largest chunks the receiver takes
+    chunk_size: u32,
+
+    chunks: SyncSender<GroupChunk>,
+}
+
+/// Sends the file to all receivers of the copies at once (`--group`): waits until they're connected,
+/// then reads every chunk once and fans it out to them. Each receiver is sent to by a thread of its own,
+/// acknowledging at its own pace, so a slow receiver only holds up the others once its buffer is full.
+///
+/// # Arguments
+///
+/// * `offer` - The file and how it's sent
+/// * `sessions` - The sockets and passphrases of the copies
+/// * `relay` - The relay the sockets talk to, as "host:port"
+/// * `expires_in` - When the passphrases expire (optional)
+/// * `phases` - Shows and times the phases of the transfer
+///
+/// # Errors
+///
+/// Returns `NudgeError` if the file can't be read, or any of the receivers failed
+fn serve_group(
+    offer: &Offer,
+    sessions: &[(UdpSocket, Option<PortMapping>, X2SPassphraseProvidedMessage)],
+    relay: &str,
+    expires_in: Option<Duration>,
+    phases: &PhaseProgress,
+) -> Result<()> {
+    phases.enter(Phase::WaitingForPeer);
+    let (members_tx, members_rx) = mpsc::channel();
+    let (results, read_result) = std::thread::scope(|scope| {
+        let handles: Vec<_> = sessions.iter()
+            .enumerate()
+            .map(|(index, (socket, _port_mapping, passphrase_message))| {
+                let members_tx = members_tx.clone();
+                scope.spawn(move || -> Result<PeerTransfer> {
+                    let mut file = File::open(offer.file_path)?;
+                    let phases = PhaseProgress::hidden(SENDER_PHASES);
+                    let (safe_connection, conn_req, tuning) = connect_receiver(
+                        offer, socket, relay, &mut file, &passphrase_message.passphrase, expires_in, &phases,
+                    )?;
+                    let (chunks_tx, chunks_rx) = mpsc::sync_channel(GROUP_BUFFER_CHUNKS);
+                    let member = GroupMember {
+                        index,
+                        sparse: conn_req.sparse,
+                        compress: conn_req.compress,
+                        zstd: conn_req.zstd,
+                        chunk_size: tuning.chunk_size,
+                        chunks: chunks_tx,
+                    };
+                    // the group is complete once every thread handed over its receiver or failed
+                    let joined = members_tx.send(member).is_ok();
+                    drop(members_tx);
+                    if !joined {
+                        return Ok(PeerTransfer { peer: conn_req.receiver_host.to_string(), bytes: 0, duration: Duration::ZERO });
+                    }
+                    send_to_group_member(safe_connection, &conn_req, offer, chunks_rx)
+                })
+            })
+            .collect();
+        drop(members_tx);
+        let members: Vec<GroupMember> = members_rx.iter().collect();
+
+        let start_time = Instant::now();
+        let receivers = members.len();
+        let read_result = if members.is_empty() {
+            Ok(())
+        } else {
+            phases.enter(Phase::Transferring);
+            fan_out_file(offer, members)
+        };
+        let results: Vec<Result<PeerTransfer>> = handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect();
+        if receivers > 0 && read_result.is_ok() {
+            status!(
+                "{} File sent to {} receivers in {}s!",
+                success_mark(),
+                receivers,
+                start_time.elapsed().as_millis() as f64 / 1000.0
+            );
+        }
+        (results, read_result)
+    });
+    read_result?;
+    report_copies(sessions.iter().map(|(_, _, passphrase_message)| &passphrase_message.passphrase), results)
+}
+
+/// Prints how every copy went and what was sent to all receivers together.
+///
+/// # Arguments
+///
+/// * `passphrases` - The passphrases of the copies
+/// * `results` - What was sent to the receiver of every copy, in the same order
+///
+/// # Errors
+///
+/// Returns the error of the first copy which failed
+fn report_copies<'a>(
+    passphrases: impl Iterator<Item = &'a Passphrase<'static>>,
+    results: Vec<Result<PeerTransfer>>,
+) -> Result<()> {
+    let mut first_error = None;
+    let mut transfers = Vec::new();
+    for (passphrase, result) in passphrases.zip(results) {
+        match result {
+            Ok(transfer) => {
+                status!(
+                    "{} Copy {} was received by {}: {}",
+                    success_mark(),
+                    style(passphrase).cyan(),
+                    style(&transfer.peer).cyan(),
+                    transfer
+                );
+                transfers.push(transfer);
+            }
+            Err(e) => {
+                status!(
+                    "{} Copy {} failed: {}",
+                    failure_mark(),
+                    style(passphrase).cyan(),
+                    e
+                );
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if !transfers.is_empty() {
+        status!("{} Total: {}", style("[~]").bold().yellow(), TransferTotals::concurrent(&transfers));
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Reads the file once and hands every chunk to all members of the group, dropping members whose thread failed.
+fn fan_out_file(offer: &Offer, mut members: Vec<GroupMember>) -> Result<()> {
+    let (send_opts, tuning) = (offer.send_opts, offer.tuning);
+    // the data stream is the same for everyone, so holes are skipped and data compressed only if all agree
+    let extents = offer.extents.as_deref().filter(|_| members.iter().all(|member| member.sparse));
+    let compression = compression::negotiate(
+        send_opts.compress,
+        members.iter().all(|member| member.compress),
+        members.iter().all(|member| member.zstd),
+    );
+    let chunk_size = members.iter().map(|member| member.chunk_size).min().unwrap_or(tuning.chunk_size);
+    members.retain(|member| member.chunks.send(GroupChunk::Start { sparse: extents.is_some(), compression }).is_ok());
+    status!(
+        "{} Sending {} to {} receivers at once (chunk-size: {})...",
+        style("[~]").bold().yellow(),
+        format_bytes(offer.file_size),
+        members.len(),
+        style(format_bytes(u64::from(chunk_size))).dim()
+    );
+
+    let mut file = File::open(offer.file_path)?;
+    let (mut reader, data_size): (Box<dyn Read>, u64) = match (extents, offer.stream()) {
+        (Some(extents), _) => (Box::new(SparseReader::new(&mut file, extents)), data_size(extents)),
+        (None, Some(stream)) => (stream, offer.file_size),
+        (None, None) => (Box::new((&mut file).take(offer.file_size)), offer.file_size),
+    };
+    if let Some(algorithm) = compression {
+        reader = compression::compress(reader, algorithm)?;
+    }
+
+    let progress_bar = new_downloader_progressbar(data_size);
+    let mut bytes_read_total: u64 = 0;
+    let mut buffer: Vec<u8> = vec![0; chunk_size as usize];
+    let mut io_background = false;
+    loop {
+        follow_background_mode(&offer.bandwidth, &mut io_background);
+        let read_span = span(Stage::Read);
+        let bytes_read = match read_with_retry(&mut reader, &mut buffer, send_opts.read_retries, READ_RETRY_BACKOFF) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                let reason = format!("Sender cannot read the file: {}", e);
+                for member in &members {
+                    let _ = member.chunks.send(GroupChunk::Abort(reason.clone()));
+                }
+                return Err(e.into());
+            }
+        };
+        drop(read_span);
+        if bytes_read == 0 {
+            break;
+        }
+
+        let chunk: Arc<[u8]> = Arc::from(&buffer[..bytes_read]);
+        let before = members.len();
+        members.retain(|member| member.chunks.send(GroupChunk::Data(chunk.clone())).is_ok());
+        if members.len() < before {
+            debug!("{} receivers of the group left, {} remaining", before - members.len(), members.len());
+        }
+        if members.is_empty() {
+            break;
+        }
+        bytes_read_total += bytes_read as u64;
+        progress_bar.set_position(bytes_read_total);
+    }
+    // closing the channels ends the sessions once the members sent everything
+    debug!("Read the file for the copies {:?}", members.iter().map(|member| member.index + 1).collect::<Vec<_>>());
+    drop(members);
+    progress_bar.finish_with_message(complete_message());
+    Ok(())
+}
+
+/// Sends the chunks fanned out by `fan_out_file` to a receiver of the group, and ends the session after the last one.
+fn send_to_group_member(
+    mut safe_connection: ReliableUdpSocket,
+    conn_req: &X2SSenderConnectToReceiverMessage,
+    offer: &Offer,
+    chunks: Receiver<GroupChunk>,
+) -> Result<PeerTransfer> {
+    let delay = offer.tuning.delay;
+    let start_time = Instant::now();
+    let mut pacer = offer.bandwidth.join(0);
+    let mut bytes_sent: u64 = 0;
+    for chunk in chunks {
+        match chunk {
+            GroupChunk::Start { sparse, compression } => {
+                if let Some(extents) = offer.extents.as_deref().filter(|_| sparse) {
+                    write_message(&mut safe_connection, &SparseMapMessage { extents: extents.to_vec() }, delay)?;
+                }
+                if conn_req.compress || conn_req.zstd {
+                    let message = CompressionMessage { compressed: compression.is_some(), algorithm: compression.unwrap_or_default() };
+                    write_message(&mut safe_connection, &message, delay)?;
+                }
+                // the group is sent the same data stream, from the beginning
+                if conn_req.resume_offset.is_some() {
+                    write_message(&mut safe_connection, &ResumeMessage { offset: 0 }, delay)?;
+                }
+                // the receiver waited for the others, which isn't the system sleeping
+                safe_connection.mark_busy();
+            }
+            GroupChunk::Data(data) => {
+                wait_for_share(&mut safe_connection, &mut pacer, data.len(), start_time);
+                let send_span = span(Stage::Send);
+                safe_connection.write_and_flush(&data, false, delay)?;
+                drop(send_span);
+                bytes_sent += data.len() as u64;
+            }
+            GroupChunk::Abort(reason) => {
+                safe_connection.abort(&reason);
+                return Ok(PeerTransfer { peer: conn_req.receiver_host.to_string(), bytes: bytes_sent, duration: start_time.elapsed() });
+            }
+        }
+    }
+    let statistics = safe_connection.statistics();
+    safe_connection.end();
+    debug!("Datagrams to {}: {}", conn_req.receiver_host, statistics);
+    Ok(PeerTransfer { peer: conn_req.receiver_host.to_string(), bytes: bytes_sent, duration: start_time.elapsed() })
+}
+
+/// Waits until the chunk fits into the receiver's share of the bandwidth (`--max-rate`).
+///
+/// # Arguments
+///
+/// * `safe_connection` - The connection to the receiver
+/// * `pacer` - Keeps the receiver to its share
+/// * `bytes` - The size of the chunk about to be sent
+/// * `start_time` - When sending to the receiver started
+fn wait_for_share(safe_connection: &mut ReliableUdpSocket, pacer: &mut PeerPacer, bytes: usize, start_time: Instant) {
+    let wait = pacer.pace(bytes, start_time.elapsed().as_millis() as u64);
+    if !wait.is_zero() {
+        std::thread::sleep(wait);
+        // no acknowledgments arrive while waiting, which isn't the system sleeping
+        safe_connection.mark_busy();
+    }
+}
+
+/// Holds the data back until the schedule (`--start-at`, `--window`) lets it go on, keeping the connection
+/// to the receiver alive meanwhile.
+///
+/// # Arguments
+///
+/// * `safe_connection` - The connection to the receiver
+/// * `schedule` - When data is sent
+/// * `start` - When to start sending (`--start-at`), the window applies from then on
+///
+/// # Errors
+///
+/// Returns `NudgeError::TransferAborted` if the receiver aborted meanwhile
+fn wait_for_schedule(safe_connection: &mut ReliableUdpSocket, schedule: &Schedule, start: Instant) -> Result<()> {
+    let mut paused = false;
+    loop {
+        let wait = match start.saturating_duration_since(Instant::now()) {
+            wait if wait.is_zero() => schedule.until_window(OffsetDateTime::now_utc()),
+            wait => wait,
+        };
+        if wait.is_zero() {
+            if paused {
+                status!("{} Sending again, as scheduled", style("[~]").bold().yellow());
+            }
+            return Ok(());
+        }
+        if !paused {
+            status!(
+                "{} Waiting {} to send {} (local time)",
+                style("[~]").bold().yellow(),
+                style(format_duration(wait)).dim(),
+                schedule
+            );
+            paused = true;
+        }
+        safe_connection.keep_alive(wait.min(SCHEDULE_KEEP_ALIVE_INTERVAL))?;
+    }
+}
+
+/// Follows the background mode (`--background`, toggled by SIGUSR1) with the I/O priority of the calling thread,
+/// which reads the file.
+///
+/// # Arguments
+///
+/// * `bandwidth` - The bandwidth share, which knows the mode
+/// * `io_background` - Whether the thread reads with idle I/O priority, updated to the mode
+fn follow_background_mode(bandwidth: &BandwidthShare, io_background: &mut bool) {
+    if let Some(background) = bandwidth.follow_toggles(background_toggles()) {
+        let limit = match bandwidth.rate() {
+            Some(rate) => format!("at most {}/s", format_bytes(rate)),
+            None => "no bandwidth limit".to_string(),
+        };
+        status!(
+            "{} Switched to {} ({})",
+            style("[~]").bold().yellow(),
+            if background { "the background mode" } else { "the foreground mode" },
+            limit
+        );
+    }
+    let background = bandwidth.is_background();
+    if background != *io_background {
+        set_io_priority(background);
+        *io_background = background;
+    }
+}
+
+/// Restricts the process to the connection to the receiver and reading the files being sent.
+fn enter_sandbox(paths: &[&Path]) -> Result<()> {
+    let status = sandbox::enter(&[], paths)?;
+    match status.landlock_abi {
+        Some(abi) => status!(
+            "{} Sandboxed: network limited to the receiver, files to the ones being sent (Landlock ABI {})",
+            success_mark(),
+            abi
+        ),
+        None => status!(
+            "{} Sandboxed: network limited to the receiver, but the kernel doesn't support Landlock to limit file access",
+            style("[~]").bold().yellow()
+        ),
+    }
+    Ok(())
+}
+
+/// Checks whether the file is small and looks like text, so the receiver may preview it
+fn is_previewable(file: &mut File, file_size: u64) -> Result<bool> {
+    if file_size == 0 || file_size > PREVIEW_MAX_FILE_SIZE {
+        return Ok(false);
+    }
+    let mut beginning = Vec::with_capacity(MAX_PREVIEW_BYTES as usize);
+    (&mut *file).take(MAX_PREVIEW_BYTES as u64).read_to_end(&mut beginning)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(looks_like_text(&beginning))
+}
+
+/// Tells the receiver whether the data stream is encrypted, and starts encrypting it if nudge was built with
+/// the `crypto` feature. This is the first message after connecting, so nothing else is sent in plaintext.
+/// The key is always agreed on with a key exchange. Unless `--allow-unencrypted` is given, a receiver asking for
+/// encryption without one is refused, as is a sender built without the `crypto` feature.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase as given, not the hash a blind relay knows.
+/// * `salt` - The salt of the receiver (hex).
+/// * `key_exchange` - Whether the receiver agrees on the key with a key exchange.
+/// * `allow_unencrypted` - Whether the data stream may be sent unencrypted (`--allow-unencrypted`).
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidEncryptionSalt` if the salt isn't hex,
+/// `NudgeError::EncryptionDeclined` if the data stream wouldn't be encrypted,
+/// or `NudgeError::KeyExchangeFailed` if the receiver doesn't know the passphrase
+fn start_encryption(
+    safe_connection: &mut ReliableUdpSocket,
+    passphrase: &Passphrase<'static>,
+    salt: &str,
+    key_exchange: bool,
+    allow_unencrypted: bool,
+    delay: u64,
+) -> Result<()> {
+    let salt = decode_hex(salt).ok_or_else(|| NudgeError::InvalidEncryptionSalt(salt.to_string()))?;
+    let encrypted = encryption::is_supported() && key_exchange;
+    write_message(safe_connection, &EncryptionMessage { encrypted, key_exchange: encrypted }, delay)?;
+    if encrypted {
+        let cipher = exchange_keys(safe_connection, passphrase, &salt, Role::Sender, delay)?;
+        safe_connection.start_encryption(cipher)?;
+    } else if !allow_unencrypted {
+        return Err(NudgeError::EncryptionDeclined);
+    } else if !key_exchange {
+        status!("{} The data stream isn't encrypted, the receiver didn't offer a key exchange", style("[~]").bold().yellow());
+    } else {
+        status!("{} The data stream isn't encrypted, nudge was built without the crypto feature", style("[~]").bold().yellow());
+    }
     Ok(())
 }
 
+/// Starts encrypting the data stream between two paired peers (`exchange`, `pipe` and `forward`), first thing after
+/// connecting. The host agrees to the salt of the joining side like a sender, the joining side answers like a
+/// receiver. If it fails, the peer learns why instead of waiting for data.
+///
+/// # Arguments
+///
+/// * `connection` - The connection to the peer.
+/// * `pairing` - The pairing with the peer, carrying the passphrase and the salt.
+/// * `allow_unencrypted` - Whether the data stream may be sent unencrypted (`--allow-unencrypted`).
+///
+/// # Returns
+///
+/// `Result<ReliableUdpSocket>` - The connection, encrypted unless `allow_unencrypted` and the peer can't encrypt.
+///
+/// # Errors
+///
+/// Returns `NudgeError::EncryptionDeclined` if the data stream wouldn't be encrypted (unless `--allow-unencrypted`),
+/// `NudgeError::EncryptionUnsupported` if nudge was built without the crypto feature (unless `--allow-unencrypted`),
+/// or `NudgeError::KeyExchangeFailed` if the peer doesn't know the passphrase
+pub(crate) fn encrypt_pairing(
+    mut connection: ReliableUdpSocket,
+    pairing: &Pairing,
+    allow_unencrypted: bool,
+    delay: u64,
+) -> Result<ReliableUdpSocket> {
+    let result = match &pairing.encryption_salt {
+        Some(salt) if pairing.is_host => {
+            start_encryption(&mut connection, &pairing.passphrase, salt, pairing.key_exchange, allow_unencrypted, delay)
+        }
+        Some(salt) => {
+            let salt = decode_hex(salt).ok_or_else(|| NudgeError::InvalidEncryptionSalt(salt.to_string()))?;
+            get_command::start_encryption(&mut connection, &pairing.passphrase, &salt, allow_unencrypted, delay)
+        }
+        None if allow_unencrypted => {
+            status!("{} The data stream isn't encrypted, the peer can't encrypt", style("[~]").bold().yellow());
+            Ok(())
+        }
+        None if !encryption::is_supported() => Err(NudgeError::EncryptionUnsupported),
+        None => Err(NudgeError::EncryptionDeclined),
+    };
+    match result {
+        Ok(()) => Ok(connection),
+        Err(e) => {
+            connection.abort(&e.to_string());
+            Err(e)
+        }
+    }
+}
+
+/// Agrees on the key of the data stream with the peer, authenticated by the passphrase (see `KeyExchange`).
+/// The sender sends its element and confirmation first, both sides send their confirmation before checking the
+/// peer's, so both learn right away if the exchange failed.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase as given, not the hash a blind relay knows.
+/// * `salt` - The salt of the receiver.
+/// * `role` - Whether we send or receive the file.
+///
+/// # Errors
+///
+/// Returns `NudgeError::KeyExchangeFailed` if the peer doesn't know the passphrase
+pub(crate) fn exchange_keys(
+    safe_connection: &mut ReliableUdpSocket,
+    passphrase: &Passphrase<'static>,
+    salt: &[u8],
+    role: Role,
+    delay: u64,
+) -> Result<FrameCipher> {
+    let (exchange, element) = KeyExchange::start(passphrase, salt, role)?;
+    let element = KeyExchangeMessage { element: encode_hex(&element) };
+    let peer_element: KeyExchangeMessage = match role {
+        Role::Sender => {
+            write_message(safe_connection, &element, delay)?;
+            read_message(safe_connection)?
+        }
+        Role::Receiver => {
+            let peer_element = read_message(safe_connection)?;
+            write_message(safe_connection, &element, delay)?;
+            peer_element
+        }
+    };
+    let key = exchange.finish(&decode_hex(&peer_element.element).unwrap_or_default())?;
+
+    let confirmation = KeyConfirmationMessage { confirmation: encode_hex(&key.confirmation()) };
+    let peer_confirmation: KeyConfirmationMessage = match role {
+        Role::Sender => {
+            write_message(safe_connection, &confirmation, delay)?;
+            read_message(safe_connection)?
+        }
+        Role::Receiver => {
+            let peer_confirmation = read_message(safe_connection)?;
+            write_message(safe_connection, &confirmation, delay)?;
+            peer_confirmation
+        }
+    };
+    let cipher = key.confirm(&decode_hex(&peer_confirmation.confirmation).unwrap_or_default())?;
+    debug!(target: "crypto", "Agreed on the key of the data stream with the peer");
+    Ok(cipher)
+}
+
+/// Challenges the receiver to prove owning the identity it claimed,
+/// by signing a random nonce together with the passphrase of the session.
+///
+/// # Errors
+///
+/// Returns `NudgeError::IdentityProofFailed` if the signature doesn't match the claimed key
+fn challenge_identity(
+    safe_connection: &mut ReliableUdpSocket,
+    key: &PublicKey,
+    contact_book: &ContactBook,
+    passphrase: &Passphrase<'static>,
+    delay: u64,
+) -> Result<()> {
+    let nonce = encode_hex(&thread_rng().gen::<[u8; 32]>());
+    write_message(safe_connection, &IdentityChallengeMessage { nonce: nonce.clone() }, delay)?;
+    let proof: IdentityProofMessage = read_message(safe_connection)?;
+    if !key.verify(&identity_proof_message(&nonce, passphrase), &proof.signature)? {
+        return Err(NudgeError::IdentityProofFailed(key.fingerprint()));
+    }
+
+    let name = contact_book.find_by_key(key).map_or("unknown", |contact| contact.name.as_str());
+    status!(
+        "{} Receiver proved identity {} ({})",
+        success_mark(),
+        style(name).cyan(),
+        style(key.fingerprint()).dim()
+    );
+    Ok(())
+}
+
+/// Answers the receiver's preview request with the beginning of the file
+/// and waits for the receiver to decide whether to download it.
+///
+/// # Errors
+///
+/// Returns `NudgeError::DownloadDeclined` if the receiver doesn't want the file
+fn send_preview(safe_connection: &mut ReliableUdpSocket, file: &mut File, delay: u64) -> Result<()> {
+    let preview_request: PreviewRequestMessage = read_message(safe_connection)?;
+    debug!("Receiver asked for a preview of {} bytes", preview_request.max_bytes);
+
+    let mut preview = Vec::new();
+    (&mut *file).take(preview_request.max_bytes.min(MAX_PREVIEW_BYTES) as u64).read_to_end(&mut preview)?;
+    file.seek(SeekFrom::Start(0))?;
+    safe_connection.write_and_flush(&preview, true, delay)?;
+
+    status!("{} Receiver is looking at the preview...", style("[~]").bold().yellow());
+    let decision: PreviewDecisionMessage = read_message(safe_connection)?;
+    if !decision.download {
+        return Err(NudgeError::DownloadDeclined);
+    }
+    Ok(())
+}
+
+/// Answers the receiver's request for the extended attributes of the file,
+/// which are only shared if enabled with `--xattrs`
+fn send_attributes(safe_connection: &mut ReliableUdpSocket, file: &File, share: bool, delay: u64) -> Result<()> {
+    let attributes = if share { Some(read_attributes(file)?) } else { None };
+    debug!(target: "io", "Sending extended attributes: {:?}", attributes);
+    write_message(safe_connection, &ExtendedAttributesMessage { attributes }, delay)
+}
+
 /// Binds a UDP socket to a local address
 ///
+/// # Arguments
+///
+/// * `bind` - IP address or interface to bind to (optional, all IPv4 interfaces by default)
+///
 /// # Errors
 ///
-/// Returns `NudgeError::Io` if binding fails
-fn bind_socket() -> Result<UdpSocket> {
-    let local_bind_address = (Ipv4Addr::from(0u32), 0);
+/// Returns `NudgeError::UnknownInterface` if there is no such interface,
+/// or `NudgeError::Io` if binding fails
+pub(crate) fn bind_socket(bind: Option<&str>) -> Result<UdpSocket> {
+    let local_bind_address = match bind {
+        Some(bind) => (resolve_bind_address(bind, &list_interfaces()?)?, 0),
+        None => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+    };
     debug!("Binding UDP socket to local address: {:?}", local_bind_address);
-    Ok(UdpSocket::bind(&local_bind_address)?)
+    Ok(UdpSocket::bind(local_bind_address)?)
+}
+
+/// Asks the router to forward a port to the socket, if enabled.
+/// Without a mapping, the peers rely on hole punching as usual.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket
+/// * `enabled` - Whether `--port-mapping` was passed
+///
+/// # Returns
+///
+/// `Option<PortMapping>` - The mapping, removed from the router when dropped
+pub(crate) fn map_port(socket: &UdpSocket, enabled: bool) -> Option<PortMapping> {
+    if !enabled {
+        return None;
+    }
+    match PortMapping::create(socket) {
+        Ok(mapping) => {
+            status!(
+                "{} Mapped port {} on the router via {}",
+                success_mark(),
+                style(mapping.external_port).cyan(),
+                mapping.protocol()
+            );
+            Some(mapping)
+        }
+        Err(e) => {
+            status!("{} {}, relying on hole punching", style("[~]").bold().yellow(), e);
+            None
+        }
+    }
 }
 
 /// Connects the UDP socket to the relay server
@@ -118,11 +1944,95 @@ fn bind_socket() -> Result<UdpSocket> {
 ///
 /// # Errors
 ///
-/// Returns `NudgeError::Io` if connection fails
-fn connect_to_relay_server(socket: &UdpSocket, root_opts: &RootOpts) -> Result<()> {
+/// Returns `NudgeError::ResolveFailed` if the relay host can't be resolved, `NudgeError::Io` if connection fails
+pub(crate) fn connect_to_relay_server(socket: &UdpSocket, root_opts: &RootOpts) -> Result<()> {
     let relay_address = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
-    debug!("Connecting to relay-server: {}...", relay_address);
-    Ok(socket.connect(&relay_address)?)
+    connect_to_relay(socket, &relay_address, root_opts)
+}
+
+/// Connects the socket to a relay, resolving its host name with the resolver selected with `--doh`
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket
+/// * `relay_address` - Address of the relay as `<host>:<port>`
+/// * `root_opts` - Root options selecting the resolver
+///
+/// # Errors
+///
+/// Returns `NudgeError::ResolveFailed` if the relay host can't be resolved, `NudgeError::Io` if connection fails
+pub(crate) fn connect_to_relay(socket: &UdpSocket, relay_address: &str, root_opts: &RootOpts) -> Result<()> {
+    let resolver = resolver(root_opts.doh.as_deref())?;
+    let mut addr = resolve_address(resolver.as_ref(), relay_address)?;
+    if root_opts.tunnel {
+        addr = tunnel::open(socket.local_addr()?.port(), addr)?;
+    }
+    debug!(target: "relay_client", "Connecting to relay-server: {} ({})...", relay_address, addr);
+    Ok(socket.connect(addr)?)
+}
+
+/// Requests a ticket for the session from the relay and writes it to a file,
+/// so it can be handed to the receiver out-of-band (e.g. as a QR code)
+///
+/// # Arguments
+///
+/// * `socket` - The socket connected to the relay
+/// * `relay` - Address of the relay as "host:port", which is stored in the ticket
+/// * `passphrase` - Passphrase of the registered session
+/// * `ticket_file` - Path of the file to write the ticket to
+fn write_ticket(socket: &UdpSocket, relay: &str, passphrase: &Passphrase<'static>, ticket_file: &Path) -> Result<()> {
+    let ticket_message: X2STicketMessage = request(socket, "S2X_RT", &S2XRequestTicketMessage {
+        passphrase: passphrase.clone(),
+        relay: relay.to_string(),
+    }, "X2S_TKT")?;
+    std::fs::write(ticket_file, format!("{}\n", ticket_message.ticket))?;
+    status!(
+        "{} Ticket written to {}",
+        success_mark(),
+        style(ticket_file.display()).yellow()
+    );
+    Ok(())
+}
+
+/// Determines how many bytes of a device are sent. Block devices are sent up to their capacity (found by seeking
+/// to the end) unless --size-hint asks for less, character devices only end where --size-hint says.
+///
+/// # Arguments
+///
+/// * `file` - The opened device, rewound to the start again
+/// * `file_path` - Path of the device, for the messages
+/// * `kind` - Whether it's a block or character device
+/// * `send_opts` - Send options containing --allow-device and --size-hint
+///
+/// # Errors
+///
+/// Returns `NudgeError::DeviceNotAllowed` without --allow-device, `NudgeError::DeviceSizeUnknown` for a character
+/// device without --size-hint, and `NudgeError::SizeHintTooLarge` if the hint exceeds the capacity of a block device
+fn device_size(file: &mut File, file_path: &str, kind: DeviceKind, send_opts: &SendOpts) -> Result<u64> {
+    if !send_opts.allow_device {
+        return Err(NudgeError::DeviceNotAllowed(file_path.to_string()));
+    }
+    let size = match kind {
+        DeviceKind::Character => send_opts.size_hint
+            .ok_or_else(|| NudgeError::DeviceSizeUnknown(file_path.to_string()))?,
+        DeviceKind::Block => {
+            let capacity = file.seek(SeekFrom::End(0))?;
+            file.rewind()?;
+            match send_opts.size_hint {
+                Some(size_hint) if size_hint > capacity => {
+                    return Err(NudgeError::SizeHintTooLarge(file_path.to_string(), size_hint, capacity));
+                }
+                size_hint => size_hint.unwrap_or(capacity),
+            }
+        }
+    };
+    status!(
+        "{} Sending {} of the device {}",
+        style("[~]").bold().yellow(),
+        format_bytes(size),
+        style(file_path).cyan()
+    );
+    Ok(size)
 }
 
 /// Computes the hash of the file if not skipped
@@ -131,84 +2041,218 @@ fn connect_to_relay_server(socket: &UdpSocket, root_opts: &RootOpts) -> Result<(
 ///
 /// * `skip_hash` - Boolean flag to skip hashing
 /// * `file` - Mutable reference to the file to be hashed
+/// * `memory_profile` - Memory profile limiting the hash buffer size
 ///
 /// # Errors
 ///
 /// Returns `NudgeError::Io` if hashing or seeking fails
-fn compute_file_hash(skip_hash: bool, file: &mut File) -> Result<AnonymousString> {
+pub(crate) fn compute_file_hash(skip_hash: bool, file: &mut File, memory_profile: &MemoryProfile) -> Result<AnonymousString> {
     if skip_hash {
         Ok(AnonymousString(None))
     } else {
-        debug!("Creating hash of file...");
-        let hash = hash_file_and_seek(file)?;
+        debug!(target: "crypto", "Creating hash of file...");
+        let hash = hash_file_and_seek(file, memory_profile.hash_buffer_size)?;
         file.seek(std::io::SeekFrom::Start(0))?;
         Ok(AnonymousString(Some(hash)))
     }
 }
 
+/// Computes the hash of the file, reusing the hash of an earlier send if the file didn't change since.
+/// A cache which can't be written is only logged, the transfer doesn't depend on it.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if hashing or seeking fails
+fn compute_cached_file_hash(
+    file_path: &str,
+    file: &mut File,
+    metadata: &Metadata,
+    memory_profile: &MemoryProfile,
+) -> Result<AnonymousString> {
+    let (Some(mut cache), Some(identity)) = (HashCache::open(), FileIdentity::of(Path::new(file_path), metadata)) else {
+        return compute_file_hash(false, file, memory_profile);
+    };
+    let now = current_unix_millis();
+    if let Some(hash) = cache.lookup(&identity, now) {
+        debug!(target: "crypto", "Reusing the cached hash of {}", file_path);
+        status!("{} File unchanged since the last send, reusing its hash", style("[~]").bold().yellow());
+        if let Err(e) = cache.save() {
+            warn!(target: "io", "Cannot update the hash cache: {}", e);
+        }
+        return Ok(AnonymousString(Some(hash)));
+    }
+
+    let hash = compute_file_hash(false, file, memory_profile)?;
+    if let AnonymousString(Some(hash)) = &hash {
+        cache.insert(identity, hash.clone(), now);
+        if let Err(e) = cache.save() {
+            warn!(target: "io", "Cannot update the hash cache: {}", e);
+        }
+    }
+    Ok(hash)
+}
 
 /// Sends the file to the peer in chunks
 ///
 /// # Arguments
 ///
-/// * `socket` - The UDP socket
+/// * `safe_connection` - The connection to the receiver
 /// * `file` - Mutable reference to the file to be sent
-/// * `send_opts` - Send options containing delay, chunk size, etc.
-/// * `file_size` - Size of the file to be sent
+/// * `offer` - The file and how it's sent, with the number of read retries and the bandwidth limit
+/// * `tuning` - Chunk size and delay of the transfer
+/// * `extents` - Data regions of a sparse file, only these are sent (optional)
+/// * `compression` - How the data stream is compressed, if it is
+/// * `offset` - Bytes of the file the receiver kept from an interrupted download, sent from there on
+/// * `peer` - The host of the receiver
+///
+/// # Returns
+///
+/// `Result<PeerTransfer>` - What was sent to the receiver.
 ///
 /// # Errors
 ///
 /// Returns `NudgeError` if any step of the sending process fails
-fn send_file(socket: &UdpSocket, file: &mut File, send_opts: &SendOpts, file_size: u64) -> Result<()> {
-    let mut safe_connection = ReliableUdpSocket::new(socket.try_clone()?);
-    println!(
-        "{} Sending {} bytes (chunk-size: {})...",
+#[allow(clippy::too_many_arguments)]
+fn send_file(
+    safe_connection: ReliableUdpSocket,
+    file: &mut File,
+    offer: &Offer,
+    tuning: &TransferTuning,
+    extents: Option<&[Extent]>,
+    compression: Option<Algorithm>,
+    offset: u64,
+    peer: &str,
+) -> Result<PeerTransfer> {
+    let file_size = offer.file_size;
+    // the file is a byte stream, so it can go on in smaller chunks if large packets get lost on the way
+    let mut safe_connection = safe_connection.with_chunk_fallback(UNFRAGMENTED_CHUNK_SIZE as usize);
+    status!(
+        "{} Sending {} (chunk-size: {})...",
         style("[~]").bold().yellow(),
-        file_size,
-        style(format_size(send_opts.chunk_size, DECIMAL)).dim()
+        if offer.streaming { "stdin".to_string() } else { format_bytes(file_size) },
+        style(format_bytes(u64::from(tuning.chunk_size))).dim()
     );
 
-    let progress_bar = new_downloader_progressbar(file_size);
+    // only the data regions of sparse files are sent
+    let (mut reader, data_size): (Box<dyn Read>, u64) = match (extents, offer.stream()) {
+        (Some(extents), _) => {
+            let data_size = data_size(extents);
+            status!(
+                "{} Skipping {} of holes in the sparse file",
+                style("[~]").bold().yellow(),
+                format_bytes(file_size - data_size)
+            );
+            (Box::new(SparseReader::new(file, extents)), data_size)
+        }
+        (None, Some(stream)) => (stream, file_size),
+        // stdin is sent until it ends
+        (None, None) if offer.streaming => (Box::new(file), 0),
+        // devices may hold more than is sent
+        (None, None) => {
+            if offset > 0 {
+                status!(
+                    "{} Resuming the download of the receiver after {}",
+                    style("[~]").bold().yellow(),
+                    format_bytes(offset)
+                );
+                file.seek(SeekFrom::Start(offset))?;
+            }
+            (Box::new(file.take(file_size - offset)), file_size - offset)
+        }
+    };
+    if let Some(algorithm) = compression {
+        status!("{} Compressing the data stream ({})", style("[~]").bold().yellow(), algorithm);
+        reader = compression::compress(reader, algorithm)?;
+    }
+    // the acknowledged data is only an offset in the file if the file itself is sent as it is
+    let session = offer.session.as_ref().filter(|_| extents.is_none() && offer.stream().is_none() && compression.is_none());
 
-    // Used for calculating the total time taken
-    let start_time = current_unix_millis();
+    // the receiver is connected already and waits for the first chunk
+    if let Some(schedule) = &offer.schedule {
+        let start = Instant::now() + schedule.until_start(OffsetDateTime::now_utc());
+        wait_for_schedule(&mut safe_connection, schedule, start)?;
+    }
+
+    let progress_bar = match offer.streaming {
+        true => new_stream_progressbar(),
+        false => new_downloader_progressbar(data_size),
+    };
+
+    // Used for calculating the total time taken, with the monotonic clock in case the wall clock changes meanwhile
+    let start_time = Instant::now();
 
     // Used for updating the progressbar
     let mut bytes_sent: u64 = 0;
 
-    // update progress every 25 KiB
-    let update_progress_rate = (1024 * 25) / send_opts.chunk_size;
-    let mut current_progress = 0;
-
-    let mut buffer: Vec<u8> = vec![0; send_opts.chunk_size as usize];
+    let mut buffer: Vec<u8> = vec![0; tuning.chunk_size as usize];
+    let mut pacer = offer.bandwidth.join(0);
+    let mut io_background = false;
 
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
+    let statistics = loop {
+        follow_background_mode(&offer.bandwidth, &mut io_background);
+        let read_span = span(Stage::Read);
+        let bytes_read = match read_with_retry(&mut reader, &mut buffer, offer.send_opts.read_retries, READ_RETRY_BACKOFF) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                // let the receiver know the file is incomplete, instead of ending the session regularly
+                safe_connection.abort(&format!("Sender cannot read the file: {}", e));
+                return Err(e.into());
+            }
+        };
+        drop(read_span);
         if bytes_read == 0 {
-            progress_bar.finish_with_message("Transfer complete! 🎉");
+            progress_bar.finish_with_message(complete_message());
+            let statistics = safe_connection.statistics();
             safe_connection.end();
-            break;
+            if let Some(session) = offer.session.as_ref() {
+                session.lock().unwrap().complete();
+            }
+            break statistics;
         }
 
         // Send the data from the buffer over the connection
+        if let Some(schedule) = &offer.schedule {
+            wait_for_schedule(&mut safe_connection, schedule, start_time)?;
+        }
+        wait_for_share(&mut safe_connection, &mut pacer, bytes_read, start_time);
         safe_connection.write_and_flush(
             &buffer[..bytes_read],
             false,
-            send_opts.delay,
+            tuning.delay,
         )?;
 
         bytes_sent += bytes_read as u64;
+        progress_bar.set_position(bytes_sent);
+        if let Some(session) = session {
+            let acknowledged = bytes_sent.saturating_sub(safe_connection.unacknowledged_bytes());
+            session.lock().unwrap().record(offset + acknowledged, current_unix_millis());
+        }
 
-        current_progress += 1;
-        if current_progress % update_progress_rate == 0 {
-            progress_bar.set_position(bytes_sent);
+        if let Some(chunk_limit) = safe_connection.chunk_limit().filter(|chunk_limit| buffer.len() > *chunk_limit) {
+            status!(
+                "{} Large packets don't reach the receiver, continuing with chunks of {}",
+                style("[~]").bold().yellow(),
+                style(format_bytes(chunk_limit as u64)).dim()
+            );
+            buffer.truncate(chunk_limit);
         }
-    }
+    };
 
-    println!(
+    status!(
         "{} File sent successfully in {}s!",
-        style("[✔]").bold().green(),
-        (current_unix_millis() - start_time) as f64 / 1000.0
+        success_mark(),
+        start_time.elapsed().as_millis() as f64 / 1000.0
     );
-    Ok(())
+    // the size of a stream is only known to the receiver
+    if compression.is_some() && !offer.streaming {
+        status!(
+            "{} Compressed {} to {}",
+            style("[~]").bold().yellow(),
+            format_bytes(data_size),
+            format_bytes(bytes_sent)
+        );
+    }
+    status!("{} Datagrams: {}", style("[~]").bold().yellow(), statistics);
+    profiling::dump();
+    Ok(PeerTransfer { peer: peer.to_string(), bytes: bytes_sent, duration: start_time.elapsed() })
 }
\ No newline at end of file
@@ -1,32 +1,157 @@
-use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
 use std::str;
+use std::thread;
+use std::time::Duration;
 
 use clap::Parser;
 use crate::commands::RootOpts;
 
 use crate::error::{NudgeError, Result};
 use crate::error::NudgeError::UnknownCommand;
+use crate::utils::blind::BlindSalt;
+use crate::utils::duration::{format_duration, parse_duration};
+use crate::utils::geoip::GeoIpLookup;
 use crate::utils::passphrase::{Passphrase, PassphraseGenerator};
-use crate::utils::{AnonymousString, current_unix_millis};
+use crate::utils::platform::{take_reload_request, watch_reload_signal};
+use crate::utils::relay_config::{RateLimiter, RelayConfig};
+use crate::utils::relay_simulation::{DropRule, RelaySimulation};
+use crate::utils::relay_stats::RelayStatistics;
+use crate::utils::shard::{route_message, shard_for, unwrap_forwarded, wrap_forwarded, GeneratedPassphrases};
+use crate::utils::ticket::TicketPayload;
+use crate::utils::tunnel::{self, Allocations, Listeners};
+use crate::utils::webhook::{WebhookEvent, WebhookEventKind};
+use crate::utils::{current_unix_millis, deterministic, AnonymousString};
 use crate::models::*;
 
+/// How long an accepted session is kept to answer retries of the receiver
+const ACCEPTED_SESSION_GRACE: Duration = Duration::from_secs(30);
+
+/// How long receivers of a session kept open (`send --stay-open`) wait while the sender serves another receiver
+const SENDER_BUSY_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// How often the relay checks for a reload request while no messages arrive
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Parser, Debug)]
-pub struct RelayServerOpts {}
+pub struct RelayServerOpts {
+    /// How long a passphrase stays valid if no receiver connects, e.g. 15m or 1h.
+    /// Senders can request a shorter lifetime with --expire
+    #[clap(long, default_value = "1h", value_parser = parse_duration)]
+    session_ttl: Duration,
+
+    /// Add words to the generated passphrases until each carries at least this many bits of entropy
+    /// (3 words carry about 37 bits, every further one about 12)
+    #[clap(long, value_name = "BITS", default_value = "0", value_parser = clap::value_parser!(u32).range(0..=256))]
+    min_entropy_bits: u32,
+
+    /// Only accept hashed passphrases (clients pass --blind) and store them hashed again with a random salt,
+    /// so the logs of the relay don't reveal usable passphrases
+    #[clap(long)]
+    blind: bool,
+
+    /// JSON file with the TTL, rate limit, allow/deny lists and auth tokens.
+    /// Reloaded on SIGHUP without dropping the registered passphrases
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Also listen for TCP on the relay port, tunneling the datagrams of clients on networks blocking UDP
+    /// (clients pass --tunnel)
+    #[clap(long)]
+    tunnel: bool,
+
+    /// Also accept WebSockets on the relay port (TCP), bridging clients which can't send datagrams, e.g. browsers,
+    /// to the relay and their peer
+    #[clap(long)]
+    websocket: bool,
+
+    /// Test only: shift the ports of the addresses the peers are told about, like a symmetric NAT
+    #[clap(long, hide = true, default_value = "0", allow_hyphen_values = true)]
+    simulate_port_shift: i32,
+
+    /// Test only: hold back the responses introducing the peers to each other (in milliseconds)
+    #[clap(long, hide = true, default_value = "0")]
+    simulate_match_delay_ms: u64,
+
+    /// Test only: drop messages from and to clients with this prefix, e.g. X2R_ASC (all) or S2X_RP:2 (the first two)
+    #[clap(long, hide = true, value_parser = DropRule::parse)]
+    simulate_drop: Vec<DropRule>,
 
-pub fn run(root_opts: &RootOpts, _: &RelayServerOpts) -> Result<()> {
-    let passphrase_generator = PassphraseGenerator::new()?;
-    let mut client_map = HashMap::new();
+    /// MaxMind Country (or City) database to show receivers the country of the sender (optional)
+    #[cfg(feature = "geoip")]
+    #[clap(long)]
+    geoip_country_db: Option<PathBuf>,
+
+    /// MaxMind ASN database to show receivers the network of the sender (optional)
+    #[cfg(feature = "geoip")]
+    #[clap(long)]
+    geoip_asn_db: Option<PathBuf>,
+}
+
+pub fn run(root_opts: &RootOpts, server_opts: &RelayServerOpts) -> Result<()> {
+    let passphrase_generator = PassphraseGenerator::new()?.with_min_entropy(server_opts.min_entropy_bits);
+    let mut client_map = BTreeMap::new();
+    let geoip = open_geoip(server_opts)?;
+    let mut config = load_config(server_opts)?;
+    let mut rate_limiter = RateLimiter::default();
+    let mut generated_passphrases = GeneratedPassphrases::default();
+    let mut statistics = RelayStatistics::new(current_unix_millis());
+    let simulation = RelaySimulation::new(
+        server_opts.simulate_port_shift,
+        Duration::from_millis(server_opts.simulate_match_delay_ms),
+        &server_opts.simulate_drop,
+    );
+    if simulation.is_active() {
+        warn!("Simulating a misbehaving relay for tests: {:?}", simulation);
+    }
 
     let bind_addr = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
     info!("Starting server on {}", bind_addr);
+    info!(
+        "Generating passphrases of {} words out of {} ({:.1} bits of entropy)",
+        passphrase_generator.word_count(),
+        passphrase_generator.list_size(),
+        passphrase_generator.entropy_bits(),
+    );
+    if config.blind_salt.is_some() {
+        info!("Blind mode: only accepting hashed passphrases, senders generate them");
+    } else if deterministic::is_enabled() {
+        warn!("Generated passphrases are predictable with --deterministic, never expose this relay");
+    }
 
     let listener = UdpSocket::bind(&bind_addr)?;
+    let allocations = Allocations::default();
+    if server_opts.tunnel || server_opts.websocket {
+        let tunnel_listener = TcpListener::bind(&bind_addr)?;
+        let listeners = Listeners { tunnel: server_opts.tunnel, websocket: server_opts.websocket };
+        let accepted = match (server_opts.tunnel, server_opts.websocket) {
+            (true, true) => "tunnels and WebSockets",
+            (true, false) => "tunnels",
+            _ => "WebSockets",
+        };
+        info!("Accepting {} on {} (TCP)", accepted, bind_addr);
+        let (relay_port, allocations) = (listener.local_addr()?.port(), allocations.clone());
+        thread::spawn(move || tunnel::serve(tunnel_listener, relay_port, allocations, listeners));
+    }
+    // wake up regularly to pick up reload requests
+    listener.set_read_timeout(Some(RELOAD_POLL_INTERVAL))?;
+    watch_reload_signal();
 
-    let mut buf = [0u8; 1024];
+    // leaves room for the address in front of forwarded messages
+    let mut buf = [0u8; 2048];
 
     loop {
-        let (len, addr) = listener.recv_from(&mut buf)?;
+        if take_reload_request() {
+            reload_config(&mut config, server_opts);
+        }
+
+        let (len, addr) = match listener.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => continue,
+            Err(e) => return Err(e.into()),
+        };
         info!("Received {} bytes from {}", len, addr);
 
         let received_str = match str::from_utf8(&buf[..len]) {
@@ -36,14 +161,71 @@ pub fn run(root_opts: &RootOpts, _: &RelayServerOpts) -> Result<()> {
                 continue;
             }
         };
-        info!("({}) Received Data: {:?}", addr, received_str);
+        // the hashed passphrases are as good as the passphrases for claiming a session, and admin tokens are secret
+        if config.blind_salt.is_some() || received_str.starts_with("A2X_") {
+            info!("({}) Received {}", addr, received_str.split_whitespace().next().unwrap_or_default());
+        } else {
+            info!("({}) Received Data: {:?}", addr, received_str);
+        }
 
-        match handle_message(received_str, &listener, &addr, &passphrase_generator, &mut client_map) {
+        // responses of the shards are passed on to their clients
+        if config.shards.contains(&addr) {
+            pass_to_client(&listener, &addr, received_str);
+            continue;
+        }
+
+        // messages forwarded by a front relay are handled as if the client sent them
+        let (addr, received_str, front) = match unwrap_forwarded(received_str) {
+            Some((client_addr, message)) if config.is_trusted_front(addr.ip()) => (client_addr, message, Some(addr)),
+            Some(_) => {
+                warn!("({}) Dropped forwarded message from untrusted relay", addr);
+                continue;
+            }
+            None => (addr, received_str, None),
+        };
+        if simulation.drops(received_str) {
+            info!("({}) Dropped the message (simulation)", addr);
+            continue;
+        }
+        let reply = ReplySocket { socket: &listener, front, simulation: &simulation };
+
+        let now = current_unix_millis();
+        rate_limiter.prune(now);
+        // tunneled clients are held to the limits of their own address, not the one of their allocation
+        let client_addr = allocations.client_of(&addr).map_or(addr, |ip| SocketAddr::new(ip, addr.port()));
+        if let Err(e) = check_client(&config, &mut rate_limiter, &client_addr, now) {
+            warn!("({}) Rejected message: {}", addr, e);
+            if let Err(e) = send_error(&reply, &addr, &e.to_string()) {
+                error!("Cannot even send the error to the client: {}", e);
+            }
+            continue;
+        }
+
+        let result = if config.shards.is_empty() {
+            prune_expired_sessions(&mut client_map, &mut statistics, now);
+            handle_message(
+                received_str, &reply, &addr, &passphrase_generator, &mut client_map, &mut statistics, geoip.as_ref(),
+                &config,
+            )
+        } else {
+            generated_passphrases.prune(now);
+            forward_to_shard(
+                received_str, &reply, &addr, &passphrase_generator, &mut generated_passphrases, &config.shards, now,
+            )
+        };
+
+        match result {
             Ok(_) => info!("Handled message without error"),
+            Err(NudgeError::RelayBusy(retry_after_secs)) => {
+                warn!("({}) Relay is busy, asked the sender to retry in {}s", addr, retry_after_secs);
+                if let Err(e) = send_busy(&reply, &addr, retry_after_secs) {
+                    error!("Cannot even send the busy message to the client: {}", e);
+                }
+            }
             Err(e) => {
                 warn!("Handled message with error: {}", e);
 
-                match send_error(&listener, &addr, &e.to_string()) {
+                match send_error(&reply, &addr, &e.to_string()) {
                     Ok(_) => info!("Sent error message to client"),
                     Err(e) => error!("Cannot even send the error to the client: {}", e),
                 }
@@ -52,140 +234,684 @@ pub fn run(root_opts: &RootOpts, _: &RelayServerOpts) -> Result<()> {
     }
 }
 
+/// Sends responses to clients, through the front relay if the client's message was forwarded by one
+struct ReplySocket<'a> {
+    socket: &'a UdpSocket,
+    front: Option<SocketAddr>,
+    simulation: &'a RelaySimulation,
+}
+
+impl ReplySocket<'_> {
+    fn send_to(&self, response: &[u8], addr: &SocketAddr) -> std::io::Result<usize> {
+        let (datagram, target) = match &self.front {
+            Some(front) => (wrap_forwarded(addr, response), *front),
+            None => (response.to_vec(), *addr),
+        };
+
+        let text = String::from_utf8_lossy(response);
+        if self.simulation.drops(&text) {
+            info!("({}) Dropped the response (simulation)", addr);
+            return Ok(datagram.len());
+        }
+        let delay = self.simulation.delay_of(&text);
+        if !delay.is_zero() {
+            debug!("({}) Holding back the response for {:?} (simulation)", addr, delay);
+            let socket = self.socket.try_clone()?;
+            thread::spawn(move || {
+                thread::sleep(delay);
+                if let Err(e) = socket.send_to(&datagram, target) {
+                    error!("Cannot send the held back response to {}: {}", target, e);
+                }
+            });
+            return Ok(response.len());
+        }
+        self.socket.send_to(&datagram, target)
+    }
+}
+
+/// Forwards a client message to the shard responsible for its passphrase (front relay only).
+fn forward_to_shard(
+    received_str: &str,
+    listener: &ReplySocket,
+    addr: &SocketAddr,
+    passphrase_generator: &PassphraseGenerator,
+    generated_passphrases: &mut GeneratedPassphrases,
+    shards: &[SocketAddr],
+    now: u64,
+) -> Result<()> {
+    let (passphrase, message) = route_message(received_str, addr, passphrase_generator, generated_passphrases, now)?;
+    let shard = shard_for(&passphrase, shards).expect("front relays have at least one shard");
+    debug!("({}) Forwarding message to shard {}", addr, shard);
+    listener.socket.send_to(&wrap_forwarded(addr, message.as_bytes()), shard)?;
+    Ok(())
+}
+
+/// Passes the response of a shard on to the client it's meant for (front relay only).
+fn pass_to_client(listener: &UdpSocket, shard: &SocketAddr, received_str: &str) {
+    let Some((client_addr, response)) = unwrap_forwarded(received_str) else {
+        warn!("({}) Received unexpected message from shard", shard);
+        return;
+    };
+    if let Err(e) = listener.send_to(response.as_bytes(), client_addr) {
+        error!("Cannot pass the response of shard {} to {}: {}", shard, client_addr, e);
+    }
+}
+
+/// Reads the configuration file, or uses the command line options if none was given.
+fn load_config(server_opts: &RelayServerOpts) -> Result<RelayConfig> {
+    let mut config = match &server_opts.config {
+        Some(path) => RelayConfig::load(path, server_opts.session_ttl)?,
+        None => RelayConfig::new(server_opts.session_ttl),
+    };
+    config.blind_salt = server_opts.blind.then(BlindSalt::random);
+    Ok(config)
+}
+
+/// Replaces the configuration with the one from the configuration file.
+/// If the file is invalid, the current configuration is kept.
+/// Registered passphrases keep the TTL they were registered with.
+fn reload_config(config: &mut RelayConfig, server_opts: &RelayServerOpts) {
+    let Some(path) = &server_opts.config else {
+        warn!("Reload requested, but the relay was started without --config");
+        return;
+    };
+    match RelayConfig::load(path, server_opts.session_ttl) {
+        Ok(new_config) => {
+            info!("Reloaded configuration from {}", path.display());
+            debug!("Configuration: {:?}", new_config);
+            let previous = std::mem::replace(config, new_config);
+            config.blind_salt = previous.blind_salt;
+            config.inherit_ticket_key(previous);
+        }
+        Err(e) => error!("Cannot reload configuration, keeping the current one: {}", e),
+    }
+}
+
+/// Checks whether the client may use the relay, before its message is parsed.
+///
+/// # Errors
+///
+/// Returns `NudgeError::AddressNotAllowed` if the address is blocked
+/// or `NudgeError::RateLimited` if it sent too many messages.
+fn check_client(config: &RelayConfig, rate_limiter: &mut RateLimiter, addr: &SocketAddr, now: u64) -> Result<()> {
+    config.check_address(addr.ip())?;
+    match config.rate_limit {
+        Some(limit) => rate_limiter.check(addr.ip(), limit, now),
+        None => Ok(()),
+    }
+}
+
+/// Removes all passphrases whose lifetime has ended.
+///
+/// # Arguments
+///
+/// * `client_map` - The passphrases known to the relay.
+/// * `statistics` - Counts the sessions expiring without being claimed.
+/// * `now` - The current time in unix millis.
+fn prune_expired_sessions(
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    statistics: &mut RelayStatistics,
+    now: u64,
+) {
+    client_map.retain(|passphrase, file_info| {
+        let alive = file_info.expires_at > now;
+        if !alive {
+            debug!("Passphrase {} expired", passphrase);
+            statistics.record_removal(file_info);
+        }
+        alive
+    });
+}
+
+/// Returns how long a session stays valid.
+/// The sender can only shorten the relay's TTL, never extend it.
+///
+/// # Arguments
+///
+/// * `session_ttl` - The default TTL of the relay.
+/// * `requested_secs` - The lifetime requested by the sender (optional).
+fn effective_ttl(session_ttl: Duration, requested_secs: Option<u64>) -> Duration {
+    match requested_secs {
+        Some(secs) => session_ttl.min(Duration::from_secs(secs)),
+        None => session_ttl,
+    }
+}
+
+#[cfg(feature = "geoip")]
+fn open_geoip(server_opts: &RelayServerOpts) -> Result<Option<GeoIpLookup>> {
+    let geoip = GeoIpLookup::open(
+        server_opts.geoip_country_db.as_deref(),
+        server_opts.geoip_asn_db.as_deref(),
+    )?;
+    if geoip.is_some() {
+        info!("GeoIP lookup enabled");
+    }
+    Ok(geoip)
+}
+
+#[cfg(not(feature = "geoip"))]
+fn open_geoip(_: &RelayServerOpts) -> Result<Option<GeoIpLookup>> {
+    Ok(None)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handle_message(
     received_str: &str,
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     addr: &SocketAddr,
     passphrase_generator: &PassphraseGenerator,
-    client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    statistics: &mut RelayStatistics,
+    geoip: Option<&GeoIpLookup>,
+    config: &RelayConfig,
 ) -> Result<()> {
+    // a bare prefix has no payload, which the handlers refuse as invalid JSON
     match received_str.split_whitespace().next() {
         // Sender -> Server; Request Passphrase
         Some("S2X_RP") => handle_sender_request_passphrase_message(
-            &listener, &addr, &received_str[7..], &passphrase_generator, client_map,
+            listener, addr, received_str.get(7..).unwrap_or_default(), passphrase_generator, client_map, statistics, geoip, config,
         ),
         // Receiver -> Server; Request File Info
         Some("R2X_RFI") => handle_receiver_request_file_info(
-            &listener, &addr, &received_str[8..], client_map,
+            listener, addr, received_str.get(8..).unwrap_or_default(), client_map, statistics, config,
+        ),
+        // Receiver -> Server; Wake the sender of an offer
+        Some("R2X_WAKE") => handle_receiver_wake_sender(
+            listener, addr, received_str.get(9..).unwrap_or_default(), client_map, config,
         ),
         // Receiver -> Server; Accept Connection
         Some("R2X_RSC") => handle_receiver_accept(
-            &listener, &addr, &received_str[8..], client_map,
+            listener, addr, received_str.get(8..).unwrap_or_default(), client_map, statistics, config,
+        ),
+        // Sender -> Server; Request Ticket
+        Some("S2X_RT") => handle_sender_request_ticket(
+            listener, addr, received_str.get(7..).unwrap_or_default(), client_map, config,
+        ),
+        // Sender -> Server; Heartbeat
+        Some("S2X_HB") => handle_sender_heartbeat(
+            listener, addr, received_str.get(7..).unwrap_or_default(), client_map, config,
+        ),
+        // Sender -> Server; Ready for the next receiver
+        Some("S2X_RDY") => handle_sender_ready(
+            listener, addr, received_str.get(8..).unwrap_or_default(), client_map, config,
+        ),
+        // Admin -> Server; Request Statistics
+        Some("A2X_STATS") => handle_admin_request_statistics(
+            listener, addr, received_str.get(10..).unwrap_or_default(), client_map, statistics, config,
         ),
         _ => Err(UnknownCommand)
     }
 }
 
 /// Handle a SEND_REQ packet
+#[allow(clippy::too_many_arguments)]
 fn handle_sender_request_passphrase_message(
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     addr: &SocketAddr,
     payload_str: &str,
     passphrase_generator: &PassphraseGenerator,
-    client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    statistics: &mut RelayStatistics,
+    geoip: Option<&GeoIpLookup>,
+    config: &RelayConfig,
 ) -> Result<()> {
     let payload: S2XRequestPassphraseMessage = serde_json::from_str(payload_str)?;
+    config.check_token(payload.relay_token.as_deref())?;
+
+    // a retry of a registration which was already answered gets the same passphrase, and a sender which restarted
+    // with its previous registration ID (`send --registration-id`) takes over its session from its new address
+    let registration = find_registration(client_map, payload.registration_id.as_deref(), &payload.file_hash);
+    if let Some((passphrase, file_info)) = registration {
+        if file_info.sender_addr == *addr {
+            debug!("({}) Sender retried registration of {}", addr, passphrase);
+        } else {
+            info!("({}) Sender of {} registered again, taking over the session from {}", addr, passphrase, file_info.sender_addr);
+            file_info.sender_addr = *addr;
+            file_info.sender_mapped_port = payload.mapped_port;
+        }
+        let now = current_unix_millis();
+        // the receiver may have restarted too, whichever receiver claims the session next gets the rest of the file
+        if payload.resume && file_info.receiver_addr.is_some() {
+            debug!("({}) Sender resumes {}, releasing it from {:?}", addr, passphrase, file_info.receiver_addr);
+            file_info.receiver_addr = None;
+            file_info.pending_connection = None;
+            file_info.expires_at = file_info.expires_at.max(now + effective_ttl(config.session_ttl, payload.expire_secs).as_millis() as u64);
+        }
+        // the receiver which accepted the session waits for the sender to come back, the sender needs time to connect
+        if file_info.pending_connection.is_some() && file_info.on_match.is_remove() {
+            file_info.expires_at = file_info.expires_at.max(now + ACCEPTED_SESSION_GRACE.as_millis() as u64);
+        }
+        let remaining_ttl = Duration::from_millis(file_info.expires_at.saturating_sub(now));
+        // a blind relay only knows the key, the sender gets back what it sent
+        let passphrase = payload.passphrase.unwrap_or_else(|| passphrase.clone());
+        send_passphrase_to_sender(listener, addr, passphrase, remaining_ttl, file_info.on_match, file_info.offer_only)?;
+        // a receiver accepted the session meanwhile, the sender may have missed its request while restarting
+        if let Some(connection) = &file_info.pending_connection {
+            debug!("({}) Passing on the connection request of {} again", addr, connection.receiver_addr);
+            send_sender_connect_to_receiver(listener, addr, connection)?;
+        }
+        return Ok(());
+    }
+
+    // shed load instead of registering sessions the relay can't keep up with
+    if config.max_sessions.is_some_and(|max_sessions| client_map.len() >= max_sessions) {
+        return Err(NudgeError::RelayBusy(config.busy_retry_after.as_secs()));
+    }
+
+    let (passphrase, key) = match payload.passphrase {
+        Some(passphrase) => {
+            passphrase.validate_chosen()?;
+            let key = config.session_key(&passphrase)?;
+            if client_map.contains_key(&key) {
+                return Err(NudgeError::PassphraseInUse);
+            }
+            (passphrase, key)
+        }
+        // a blind relay would see the passphrases it generates
+        None if config.blind_salt.is_some() => return Err(NudgeError::BlindPassphraseRequired),
+        None => {
+            let passphrase = passphrase_generator.generate().ok_or(NudgeError::PassphraseGenerationError)?;
+            (passphrase.clone(), passphrase)
+        }
+    };
+
+    let ttl = effective_ttl(config.session_ttl, payload.expire_secs);
+    let created_at = current_unix_millis();
 
     let file_info = FileInfo {
         file_size: payload.file_size,
         file_name: payload.file_name,
         file_hash: payload.file_hash,
-        created_at: current_unix_millis(),
+        created_at,
+        expires_at: created_at + ttl.as_millis() as u64,
         sender_host: payload.sender_host,
         sender_addr: *addr,
+        sender_mapped_port: payload.mapped_port,
+        sender_geo: geoip.and_then(|geoip| geoip.lookup(addr.ip())),
+        kind: payload.kind,
+        previewable: payload.previewable,
+        file_mode: payload.file_mode,
+        sparse: payload.sparse,
+        directory: payload.directory,
+        file_count: payload.file_count,
+        receiver_addr: None,
+        pending_connection: None,
+        registration_id: payload.registration_id,
+        announce: payload.announce,
+        lookups: 0,
+        matched_at: None,
+        on_match: payload.on_match,
+        offer_only: payload.offer_only,
+        compress: payload.compress,
+        hash_algorithm: payload.hash_algorithm,
+        streaming: payload.streaming,
+        schedule: payload.schedule,
+        requested_by: None,
     };
 
-    let passphrase = passphrase_generator.generate()
-        .ok_or(NudgeError::PassphraseGenerationError)?;
+    announce(config, WebhookEventKind::Registered, &key, &file_info, None);
+    statistics.record_registration();
+    client_map.insert(key, file_info);
+    send_passphrase_to_sender(listener, addr, passphrase, ttl, payload.on_match, payload.offer_only)
+}
+
+/// Posts the event to the webhook of the relay, if there is one and the sender opted in.
+fn announce(
+    config: &RelayConfig,
+    event: WebhookEventKind,
+    passphrase: &Passphrase<'static>,
+    file_info: &FileInfo,
+    receiver_host: Option<AnonymousString>,
+) {
+    if let Some(webhook) = config.webhook.as_ref().filter(|_| file_info.announce) {
+        debug!("Announcing {:?} of {} to the webhook", event, passphrase);
+        webhook.notify(WebhookEvent::new(event, passphrase, file_info, receiver_host));
+    }
+}
 
-    client_map.insert(passphrase.clone(), file_info);
-    send_passphrase_to_sender(listener, addr, passphrase)
+/// Finds the session a sender registered with the given registration ID, for the same file.
+/// The sender may have restarted since, and register from another address.
+fn find_registration<'a>(
+    client_map: &'a mut BTreeMap<Passphrase<'static>, FileInfo>,
+    registration_id: Option<&str>,
+    file_hash: &AnonymousString,
+) -> Option<(&'a Passphrase<'static>, &'a mut FileInfo)> {
+    let registration_id = registration_id?;
+    client_map.iter_mut().find(|(_, file_info)| {
+        file_info.registration_id.as_deref() == Some(registration_id) && file_info.file_hash == *file_hash
+    })
 }
 
 fn send_passphrase_to_sender(
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     addr: &SocketAddr,
     passphrase: Passphrase<'static>,
+    ttl: Duration,
+    on_match: MatchPolicy,
+    offer_only: bool,
 ) -> Result<()> {
-    let response_payload = X2SPassphraseProvidedMessage { passphrase };
+    let response_payload = X2SPassphraseProvidedMessage {
+        passphrase,
+        expires_in_secs: Some(ttl.as_secs()),
+        on_match: Some(on_match),
+        offer_only,
+    };
     let response = format!("X2S_PPM {}\n", serde_json::to_string(&response_payload)?);
     listener.send_to(response.as_bytes(), addr)?;
     Ok(())
 }
 
 fn handle_receiver_request_file_info(
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     addr: &SocketAddr,
     payload_str: &str,
-    client_map: &HashMap<Passphrase<'static>, FileInfo>,
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    statistics: &mut RelayStatistics,
+    config: &RelayConfig,
 ) -> Result<()> {
     let payload: R2XRequestFileInfoMessage = serde_json::from_str(payload_str)?;
 
-    if let Some(file_info) = client_map.get(&payload.passphrase) {
-        send_file_info_to_receiver(listener, addr, file_info)
-    } else {
-        Err(NudgeError::PassphraseNotFound)
+    match client_map.get_mut(&config.session_key(&payload.passphrase)?) {
+        // sessions which were already accepted are only kept to answer retries
+        Some(file_info) if file_info.receiver_addr.is_none() => {
+            check_ticket(config, &payload.passphrase, file_info, payload.ticket.as_deref())?;
+            statistics.record_lookup(file_info);
+            send_file_info_to_receiver(listener, addr, file_info)
+        }
+        // the sender of a session kept open serves one receiver at a time, the others come back later
+        Some(file_info) if file_info.on_match == MatchPolicy::Keep => Err(NudgeError::RelayBusy(SENDER_BUSY_RETRY_AFTER.as_secs())),
+        _ => Err(NudgeError::PassphraseNotFound),
+    }
+}
+
+/// Checks the ticket a receiver presented for a session.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidTicket` if the ticket is invalid or was issued for another session,
+/// or `NudgeError::TicketRequired` if the relay requires tickets for files and the receiver has none.
+fn check_ticket(
+    config: &RelayConfig,
+    passphrase: &Passphrase,
+    file_info: &FileInfo,
+    ticket: Option<&str>,
+) -> Result<()> {
+    match ticket {
+        Some(ticket) => {
+            let payload = config.ticket_key.verify(ticket, current_unix_millis())?;
+            if payload.passphrase != *passphrase {
+                return Err(NudgeError::InvalidTicket("issued for another session".to_string()));
+            }
+            Ok(())
+        }
+        // only `nudge get` can redeem tickets, the other commands meet via passphrase as usual
+        None if config.require_tickets && file_info.kind.is_file() => Err(NudgeError::TicketRequired),
+        None => Ok(()),
     }
 }
 
 fn send_file_info_to_receiver(
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     addr: &SocketAddr,
     file_info: &FileInfo,
 ) -> Result<()> {
-    let response = format!("X2R_AFI {}\n", serde_json::to_string(file_info)?);
+    let file_info = FileInfo { sender_addr: listener.simulation.mangle(&file_info.sender_addr), ..file_info.clone() };
+    let response = format!("X2R_AFI {}\n", serde_json::to_string(&file_info)?);
     listener.send_to(response.as_bytes(), addr)?;
     Ok(())
 }
 
 fn handle_receiver_accept(
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     addr: &SocketAddr,
     payload_str: &str,
-    client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    statistics: &mut RelayStatistics,
+    config: &RelayConfig,
 ) -> Result<()> {
     let payload: R2XRequestSenderConnectionMessage = serde_json::from_str(payload_str)?;
 
     // check if the passphrase exists
-    let file_info = match client_map.get_mut(&payload.passphrase) {
+    let key = config.session_key(&payload.passphrase)?;
+    let file_info = match client_map.get_mut(&key) {
         Some(file_info) => file_info,
         None => return Err(NudgeError::PassphraseNotFound),
     };
+    check_ticket(config, &payload.passphrase, file_info, payload.ticket.as_deref())?;
 
-    // make sure the file hash matches
-    if file_info.file_hash == payload.file_hash {
-        info!(
-            "({}) File hash matches, sending sender ({}) to receiver ({})",
-            addr, file_info.sender_addr, addr
-        );
+    // receivers which don't know offers are let in once the sender came online
+    if file_info.offer_only {
+        return ask_sender_online(listener, config, &key, file_info, payload.receiver_host);
+    }
 
-        // Clone the necessary info before removing the entry
-        let sender_addr = file_info.sender_addr;
+    // a retry of the receiver which accepted the session is answered again,
+    // any other receiver is too late
+    match file_info.receiver_addr {
+        Some(receiver_addr) if receiver_addr == *addr => {
+            debug!("({}) Receiver accepted the session again, repeating the responses", addr);
+        }
+        Some(_) if file_info.on_match == MatchPolicy::Keep => return Err(NudgeError::RelayBusy(SENDER_BUSY_RETRY_AFTER.as_secs())),
+        Some(_) => return Err(NudgeError::PassphraseNotFound),
+        None if file_info.file_hash == payload.file_hash => {
+            info!(
+                "({}) File hash matches, sending sender ({}) to receiver ({})",
+                addr, file_info.sender_addr, addr
+            );
+            let now = current_unix_millis();
+            file_info.receiver_addr = Some(*addr);
+            match file_info.on_match {
+                // keep the session for a moment instead of removing it, in case the receiver retries
+                MatchPolicy::Remove => file_info.expires_at = now + ACCEPTED_SESSION_GRACE.as_millis() as u64,
+                // stays until it expires, the sender reopens it for the next receiver
+                MatchPolicy::Keep => {}
+            }
+            let wait = statistics.record_match(file_info, now);
+            info!(
+                "({}) Session claimed after {} and {} lookups",
+                addr, format_duration(Duration::from_millis(wait)), file_info.lookups
+            );
+            announce(
+                config, WebhookEventKind::Matched, &key, file_info, Some(payload.receiver_host.clone()),
+            );
+        }
+        // make sure the file hash matches
+        None => return Err(NudgeError::PassphraseNotFound),
+    }
 
-        client_map.remove(&payload.passphrase);
+    let connection = connection_request(listener, addr, &payload);
+    send_sender_connect_to_receiver(listener, &file_info.sender_addr, &connection)?;
+    file_info.pending_connection = Some(connection);
+    // the sender may have moved since the receiver looked it up, it reconnects to the current address
+    let accepted = X2RSenderConnectionAcceptedMessage {
+        sender_addr: Some(listener.simulation.mangle(&file_info.sender_addr)),
+        sender_mapped_port: file_info.sender_mapped_port,
+    };
+    let response = format!("X2R_ASC {}\n", serde_json::to_string(&accepted)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
 
-        send_sender_connect_to_receiver(listener, &sender_addr, addr, payload.receiver_host)
-    } else {
-        Err(NudgeError::PassphraseNotFound)
+/// Lets a receiver redeeming the passphrase of an offer (`send --offer-only`) know whether the sender is online.
+fn handle_receiver_wake_sender(
+    listener: &ReplySocket,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    config: &RelayConfig,
+) -> Result<()> {
+    let payload: R2XWakeSenderMessage = serde_json::from_str(payload_str)?;
+    let key = config.session_key(&payload.passphrase)?;
+    let file_info = client_map.get_mut(&key).ok_or(NudgeError::PassphraseNotFound)?;
+    check_ticket(config, &payload.passphrase, file_info, payload.ticket.as_deref())?;
+    if file_info.offer_only {
+        return ask_sender_online(listener, config, &key, file_info, payload.receiver_host);
+    }
+    let response = format!("X2R_ONL {}\n", serde_json::to_string(&X2RSenderOnlineMessage {})?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// Passes the request of a receiver on to the sender of an offer, which comes online for it. A sender which
+/// misses the request learns of it with its next heartbeat.
+///
+/// # Errors
+///
+/// Returns `NudgeError::SenderOffline` for the receiver to try again once the sender is online.
+fn ask_sender_online(
+    listener: &ReplySocket,
+    config: &RelayConfig,
+    key: &Passphrase<'static>,
+    file_info: &mut FileInfo,
+    receiver_host: AnonymousString,
+) -> Result<()> {
+    if file_info.requested_by.is_none() {
+        info!("Receiver asked for the offer {}, waking the sender ({})", key, file_info.sender_addr);
+        announce(config, WebhookEventKind::Requested, key, file_info, Some(receiver_host.clone()));
+    }
+    file_info.requested_by = Some(receiver_host);
+    let ping = X2SHeartbeatMessage { requested: true, receiver_host: file_info.requested_by.clone() };
+    let request = format!("X2S_HB {}\n", serde_json::to_string(&ping)?);
+    listener.send_to(request.as_bytes(), &file_info.sender_addr)?;
+    Err(NudgeError::SenderOffline)
+}
+
+/// Creates the request for the sender to connect to the receiver, passing on what the receiver asked for.
+fn connection_request(
+    listener: &ReplySocket,
+    receiver_addr: &SocketAddr,
+    request: &R2XRequestSenderConnectionMessage,
+) -> X2SSenderConnectToReceiverMessage {
+    X2SSenderConnectToReceiverMessage {
+        receiver_addr: listener.simulation.mangle(receiver_addr),
+        receiver_host: request.receiver_host.clone(),
+        receiver_mapped_port: request.mapped_port,
+        preview: request.preview,
+        xattrs: request.xattrs,
+        sparse: request.sparse,
+        manifest: request.manifest,
+        compress: request.compress,
+        zstd: request.zstd,
+        resume_offset: request.resume_offset,
+        encryption_salt: request.encryption_salt.clone(),
+        key_exchange: request.key_exchange,
+        max_chunk_size: request.max_chunk_size,
+        receiver_identity: request.identity,
     }
 }
 
 fn send_sender_connect_to_receiver(
-    listener: &UdpSocket,
+    listener: &ReplySocket,
     sender_addr: &SocketAddr,
-    receiver_addr: &SocketAddr,
-    sender_host: AnonymousString,
+    connection: &X2SSenderConnectToReceiverMessage,
 ) -> Result<()> {
-    let response_payload = X2SSenderConnectToReceiverMessage {
-        receiver_addr: *receiver_addr,
-        receiver_host: sender_host,
-    };
-    let response = format!("X2S_SCON {}\n", serde_json::to_string(&response_payload)?);
+    let response = format!("X2S_SCON {}\n", serde_json::to_string(connection)?);
     listener.send_to(response.as_bytes(), sender_addr)?;
     Ok(())
 }
 
-fn send_error(listener: &UdpSocket, addr: &SocketAddr, error: &str) -> Result<()> {
+/// Serves the statistics of the sessions to an operator presenting one of the admin tokens.
+fn handle_admin_request_statistics(
+    listener: &ReplySocket,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &BTreeMap<Passphrase<'static>, FileInfo>,
+    statistics: &RelayStatistics,
+    config: &RelayConfig,
+) -> Result<()> {
+    let payload: A2XRequestStatisticsMessage = serde_json::from_str(payload_str)?;
+    config.check_admin_token(&payload.admin_token)?;
+    let report = statistics.report(client_map, current_unix_millis());
+    let response = format!("X2A_STATS {}\n", serde_json::to_string(&report)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// Signs a ticket for the session of the sender, so a receiver can pick up the file with it.
+fn handle_sender_request_ticket(
+    listener: &ReplySocket,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &BTreeMap<Passphrase<'static>, FileInfo>,
+    config: &RelayConfig,
+) -> Result<()> {
+    let payload: S2XRequestTicketMessage = serde_json::from_str(payload_str)?;
+
+    // only the sender of the session gets a ticket for it
+    let file_info = match client_map.get(&config.session_key(&payload.passphrase)?) {
+        Some(file_info) if file_info.sender_addr == *addr && file_info.kind.is_file() => file_info,
+        _ => return Err(NudgeError::PassphraseNotFound),
+    };
+    let ticket = config.ticket_key.sign(&TicketPayload {
+        passphrase: payload.passphrase,
+        relay: payload.relay,
+        expires_at: file_info.expires_at,
+        file_name: file_info.file_name.clone(),
+        file_size: file_info.file_size,
+    })?;
+    let response = format!("X2S_TKT {}\n", serde_json::to_string(&X2STicketMessage { ticket })?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// Confirms the sender that its session is still waiting for a receiver.
+fn handle_sender_heartbeat(
+    listener: &ReplySocket,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &BTreeMap<Passphrase<'static>, FileInfo>,
+    config: &RelayConfig,
+) -> Result<()> {
+    let payload: S2XHeartbeatMessage = serde_json::from_str(payload_str)?;
+    match client_map.get(&config.session_key(&payload.passphrase)?) {
+        Some(file_info) if file_info.sender_addr == *addr => {
+            let heartbeat = X2SHeartbeatMessage {
+                requested: file_info.requested_by.is_some(),
+                receiver_host: file_info.requested_by.clone(),
+            };
+            let response = format!("X2S_HB {}\n", serde_json::to_string(&heartbeat)?);
+            listener.send_to(response.as_bytes(), addr)?;
+            Ok(())
+        }
+        _ => Err(NudgeError::PassphraseNotFound),
+    }
+}
+
+/// Reopens a session kept open (`send --stay-open`) for the next receiver, once the sender served the last one,
+/// or opens an offer (`send --offer-only`) once its sender came online.
+fn handle_sender_ready(
+    listener: &ReplySocket,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &mut BTreeMap<Passphrase<'static>, FileInfo>,
+    config: &RelayConfig,
+) -> Result<()> {
+    let payload: S2XReadyMessage = serde_json::from_str(payload_str)?;
+    match client_map.get_mut(&config.session_key(&payload.passphrase)?) {
+        Some(file_info)
+            if file_info.sender_addr == *addr && (file_info.on_match == MatchPolicy::Keep || file_info.offer_only) =>
+        {
+            debug!("({}) Sender is ready for the next receiver", addr);
+            file_info.receiver_addr = None;
+            file_info.pending_connection = None;
+            file_info.offer_only = false;
+            file_info.requested_by = None;
+            let expires_in_secs = file_info.expires_at.saturating_sub(current_unix_millis()) / 1000;
+            let response = format!("X2S_RDY {}\n", serde_json::to_string(&X2SReadyMessage { expires_in_secs })?);
+            listener.send_to(response.as_bytes(), addr)?;
+            Ok(())
+        }
+        _ => Err(NudgeError::PassphraseNotFound),
+    }
+}
+
+/// Asks a sender to register again later, since the relay is saturated
+fn send_busy(listener: &ReplySocket, addr: &SocketAddr, retry_after_secs: u64) -> Result<()> {
+    let response = format!("BUSY {}\n", serde_json::to_string(&RelayBusyMessage { retry_after_secs })?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+fn send_error(listener: &ReplySocket, addr: &SocketAddr, error: &str) -> Result<()> {
     let response = format!("ERROR {}\n", error);
     listener.send_to(response.as_bytes(), addr)?;
     Ok(())
@@ -1,5 +1,9 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
+use crate::utils::duration::format_duration;
+
 #[derive(Error, Debug)]
 pub enum NudgeError {
     #[error("IO Error")]
@@ -26,9 +30,246 @@ pub enum NudgeError {
     #[error("Passphrase not found")]
     PassphraseNotFound,
 
+    #[error("No passphrase given. Pass it as argument, via NUDGE_PASSPHRASE or --passphrase-stdin")]
+    PassphraseMissing,
+
+    #[error("Invalid link: {0}. Expected nudge://<relay-host>:<port>/<passphrase>")]
+    InvalidLink(String),
+
+    #[error("Passphrase is already in use")]
+    PassphraseInUse,
+
+    #[error("Invalid passphrase: {0}")]
+    InvalidPassphrase(String),
+
+    #[error("The passphrase is meant for `{0}`, run that instead")]
+    WrongSessionKind(crate::models::SessionKind),
+
+    #[error("Exchange failed: {0}")]
+    ExchangeProtocolError(String),
+
+    #[error("Peer aborted the transfer: {0}")]
+    TransferAborted(String),
+
+    #[error("Another nudge is writing {0}")]
+    FileLocked(String),
+
+    #[error("{0} is already being received from this session by {1}. Remove {2} if that receiver is gone")]
+    DuplicateSession(String, String, String),
+
+    #[error("Invalid duration: {0}. Use e.g. 90s, 15m, 1h30m or 2d")]
+    InvalidDuration(String),
+
+    #[error("Invalid time: {0}. Use e.g. 02:00, or 01:00-06:00 for a window")]
+    InvalidSchedule(String),
+
+    #[error("Invalid size: {0}. Use e.g. 500, 50MB or 32GiB")]
+    InvalidSize(String),
+
+    #[error("Passphrase expired before a receiver connected")]
+    SessionExpired,
+
+    #[error("Invalid relay configuration: {0}")]
+    InvalidRelayConfig(String),
+
+    #[error("Invalid file mode: {0} (expected octal permissions like 0600)")]
+    InvalidFileMode(String),
+
+    #[error("Invalid output template: {0}")]
+    InvalidOutTemplate(String),
+
+    #[error("Invalid rename pattern: {0}")]
+    InvalidRenamePattern(String),
+
+    #[error("No identity at {0}, create one with `nudge identity create`")]
+    IdentityMissing(String),
+
+    #[error("An identity already exists at {0}, pass --force to replace it")]
+    IdentityExists(String),
+
+    #[error("Invalid identity: {0}")]
+    InvalidIdentity(String),
+
+    #[error("Wrong passphrase for the identity")]
+    WrongIdentityPassphrase,
+
+    #[error("Invalid contact: {0}")]
+    InvalidContact(String),
+
+    #[error("No contact named {0}, see `nudge contacts list`")]
+    UnknownContact(String),
+
+    #[error("The receiver is not {0}: {1}")]
+    ContactMismatch(String, String),
+
+    #[error("The receiver failed to prove owning the identity {0}")]
+    IdentityProofFailed(String),
+
+    #[error("Invalid receiver policy: {0}")]
+    InvalidReceiverPolicy(String),
+
+    #[error("No rule of the receiver policy accepts {0}")]
+    PolicyRejected(String),
+
+    #[error("Too many requests, try again later")]
+    RateLimited,
+
+    #[error("The relay is busy, try again in {0}s")]
+    RelayBusy(u64),
+
+    #[error("Invalid session ticket: {0}")]
+    InvalidTicket(String),
+
+    #[error("The relay only hands out this file for a session ticket")]
+    TicketRequired,
+
+    #[error("{0} is a block device, pass --allow-block-device to overwrite it with the file")]
+    BlockDeviceNotAllowed(String),
+
+    #[error("{0} is too small for the file ({1} bytes, the device holds {2} bytes)")]
+    BlockDeviceTooSmall(String, u64, u64),
+
+    #[error("{0} is a device, pass --allow-device to send its contents")]
+    DeviceNotAllowed(String),
+
+    #[error("Cannot tell the size of {0}, pass --size-hint or stream it with `nudge pipe`")]
+    DeviceSizeUnknown(String),
+
+    #[error("--size-hint of {1} bytes exceeds {0}, which holds {2} bytes")]
+    SizeHintTooLarge(String, u64, u64),
+
+    #[error("stdin can only be read once, it can't be sent with {0}")]
+    StdinNotRepeatable(&'static str),
+
+    #[error("The relay is blind and only accepts hashed passphrases, pass --blind (sender and receiver)")]
+    BlindPassphraseRequired,
+
+    #[error("Webhook failed: {0}")]
+    WebhookFailed(String),
+
+    #[error("Your address is not allowed to use this relay")]
+    AddressNotAllowed,
+
+    #[error("The relay requires a valid --relay-token to register a passphrase")]
+    InvalidRelayToken,
+
+    #[error("The relay has no admin tokens configured, so it doesn't serve its statistics")]
+    AdminApiDisabled,
+
+    #[error("The relay requires a valid --admin-token to serve its statistics")]
+    InvalidAdminToken,
+
+    #[error("No interface or IP address named {0}, see --list-interfaces")]
+    UnknownInterface(String),
+
+    #[error("Port mapping failed: {0}")]
+    PortMappingFailed(String),
+
+    #[error("Cannot resolve {0}: {1}")]
+    ResolveFailed(String, String),
+
+    #[error("nudge was built without the compression feature")]
+    CompressionUnsupported,
+
+    #[error("nudge was built without the crypto feature")]
+    EncryptionUnsupported,
+
+    #[error("Invalid encryption salt from the receiver: {0}")]
+    InvalidEncryptionSalt(String),
+
+    #[error("The key exchange with the peer failed, it doesn't know the passphrase or someone in between tampered with the connection")]
+    KeyExchangeFailed,
+
+    #[error("The peer declined to agree on a key for the data stream, refusing to send or receive it unencrypted")]
+    EncryptionDeclined,
+
+    #[error("The ticket only carries the hash of the passphrase, refusing to receive the file unencrypted")]
+    TicketWithoutPassphrase,
+
+    #[error("The compressed data stream is corrupted: {0}")]
+    CorruptedStream(String),
+
+    #[error("Invalid batch file: {0}")]
+    InvalidBatch(String),
+
+    #[error("Invalid manifest file: {0}")]
+    InvalidSendManifest(String),
+
+    #[error("No files match the pattern {0}")]
+    NoGlobMatches(String),
+
+    #[error("Invalid code in line {0} of the batch file: {1}")]
+    InvalidBatchCode(usize, Box<NudgeError>),
+
+    #[error("Invalid relay simulation: {0}")]
+    InvalidSimulation(String),
+
+    #[error("Cannot sandbox the process: {0}")]
+    SandboxFailed(String),
+
+    #[error("The receiver declined the download after the preview")]
+    DownloadDeclined,
+
+    #[error("Cannot derive the schema of {0}: {1}")]
+    SchemaError(String, String),
+
+    #[error("Unknown message {0}, see `nudge protocol-schema --list`")]
+    UnknownMessage(String),
+
+    #[error("Unknown error code {0}, see `nudge explain --list`")]
+    UnknownErrorCode(String),
+
     #[error("Failed to parse JSON")]
     JsonParseError(#[from] serde_json::Error),
 
+    #[error("No response from the relay within {}, raise --relay-timeout if it's slow to answer", format_duration(*.0))]
+    RelayTimeout(Duration),
+
+    #[error("The relay stopped responding while waiting for a receiver (no answer within {})", format_duration(*.0))]
+    RelayUnresponsive(Duration),
+
+    #[error("The relay removes sessions once a receiver claimed them, --stay-open needs a newer relay")]
+    MatchPolicyUnsupported,
+
+    #[error("The relay doesn't keep offers for senders which aren't online, --offer-only needs a newer relay")]
+    OfferUnsupported,
+
+    #[error("The sender only offered the file and isn't online yet. They were asked to come online, try again later")]
+    SenderOffline,
+
+    #[error("The directory received isn't a valid archive: {0}")]
+    InvalidArchive(String),
+
+    #[error("The sender sent an entry outside of the output directory: {0}")]
+    UnsafePath(String),
+
+    #[error("The sender shares {0}, which can't be {1}")]
+    OutputUnsupported(&'static str, &'static str),
+
+    #[error("{0} can't be sent together with other files, only regular files can")]
+    BundleUnsupported(String),
+
+    #[error("Several files are named {0}, but the receiver stores them in one directory")]
+    DuplicateFileName(String),
+
+    #[error("The files listed by the sender don't add up to what it announced")]
+    ManifestMismatch,
+
+    #[error("The sender continues the download at byte {0}, but only {1} bytes were received before")]
+    InvalidResumeOffset(u64, u64),
+
+    #[error("Hash mismatch of {0}, the file is corrupted")]
+    FileHashMismatch(String),
+
+    #[error("The sender hashed the file with {0}, which this version can't check. Update nudge, or pass --skip-hash to accept the file unchecked")]
+    UnknownHashAlgorithm(String),
+
+    #[error("Incompatible chunk sizes: the sender was started with --chunk-size {0}, but the receiver takes chunks of at most {1} bytes. Start both with the same --chunk-size, or omit it on the sender")]
+    ChunkSizeMismatch(u32, u32),
+
+    #[error("No packet from the peer within {} of connecting (--peer-timeout), a firewall or NAT probably blocks the direct connection", format_duration(*.0))]
+    PeerTimeout(Duration),
+
     #[error("Server returned error: {0}")]
     ServerError(String),
 
@@ -46,6 +287,65 @@ pub enum NudgeError {
 
     #[error("Unknown command")]
     UnknownCommand,
+
+    #[cfg(feature = "geoip")]
+    #[error("Failed to read GeoIP database: {0}")]
+    GeoIpError(#[from] maxminddb::MaxMindDBError),
+}
+
+impl NudgeError {
+    /// Returns the code of the explanation of the error (see `utils::error_catalog`), `None` if there's none.
+    pub fn code(&self) -> Option<&'static str> {
+        let code = match self {
+            NudgeError::PassphraseNotFound => "E101",
+            NudgeError::PassphraseInUse => "E102",
+            NudgeError::PassphraseMissing => "E103",
+            NudgeError::SessionExpired => "E104",
+            NudgeError::WrongSessionKind(_) => "E105",
+            NudgeError::RelayTimeout(_) => "E201",
+            // nothing listens on the relay's port
+            NudgeError::Io(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => "E201",
+            NudgeError::RelayUnresponsive(_) => "E202",
+            NudgeError::RateLimited => "E203",
+            NudgeError::RelayBusy(_) => "E204",
+            NudgeError::BlindPassphraseRequired => "E205",
+            NudgeError::AddressNotAllowed => "E206",
+            NudgeError::InvalidRelayToken => "E207",
+            NudgeError::TicketRequired => "E208",
+            NudgeError::SenderOffline => "E209",
+            NudgeError::PeerTimeout(_) => "E301",
+            NudgeError::TransferAborted(_) => "E302",
+            NudgeError::ChunkSizeMismatch(..) => "E303",
+            NudgeError::KeyExchangeFailed => "E304",
+            NudgeError::EncryptionDeclined => "E305",
+            NudgeError::TicketWithoutPassphrase => "E306",
+            NudgeError::FileHashMismatch(_) | NudgeError::HashMismatch(..) => "E401",
+            NudgeError::FileLocked(_) => "E402",
+            NudgeError::DuplicateSession(..) => "E403",
+            NudgeError::UnknownHashAlgorithm(_) => "E404",
+            NudgeError::ContactMismatch(..) | NudgeError::IdentityProofFailed(_) => "E501",
+            NudgeError::IdentityMissing(_) => "E502",
+            NudgeError::WrongIdentityPassphrase => "E503",
+            NudgeError::CompressionUnsupported | NudgeError::EncryptionUnsupported => "E601",
+            NudgeError::InvalidBatchCode(_, error) => return error.code(),
+            // errors of the relay arrive as their text
+            NudgeError::ServerError(message) => {
+                let relay_errors = [
+                    NudgeError::PassphraseNotFound,
+                    NudgeError::PassphraseInUse,
+                    NudgeError::RateLimited,
+                    NudgeError::BlindPassphraseRequired,
+                    NudgeError::AddressNotAllowed,
+                    NudgeError::InvalidRelayToken,
+                    NudgeError::TicketRequired,
+                    NudgeError::SenderOffline,
+                ];
+                return relay_errors.iter().find(|error| message.contains(&error.to_string())).and_then(NudgeError::code);
+            }
+            _ => return None,
+        };
+        Some(code)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, NudgeError>;
@@ -58,7 +358,7 @@ mod tests {
 
     #[test]
     fn test_io_error() {
-        let io_error = io::Error::new(io::ErrorKind::Other, "some IO error");
+        let io_error = io::Error::other("some IO error");
         let nudge_error: NudgeError = io_error.into();
         assert!(matches!(nudge_error, NudgeError::Io(_)));
     }
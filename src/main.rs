@@ -1,42 +1,71 @@
 #[macro_use]
-extern crate simple_log;
-
-use clap::{Parser};
-use simple_log::LogConfigBuilder;
+extern crate tracing;
 
 use crate::error::Result;
-use crate::commands::{SubCommand, server_command, send_command, get_command};
+use crate::utils::{deterministic, logging};
+use crate::utils::error_catalog::explain;
+use crate::utils::serialize::set_relay_timeout;
+use crate::utils::ui::{disable_colors, disable_emoji, is_no_color_env, reserve_stdout_for_data, Verbosity};
+use crate::commands::{SubCommand, server_command, send_command, get_command, doctor_command, exchange_command, pipe_command, forward_command, identity_command, contacts_command, protocol_schema_command, relay_stats_command, explain_command};
 
 mod error;
+#[macro_use]
 mod utils;
 
 // subcommands
 mod commands;
 mod models;
 
-
 fn main() -> Result<()> {
-    let opts = commands::RootOpts::parse();
+    let opts = commands::RootOpts::parse_with_implied_command();
+
+    let verbosity = Verbosity::from_flags(opts.quiet, opts.verbose);
+    verbosity.apply();
+    opts.units.apply();
+    set_relay_timeout(opts.relay_timeout);
+    if opts.deterministic {
+        deterministic::enable();
+        disable_colors();
+    }
+    if opts.no_color || is_no_color_env() {
+        disable_colors();
+    }
+    if opts.no_emoji {
+        disable_emoji();
+    }
 
-    // init logger
-    let log_config = LogConfigBuilder::builder()
-        .level(if opts.verbose {
-            log::Level::Debug.as_str()
-        } else {
-            log::Level::Info.as_str()
-        })
-        .time_format("%d-%m/%H:%M:%S")
-        .output_console()
-        .build();
-    simple_log::new(log_config).expect("Failed to initialize logger");
+    // `pipe` and `get -o -` write the peer's data to stdout, which the logger (printing to stdout) would corrupt
+    let stdout_is_data = match &opts.subcmd {
+        SubCommand::Pipe(_) => true,
+        SubCommand::Get(get_opts) => get_opts.writes_to_stdout(),
+        _ => false,
+    };
+    if stdout_is_data {
+        reserve_stdout_for_data();
+    } else {
+        logging::init(verbosity, &opts.debug, opts.deterministic);
+    }
 
     match match &opts.subcmd {
-        SubCommand::Serve(server_opts) => server_command::run(&opts, &server_opts),
-        SubCommand::Send(send_opts) => send_command::run(&opts, &send_opts),
-        SubCommand::Get(get_opts) => get_command::run(&opts, &get_opts),
+        SubCommand::Serve(server_opts) => server_command::run(&opts, server_opts),
+        SubCommand::Send(send_opts) => send_command::run(&opts, send_opts),
+        SubCommand::Get(get_opts) => get_command::run(&opts, get_opts),
+        SubCommand::Exchange(exchange_opts) => exchange_command::run(&opts, exchange_opts),
+        SubCommand::Pipe(pipe_opts) => pipe_command::run(&opts, pipe_opts),
+        SubCommand::Forward(forward_opts) => forward_command::run(&opts, forward_opts),
+        SubCommand::Doctor(doctor_opts) => doctor_command::run(&opts, doctor_opts),
+        SubCommand::Identity(identity_opts) => identity_command::run(&opts, identity_opts),
+        SubCommand::Contacts(contacts_opts) => contacts_command::run(&opts, contacts_opts),
+        SubCommand::ProtocolSchema(protocol_schema_opts) => protocol_schema_command::run(&opts, protocol_schema_opts),
+        SubCommand::RelayStats(relay_stats_opts) => relay_stats_command::run(&opts, relay_stats_opts),
+        SubCommand::Explain(explain_opts) => explain_command::run(&opts, explain_opts),
+        SubCommand::Implied(_) => unreachable!("replaced by the command it implies when parsing"),
     } {
         Err(e) => {
             error!("Error: {}", e);
+            if let Some(explanation) = explain(&e) {
+                error!("Hint: {} (see `nudge explain {}`)", explanation.hint, explanation.code);
+            }
             Err(e)
         }
         _ => Ok(()),
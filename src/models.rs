@@ -1,9 +1,12 @@
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
 use serde::{Deserialize, Serialize};
+use crate::utils::compression::Algorithm;
+use crate::utils::identity::PublicKey;
 use crate::utils::passphrase::Passphrase;
 use crate::utils::AnonymousString;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     /// Size of the file in bytes
     pub(crate) file_size: u64,
@@ -14,17 +17,206 @@ pub struct FileInfo {
     /// Hash of the file (optional)
     pub(crate) file_hash: AnonymousString,
 
-    /// Hostname of the sender (optional)
+    /// Display name or hostname of the sender (optional)
     pub(crate) sender_host: AnonymousString,
 
     /// Timestamp when the file was created
     pub(crate) created_at: u64,
 
+    /// Timestamp after which the relay forgets the passphrase
+    #[serde(default)]
+    pub(crate) expires_at: u64,
+
     /// Address of the sender
     pub(crate) sender_addr: SocketAddr,
+
+    /// Port the sender mapped on its router, to be used instead of the port of `sender_addr` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sender_mapped_port: Option<u16>,
+
+    /// Origin of the sender, looked up by the relay (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sender_geo: Option<GeoInfo>,
+
+    /// What the passphrase is used for
+    #[serde(default, skip_serializing_if = "SessionKind::is_file")]
+    pub(crate) kind: SessionKind,
+
+    /// Whether the sender offers a preview of the (textual) file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) previewable: bool,
+
+    /// Permissions of the sender's file, applied by receivers passing `--preserve` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) file_mode: Option<u32>,
+
+    /// Whether the file has holes, which the sender skips if the receiver accepts a `SparseMapMessage`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) sparse: bool,
+
+    /// Whether the sender shares a directory, sent as a tar archive the receiver unpacks
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) directory: bool,
+
+    /// Number of files the sender shares at once, listed in a `FileManifestMessage` after connecting (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) file_count: Option<u32>,
+
+    /// Whether the sender only offered the file and comes online once a receiver asks for it (`send --offer-only`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) offer_only: bool,
+
+    /// Whether the sender compresses the data stream for receivers taking zstd (`send --compress`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) compress: bool,
+
+    /// Algorithm of `file_hash`, older senders don't name it and hash with BLAKE3 (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hash_algorithm: Option<String>,
+
+    /// Whether the sender streams data of unknown length (`send -`), `file_size` is 0 then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) streaming: bool,
+
+    /// When the sender sends data in its local time, like "between 01:00 and 06:00" (`send --window`, optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) schedule: Option<String>,
+
+    /// Receiver which asked the sender of an offer to come online, kept by the relay until the sender is (optional)
+    #[serde(skip)]
+    pub(crate) requested_by: Option<AnonymousString>,
+
+    /// Address of the receiver which accepted the session, kept by the relay to answer retries
+    #[serde(skip)]
+    pub(crate) receiver_addr: Option<SocketAddr>,
+
+    /// Connection request of the receiver which accepted the session, kept by the relay to pass it on again to a
+    /// sender which restarted and registered again (optional)
+    #[serde(skip)]
+    pub(crate) pending_connection: Option<X2SSenderConnectToReceiverMessage>,
+
+    /// ID the sender registered the session with, kept by the relay to recognize retries
+    #[serde(skip)]
+    pub(crate) registration_id: Option<String>,
+
+    /// Whether the sender agreed to the relay announcing the session to its webhook
+    #[serde(skip)]
+    pub(crate) announce: bool,
+
+    /// How often receivers looked up the passphrase, counted by the relay for its statistics
+    #[serde(skip)]
+    pub(crate) lookups: u32,
+
+    /// Timestamp when a receiver claimed the session, kept by the relay for its statistics (optional)
+    #[serde(skip)]
+    pub(crate) matched_at: Option<u64>,
+
+    /// What the relay does with the session once a receiver claimed it, chosen by the sender
+    #[serde(skip)]
+    pub(crate) on_match: MatchPolicy,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What a passphrase is used for, so peers running different commands don't pair up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionKind {
+    /// A single file sent with `nudge send`
+    #[default]
+    File,
+
+    /// Files swapped with `nudge exchange`
+    Exchange,
+
+    /// A channel bridged to stdin/stdout with `nudge pipe`
+    Pipe,
+
+    /// TCP connections forwarded with `nudge forward`
+    Forward,
+}
+
+impl SessionKind {
+    pub fn is_file(&self) -> bool {
+        *self == SessionKind::File
+    }
+
+    /// Returns the command the other peer has to run to join the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase of the session.
+    pub fn join_command(&self, passphrase: &str) -> String {
+        match self {
+            SessionKind::File => format!("nudge get {}", passphrase),
+            SessionKind::Exchange => format!("nudge exchange --code {}", passphrase),
+            SessionKind::Pipe => format!("nudge pipe {}", passphrase),
+            SessionKind::Forward => format!("nudge forward --code {} --local <PORT> | --remote <PORT>", passphrase),
+        }
+    }
+}
+
+/// What the relay does with a session once a receiver claimed it, chosen by the sender when registering
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchPolicy {
+    /// Forget the session, after answering retries of the receiver for a moment
+    #[default]
+    Remove,
+
+    /// Keep the session until it expires. The sender serves one receiver at a time and reopens the session
+    /// for the next one with `S2XReadyMessage` (`send --stay-open`)
+    Keep,
+}
+
+impl MatchPolicy {
+    pub fn is_remove(&self) -> bool {
+        *self == MatchPolicy::Remove
+    }
+}
+
+impl Display for SessionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SessionKind::File => "nudge get",
+            SessionKind::Exchange => "nudge exchange",
+            SessionKind::Pipe => "nudge pipe",
+            SessionKind::Forward => "nudge forward",
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GeoInfo {
+    /// ISO code of the country, e.g. "DE"
+    pub(crate) country: Option<String>,
+
+    /// Number of the autonomous system
+    pub(crate) asn: Option<u32>,
+
+    /// Organization operating the autonomous system
+    pub(crate) as_org: Option<String>,
+}
+
+impl Display for GeoInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        match (self.asn, &self.as_org) {
+            (Some(asn), Some(org)) => parts.push(format!("AS{} {}", asn, org)),
+            (Some(asn), None) => parts.push(format!("AS{}", asn)),
+            (None, Some(org)) => parts.push(org.clone()),
+            (None, None) => {}
+        }
+        if parts.is_empty() {
+            f.write_str("unknown")
+        } else {
+            f.write_str(&parts.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct S2XRequestPassphraseMessage {
     /// Size of the file in bytes
     pub(crate) file_size: u64,
@@ -35,37 +227,609 @@ pub struct S2XRequestPassphraseMessage {
     /// Hash of the file (optional)
     pub(crate) file_hash: AnonymousString,
 
-    /// Hostname of the sender (optional)
+    /// Display name or hostname of the sender (optional)
     pub(crate) sender_host: AnonymousString,
+
+    /// Passphrase chosen by the sender instead of a generated one, hashed (`blind:<hex>`) with `--blind` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) passphrase: Option<Passphrase<'static>>,
+
+    /// Seconds until the passphrase expires, if shorter than the relay's TTL (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expire_secs: Option<u64>,
+
+    /// What the passphrase is used for
+    #[serde(default, skip_serializing_if = "SessionKind::is_file")]
+    pub(crate) kind: SessionKind,
+
+    /// Token authorizing the sender at relays which require one (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) relay_token: Option<String>,
+
+    /// Random ID chosen by the sender, so retries of the request return the same passphrase (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) registration_id: Option<String>,
+
+    /// Port the sender mapped on its router via UPnP or NAT-PMP (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mapped_port: Option<u16>,
+
+    /// Whether the sender offers a preview of the (textual) file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) previewable: bool,
+
+    /// Permissions of the sender's file, applied by receivers passing `--preserve` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) file_mode: Option<u32>,
+
+    /// Whether the file has holes, which the sender skips if the receiver accepts a `SparseMapMessage`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) sparse: bool,
+
+    /// Whether the sender shares a directory, sent as a tar archive
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) directory: bool,
+
+    /// Number of files shared at once (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) file_count: Option<u32>,
+
+    /// Whether the relay may announce the session (including the passphrase) to its webhook
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) announce: bool,
+
+    /// What the relay does with the session once a receiver claimed it
+    #[serde(default, skip_serializing_if = "MatchPolicy::is_remove")]
+    pub(crate) on_match: MatchPolicy,
+
+    /// Whether the sender only offers the file and comes online once a receiver asks for it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) offer_only: bool,
+
+    /// Whether the sender compresses the data stream for receivers taking zstd
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) compress: bool,
+
+    /// Algorithm of `file_hash` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hash_algorithm: Option<String>,
+
+    /// Whether the sender streams data of unknown length (`send -`), `file_size` is 0 then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) streaming: bool,
+
+    /// When the sender sends data in its local time (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) schedule: Option<String>,
+
+    /// Whether a sender taking over its session (`send --resume-session`) releases it from the receiver which
+    /// claimed it, so the receiver can claim it again after restarting as well
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) resume: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct X2SPassphraseProvidedMessage {
-    /// Passphrase to access the file
+    /// Passphrase to access the file, hashed (`blind:<hex>`) with `--blind`
     pub(crate) passphrase: Passphrase<'static>,
+
+    /// Seconds until the relay forgets the passphrase (optional, for older relays)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_in_secs: Option<u64>,
+
+    /// What the relay does with the session once a receiver claimed it, older relays always remove it (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) on_match: Option<MatchPolicy>,
+
+    /// Whether the relay keeps the session as an offer, older relays don't
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) offer_only: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct R2XRequestFileInfoMessage {
-    /// Passphrase to access the file
+    /// Passphrase to access the file, hashed (`blind:<hex>`) with `--blind`
     pub(crate) passphrase: Passphrase<'static>,
+
+    /// Session ticket issued to the sender, required by relays with `require_tickets` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ticket: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct R2XRequestSenderConnectionMessage {
-    /// Passphrase to access the file
+    /// Passphrase to access the file, hashed (`blind:<hex>`) with `--blind`
     pub(crate) passphrase: Passphrase<'static>,
 
     /// Size of the file in bytes (optional)
     pub(crate) file_hash: AnonymousString,
 
-    /// Hostname of the receiver (optional)
+    /// Display name or hostname of the receiver (optional)
     pub(crate) receiver_host: AnonymousString,
+
+    /// Port the receiver mapped on its router via UPnP or NAT-PMP (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mapped_port: Option<u16>,
+
+    /// Whether the receiver asks for a preview before the download
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) preview: bool,
+
+    /// Whether the receiver asks for the extended attributes of the file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) xattrs: bool,
+
+    /// Whether the receiver accepts a sparse transfer of the file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) sparse: bool,
+
+    /// Whether the receiver splits several files shared at once
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) manifest: bool,
+
+    /// Whether the receiver asks for a compressed data stream
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) compress: bool,
+
+    /// Whether the receiver takes a zstd compressed data stream
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) zstd: bool,
+
+    /// Bytes of the file the receiver kept from an interrupted download, to be skipped by the sender (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) resume_offset: Option<u64>,
+
+    /// Random salt (hex) the key of an encrypted data stream is derived with, if the receiver asks for one (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encryption_salt: Option<String>,
+
+    /// Whether the receiver agrees on the key with a key exchange (`KeyExchangeMessage`) instead of deriving it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) key_exchange: bool,
+
+    /// Largest chunks the receiver takes, if it was started with `--chunk-size` or `--profile` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_chunk_size: Option<u32>,
+
+    /// Session ticket issued to the sender, required by relays with `require_tickets` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ticket: Option<String>,
+
+    /// Public key of the receiver's identity, which it proves owning after connecting (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) identity: Option<PublicKey>,
+}
+
+/// Asks the relay to sign a ticket for the sender's session (prefix `S2X_RT`),
+/// which lets a receiver pick up the file later without knowing the relay
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2XRequestTicketMessage {
+    /// Passphrase of the session
+    pub(crate) passphrase: Passphrase<'static>,
+
+    /// Address the sender reached the relay at, as "host:port"
+    pub(crate) relay: String,
+}
+
+/// The ticket signed by the relay (prefix `X2S_TKT`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2STicketMessage {
+    /// The ticket, see `TicketKey::sign`
+    pub(crate) ticket: String,
+}
+
+/// Tells the relay that the sender still waits for a receiver (prefix `S2X_HB`), sent with `send --backup-relay`
+/// to notice a relay which stopped responding
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2XHeartbeatMessage {
+    /// Passphrase of the session
+    pub(crate) passphrase: Passphrase<'static>,
+}
+
+/// Confirms the sender that the relay still holds its session (prefix `X2S_HB`). The relay also sends it unasked
+/// to the sender of an offer (`send --offer-only`) once a receiver asks for the file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2SHeartbeatMessage {
+    /// Whether a receiver asked the sender of an offer to come online
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) requested: bool,
+
+    /// Display name or hostname of the receiver which asked (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) receiver_host: Option<AnonymousString>,
+}
+
+/// Asks the relay to wake the sender of an offer (`send --offer-only`) for a receiver (prefix `R2X_WAKE`),
+/// answered with `X2RSenderOnlineMessage` once the sender is online
+#[derive(Debug, Serialize, Deserialize)]
+pub struct R2XWakeSenderMessage {
+    /// Passphrase to access the file, hashed (`blind:<hex>`) with `--blind`
+    pub(crate) passphrase: Passphrase<'static>,
+
+    /// Display name or hostname of the receiver (optional)
+    pub(crate) receiver_host: AnonymousString,
+
+    /// Session ticket issued to the sender, required by relays with `require_tickets` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ticket: Option<String>,
+}
+
+/// Tells the receiver that the sender of an offer came online and can be connected to (prefix `X2R_ONL`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2RSenderOnlineMessage {}
+
+/// Tells the relay that the sender of a session kept open (`send --stay-open`) served its receiver and waits for
+/// the next one (prefix `S2X_RDY`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2XReadyMessage {
+    /// Passphrase of the session
+    pub(crate) passphrase: Passphrase<'static>,
+}
+
+/// Confirms the sender that the session is open for the next receiver (prefix `X2S_RDY`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2SReadyMessage {
+    /// Seconds until the relay forgets the passphrase
+    pub(crate) expires_in_secs: u64,
+}
+
+/// Tells a sender that the relay is saturated and can't register the session right now (prefix `BUSY`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayBusyMessage {
+    /// Seconds the sender should wait before registering again
+    pub(crate) retry_after_secs: u64,
+}
+
+/// Asks the relay for the statistics of its sessions (prefix `A2X_STATS`), answered with `X2AStatisticsMessage`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct A2XRequestStatisticsMessage {
+    /// One of the admin tokens of the relay
+    pub(crate) admin_token: String,
+}
+
+/// Statistics of the sessions of a relay (prefix `X2A_STATS`), never naming their passphrases
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct X2AStatisticsMessage {
+    /// Seconds since the relay started
+    pub(crate) uptime_secs: u64,
+
+    /// Sessions registered since the relay started
+    pub(crate) registered: u64,
+
+    /// Sessions claimed by a receiver since the relay started
+    pub(crate) matched: u64,
+
+    /// Sessions which expired without being claimed
+    pub(crate) expired: u64,
+
+    /// Lookups of registered passphrases by receivers
+    pub(crate) lookups: u64,
+
+    /// Sessions registered right now
+    pub(crate) active: u64,
+
+    /// Sessions registered right now which no receiver claimed yet
+    pub(crate) waiting: u64,
+
+    /// Seconds the longest waiting session waits for a receiver (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) oldest_waiting_secs: Option<u64>,
+
+    /// How long the recently claimed sessions waited for their receiver (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) wait_millis: Option<WaitPercentiles>,
 }
 
+/// Percentiles of how long sessions waited for a receiver, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WaitPercentiles {
+    pub(crate) p50: u64,
+    pub(crate) p90: u64,
+    pub(crate) max: u64,
+}
+
+/// Confirms the receiver that the relay told the sender to connect
 #[derive(Debug, Serialize, Deserialize)]
+pub struct X2RSenderConnectionAcceptedMessage {
+    /// Current address of the sender, which changes if it restarted and took over its session (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sender_addr: Option<SocketAddr>,
+
+    /// Port the sender mapped on its router, to be used instead of the port of `sender_addr` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sender_mapped_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct X2SSenderConnectToReceiverMessage {
     /// Address of the receiver
     pub(crate) receiver_addr: SocketAddr,
     pub(crate) receiver_host: AnonymousString,
+
+    /// Port the receiver mapped on its router, to be used instead of the port of `receiver_addr` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) receiver_mapped_port: Option<u16>,
+
+    /// Whether the receiver asks for a preview (`PreviewRequestMessage`) before the download
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) preview: bool,
+
+    /// Whether the receiver asks for the extended attributes of the file (`ExtendedAttributesMessage`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) xattrs: bool,
+
+    /// Whether the receiver accepts a sparse transfer of the file (`SparseMapMessage`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) sparse: bool,
+
+    /// Whether the receiver splits several files shared at once (`FileManifestMessage`),
+    /// otherwise it gets them as one file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) manifest: bool,
+
+    /// Whether the receiver asks for a compressed data stream (`CompressionMessage`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) compress: bool,
+
+    /// Whether the receiver takes a zstd compressed data stream, told if it asks for compression or the sender
+    /// compresses anyway (`FileInfo::compress`). Receivers telling it always get a `CompressionMessage`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) zstd: bool,
+
+    /// Bytes of the file the receiver kept from an interrupted download, answered with a `ResumeMessage` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) resume_offset: Option<u64>,
+
+    /// Salt (hex) of the receiver asking for an encrypted data stream, answered with an `EncryptionMessage` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encryption_salt: Option<String>,
+
+    /// Whether the receiver agrees on the key with a key exchange (`KeyExchangeMessage`) instead of deriving it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) key_exchange: bool,
+
+    /// Largest chunks the receiver takes, the sender aborts if it can't send chunks that small (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_chunk_size: Option<u32>,
+
+    /// Public key the receiver claims, to be challenged (`IdentityChallengeMessage`) before anything else (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) receiver_identity: Option<PublicKey>,
+}
+
+/// Tells the receiver whether the data stream is encrypted from the next packet on, sent by the sender right after
+/// connecting if the receiver asks for encryption
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptionMessage {
+    /// Whether the packets are sealed from now on, `false` if the sender was built without encryption
+    pub(crate) encrypted: bool,
+
+    /// Whether the key is agreed on with a key exchange first, `false` if it's derived from the passphrase and salt
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) key_exchange: bool,
+}
+
+/// Element of the key exchange authenticated by the passphrase (SPAKE2), the sender sends its element first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyExchangeMessage {
+    /// The point (hex) blinded with the passphrase
+    pub(crate) element: String,
+}
+
+/// Proves having derived the same key in the key exchange, i.e. knowing the passphrase, the sender sends its first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyConfirmationMessage {
+    /// MAC (hex) of the transcript of the key exchange
+    pub(crate) confirmation: String,
+}
+
+/// Asks the receiver to prove owning the identity it claimed, sent by the sender right after connecting
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityChallengeMessage {
+    /// Random nonce (hex), signed together with the passphrase, see `identity_proof_message`
+    pub(crate) nonce: String,
+}
+
+/// The receiver's answer to the `IdentityChallengeMessage`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityProofMessage {
+    /// Signature of the challenge (hex)
+    pub(crate) signature: String,
+}
+
+/// Asks the sender for the beginning of the file, sent by the receiver right after connecting
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewRequestMessage {
+    /// Maximum number of bytes to preview
+    pub(crate) max_bytes: u32,
+}
+
+/// Tells the sender whether the receiver wants the file after seeing the preview
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewDecisionMessage {
+    pub(crate) download: bool,
+}
+
+/// Extended attributes of the file, sent by the sender before the file if the receiver asks for them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtendedAttributesMessage {
+    /// The attributes, or `None` if the sender doesn't share them
+    pub(crate) attributes: Option<Vec<ExtendedAttribute>>,
+}
+
+/// An extended attribute of a file, e.g. `user.xdg.origin.url`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtendedAttribute {
+    /// Name of the attribute, including its namespace
+    pub(crate) name: String,
+
+    /// Value of the attribute, hex encoded
+    pub(crate) value: String,
+}
+
+/// Regions of the file containing data, sent by the sender before a sparse transfer.
+/// Only the data of these regions is transferred, everything in between reads as zeros
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SparseMapMessage {
+    pub(crate) extents: Vec<Extent>,
+}
+
+/// Lists the files shared at once, sent by the sender after connecting if the receiver splits them.
+/// Their contents follow in this order as a single data stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileManifestMessage {
+    pub(crate) files: Vec<ManifestFile>,
+}
+
+/// A file of a `FileManifestMessage`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Name of the file, without a directory
+    pub(crate) name: String,
+    pub(crate) size: u64,
+
+    /// BLAKE3 hash of the file, `None` with `--skip-hash`
+    pub(crate) hash: AnonymousString,
+
+    /// Label of the file from `send --from-manifest` (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+}
+
+/// Tells the receiver whether the data stream is compressed, sent by the sender before the file
+/// if the receiver asks for compression or takes zstd
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompressionMessage {
+    /// Whether the data is compressed, `false` if the sender was built without compression
+    pub(crate) compressed: bool,
+
+    /// How the data is compressed, deflate for older senders
+    #[serde(default)]
+    pub(crate) algorithm: Algorithm,
+}
+
+/// Tells the receiver where the data stream starts, sent by the sender before the file if the receiver asks to
+/// resume an interrupted download
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeMessage {
+    /// Offset of the first byte sent, the one asked for or 0 if the sender sends the whole file again
+    /// (e.g. a directory, which can't be resumed)
+    pub(crate) offset: u64,
+}
+
+/// A region of a file containing data
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Extent {
+    /// Offset of the region in bytes
+    pub(crate) offset: u64,
+
+    /// Length of the region in bytes
+    pub(crate) len: u64,
+}
+
+/// File offered in a `nudge exchange` session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeFile {
+    /// Name of the file
+    pub(crate) file_name: String,
+
+    /// Size of the file in bytes
+    pub(crate) file_size: u64,
+
+    /// Hash of the file (optional)
+    pub(crate) file_hash: AnonymousString,
+}
+
+/// Files a peer offers in a `nudge exchange` session
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExchangeOfferMessage {
+    pub(crate) files: Vec<ExchangeFile>,
+}
+
+/// Files a peer wants to receive in a `nudge exchange` session, as indices into the offer
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExchangeAcceptMessage {
+    pub(crate) accepted: Vec<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_info_display() {
+        let geo_info = GeoInfo {
+            country: Some("DE".to_string()),
+            asn: Some(3320),
+            as_org: Some("Deutsche Telekom AG".to_string()),
+        };
+        assert_eq!(geo_info.to_string(), "DE, AS3320 Deutsche Telekom AG");
+        assert_eq!(GeoInfo::default().to_string(), "unknown");
+    }
+
+    #[test]
+    fn test_file_info_without_geo_info() {
+        let json = r#"{"file_size":1,"file_name":"a","file_hash":null,"sender_host":null,"created_at":0,"sender_addr":"127.0.0.1:1"}"#;
+        let file_info: FileInfo = serde_json::from_str(json).unwrap();
+        assert!(file_info.sender_geo.is_none());
+        assert_eq!(file_info.expires_at, 0);
+        assert_eq!(file_info.kind, SessionKind::File);
+    }
+
+    #[test]
+    fn test_match_policy_serialization() {
+        // registrations of older senders leave the policy to the relay, which removes claimed sessions
+        let json = r#"{"file_size":1,"file_name":"a","file_hash":null,"sender_host":null}"#;
+        let registration: S2XRequestPassphraseMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(registration.on_match, MatchPolicy::Remove);
+        assert!(!serde_json::to_string(&registration).unwrap().contains("on_match"));
+
+        let registration = S2XRequestPassphraseMessage { on_match: MatchPolicy::Keep, ..registration };
+        assert!(serde_json::to_string(&registration).unwrap().contains(r#""on_match":"keep""#));
+
+        // older relays don't confirm the policy
+        let answer: X2SPassphraseProvidedMessage = serde_json::from_str(r#"{"passphrase":"a-b-c"}"#).unwrap();
+        assert_eq!(answer.on_match, None);
+    }
+
+    #[test]
+    fn test_session_kind_serialization() {
+        assert_eq!(serde_json::to_string(&SessionKind::Pipe).unwrap(), r#""pipe""#);
+        assert_eq!(SessionKind::Exchange.join_command("a-b-c"), "nudge exchange --code a-b-c");
+    }
+
+    #[test]
+    fn test_passphrase_provided_without_expiry() {
+        let json = r#"{"passphrase":"some-pass-phrase"}"#;
+        let message: X2SPassphraseProvidedMessage = serde_json::from_str(json).unwrap();
+        assert!(message.expires_in_secs.is_none());
+        assert!(!message.offer_only);
+    }
+
+    #[test]
+    fn test_connection_accepted_without_sender_addr() {
+        // older relays confirm without telling where the sender is
+        let message: X2RSenderConnectionAcceptedMessage = serde_json::from_str("{}").unwrap();
+        assert!(message.sender_addr.is_none());
+        let message = X2RSenderConnectionAcceptedMessage { sender_addr: None, sender_mapped_port: None };
+        assert_eq!(serde_json::to_string(&message).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_offer_serialization() {
+        // heartbeats of older relays don't name a receiver
+        let heartbeat: X2SHeartbeatMessage = serde_json::from_str("{}").unwrap();
+        assert!(!heartbeat.requested);
+        assert_eq!(serde_json::to_string(&heartbeat).unwrap(), "{}");
+
+        // anonymous receivers are announced too
+        let heartbeat = X2SHeartbeatMessage { requested: true, receiver_host: Some(AnonymousString(None)) };
+        let heartbeat: X2SHeartbeatMessage = serde_json::from_str(&serde_json::to_string(&heartbeat).unwrap()).unwrap();
+        assert!(heartbeat.requested);
+
+        let json = r#"{"file_size":1,"file_name":"a","file_hash":null,"sender_host":null,"created_at":0,"sender_addr":"127.0.0.1:1","offer_only":true}"#;
+        let file_info: FileInfo = serde_json::from_str(json).unwrap();
+        assert!(file_info.offer_only);
+        assert!(file_info.requested_by.is_none());
+    }
 }
@@ -0,0 +1,306 @@
+//! Accounts for what the sender sent to every receiver, and shares a bandwidth limit (`send --max-rate`)
+//! among the receivers served at the same time, so a single receiver can't take up all of it. In the background mode
+//! (`send --background`), the limit is lowered to the background rate.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::utils::units::format_bytes;
+
+/// Most data a receiver may send ahead of its share after it was held up, as a fraction of a second
+const BURST_MILLIS: u64 = 250;
+
+/// What was sent to a receiver
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerTransfer {
+    /// The host of the receiver
+    pub peer: String,
+
+    /// Bytes of the data stream sent, after compression
+    pub bytes: u64,
+
+    /// Time from the first to the last chunk
+    pub duration: Duration,
+}
+
+impl PeerTransfer {
+    /// Returns the average speed in bytes per second.
+    pub fn bytes_per_second(&self) -> u64 {
+        bytes_per_second(self.bytes, self.duration)
+    }
+}
+
+impl std::fmt::Display for PeerTransfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {:.3}s ({}/s)",
+            format_bytes(self.bytes),
+            self.duration.as_secs_f64(),
+            format_bytes(self.bytes_per_second())
+        )
+    }
+}
+
+/// What was sent to all receivers together
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferTotals {
+    /// Receivers which got the file
+    pub peers: u32,
+
+    /// Bytes sent to all of them
+    pub bytes: u64,
+
+    /// Time spent sending, overlapping transfers counted once
+    pub duration: Duration,
+}
+
+impl TransferTotals {
+    /// Adds up transfers which ran at the same time (`--copies`), the longest one is the time spent sending.
+    pub fn concurrent<'a>(transfers: impl IntoIterator<Item = &'a PeerTransfer>) -> Self {
+        transfers.into_iter().fold(TransferTotals::default(), |totals, transfer| TransferTotals {
+            peers: totals.peers + 1,
+            bytes: totals.bytes + transfer.bytes,
+            duration: totals.duration.max(transfer.duration),
+        })
+    }
+
+    /// Adds a transfer which ran after the others (`--stay-open`).
+    pub fn add_sequential(&mut self, transfer: &PeerTransfer) {
+        self.peers += 1;
+        self.bytes += transfer.bytes;
+        self.duration += transfer.duration;
+    }
+
+    /// Returns the average speed of all transfers together in bytes per second.
+    pub fn bytes_per_second(&self) -> u64 {
+        bytes_per_second(self.bytes, self.duration)
+    }
+}
+
+impl std::fmt::Display for TransferTotals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} to {} receiver{} in {:.3}s ({}/s)",
+            format_bytes(self.bytes),
+            self.peers,
+            if self.peers == 1 { "" } else { "s" },
+            self.duration.as_secs_f64(),
+            format_bytes(self.bytes_per_second())
+        )
+    }
+}
+
+fn bytes_per_second(bytes: u64, duration: Duration) -> u64 {
+    let millis = duration.as_millis();
+    if millis == 0 {
+        return bytes;
+    }
+    (u128::from(bytes) * 1000 / millis) as u64
+}
+
+/// A bandwidth limit shared equally by the receivers being sent to
+#[derive(Debug)]
+pub struct BandwidthShare {
+    /// The limit for all receivers together in bytes per second, `None` for no limit
+    rate: Option<u64>,
+
+    /// The limit in the background mode, if `rate` isn't lower
+    background_rate: u64,
+
+    /// Whether sending runs in the background mode, can be toggled while sending
+    background: AtomicBool,
+
+    /// Toggle requests followed so far, see `follow_toggles`
+    toggles: AtomicU32,
+
+    /// Receivers being sent to
+    active: AtomicU32,
+}
+
+impl BandwidthShare {
+    /// Creates the share.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - The limit for all receivers together in bytes per second (optional)
+    /// * `background_rate` - The limit in the background mode in bytes per second
+    /// * `background` - Whether to start in the background mode
+    pub fn new(rate: Option<u64>, background_rate: u64, background: bool) -> Self {
+        BandwidthShare {
+            rate,
+            background_rate,
+            background: AtomicBool::new(background),
+            toggles: AtomicU32::new(0),
+            active: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns whether sending runs in the background mode.
+    pub fn is_background(&self) -> bool {
+        self.background.load(Ordering::Relaxed)
+    }
+
+    /// Switches between the background mode and the regular limit for all receivers, once for every toggle
+    /// requested since the last call.
+    ///
+    /// # Arguments
+    ///
+    /// * `toggles` - How often toggling was requested in total.
+    ///
+    /// # Returns
+    ///
+    /// `Option<bool>` - Whether sending runs in the background mode now, `None` if the mode didn't change.
+    pub fn follow_toggles(&self, toggles: u32) -> Option<bool> {
+        let followed = self.toggles.swap(toggles, Ordering::Relaxed);
+        // toggling twice is back to the same mode
+        if toggles.wrapping_sub(followed).is_multiple_of(2) {
+            return None;
+        }
+        Some(!self.background.fetch_xor(true, Ordering::Relaxed))
+    }
+
+    /// Returns the limit for all receivers together in bytes per second, if there's a limit.
+    pub fn rate(&self) -> Option<u64> {
+        match self.is_background() {
+            true => Some(self.rate.map_or(self.background_rate, |rate| rate.min(self.background_rate))),
+            false => self.rate,
+        }
+    }
+
+    /// Counts a receiver as active until the returned pacer is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in millis, on the clock later passed to `PeerPacer::pace`.
+    pub fn join(&self, now: u64) -> PeerPacer<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        PeerPacer { share: self, budget: 0, last: now }
+    }
+
+    /// Returns the share of a single receiver in bytes per second, if there's a limit.
+    pub fn peer_rate(&self) -> Option<u64> {
+        let active = u64::from(self.active.load(Ordering::Relaxed).max(1));
+        self.rate().map(|rate| (rate / active).max(1))
+    }
+}
+
+/// Keeps a receiver to its share of the bandwidth
+#[derive(Debug)]
+pub struct PeerPacer<'a> {
+    share: &'a BandwidthShare,
+
+    /// Bytes the receiver may still be sent without waiting, negative once it's ahead of its share
+    budget: i64,
+
+    /// When the budget was topped up last (millis)
+    last: u64,
+}
+
+impl PeerPacer<'_> {
+    /// Accounts for a chunk about to be sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The size of the chunk.
+    /// * `now` - The current time in millis.
+    ///
+    /// # Returns
+    ///
+    /// `Duration` - How long to wait before sending the chunk to stay within the share, zero without a limit.
+    pub fn pace(&mut self, bytes: usize, now: u64) -> Duration {
+        let Some(rate) = self.share.peer_rate() else {
+            return Duration::ZERO;
+        };
+        let elapsed = now.saturating_sub(self.last);
+        self.last = now;
+        let burst = (rate * BURST_MILLIS / 1000) as i64;
+        let earned = (u128::from(rate) * u128::from(elapsed) / 1000).min(i64::MAX as u128) as i64;
+        self.budget = self.budget.saturating_add(earned).min(burst) - bytes as i64;
+        if self.budget >= 0 {
+            return Duration::ZERO;
+        }
+        let wait = Duration::from_millis((self.budget.unsigned_abs() * 1000).div_ceil(rate));
+        // the budget is earned while waiting
+        self.last += wait.as_millis() as u64;
+        self.budget = 0;
+        wait
+    }
+}
+
+impl Drop for PeerPacer<'_> {
+    fn drop(&mut self) {
+        self.share.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(bytes: u64, millis: u64) -> PeerTransfer {
+        PeerTransfer { peer: "peer".to_string(), bytes, duration: Duration::from_millis(millis) }
+    }
+
+    #[test]
+    fn test_totals() {
+        let transfers = [transfer(1000, 500), transfer(3000, 2000)];
+        let concurrent = TransferTotals::concurrent(&transfers);
+        assert_eq!(concurrent, TransferTotals { peers: 2, bytes: 4000, duration: Duration::from_secs(2) });
+        assert_eq!(concurrent.bytes_per_second(), 2000);
+
+        let mut sequential = TransferTotals::default();
+        transfers.iter().for_each(|transfer| sequential.add_sequential(transfer));
+        assert_eq!(sequential.duration, Duration::from_millis(2500));
+        assert_eq!(sequential.bytes_per_second(), 1600);
+        assert_eq!(transfer(10, 0).bytes_per_second(), 10);
+    }
+
+    #[test]
+    fn test_pace() {
+        let share = BandwidthShare::new(Some(4000), 1000, false);
+        let mut first = share.join(0);
+        assert_eq!(share.peer_rate(), Some(4000));
+        // 4000 bytes at 4000 bytes/s take a second
+        assert_eq!(first.pace(4000, 0), Duration::from_secs(1));
+        assert_eq!(first.pace(1000, 1000), Duration::from_millis(250));
+
+        // a second receiver halves the share
+        let mut second = share.join(1250);
+        assert_eq!(share.peer_rate(), Some(2000));
+        assert_eq!(second.pace(1000, 1250), Duration::from_millis(500));
+        drop(first);
+        assert_eq!(share.peer_rate(), Some(4000));
+        // catching up after a pause is limited to the burst
+        assert_eq!(second.pace(1000, 11750), Duration::ZERO);
+        assert_eq!(second.pace(1000, 11750), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_pace_unlimited() {
+        let share = BandwidthShare::new(None, 1000, false);
+        let mut pacer = share.join(0);
+        assert_eq!(share.peer_rate(), None);
+        assert_eq!(pacer.pace(1 << 30, 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_background() {
+        let share = BandwidthShare::new(None, 1000, true);
+        assert_eq!(share.rate(), Some(1000));
+        assert_eq!(share.follow_toggles(0), None);
+        assert_eq!(share.follow_toggles(1), Some(false));
+        assert_eq!(share.rate(), None);
+        assert_eq!(share.follow_toggles(1), None);
+        assert_eq!(share.follow_toggles(3), None);
+
+        // a lower limit is kept in the background mode
+        let share = BandwidthShare::new(Some(500), 1000, false);
+        assert_eq!(share.follow_toggles(1), Some(true));
+        assert_eq!(share.rate(), Some(500));
+        let _pacer = share.join(0);
+        let _other = share.join(0);
+        assert_eq!(share.peer_rate(), Some(250));
+    }
+}
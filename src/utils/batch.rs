@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::error::{NudgeError, Result};
+
+/// A code of a batch file (`get --batch`), a passphrase or a link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchCode {
+    /// Line of the code in the batch file, starting at 1
+    pub line: usize,
+    pub code: String,
+}
+
+/// Parses the codes of a batch file, one per line. Empty lines and comments (`# ...`) are skipped.
+///
+/// # Arguments
+///
+/// * `contents` - The contents of the batch file.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidBatch` if the file has no codes or a code appears twice.
+pub fn parse_batch(contents: &str) -> Result<Vec<BatchCode>> {
+    let mut seen = HashSet::new();
+    let mut codes = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let code = line.trim();
+        if code.is_empty() || code.starts_with('#') {
+            continue;
+        }
+        // the second download of a file would fail after the first one, so refuse it before anything moves
+        if !seen.insert(code) {
+            return Err(NudgeError::InvalidBatch(format!("the code in line {} appears twice", index + 1)));
+        }
+        codes.push(BatchCode { line: index + 1, code: code.to_string() });
+    }
+    if codes.is_empty() {
+        return Err(NudgeError::InvalidBatch("no codes found".to_string()));
+    }
+    Ok(codes)
+}
+
+/// Reads the codes of a batch file, see `parse_batch`.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the file can't be read, or the error of `parse_batch`.
+pub fn read_batch_file(path: &Path) -> Result<Vec<BatchCode>> {
+    parse_batch(&std::fs::read_to_string(path)?)
+}
+
+/// A file of a manifest file (`send --from-manifest`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedFile {
+    /// Line of the file in the manifest file, starting at 1
+    pub line: usize,
+    pub path: PathBuf,
+
+    /// Shown to the receiver, and next to the passphrase of the file with `--separate` (optional)
+    pub label: Option<String>,
+}
+
+/// Parses the files of a manifest file, one path per line, optionally followed by a tab and a label.
+/// Empty lines and comments (`# ...`) are skipped.
+///
+/// # Arguments
+///
+/// * `contents` - The contents of the manifest file.
+/// * `base` - The directory relative paths are resolved in, the one of the manifest file.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidSendManifest` if the file lists no files or a file appears twice.
+pub fn parse_send_manifest(contents: &str, base: &Path) -> Result<Vec<ListedFile>> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let (path, label) = match line.split_once('\t') {
+            Some((path, label)) => (path.trim(), Some(label.trim()).filter(|label| !label.is_empty())),
+            None => (line.trim(), None),
+        };
+        if path.is_empty() || path.starts_with('#') {
+            continue;
+        }
+        let path = base.join(path);
+        if !seen.insert(path.clone()) {
+            return Err(NudgeError::InvalidSendManifest(format!("the file in line {} appears twice", index + 1)));
+        }
+        files.push(ListedFile { line: index + 1, path, label: label.map(str::to_string) });
+    }
+    if files.is_empty() {
+        return Err(NudgeError::InvalidSendManifest("no files found".to_string()));
+    }
+    Ok(files)
+}
+
+/// Reads the files of a manifest file, see `parse_send_manifest`.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the file can't be read, or the error of `parse_send_manifest`.
+pub fn read_send_manifest(path: &Path) -> Result<Vec<ListedFile>> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    parse_send_manifest(&std::fs::read_to_string(path)?, base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch() {
+        let codes = parse_batch("# photos\nfirst-code\n\n  nudge://relay.example:4000/second-code  \n").unwrap();
+        assert_eq!(codes, vec![
+            BatchCode { line: 2, code: "first-code".to_string() },
+            BatchCode { line: 4, code: "nudge://relay.example:4000/second-code".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_send_manifest() {
+        let contents = "# reports\nq3.pdf\tQ3 report\n\n/srv/data.csv\t \nnotes.txt\n";
+        let files = parse_send_manifest(contents, Path::new("inbox")).unwrap();
+        assert_eq!(files, vec![
+            ListedFile { line: 2, path: PathBuf::from("inbox/q3.pdf"), label: Some("Q3 report".to_string()) },
+            ListedFile { line: 4, path: PathBuf::from("/srv/data.csv"), label: None },
+            ListedFile { line: 5, path: PathBuf::from("inbox/notes.txt"), label: None },
+        ]);
+        assert!(matches!(parse_send_manifest("\n# none\n", Path::new("")), Err(NudgeError::InvalidSendManifest(_))));
+        assert!(matches!(parse_send_manifest("a\n./b\na\n", Path::new("")), Err(NudgeError::InvalidSendManifest(_))));
+    }
+
+    #[test]
+    fn test_parse_invalid_batch() {
+        assert!(matches!(parse_batch("# nothing\n\n"), Err(NudgeError::InvalidBatch(_))));
+        assert!(matches!(parse_batch("code\nother\ncode\n"), Err(NudgeError::InvalidBatch(_))));
+    }
+}
@@ -0,0 +1,141 @@
+//! Blind relays (`serve --blind`) never see the passphrases. Clients passing `--blind` send a hash of the
+//! passphrase instead, which the relay hashes again with a salt of its own before storing the session under it.
+//! So neither the logs nor the statistics of the relay show a passphrase, or a hash clients would accept.
+//!
+//! Peers have to agree on the client side hash without asking the relay first, so its salt is a constant.
+//! It's derived with Argon2id instead, which makes trying the word combinations of generated passphrases against a
+//! memory dump of the relay slow, and hard to speed up with GPUs.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{LazyLock, Mutex};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::Rng;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::{deterministic, encode_hex};
+use crate::utils::passphrase::{Passphrase, PassphraseGenerator};
+
+/// Prefix of hashed passphrases, e.g. `blind:3f9a...`
+pub const BLIND_PREFIX: &str = "blind:";
+
+/// Salt of the hash clients send instead of the passphrase
+const BLIND_CONTEXT: &str = "nudge-ngx 2024 blind relay passphrase";
+
+/// Memory (KiB) and passes of Argon2id over it when hashing a passphrase for a blind relay. Both peers pay it once
+/// per session, someone guessing the passphrase from a hash once per guess
+const BLIND_KDF_MEMORY_KIB: u32 = 19 * 1024;
+const BLIND_KDF_ITERATIONS: u32 = 2;
+
+/// Hashes computed before, the senders and receivers ask for the hash of their passphrase again and again
+static BLIND_HASHES: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Mutex::default);
+
+/// Length of a hex encoded hash
+const HASH_HEX_LEN: usize = 64;
+
+/// Returns whether the passphrase is already hashed, e.g. the one of a ticket issued by a blind relay.
+pub fn is_blind(passphrase: &Passphrase) -> bool {
+    passphrase.0.strip_prefix(BLIND_PREFIX)
+        .is_some_and(|hash| hash.len() == HASH_HEX_LEN && hash.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
+/// Returns what the client sends to the relay instead of the passphrase.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase shared with the peer.
+/// * `blind` - Whether `--blind` was passed.
+///
+/// # Returns
+///
+/// `Passphrase` - The hashed passphrase with `--blind` (or if it's hashed already), the passphrase itself otherwise.
+pub fn relay_passphrase(passphrase: &Passphrase, blind: bool) -> Passphrase<'static> {
+    if !blind || is_blind(passphrase) {
+        return Passphrase::from(passphrase.0.to_string());
+    }
+    let mut hashes = BLIND_HASHES.lock().unwrap();
+    let hash = hashes.entry(passphrase.0.to_string()).or_insert_with(|| {
+        let params = Params::new(BLIND_KDF_MEMORY_KIB, BLIND_KDF_ITERATIONS, 1, Some(32)).expect("valid parameters");
+        let mut hash = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.0.as_bytes(), BLIND_CONTEXT.as_bytes(), &mut hash)
+            .expect("valid parameters");
+        encode_hex(&hash)
+    });
+    Passphrase::from(format!("{}{}", BLIND_PREFIX, hash))
+}
+
+/// Generates a passphrase on the client, since blind relays can't hand out passphrases they never see.
+///
+/// # Errors
+///
+/// Returns `NudgeError::PassphraseGenerationError` if no passphrase could be generated.
+pub fn generate_passphrase() -> Result<Passphrase<'static>> {
+    PassphraseGenerator::new()?.generate().ok_or(NudgeError::PassphraseGenerationError)
+}
+
+/// Salt a blind relay hashes the passphrases of the clients with, random per start
+#[derive(Clone, Copy)]
+pub struct BlindSalt([u8; 32]);
+
+impl BlindSalt {
+    /// Creates a random salt, the sessions are lost on restart anyway.
+    pub fn random() -> Self {
+        BlindSalt(deterministic::rng().gen())
+    }
+
+    /// Returns the key the relay stores the session of a hashed passphrase under.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::BlindPassphraseRequired` if the client sent the passphrase itself.
+    pub fn session_key(&self, passphrase: &Passphrase) -> Result<Passphrase<'static>> {
+        if !is_blind(passphrase) {
+            return Err(NudgeError::BlindPassphraseRequired);
+        }
+        let hash = blake3::keyed_hash(&self.0, passphrase.0.as_bytes());
+        Ok(Passphrase::from(format!("{}{}", BLIND_PREFIX, hash.to_hex())))
+    }
+}
+
+impl Debug for BlindSalt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BlindSalt(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_passphrase() {
+        let passphrase = Passphrase::from("correct-horse-battery");
+        assert_eq!(relay_passphrase(&passphrase, false), passphrase);
+
+        let blinded = relay_passphrase(&passphrase, true);
+        assert!(is_blind(&blinded));
+        assert!(!blinded.0.contains("horse"));
+        assert_eq!(relay_passphrase(&passphrase, true), blinded);
+        // hashed passphrases (e.g. of tickets) aren't hashed twice
+        assert_eq!(relay_passphrase(&blinded, true), blinded);
+        assert_eq!(relay_passphrase(&blinded, false), blinded);
+    }
+
+    #[test]
+    fn test_session_key() {
+        let salt = BlindSalt::random();
+        let blinded = relay_passphrase(&Passphrase::from("correct-horse-battery"), true);
+        let key = salt.session_key(&blinded).unwrap();
+        assert_ne!(key, blinded);
+        assert_eq!(salt.session_key(&blinded).unwrap(), key);
+        assert_ne!(BlindSalt([7; 32]).session_key(&blinded).unwrap(), key);
+
+        assert!(matches!(
+            salt.session_key(&Passphrase::from("correct-horse-battery")),
+            Err(NudgeError::BlindPassphraseRequired)
+        ));
+        assert!(salt.session_key(&Passphrase::from("blind:not-a-hash")).is_err());
+    }
+}
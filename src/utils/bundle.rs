@@ -0,0 +1,392 @@
+//! Sends several files at once (`send <FILE> <FILE>...`). The sender lists the files (name, size and hash) in a
+//! `FileManifestMessage` after connecting and sends their contents one after another as a single data stream,
+//! which the receiver splits up into the files again, checking the hash of each one as it's complete.
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Take, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{NudgeError, Result};
+use crate::models::{FileManifestMessage, ManifestFile};
+use crate::utils::platform::set_file_mode;
+use crate::utils::rename::RenamePattern;
+use crate::utils::sanitize::sanitize;
+use crate::utils::ui::{failure_mark, style, success_mark};
+use crate::utils::units::format_bytes;
+use crate::utils::AnonymousString;
+
+/// Files shared at once
+#[derive(Debug)]
+pub struct FileBundle {
+    paths: Vec<PathBuf>,
+    manifest: FileManifestMessage,
+}
+
+impl FileBundle {
+    /// Lists the files, not hashed yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - The files, in the order they're sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::BundleUnsupported` if a path isn't a regular file (e.g. a directory or a device),
+    /// `NudgeError::DuplicateFileName` if two files have the same name, or `NudgeError::Io` if one doesn't exist.
+    pub fn scan(paths: &[&str]) -> Result<Self> {
+        let mut files: Vec<ManifestFile> = Vec::with_capacity(paths.len());
+        for path in paths {
+            let metadata = fs::metadata(path)?;
+            let name = match Path::new(path).file_name() {
+                Some(name) if metadata.is_file() => name.to_string_lossy().to_string(),
+                _ => return Err(NudgeError::BundleUnsupported(path.to_string())),
+            };
+            if files.iter().any(|file| file.name == name) {
+                return Err(NudgeError::DuplicateFileName(name));
+            }
+            files.push(ManifestFile { name, size: metadata.len(), hash: AnonymousString(None), label: None });
+        }
+        Ok(FileBundle {
+            paths: paths.iter().map(PathBuf::from).collect(),
+            manifest: FileManifestMessage { files },
+        })
+    }
+
+    /// Labels the files, e.g. from a manifest file (`send --from-manifest`).
+    ///
+    /// # Arguments
+    ///
+    /// * `labels` - The labels, in the order of the files.
+    pub fn with_labels(mut self, labels: &[Option<String>]) -> Self {
+        for (file, label) in self.manifest.files.iter_mut().zip(labels) {
+            file.label = label.clone();
+        }
+        self
+    }
+
+    /// Hashes every file and the data stream of all of them, reading each file once.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer_size` - The size of the buffer used to read the files.
+    ///
+    /// # Returns
+    ///
+    /// `Result<String>` - The hash of the data stream.
+    pub fn hash(&mut self, buffer_size: usize) -> Result<String> {
+        let mut stream_hasher = blake3::Hasher::new();
+        let mut buffer = vec![0; buffer_size];
+        for (path, file) in self.paths.iter().zip(&mut self.manifest.files) {
+            let mut file_hasher = blake3::Hasher::new();
+            let mut content = File::open(path)?.take(file.size);
+            loop {
+                let bytes_read = content.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                file_hasher.update(&buffer[..bytes_read]);
+                stream_hasher.update(&buffer[..bytes_read]);
+            }
+            file.hash = AnonymousString(Some(file_hasher.finalize().to_hex().to_string()));
+        }
+        Ok(stream_hasher.finalize().to_hex().to_string())
+    }
+
+    /// Returns the number of files.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Returns the paths of the files, in the order they're sent.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Returns the size of all files together.
+    pub fn size(&self) -> u64 {
+        self.manifest.files.iter().map(|file| file.size).sum()
+    }
+
+    /// Returns the list of files sent to the receiver.
+    pub fn manifest(&self) -> &FileManifestMessage {
+        &self.manifest
+    }
+
+    /// Returns a reader of the data stream, opening the files one after another.
+    pub fn reader(&self) -> BundleReader<'_> {
+        BundleReader { bundle: self, index: 0, file: None }
+    }
+}
+
+/// Reads the contents of the files of a bundle one after another
+pub struct BundleReader<'a> {
+    bundle: &'a FileBundle,
+
+    /// The file read next, or being read
+    index: usize,
+    file: Option<Take<File>>,
+}
+
+impl Read for BundleReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.file {
+                let read = file.read(buffer)?;
+                if read > 0 || buffer.is_empty() {
+                    return Ok(read);
+                }
+                // the size was listed already, a file growing meanwhile is cut off
+                if file.limit() > 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("{} shrank while it was sent", self.bundle.paths[self.index].display()),
+                    ));
+                }
+                self.file = None;
+                self.index += 1;
+                continue;
+            }
+            let Some(path) = self.bundle.paths.get(self.index) else {
+                return Ok(0);
+            };
+            self.file = Some(File::open(path)?.take(self.bundle.manifest.files[self.index].size));
+        }
+    }
+}
+
+/// A file being received
+#[derive(Debug)]
+struct ReceivedFile {
+    /// Where the file is stored, next to an existing one of the same name
+    path: PathBuf,
+    file: File,
+    hasher: blake3::Hasher,
+    remaining: u64,
+}
+
+/// Splits the data stream of a bundle into the files of its manifest, stored in a directory
+#[derive(Debug)]
+pub struct BundleWriter {
+    directory: PathBuf,
+    files: Vec<ManifestFile>,
+
+    /// Names files next to existing ones, `None` to overwrite them
+    rename: Option<RenamePattern>,
+    mode: u32,
+
+    /// The file written next, or being written
+    index: usize,
+    current: Option<ReceivedFile>,
+
+    /// The first file which didn't match its hash (optional)
+    mismatched: Option<String>,
+}
+
+impl BundleWriter {
+    /// Creates the directory the files are stored in.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - Where the files are stored.
+    /// * `manifest` - The files listed by the sender.
+    /// * `rename` - Names files next to existing ones, `None` to overwrite them.
+    /// * `mode` - Permissions of the files (restricted by the umask).
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::UnsafePath` if a name isn't a plain file name, or `NudgeError::Io` if the directory
+    /// can't be created.
+    pub fn new(directory: &Path, manifest: FileManifestMessage, rename: Option<RenamePattern>, mode: u32) -> Result<Self> {
+        for file in &manifest.files {
+            let mut components = Path::new(&file.name).components();
+            if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+                return Err(NudgeError::UnsafePath(file.name.clone()));
+            }
+        }
+        fs::create_dir_all(directory)?;
+        Ok(BundleWriter {
+            directory: directory.to_path_buf(),
+            files: manifest.files,
+            rename,
+            mode,
+            index: 0,
+            current: None,
+            mismatched: None,
+        })
+    }
+
+    /// Checks all files were received completely and match their hashes.
+    ///
+    /// # Returns
+    ///
+    /// `Result<usize>` - The number of files stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::ManifestMismatch` if the data stream ended before the last file,
+    /// or `NudgeError::FileHashMismatch` if a file doesn't match its hash.
+    pub fn finish(&mut self) -> Result<usize> {
+        // empty files at the end have no data to be created by
+        while self.current.is_none() && self.files.get(self.index).is_some_and(|file| file.size == 0) {
+            self.open_next()?;
+        }
+        if self.index < self.files.len() {
+            return Err(NudgeError::ManifestMismatch);
+        }
+        match self.mismatched.take() {
+            Some(name) => Err(NudgeError::FileHashMismatch(name)),
+            None => Ok(self.files.len()),
+        }
+    }
+
+    /// Creates the next file, an empty one is complete right away.
+    fn open_next(&mut self) -> io::Result<()> {
+        let listed = self.files.get(self.index).ok_or_else(|| io::Error::other(NudgeError::ManifestMismatch))?;
+        let path = self.directory.join(&listed.name);
+        let path = match &self.rename {
+            Some(rename) => rename.unique_path(&path).map_err(io::Error::other)?,
+            None => path,
+        };
+        // creating the file would follow an existing symlink out of the directory
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(io::Error::other(NudgeError::UnsafePath(listed.name.clone())));
+        }
+        let file = File::create(&path)?;
+        set_file_mode(&file, self.mode).map_err(io::Error::other)?;
+        self.current = Some(ReceivedFile { path, file, hasher: blake3::Hasher::new(), remaining: listed.size });
+        if listed.size == 0 {
+            self.close_current();
+        }
+        Ok(())
+    }
+
+    /// Checks the hash of the complete file and reports it.
+    fn close_current(&mut self) {
+        let Some(received) = self.current.take() else {
+            return;
+        };
+        let listed = &self.files[self.index];
+        let matches = listed.hash.0.as_ref().is_none_or(|hash| *hash == received.hasher.finalize().to_hex().as_str());
+        let mark = if matches { success_mark().to_string() } else { failure_mark().to_string() };
+        let stored_name = received.path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let label = listed.label.as_deref().map(|label| format!(" {}", style(sanitize(label)).dim())).unwrap_or_default();
+        status!(
+            "{} ({}/{}) {} [{}]{}{}",
+            mark,
+            self.index + 1,
+            self.files.len(),
+            style(sanitize(&stored_name)).yellow(),
+            format_bytes(listed.size),
+            label,
+            if matches { "" } else { " hash mismatch" }
+        );
+        if !matches && self.mismatched.is_none() {
+            self.mismatched = Some(listed.name.clone());
+        }
+        self.index += 1;
+    }
+}
+
+impl Write for BundleWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut data = buffer;
+        while !data.is_empty() {
+            let Some(received) = &mut self.current else {
+                self.open_next()?;
+                continue;
+            };
+            let take = received.remaining.min(data.len() as u64) as usize;
+            received.file.write_all(&data[..take])?;
+            received.hasher.update(&data[..take]);
+            received.remaining -= take as u64;
+            data = &data[take..];
+            if received.remaining == 0 {
+                self.close_current();
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle_of(directory: &Path, files: &[(&str, &[u8])]) -> FileBundle {
+        let paths: Vec<String> = files.iter().map(|(name, content)| {
+            let path = directory.join(name);
+            fs::write(&path, content).unwrap();
+            path.display().to_string()
+        }).collect();
+        FileBundle::scan(&paths.iter().map(String::as_str).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let source = tempfile::tempdir_in(".").unwrap();
+        let mut bundle = bundle_of(source.path(), &[("a.txt", b"hello"), ("empty", b""), ("b.bin", &[7; 3000]), ("last", b"")]);
+        let stream_hash = bundle.hash(1024).unwrap();
+        assert_eq!(bundle.len(), 4);
+        assert_eq!(bundle.size(), 3005);
+
+        let mut stream = Vec::new();
+        bundle.reader().read_to_end(&mut stream).unwrap();
+        assert_eq!(stream.len(), 3005);
+        assert_eq!(blake3::hash(&stream).to_hex().to_string(), stream_hash);
+
+        let target = tempfile::tempdir_in(".").unwrap();
+        let directory = target.path().join("received");
+        let mut writer = BundleWriter::new(&directory, bundle.manifest().clone(), None, 0o644).unwrap();
+        for piece in stream.chunks(700) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 4);
+        assert_eq!(fs::read(directory.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(directory.join("b.bin")).unwrap(), vec![7; 3000]);
+        assert!(directory.join("empty").exists());
+        assert!(directory.join("last").exists());
+    }
+
+    #[test]
+    fn test_hash_mismatch_and_truncation() {
+        let source = tempfile::tempdir_in(".").unwrap();
+        let mut bundle = bundle_of(source.path(), &[("a", b"first"), ("b", b"second")]);
+        bundle.hash(1024).unwrap();
+        let target = tempfile::tempdir_in(".").unwrap();
+
+        let mut writer = BundleWriter::new(target.path(), bundle.manifest().clone(), None, 0o644).unwrap();
+        writer.write_all(b"firsTsecond").unwrap();
+        assert!(matches!(writer.finish(), Err(NudgeError::FileHashMismatch(name)) if name == "a"));
+
+        let mut writer = BundleWriter::new(target.path(), bundle.manifest().clone(), None, 0o644).unwrap();
+        writer.write_all(b"firstsec").unwrap();
+        assert!(matches!(writer.finish(), Err(NudgeError::ManifestMismatch)));
+        // more data than listed
+        assert!(writer.write_all(b"ondextra").is_err());
+    }
+
+    #[test]
+    fn test_rejects_unsafe_names_and_duplicates() {
+        let target = tempfile::tempdir_in(".").unwrap();
+        for name in ["../escaped", "/etc/escaped", "dir/file", ".."] {
+            let manifest = FileManifestMessage {
+                files: vec![ManifestFile { name: name.to_string(), size: 0, hash: AnonymousString(None), label: None }],
+            };
+            let result = BundleWriter::new(target.path(), manifest, None, 0o644);
+            assert!(matches!(result, Err(NudgeError::UnsafePath(_))), "{} should be rejected", name);
+        }
+
+        let first = tempfile::tempdir_in(".").unwrap();
+        let second = tempfile::tempdir_in(".").unwrap();
+        let paths = [first.path().join("same"), second.path().join("same")];
+        paths.iter().for_each(|path| fs::write(path, b"").unwrap());
+        let paths: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+        let result = FileBundle::scan(&[&paths[0], &paths[1]]);
+        assert!(matches!(result, Err(NudgeError::DuplicateFileName(name)) if name == "same"));
+        assert!(matches!(FileBundle::scan(&[&first.path().display().to_string()]), Err(NudgeError::BundleUnsupported(_))));
+    }
+}
@@ -0,0 +1,234 @@
+//! Writes the datagrams a peer sends and receives to a pcap file (`--capture`), to analyze transfers in Wireshark.
+//! Every datagram is wrapped in an IP and UDP header with the addresses of both peers (link-type RAW), so the
+//! capture looks like one taken on the wire. Datagrams of the reliable layer start with a header of fixed layout:
+//! the packet ID (2 bytes, big endian) and the packet type (1 byte), followed by the payload of the type.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::error::Result;
+use crate::utils::deterministic;
+
+/// Magic number of pcap files with timestamps in microseconds
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// Largest datagram stored in full
+const SNAPLEN: u32 = 262_144;
+
+/// Link-type of packets starting with their IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+
+/// Hop limit of the generated IP headers
+const TTL: u8 = 64;
+
+const PROTOCOL_UDP: u8 = 17;
+
+/// A pcap file datagrams are recorded to, shared by all connections of the process
+#[derive(Clone)]
+pub struct PacketCapture {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl std::fmt::Debug for PacketCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketCapture").finish_non_exhaustive()
+    }
+}
+
+impl PacketCapture {
+    /// Creates the pcap file, replacing an existing one.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where to write the capture.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be created.
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    fn new(mut writer: Box<dyn Write + Send>) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PacketCapture { writer: Arc::new(Mutex::new(writer)) })
+    }
+
+    /// Records a datagram sent or received. Failing to write is logged, not fatal to the transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The address the datagram was sent from.
+    /// * `destination` - The address the datagram was sent to.
+    /// * `payload` - The datagram.
+    pub fn record(&self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        let packet = encapsulate(source, destination, payload);
+        let micros = capture_time();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len().min(SNAPLEN as usize) as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..packet.len().min(SNAPLEN as usize)]);
+
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writer.write_all(&record) {
+            warn!(target: "io", "Failed to write to the capture: {}", e);
+        }
+    }
+}
+
+/// Microseconds since the unix epoch, on the deterministic clock if enabled.
+fn capture_time() -> u64 {
+    if let Some(millis) = deterministic::unix_millis() {
+        return millis * 1000;
+    }
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+/// Wraps the datagram in the IP and UDP headers it had on the wire.
+/// Addresses of different families (dual-stack sockets) are both written as IPv6.
+fn encapsulate(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = u16::try_from(8 + payload.len()).unwrap_or(u16::MAX);
+    let mut udp = Vec::with_capacity(8 + payload.len());
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&udp_length.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&[0, PROTOCOL_UDP]);
+            pseudo_header.extend_from_slice(&udp_length.to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo_header);
+
+            let total_length = u16::try_from(20 + udp.len()).unwrap_or(u16::MAX);
+            let mut packet = Vec::with_capacity(20 + udp.len());
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_length.to_be_bytes());
+            // no identification, don't fragment
+            packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, PROTOCOL_UDP, 0, 0]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+            let checksum = checksum(&packet).to_be_bytes();
+            packet[10..12].copy_from_slice(&checksum);
+            packet.extend_from_slice(&udp);
+            packet
+        }
+        (source, destination) => {
+            let (source, destination) = (to_ipv6(source), to_ipv6(destination));
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&u32::from(udp_length).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, PROTOCOL_UDP]);
+            set_udp_checksum(&mut udp, &pseudo_header);
+
+            let mut packet = Vec::with_capacity(40 + udp.len());
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_length.to_be_bytes());
+            packet.extend_from_slice(&[PROTOCOL_UDP, TTL]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+            packet.extend_from_slice(&udp);
+            packet
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Fills in the checksum of the UDP header, over the pseudo header of the IP version and the datagram.
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let sum = sum_words(pseudo_header, sum_words(udp, 0));
+    // a checksum of zero means none was computed, its complement is sent instead
+    let checksum = match fold(sum) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// The internet checksum (RFC 1071) of the data.
+fn checksum(data: &[u8]) -> u16 {
+    fold(sum_words(data, 0))
+}
+
+fn sum_words(data: &[u8], sum: u32) -> u32 {
+    data.chunks(2).fold(sum, |sum, word| {
+        let word = u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]);
+        let sum = sum + u32::from(word);
+        (sum & 0xffff) + (sum >> 16)
+    })
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let file = tempfile::NamedTempFile::new_in(".").unwrap();
+        let capture = PacketCapture::create(file.path()).unwrap();
+        let sender: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let receiver: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        capture.record(sender, receiver, &[0, 1, 0, 42]);
+        capture.clone().record("[2001:db8::1]:4000".parse().unwrap(), receiver, &[0, 1, 1]);
+        // the file is flushed once the last handle is dropped
+        drop(capture);
+
+        let mut contents = Vec::new();
+        File::open(file.path()).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(&contents[..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&contents[20..24], &LINKTYPE_RAW.to_le_bytes());
+
+        // IPv4: 20 bytes IP header, 8 bytes UDP header, 4 bytes payload
+        let ipv4 = &contents[40..72];
+        assert_eq!(&contents[32..36], &32u32.to_le_bytes());
+        assert_eq!(ipv4[0], 0x45);
+        assert_eq!(checksum(&ipv4[..20]), 0);
+        assert_eq!(&ipv4[20..24], &[0x0f, 0xa0, 0x13, 0x88]);
+        assert_eq!(&ipv4[28..], &[0, 1, 0, 42]);
+        let mut pseudo_header = vec![192, 0, 2, 1, 198, 51, 100, 7, 0, PROTOCOL_UDP, 0, 12];
+        pseudo_header.extend_from_slice(&ipv4[20..]);
+        assert_eq!(checksum(&pseudo_header), 0);
+
+        // mixed families are written as IPv6
+        let ipv6 = &contents[88..];
+        assert_eq!(ipv6.len(), 40 + 8 + 3);
+        assert_eq!(ipv6[0] >> 4, 6);
+        assert_eq!(&ipv6[24..40], &"::ffff:198.51.100.7".parse::<Ipv6Addr>().unwrap().octets());
+    }
+}
@@ -0,0 +1,230 @@
+//! Compression of the data stream (`send --compress`, `get --compress`), compiled in with the `compression` feature.
+//! The sender compresses the file (after skipping the holes of sparse files), the receiver decompresses the chunks
+//! as they arrive, so nothing but the current chunk is buffered.
+//!
+//! Receivers tell the sender whether they take zstd, which compresses better and faster than deflate.
+//! Receivers which don't (older ones) only get a deflated stream, and only if they ask for compression.
+
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+
+/// Size of the pieces the decompressed data is handed on in, bounding the memory a single chunk can inflate to
+#[cfg(feature = "compression")]
+const INFLATE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Level of zstd, fast enough for the sender to keep up with the network
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the data stream is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    Deflate,
+    Zstd,
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Algorithm::Deflate => write!(f, "deflate"),
+            Algorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Returns whether nudge was built with the `compression` feature.
+pub fn is_supported() -> bool {
+    cfg!(feature = "compression")
+}
+
+/// Chooses how to compress the data stream for a receiver.
+///
+/// # Arguments
+///
+/// * `sender_compress` - Whether the sender asks for compression (`send --compress`).
+/// * `receiver_compress` - Whether the receiver asks for compression (`get --compress`).
+/// * `receiver_zstd` - Whether the receiver takes zstd, older receivers don't tell.
+///
+/// # Returns
+///
+/// `Option<Algorithm>` - The algorithm, `None` if the stream isn't compressed: nobody asked, this build can't
+/// compress, or the sender asked but the receiver can't tell it can decompress.
+pub fn negotiate(sender_compress: bool, receiver_compress: bool, receiver_zstd: bool) -> Option<Algorithm> {
+    if !is_supported() || !(sender_compress || receiver_compress) {
+        return None;
+    }
+    if receiver_zstd {
+        Some(Algorithm::Zstd)
+    } else if receiver_compress {
+        Some(Algorithm::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Compresses the data read from a reader.
+///
+/// # Arguments
+///
+/// * `reader` - The reader of the (uncompressed) file.
+/// * `algorithm` - How to compress it.
+///
+/// # Errors
+///
+/// Returns `NudgeError::CompressionUnsupported` without the `compression` feature.
+#[cfg(feature = "compression")]
+pub fn compress<'a>(reader: Box<dyn Read + 'a>, algorithm: Algorithm) -> Result<Box<dyn Read + 'a>> {
+    Ok(match algorithm {
+        // fast compression, so the sender keeps up with the network
+        Algorithm::Deflate => Box::new(flate2::read::DeflateEncoder::new(reader, flate2::Compression::fast())),
+        Algorithm::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, ZSTD_LEVEL)?),
+    })
+}
+
+/// Compresses the data read from a reader, impossible without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+pub fn compress<'a>(_: Box<dyn Read + 'a>, _: Algorithm) -> Result<Box<dyn Read + 'a>> {
+    Err(NudgeError::CompressionUnsupported)
+}
+
+/// The state of the decompression
+#[cfg(feature = "compression")]
+enum Inflate {
+    Deflate(flate2::Decompress),
+    Zstd(zstd::stream::raw::Decoder<'static>),
+}
+
+/// Decompresses the data stream chunk by chunk
+pub struct Decompressor {
+    #[cfg(feature = "compression")]
+    inflate: Inflate,
+
+    #[cfg(feature = "compression")]
+    buffer: Vec<u8>,
+}
+
+impl Decompressor {
+    /// Creates a decompressor for a compressed data stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::CompressionUnsupported` without the `compression` feature.
+    #[cfg(feature = "compression")]
+    pub fn new(algorithm: Algorithm) -> Result<Self> {
+        let inflate = match algorithm {
+            Algorithm::Deflate => Inflate::Deflate(flate2::Decompress::new(false)),
+            Algorithm::Zstd => Inflate::Zstd(zstd::stream::raw::Decoder::new()?),
+        };
+        Ok(Decompressor { inflate, buffer: vec![0; INFLATE_BUFFER_SIZE] })
+    }
+
+    /// Creates a decompressor, impossible without the `compression` feature.
+    #[cfg(not(feature = "compression"))]
+    pub fn new(_: Algorithm) -> Result<Self> {
+        Err(NudgeError::CompressionUnsupported)
+    }
+
+    /// Decompresses a chunk of the stream and hands the data on in pieces, e.g. to write it to the output.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The next chunk of the compressed stream.
+    /// * `sink` - Called with every piece of decompressed data.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::CorruptedStream` if the stream isn't valid compressed data, or the error of the sink.
+    #[cfg(feature = "compression")]
+    pub fn decompress(&mut self, mut chunk: &[u8], mut sink: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        loop {
+            let (consumed, produced, finished) = match &mut self.inflate {
+                Inflate::Deflate(inflate) => {
+                    let (total_in, total_out) = (inflate.total_in(), inflate.total_out());
+                    let status = inflate.decompress(chunk, &mut self.buffer, flate2::FlushDecompress::None)
+                        .map_err(|e| NudgeError::CorruptedStream(e.to_string()))?;
+                    let consumed = (inflate.total_in() - total_in) as usize;
+                    let produced = (inflate.total_out() - total_out) as usize;
+                    (consumed, produced, status == flate2::Status::StreamEnd)
+                }
+                Inflate::Zstd(decoder) => {
+                    use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+                    let mut input = InBuffer::around(chunk);
+                    let mut output = OutBuffer::around(&mut self.buffer[..]);
+                    decoder.run(&mut input, &mut output).map_err(|e| NudgeError::CorruptedStream(e.to_string()))?;
+                    // a zstd stream may hold several frames, it ends with the data
+                    (input.pos(), output.pos(), false)
+                }
+            };
+            chunk = &chunk[consumed..];
+            if produced > 0 {
+                sink(&self.buffer[..produced])?;
+            }
+            // a full buffer means there may be more data pending, even if the chunk is consumed
+            if finished || (chunk.is_empty() && produced < self.buffer.len()) {
+                return Ok(());
+            }
+            if consumed == 0 && produced == 0 {
+                return Err(NudgeError::CorruptedStream("the stream doesn't make progress".to_string()));
+            }
+        }
+    }
+
+    /// Decompresses a chunk of the stream, there is nothing to decompress without the `compression` feature.
+    #[cfg(not(feature = "compression"))]
+    pub fn decompress(&mut self, _: &[u8], _: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        Err(NudgeError::CompressionUnsupported)
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..200_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        for algorithm in [Algorithm::Deflate, Algorithm::Zstd] {
+            let mut compressed = Vec::new();
+            compress(Box::new(data.as_slice()), algorithm).unwrap().read_to_end(&mut compressed).unwrap();
+            assert!(compressed.len() < data.len());
+
+            // decompress in chunks like they arrive from the network
+            let mut decompressor = Decompressor::new(algorithm).unwrap();
+            let mut inflated = Vec::new();
+            for chunk in compressed.chunks(4096) {
+                decompressor.decompress(chunk, |piece| {
+                    assert!(piece.len() <= INFLATE_BUFFER_SIZE);
+                    inflated.extend_from_slice(piece);
+                    Ok(())
+                }).unwrap();
+            }
+            assert_eq!(inflated, data, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_stream() {
+        for algorithm in [Algorithm::Deflate, Algorithm::Zstd] {
+            let mut decompressor = Decompressor::new(algorithm).unwrap();
+            let result = decompressor.decompress(&[0xff; 64], |_| Ok(()));
+            assert!(matches!(result, Err(NudgeError::CorruptedStream(_))), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(false, false, true), None);
+        assert_eq!(negotiate(true, false, true), Some(Algorithm::Zstd));
+        assert_eq!(negotiate(false, true, true), Some(Algorithm::Zstd));
+        // older receivers only take deflate, and only if they ask for it
+        assert_eq!(negotiate(false, true, false), Some(Algorithm::Deflate));
+        assert_eq!(negotiate(true, false, false), None);
+    }
+}
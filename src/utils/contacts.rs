@@ -0,0 +1,226 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::identity::PublicKey;
+use crate::utils::network_profile::NetworkProfile;
+use crate::utils::platform::config_dir;
+
+/// Name of the contact book in the configuration directory
+const CONTACTS_FILE_NAME: &str = "contacts.json";
+
+/// Version of the contact book format, raised on incompatible changes
+const CONTACTS_VERSION: u32 = 1;
+
+/// A peer files are sent to regularly, addressed by name with `send --to`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    /// Name of the contact, e.g. "alice"
+    pub name: String,
+
+    /// Public key of the contact's identity, the receiver has to prove owning it
+    pub public_key: PublicKey,
+
+    /// Relay to register sessions for this contact at, as "host:port" (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+
+    /// Network profile used for transfers to this contact (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<NetworkProfile>,
+
+    /// Name shown to this contact instead of the hostname (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+impl Contact {
+    /// Checks that a name can be typed on the command line, i.e. consists of letters, digits, `.`, `_` and `-`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidContact` if the name is empty or contains other characters.
+    pub fn validate_name(name: &str) -> Result<String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+            return Err(NudgeError::InvalidContact(format!(
+                "{:?}: names consist of letters, digits, '.', '_' and '-'", name
+            )));
+        }
+        Ok(name.to_string())
+    }
+}
+
+/// The contacts as stored on disk, e.g.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "contacts": [
+///     { "name": "alice", "public_key": "ed25519:3d4017c3...", "relay": "relay.example:4000", "profile": "wan" }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactBook {
+    /// Version of the format
+    version: u32,
+
+    /// The contacts, sorted by name
+    contacts: Vec<Contact>,
+}
+
+impl Default for ContactBook {
+    fn default() -> Self {
+        ContactBook { version: CONTACTS_VERSION, contacts: Vec::new() }
+    }
+}
+
+impl ContactBook {
+    /// Returns the path of the contact book in the configuration directory, see `config_dir`.
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(CONTACTS_FILE_NAME))
+    }
+
+    /// Opens the contact book in the configuration directory, see `config_dir`.
+    /// Without a configuration directory, the book is empty.
+    pub fn open() -> Result<Self> {
+        match Self::default_path() {
+            Some(path) => Self::load(&path),
+            None => Ok(ContactBook::default()),
+        }
+    }
+
+    /// Reads the contact book. A missing file results in an empty book.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidContact` if the file is corrupt.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ContactBook::default()),
+            contents => contents?,
+        };
+        let book: ContactBook = serde_json::from_slice(&contents)
+            .map_err(|e| NudgeError::InvalidContact(format!("{}: {}", path.display(), e)))?;
+        if book.version != CONTACTS_VERSION {
+            return Err(NudgeError::InvalidContact(format!("{}: unsupported version {}", path.display(), book.version)));
+        }
+        Ok(book)
+    }
+
+    /// Writes the contact book, replacing the file atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    /// Returns the contact with the name.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::UnknownContact` if there is none.
+    pub fn get(&self, name: &str) -> Result<&Contact> {
+        self.contacts.iter()
+            .find(|contact| contact.name == name)
+            .ok_or_else(|| NudgeError::UnknownContact(name.to_string()))
+    }
+
+    /// Returns the contact owning the public key (if any).
+    pub fn find_by_key(&self, public_key: &PublicKey) -> Option<&Contact> {
+        self.contacts.iter().find(|contact| contact.public_key == *public_key)
+    }
+
+    /// Adds a contact, or replaces the one with the same name.
+    ///
+    /// # Returns
+    ///
+    /// `Option<Contact>` - The replaced contact (if any).
+    pub fn insert(&mut self, contact: Contact) -> Option<Contact> {
+        let replaced = self.remove(&contact.name);
+        let index = self.contacts.partition_point(|other| other.name < contact.name);
+        self.contacts.insert(index, contact);
+        replaced
+    }
+
+    /// Removes the contact with the name, returning it (if any).
+    pub fn remove(&mut self, name: &str) -> Option<Contact> {
+        let index = self.contacts.iter().position(|contact| contact.name == name)?;
+        Some(self.contacts.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(name: &str, key: u8) -> Contact {
+        Contact {
+            name: name.to_string(),
+            public_key: PublicKey::parse(&format!("{:02x}", key).repeat(32)).unwrap(),
+            relay: None,
+            profile: None,
+            display_name: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(Contact::validate_name("alice").is_ok());
+        assert!(Contact::validate_name("bob.work-2").is_ok());
+        assert!(Contact::validate_name("").is_err());
+        assert!(Contact::validate_name("alice smith").is_err());
+        assert!(Contact::validate_name("../alice").is_err());
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut book = ContactBook::default();
+        assert_eq!(book.insert(contact("carol", 3)), None);
+        assert_eq!(book.insert(contact("alice", 1)), None);
+        assert_eq!(book.insert(contact("alice", 2)), Some(contact("alice", 1)));
+        let names: Vec<&str> = book.contacts().iter().map(|contact| contact.name.as_str()).collect();
+        assert_eq!(names, ["alice", "carol"]);
+
+        assert_eq!(book.get("alice").unwrap(), &contact("alice", 2));
+        assert!(matches!(book.get("bob"), Err(NudgeError::UnknownContact(_))));
+        assert_eq!(book.find_by_key(&contact("", 3).public_key).unwrap().name, "carol");
+        assert_eq!(book.find_by_key(&contact("", 1).public_key), None);
+
+        assert_eq!(book.remove("carol"), Some(contact("carol", 3)));
+        assert_eq!(book.remove("carol"), None);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTACTS_FILE_NAME);
+        assert_eq!(ContactBook::load(&path).unwrap(), ContactBook::default());
+
+        let mut book = ContactBook::default();
+        book.insert(Contact {
+            relay: Some("relay.example:4000".to_string()),
+            profile: Some(NetworkProfile::Wan),
+            ..contact("alice", 1)
+        });
+        book.save(&path).unwrap();
+        assert_eq!(ContactBook::load(&path).unwrap(), book);
+        assert!(fs::read_to_string(&path).unwrap().contains(r#""profile": "wan""#));
+
+        fs::write(&path, "[]").unwrap();
+        assert!(matches!(ContactBook::load(&path), Err(NudgeError::InvalidContact(_))));
+    }
+}
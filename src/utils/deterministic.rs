@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Seed of the random numbers with `--deterministic`
+const SEED: u64 = 0x006e_7564_6765; // "nudge"
+
+/// Time the clock starts at with `--deterministic` (2024-01-01T00:00:00Z, unix millis)
+const EPOCH_MILLIS: u64 = 1_704_067_200_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// When `--deterministic` was enabled, the clock advances from `EPOCH_MILLIS` from then on
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Makes the process reproducible for golden-output tests (`--deterministic`):
+/// random numbers are seeded, the clock starts at a fixed time and advances monotonically.
+pub fn enable() {
+    START.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether `--deterministic` was passed.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the time of the deterministic clock, or `None` if it's not enabled.
+///
+/// # Returns
+///
+/// `Option<u64>` - Milliseconds since `EPOCH_MILLIS` was "now", as unix millis.
+pub fn unix_millis() -> Option<u64> {
+    if !is_enabled() {
+        return None;
+    }
+    let elapsed = START.get().map_or(0, |start| start.elapsed().as_millis() as u64);
+    Some(EPOCH_MILLIS + elapsed)
+}
+
+/// Returns a random number generator, which starts with the same numbers every run with `--deterministic`.
+pub fn rng() -> StdRng {
+    if is_enabled() {
+        StdRng::seed_from_u64(SEED)
+    } else {
+        StdRng::from_entropy()
+    }
+}
+
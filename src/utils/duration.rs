@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use crate::error::{NudgeError, Result};
+
+/// Parses a human readable duration like "90s", "15m", "1h30m" or "2d".
+/// A number without unit is interpreted as seconds.
+///
+/// # Arguments
+///
+/// * `input` - The duration to parse.
+///
+/// # Returns
+///
+/// * `Ok(Duration)` - The parsed duration.
+/// * `Err(NudgeError::InvalidDuration)` - If the input is empty, has an unknown unit or overflows.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let invalid = || NudgeError::InvalidDuration(input.to_string());
+    if input.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut total_secs: u64 = 0;
+    let mut number = String::new();
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total_secs = value.checked_mul(multiplier)
+            .and_then(|secs| total_secs.checked_add(secs))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total_secs = total_secs.checked_add(value).ok_or_else(invalid)?;
+    }
+
+    Ok(Duration::from_secs(total_secs))
+}
+
+/// Formats a duration like "1h30m" (the inverse of `parse_duration`).
+///
+/// # Arguments
+///
+/// * `duration` - The duration to format.
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    if secs == 0 {
+        return "0s".to_string();
+    }
+
+    let mut formatted = String::new();
+    for (unit, unit_secs) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if secs >= unit_secs {
+            formatted.push_str(&format!("{}{}", secs / unit_secs, unit));
+            secs %= unit_secs;
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(2 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn test_parse_invalid_duration() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("15x").is_err());
+        assert!(parse_duration("99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(90 * 60)), "1h30m");
+        assert_eq!(format_duration(Duration::from_secs(24 * 60 * 60 + 5)), "1d5s");
+    }
+}
@@ -0,0 +1,432 @@
+//! Encryption of the data stream between sender and receiver, compiled in with the `crypto` feature.
+//!
+//! The key is agreed on with a key exchange authenticated by the passphrase (SPAKE2, see `KeyExchange`), so
+//! nobody but the peers learns it, and someone in between who doesn't know the passphrase can't take over the
+//! session. The exchange is bound to a random salt of the receiver (`encryption_salt` of the connection request). Which
+//! side encrypts isn't negotiated: a peer asking for encryption refuses to go on if the other declines, so someone in
+//! between can't strip it. Every data packet is sealed with ChaCha20-Poly1305 on its own, with a nonce sent along,
+//! so lost, resent and reordered packets can still be opened. The associated data binds each packet to the session
+//! (the salt of the receiver is its ID) and to its full 64 bit number, so a packet can neither be replayed once the
+//! 16 bit packet IDs wrapped around nor in another session. Each direction has a key of its own.
+//!
+//! Relays never see the data stream, and with `--blind` not even the passphrase, so they can't derive the key.
+//!
+//! The peers of `exchange`, `pipe` and `forward` agree on the key the same way, the joining side choosing the salt.
+//! A ticket of a blind relay only carries the hash of the passphrase, so its receiver can't derive the key and is
+//! refused unless it passes `--allow-unencrypted`.
+
+#[cfg(feature = "crypto")]
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit};
+use rand::Rng;
+#[cfg(feature = "crypto")]
+use spake2::{Ed25519Group, Spake2};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::passphrase::Passphrase;
+#[cfg(feature = "crypto")]
+use crate::utils::encode_hex;
+
+/// Length of the salt of the receiver in bytes
+pub const SALT_LENGTH: usize = 16;
+
+/// Bytes a sealed frame is longer than the frame: the packet type of sealed frames, the nonce and the tag
+pub const SEAL_OVERHEAD: usize = 1 + NONCE_LENGTH + TAG_LENGTH;
+
+/// Identities of the sides of the key exchange, so the key is bound to who sends and who receives
+#[cfg(feature = "crypto")]
+const SENDER_IDENTITY: &[u8] = b"nudge-ngx sender";
+#[cfg(feature = "crypto")]
+const RECEIVER_IDENTITY: &[u8] = b"nudge-ngx receiver";
+
+/// Contexts of the keys derived from the key agreed on in the key exchange
+#[cfg(feature = "crypto")]
+const STREAM_KEY_CONTEXT: &str = "nudge-ngx 2024 data stream key";
+#[cfg(feature = "crypto")]
+const CONFIRMATION_KEY_CONTEXT: &str = "nudge-ngx 2024 key confirmation";
+
+/// Length of the associated data of a sealed frame: the session ID, the number of the packet and its ID
+#[cfg(feature = "crypto")]
+const ASSOCIATED_DATA_LENGTH: usize = SALT_LENGTH + 8 + 2;
+
+/// Length of nonces and tags of ChaCha20-Poly1305
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// Returns whether nudge was built with the `crypto` feature.
+pub fn is_supported() -> bool {
+    cfg!(feature = "crypto")
+}
+
+/// Creates the random salt of the receiver, sent to the sender with the connection request.
+pub fn generate_salt() -> [u8; SALT_LENGTH] {
+    rand::thread_rng().gen()
+}
+
+/// The side of the session, both sides seal with the key of their direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// Seals the frames sent and opens the frames received on a connection
+pub struct FrameCipher {
+    /// Key of the frames sent
+    seal_key: [u8; 32],
+
+    /// Key of the frames received
+    open_key: [u8; 32],
+
+    /// Random beginning of the nonces of the frames sent, followed by a counter
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    nonce_prefix: [u8; 4],
+
+    /// Frames sealed so far, never reused in a nonce
+    sealed: u64,
+
+    /// ID of the session, the salt of the receiver
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    session_id: [u8; SALT_LENGTH],
+}
+
+impl std::fmt::Debug for FrameCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the keys stay out of debug output
+        f.debug_struct("FrameCipher").field("sealed", &self.sealed).finish_non_exhaustive()
+    }
+}
+
+impl FrameCipher {
+    /// Derives the keys of both directions from the key agreed on in the key exchange.
+    #[cfg(feature = "crypto")]
+    fn with_key(key: &[u8; 32], role: Role, session_id: [u8; SALT_LENGTH]) -> Self {
+        let to_receiver = *blake3::keyed_hash(key, b"sender to receiver").as_bytes();
+        let to_sender = *blake3::keyed_hash(key, b"receiver to sender").as_bytes();
+        let (seal_key, open_key) = match role {
+            Role::Sender => (to_receiver, to_sender),
+            Role::Receiver => (to_sender, to_receiver),
+        };
+        FrameCipher { seal_key, open_key, nonce_prefix: rand::thread_rng().gen(), sealed: 0, session_id }
+    }
+
+    /// Seals a frame: the 3 byte header stays readable with the packet type replaced, the original type and
+    /// the data are encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame, its header and data.
+    /// * `sealed_type` - The packet type of sealed frames.
+    /// * `number` - The number of the packet in the session, its ID before wrapping around.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<u8>` - The header, the nonce, the encrypted type and data, and the tag.
+    #[cfg(feature = "crypto")]
+    pub fn seal(&mut self, frame: &[u8], sealed_type: u8, number: u64) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LENGTH];
+        nonce[..4].copy_from_slice(&self.nonce_prefix);
+        nonce[4..].copy_from_slice(&self.sealed.to_be_bytes());
+        self.sealed += 1;
+
+        let mut sealed = Vec::with_capacity(frame.len() + SEAL_OVERHEAD);
+        sealed.extend_from_slice(&[frame[0], frame[1], sealed_type]);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&frame[2..]);
+        let tag = ChaCha20Poly1305::new(&self.seal_key.into())
+            .encrypt_in_place_detached(&nonce.into(), &self.associated_data(frame, number), &mut sealed[3 + NONCE_LENGTH..])
+            .expect("frames are far shorter than the limit of ChaCha20-Poly1305");
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    /// Seals a frame, impossible without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn seal(&mut self, frame: &[u8], _: u8, _: u64) -> Vec<u8> {
+        frame.to_vec()
+    }
+
+    /// Opens a frame of the peer in place, so it's left with its header and data like a frame which wasn't sealed.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The sealed frame.
+    /// * `number` - The number of the packet the peer sealed it as, see `seal`.
+    ///
+    /// # Returns
+    ///
+    /// `Option<usize>` - The length of the opened frame, `None` if the frame was forged, damaged or replayed.
+    pub fn open(&self, frame: &mut [u8], number: u64) -> Option<usize> {
+        self.open_frame(&self.open_key, frame, number)
+    }
+
+    /// Opens a frame we sealed, e.g. to split it into smaller ones.
+    ///
+    /// # Returns
+    ///
+    /// `Option<Vec<u8>>` - The frame with its header and data, `None` if it wasn't sealed by us.
+    pub fn open_sent(&self, frame: &[u8], number: u64) -> Option<Vec<u8>> {
+        let mut frame = frame.to_vec();
+        let length = self.open_frame(&self.seal_key, &mut frame, number)?;
+        frame.truncate(length);
+        Some(frame)
+    }
+
+    /// Returns the associated data of a frame: the session ID, the number of the packet and its ID.
+    #[cfg(feature = "crypto")]
+    fn associated_data(&self, frame: &[u8], number: u64) -> [u8; ASSOCIATED_DATA_LENGTH] {
+        let mut associated_data = [0; ASSOCIATED_DATA_LENGTH];
+        associated_data[..SALT_LENGTH].copy_from_slice(&self.session_id);
+        associated_data[SALT_LENGTH..SALT_LENGTH + 8].copy_from_slice(&number.to_be_bytes());
+        associated_data[SALT_LENGTH + 8..].copy_from_slice(&frame[..2]);
+        associated_data
+    }
+
+    #[cfg(feature = "crypto")]
+    fn open_frame(&self, key: &[u8; 32], frame: &mut [u8], number: u64) -> Option<usize> {
+        // the header, the nonce, at least the packet type and the tag
+        if frame.len() < 3 + NONCE_LENGTH + 1 + TAG_LENGTH {
+            return None;
+        }
+        let associated_data = self.associated_data(frame, number);
+        let nonce: [u8; NONCE_LENGTH] = frame[3..3 + NONCE_LENGTH].try_into().ok()?;
+        let (sealed, tag) = frame.split_at_mut(frame.len() - TAG_LENGTH);
+        let (_, ciphertext) = sealed.split_at_mut(3 + NONCE_LENGTH);
+        // the data is only decrypted once the tag matched
+        ChaCha20Poly1305::new(key.into())
+            .decrypt_in_place_detached(&nonce.into(), &associated_data, ciphertext, (&*tag).into())
+            .ok()?;
+        let length = ciphertext.len();
+        frame.copy_within(3 + NONCE_LENGTH..3 + NONCE_LENGTH + length, 2);
+        Some(2 + length)
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn open_frame(&self, _: &[u8; 32], _: &mut [u8], _: u64) -> Option<usize> {
+        None
+    }
+}
+
+/// A key exchange authenticated by the passphrase in progress (SPAKE2 over edwards25519, see the `spake2` crate).
+/// The sender is side A, the receiver B.
+pub struct KeyExchange {
+    #[cfg(feature = "crypto")]
+    spake2: Spake2<Ed25519Group>,
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    role: Role,
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    session_id: [u8; SALT_LENGTH],
+}
+
+/// The key agreed on with the peer, before its confirmation was checked
+pub struct ExchangedKey {
+    /// Key of the confirmations, derived from the shared key
+    #[cfg(feature = "crypto")]
+    confirmation_key: [u8; 32],
+
+    /// Key of the data stream, derived from the shared key
+    #[cfg(feature = "crypto")]
+    stream_key: [u8; 32],
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    role: Role,
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    session_id: [u8; SALT_LENGTH],
+}
+
+impl KeyExchange {
+    /// Starts the key exchange.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase as given, not the hash a blind relay knows.
+    /// * `salt` - The salt of the receiver, so the exchange is bound to the connection request. It's also the ID of
+    ///   the session the packets are sealed for.
+    /// * `role` - Whether we send or receive the file.
+    ///
+    /// # Returns
+    ///
+    /// `Result<(Self, Vec<u8>)>` - The exchange and the element to send to the peer (`KeyExchangeMessage`).
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidEncryptionSalt` if the salt isn't `SALT_LENGTH` bytes long,
+    /// or `NudgeError::EncryptionUnsupported` without the `crypto` feature.
+    #[cfg(feature = "crypto")]
+    pub fn start(passphrase: &Passphrase, salt: &[u8], role: Role) -> Result<(Self, Vec<u8>)> {
+        let session_id = salt.try_into().map_err(|_| NudgeError::InvalidEncryptionSalt(encode_hex(salt)))?;
+        let password = spake2::Password::new([salt, passphrase.0.as_bytes()].concat());
+        let (sender, receiver) = (spake2::Identity::new(SENDER_IDENTITY), spake2::Identity::new(RECEIVER_IDENTITY));
+        let (spake2, element) = match role {
+            Role::Sender => Spake2::<Ed25519Group>::start_a(&password, &sender, &receiver),
+            Role::Receiver => Spake2::<Ed25519Group>::start_b(&password, &sender, &receiver),
+        };
+        Ok((KeyExchange { spake2, role, session_id }, element))
+    }
+
+    /// Starts the key exchange, impossible without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn start(_: &Passphrase, _: &[u8], _: Role) -> Result<(Self, Vec<u8>)> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+
+    /// Finishes the key exchange with the element of the peer.
+    ///
+    /// # Returns
+    ///
+    /// `Result<ExchangedKey>` - The key, only matching the peer's if it knows the passphrase.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::KeyExchangeFailed` if the element of the peer isn't valid.
+    #[cfg(feature = "crypto")]
+    pub fn finish(self, peer_element: &[u8]) -> Result<ExchangedKey> {
+        let shared = self.spake2.finish(peer_element).map_err(|_| NudgeError::KeyExchangeFailed)?;
+        Ok(ExchangedKey {
+            confirmation_key: blake3::derive_key(CONFIRMATION_KEY_CONTEXT, &shared),
+            stream_key: blake3::derive_key(STREAM_KEY_CONTEXT, &shared),
+            role: self.role,
+            session_id: self.session_id,
+        })
+    }
+
+    /// Finishes the key exchange, impossible without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn finish(self, _: &[u8]) -> Result<ExchangedKey> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+}
+
+impl ExchangedKey {
+    /// Returns the confirmation to send to the peer (`KeyConfirmationMessage`), proving we derived the same key.
+    #[cfg(feature = "crypto")]
+    pub fn confirmation(&self) -> Vec<u8> {
+        self.confirmation_of(self.role).as_bytes().to_vec()
+    }
+
+    /// Returns the confirmation of a side, the shared key is already bound to the whole exchange.
+    #[cfg(feature = "crypto")]
+    fn confirmation_of(&self, role: Role) -> blake3::Hash {
+        let identity = match role {
+            Role::Sender => SENDER_IDENTITY,
+            Role::Receiver => RECEIVER_IDENTITY,
+        };
+        blake3::keyed_hash(&self.confirmation_key, identity)
+    }
+
+    /// Returns the confirmation to send to the peer, none without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn confirmation(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Checks the confirmation of the peer, proving it knows the passphrase, and keys the data stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::KeyExchangeFailed` if the peer derived another key.
+    #[cfg(feature = "crypto")]
+    pub fn confirm(self, peer_confirmation: &[u8]) -> Result<FrameCipher> {
+        let peer_role = match self.role {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        };
+        // comparing blake3 hashes takes constant time
+        let confirmed = <[u8; 32]>::try_from(peer_confirmation)
+            .is_ok_and(|confirmation| blake3::Hash::from(confirmation) == self.confirmation_of(peer_role));
+        if !confirmed {
+            return Err(NudgeError::KeyExchangeFailed);
+        }
+        Ok(FrameCipher::with_key(&self.stream_key, self.role, self.session_id))
+    }
+
+    /// Checks the confirmation of the peer, impossible without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn confirm(self, _: &[u8]) -> Result<FrameCipher> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+}
+
+/// Runs the key exchange of both sides, returning the ciphers of the sender and the receiver.
+#[cfg(all(test, feature = "crypto"))]
+pub(crate) fn exchange_for_test(passphrase: &Passphrase, salt: &[u8]) -> (FrameCipher, FrameCipher) {
+    let (sender, sender_element) = KeyExchange::start(passphrase, salt, Role::Sender).unwrap();
+    let (receiver, receiver_element) = KeyExchange::start(passphrase, salt, Role::Receiver).unwrap();
+    let sender = sender.finish(&receiver_element).unwrap();
+    let receiver = receiver.finish(&sender_element).unwrap();
+    let (sender_confirmation, receiver_confirmation) = (sender.confirmation(), receiver.confirmation());
+    (sender.confirm(&receiver_confirmation).unwrap(), receiver.confirm(&sender_confirmation).unwrap())
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+
+    const SEALED: u8 = 7;
+
+    #[test]
+    fn test_seal_and_open() {
+        let passphrase = Passphrase::from("beaver-canoe-tulip".to_string());
+        let (mut sender, receiver) = exchange_for_test(&passphrase, &generate_salt());
+
+        let frame = [0, 42, 0, b'd', b'a', b't', b'a'];
+        let mut sealed = sender.seal(&frame, SEALED, 42);
+        assert_eq!(sealed.len(), frame.len() + SEAL_OVERHEAD);
+        assert_eq!(&sealed[..3], &[0, 42, SEALED]);
+        assert!(!sealed.windows(4).any(|window| window == b"data"));
+        // every frame gets a nonce of its own
+        assert_ne!(sender.seal(&frame, SEALED, 42)[3..], sealed[3..]);
+        assert_eq!(sender.open_sent(&sealed, 42).unwrap(), frame);
+
+        // sealed frames only open in the other direction
+        assert!(sender.open(&mut sealed.clone(), 42).is_none());
+        let length = receiver.open(&mut sealed, 42).unwrap();
+        assert_eq!(&sealed[..length], &frame);
+    }
+
+    #[test]
+    fn test_key_exchange() {
+        let passphrase = Passphrase::from("beaver-canoe-tulip".to_string());
+        let salt = generate_salt();
+        let (mut sender, receiver) = exchange_for_test(&passphrase, &salt);
+
+        let frame = [0, 42, 0, b'd', b'a', b't', b'a'];
+        let mut sealed = sender.seal(&frame, SEALED, 42);
+        let length = receiver.open(&mut sealed, 42).unwrap();
+        assert_eq!(&sealed[..length], &frame);
+
+        // a peer without the passphrase can't confirm
+        let (sender, sender_element) = KeyExchange::start(&passphrase, &salt, Role::Sender).unwrap();
+        let guess = Passphrase::from("beaver-canoe-tuba".to_string());
+        let (guesser, guesser_element) = KeyExchange::start(&guess, &salt, Role::Receiver).unwrap();
+        let guesser_confirmation = guesser.finish(&sender_element).unwrap().confirmation();
+        let sender = sender.finish(&guesser_element).unwrap();
+        assert!(matches!(sender.confirm(&guesser_confirmation), Err(NudgeError::KeyExchangeFailed)));
+
+        // nor by reflecting the sender's own element
+        let (sender, sender_element) = KeyExchange::start(&passphrase, &salt, Role::Sender).unwrap();
+        assert!(matches!(sender.finish(&sender_element), Err(NudgeError::KeyExchangeFailed)));
+    }
+
+    #[test]
+    fn test_open_forged() {
+        let passphrase = Passphrase::from("beaver-canoe-tulip".to_string());
+        let (mut sender, receiver) = exchange_for_test(&passphrase, &generate_salt());
+        let (_, other_receiver) = exchange_for_test(&passphrase, &generate_salt());
+        let sealed = sender.seal(&[0, 1, 0, 42], SEALED, 1);
+        // every session has a key of its own
+        assert!(other_receiver.open(&mut sealed.clone(), 1).is_none());
+
+        // the packet ID can't be changed, e.g. to reorder the data
+        let mut renumbered = sealed.clone();
+        renumbered[1] = 2;
+        assert!(receiver.open(&mut renumbered, 2).is_none());
+        assert!(receiver.open(&mut sealed[..10].to_vec(), 1).is_none());
+        // nor replayed once the packet IDs wrapped around
+        assert!(receiver.open(&mut sealed.clone(), 1 + 0x10000).is_none());
+        assert!(receiver.open(&mut sealed.clone(), 1).is_some());
+
+        // nor in a session with another ID, even with the same key
+        let sealed = FrameCipher::with_key(&[1; 32], Role::Sender, [1; SALT_LENGTH]).seal(&[0, 1, 0, 42], SEALED, 1);
+        assert!(FrameCipher::with_key(&[1; 32], Role::Receiver, [2; SALT_LENGTH]).open(&mut sealed.clone(), 1).is_none());
+        assert!(FrameCipher::with_key(&[1; 32], Role::Receiver, [1; SALT_LENGTH]).open(&mut sealed.clone(), 1).is_some());
+    }
+}
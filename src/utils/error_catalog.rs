@@ -0,0 +1,330 @@
+//! Explanations of the errors users run into most, with their causes and how to fix them. `NudgeError::code` names
+//! the explanation of an error, its hint is printed below the error and `nudge explain <CODE>` prints all of it.
+//!
+//! Codes are grouped by where things went wrong: `E1xx` passphrases and sessions, `E2xx` the relay, `E3xx` the
+//! connection between the peers, `E4xx` files, `E5xx` identities and `E6xx` the build. Codes are never reused.
+
+use crate::error::NudgeError;
+
+/// Explanation of an error
+#[derive(Debug)]
+pub struct Explanation {
+    /// Stable code of the error, e.g. `E101`
+    pub code: &'static str,
+
+    /// What happened, in a few words
+    pub title: &'static str,
+
+    /// The most likely fix, printed below the error
+    pub hint: &'static str,
+
+    pub causes: &'static [&'static str],
+    pub fixes: &'static [&'static str],
+}
+
+pub const EXPLANATIONS: [Explanation; 28] = [
+    Explanation {
+        code: "E101",
+        title: "Passphrase not found",
+        hint: "the code may have expired, been used by another receiver or been mistyped, check it with the sender",
+        causes: &[
+            "The passphrase was mistyped, e.g. a word swapped or a dash missing",
+            "No receiver connected within the sender's --session-ttl, so the relay forgot the passphrase",
+            "Another receiver already claimed the session (unless the sender passed --stay-open or --copies)",
+            "Sender and receiver use different relays, or only one of them passes --blind",
+        ],
+        fixes: &[
+            "Compare the passphrase with the one the sender printed, or ask for a link (send --link) to copy instead",
+            "Check that both use the same --relay-host and --relay-port, and both or neither pass --blind",
+            "Ask the sender to send again for a new passphrase",
+        ],
+    },
+    Explanation {
+        code: "E102",
+        title: "Passphrase already in use",
+        hint: "another sender registered this passphrase, choose another --code or let nudge generate one",
+        causes: &["A session with the same passphrase is registered at the relay, e.g. by an earlier send still running"],
+        fixes: &[
+            "Stop the earlier sender, or wait until its --session-ttl is over",
+            "Pass another --code, or none to get a generated passphrase",
+        ],
+    },
+    Explanation {
+        code: "E103",
+        title: "No passphrase given",
+        hint: "pass the passphrase as argument, via NUDGE_PASSPHRASE or --passphrase-stdin",
+        causes: &["`get` was run without a passphrase, and NUDGE_PASSPHRASE isn't set"],
+        fixes: &["Run `nudge get <PASSPHRASE>`, or pipe it in with `--passphrase-stdin`"],
+    },
+    Explanation {
+        code: "E104",
+        title: "Passphrase expired",
+        hint: "no receiver connected within --session-ttl, raise it or send again",
+        causes: &["The relay forgot the passphrase since no receiver connected within the sender's --session-ttl"],
+        fixes: &["Send again, with a longer --session-ttl if the receiver needs more time"],
+    },
+    Explanation {
+        code: "E105",
+        title: "Passphrase meant for another command",
+        hint: "the passphrase belongs to a session of another command, run the one named in the error",
+        causes: &["The passphrase was created by e.g. `nudge pipe` or `nudge exchange`, but used with `nudge get`"],
+        fixes: &["Run the command named in the error with the passphrase"],
+    },
+    Explanation {
+        code: "E201",
+        title: "The relay doesn't answer",
+        hint: "check the relay host and port, pass --tunnel if the network blocks UDP, or raise --relay-timeout",
+        causes: &[
+            "The relay isn't running, or runs on another host or port",
+            "A firewall drops UDP to the relay",
+            "The relay is slow to answer, e.g. over a congested link",
+        ],
+        fixes: &[
+            "Check --relay-host and --relay-port (or NUDGE_RELAY_HOST and NUDGE_RELAY_PORT)",
+            "Run `nudge doctor` to see whether the relay's host resolves",
+            "Pass --tunnel to reach the relay over TCP if the network blocks UDP, e.g. guest Wi-Fi (the relay has to run `serve --tunnel`)",
+            "Raise --relay-timeout, or name --backup-relay for the sender to fall back to",
+        ],
+    },
+    Explanation {
+        code: "E202",
+        title: "The relay stopped responding",
+        hint: "the relay went away while waiting for a receiver, send again once it's back",
+        causes: &["The relay was restarted or became unreachable while the sender waited for a receiver"],
+        fixes: &["Send again once the relay is back, or name --backup-relay for the sender to fall back to"],
+    },
+    Explanation {
+        code: "E203",
+        title: "Rate limited by the relay",
+        hint: "the relay got too many requests from your address, wait a minute and try again",
+        causes: &["Many requests from the same address within a short time, e.g. a script retrying in a loop"],
+        fixes: &["Wait a minute before trying again, and space out automated transfers"],
+    },
+    Explanation {
+        code: "E204",
+        title: "The relay is busy",
+        hint: "the relay holds as many sessions as it takes, try again after the time in the error",
+        causes: &["The relay reached its limit of sessions"],
+        fixes: &["Try again after the time named in the error, or use another relay"],
+    },
+    Explanation {
+        code: "E205",
+        title: "The relay is blind",
+        hint: "the relay only accepts hashed passphrases, pass --blind on both sender and receiver",
+        causes: &["The relay was started with --blind, but the client sent the plain passphrase"],
+        fixes: &["Pass --blind (or set NUDGE_BLIND=true) on both sender and receiver"],
+    },
+    Explanation {
+        code: "E206",
+        title: "Address not allowed",
+        hint: "the relay's allow or deny list excludes your address, ask its operator",
+        causes: &["The relay's configuration denies your address, or only allows others"],
+        fixes: &["Ask the operator of the relay to allow your address, or use another relay"],
+    },
+    Explanation {
+        code: "E207",
+        title: "Relay token required",
+        hint: "the relay only registers passphrases with a valid --relay-token",
+        causes: &["The relay requires a token to register passphrases, and none or a wrong one was passed"],
+        fixes: &["Pass the token of the relay with --relay-token or NUDGE_RELAY_TOKEN"],
+    },
+    Explanation {
+        code: "E208",
+        title: "Session ticket required",
+        hint: "the sender only hands out the file for its session ticket, ask for it and pass --ticket",
+        causes: &["The sender asked the relay to only hand out the file for a signed ticket"],
+        fixes: &["Ask the sender for the ticket and pass it with --ticket or --ticket-file"],
+    },
+    Explanation {
+        code: "E209",
+        title: "Sender offline",
+        hint: "the sender only offered the file and was asked to come online, try again in a while",
+        causes: &["The sender registered the file with --offer-only and isn't running right now"],
+        fixes: &["Try again once the sender is online, the relay already asked it to come online"],
+    },
+    Explanation {
+        code: "E301",
+        title: "No direct connection to the peer",
+        hint: "a firewall or NAT probably blocks the direct connection, try --port-mapping or another network",
+        causes: &[
+            "Both peers are behind NATs which don't allow punching holes for UDP, e.g. carrier-grade NAT",
+            "A firewall drops UDP between the peers",
+            "The peer crashed or was stopped right after connecting",
+        ],
+        fixes: &[
+            "Pass --port-mapping on one side, so the router forwards a port",
+            "Move one side to another network, or to a host with a public address",
+            "Raise --peer-timeout on links with a long delay",
+        ],
+    },
+    Explanation {
+        code: "E302",
+        title: "The peer aborted the transfer",
+        hint: "the peer stopped the transfer, the reason it gave is in the error",
+        causes: &["The peer was stopped, or ran into an error of its own, e.g. a full disk"],
+        fixes: &["Check the output of the peer, then start the transfer again"],
+    },
+    Explanation {
+        code: "E303",
+        title: "Incompatible chunk sizes",
+        hint: "start sender and receiver with the same --chunk-size, or omit it on the sender",
+        causes: &["The sender was started with a --chunk-size larger than the receiver takes"],
+        fixes: &["Pass the same --chunk-size on both sides, or omit it on the sender"],
+    },
+    Explanation {
+        code: "E304",
+        title: "Key exchange failed",
+        hint: "the peer doesn't know the passphrase, compare it with the sender's and make sure nobody else used it",
+        causes: &[
+            "Someone in between, e.g. a relay which isn't trustworthy, tried to take over the session without knowing the passphrase",
+            "The peer's data was damaged on the way",
+        ],
+        fixes: &[
+            "Compare the passphrase on both sides and send again with a new one",
+            "Pass --blind on both sides, so the relay never sees the passphrase",
+        ],
+    },
+    Explanation {
+        code: "E305",
+        title: "Peer declined encryption",
+        hint: "the peer won't agree on a key for the data stream, rebuild it with the crypto feature or pass --allow-unencrypted",
+        causes: &[
+            "The sender or the receiver was built without the crypto feature",
+            "Someone in between, e.g. a relay which isn't trustworthy, tampered with the messages to strip the encryption",
+        ],
+        fixes: &[
+            "Build nudge with the default features on both sides, see Cargo Features in the README",
+            "Pass --blind on both sides, so the relay never sees the passphrase",
+        ],
+    },
+    Explanation {
+        code: "E306",
+        title: "Ticket without passphrase",
+        hint: "the key of the data stream is derived from the passphrase, receive with it instead of the ticket or pass --allow-unencrypted",
+        causes: &["The ticket was issued by a blind relay, which only knows the hash of the passphrase"],
+        fixes: &[
+            "Ask the sender for the passphrase and run `nudge --blind -x <relay> get <passphrase>`",
+            "Pass --allow-unencrypted to receive the file unencrypted anyway",
+        ],
+    },
+    Explanation {
+        code: "E401",
+        title: "Hash mismatch",
+        hint: "the received file differs from the sent one, send it again",
+        causes: &[
+            "The file changed on the sender's disk while it was sent",
+            "The file was damaged on the receiver's disk, or an --append target changed",
+        ],
+        fixes: &["Send the file again, once it doesn't change anymore"],
+    },
+    Explanation {
+        code: "E402",
+        title: "Output file locked",
+        hint: "another nudge is writing the file, wait for it or choose another --out-file",
+        causes: &["Another `nudge get` is writing to the same output file"],
+        fixes: &["Wait for the other receiver to finish, or pass another --out-file"],
+    },
+    Explanation {
+        code: "E403",
+        title: "Session already being received",
+        hint: "another receiver downloads this session to the same file, remove the state file if it's gone",
+        causes: &["A receiver of the same session writes to the same file, or crashed without removing its state file"],
+        fixes: &["Wait for the other receiver, or remove the state file named in the error if it's gone"],
+    },
+    Explanation {
+        code: "E404",
+        title: "Unknown hash algorithm",
+        hint: "the sender hashed the file with an algorithm this version can't check, update nudge",
+        causes: &["The sender runs a newer version of nudge, hashing with an algorithm this version doesn't know"],
+        fixes: &[
+            "Update nudge on the receiver",
+            "Pass --skip-hash to accept the file without checking it",
+        ],
+    },
+    Explanation {
+        code: "E501",
+        title: "Receiver isn't the contact",
+        hint: "the receiver didn't identify as the contact passed with --to, it has to run `get --identify`",
+        causes: &[
+            "The receiver didn't pass --identify",
+            "The receiver has another identity than the one stored for the contact, e.g. after recreating it",
+        ],
+        fixes: &[
+            "Ask the receiver to run `nudge get --identify <PASSPHRASE>`",
+            "Compare the contact's key with `nudge identity export` on the receiver and update the contact",
+        ],
+    },
+    Explanation {
+        code: "E502",
+        title: "No identity",
+        hint: "create an identity with `nudge identity create` first",
+        causes: &["--identify or --to need an identity, and there's none at the path in the error"],
+        fixes: &["Run `nudge identity create`, or point --identity-file at an existing one"],
+    },
+    Explanation {
+        code: "E503",
+        title: "Wrong passphrase for the identity",
+        hint: "the identity is protected with another passphrase, check NUDGE_IDENTITY_PASSPHRASE",
+        causes: &["The identity was created with --protect, and the passphrase given doesn't decrypt it"],
+        fixes: &["Enter the passphrase the identity was protected with, or correct NUDGE_IDENTITY_PASSPHRASE"],
+    },
+    Explanation {
+        code: "E601",
+        title: "Feature not built in",
+        hint: "nudge was built without a cargo feature this needs, rebuild it with the default features",
+        causes: &["nudge was built with --no-default-features, e.g. for an embedded device"],
+        fixes: &["Build nudge with the feature named in the error, see Cargo Features in the README"],
+    },
+];
+
+/// Looks up an explanation by its code, regardless of case.
+pub fn explanation(code: &str) -> Option<&'static Explanation> {
+    EXPLANATIONS.iter().find(|explanation| explanation.code.eq_ignore_ascii_case(code.trim()))
+}
+
+/// Returns the explanation of an error, if there's one.
+pub fn explain(error: &NudgeError) -> Option<&'static Explanation> {
+    error.code().and_then(explanation)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_codes_are_unique() {
+        let codes: HashSet<&str> = EXPLANATIONS.iter().map(|explanation| explanation.code).collect();
+        assert_eq!(codes.len(), EXPLANATIONS.len());
+    }
+
+    #[test]
+    fn test_explain() {
+        assert_eq!(explanation("e101").unwrap().title, "Passphrase not found");
+        assert!(explanation("E999").is_none());
+        assert_eq!(explain(&NudgeError::PassphraseNotFound).unwrap().code, "E101");
+        assert_eq!(explain(&NudgeError::PeerTimeout(Duration::from_secs(20))).unwrap().code, "E301");
+        assert!(explain(&NudgeError::UnknownCommand).is_none());
+
+        // errors of the relay arrive as their text
+        let relay_error = NudgeError::ServerError(NudgeError::PassphraseNotFound.to_string());
+        assert_eq!(explain(&relay_error).unwrap().code, "E101");
+        assert!(explain(&NudgeError::ServerError("Something else".to_string())).is_none());
+    }
+
+    #[test]
+    fn test_every_code_is_explained() {
+        let errors = [
+            NudgeError::PassphraseInUse,
+            NudgeError::RateLimited,
+            NudgeError::BlindPassphraseRequired,
+            NudgeError::ChunkSizeMismatch(1, 2),
+            NudgeError::EncryptionUnsupported,
+        ];
+        for error in errors {
+            assert!(explain(&error).is_some(), "{:?} names no explanation", error);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::IpAddr;
+#[cfg(feature = "geoip")]
+use std::path::Path;
+
+#[cfg(feature = "geoip")]
+use maxminddb::{geoip2, Reader};
+
+#[cfg(feature = "geoip")]
+use crate::error::Result;
+use crate::models::GeoInfo;
+
+/// Looks up the origin of peers in MaxMind GeoIP2 / GeoLite2 databases.
+/// Without the `geoip` feature, no lookup can be created.
+#[cfg_attr(not(feature = "geoip"), allow(dead_code))]
+pub struct GeoIpLookup {
+    #[cfg(feature = "geoip")]
+    country_db: Option<Reader<Vec<u8>>>,
+
+    #[cfg(feature = "geoip")]
+    asn_db: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIpLookup {
+    /// Opens the given databases.
+    ///
+    /// # Arguments
+    ///
+    /// * `country_db` - Path to a Country (or City) database (optional).
+    /// * `asn_db` - Path to an ASN database (optional).
+    ///
+    /// # Returns
+    ///
+    /// `Result<Option<GeoIpLookup>>` - `None` if no database was given.
+    #[cfg(feature = "geoip")]
+    pub fn open(country_db: Option<&Path>, asn_db: Option<&Path>) -> Result<Option<Self>> {
+        if country_db.is_none() && asn_db.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(GeoIpLookup {
+            country_db: country_db.map(Reader::open_readfile).transpose()?,
+            asn_db: asn_db.map(Reader::open_readfile).transpose()?,
+        }))
+    }
+
+    /// Looks up the country and autonomous system of an address.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The address to look up.
+    ///
+    /// # Returns
+    ///
+    /// `Option<GeoInfo>` - `None` if the address isn't in any of the databases.
+    #[cfg(feature = "geoip")]
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let country = self.country_db.as_ref()
+            .and_then(|db| db.lookup::<geoip2::Country>(ip).ok())
+            .and_then(|record| record.country)
+            .and_then(|country| country.iso_code)
+            .map(str::to_string);
+        let asn = self.asn_db.as_ref()
+            .and_then(|db| db.lookup::<geoip2::Asn>(ip).ok());
+
+        let info = GeoInfo {
+            country,
+            asn: asn.as_ref().and_then(|asn| asn.autonomous_system_number),
+            as_org: asn.and_then(|asn| asn.autonomous_system_organization).map(str::to_string),
+        };
+        if info == GeoInfo::default() {
+            None
+        } else {
+            Some(info)
+        }
+    }
+
+    /// Looks up the country and autonomous system of an address.
+    #[cfg(not(feature = "geoip"))]
+    pub fn lookup(&self, _: IpAddr) -> Option<GeoInfo> {
+        None
+    }
+}
@@ -0,0 +1,189 @@
+//! Expands the glob patterns passed to `nudge send` (`"*.log"`, `docs/**/*.pdf`), since shells on Windows (and
+//! quoted arguments everywhere) pass them on unexpanded. `*` and `?` match within a name, `[abc]`, `[a-z]` and
+//! `[!abc]` one character of a set, and a `**` component any number of directories. Like in shells, wildcards
+//! don't match names starting with a dot.
+
+use std::fs;
+use std::path::{is_separator, Path, PathBuf};
+
+use crate::error::{NudgeError, Result};
+
+/// Returns whether the argument contains wildcards.
+pub fn is_pattern(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+/// Collects the files matching the pattern, sorted by path. Directories aren't matched, only the files in them.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern, relative to the current directory or absolute.
+///
+/// # Errors
+///
+/// Returns `NudgeError::NoGlobMatches` if no file matches the pattern.
+pub fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    // the components before the first wildcard are taken as they are
+    let wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+    let (base, rest) = match pattern[..wildcard].rfind(is_separator) {
+        Some(index) => (&pattern[..=index], &pattern[index + 1..]),
+        None => ("", pattern),
+    };
+    let components: Vec<&str> = rest.split(is_separator).filter(|component| !component.is_empty()).collect();
+
+    let mut matches = Vec::new();
+    walk(Path::new(base), &components, &mut matches);
+    // overlapping components (e.g. `**/**`) reach files more than once
+    matches.sort();
+    matches.dedup();
+    if matches.is_empty() {
+        return Err(NudgeError::NoGlobMatches(pattern.to_string()));
+    }
+    debug!("{} matches {} files", pattern, matches.len());
+    Ok(matches)
+}
+
+fn walk(dir: &Path, components: &[&str], matches: &mut Vec<PathBuf>) {
+    let Some((&component, rest)) = components.split_first() else {
+        return;
+    };
+    if component == "**" {
+        // a trailing `**` matches every file below the directory
+        let rest = if rest.is_empty() { &["*"][..] } else { rest };
+        walk(dir, rest, matches);
+        for (name, path) in entries(dir) {
+            // symlinked directories aren't followed, they may loop
+            let is_dir = fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+            if is_dir && !name.starts_with('.') {
+                walk(&path, components, matches);
+            }
+        }
+        return;
+    }
+    for (name, path) in entries(dir) {
+        if !matches_name(component, &name) {
+            continue;
+        }
+        match rest.is_empty() {
+            true if path.is_file() => matches.push(path),
+            false if path.is_dir() => walk(&path, rest, matches),
+            _ => {}
+        }
+    }
+}
+
+/// Names and paths of the entries of the directory, none if it can't be read.
+fn entries(dir: &Path) -> Vec<(String, PathBuf)> {
+    let read_dir = match fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            debug!("Cannot read {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    read_dir
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = dir.join(&name);
+            (name, path)
+        })
+        .collect()
+}
+
+/// Returns whether a name matches a pattern component without separators.
+fn matches_name(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_from(&pattern, &name)
+}
+
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches_from(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches_from(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), class_end(pattern)) {
+            (Some(&c), Some(end)) => in_class(&pattern[1..end], c) && matches_from(&pattern[end + 1..], &name[1..]),
+            (None, _) => false,
+            // an unclosed bracket is taken literally
+            (Some(&c), None) => c == '[' && matches_from(&pattern[1..], &name[1..]),
+        },
+        Some(&c) => name.first() == Some(&c) && matches_from(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Index of the `]` closing the class starting at the beginning of the pattern. A `]` right after the opening
+/// bracket (or its negation) is part of the class.
+fn class_end(pattern: &[char]) -> Option<usize> {
+    let start = match pattern.get(1) {
+        Some('!' | '^') => 3,
+        _ => 2,
+    };
+    pattern.iter().skip(start).position(|&c| c == ']').map(|index| index + start)
+}
+
+fn in_class(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!' | '^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut index = 0;
+    while index < class.len() {
+        if class.get(index + 1) == Some(&'-') && index + 2 < class.len() {
+            found |= (class[index]..=class[index + 2]).contains(&c);
+            index += 3;
+        } else {
+            found |= class[index] == c;
+            index += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_name() {
+        assert!(matches_name("*.log", "app.log"));
+        assert!(!matches_name("*.log", ".log"));
+        assert!(!matches_name("*.log", "app.log.1"));
+        assert!(matches_name("app-?.log", "app-1.log"));
+        assert!(!matches_name("app-?.log", "app-10.log"));
+        assert!(matches_name("[ab]*", "beta"));
+        assert!(!matches_name("[!ab]*", "beta"));
+        assert!(matches_name("report-[0-9][0-9].pdf", "report-07.pdf"));
+        assert!(!matches_name("report-[0-9].pdf", "report-x.pdf"));
+        assert!(matches_name("[]]", "]"));
+        assert!(matches_name("[draft", "[draft"));
+        assert!(matches_name(".*", ".env"));
+        assert!(!matches_name("*", ".env"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let dir = tempfile::tempdir_in(".").unwrap();
+        for path in ["a.log", "b.log", "c.txt", ".hidden.log", "docs/x.pdf", "docs/sub/y.pdf", "docs/sub/z.txt"] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"data").unwrap();
+        }
+        let root = dir.path().display().to_string();
+        let relative = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths.iter().map(|path| path.strip_prefix(dir.path()).unwrap().display().to_string()).collect()
+        };
+
+        assert_eq!(relative(expand(&format!("{}/*.log", root)).unwrap()), ["a.log", "b.log"]);
+        assert_eq!(relative(expand(&format!("{}/docs/**/*.pdf", root)).unwrap()), ["docs/sub/y.pdf", "docs/x.pdf"]);
+        assert_eq!(relative(expand(&format!("{}/d*/**", root)).unwrap()), ["docs/sub/y.pdf", "docs/sub/z.txt", "docs/x.pdf"]);
+        // directories aren't matched themselves
+        assert_eq!(relative(expand(&format!("{}/*", root)).unwrap()), ["a.log", "b.log", "c.txt"]);
+        assert!(matches!(expand(&format!("{}/*.csv", root)), Err(NudgeError::NoGlobMatches(_))));
+    }
+}
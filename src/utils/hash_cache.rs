@@ -0,0 +1,195 @@
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::utils::platform::config_dir;
+
+/// Name of the cache file in the configuration directory
+const CACHE_FILE_NAME: &str = "hash-cache.json";
+
+/// Maximum number of files remembered, the least recently used ones are forgotten first
+const MAX_ENTRIES: usize = 1000;
+
+/// Identifies a file and its content without reading it.
+/// Any change to the content changes the modification (and on unix the status change) time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileIdentity {
+    path: PathBuf,
+    device: u64,
+    inode: u64,
+    size: u64,
+    modified_nanos: u128,
+    changed_nanos: i128,
+}
+
+impl FileIdentity {
+    /// Determines the identity of an opened file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the file.
+    /// * `metadata` - Metadata of the opened file.
+    ///
+    /// # Returns
+    ///
+    /// `Option<FileIdentity>` - The identity, or `None` if the platform doesn't report modification times.
+    pub fn of(path: &Path, metadata: &Metadata) -> Option<Self> {
+        let modified_nanos = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+        #[cfg(unix)]
+        let (device, inode, changed_nanos) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino(), metadata.ctime() as i128 * 1_000_000_000 + metadata.ctime_nsec() as i128)
+        };
+        #[cfg(not(unix))]
+        let (device, inode, changed_nanos) = (0, 0, 0);
+        Some(FileIdentity {
+            path: fs::canonicalize(path).ok()?,
+            device,
+            inode,
+            size: metadata.len(),
+            modified_nanos,
+            changed_nanos,
+        })
+    }
+}
+
+/// A hash computed before, with the time it was last used (unix millis)
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    identity: FileIdentity,
+    hash: String,
+    last_used: u64,
+}
+
+/// Hashes of files sent before, so sending an unchanged file again doesn't need to hash it
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: Vec<CacheEntry>,
+}
+
+impl HashCache {
+    /// Opens the cache in the configuration directory, see `config_dir`.
+    ///
+    /// # Returns
+    ///
+    /// `Option<HashCache>` - The cache, or `None` if there is no configuration directory.
+    pub fn open() -> Option<Self> {
+        config_dir().map(|dir| Self::load(dir.join(CACHE_FILE_NAME)))
+    }
+
+    /// Loads the cache from a file. A missing or corrupt file results in an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the cache file.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                debug!(target: "io", "Ignoring corrupt hash cache {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        HashCache { path, entries }
+    }
+
+    /// Returns the hash of the file if it was computed before and the file didn't change since.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Identity of the file.
+    /// * `now` - The current time in unix millis.
+    pub fn lookup(&mut self, identity: &FileIdentity, now: u64) -> Option<String> {
+        let entry = self.entries.iter_mut().find(|entry| entry.identity == *identity)?;
+        entry.last_used = now;
+        Some(entry.hash.clone())
+    }
+
+    /// Remembers the hash of a file, replacing the entry of an earlier version of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Identity of the file.
+    /// * `hash` - The hash of its content.
+    /// * `now` - The current time in unix millis.
+    pub fn insert(&mut self, identity: FileIdentity, hash: String, now: u64) {
+        self.entries.retain(|entry| entry.identity.path != identity.path);
+        self.entries.push(CacheEntry { identity, hash, last_used: now });
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_used));
+            self.entries.truncate(MAX_ENTRIES);
+        }
+    }
+
+    /// Writes the cache to its file, replacing it atomically.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(&self.entries)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn identity(path: &str, size: u64) -> FileIdentity {
+        FileIdentity { path: PathBuf::from(path), device: 1, inode: 2, size, modified_nanos: 3, changed_nanos: 4 }
+    }
+
+    #[test]
+    fn test_lookup_insert() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = HashCache::load(dir.path().join(CACHE_FILE_NAME));
+        assert_eq!(cache.lookup(&identity("/a", 1), 0), None);
+
+        cache.insert(identity("/a", 1), "hash-1".to_string(), 0);
+        assert_eq!(cache.lookup(&identity("/a", 1), 1), Some("hash-1".to_string()));
+        // a changed file isn't found, and replaces the old entry
+        assert_eq!(cache.lookup(&identity("/a", 2), 1), None);
+        cache.insert(identity("/a", 2), "hash-2".to_string(), 2);
+        assert_eq!(cache.entries.len(), 1);
+
+        cache.save().unwrap();
+        let mut cache = HashCache::load(dir.path().join(CACHE_FILE_NAME));
+        assert_eq!(cache.lookup(&identity("/a", 2), 3), Some("hash-2".to_string()));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = HashCache::load(PathBuf::from("unused"));
+        for i in 0..=MAX_ENTRIES as u64 {
+            cache.insert(identity(&format!("/{}", i), i), i.to_string(), i);
+        }
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert_eq!(cache.lookup(&identity("/0", 0), 0), None);
+        assert!(cache.lookup(&identity("/1", 1), 0).is_some());
+    }
+
+    #[test]
+    fn test_corrupt_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CACHE_FILE_NAME);
+        fs::write(&path, b"{ not json").unwrap();
+        assert!(HashCache::load(path).entries.is_empty());
+    }
+
+    #[test]
+    fn test_identity_changes_with_content() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let before = FileIdentity::of(file.path(), &file.as_file().metadata().unwrap()).unwrap();
+        file.write_all(b"changed").unwrap();
+        let after = FileIdentity::of(file.path(), &file.as_file().metadata().unwrap()).unwrap();
+        assert_ne!(before, after);
+    }
+}
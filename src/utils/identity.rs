@@ -0,0 +1,522 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "crypto")]
+use argon2::{Algorithm, Argon2, Params, Version};
+#[cfg(feature = "crypto")]
+use chacha20poly1305::{aead::Aead, aead::Payload, ChaCha20Poly1305, KeyInit};
+#[cfg(feature = "crypto")]
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{Rng, thread_rng};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::passphrase::Passphrase;
+use crate::utils::platform::{config_dir, set_file_mode};
+use crate::utils::ui::password;
+use crate::utils::{decode_hex, encode_hex};
+
+/// Name of the identity file in the configuration directory
+const IDENTITY_FILE_NAME: &str = "identity.json";
+
+/// Version of the identity file format, raised on incompatible changes
+const IDENTITY_VERSION: u32 = 1;
+
+/// Prefix of encoded public keys, names the algorithm
+const PUBLIC_KEY_PREFIX: &str = "ed25519:";
+
+/// Environment variable holding the passphrase of a protected identity, so scripts don't need a prompt
+pub const IDENTITY_PASSPHRASE_ENV: &str = "NUDGE_IDENTITY_PASSPHRASE";
+
+/// Length of Ed25519 keys and signatures in bytes
+const KEY_LENGTH: usize = 32;
+#[cfg(feature = "crypto")]
+const SIGNATURE_LENGTH: usize = 64;
+
+/// Memory (KiB) and passes of Argon2id over it when deriving the key of a protected identity, so guessing the
+/// passphrase of a stolen identity file is slow and can't be sped up much with GPUs
+const KDF_MEMORY_KIB: u32 = 64 * 1024;
+const KDF_ITERATIONS: u32 = 3;
+
+/// Length of the salt of the key derivation and the nonce of the encryption in bytes
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+
+/// Prefix of the message signed to prove owning an identity to a peer
+const PROOF_CONTEXT: &str = "nudge-ngx identity proof v1\n";
+
+/// Public key of an identity, shared with peers to recognize (pin) it
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PublicKey([u8; KEY_LENGTH]);
+
+impl PublicKey {
+    /// Parses a public key, e.g. `ed25519:3d4017c3...` (the prefix is optional).
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidIdentity` if the input isn't a hex encoded 32 byte key.
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        let hex = input.strip_prefix(PUBLIC_KEY_PREFIX).unwrap_or(input);
+        decode_hex(hex)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(PublicKey)
+            .ok_or_else(|| NudgeError::InvalidIdentity(format!("{} is not a public key", input)))
+    }
+
+    /// Returns a short, human comparable digest of the key, e.g. `3f2a-91bc-07de-55a1`.
+    pub fn fingerprint(&self) -> String {
+        let digest = encode_hex(&blake3::hash(&self.0).as_bytes()[..8]);
+        digest.as_bytes()
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).expect("hex is ascii"))
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Checks a signature made with the secret key of this identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The signed message.
+    /// * `signature` - The hex encoded signature.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::EncryptionUnsupported` without the `crypto` feature.
+    #[cfg(feature = "crypto")]
+    pub fn verify(&self, message: &[u8], signature: &str) -> Result<bool> {
+        let Ok(key) = VerifyingKey::from_bytes(&self.0) else {
+            return Ok(false);
+        };
+        Ok(decode_hex(signature)
+            .and_then(|signature| <[u8; SIGNATURE_LENGTH]>::try_from(signature).ok())
+            .is_some_and(|signature| key.verify_strict(message, &Signature::from_bytes(&signature)).is_ok()))
+    }
+
+    /// Checks a signature, impossible without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn verify(&self, _: &[u8], _: &str) -> Result<bool> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", PUBLIC_KEY_PREFIX, encode_hex(&self.0))
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = NudgeError;
+
+    fn try_from(value: String) -> Result<Self> {
+        PublicKey::parse(&value)
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(value: PublicKey) -> Self {
+        value.to_string()
+    }
+}
+
+/// Returns the message a receiver signs to prove owning its identity to the sender.
+/// It is bound to the session by the passphrase, and to the connection by the sender's random nonce.
+///
+/// # Arguments
+///
+/// * `nonce` - The nonce of the `IdentityChallengeMessage`.
+/// * `passphrase` - The passphrase of the session.
+pub fn identity_proof_message(nonce: &str, passphrase: &Passphrase) -> Vec<u8> {
+    format!("{}{}\n{}", PROOF_CONTEXT, nonce, passphrase).into_bytes()
+}
+
+/// Long-lived keypair of this peer, used to sign and to be recognized by peers pinning its public key
+pub struct Identity {
+    seed: [u8; KEY_LENGTH],
+    public_key: PublicKey,
+}
+
+impl Identity {
+    /// Creates a new random identity.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::EncryptionUnsupported` without the `crypto` feature.
+    pub fn generate() -> Result<Self> {
+        Self::from_seed(thread_rng().gen())
+    }
+
+    #[cfg(feature = "crypto")]
+    fn from_seed(seed: [u8; KEY_LENGTH]) -> Result<Self> {
+        let public_key = PublicKey(SigningKey::from_bytes(&seed).verifying_key().to_bytes());
+        Ok(Identity { seed, public_key })
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn from_seed(_: [u8; KEY_LENGTH]) -> Result<Self> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Signs a message.
+    ///
+    /// # Returns
+    ///
+    /// `String` - The hex encoded signature.
+    #[cfg(feature = "crypto")]
+    pub fn sign(&self, message: &[u8]) -> String {
+        encode_hex(&SigningKey::from_bytes(&self.seed).sign(message).to_bytes())
+    }
+
+    /// Signs a message, identities can't be created without the `crypto` feature.
+    #[cfg(not(feature = "crypto"))]
+    pub fn sign(&self, _: &[u8]) -> String {
+        unreachable!("identities can't be created without the crypto feature")
+    }
+
+    /// Stores the secret key in an identity file, encrypted if a passphrase is given.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - Passphrase protecting the secret key (optional).
+    /// * `created_at` - When the identity was created (unix millis).
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidIdentity` if the key can't be derived from the passphrase.
+    pub fn seal(&self, passphrase: Option<&str>, created_at: u64) -> Result<IdentityFile> {
+        self.seal_with_cost(passphrase, created_at, KDF_MEMORY_KIB, KDF_ITERATIONS)
+    }
+
+    fn seal_with_cost(&self, passphrase: Option<&str>, created_at: u64, memory_kib: u32, iterations: u32) -> Result<IdentityFile> {
+        let (secret_key, protection) = match passphrase {
+            Some(passphrase) => {
+                let protection = Protection {
+                    salt: encode_hex(&thread_rng().gen::<[u8; SALT_LENGTH]>()),
+                    memory_kib,
+                    iterations,
+                    nonce: encode_hex(&thread_rng().gen::<[u8; NONCE_LENGTH]>()),
+                };
+                let ciphertext = SealingKey::derive(passphrase, &protection)?.encrypt(&protection, &self.seed, &self.public_key)?;
+                (encode_hex(&ciphertext), Some(protection))
+            }
+            None => (encode_hex(&self.seed), None),
+        };
+        Ok(IdentityFile { version: IDENTITY_VERSION, public_key: self.public_key, created_at, secret_key, protection })
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity").field("public_key", &self.public_key).finish_non_exhaustive()
+    }
+}
+
+/// How the secret key of a protected identity is encrypted: with ChaCha20-Poly1305, keyed by Argon2id of the
+/// passphrase, and bound to the public key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Protection {
+    /// Random salt of the key derivation (hex)
+    salt: String,
+
+    /// Memory of Argon2id in KiB
+    memory_kib: u32,
+
+    /// Passes of Argon2id over the memory
+    iterations: u32,
+
+    /// Random nonce of the encryption (hex), the tag is appended to the encrypted secret key
+    nonce: String,
+}
+
+/// Key derived from the passphrase of a protected identity
+#[cfg(feature = "crypto")]
+struct SealingKey(ChaCha20Poly1305);
+
+#[cfg(feature = "crypto")]
+impl SealingKey {
+    fn derive(passphrase: &str, protection: &Protection) -> Result<Self> {
+        let invalid = |reason: String| NudgeError::InvalidIdentity(reason);
+        let salt = decode_hex(&protection.salt).ok_or_else(|| invalid("malformed salt".to_string()))?;
+        let params = Params::new(protection.memory_kib, protection.iterations, 1, Some(32))
+            .map_err(|e| invalid(format!("invalid key derivation: {}", e)))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| invalid(format!("invalid key derivation: {}", e)))?;
+        Ok(SealingKey(ChaCha20Poly1305::new(&key.into())))
+    }
+
+    fn encrypt(&self, protection: &Protection, seed: &[u8], public_key: &PublicKey) -> Result<Vec<u8>> {
+        let nonce = Self::nonce(protection)?;
+        let payload = Payload { msg: seed, aad: &public_key.0 };
+        self.0.encrypt(&nonce.into(), payload).map_err(|_| NudgeError::InvalidIdentity("encryption failed".to_string()))
+    }
+
+    /// Decrypts the secret key, `None` if the passphrase is wrong or the file was tampered with.
+    fn decrypt(&self, protection: &Protection, ciphertext: &[u8], public_key: &PublicKey) -> Result<Option<Vec<u8>>> {
+        let nonce = Self::nonce(protection)?;
+        Ok(self.0.decrypt(&nonce.into(), Payload { msg: ciphertext, aad: &public_key.0 }).ok())
+    }
+
+    fn nonce(protection: &Protection) -> Result<[u8; NONCE_LENGTH]> {
+        decode_hex(&protection.nonce)
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or_else(|| NudgeError::InvalidIdentity("malformed nonce".to_string()))
+    }
+}
+
+/// Key of a protected identity, impossible to derive without the `crypto` feature
+#[cfg(not(feature = "crypto"))]
+struct SealingKey;
+
+#[cfg(not(feature = "crypto"))]
+impl SealingKey {
+    fn derive(_: &str, _: &Protection) -> Result<Self> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+
+    fn encrypt(&self, _: &Protection, _: &[u8], _: &PublicKey) -> Result<Vec<u8>> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+
+    fn decrypt(&self, _: &Protection, _: &[u8], _: &PublicKey) -> Result<Option<Vec<u8>>> {
+        Err(NudgeError::EncryptionUnsupported)
+    }
+}
+
+/// The identity as stored on disk, e.g.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "public_key": "ed25519:3d4017c3...",
+///   "created_at": 1717156800000,
+///   "secret_key": "4ccd089b...",
+///   "protection": null
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityFile {
+    /// Version of the format
+    version: u32,
+
+    /// Public key of the identity, readable without the passphrase
+    public_key: PublicKey,
+
+    /// When the identity was created (unix millis)
+    created_at: u64,
+
+    /// The secret key (hex), encrypted if `protection` is set
+    secret_key: String,
+
+    /// Parameters of the encryption, `null` if the secret key is stored in plain
+    protection: Option<Protection>,
+}
+
+impl IdentityFile {
+    /// Returns the path of the identity in the configuration directory, see `config_dir`.
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(IDENTITY_FILE_NAME))
+    }
+
+    /// Reads an identity file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::IdentityMissing` if there is no file and `NudgeError::InvalidIdentity` if it is corrupt.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(NudgeError::IdentityMissing(path.display().to_string()));
+            }
+            contents => contents?,
+        };
+        let file: IdentityFile = serde_json::from_slice(&contents)
+            .map_err(|e| NudgeError::InvalidIdentity(format!("{}: {}", path.display(), e)))?;
+        if file.version != IDENTITY_VERSION {
+            return Err(NudgeError::InvalidIdentity(format!("{}: unsupported version {}", path.display(), file.version)));
+        }
+        Ok(file)
+    }
+
+    /// Writes the identity file, readable by the current user only.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the identity file.
+    /// * `replace` - Whether an existing identity may be replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::IdentityExists` if there is an identity already and `replace` isn't set.
+    pub fn save(&self, path: &Path, replace: bool) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = match OpenOptions::new().write(true).create_new(!replace).create(replace).truncate(replace).open(path) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(NudgeError::IdentityExists(path.display().to_string()));
+            }
+            file => file?,
+        };
+        set_file_mode(&file, 0o600)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protection.is_some()
+    }
+
+    /// Decrypts the secret key.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - Passphrase of a protected identity (ignored otherwise).
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::WrongIdentityPassphrase` if the passphrase is missing or wrong,
+    /// and `NudgeError::InvalidIdentity` if the secret key doesn't belong to the public key.
+    pub fn unseal(&self, passphrase: Option<&str>) -> Result<Identity> {
+        let invalid = |reason: &str| NudgeError::InvalidIdentity(reason.to_string());
+        let secret_key = decode_hex(&self.secret_key).ok_or_else(|| invalid("malformed secret key"))?;
+        let seed = match &self.protection {
+            Some(protection) => {
+                let passphrase = passphrase.ok_or(NudgeError::WrongIdentityPassphrase)?;
+                SealingKey::derive(passphrase, protection)?
+                    .decrypt(protection, &secret_key, &self.public_key)?
+                    .ok_or(NudgeError::WrongIdentityPassphrase)?
+            }
+            None => secret_key,
+        };
+        let seed: [u8; KEY_LENGTH] = seed.try_into().map_err(|_| invalid("malformed secret key"))?;
+        let identity = Identity::from_seed(seed)?;
+        if identity.public_key != self.public_key {
+            return Err(invalid("the secret key doesn't match the public key"));
+        }
+        Ok(identity)
+    }
+
+    /// Decrypts the secret key, taking the passphrase of a protected identity from
+    /// `$NUDGE_IDENTITY_PASSPHRASE` or asking for it.
+    pub fn unseal_interactive(&self) -> Result<Identity> {
+        if !self.is_protected() {
+            return self.unseal(None);
+        }
+        let passphrase = match std::env::var(IDENTITY_PASSPHRASE_ENV) {
+            Ok(passphrase) => passphrase,
+            Err(_) => password("Passphrase of the identity", false)?,
+        };
+        self.unseal(Some(&passphrase))
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_key() {
+        let public_key = Identity::from_seed([1; 32]).unwrap().public_key;
+        let encoded = public_key.to_string();
+        assert!(encoded.starts_with(PUBLIC_KEY_PREFIX));
+        assert_eq!(PublicKey::parse(&encoded).unwrap(), public_key);
+        assert_eq!(PublicKey::parse(&encoded[PUBLIC_KEY_PREFIX.len()..]).unwrap(), public_key);
+        assert!(PublicKey::parse("ed25519:abcd").is_err());
+        assert_eq!(public_key.fingerprint().len(), 19);
+        assert_ne!(public_key.fingerprint(), Identity::from_seed([2; 32]).unwrap().public_key.fingerprint());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let identity = Identity::from_seed([1; 32]).unwrap();
+        let signature = identity.sign(b"hello");
+        assert!(identity.public_key.verify(b"hello", &signature).unwrap());
+        assert!(!identity.public_key.verify(b"hallo", &signature).unwrap());
+        assert!(!Identity::from_seed([2; 32]).unwrap().public_key.verify(b"hello", &signature).unwrap());
+        assert!(!identity.public_key.verify(b"hello", "not hex").unwrap());
+    }
+
+    #[test]
+    fn test_identity_proof() {
+        let identity = Identity::from_seed([1; 32]).unwrap();
+        let passphrase = Passphrase::from("correct-horse-battery".to_string());
+        let signature = identity.sign(&identity_proof_message("abcd", &passphrase));
+        assert!(identity.public_key.verify(&identity_proof_message("abcd", &passphrase), &signature).unwrap());
+        // a proof can't be replayed for another challenge or session
+        assert!(!identity.public_key.verify(&identity_proof_message("abce", &passphrase), &signature).unwrap());
+        let other = Passphrase::from("correct-horse-staple".to_string());
+        assert!(!identity.public_key.verify(&identity_proof_message("abcd", &other), &signature).unwrap());
+    }
+
+    #[test]
+    fn test_seal_unseal() {
+        let identity = Identity::from_seed([3; 32]).unwrap();
+        let plain = identity.seal(None, 1000).unwrap();
+        assert!(!plain.is_protected());
+        assert_eq!(plain.unseal(None).unwrap().seed, identity.seed);
+
+        let protected = identity.seal_with_cost(Some("hunter2"), 1000, 8, 1).unwrap();
+        assert!(protected.is_protected());
+        assert_ne!(protected.secret_key, plain.secret_key);
+        assert_eq!(protected.unseal(Some("hunter2")).unwrap().seed, identity.seed);
+        assert!(matches!(protected.unseal(Some("hunter3")), Err(NudgeError::WrongIdentityPassphrase)));
+        assert!(matches!(protected.unseal(None), Err(NudgeError::WrongIdentityPassphrase)));
+
+        let mut swapped = plain.clone();
+        swapped.public_key = Identity::from_seed([4; 32]).unwrap().public_key;
+        assert!(matches!(swapped.unseal(None), Err(NudgeError::InvalidIdentity(_))));
+        // the encrypted secret key is bound to the public key
+        let mut swapped = protected.clone();
+        swapped.public_key = Identity::from_seed([4; 32]).unwrap().public_key;
+        assert!(matches!(swapped.unseal(Some("hunter2")), Err(NudgeError::WrongIdentityPassphrase)));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(IDENTITY_FILE_NAME);
+        assert!(matches!(IdentityFile::load(&path), Err(NudgeError::IdentityMissing(_))));
+
+        let file = Identity::from_seed([5; 32]).unwrap().seal(None, 1000).unwrap();
+        file.save(&path, false).unwrap();
+        assert_eq!(IdentityFile::load(&path).unwrap(), file);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let other = Identity::from_seed([6; 32]).unwrap().seal(None, 2000).unwrap();
+        assert!(matches!(other.save(&path, false), Err(NudgeError::IdentityExists(_))));
+        other.save(&path, true).unwrap();
+        assert_eq!(IdentityFile::load(&path).unwrap(), other);
+
+        fs::write(&path, "{}").unwrap();
+        assert!(matches!(IdentityFile::load(&path), Err(NudgeError::InvalidIdentity(_))));
+    }
+}
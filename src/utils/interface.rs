@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::ui::{failure_mark, style};
+
+/// An address assigned to a network interface
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkInterface {
+    /// Name of the interface, e.g. `eth0` or `wg0`
+    pub name: String,
+
+    /// Address assigned to the interface (an interface may have several)
+    pub addr: IpAddr,
+}
+
+/// Lists the addresses of all network interfaces which are up.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the interfaces can't be queried.
+#[cfg(unix)]
+pub fn list_interfaces() -> Result<Vec<NetworkInterface>> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs allocates the list, which is freed below
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut interfaces = Vec::new();
+    let mut current = addrs;
+    while !current.is_null() {
+        // SAFETY: the entries (and their names and addresses) are valid until freeifaddrs
+        let entry = unsafe { &*current };
+        current = entry.ifa_next;
+        if entry.ifa_addr.is_null() || entry.ifa_flags & libc::IFF_UP as libc::c_uint == 0 {
+            continue;
+        }
+        let addr = match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) }.to_string_lossy().into_owned();
+        interfaces.push(NetworkInterface { name, addr });
+    }
+
+    // SAFETY: addrs was allocated by getifaddrs and isn't used afterwards
+    unsafe { libc::freeifaddrs(addrs) };
+    Ok(interfaces)
+}
+
+#[cfg(not(unix))]
+pub fn list_interfaces() -> Result<Vec<NetworkInterface>> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "listing interfaces is not supported").into())
+}
+
+/// Resolves the argument of `--bind` to a local address.
+///
+/// An IP address is used as is. Otherwise, the argument names an interface,
+/// whose IPv4 address is preferred over its IPv6 addresses (the relay is usually reached via IPv4).
+///
+/// # Arguments
+///
+/// * `bind` - An IP address or the name of an interface.
+/// * `interfaces` - The interfaces of this host, see `list_interfaces`.
+///
+/// # Errors
+///
+/// Returns `NudgeError::UnknownInterface` if no interface has that name.
+pub fn resolve_bind_address(bind: &str, interfaces: &[NetworkInterface]) -> Result<IpAddr> {
+    if let Ok(addr) = bind.parse::<IpAddr>() {
+        return Ok(addr);
+    }
+    let mut addrs = interfaces.iter()
+        .filter(|interface| interface.name == bind)
+        .map(|interface| interface.addr);
+    let first = addrs.next().ok_or_else(|| NudgeError::UnknownInterface(bind.to_string()))?;
+    Ok(std::iter::once(first).chain(addrs).find(IpAddr::is_ipv4).unwrap_or(first))
+}
+
+/// Prints the interfaces of this host, for `--list-interfaces`.
+pub fn print_interfaces() -> Result<()> {
+    let interfaces = list_interfaces()?;
+    if interfaces.is_empty() {
+        println!("{} No network interfaces are up", failure_mark());
+    }
+    for interface in interfaces {
+        println!(
+            "{} {} {}",
+            style("[~]").bold().yellow(),
+            style(&interface.name).cyan(),
+            interface.addr
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, addr: &str) -> NetworkInterface {
+        NetworkInterface { name: name.to_string(), addr: addr.parse().unwrap() }
+    }
+
+    #[test]
+    fn test_resolve_bind_address() {
+        let interfaces = vec![
+            interface("eth0", "fe80::1"),
+            interface("eth0", "192.168.1.10"),
+            interface("wg0", "fd00::2"),
+        ];
+        assert_eq!(resolve_bind_address("10.0.0.1", &interfaces).unwrap(), "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(resolve_bind_address("eth0", &interfaces).unwrap(), "192.168.1.10".parse::<IpAddr>().unwrap());
+        assert_eq!(resolve_bind_address("wg0", &interfaces).unwrap(), "fd00::2".parse::<IpAddr>().unwrap());
+        assert!(matches!(resolve_bind_address("tun0", &interfaces), Err(NudgeError::UnknownInterface(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_list_interfaces() {
+        let interfaces = list_interfaces().unwrap();
+        assert!(interfaces.iter().any(|interface| interface.addr.is_loopback()));
+    }
+}
@@ -0,0 +1,138 @@
+//! Debug logs, grouped by subsystem so `--debug transport` shows what the reliable UDP layer does without the
+//! rest of the process drowning it out (or the other way round).
+//! Logs of a subsystem are written with its target, e.g. `debug!(target: "transport", ...)`,
+//! everything else keeps the module path as target.
+
+use std::fmt;
+
+use clap::ValueEnum;
+use time::{OffsetDateTime, UtcOffset};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::{FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::utils::ui::Verbosity;
+
+/// Subsystems whose debug logs can be shown on their own with `--debug`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogTarget {
+    /// Requests to the relay and the rendezvous with the peer
+    #[value(name = "relay_client")]
+    RelayClient,
+    /// The reliable UDP connection between the peers: packets, retransmits, flow control
+    Transport,
+    /// Hashes, signatures, identities and tickets
+    Crypto,
+    /// Reading and writing files, devices and their metadata
+    Io,
+}
+
+impl LogTarget {
+    /// Returns the target the subsystem logs with.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogTarget::RelayClient => "relay_client",
+            LogTarget::Transport => "transport",
+            LogTarget::Crypto => "crypto",
+            LogTarget::Io => "io",
+        }
+    }
+}
+
+/// Prints the logs to stdout like `15-10/09:51:47 [DEBUG] <transport@nudge::utils::reliable_udp:120>:...`.
+///
+/// # Arguments
+///
+/// * `verbosity` - Selects the level of all logs.
+/// * `debug_targets` - Subsystems whose debug (trace with `-vv`) logs are shown, the others only log from info up.
+/// * `deterministic` - Whether the timestamps are replaced, so logs of different runs can be compared.
+pub fn init(verbosity: Verbosity, debug_targets: &[LogTarget], deterministic: bool) {
+    let format = LogFormat {
+        // the offset can only be determined safely while the process has a single thread
+        offset: UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC),
+        deterministic,
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().event_format(format).with_writer(std::io::stdout))
+        .with(filter(verbosity, debug_targets))
+        .init();
+}
+
+/// Returns the filter passing the logs of the verbosity. With subsystems to debug, `-v` only applies to them.
+fn filter(verbosity: Verbosity, debug_targets: &[LogTarget]) -> Targets {
+    let level = verbosity.log_level();
+    if debug_targets.is_empty() {
+        return Targets::new().with_default(level);
+    }
+    let debug_level = level.max(Level::DEBUG);
+    Targets::new()
+        .with_default(level.min(Level::INFO))
+        .with_targets(debug_targets.iter().map(|target| (target.as_str(), debug_level)))
+}
+
+/// Format of the log lines, see `init`
+struct LogFormat {
+    offset: UtcOffset,
+    deterministic: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for LogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let metadata = event.metadata();
+        if self.deterministic {
+            write!(writer, "00-00/00:00:00")?;
+        } else {
+            let now = OffsetDateTime::now_utc().to_offset(self.offset);
+            write!(
+                writer,
+                "{:02}-{:02}/{:02}:{:02}:{:02}",
+                now.day(), u8::from(now.month()), now.hour(), now.minute(), now.second()
+            )?;
+        }
+        write!(writer, " [{}] <", metadata.level())?;
+        let module = metadata.module_path().unwrap_or_default();
+        if metadata.target() != module {
+            write!(writer, "{}@", metadata.target())?;
+        }
+        write!(writer, "{}:{}>:", module, metadata.line().unwrap_or_default())?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let targets = filter(Verbosity::Normal, &[LogTarget::Transport]);
+        assert!(targets.would_enable("transport", &Level::DEBUG));
+        assert!(!targets.would_enable("transport", &Level::TRACE));
+        assert!(!targets.would_enable("relay_client", &Level::DEBUG));
+        assert!(targets.would_enable("nudge::commands::send_command", &Level::INFO));
+        assert!(!targets.would_enable("nudge::commands::send_command", &Level::DEBUG));
+
+        let targets = filter(Verbosity::Trace, &[LogTarget::Io]);
+        assert!(targets.would_enable("io", &Level::TRACE));
+        assert!(!targets.would_enable("crypto", &Level::DEBUG));
+
+        let targets = filter(Verbosity::Verbose, &[]);
+        assert!(targets.would_enable("crypto", &Level::DEBUG));
+        assert!(targets.would_enable("nudge::commands::send_command", &Level::DEBUG));
+    }
+
+    #[test]
+    fn test_target_names() {
+        for target in LogTarget::value_variants() {
+            assert_eq!(target.to_possible_value().unwrap().get_name(), target.as_str());
+        }
+    }
+}
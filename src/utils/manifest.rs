@@ -0,0 +1,231 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::models::FileInfo;
+use crate::utils::summary::{PhaseDuration, TransferSummary};
+use crate::utils::HASH_ALGORITHM;
+
+/// Version of the manifest format, raised on incompatible changes
+const MANIFEST_VERSION: u32 = 1;
+
+/// Record of a finished download, written with `get --manifest` for tools verifying and archiving the files, e.g.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "sender": "alice-laptop",
+///   "files": [
+///     {
+///       "name": "report.pdf",
+///       "path": "/srv/drop/report.pdf",
+///       "size": 52311,
+///       "hash": "9f86d081...",
+///       "hash_algorithm": "blake3",
+///       "hash_verified": true,
+///       "offered_at": 1717156800000,
+///       "received_at": 1717156812345,
+///       "duration_millis": 812,
+///       "phases": [
+///         { "phase": "looking_up", "duration_millis": 35 },
+///         { "phase": "connecting", "duration_millis": 1204 },
+///         { "phase": "transferring", "duration_millis": 812 },
+///         { "phase": "verifying", "duration_millis": 40 }
+///       ]
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransferManifest {
+    /// Version of the format
+    pub version: u32,
+
+    /// Display name or hostname of the sender (`null` if not shared)
+    pub sender: Option<String>,
+
+    /// The received files
+    pub files: Vec<ManifestEntry>,
+}
+
+/// A received file in the manifest
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Name of the file as offered by the sender
+    pub name: String,
+
+    /// Where the file was written to
+    pub path: PathBuf,
+
+    /// Size of the file in bytes
+    pub size: u64,
+
+    /// Hash of the file announced by the sender (`null` if the sender didn't hash it)
+    pub hash: Option<String>,
+
+    /// Algorithm of `hash`
+    pub hash_algorithm: String,
+
+    /// Whether the received file was checked against `hash`
+    pub hash_verified: bool,
+
+    /// When the sender offered the file (unix millis)
+    pub offered_at: u64,
+
+    /// When the download finished (unix millis)
+    pub received_at: u64,
+
+    /// Duration of the transfer in milliseconds
+    pub duration_millis: u64,
+
+    /// Time spent in each phase of the download, measured with the monotonic clock
+    #[serde(default)]
+    pub phases: Vec<PhaseDuration>,
+}
+
+impl ManifestEntry {
+    /// Creates the entry of a downloaded file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_info` - The file as offered by the sender.
+    /// * `summary` - Summary of the finished transfer.
+    /// * `received_at` - When the download finished (unix millis).
+    pub fn new(file_info: &FileInfo, summary: &TransferSummary, received_at: u64) -> Self {
+        ManifestEntry {
+            name: file_info.file_name.clone(),
+            path: summary.path.clone(),
+            size: summary.size,
+            hash: summary.hash.clone().or_else(|| file_info.file_hash.0.clone()),
+            // the hash of the sender, if it wasn't checked, is of the algorithm the sender named
+            hash_algorithm: match summary.hash {
+                Some(_) => HASH_ALGORITHM.to_string(),
+                None => file_info.hash_algorithm.clone().unwrap_or_else(|| HASH_ALGORITHM.to_string()),
+            },
+            hash_verified: summary.hash.is_some(),
+            offered_at: file_info.created_at,
+            received_at,
+            duration_millis: summary.duration_millis,
+            phases: summary.phases.clone(),
+        }
+    }
+}
+
+impl TransferManifest {
+    /// Creates a manifest of files received from the same sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - Display name or hostname of the sender (optional).
+    /// * `files` - The received files.
+    pub fn new(sender: Option<String>, files: Vec<ManifestEntry>) -> Self {
+        TransferManifest { version: MANIFEST_VERSION, sender, files }
+    }
+
+    /// Writes the manifest as pretty printed JSON, replacing the file atomically.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the manifest file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::MatchPolicy;
+    use crate::utils::AnonymousString;
+    use crate::utils::ui::Phase;
+
+    use super::*;
+
+    fn file_info(file_hash: Option<&str>) -> FileInfo {
+        FileInfo {
+            file_size: 2000,
+            file_name: "report.pdf".to_string(),
+            file_hash: AnonymousString(file_hash.map(str::to_string)),
+            sender_host: AnonymousString(None),
+            created_at: 1000,
+            expires_at: 0,
+            sender_addr: "127.0.0.1:4000".parse().unwrap(),
+            sender_mapped_port: None,
+            sender_geo: None,
+            kind: Default::default(),
+            previewable: false,
+            file_mode: None,
+            sparse: false,
+            directory: false,
+            file_count: None,
+            offer_only: false,
+            compress: false,
+            hash_algorithm: None,
+            streaming: false,
+            schedule: None,
+            requested_by: None,
+            receiver_addr: None,
+            pending_connection: None,
+            registration_id: None,
+            announce: false,
+            lookups: 0,
+            matched_at: None,
+            on_match: MatchPolicy::Remove,
+        }
+    }
+
+    fn summary(hash: Option<&str>) -> TransferSummary {
+        TransferSummary {
+            path: PathBuf::from("/tmp/report.pdf"),
+            size: 2000,
+            hash: hash.map(str::to_string),
+            duration_millis: 500,
+            phases: vec![PhaseDuration { phase: Phase::Transferring, duration_millis: 500 }],
+        }
+    }
+
+    #[test]
+    fn test_entry_without_phases() {
+        // manifests written before the phases were recorded
+        let json = r#"{"name":"a","path":"/a","size":1,"hash":null,"hash_algorithm":"blake3","hash_verified":false,
+            "offered_at":1,"received_at":2,"duration_millis":3}"#;
+        let entry: ManifestEntry = serde_json::from_str(json).unwrap();
+        assert!(entry.phases.is_empty());
+    }
+
+    #[test]
+    fn test_entry() {
+        let entry = ManifestEntry::new(&file_info(Some("abc")), &summary(Some("abc")), 2000);
+        assert_eq!(entry.hash.as_deref(), Some("abc"));
+        assert!(entry.hash_verified);
+        assert_eq!((entry.offered_at, entry.received_at), (1000, 2000));
+
+        // with --skip-hash, the announced hash is recorded but not marked as verified
+        let entry = ManifestEntry::new(&file_info(Some("abc")), &summary(None), 2000);
+        assert_eq!(entry.hash.as_deref(), Some("abc"));
+        assert!(!entry.hash_verified);
+
+        let entry = ManifestEntry::new(&file_info(None), &summary(None), 2000);
+        assert_eq!(entry.hash, None);
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        let manifest = TransferManifest::new(
+            Some("alice".to_string()),
+            vec![ManifestEntry::new(&file_info(Some("abc")), &summary(Some("abc")), 2000)],
+        );
+        manifest.write(&path).unwrap();
+        let written: TransferManifest = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, manifest);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+/// Limits for the buffers nudge allocates during a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryProfile {
+    /// Size of the buffer used when hashing files (in bytes)
+    pub hash_buffer_size: usize,
+
+    /// Maximum number of unacknowledged packets kept around for retransmission,
+    /// and the receive window advertised to the peer
+    pub max_in_flight: usize,
+}
+
+impl MemoryProfile {
+    /// Profile used by default
+    pub const DEFAULT: MemoryProfile = MemoryProfile {
+        hash_buffer_size: 8192,
+        max_in_flight: 4096,
+    };
+
+    /// Profile for routers, Raspberry Pis and other memory constrained devices
+    pub const LOW: MemoryProfile = MemoryProfile {
+        hash_buffer_size: 1024,
+        max_in_flight: 32,
+    };
+
+    /// Returns the profile selected by the `--low-memory` flag.
+    ///
+    /// # Arguments
+    ///
+    /// * `low_memory` - Whether the low memory profile should be used.
+    pub fn select(low_memory: bool) -> Self {
+        if low_memory {
+            Self::LOW
+        } else {
+            Self::DEFAULT
+        }
+    }
+
+    /// Estimates the peak memory (in bytes) used by the buffers of a single transfer.
+    ///
+    /// This includes the read/write buffer, the packet buffer, the hash buffer and
+    /// the retransmission buffers, but not the memory used by the runtime itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_size` - The chunk size used for the transfer.
+    ///
+    /// # Returns
+    ///
+    /// `u64` - The estimated peak memory in bytes.
+    pub fn peak_transfer_memory(&self, chunk_size: u32) -> u64 {
+        // each packet carries a 3 byte header
+        let packet_size = chunk_size as u64 + 3;
+        let io_buffers = chunk_size as u64 + packet_size;
+        let retransmit_buffers = packet_size * self.max_in_flight as u64;
+        io_buffers + self.hash_buffer_size as u64 + retransmit_buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        assert_eq!(MemoryProfile::select(false), MemoryProfile::DEFAULT);
+        assert_eq!(MemoryProfile::select(true), MemoryProfile::LOW);
+    }
+
+    #[test]
+    fn test_low_profile_uses_less_memory() {
+        let default = MemoryProfile::DEFAULT.peak_transfer_memory(4096);
+        let low = MemoryProfile::LOW.peak_transfer_memory(4096);
+        assert!(low < default);
+    }
+
+    #[test]
+    fn test_peak_transfer_memory() {
+        let profile = MemoryProfile {
+            hash_buffer_size: 100,
+            max_in_flight: 2,
+        };
+        // 10 (read buffer) + 13 (packet buffer) + 100 (hash buffer) + 2 * 13 (retransmit buffers)
+        assert_eq!(profile.peak_transfer_memory(10), 149);
+    }
+}
@@ -1,19 +1,72 @@
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::Read;
-use std::time::SystemTime;
-use console::style;
-use dialoguer::theme::ColorfulTheme;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use gethostname::gethostname;
-use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use crate::error::{NudgeError, Result};
 
+#[macro_use]
+pub mod ui;
+pub mod bandwidth;
+pub mod batch;
+pub mod blind;
+pub mod bundle;
+pub mod capture;
+pub mod compression;
+pub mod contacts;
+pub mod deterministic;
+pub mod duration;
+pub mod encryption;
+pub mod error_catalog;
+pub mod geoip;
+pub mod glob;
+pub mod hash_cache;
+pub mod identity;
+pub mod interface;
+pub mod logging;
+pub mod manifest;
+pub mod memory;
+pub mod mux;
+pub mod network_profile;
+pub mod pacing;
+pub mod partial;
+pub mod out_template;
 pub mod passphrase;
+pub mod platform;
+pub mod port_mapping;
+pub mod preview;
+pub mod profiling;
+pub mod receiver_policy;
+pub mod relay_config;
+pub mod relay_simulation;
+pub mod relay_stats;
+pub mod rename;
 pub mod reliable_udp;
+pub mod rendezvous;
+pub mod resolver;
+pub mod sandbox;
+pub mod sanitize;
+pub mod schedule;
+pub mod sender_session;
+pub mod session_lock;
+pub mod schema;
+pub mod shard;
 pub mod socket;
+pub mod sparse;
 pub mod serialize;
+pub mod summary;
+pub mod tar;
+pub mod ticket;
+pub mod tunnel;
+pub mod units;
+pub mod watchdog;
+pub mod webhook;
+pub mod websocket;
+pub mod xattr;
 
 #[cfg(debug_assertions)]
 pub const DEFAULT_RELAY_HOST: &str = "127.0.0.1";
@@ -34,7 +87,8 @@ pub struct AnonymousString(pub Option<String>);
 impl Display for AnonymousString {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if let Some(ref hostname) = self.0 {
-            f.write_str(hostname)
+            // hostnames and display names are chosen by the peer
+            f.write_str(&sanitize::sanitize(hostname))
         } else {
             f.write_str("<anonymous>")
         }
@@ -42,18 +96,48 @@ impl Display for AnonymousString {
 }
 
 /// Returns the current time in milliseconds since the Unix epoch.
+/// With `--deterministic`, the time starts at a fixed date instead.
 ///
 /// # Returns
 ///
 /// `u64` - The current time in milliseconds.
 pub fn current_unix_millis() -> u64 {
+    if let Some(millis) = deterministic::unix_millis() {
+        return millis;
+    }
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_millis() as u64
 }
 
-/// Retrieves the hostname of the system.
+/// Creates a random ID for a registration at the relay, so the relay recognizes retries of it.
+pub fn new_registration_id() -> String {
+    format!("{:016x}", deterministic::rng().gen::<u64>())
+}
+
+/// Encodes bytes as lowercase hex, e.g. [0xca, 0xfe] as "cafe".
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes a string created by `encode_hex`.
+///
+/// # Returns
+///
+/// `Option<Vec<u8>>` - The bytes, or `None` if the string isn't valid hex.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Retrieves the hostname of the system, falling back to the environment if the system
+/// doesn't report a usable hostname (e.g. "localhost" on Android).
 ///
 /// # Returns
 ///
@@ -61,81 +145,311 @@ pub fn current_unix_millis() -> u64 {
 pub fn get_hostname() -> Result<String> {
     gethostname()
         .into_string()
-        .map_err(|_| NudgeError::HostnameError)
+        .ok()
+        .filter(|hostname| platform::is_usable_hostname(hostname))
+        .or_else(platform::hostname_from_env)
+        .ok_or(NudgeError::HostnameError)
 }
 
-/// Returns either an anonymous string or the hostname based on the `hide` parameter.
+/// Returns the identity shown to the peer and the relay.
+///
+/// The display name takes precedence over the hostname, which is only shared if requested.
 ///
 /// # Arguments
 ///
-/// * `hide` - A boolean indicating whether to hide the hostname.
+/// * `share_hostname` - A boolean indicating whether to share the hostname.
+/// * `display_name` - A name chosen by the user, e.g. "Alice's laptop".
 ///
 /// # Returns
 ///
-/// `Result<AnonymousString>` - An `AnonymousString` containing either `None` if hidden or `Some(hostname)` if not.
-pub fn hide_or_get_hostname(hide: bool) -> Result<AnonymousString> {
-    Ok(AnonymousString(if hide { None } else { Some(get_hostname()?) }))
+/// `AnonymousString` - `Some(identity)`, or `None` if nothing should be shared
+/// (or no hostname could be found).
+pub fn peer_identity(share_hostname: bool, display_name: Option<&str>) -> AnonymousString {
+    if let Some(display_name) = display_name.map(str::trim).filter(|name| !name.is_empty()) {
+        return AnonymousString(Some(display_name.to_string()));
+    }
+    if !share_hostname {
+        return AnonymousString(None);
+    }
+    match get_hostname() {
+        Ok(hostname) => AnonymousString(Some(hostname)),
+        Err(e) => {
+            debug!("{}, continuing as <anonymous>", e);
+            AnonymousString(None)
+        }
+    }
+}
+
+/// Name of the algorithm files are hashed with, announced to receivers along with the hash
+pub const HASH_ALGORITHM: &str = "blake3";
+
+/// Returns whether hashes of an algorithm can be checked.
+///
+/// # Arguments
+///
+/// * `algorithm` - The algorithm the sender announced, older senders don't (they use BLAKE3 as well).
+pub fn is_known_hash_algorithm(algorithm: Option<&str>) -> bool {
+    algorithm.is_none_or(|algorithm| algorithm.eq_ignore_ascii_case(HASH_ALGORITHM))
+}
+
+/// Returns the name a file of the sender is stored under, the last component of the name it sent. Policies,
+/// renaming and unpacking all work below the output directory, so the name has to be a plain file name, e.g. not `..`.
+///
+/// # Errors
+///
+/// Returns `NudgeError::UnsafePath` if the last component isn't a plain file name.
+pub fn sender_file_name(name: &str) -> Result<&str> {
+    let file_name = name.rsplit('/').next().unwrap_or_default();
+    let mut components = Path::new(file_name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(file_name),
+        _ => Err(NudgeError::UnsafePath(name.to_string())),
+    }
 }
 
 /// Hashes the contents of a file using the BLAKE3 hashing algorithm and resets the file's cursor to the start.
 ///
 /// # Arguments
 ///
-/// * `file` - A mutable reference to the file to be hashed.
+/// * `file` - A mutable reference to the file (or a part of it) to be hashed.
+/// * `buffer_size` - The size of the buffer used to read the file.
 ///
 /// # Returns
 ///
 /// `Result<String>` - The hexadecimal hash string of the file contents.
-pub fn hash_file_and_seek(file: &mut File) -> Result<String> {
+pub fn hash_file_and_seek(file: &mut impl Read, buffer_size: usize) -> Result<String> {
     let mut hasher = blake3::Hasher::new();
-    let mut buffer = [0; 8192];
+    hash_into(&mut hasher, file, buffer_size)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
 
+/// Updates the hasher with everything read from the reader.
+fn hash_into(hasher: &mut blake3::Hasher, reader: &mut impl Read, buffer_size: usize) -> Result<()> {
+    let mut buffer = vec![0; buffer_size];
     loop {
-        let bytes_read = file.read(&mut buffer)?;
+        let bytes_read = reader.read(&mut buffer)?;
         if bytes_read == 0 {
-            break;
+            return Ok(());
         }
         hasher.update(&buffer[..bytes_read]);
     }
+}
 
-    Ok(hasher.finalize().to_hex().to_string())
+/// Size of the zeros hashed at once for the ranges a `HashingWriter` skipped
+const ZEROS_SIZE: usize = 64 * 1024;
+
+/// Hashes the data written through it with BLAKE3 while writing, in the order of the file. The ranges skipped by
+/// seeking ahead (the holes of sparse files) are hashed as zeros, so the hash is the one of the whole file.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+
+    /// Position of the hashed data in `inner`, e.g. where an appended download starts
+    base: u64,
+
+    /// Current position relative to `base`
+    position: u64,
+
+    /// Bytes hashed from `base` on
+    hashed: u64,
 }
 
-/// Creates a customized theme for prompts.
-///
-/// # Returns
-///
-/// `ColorfulTheme` - A theme with customized prompt, success, and error prefixes.
-pub fn question_theme() -> ColorfulTheme {
-    let mut theme = ColorfulTheme::default();
-    theme.prompt_prefix = style("[?]".to_string()).for_stderr().dim();
-    theme.success_prefix = style("[✔]".to_string()).for_stderr().bold().green();
-    theme.error_prefix = style("[✗]".to_string()).for_stderr().bold().red();
-    theme
+impl<W: Read + Write + Seek> HashingWriter<W> {
+    /// Starts hashing the data written, after hashing what's there already.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Where the data is written to.
+    /// * `base` - Position the hashed data starts at in `inner`.
+    /// * `written` - Bytes already written from `base` on, e.g. of a resumed download. They are read and hashed,
+    ///   the writes continue after them.
+    /// * `buffer_size` - The size of the buffer used to read the data already written.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the data already written can't be read.
+    pub fn start(mut inner: W, base: u64, written: u64, buffer_size: usize) -> Result<Self> {
+        let mut hasher = blake3::Hasher::new();
+        if written > 0 {
+            inner.seek(SeekFrom::Start(base))?;
+            hash_into(&mut hasher, &mut (&mut inner).take(written), buffer_size)?;
+        }
+        inner.seek(SeekFrom::Start(base + written))?;
+        Ok(HashingWriter { inner, hasher, base, position: written, hashed: written })
+    }
+}
+
+impl<W> HashingWriter<W> {
+    /// Finishes the hash of the data written.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Size of the data, the end of the data not written (a hole at the end of a sparse file) is zeros.
+    ///
+    /// # Returns
+    ///
+    /// `String` - The hexadecimal hash string of the data.
+    pub fn finalize(mut self, size: u64) -> String {
+        self.hash_zeros_to(size);
+        self.hasher.finalize().to_hex().to_string()
+    }
+
+    fn hash_zeros_to(&mut self, position: u64) {
+        let zeros = [0; ZEROS_SIZE];
+        while self.hashed < position {
+            let len = (position - self.hashed).min(ZEROS_SIZE as u64) as usize;
+            self.hasher.update(&zeros[..len]);
+            self.hashed += len as u64;
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // the data before was hashed already, the file would differ from the hash
+        if self.position < self.hashed {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "data written out of order can't be hashed"));
+        }
+        self.hash_zeros_to(self.position);
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.position += written as u64;
+        self.hashed = self.position;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for HashingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.inner.seek(pos)?;
+        self.position = position.checked_sub(self.base)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "can't seek before the hashed data"))?;
+        Ok(position)
+    }
 }
 
-/// Creates a new progress bar with a specified length and custom style.
+/// Longest pause between two attempts of `read_with_retry`
+const MAX_READ_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Reads into the buffer, retrying failed reads (e.g. NFS hiccups or flaky removable media)
+/// with an exponential backoff.
 ///
 /// # Arguments
 ///
-/// * `length` - The total length of the progress bar.
+/// * `reader` - The reader, e.g. the file being sent.
+/// * `buffer` - The buffer to read into.
+/// * `retries` - How often a failed read is retried.
+/// * `backoff` - Pause before the first retry, doubled for every further retry.
 ///
 /// # Returns
 ///
-/// `ProgressBar` - A progress bar configured with a custom style and prefix.
-pub fn new_downloader_progressbar(len: u64) -> ProgressBar {
-    let progress_bar = ProgressBar::new(len)
-        .with_prefix("[>]");
-    progress_bar.set_style(ProgressStyle::with_template("{prefix:.orange} {elapsed_precise} :: |{wide_bar:.white/dim}| :: {bytes}/{total_bytes}")
-        .unwrap()
-        .progress_chars("█ :"));
-    progress_bar
+/// `std::io::Result<usize>` - The number of bytes read, or the last error if all retries failed.
+pub fn read_with_retry(
+    reader: &mut impl Read,
+    buffer: &mut [u8],
+    retries: u32,
+    backoff: Duration,
+) -> std::io::Result<usize> {
+    let mut backoff = backoff;
+    let mut attempt = 0;
+    loop {
+        match reader.read(buffer) {
+            Ok(bytes_read) => return Ok(bytes_read),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(target: "io", "Cannot read file ({}), retrying in {:?} ({}/{})", e, backoff, attempt, retries);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_READ_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Reader failing a number of times before returning data
+    struct FlakyReader {
+        failures: u32,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(std::io::Error::other("flaky"));
+            }
+            buffer[0] = 42;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_is_known_hash_algorithm() {
+        assert!(is_known_hash_algorithm(None));
+        assert!(is_known_hash_algorithm(Some("BLAKE3")));
+        assert!(!is_known_hash_algorithm(Some("sha256")));
+    }
+
+    #[test]
+    fn test_sender_file_name() {
+        assert_eq!(sender_file_name("report.pdf").unwrap(), "report.pdf");
+        assert_eq!(sender_file_name("docs/report.pdf").unwrap(), "report.pdf");
+        for name in ["..", ".", "a/..", "a/.", "docs/", ""] {
+            assert!(matches!(sender_file_name(name), Err(NudgeError::UnsafePath(_))), "{} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_hashing_writer() {
+        let expected = |data: &[u8]| blake3::hash(data).to_hex().to_string();
+
+        // holes skipped by seeking ahead, and at the end, are zeros
+        let mut writer = HashingWriter::start(std::io::Cursor::new(Vec::new()), 0, 0, 16).unwrap();
+        writer.write_all(b"abc").unwrap();
+        writer.seek(SeekFrom::Start(10)).unwrap();
+        writer.write_all(b"def").unwrap();
+        assert_eq!(writer.finalize(20), expected(b"abc\0\0\0\0\0\0\0def\0\0\0\0\0\0\0"));
+
+        // the data already written is hashed first, the data before the base isn't
+        let mut writer = HashingWriter::start(std::io::Cursor::new(b"log:abc".to_vec()), 4, 3, 2).unwrap();
+        writer.write_all(b"def").unwrap();
+        assert_eq!(writer.finalize(6), expected(b"abcdef"));
+
+        let mut writer = HashingWriter::start(std::io::Cursor::new(Vec::new()), 0, 0, 16).unwrap();
+        writer.write_all(b"abc").unwrap();
+        writer.seek(SeekFrom::Start(1)).unwrap();
+        assert_eq!(writer.write(b"x").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_with_retry() {
+        let mut buffer = [0; 1];
+        let mut reader = FlakyReader { failures: 2 };
+        assert_eq!(read_with_retry(&mut reader, &mut buffer, 2, Duration::ZERO).unwrap(), 1);
+        assert_eq!(buffer[0], 42);
+
+        let mut reader = FlakyReader { failures: 3 };
+        assert!(read_with_retry(&mut reader, &mut buffer, 2, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_hex(&[0xca, 0xfe, 0x00, 0x01]), "cafe0001");
+        assert_eq!(decode_hex("cafe0001"), Some(vec![0xca, 0xfe, 0x00, 0x01]));
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+        assert_eq!(decode_hex("caf"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("ü1"), None);
+    }
+
     #[test]
     fn test_current_unix_millis() {
         let before = SystemTime::now()
@@ -149,4 +463,20 @@ mod tests {
             .as_millis() as u64;
         assert!(millis >= before && millis <= after, "The current_unix_millis function should return the correct time in milliseconds.");
     }
+
+    #[test]
+    fn test_peer_identity_hidden_by_default() {
+        assert_eq!(peer_identity(false, None), AnonymousString(None));
+    }
+
+    #[test]
+    fn test_peer_identity_display_name() {
+        let identity = peer_identity(true, Some(" Alice's laptop "));
+        assert_eq!(identity, AnonymousString(Some("Alice's laptop".to_string())));
+    }
+
+    #[test]
+    fn test_peer_identity_empty_display_name() {
+        assert_eq!(peer_identity(false, Some("  ")), AnonymousString(None));
+    }
 }
@@ -0,0 +1,69 @@
+/// Frame multiplexing several TCP connections over one reliable channel (`nudge forward`).
+///
+/// Encoded as 1 byte type, 4 byte connection id (big endian) and the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuxFrame {
+    /// A client connected to the listening side, the other side should connect to its target
+    Open(u32),
+
+    /// Data for the connection
+    Data(u32, Vec<u8>),
+
+    /// The connection was closed (or couldn't be opened) on the sending side
+    Close(u32),
+}
+
+/// Size of the type and connection id in front of the payload
+pub const MUX_HEADER_SIZE: usize = 5;
+
+impl MuxFrame {
+    /// Encodes the frame to send it over the channel.
+    pub fn encode(&self) -> Vec<u8> {
+        let (frame_type, connection_id, payload): (u8, u32, &[u8]) = match self {
+            MuxFrame::Open(id) => (0, *id, &[]),
+            MuxFrame::Data(id, data) => (1, *id, data),
+            MuxFrame::Close(id) => (2, *id, &[]),
+        };
+        let mut encoded = Vec::with_capacity(MUX_HEADER_SIZE + payload.len());
+        encoded.push(frame_type);
+        encoded.extend_from_slice(&connection_id.to_be_bytes());
+        encoded.extend_from_slice(payload);
+        encoded
+    }
+
+    /// Decodes a frame received from the channel.
+    ///
+    /// # Returns
+    ///
+    /// `Option<MuxFrame>` - The frame, or `None` if it's truncated or of an unknown type.
+    pub fn decode(data: &[u8]) -> Option<MuxFrame> {
+        if data.len() < MUX_HEADER_SIZE {
+            return None;
+        }
+        let connection_id = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+        match data[0] {
+            0 => Some(MuxFrame::Open(connection_id)),
+            1 => Some(MuxFrame::Data(connection_id, data[MUX_HEADER_SIZE..].to_vec())),
+            2 => Some(MuxFrame::Close(connection_id)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        for frame in [MuxFrame::Open(1), MuxFrame::Data(70000, b"hello".to_vec()), MuxFrame::Close(u32::MAX)] {
+            assert_eq!(MuxFrame::decode(&frame.encode()), Some(frame));
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(MuxFrame::decode(&[1, 0, 0]), None);
+        assert_eq!(MuxFrame::decode(&[9, 0, 0, 0, 1]), None);
+    }
+}
@@ -0,0 +1,170 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::memory::MemoryProfile;
+
+/// Delay between two packets (in microseconds) if neither `--delay` nor `--profile` is given
+const DEFAULT_DELAY: u64 = 500;
+
+/// Chunk size (in bytes) if neither `--chunk-size` nor `--profile` is given
+const DEFAULT_CHUNK_SIZE: u32 = 4096;
+
+/// Largest chunk size, a chunk and the 3 byte header must fit into a frame
+pub const MAX_CHUNK_SIZE: u32 = 0xfffc;
+
+/// Chunk size keeping a packet (with the IPv6 and UDP headers) below the minimum IPv6 MTU of 1280 bytes,
+/// so packets are never fragmented on the way
+pub const UNFRAGMENTED_CHUNK_SIZE: u32 = 1200;
+
+/// Bundles of transfer settings for typical networks, selected with `--profile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkProfile {
+    /// Local network: large packets, (almost) no pacing and a large window
+    Lan,
+    /// Internet: unfragmented packets, light pacing and a moderate window
+    Wan,
+    /// Cellular and other lossy, high latency links: unfragmented packets, strong pacing and a small window
+    Mobile,
+}
+
+/// Transfer settings of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferTuning {
+    /// Size of the data in a packet (in bytes)
+    pub chunk_size: u32,
+
+    /// Delay between two packets (in microseconds)
+    pub delay: u64,
+
+    /// Maximum number of unacknowledged packets, and the receive window advertised to the peer
+    pub max_in_flight: usize,
+}
+
+impl NetworkProfile {
+    /// Returns the settings bundled by the profile.
+    pub fn tuning(self) -> TransferTuning {
+        match self {
+            NetworkProfile::Lan => TransferTuning { chunk_size: 16384, delay: 50, max_in_flight: 4096 },
+            NetworkProfile::Wan => TransferTuning { chunk_size: UNFRAGMENTED_CHUNK_SIZE, delay: 100, max_in_flight: 1024 },
+            NetworkProfile::Mobile => TransferTuning { chunk_size: UNFRAGMENTED_CHUNK_SIZE, delay: 1000, max_in_flight: 128 },
+        }
+    }
+}
+
+impl TransferTuning {
+    /// Combines the selected profile with the explicitly given flags, which take precedence.
+    /// The window never exceeds the one allowed by the memory profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The network profile selected with `--profile` (optional).
+    /// * `chunk_size` - The chunk size given with `--chunk-size` (optional).
+    /// * `delay` - The delay given with `--delay` (optional).
+    /// * `memory_profile` - The memory profile selected with `--low-memory`.
+    pub fn resolve(
+        profile: Option<NetworkProfile>,
+        chunk_size: Option<u32>,
+        delay: Option<u64>,
+        memory_profile: &MemoryProfile,
+    ) -> Self {
+        let base = profile.map(NetworkProfile::tuning).unwrap_or(TransferTuning {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            delay: DEFAULT_DELAY,
+            max_in_flight: memory_profile.max_in_flight,
+        });
+        TransferTuning {
+            chunk_size: chunk_size.unwrap_or(base.chunk_size),
+            delay: delay.unwrap_or(base.delay),
+            max_in_flight: base.max_in_flight.min(memory_profile.max_in_flight),
+        }
+    }
+}
+
+/// Agrees on the chunk size with the receiver, which announces the largest chunks it takes if it was started with
+/// `--chunk-size` or `--profile` (e.g. because its network drops fragmented packets). A default or profile chunk size
+/// of ours is lowered to it, one given with `--chunk-size` is not.
+///
+/// # Arguments
+///
+/// * `chunk_size` - Our chunk size.
+/// * `explicit` - Whether our chunk size was given with `--chunk-size`.
+/// * `receiver_max` - The largest chunk size the receiver takes (optional, older receivers don't tell).
+///
+/// # Errors
+///
+/// Returns `NudgeError::ChunkSizeMismatch` if our explicit chunk size is larger than the receiver takes.
+pub fn negotiate_chunk_size(chunk_size: u32, explicit: bool, receiver_max: Option<u32>) -> Result<u32> {
+    let Some(receiver_max) = receiver_max else {
+        return Ok(chunk_size);
+    };
+    let receiver_max = receiver_max.clamp(1, MAX_CHUNK_SIZE);
+    match chunk_size <= receiver_max {
+        true => Ok(chunk_size),
+        false if explicit => Err(NudgeError::ChunkSizeMismatch(chunk_size, receiver_max)),
+        false => Ok(receiver_max),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: [NetworkProfile; 3] = [NetworkProfile::Lan, NetworkProfile::Wan, NetworkProfile::Mobile];
+
+    #[test]
+    fn test_default() {
+        let tuning = TransferTuning::resolve(None, None, None, &MemoryProfile::DEFAULT);
+        assert_eq!(tuning, TransferTuning { chunk_size: 4096, delay: 500, max_in_flight: 4096 });
+        assert_eq!(DEFAULT_CHUNK_SIZE.to_string(), crate::utils::DEFAULT_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_profiles() {
+        for profile in PROFILES {
+            let tuning = TransferTuning::resolve(Some(profile), None, None, &MemoryProfile::DEFAULT);
+            assert_eq!(tuning, profile.tuning(), "{:?}", profile);
+            // a chunk and the 3 byte header must fit into a frame
+            assert!(tuning.chunk_size > 0 && tuning.chunk_size <= MAX_CHUNK_SIZE, "{:?}", profile);
+            assert!(tuning.max_in_flight > 0, "{:?}", profile);
+        }
+
+        let (lan, wan, mobile) = (NetworkProfile::Lan.tuning(), NetworkProfile::Wan.tuning(), NetworkProfile::Mobile.tuning());
+        // the lossier the network, the more careful the sender
+        assert!(lan.chunk_size > wan.chunk_size && wan.chunk_size >= mobile.chunk_size);
+        assert!(lan.delay < wan.delay && wan.delay < mobile.delay);
+        assert!(lan.max_in_flight > wan.max_in_flight && wan.max_in_flight > mobile.max_in_flight);
+        // packets on the internet stay below the minimum IPv6 MTU (40 bytes IPv6, 8 bytes UDP, 3 bytes header)
+        assert!(wan.chunk_size + 3 + 8 + 40 <= 1280);
+        assert!(mobile.chunk_size + 3 + 8 + 40 <= 1280);
+    }
+
+    #[test]
+    fn test_flags_take_precedence() {
+        for profile in PROFILES {
+            let tuning = TransferTuning::resolve(Some(profile), Some(2048), Some(7), &MemoryProfile::DEFAULT);
+            assert_eq!((tuning.chunk_size, tuning.delay), (2048, 7), "{:?}", profile);
+            assert_eq!(tuning.max_in_flight, profile.tuning().max_in_flight, "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn test_low_memory_caps_window() {
+        for profile in PROFILES {
+            let tuning = TransferTuning::resolve(Some(profile), None, None, &MemoryProfile::LOW);
+            assert_eq!(tuning.max_in_flight, MemoryProfile::LOW.max_in_flight, "{:?}", profile);
+        }
+        let tuning = TransferTuning::resolve(None, None, None, &MemoryProfile::LOW);
+        assert_eq!(tuning.max_in_flight, MemoryProfile::LOW.max_in_flight);
+    }
+
+    #[test]
+    fn test_negotiate_chunk_size() {
+        assert_eq!(negotiate_chunk_size(16384, false, None).unwrap(), 16384);
+        assert_eq!(negotiate_chunk_size(4096, true, Some(16384)).unwrap(), 4096);
+        assert_eq!(negotiate_chunk_size(16384, false, Some(1200)).unwrap(), 1200);
+        assert_eq!(negotiate_chunk_size(4096, false, Some(0)).unwrap(), 1);
+        assert!(matches!(negotiate_chunk_size(16384, true, Some(1200)), Err(NudgeError::ChunkSizeMismatch(16384, 1200))));
+    }
+}
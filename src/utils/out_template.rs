@@ -0,0 +1,263 @@
+use std::path::{Component, Path, PathBuf};
+
+use time::OffsetDateTime;
+
+use crate::error::{NudgeError, Result};
+use crate::models::FileInfo;
+
+/// Value of the `{sender}` variable if the sender doesn't share its name
+const ANONYMOUS_SENDER: &str = "anonymous";
+
+/// A variable of the template, expanded from the `FileInfo` of the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    /// File name of the sender, e.g. "report.tar.gz"
+    Name,
+    /// File name without the last extension, e.g. "report.tar"
+    Stem,
+    /// Last extension without the dot, e.g. "gz" (empty if none)
+    Ext,
+    /// Display name or hostname of the sender ("anonymous" if not shared)
+    Sender,
+    /// Date the sender registered the file (UTC), e.g. "2024-05-31"
+    Date,
+    /// Year of `Date`, e.g. "2024"
+    Year,
+    /// Month of `Date`, e.g. "05"
+    Month,
+    /// Day of `Date`, e.g. "31"
+    Day,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "name" => Variable::Name,
+            "stem" => Variable::Stem,
+            "ext" => Variable::Ext,
+            "sender" => Variable::Sender,
+            "date" => Variable::Date,
+            "year" => Variable::Year,
+            "month" => Variable::Month,
+            "day" => Variable::Day,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// Template for the path of received files, relative to the working directory
+/// (or the `out_dir` of a receiver policy), e.g. "{date}/{sender}/{name}".
+///
+/// Expanded values never contain path separators or `..`, so a sender can't choose where the file ends up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutTemplate {
+    parts: Vec<Part>,
+}
+
+impl OutTemplate {
+    /// Parses a template. Braces are written as `{{` and `}}`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidOutTemplate` if the template uses an unknown variable,
+    /// has unbalanced braces or isn't a relative path without `..`.
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = |reason: &str| NudgeError::InvalidOutTemplate(format!("{}: {}", input, reason));
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(invalid("unbalanced {")),
+                        }
+                    }
+                    let variable = Variable::parse(&name)
+                        .ok_or_else(|| invalid(&format!("unknown variable {{{}}}", name)))?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Variable(variable));
+                }
+                '}' => return Err(invalid("unbalanced }")),
+                '\\' => return Err(invalid("use / to separate directories")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        let template = OutTemplate { parts };
+        // the literal parts alone must stay below the output directory
+        let skeleton = template.render(|_| "x".to_string());
+        if !Path::new(&skeleton).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(invalid("must be a relative path without .."));
+        }
+        if !template.parts.iter().any(|part| matches!(part, Part::Variable(_))) {
+            return Err(invalid("must contain a variable, e.g. {name}"));
+        }
+        Ok(template)
+    }
+
+    /// Expands the template for the offered file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_info` - The file offered by the sender.
+    ///
+    /// # Returns
+    ///
+    /// `PathBuf` - The relative path to store the file at.
+    pub fn expand(&self, file_info: &FileInfo) -> PathBuf {
+        let name = file_info.file_name.split('/').next_back().unwrap_or_default();
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+            _ => (name, ""),
+        };
+        let date = OffsetDateTime::from_unix_timestamp_nanos(file_info.created_at as i128 * 1_000_000)
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+        let rendered = self.render(|variable| match variable {
+            Variable::Name => sanitize(name),
+            Variable::Stem => sanitize(stem),
+            Variable::Ext => sanitize(ext),
+            Variable::Sender => sanitize(file_info.sender_host.0.as_deref().unwrap_or(ANONYMOUS_SENDER)),
+            Variable::Date => format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day()),
+            Variable::Year => format!("{:04}", date.year()),
+            Variable::Month => format!("{:02}", date.month() as u8),
+            Variable::Day => format!("{:02}", date.day()),
+        });
+        // empty values (e.g. a missing extension) must not create empty or special directories
+        let path: PathBuf = rendered.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| if matches!(segment, "." | "..") { "_" } else { segment })
+            .collect();
+        if path.as_os_str().is_empty() {
+            return PathBuf::from(sanitize(name));
+        }
+        path
+    }
+
+    fn render(&self, mut value: impl FnMut(Variable) -> String) -> String {
+        self.parts.iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Variable(variable) => value(*variable),
+            })
+            .collect()
+    }
+}
+
+/// Makes a value usable as (part of) a single path segment,
+/// replacing separators, control characters and characters Windows doesn't allow in file names.
+fn sanitize(value: &str) -> String {
+    let value: String = value.trim()
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    match value.as_str() {
+        "." | ".." => "_".to_string(),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::MatchPolicy;
+    use crate::utils::AnonymousString;
+
+    use super::*;
+
+    fn file_info(file_name: &str, sender_host: Option<&str>) -> FileInfo {
+        FileInfo {
+            file_size: 1,
+            file_name: file_name.to_string(),
+            file_hash: AnonymousString(None),
+            sender_host: AnonymousString(sender_host.map(str::to_string)),
+            // 2024-05-31 12:00:00 UTC
+            created_at: 1_717_156_800_000,
+            expires_at: 0,
+            sender_addr: "127.0.0.1:4000".parse().unwrap(),
+            sender_mapped_port: None,
+            sender_geo: None,
+            kind: Default::default(),
+            previewable: false,
+            file_mode: None,
+            sparse: false,
+            directory: false,
+            file_count: None,
+            offer_only: false,
+            compress: false,
+            hash_algorithm: None,
+            streaming: false,
+            schedule: None,
+            requested_by: None,
+            receiver_addr: None,
+            pending_connection: None,
+            registration_id: None,
+            announce: false,
+            lookups: 0,
+            matched_at: None,
+            on_match: MatchPolicy::Remove,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(OutTemplate::parse("{date}/{sender}/{name}").is_ok());
+        assert!(OutTemplate::parse("{{literal}}-{name}").is_ok());
+        assert!(OutTemplate::parse("{nme}").is_err());
+        assert!(OutTemplate::parse("{name").is_err());
+        assert!(OutTemplate::parse("name}").is_err());
+        assert!(OutTemplate::parse("inbox").is_err());
+        assert!(OutTemplate::parse("/srv/{name}").is_err());
+        assert!(OutTemplate::parse("../{name}").is_err());
+        assert!(OutTemplate::parse("a\\{name}").is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let template = OutTemplate::parse("{date}/{sender}/{name}").unwrap();
+        assert_eq!(
+            template.expand(&file_info("report.pdf", Some("Alice's laptop"))),
+            PathBuf::from("2024-05-31/Alice's laptop/report.pdf"),
+        );
+        assert_eq!(template.expand(&file_info("report.pdf", None)), PathBuf::from("2024-05-31/anonymous/report.pdf"));
+
+        let template = OutTemplate::parse("{year}/{month}/{ext}/{stem}-{day}.{ext}").unwrap();
+        assert_eq!(template.expand(&file_info("a.tar.gz", None)), PathBuf::from("2024/05/gz/a.tar-31.gz"));
+        // a missing extension doesn't create an empty directory
+        assert_eq!(template.expand(&file_info("README", None)), PathBuf::from("2024/05/README-31."));
+        assert_eq!(template.expand(&file_info(".bashrc", None)), PathBuf::from("2024/05/.bashrc-31."));
+    }
+
+    #[test]
+    fn test_expand_is_safe() {
+        let template = OutTemplate::parse("{sender}/{name}").unwrap();
+        assert_eq!(template.expand(&file_info("x", Some("../.."))), PathBuf::from(".._../x"));
+        assert_eq!(template.expand(&file_info("x", Some(".."))), PathBuf::from("_/x"));
+        assert_eq!(template.expand(&file_info("x", Some("/etc"))), PathBuf::from("_etc/x"));
+        assert_eq!(template.expand(&file_info("a/..", Some("c:\\"))), PathBuf::from("c__/_"));
+        assert_eq!(OutTemplate::parse("{ext}").unwrap().expand(&file_info("README", None)), PathBuf::from("README"));
+    }
+}
@@ -0,0 +1,133 @@
+//! Spaces the datagrams of a transfer `--delay` apart on a fixed schedule. Sleeping for the delay after every
+//! datagram adds the time spent sending and the oversleeping of the OS to every gap, and the gaps drift apart.
+//! The schedule instead gives every datagram a departure time, waits for it precisely (sleeping, then spinning
+//! the last stretch) and, with `send --txtime` on Linux, hands it to the kernel to send the datagram on time.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Most time the schedule may lag behind, e.g. after waiting for acknowledgments.
+/// Datagrams behind by more restart the schedule instead of being sent in a burst to catch up
+const MAX_LAG: Duration = Duration::from_millis(2);
+
+/// The end of a wait shorter than this is spun instead of slept, since the OS oversleeps
+const SPIN_THRESHOLD: Duration = Duration::from_micros(100);
+
+/// How far ahead of their departure datagrams are handed to the kernel with SO_TXTIME
+const TXTIME_LEAD: Duration = Duration::from_millis(1);
+
+/// Departure times of the datagrams sent on a connection
+#[derive(Debug, Default)]
+pub struct PacketPacer {
+    /// Departure of the next datagram, `None` until the first one is sent
+    next: Option<Instant>,
+
+    /// Whether the kernel sends the datagrams at their departure (SO_TXTIME)
+    txtime: bool,
+}
+
+impl PacketPacer {
+    /// Creates a pacer.
+    ///
+    /// # Arguments
+    ///
+    /// * `txtime` - Whether the kernel sends the datagrams at their departure, so they only need to be handed over
+    ///   shortly before.
+    pub fn new(txtime: bool) -> Self {
+        PacketPacer { next: None, txtime }
+    }
+
+    /// Returns whether the kernel sends the datagrams at their departure.
+    pub fn txtime(&self) -> bool {
+        self.txtime
+    }
+
+    /// Gives the next datagram its departure time.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time.
+    /// * `interval` - Time between two datagrams, zero sends them right away.
+    ///
+    /// # Returns
+    ///
+    /// `Instant` - When to send the datagram, the current time if it's due already.
+    pub fn schedule(&mut self, now: Instant, interval: Duration) -> Instant {
+        // datagrams slightly behind schedule go out right away, the following ones keep the original spacing
+        let (departure, scheduled) = match self.next {
+            Some(next) if now.saturating_duration_since(next) <= MAX_LAG => (next.max(now), next),
+            _ => (now, now),
+        };
+        self.next = Some(scheduled + interval);
+        departure
+    }
+
+    /// Waits until the datagram may be handed to the socket: its departure, or shortly before with SO_TXTIME.
+    ///
+    /// # Arguments
+    ///
+    /// * `departure` - The departure from `schedule`.
+    pub fn wait(&self, departure: Instant) {
+        if self.txtime {
+            let now = Instant::now();
+            if let Some(wait) = departure.checked_duration_since(now).and_then(|wait| wait.checked_sub(TXTIME_LEAD)) {
+                thread::sleep(wait);
+            }
+            return;
+        }
+        wait_until(departure);
+    }
+}
+
+/// Sleeps until shortly before the deadline, then spins until it's reached.
+fn wait_until(deadline: Instant) {
+    let now = Instant::now();
+    if let Some(sleep) = deadline.saturating_duration_since(now).checked_sub(SPIN_THRESHOLD) {
+        thread::sleep(sleep);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let start = Instant::now();
+        let interval = Duration::from_micros(500);
+        let mut pacer = PacketPacer::new(false);
+        assert_eq!(pacer.schedule(start, interval), start);
+        // sending took time, the gap stays the same
+        assert_eq!(pacer.schedule(start + Duration::from_micros(100), interval), start + interval);
+        assert_eq!(pacer.schedule(start + Duration::from_micros(100), interval), start + interval * 2);
+        // slightly late, sent right away and the schedule is kept
+        let late = start + interval * 3 + Duration::from_micros(300);
+        assert_eq!(pacer.schedule(late, interval), late);
+        assert_eq!(pacer.schedule(late, interval), start + interval * 4);
+        // far behind, the schedule restarts instead of bursting
+        let resumed = start + Duration::from_secs(1);
+        assert_eq!(pacer.schedule(resumed, interval), resumed);
+        assert_eq!(pacer.schedule(resumed, interval), resumed + interval);
+    }
+
+    #[test]
+    fn test_schedule_without_delay() {
+        let start = Instant::now();
+        let mut pacer = PacketPacer::new(false);
+        assert_eq!(pacer.schedule(start, Duration::ZERO), start);
+        assert_eq!(pacer.schedule(start, Duration::ZERO), start);
+        let later = start + Duration::from_millis(5);
+        assert_eq!(pacer.schedule(later, Duration::ZERO), later);
+    }
+
+    #[test]
+    fn test_wait() {
+        let pacer = PacketPacer::new(false);
+        let deadline = Instant::now() + Duration::from_millis(2);
+        pacer.wait(deadline);
+        assert!(Instant::now() >= deadline);
+    }
+}
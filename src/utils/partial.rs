@@ -0,0 +1,191 @@
+//! Sidecar files next to the output of `get` (`report.pdf.nudge-part`), recording how much of the file was received.
+//! If the transfer dies, the next `get` of the same file asks the sender to continue after the recorded bytes
+//! (`ResumeMessage`) instead of starting over. The sidecar is removed once the data stream is complete.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::models::FileInfo;
+
+/// Appended to the name of the output file, e.g. `report.pdf.nudge-part`
+pub const PART_SUFFIX: &str = ".nudge-part";
+
+/// How often the received bytes are recorded while receiving, in milliseconds
+const SAVE_INTERVAL_MILLIS: u64 = 1000;
+
+/// Content of a sidecar
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PartState {
+    /// Hash and size of the sender's file, a download is only resumed for the same file
+    file_hash: String,
+    file_size: u64,
+
+    /// Bytes written to the output, from its beginning
+    received: u64,
+}
+
+/// Records the progress of a download in its sidecar, the last progress is recorded when this is dropped
+/// before the download completed.
+#[derive(Debug)]
+pub struct PartialDownload {
+    path: PathBuf,
+    state: PartState,
+
+    /// When the progress was recorded last (unix millis)
+    saved_at: u64,
+    complete: bool,
+}
+
+impl PartialDownload {
+    /// Looks for the sidecar of an interrupted download of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `out_file_name` - Path of the output file.
+    /// * `file_info` - The file about to be downloaded.
+    ///
+    /// # Returns
+    ///
+    /// `Option<u64>` - The bytes received before, or `None` if there's nothing to resume: no sidecar, one of
+    /// another file, or an output shorter than recorded.
+    pub fn find(out_file_name: &Path, file_info: &FileInfo) -> Option<u64> {
+        let json = fs::read_to_string(part_path(out_file_name)).ok()?;
+        let state: PartState = serde_json::from_str(&json).ok()?;
+        let output_size = fs::metadata(out_file_name).ok()?.len();
+        let same_file = Some(&state.file_hash) == file_info.file_hash.0.as_ref() && state.file_size == file_info.file_size;
+        (same_file && state.received > 0 && state.received <= output_size.min(state.file_size)).then_some(state.received)
+    }
+
+    /// Starts recording the progress of a download, which has the file hash of the sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `out_file_name` - Path of the output file.
+    /// * `file_info` - The file being downloaded.
+    /// * `received` - Bytes already in the output, e.g. of a resumed download.
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Option<Self>>` - The recording, `None` if the sender sent no hash to recognize the file by.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the sidecar can't be written.
+    pub fn start(out_file_name: &Path, file_info: &FileInfo, received: u64, now: u64) -> Result<Option<Self>> {
+        let Some(file_hash) = file_info.file_hash.0.clone() else {
+            return Ok(None);
+        };
+        let partial = PartialDownload {
+            path: part_path(out_file_name),
+            state: PartState { file_hash, file_size: file_info.file_size, received },
+            saved_at: now,
+            complete: false,
+        };
+        partial.save()?;
+        Ok(Some(partial))
+    }
+
+    /// Notes the bytes written to the output, recorded in the sidecar at most every `SAVE_INTERVAL_MILLIS`.
+    /// Failing to record is logged, not fatal to the download.
+    ///
+    /// # Arguments
+    ///
+    /// * `received` - Bytes written to the output, from its beginning.
+    /// * `now` - The current time in unix millis.
+    pub fn record(&mut self, received: u64, now: u64) {
+        self.state.received = received;
+        if now.saturating_sub(self.saved_at) < SAVE_INTERVAL_MILLIS {
+            return;
+        }
+        self.saved_at = now;
+        if let Err(e) = self.save() {
+            warn!(target: "io", "Cannot record the progress in {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Removes the sidecar, the data stream is complete and there's nothing left to resume.
+    pub fn complete(mut self) {
+        self.complete = true;
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(target: "io", "Cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string(&self.state)?)?;
+        Ok(())
+    }
+}
+
+impl Drop for PartialDownload {
+    fn drop(&mut self) {
+        if self.complete {
+            return;
+        }
+        match self.save() {
+            Ok(()) => debug!(target: "io", "Recorded {} received bytes in {}", self.state.received, self.path.display()),
+            Err(e) => warn!(target: "io", "Cannot record the progress in {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Returns the path of the sidecar of an output file.
+pub fn part_path(out_file_name: &Path) -> PathBuf {
+    let mut name = out_file_name.file_name().unwrap_or_default().to_os_string();
+    name.push(PART_SUFFIX);
+    out_file_name.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::AnonymousString;
+
+    fn file_info(file_hash: Option<&str>) -> FileInfo {
+        serde_json::from_value(serde_json::json!({
+            "file_size": 100,
+            "file_name": "report.pdf",
+            "file_hash": file_hash,
+            "sender_host": null,
+            "created_at": 0,
+            "sender_addr": "127.0.0.1:1",
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("report.pdf");
+        fs::write(&out, [0; 100]).unwrap();
+        let info = file_info(Some("abc"));
+        assert_eq!(PartialDownload::find(&out, &info), None);
+
+        let mut partial = PartialDownload::start(&out, &info, 0, 0).unwrap().unwrap();
+        partial.record(10, 500);
+        // recorded at most once a second, and when dropped
+        assert_eq!(PartialDownload::find(&out, &info), None);
+        partial.record(40, 1000);
+        assert_eq!(PartialDownload::find(&out, &info), Some(40));
+        partial.record(60, 1200);
+        drop(partial);
+        assert_eq!(PartialDownload::find(&out, &info), Some(60));
+
+        // only the same file is resumed
+        assert_eq!(PartialDownload::find(&out, &file_info(Some("def"))), None);
+        let partial = PartialDownload::start(&out, &info, 60, 2000).unwrap().unwrap();
+        partial.complete();
+        assert!(!part_path(&out).exists());
+    }
+
+    #[test]
+    fn test_resume_without_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("report.pdf");
+        let info = FileInfo { file_hash: AnonymousString(None), ..file_info(Some("abc")) };
+        assert!(PartialDownload::start(&out, &info, 0, 0).unwrap().is_none());
+    }
+}
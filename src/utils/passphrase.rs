@@ -1,11 +1,25 @@
 use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
-use rand::{Rng, thread_rng};
+use std::io::BufRead;
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use crate::error::Result;
+use crate::error::{NudgeError, Result};
+use crate::utils::{deterministic, DEFAULT_RELAY_PORT};
+
+/// Scheme of links carrying the relay together with the passphrase
+pub const LINK_SCHEME: &str = "nudge://";
 
-/// A passphrase generator that can generate passphrases
-pub struct PassphraseGenerator(Vec<String>);
+/// A passphrase generator that can generate passphrases.
+/// The words are picked by `StdRng` (ChaCha12) seeded from the operating system, a CSPRNG,
+/// unless `--deterministic` seeds it with a constant
+pub struct PassphraseGenerator {
+    words: Vec<String>,
+    word_count: usize,
+    rng: Mutex<StdRng>,
+}
 
 // A passphrase, e.g. "correct-horse-battery"
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
@@ -29,9 +43,169 @@ impl Display for Passphrase<'_> {
     }
 }
 
+impl Passphrase<'_> {
+    /// Minimum length of passphrases chosen by the sender
+    pub const MIN_CHOSEN_LEN: usize = 8;
+
+    /// Maximum length of passphrases chosen by the sender
+    pub const MAX_CHOSEN_LEN: usize = 128;
+
+    /// Minimum estimated entropy of passphrases chosen by the sender, a bit more than 3 generated words carry
+    pub const MIN_CHOSEN_ENTROPY_BITS: f64 = 40.0;
+
+    /// Reads a passphrase from the first non-empty line of the reader (e.g. stdin or a file).
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reader to read the passphrase from.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Passphrase)` - The trimmed passphrase.
+    /// * `Err(NudgeError::PassphraseMissing)` - If the reader contains no passphrase.
+    pub fn read_from(reader: impl BufRead) -> Result<Passphrase<'static>> {
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() {
+                return Ok(Passphrase::from(line.to_string()));
+            }
+        }
+        Err(NudgeError::PassphraseMissing)
+    }
+
+    /// Checks whether a passphrase chosen by the sender is acceptable.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the passphrase can be used.
+    /// * `Err(NudgeError::InvalidPassphrase)` - If the passphrase is too short, too long, too easy to guess
+    ///   or contains whitespace.
+    pub fn validate_chosen(&self) -> Result<()> {
+        let len = self.0.chars().count();
+        if !(Self::MIN_CHOSEN_LEN..=Self::MAX_CHOSEN_LEN).contains(&len) {
+            return Err(NudgeError::InvalidPassphrase(format!(
+                "must be between {} and {} characters long",
+                Self::MIN_CHOSEN_LEN,
+                Self::MAX_CHOSEN_LEN
+            )));
+        }
+        if self.0.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(NudgeError::InvalidPassphrase("must not contain whitespace".to_string()));
+        }
+        let entropy_bits = self.estimated_entropy_bits();
+        if entropy_bits < Self::MIN_CHOSEN_ENTROPY_BITS {
+            return Err(NudgeError::InvalidPassphrase(format!(
+                "is too easy to guess (about {:.0} bits of entropy, at least {:.0} are needed), \
+                 use more words or let the relay generate one",
+                entropy_bits,
+                Self::MIN_CHOSEN_ENTROPY_BITS
+            )));
+        }
+        Ok(())
+    }
+
+    /// Estimates the entropy of a chosen passphrase in bits, from the classes of characters it uses
+    /// (lowercase, uppercase, digits, others) and its number of distinct characters. Repeating characters
+    /// adds nothing, so "aaaaaaaaaa" or a 12 digit number don't pass for strong passphrases.
+    /// It's an upper bound for passphrases with structure, e.g. dictionary words.
+    pub fn estimated_entropy_bits(&self) -> f64 {
+        let has = |class: fn(&char) -> bool| self.0.chars().any(|c| class(&c));
+        let pool = [
+            (has(char::is_ascii_lowercase), 26),
+            (has(char::is_ascii_uppercase), 26),
+            (has(char::is_ascii_digit), 10),
+            (has(|c| !c.is_ascii_alphanumeric()), 33),
+        ].iter().filter(|(used, _)| *used).map(|(_, size)| size).sum::<u32>();
+        let distinct = self.0.chars().collect::<std::collections::HashSet<_>>().len();
+        if pool == 0 {
+            return 0.0;
+        }
+        distinct as f64 * (pool as f64).log2()
+    }
+}
+
+/// A passphrase together with the relay its session is registered at,
+/// e.g. `nudge://relay.example:4000/correct-horse-battery`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassphraseLink {
+    /// Address of the relay as "host:port"
+    pub relay: String,
+
+    pub passphrase: Passphrase<'static>,
+}
+
+impl PassphraseLink {
+    /// Checks whether the input is meant as a link rather than a plain passphrase.
+    pub fn is_link(input: &str) -> bool {
+        input.trim().starts_with(LINK_SCHEME)
+    }
+
+    /// Parses a link created by `to_string`. The port defaults to the one of the default relay.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidLink` if the input isn't a link or lacks the relay or the passphrase.
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = |reason: &str| NudgeError::InvalidLink(format!("{} ({})", input.trim(), reason));
+        let rest = input.trim().strip_prefix(LINK_SCHEME).ok_or_else(|| invalid("not a nudge:// link"))?;
+        let (relay, passphrase) = rest.split_once('/').ok_or_else(|| invalid("no passphrase"))?;
+        if relay.is_empty() {
+            return Err(invalid("no relay"));
+        }
+        let passphrase = percent_decode(passphrase.trim_end_matches('/')).ok_or_else(|| invalid("bad escape"))?;
+        if passphrase.is_empty() {
+            return Err(invalid("no passphrase"));
+        }
+
+        // a colon after the closing bracket of an IPv6 address (or in a hostname) starts the port
+        let has_port = relay.rsplit_once(']').map_or(relay.contains(':'), |(_, port)| port.starts_with(':'));
+        let relay = if has_port { relay.to_string() } else { format!("{}:{}", relay, DEFAULT_RELAY_PORT) };
+        Ok(PassphraseLink { relay, passphrase: Passphrase::from(passphrase) })
+    }
+}
+
+impl Display for PassphraseLink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}/{}", LINK_SCHEME, self.relay, percent_encode(&self.passphrase.0))
+    }
+}
+
+/// Escapes everything but unreserved URI characters, so chosen passphrases survive in a link.
+fn percent_encode(input: &str) -> String {
+    input.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// Reverses `percent_encode`.
+///
+/// # Returns
+///
+/// `Option<String>` - The decoded input, or `None` if an escape or the decoded bytes are invalid.
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let escape = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(escape, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
 impl PassphraseGenerator {
     const AVG_WORD_SIZE: usize = 5;
 
+    /// Number of words in generated passphrases, unless more are needed for the minimum entropy
+    const DEFAULT_WORD_COUNT: usize = 3;
+
     /// Creates a new PassphraseGenerator.
     ///
     /// # Returns
@@ -40,8 +214,34 @@ impl PassphraseGenerator {
     /// * `Err` - If there is an error reading the file.
     pub fn new() -> Result<Self> {
         let content = include_str!("../english-medium.txt");
-        let lines: Vec<String> = content.lines().map(str::to_owned).collect();
-        Ok(PassphraseGenerator(lines))
+        let words: Vec<String> = content.lines().map(str::to_owned).collect();
+        Ok(PassphraseGenerator { words, word_count: Self::DEFAULT_WORD_COUNT, rng: Mutex::new(deterministic::rng()) })
+    }
+
+    /// Adds words to the generated passphrases until they carry the given entropy.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_entropy_bits` - The minimum entropy of a generated passphrase in bits (`--min-entropy-bits`).
+    pub fn with_min_entropy(self, min_entropy_bits: u32) -> Self {
+        let bits_per_word = (self.words.len() as f64).log2();
+        let word_count = ((min_entropy_bits as f64 / bits_per_word).ceil() as usize).max(Self::DEFAULT_WORD_COUNT);
+        PassphraseGenerator { word_count, ..self }
+    }
+
+    /// Returns the number of words in generated passphrases.
+    pub fn word_count(&self) -> usize {
+        self.word_count
+    }
+
+    /// Returns the number of words the passphrases are picked from.
+    pub fn list_size(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Returns the entropy of a generated passphrase in bits, e.g. 37.2 for 3 words out of 5459.
+    pub fn entropy_bits(&self) -> f64 {
+        self.word_count as f64 * (self.words.len() as f64).log2()
     }
 
     /// Generates a passphrase with a given number of words.
@@ -59,7 +259,7 @@ impl PassphraseGenerator {
             return None;
         }
 
-        let mut rng = thread_rng();
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
         let mut passphrase = String::with_capacity(
             word_count * Self::AVG_WORD_SIZE + word_count - 1
         );
@@ -68,21 +268,21 @@ impl PassphraseGenerator {
             if i != 0 {
                 passphrase.push('-');
             }
-            let random_word = self.0.get(rng.gen_range(0..self.0.len()))?;
+            let random_word = self.words.get(rng.gen_range(0..self.words.len()))?;
             passphrase.push_str(random_word);
         }
 
         Some(Passphrase(Cow::Owned(passphrase)))
     }
 
-    /// Generates a passphrase with 3 words.
+    /// Generates a passphrase with 3 words, or more if a minimum entropy requires them.
     ///
     /// # Returns
     ///
     /// * `Some(Passphrase)` - The generated passphrase.
     /// * `None` - If there is an error generating the passphrase.
     pub fn generate(&self) -> Option<Passphrase<'static>> {
-        self.generate_with_count(3)
+        self.generate_with_count(self.word_count)
     }
 }
 
@@ -96,10 +296,102 @@ mod tests {
         assert_eq!(passphrase.to_string(), "example-passphrase");
     }
 
+    #[test]
+    fn test_read_from_skips_empty_lines() {
+        let passphrase = Passphrase::read_from("\n  \n correct-horse-battery \nother\n".as_bytes()).unwrap();
+        assert_eq!(passphrase.to_string(), "correct-horse-battery");
+    }
+
+    #[test]
+    fn test_read_from_empty() {
+        let result = Passphrase::read_from("\n\n".as_bytes());
+        assert!(matches!(result, Err(NudgeError::PassphraseMissing)));
+    }
+
+    #[test]
+    fn test_validate_chosen() {
+        assert!(Passphrase::from("correct-horse-battery").validate_chosen().is_ok());
+        assert!(Passphrase::from("short").validate_chosen().is_err());
+        assert!(Passphrase::from("has some spaces").validate_chosen().is_err());
+        assert!(Passphrase::from("x".repeat(129)).validate_chosen().is_err());
+        assert!(Passphrase::from("password").validate_chosen().is_err());
+        assert!(Passphrase::from("aaaaaaaaaaaaaaaa").validate_chosen().is_err());
+        assert!(Passphrase::from("123456789012345").validate_chosen().is_err());
+        assert!(Passphrase::from("Tr0ub4dor&3").validate_chosen().is_ok());
+    }
+
+    #[test]
+    fn test_estimated_entropy_bits() {
+        assert_eq!(Passphrase::from("").estimated_entropy_bits(), 0.0);
+        assert_eq!(Passphrase::from("abab").estimated_entropy_bits(), 2.0 * 26f64.log2());
+        assert_eq!(Passphrase::from("a1").estimated_entropy_bits(), 2.0 * 36f64.log2());
+        assert!(Passphrase::from("correct-horse-battery").estimated_entropy_bits() > 60.0);
+    }
+
+    #[test]
+    fn test_link() {
+        let link = PassphraseLink::parse("nudge://relay.example:4000/correct-horse-battery").unwrap();
+        assert_eq!(link.relay, "relay.example:4000");
+        assert_eq!(link.passphrase.to_string(), "correct-horse-battery");
+        assert_eq!(link.to_string(), "nudge://relay.example:4000/correct-horse-battery");
+
+        let link = PassphraseLink::parse(" nudge://[::1]/correct-horse-battery/ ").unwrap();
+        assert_eq!(link.relay, format!("[::1]:{}", DEFAULT_RELAY_PORT));
+        assert_eq!(PassphraseLink::parse("nudge://[::1]:4000/a-b-c").unwrap().relay, "[::1]:4000");
+
+        assert!(PassphraseLink::is_link("nudge://relay.example/a-b-c"));
+        assert!(!PassphraseLink::is_link("correct-horse-battery"));
+        assert!(PassphraseLink::parse("nudge://relay.example:4000/").is_err());
+        assert!(PassphraseLink::parse("nudge:///correct-horse-battery").is_err());
+        assert!(PassphraseLink::parse("nudge://relay.example:4000/bad%zz").is_err());
+        assert!(PassphraseLink::parse("https://relay.example/correct-horse-battery").is_err());
+    }
+
+    #[test]
+    fn test_link_escapes_chosen_passphrases() {
+        let link = PassphraseLink {
+            relay: "relay.example:4000".to_string(),
+            passphrase: Passphrase::from("s3cr3t/?#%ü"),
+        };
+        assert_eq!(link.to_string(), "nudge://relay.example:4000/s3cr3t%2F%3F%23%25%C3%BC");
+        assert_eq!(PassphraseLink::parse(&link.to_string()).unwrap(), link);
+    }
+
     #[test]
     fn test_passphrase_generator_new() {
         let generator = PassphraseGenerator::new().unwrap();
-        assert!(!generator.0.is_empty());
+        assert!(!generator.words.is_empty());
+        assert_eq!(generator.word_count(), 3);
+    }
+
+    #[test]
+    fn test_word_list_is_unique() {
+        // duplicates would make passphrases weaker than `entropy_bits` claims
+        let generator = PassphraseGenerator::new().unwrap();
+        let unique: std::collections::HashSet<&String> = generator.words.iter().collect();
+        assert_eq!(unique.len(), generator.list_size());
+    }
+
+    #[test]
+    fn test_rng_is_cryptographically_secure() {
+        fn assert_csprng<R: rand::CryptoRng>(_: &R) {}
+        let generator = PassphraseGenerator::new().unwrap();
+        assert_csprng(&*generator.rng.lock().unwrap());
+    }
+
+    #[test]
+    fn test_min_entropy() {
+        let generator = PassphraseGenerator::new().unwrap();
+        assert!(generator.entropy_bits() > 37.0);
+
+        let generator = PassphraseGenerator::new().unwrap().with_min_entropy(64);
+        assert_eq!(generator.word_count(), 6);
+        assert!(generator.entropy_bits() >= 64.0);
+        assert_eq!(generator.generate().unwrap().to_string().matches('-').count(), 5);
+
+        // exactly the entropy of 4 words doesn't need a fifth one
+        let four_words = (4.0 * (generator.list_size() as f64).log2()).floor() as u32;
+        assert_eq!(PassphraseGenerator::new().unwrap().with_min_entropy(four_words).word_count(), 4);
     }
 
     #[test]
@@ -0,0 +1,625 @@
+use std::env;
+use std::fs::{File, Metadata, TryLockError};
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::error::{NudgeError, Result};
+
+/// Set by the SIGHUP handler, see `watch_reload_signal`
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Counted up by the SIGUSR1 handler, see `watch_background_signal`
+static BACKGROUND_TOGGLES: AtomicU32 = AtomicU32::new(0);
+
+/// Home directory of Termux, used if `$HOME` isn't set on Android
+#[cfg(target_os = "android")]
+const TERMUX_HOME: &str = "/data/data/com.termux/files/home";
+
+/// Looks up the hostname from the environment, used if the system call doesn't return a usable name.
+///
+/// # Returns
+///
+/// `Option<String>` - The hostname, if `$HOSTNAME` (or `%COMPUTERNAME%` on Windows) is set.
+pub fn hostname_from_env() -> Option<String> {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .filter_map(|key| env::var(key).ok())
+        .find(|hostname| is_usable_hostname(hostname))
+}
+
+/// Checks whether a hostname identifies the machine.
+/// Android and some containers report "localhost", which says nothing about the sender.
+///
+/// # Arguments
+///
+/// * `hostname` - The hostname to check.
+pub fn is_usable_hostname(hostname: &str) -> bool {
+    let hostname = hostname.trim();
+    !hostname.is_empty() && hostname != "localhost"
+}
+
+/// Returns the home directory of the current user.
+///
+/// # Returns
+///
+/// `Option<PathBuf>` - The home directory, or `None` if it can't be determined.
+pub fn home_dir() -> Option<PathBuf> {
+    let home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from);
+
+    #[cfg(target_os = "android")]
+    let home = home.or_else(|| Some(PathBuf::from(TERMUX_HOME)));
+
+    home
+}
+
+/// Returns the directory nudge stores its configuration in.
+///
+/// Uses `$NUDGE_CONFIG_DIR`, `$XDG_CONFIG_HOME/nudge`, `%APPDATA%\nudge` or `~/.config/nudge`, in that order.
+///
+/// # Returns
+///
+/// `Option<PathBuf>` - The configuration directory, or `None` if no home directory was found.
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("NUDGE_CONFIG_DIR").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir).join("nudge"));
+    }
+    if let Some(dir) = env::var_os("APPDATA").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir).join("nudge"));
+    }
+    home_dir().map(|home| home.join(".config").join("nudge"))
+}
+
+/// Preallocates the file to the given length.
+///
+/// Some file systems (e.g. FAT formatted SD cards or the FUSE mounts of Android's shared storage)
+/// don't support resizing files. Since the file is written sequentially anyway,
+/// preallocation is skipped on these.
+///
+/// # Arguments
+///
+/// * `file` - The file to preallocate.
+/// * `len` - The expected length of the file.
+pub fn preallocate(file: &File, len: u64) -> Result<()> {
+    match file.set_len(len) {
+        Ok(_) => Ok(()),
+        Err(e) if matches!(e.kind(), ErrorKind::Unsupported | ErrorKind::InvalidInput | ErrorKind::PermissionDenied) => {
+            debug!(target: "io", "Cannot preallocate file ({}), continuing without", e);
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Locks the file exclusively until it's closed, so two receivers can't write to it at the same time.
+///
+/// The lock is advisory and only respected by other nudge processes.
+/// File systems without locking support (e.g. some network or FUSE mounts) are written without a lock.
+///
+/// # Arguments
+///
+/// * `file` - The file to lock.
+/// * `path` - Path of the file, for the error message.
+///
+/// # Errors
+///
+/// Returns `NudgeError::FileLocked` if another process holds the lock.
+pub fn lock_file(file: &File, path: &Path) -> Result<()> {
+    match file.try_lock() {
+        Ok(_) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(NudgeError::FileLocked(path.display().to_string())),
+        Err(TryLockError::Error(e)) if matches!(e.kind(), ErrorKind::Unsupported | ErrorKind::InvalidInput) => {
+            debug!(target: "io", "Cannot lock file ({}), continuing without", e);
+            Ok(())
+        }
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Parses permissions given in octal notation, e.g. "0600" or "644".
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidFileMode` if the input isn't octal or sets more than the permission bits.
+pub fn parse_file_mode(input: &str) -> Result<u32> {
+    let invalid = || NudgeError::InvalidFileMode(input.to_string());
+    let mode = u32::from_str_radix(input.trim().trim_start_matches("0o"), 8).map_err(|_| invalid())?;
+    if mode > 0o777 {
+        return Err(invalid());
+    }
+    Ok(mode)
+}
+
+/// Returns whether the path is a block device, e.g. `/dev/sdb`.
+#[cfg(unix)]
+pub fn is_block_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+pub fn is_block_device(_: &Path) -> bool {
+    false
+}
+
+/// Kind of a device file, whose size can't be read from its metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// e.g. `/dev/sdb`, its capacity is found by seeking to the end
+    Block,
+    /// e.g. `/dev/ttyUSB0`, a stream which can only be read once
+    Character,
+}
+
+/// Returns the kind of device the path is, or `None` for regular files (and anything that can't be inspected).
+#[cfg(unix)]
+pub fn device_kind(path: &Path) -> Option<DeviceKind> {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = std::fs::metadata(path).ok()?.file_type();
+    if file_type.is_block_device() {
+        Some(DeviceKind::Block)
+    } else if file_type.is_char_device() {
+        Some(DeviceKind::Character)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn device_kind(_: &Path) -> Option<DeviceKind> {
+    None
+}
+
+/// Opens stdin as a file, so data piped into nudge (`send -`) is read like a file.
+#[cfg(unix)]
+pub fn stdin_file() -> Result<File> {
+    use std::os::fd::AsFd;
+    Ok(File::from(std::io::stdin().as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(windows)]
+pub fn stdin_file() -> Result<File> {
+    use std::os::windows::io::AsHandle;
+    Ok(File::from(std::io::stdin().as_handle().try_clone_to_owned()?))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn stdin_file() -> Result<File> {
+    Err(std::io::Error::from(ErrorKind::Unsupported).into())
+}
+
+/// Returns whether a process with this ID is running on this machine.
+///
+/// # Returns
+///
+/// `Option<bool>` - Whether it's running, or `None` if the platform can't tell.
+#[cfg(unix)]
+pub fn process_alive(pid: u32) -> Option<bool> {
+    // 0 (and negative IDs) would address process groups
+    let pid = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0)?;
+    // SAFETY: signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return Some(true);
+    }
+    // processes of other users can't be signalled, but they exist
+    Some(std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH))
+}
+
+#[cfg(not(unix))]
+pub fn process_alive(_: u32) -> Option<bool> {
+    None
+}
+
+/// Returns the permission bits of a file (e.g. 0o644), so the receiver can preserve them.
+///
+/// # Returns
+///
+/// `Option<u32>` - The permissions, or `None` on platforms without unix permissions.
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_: &Metadata) -> Option<u32> {
+    None
+}
+
+/// Sets the permission bits of a file, restricted by the umask like the permissions of newly created files.
+/// Special bits (setuid, setgid, sticky) are never set.
+///
+/// # Arguments
+///
+/// * `file` - The file to change.
+/// * `mode` - The permissions, e.g. 0o600.
+#[cfg(unix)]
+pub fn set_file_mode(file: &File, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    // SAFETY: umask can't fail, the original mask is restored right away
+    let umask = unsafe {
+        let umask = libc::umask(0o077);
+        libc::umask(umask);
+        umask
+    } as u32;
+    file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777 & !umask))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(_: &File, _: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Returns the size of the socket's receive buffer in bytes,
+/// which bounds how much data can arrive while the receiver is busy (e.g. writing to a slow disk).
+///
+/// # Returns
+///
+/// `Option<usize>` - The size, or `None` if it can't be determined on this platform.
+#[cfg(unix)]
+pub fn receive_buffer_size(socket: &UdpSocket) -> Option<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut size: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: size and len point to memory of the given length
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &mut size as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0 && size > 0).then_some(size as usize)
+}
+
+#[cfg(not(unix))]
+pub fn receive_buffer_size(_: &UdpSocket) -> Option<usize> {
+    None
+}
+
+/// Asks for a larger receive buffer, so more data can arrive while the receiver is busy.
+/// The OS may grant less (e.g. `net.core.rmem_max` on Linux), a smaller buffer is never shrunk.
+///
+/// # Arguments
+///
+/// * `socket` - The socket.
+/// * `size` - The requested size in bytes.
+#[cfg(unix)]
+pub fn grow_receive_buffer(socket: &UdpSocket, size: usize) {
+    use std::os::fd::AsRawFd;
+
+    if receive_buffer_size(socket).is_some_and(|current| current >= size) {
+        return;
+    }
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    // SAFETY: size points to memory of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        debug!(target: "transport", "Cannot grow the receive buffer: {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn grow_receive_buffer(_: &UdpSocket, _: usize) {}
+
+/// Lets the kernel send the datagrams of the socket at the departure passed to `send_at` (SO_TXTIME, Linux 4.19+).
+/// The departure is only kept by queueing disciplines pacing by it (fq, etf), others send right away.
+///
+/// # Returns
+///
+/// `bool` - Whether the socket accepts departure times.
+#[cfg(target_os = "linux")]
+pub fn enable_txtime(socket: &UdpSocket) -> bool {
+    use std::os::fd::AsRawFd;
+
+    let config = libc::sock_txtime { clockid: libc::CLOCK_MONOTONIC, flags: 0 };
+    // SAFETY: config points to memory of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            &config as *const libc::sock_txtime as *const libc::c_void,
+            std::mem::size_of::<libc::sock_txtime>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        debug!(target: "transport", "Cannot set departure times (SO_TXTIME): {}", std::io::Error::last_os_error());
+    }
+    result == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_txtime(_: &UdpSocket) -> bool {
+    false
+}
+
+/// Sends a datagram on a connected socket, to leave at the departure if `enable_txtime` succeeded.
+///
+/// # Arguments
+///
+/// * `socket` - The connected socket.
+/// * `data` - The datagram.
+/// * `departure` - When the kernel should send it.
+#[cfg(target_os = "linux")]
+pub fn send_at(socket: &UdpSocket, data: &[u8], departure: std::time::Instant) -> std::io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    // Instant can't be converted, so the departure is added to the current time of the clock it's based on
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: now points to a timespec
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let txtime = (now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+        + departure.saturating_duration_since(std::time::Instant::now()).as_nanos() as u64;
+
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    // u64s keep the buffer aligned for the cmsg header
+    let mut control = [0u64; 8];
+    // SAFETY: the message points to the iovec and the control buffer, which outlive the call,
+    // and the control buffer fits a single cmsg with a u64 (CMSG_SPACE(8) is at most 32 bytes)
+    let sent = unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<u64>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&message);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_TXTIME;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u64>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u64, txtime);
+        libc::sendmsg(socket.as_raw_fd(), &message, 0)
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_at(socket: &UdpSocket, data: &[u8], _: std::time::Instant) -> std::io::Result<usize> {
+    socket.send(data)
+}
+
+/// Makes SIGHUP request a reload of the configuration instead of terminating the process.
+/// Requests are picked up with `take_reload_request`.
+#[cfg(unix)]
+pub fn watch_reload_signal() {
+    extern "C" fn request_reload(_: libc::c_int) {
+        RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+    }
+    let handler = request_reload as extern "C" fn(libc::c_int);
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+/// There's no SIGHUP on this platform, so the configuration can't be reloaded.
+#[cfg(not(unix))]
+pub fn watch_reload_signal() {}
+
+/// Returns whether a reload was requested since the last call.
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Makes SIGUSR1 toggle the background mode of `send --background` instead of terminating the process.
+/// Requests are counted, see `background_toggles`.
+#[cfg(unix)]
+pub fn watch_background_signal() {
+    extern "C" fn request_toggle(_: libc::c_int) {
+        BACKGROUND_TOGGLES.fetch_add(1, Ordering::SeqCst);
+    }
+    let handler = request_toggle as extern "C" fn(libc::c_int);
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+    }
+}
+
+/// There's no SIGUSR1 on this platform, so the background mode can't be toggled.
+#[cfg(not(unix))]
+pub fn watch_background_signal() {}
+
+/// Returns how often toggling the background mode was requested. Every session sending keeps track of the
+/// requests it followed, so all of them are toggled.
+pub fn background_toggles() -> u32 {
+    BACKGROUND_TOGGLES.load(Ordering::SeqCst)
+}
+
+/// Lowers the I/O priority of the calling thread to the idle class, so reading the file only uses the disk while
+/// nothing else does, or restores the default priority.
+///
+/// # Arguments
+///
+/// * `background` - Whether to use the idle class.
+#[cfg(target_os = "linux")]
+pub fn set_io_priority(background: bool) {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    // the default level of the best-effort class
+    const IOPRIO_BE_DEFAULT_LEVEL: libc::c_int = 4;
+
+    let priority = match background {
+        true => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        false => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | IOPRIO_BE_DEFAULT_LEVEL,
+    };
+    // SAFETY: plain syscall without pointers, `0` is the calling thread
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) };
+    match result {
+        0 => debug!("Reading with {} I/O priority", if background { "idle" } else { "default" }),
+        _ => debug!("Cannot set the I/O priority: {}", std::io::Error::last_os_error()),
+    }
+}
+
+/// The I/O priority can't be changed on this platform, only the bandwidth is limited.
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_priority(_background: bool) {
+    debug!("The I/O priority can't be changed on this platform");
+}
+
+/// Shows a desktop notification in the background, using `notify-send` on Linux and the BSDs
+/// and `osascript` on macOS. Failures (e.g. no notification daemon) are only logged.
+///
+/// # Arguments
+///
+/// * `title` - Title of the notification.
+/// * `body` - Text of the notification.
+pub fn notify_desktop(title: &str, body: &str) {
+    let Some(mut command) = notification_command(title, body) else {
+        debug!("Desktop notifications aren't supported on this platform");
+        return;
+    };
+    std::thread::spawn(move || {
+        let status = command
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => debug!("Desktop notification failed: {}", status),
+            Err(e) => debug!("Cannot show desktop notification: {}", e),
+        }
+    });
+}
+
+#[cfg(target_os = "macos")]
+fn notification_command(title: &str, body: &str) -> Option<std::process::Command> {
+    let mut command = std::process::Command::new("osascript");
+    command.arg("-e").arg(format!(
+        "display notification {} with title {}", applescript_string(body), applescript_string(title),
+    ));
+    Some(command)
+}
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios", target_os = "android"))))]
+fn notification_command(title: &str, body: &str) -> Option<std::process::Command> {
+    let mut command = std::process::Command::new("notify-send");
+    command.arg("--app-name=nudge").arg(title).arg(body);
+    Some(command)
+}
+
+#[cfg(any(not(unix), target_os = "ios", target_os = "android"))]
+fn notification_command(_: &str, _: &str) -> Option<std::process::Command> {
+    None
+}
+
+/// Quotes text for an AppleScript string literal, e.g. `say "hi"` as `"say \"hi\""`.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn applescript_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_usable_hostname() {
+        assert!(is_usable_hostname("my-laptop"));
+        assert!(!is_usable_hostname("localhost"));
+        assert!(!is_usable_hostname(" "));
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let file = tempfile::tempfile().unwrap();
+        preallocate(&file, 1024).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn test_parse_file_mode() {
+        assert_eq!(parse_file_mode("0600").unwrap(), 0o600);
+        assert_eq!(parse_file_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_file_mode("0o750").unwrap(), 0o750);
+        assert!(parse_file_mode("4755").is_err());
+        assert!(parse_file_mode("0800").is_err());
+        assert!(parse_file_mode("rw-------").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_set_file_mode() {
+        let file = tempfile::tempfile().unwrap();
+        set_file_mode(&file, 0o640).unwrap();
+        let mode = file_mode(&file.metadata().unwrap()).unwrap();
+        // the umask may remove bits, but never adds any
+        assert_eq!(mode & !0o640, 0);
+        assert_eq!(mode & 0o600, 0o600);
+    }
+
+    #[test]
+    fn test_is_block_device() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(!is_block_device(file.path()));
+        assert!(!is_block_device(Path::new("/nonexistent/nudge")));
+    }
+
+    #[test]
+    fn test_device_kind() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(device_kind(file.path()), None);
+        assert_eq!(device_kind(Path::new("/nonexistent/nudge")), None);
+        #[cfg(unix)]
+        assert_eq!(device_kind(Path::new("/dev/null")), Some(DeviceKind::Character));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_process_alive() {
+        assert_eq!(process_alive(std::process::id()), Some(true));
+        assert_eq!(process_alive(0), None);
+        assert_eq!(process_alive(i32::MAX as u32), Some(false));
+    }
+
+    #[test]
+    fn test_grow_receive_buffer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let before = receive_buffer_size(&socket);
+        grow_receive_buffer(&socket, 1 << 20);
+        assert!(receive_buffer_size(&socket) >= before);
+    }
+
+    #[test]
+    fn test_applescript_string() {
+        assert_eq!(applescript_string("report.pdf"), "\"report.pdf\"");
+        assert_eq!(applescript_string("say \"hi\" \\o/"), "\"say \\\"hi\\\" \\\\o/\"");
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let first = File::options().write(true).open(&path).unwrap();
+        let second = File::options().write(true).open(&path).unwrap();
+
+        lock_file(&first, &path).unwrap();
+        assert!(matches!(lock_file(&second, &path), Err(NudgeError::FileLocked(_))));
+
+        drop(first);
+        lock_file(&second, &path).unwrap();
+    }
+}
@@ -0,0 +1,433 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::error::{NudgeError, Result};
+
+/// Port NAT-PMP gateways listen on
+const NAT_PMP_PORT: u16 = 5351;
+
+/// Time to wait for the first NAT-PMP response, doubled for every further attempt
+const NAT_PMP_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Number of NAT-PMP requests sent before giving up
+const NAT_PMP_ATTEMPTS: u32 = 2;
+
+/// Multicast address UPnP devices are discovered on
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+
+/// Time to wait for UPnP gateways to answer the discovery
+const SSDP_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Timeout of the HTTP requests to the UPnP gateway
+const UPNP_HTTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Services of UPnP gateways which can map ports
+const UPNP_SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Lifetime requested for mappings. Connections established in that time keep working after it
+const MAPPING_LIFETIME_SECS: u32 = 3600;
+
+/// Gateway a port mapping was created at
+#[derive(Debug)]
+enum Gateway {
+    /// Gateway speaking NAT-PMP (RFC 6886), at this address
+    NatPmp(SocketAddr),
+
+    /// UPnP Internet Gateway Device, with the control URL and type of its connection service
+    Upnp { control_url: String, service_type: String },
+}
+
+/// A UDP port mapping on the router, so peers can reach our socket without hole punching.
+/// The mapping is removed when this is dropped.
+#[derive(Debug)]
+pub struct PortMapping {
+    gateway: Gateway,
+
+    /// Port of our socket
+    internal_port: u16,
+
+    /// Port on the router forwarded to our socket
+    pub external_port: u16,
+}
+
+impl PortMapping {
+    /// Asks the router to forward a port to the socket, via NAT-PMP or else UPnP.
+    /// The same port as the socket's is requested, so the relay likely sees that one, too.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The socket peers should reach.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::PortMappingFailed` if neither protocol is available.
+    pub fn create(socket: &UdpSocket) -> Result<PortMapping> {
+        let internal_port = socket.local_addr()?.port();
+        let nat_pmp_error = match map_with_nat_pmp(internal_port) {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => failure_reason(e),
+        };
+        debug!(target: "transport", "NAT-PMP port mapping failed: {}", nat_pmp_error);
+        map_with_upnp(internal_port).map_err(|e| {
+            let upnp_error = failure_reason(e);
+            debug!(target: "transport", "UPnP port mapping failed: {}", upnp_error);
+            NudgeError::PortMappingFailed(format!("NAT-PMP: {}, UPnP: {}", nat_pmp_error, upnp_error))
+        })
+    }
+
+    /// Returns the protocol the mapping was created with.
+    pub fn protocol(&self) -> &'static str {
+        match self.gateway {
+            Gateway::NatPmp(_) => "NAT-PMP",
+            Gateway::Upnp { .. } => "UPnP",
+        }
+    }
+
+    fn remove(&self) -> Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                nat_pmp_request(gateway, &nat_pmp_map_request(self.internal_port, 0, 0))?;
+            }
+            Gateway::Upnp { control_url, service_type } => {
+                let arguments = format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>UDP</NewProtocol>",
+                    self.external_port
+                );
+                soap_request(control_url, service_type, "DeletePortMapping", &arguments)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for PortMapping {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} via {}", self.external_port, self.internal_port, self.protocol())
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        match self.remove() {
+            Ok(()) => debug!(target: "transport", "Removed port mapping {}", self),
+            Err(e) => debug!(target: "transport", "Cannot remove port mapping {}: {}", self, e),
+        }
+    }
+}
+
+/// Returns the address of a peer, preferring the port it mapped on its router (if any).
+///
+/// # Arguments
+///
+/// * `observed_addr` - The address the relay saw the peer at.
+/// * `mapped_port` - The port the peer mapped on its router (optional).
+pub fn mapped_addr(observed_addr: SocketAddr, mapped_port: Option<u16>) -> SocketAddr {
+    match mapped_port {
+        Some(port) => SocketAddr::new(observed_addr.ip(), port),
+        None => observed_addr,
+    }
+}
+
+/// Returns why a protocol failed, without the generic prefix of the error.
+fn failure_reason(error: NudgeError) -> String {
+    match error {
+        NudgeError::PortMappingFailed(reason) => reason,
+        NudgeError::Io(e) => e.to_string(),
+        e => e.to_string(),
+    }
+}
+
+fn map_with_nat_pmp(internal_port: u16) -> Result<PortMapping> {
+    let gateway = default_gateway()
+        .ok_or_else(|| NudgeError::PortMappingFailed("no default gateway".to_string()))?;
+    let gateway = SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT);
+    let response = nat_pmp_request(&gateway, &nat_pmp_map_request(internal_port, internal_port, MAPPING_LIFETIME_SECS))?;
+    let external_port = parse_nat_pmp_response(&response)?;
+    Ok(PortMapping { gateway: Gateway::NatPmp(gateway), internal_port, external_port })
+}
+
+/// Sends a NAT-PMP request to the gateway and returns its response.
+fn nat_pmp_request(gateway: &SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(gateway)?;
+    let mut timeout = NAT_PMP_INITIAL_TIMEOUT;
+    let mut buffer = [0u8; 16];
+    for _ in 0..NAT_PMP_ATTEMPTS {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buffer) {
+            Ok(bytes_read) => return Ok(buffer[..bytes_read].to_vec()),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                timeout *= 2;
+            }
+            // e.g. ICMP port unreachable, the gateway doesn't speak NAT-PMP
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(NudgeError::PortMappingFailed(format!("no response from {}", gateway)))
+}
+
+/// Builds a NAT-PMP request to map a UDP port. A lifetime of 0 removes the mapping.
+fn nat_pmp_map_request(internal_port: u16, external_port: u16, lifetime_secs: u32) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = 1; // version 0, opcode 1: map UDP
+    request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime_secs.to_be_bytes());
+    request
+}
+
+/// Parses the response to a NAT-PMP map request and returns the mapped external port.
+fn parse_nat_pmp_response(response: &[u8]) -> Result<u16> {
+    if response.len() < 16 || response[0] != 0 || response[1] != 129 {
+        return Err(NudgeError::PortMappingFailed("invalid NAT-PMP response".to_string()));
+    }
+    let result_code = u16::from_be_bytes([response[2], response[3]]);
+    if result_code != 0 {
+        return Err(NudgeError::PortMappingFailed(format!("NAT-PMP result code {}", result_code)));
+    }
+    Ok(u16::from_be_bytes([response[10], response[11]]))
+}
+
+/// Returns the gateway of the default route.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_default_gateway(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// Finds the default gateway in the format of `/proc/net/route`,
+/// whose addresses are hex numbers in the byte order of the host.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_default_gateway(route_table: &str) -> Option<Ipv4Addr> {
+    route_table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok().filter(|gateway| *gateway != 0)?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+fn map_with_upnp(internal_port: u16) -> Result<PortMapping> {
+    let location = discover_upnp_gateway()?;
+    let (_, description) = http_request(&location, "GET", &[], "")?;
+    let (service_type, control_url) = find_control_url(&description, &location)
+        .ok_or_else(|| NudgeError::PortMappingFailed("gateway has no connection service".to_string()))?;
+
+    // the gateway forwards to the address we reach it from
+    let internal_client = local_addr_towards(&control_url)?;
+    let arguments = format!(
+        "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort><NewProtocol>UDP</NewProtocol>\
+         <NewInternalPort>{port}</NewInternalPort><NewInternalClient>{client}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled><NewPortMappingDescription>nudge</NewPortMappingDescription>\
+         <NewLeaseDuration>{lifetime}</NewLeaseDuration>",
+        port = internal_port,
+        client = internal_client,
+        lifetime = MAPPING_LIFETIME_SECS,
+    );
+    soap_request(&control_url, service_type, "AddPortMapping", &arguments)?;
+    Ok(PortMapping {
+        gateway: Gateway::Upnp { control_url, service_type: service_type.to_string() },
+        internal_port,
+        external_port: internal_port,
+    })
+}
+
+/// Searches for an Internet Gateway Device and returns the URL of its description.
+fn discover_upnp_gateway() -> Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(SSDP_TIMEOUT))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_ADDRESS
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS)?;
+
+    let mut buffer = [0u8; 2048];
+    loop {
+        let bytes_read = match socket.recv(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                return Err(NudgeError::PortMappingFailed("no UPnP gateway found".to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let response = String::from_utf8_lossy(&buffer[..bytes_read]);
+        if let Some(location) = header_value(&response, "location") {
+            return Ok(location.to_string());
+        }
+    }
+}
+
+/// Calls an action of the gateway's connection service.
+fn soap_request(control_url: &str, service_type: &str, action: &str, arguments: &str) -> Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service_type}\">{arguments}</u:{action}></s:Body></s:Envelope>"
+    );
+    let soap_action = format!("\"{}#{}\"", service_type, action);
+    let headers = [
+        ("Content-Type", "text/xml; charset=\"utf-8\""),
+        ("SOAPAction", soap_action.as_str()),
+    ];
+    let (status, response) = http_request(control_url, "POST", &headers, &body)?;
+    if status != 200 {
+        return Err(NudgeError::PortMappingFailed(format!("{} failed with HTTP status {}", action, status)));
+    }
+    Ok(response)
+}
+
+/// Sends a minimal HTTP/1.0 request and returns the status code and the body of the response.
+fn http_request(url: &str, method: &str, headers: &[(&str, &str)], body: &str) -> Result<(u16, String)> {
+    let (host, path) = split_http_url(url)
+        .ok_or_else(|| NudgeError::PortMappingFailed(format!("unsupported URL {}", url)))?;
+    let mut stream = connect_http(host)?;
+
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n", method, path, host, body.len());
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok())
+        .ok_or_else(|| NudgeError::PortMappingFailed("invalid HTTP response".to_string()))?;
+    Ok((status, body.to_string()))
+}
+
+fn connect_http(host: &str) -> Result<TcpStream> {
+    let addr = host.to_socket_addrs()?.next()
+        .ok_or_else(|| NudgeError::PortMappingFailed(format!("cannot resolve {}", host)))?;
+    let stream = TcpStream::connect_timeout(&addr, UPNP_HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(UPNP_HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPNP_HTTP_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Returns our address on the network of the host the URL points to.
+fn local_addr_towards(url: &str) -> Result<IpAddr> {
+    let (host, _) = split_http_url(url)
+        .ok_or_else(|| NudgeError::PortMappingFailed(format!("unsupported URL {}", url)))?;
+    Ok(connect_http(host)?.local_addr()?.ip())
+}
+
+/// Splits a `http://host:port/path` URL into host (with port) and path.
+fn split_http_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    Some(match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    })
+}
+
+/// Returns the value of a header of an HTTP-like message, ignoring the case of its name.
+fn header_value<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Finds a service which can map ports in the description of a gateway.
+///
+/// # Returns
+///
+/// `Option<(&str, String)>` - The type of the service and its absolute control URL.
+fn find_control_url(description: &str, location: &str) -> Option<(&'static str, String)> {
+    UPNP_SERVICE_TYPES.iter().find_map(|service_type| {
+        let service = &description[description.find(&format!("<serviceType>{}</serviceType>", service_type))?..];
+        let start = service.find("<controlURL>")? + "<controlURL>".len();
+        let control_url = service[start..][..service[start..].find("</controlURL>")?].trim();
+        if control_url.starts_with("http://") {
+            return Some((*service_type, control_url.to_string()));
+        }
+        let (host, _) = split_http_url(location)?;
+        let separator = if control_url.starts_with('/') { "" } else { "/" };
+        Some((*service_type, format!("http://{}{}{}", host, separator, control_url)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat_pmp_messages() {
+        assert_eq!(
+            nat_pmp_map_request(5000, 5000, 3600),
+            [0, 1, 0, 0, 0x13, 0x88, 0x13, 0x88, 0, 0, 0x0e, 0x10]
+        );
+
+        let mut response = [0u8; 16];
+        response[1] = 129;
+        response[8..10].copy_from_slice(&5000u16.to_be_bytes());
+        response[10..12].copy_from_slice(&6000u16.to_be_bytes());
+        assert_eq!(parse_nat_pmp_response(&response).unwrap(), 6000);
+
+        response[3] = 2; // not authorized
+        assert!(parse_nat_pmp_response(&response).is_err());
+        assert!(parse_nat_pmp_response(&response[..8]).is_err());
+    }
+
+    #[test]
+    fn test_parse_default_gateway() {
+        let gateway = u32::from_ne_bytes([192, 168, 1, 1]);
+        let route_table = format!(
+            "Iface\tDestination\tGateway\tFlags\n\
+             eth0\t0001A8C0\t00000000\t0001\n\
+             eth0\t00000000\t{:08X}\t0003\n",
+            gateway
+        );
+        assert_eq!(parse_default_gateway(&route_table), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn test_header_value() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(header_value(response, "location"), Some("http://192.168.1.1:5000/rootDesc.xml"));
+        assert_eq!(header_value(response, "server"), None);
+    }
+
+    #[test]
+    fn test_find_control_url() {
+        let location = "http://192.168.1.1:5000/rootDesc.xml";
+        let description = "<service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>";
+        assert_eq!(
+            find_control_url(description, location),
+            Some(("urn:schemas-upnp-org:service:WANIPConnection:1", "http://192.168.1.1:5000/ctl/IPConn".to_string()))
+        );
+        assert_eq!(find_control_url("<root></root>", location), None);
+        assert_eq!(split_http_url("http://10.0.0.1:80"), Some(("10.0.0.1:80", "/")));
+        assert_eq!(split_http_url("https://10.0.0.1/"), None);
+    }
+
+    #[test]
+    fn test_mapped_addr() {
+        let observed: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        assert_eq!(mapped_addr(observed, None), observed);
+        assert_eq!(mapped_addr(observed, Some(5000)), "203.0.113.7:5000".parse().unwrap());
+    }
+}
@@ -0,0 +1,70 @@
+/// Largest file the sender offers a preview of
+pub const PREVIEW_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Largest preview the sender sends, so it fits into a single packet
+pub const MAX_PREVIEW_BYTES: u32 = 8192;
+
+/// Number of bytes previewed if `--preview` is passed without a size
+pub const DEFAULT_PREVIEW_BYTES: &str = "1024";
+
+/// Checks whether the beginning of a file looks like text, i.e. it's UTF-8 without NUL bytes.
+/// A character cut off at the end is ignored.
+///
+/// # Arguments
+///
+/// * `bytes` - The beginning of the file.
+pub fn looks_like_text(bytes: &[u8]) -> bool {
+    if bytes.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        // `error_len` is `None` if the input just ended in the middle of a character
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Formats a preview for the terminal: every line is indented,
+/// control characters (which could mess with the terminal) are replaced.
+///
+/// # Arguments
+///
+/// * `bytes` - The beginning of the file, as sent by the sender.
+pub fn format_preview(bytes: &[u8]) -> String {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(e) if e.error_len().is_none() => String::from_utf8_lossy(&bytes[..e.valid_up_to()]).to_string(),
+        Err(_) => String::from_utf8_lossy(bytes).to_string(),
+    };
+    text.lines()
+        .map(|line| {
+            let line: String = line.chars()
+                .map(|c| if c.is_control() && c != '\t' { char::REPLACEMENT_CHARACTER } else { c })
+                .collect();
+            format!("  │ {}", line)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_text() {
+        assert!(looks_like_text(b"hello\nworld\n"));
+        assert!(looks_like_text("grüße".as_bytes()));
+        // the preview may end in the middle of a character
+        assert!(looks_like_text(&"grüße".as_bytes()[..3]));
+        assert!(!looks_like_text(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(!looks_like_text(&[0xff, 0xfe, 0x41]));
+    }
+
+    #[test]
+    fn test_format_preview() {
+        assert_eq!(format_preview(b"first\r\nsecond\n"), "  │ first\n  │ second");
+        assert_eq!(format_preview(b"evil\x1b[2Jtext"), "  │ evil\u{fffd}[2Jtext");
+        assert_eq!(format_preview(&"grüße".as_bytes()[..3]), "  │ gr");
+    }
+}
@@ -0,0 +1,194 @@
+//! Timing of the chunk pipeline, compiled in with the `profiling` feature:
+//!
+//! ```sh
+//! cargo build --release --features profiling
+//! NUDGE_PROFILE=send.folded nudge send file.bin
+//! ```
+//!
+//! At the end of a transfer, the time spent per stage is printed to stderr, and written as folded stacks
+//! (e.g. `nudge;send;ack_wait 123456`, in microseconds) to the file in `NUDGE_PROFILE`, if set.
+//! These can be turned into a flame graph with `inferno-flamegraph` or `flamegraph.pl`.
+//! Without the feature, spans compile to nothing.
+
+#[cfg(feature = "profiling")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "profiling")]
+use std::time::Instant;
+
+/// Environment variable with the path the folded stacks are written to
+#[cfg(feature = "profiling")]
+const PROFILE_ENV: &str = "NUDGE_PROFILE";
+
+/// Stage a chunk passes through while being sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading the chunk from the file (sender)
+    Read,
+
+    /// Framing the chunk into a packet (sender)
+    Serialize,
+
+    /// Handing the packet to the socket (sender)
+    Send,
+
+    /// Sleeping for `--delay` after sending a packet (sender)
+    Pace,
+
+    /// Processing acknowledgments and waiting for them if the window is full (sender)
+    AckWait,
+
+    /// Waiting for and reassembling the next chunk (receiver)
+    Receive,
+
+    /// Writing the chunk to the output file (receiver)
+    Write,
+}
+
+#[cfg(feature = "profiling")]
+impl Stage {
+    const ALL: [Stage; 7] = [
+        Stage::Read,
+        Stage::Serialize,
+        Stage::Send,
+        Stage::Pace,
+        Stage::AckWait,
+        Stage::Receive,
+        Stage::Write,
+    ];
+
+    /// Frame of the stage in the folded stacks
+    fn frame(self) -> &'static str {
+        match self {
+            Stage::Read => "send;read",
+            Stage::Serialize => "send;serialize",
+            Stage::Send => "send;send",
+            Stage::Pace => "send;pace",
+            Stage::AckWait => "send;ack_wait",
+            Stage::Receive => "get;receive",
+            Stage::Write => "get;write",
+        }
+    }
+}
+
+/// Accumulated timings of a stage
+#[cfg(feature = "profiling")]
+struct StageTimings {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+#[cfg(feature = "profiling")]
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TIMINGS: StageTimings = StageTimings {
+    count: AtomicU64::new(0),
+    total_nanos: AtomicU64::new(0),
+    max_nanos: AtomicU64::new(0),
+};
+
+#[cfg(feature = "profiling")]
+static TIMINGS: [StageTimings; Stage::ALL.len()] = [NO_TIMINGS; Stage::ALL.len()];
+
+/// Times a stage until it's dropped.
+#[must_use]
+pub struct Span {
+    #[cfg(feature = "profiling")]
+    stage: Stage,
+
+    #[cfg(feature = "profiling")]
+    start: Instant,
+}
+
+/// Starts timing a stage, e.g. `let _span = span(Stage::Read);`.
+#[cfg(feature = "profiling")]
+pub fn span(stage: Stage) -> Span {
+    Span { stage, start: Instant::now() }
+}
+
+/// Starts timing a stage, there is nothing to time without the `profiling` feature.
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn span(_: Stage) -> Span {
+    Span {}
+}
+
+#[cfg(feature = "profiling")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        let nanos = self.start.elapsed().as_nanos() as u64;
+        let timings = &TIMINGS[self.stage as usize];
+        timings.count.fetch_add(1, Ordering::Relaxed);
+        timings.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        timings.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Prints the timings of the stages to stderr and writes the folded stacks to the file in `NUDGE_PROFILE`.
+/// The timings are reset afterwards.
+#[cfg(feature = "profiling")]
+pub fn dump() {
+    let timings: Vec<(Stage, u64, u64, u64)> = Stage::ALL.iter()
+        .map(|&stage| {
+            let timings = &TIMINGS[stage as usize];
+            (
+                stage,
+                timings.count.swap(0, Ordering::Relaxed),
+                timings.total_nanos.swap(0, Ordering::Relaxed),
+                timings.max_nanos.swap(0, Ordering::Relaxed),
+            )
+        })
+        .filter(|(_, count, _, _)| *count > 0)
+        .collect();
+    if timings.is_empty() {
+        return;
+    }
+
+    eprintln!("{:<10} {:>10} {:>12} {:>10} {:>10}", "stage", "chunks", "total (ms)", "mean (µs)", "max (µs)");
+    for (stage, count, total_nanos, max_nanos) in &timings {
+        eprintln!(
+            "{:<10} {:>10} {:>12.1} {:>10.1} {:>10.1}",
+            format!("{:?}", stage),
+            count,
+            *total_nanos as f64 / 1e6,
+            *total_nanos as f64 / *count as f64 / 1e3,
+            *max_nanos as f64 / 1e3
+        );
+    }
+
+    if let Some(path) = std::env::var_os(PROFILE_ENV) {
+        let folded = folded_stacks(timings.iter().map(|(stage, _, total_nanos, _)| (*stage, *total_nanos)));
+        if let Err(e) = std::fs::write(&path, folded) {
+            warn!("Cannot write the profile to {}: {}", path.to_string_lossy(), e);
+        }
+    }
+}
+
+/// Dumps the timings of the stages, there are none without the `profiling` feature.
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn dump() {}
+
+/// Formats the total time of the stages as folded stacks in microseconds, one line per stage.
+#[cfg(feature = "profiling")]
+fn folded_stacks(totals: impl Iterator<Item = (Stage, u64)>) -> String {
+    totals
+        .map(|(stage, total_nanos)| format!("nudge;{} {}\n", stage.frame(), total_nanos / 1000))
+        .collect()
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folded_stacks() {
+        let folded = folded_stacks([(Stage::Read, 1_500_000), (Stage::AckWait, 999)].into_iter());
+        assert_eq!(folded, "nudge;send;read 1500\nnudge;send;ack_wait 0\n");
+    }
+
+    #[test]
+    fn test_span_records_timings() {
+        drop(span(Stage::Write));
+        assert!(TIMINGS[Stage::Write as usize].count.load(Ordering::Relaxed) >= 1);
+    }
+}
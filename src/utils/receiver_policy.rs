@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::AnonymousString;
+use crate::utils::sanitize::sanitize;
+use crate::utils::units::{format_bytes, parse_size};
+
+/// Policy file for unattended receivers (JSON), e.g.
+///
+/// ```json
+/// {
+///   "rules": [
+///     {"sender_hosts": ["alice-laptop"], "out_dir": "/srv/drop/alice"},
+///     {"extensions": ["pdf", "jpg", "tar.gz"], "max_size": "50MB", "out_dir": "/srv/drop/inbox"}
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReceiverPolicyFile {
+    /// Rules in the order they're evaluated
+    rules: Vec<PolicyRuleFile>,
+}
+
+/// A rule as written in the policy file, every condition is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyRuleFile {
+    /// Allowed file extensions (any if empty)
+    extensions: Vec<String>,
+
+    /// Maximum size of the file, e.g. "50MB" or "1GiB"
+    max_size: Option<String>,
+
+    /// Display names or hostnames the sender has to present (any if empty)
+    sender_hosts: Vec<String>,
+
+    /// Directory to store matching files in (the working directory if not set)
+    out_dir: Option<PathBuf>,
+}
+
+/// A rule of the receiver policy. A file matches a rule if it meets all of its conditions
+#[derive(Debug, PartialEq)]
+pub struct PolicyRule {
+    /// Allowed file extensions, lowercase and without the leading dot (any if empty)
+    extensions: Vec<String>,
+
+    /// Maximum size of the file in bytes (optional)
+    max_size: Option<u64>,
+
+    /// Display names or hostnames the sender has to present (any if empty)
+    sender_hosts: Vec<String>,
+
+    /// Directory to store matching files in (optional)
+    pub out_dir: Option<PathBuf>,
+}
+
+impl PolicyRule {
+    /// Checks whether the offered file meets all conditions of the rule.
+    fn matches(&self, file_name: &str, file_size: Option<u64>, sender_host: &AnonymousString) -> bool {
+        let file_name = file_name.to_lowercase();
+        let extension_allowed = self.extensions.is_empty() || self.extensions.iter()
+            .any(|extension| file_name.strip_suffix(extension.as_str()).is_some_and(|stem| stem.len() > 1 && stem.ends_with('.')));
+        // a stream of unknown size may exceed any limit
+        let size_allowed = self.max_size.is_none_or(|max_size| file_size.is_some_and(|file_size| file_size <= max_size));
+        let sender_allowed = self.sender_hosts.is_empty() || sender_host.0.as_ref()
+            .is_some_and(|host| self.sender_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)));
+        extension_allowed && size_allowed && sender_allowed
+    }
+
+    /// Returns where a file accepted by this rule is stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `relative_path` - Name of the file, or the path expanded from the output template.
+    pub fn out_path(&self, relative_path: impl AsRef<Path>) -> PathBuf {
+        match &self.out_dir {
+            Some(out_dir) => out_dir.join(relative_path),
+            None => relative_path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+/// Decides which offered files an unattended receiver accepts, and where it stores them.
+/// The first matching rule applies; files matching no rule are rejected.
+#[derive(Debug)]
+pub struct ReceiverPolicy {
+    rules: Vec<PolicyRule>,
+}
+
+impl ReceiverPolicy {
+    /// Reads the policy file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the JSON policy file.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidReceiverPolicy` if the file can't be read or contains invalid values.
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| NudgeError::InvalidReceiverPolicy(format!("{}: {}", path.display(), e));
+        let contents = fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        Self::parse(&contents).map_err(|e| match e {
+            NudgeError::InvalidReceiverPolicy(reason) => invalid(&reason),
+            e => invalid(&e),
+        })
+    }
+
+    /// Parses the contents of a policy file, see `load`.
+    fn parse(contents: &str) -> Result<Self> {
+        let file: ReceiverPolicyFile = serde_json::from_str(contents)
+            .map_err(|e| NudgeError::InvalidReceiverPolicy(e.to_string()))?;
+        let rules = file.rules.into_iter()
+            .map(|rule| Ok(PolicyRule {
+                extensions: rule.extensions.iter()
+                    .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                    .collect(),
+                max_size: rule.max_size.as_deref()
+                    .map(|size| parse_size(size).map_err(|e| NudgeError::InvalidReceiverPolicy(e.to_string())))
+                    .transpose()?,
+                sender_hosts: rule.sender_hosts,
+                out_dir: rule.out_dir,
+            }))
+            .collect::<Result<_>>()?;
+        Ok(ReceiverPolicy { rules })
+    }
+
+    /// Finds the rule accepting the offered file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_name` - Name of the file, without any directories.
+    /// * `file_size` - Size of the file in bytes, `None` for a stream of unknown size (`send -`).
+    /// * `sender_host` - Display name or hostname of the sender.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::PolicyRejected` if no rule accepts the file,
+    /// or if its name can't be stored safely (e.g. "..").
+    pub fn evaluate(&self, file_name: &str, file_size: Option<u64>, sender_host: &AnonymousString) -> Result<&PolicyRule> {
+        let rejected = || NudgeError::PolicyRejected(format!(
+            "{} ({}) by {}", sanitize(file_name), file_size.map_or("unknown size".to_string(), format_bytes), sender_host,
+        ));
+        if matches!(file_name, "" | "." | "..") || file_name.contains(['/', '\\']) {
+            return Err(rejected());
+        }
+        self.rules.iter()
+            .find(|rule| rule.matches(file_name, file_size, sender_host))
+            .ok_or_else(rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(name: &str) -> AnonymousString {
+        AnonymousString(Some(name.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let policy = ReceiverPolicy::parse(
+            r#"{"rules": [{"extensions": [".PDF"], "max_size": "1KB", "out_dir": "/srv/drop"}, {}]}"#,
+        ).unwrap();
+        assert_eq!(policy.rules[0], PolicyRule {
+            extensions: vec!["pdf".to_string()],
+            max_size: Some(1000),
+            sender_hosts: Vec::new(),
+            out_dir: Some(PathBuf::from("/srv/drop")),
+        });
+        assert_eq!(policy.rules[1].out_dir, None);
+
+        assert!(ReceiverPolicy::parse(r#"{"rules": [{"max_size": "big"}]}"#).is_err());
+        assert!(ReceiverPolicy::parse(r#"{"rules": [{"extension": ["pdf"]}]}"#).is_err());
+        assert!(ReceiverPolicy::parse("{}").is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy = ReceiverPolicy::parse(r#"{"rules": [
+            {"sender_hosts": ["alice-laptop"], "out_dir": "alice"},
+            {"extensions": ["pdf", "tar.gz"], "max_size": "1MB", "out_dir": "inbox"}
+        ]}"#).unwrap();
+        let anonymous = AnonymousString(None);
+
+        let rule = policy.evaluate("holiday.mp4", Some(1 << 40), &host("Alice-Laptop")).unwrap();
+        assert_eq!(rule.out_path("holiday.mp4"), PathBuf::from("alice/holiday.mp4"));
+        assert_eq!(policy.evaluate("report.PDF", Some(1000), &anonymous).unwrap().out_dir, Some(PathBuf::from("inbox")));
+        assert!(policy.evaluate("backup.tar.gz", Some(1000), &host("bob")).is_ok());
+
+        assert!(matches!(policy.evaluate("report.pdf", Some(2_000_000), &anonymous), Err(NudgeError::PolicyRejected(_))));
+        assert!(policy.evaluate("setup.exe", Some(1000), &host("bob")).is_err());
+        // streams of unknown size only match rules without a size limit
+        assert!(policy.evaluate("report.pdf", None, &anonymous).is_err());
+        assert!(policy.evaluate("stdin", None, &host("alice-laptop")).is_ok());
+        assert!(policy.evaluate("pdf", Some(1000), &anonymous).is_err());
+        assert!(policy.evaluate(".pdf", Some(1000), &anonymous).is_err());
+        assert!(policy.evaluate("..", Some(1000), &host("alice-laptop")).is_err());
+        assert!(policy.evaluate("a\\..\\b.pdf", Some(1000), &anonymous).is_err());
+
+        let empty = ReceiverPolicy::parse(r#"{"rules": []}"#).unwrap();
+        assert!(empty.evaluate("report.pdf", Some(1000), &anonymous).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::blind::BlindSalt;
+use crate::utils::duration::parse_duration;
+use crate::utils::passphrase::Passphrase;
+use crate::utils::ticket::TicketKey;
+use crate::utils::webhook::Webhook;
+
+/// Length of the window the rate limit is counted in
+const RATE_LIMIT_WINDOW_MILLIS: u64 = 60 * 1000;
+
+/// How long senders are asked to wait if the relay is busy, unless configured otherwise
+const DEFAULT_BUSY_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Relay configuration file (JSON), e.g.
+///
+/// ```json
+/// {
+///   "session_ttl": "30m",
+///   "rate_limit": 120,
+///   "max_sessions": 10000,
+///   "busy_retry_after": "30s",
+///   "allow": ["10.0.0.0/8"],
+///   "deny": ["10.0.0.13"],
+///   "auth_tokens": ["s3cr3t"],
+///   "admin_tokens": ["4dm1n"],
+///   "shards": ["shard-1.internal:4000", "shard-2.internal:4000"],
+///   "trusted_fronts": ["10.0.0.1"],
+///   "ticket_secret": "s3cr3t",
+///   "require_tickets": true,
+///   "webhook": "http://chat.internal:8080/hooks/nudge"
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RelayConfigFile {
+    /// Overrides `--session-ttl`
+    session_ttl: Option<String>,
+
+    /// Maximum number of messages per minute and address
+    rate_limit: Option<u32>,
+
+    /// Maximum number of registered sessions, new senders are asked to retry later beyond that
+    max_sessions: Option<usize>,
+
+    /// How long senders are asked to wait if the relay is busy
+    busy_retry_after: Option<String>,
+
+    /// Addresses or networks allowed to use the relay (everyone if empty)
+    allow: Vec<String>,
+
+    /// Addresses or networks not allowed to use the relay
+    deny: Vec<String>,
+
+    /// Tokens senders need to present to register a passphrase (no token needed if empty)
+    auth_tokens: Vec<String>,
+
+    /// Tokens operators present to read the session statistics (`nudge relay-stats`, disabled if empty)
+    admin_tokens: Vec<String>,
+
+    /// Relays the sessions are forwarded to, by passphrase (front relay only)
+    shards: Vec<String>,
+
+    /// Front relays allowed to forward messages of their clients (shards only)
+    trusted_fronts: Vec<String>,
+
+    /// Secret the session tickets are signed with (a random one per start if not set)
+    ticket_secret: Option<String>,
+
+    /// Whether receivers need a session ticket to pick up files
+    require_tickets: bool,
+
+    /// URL session events are POSTed to, for senders which opted in with `send --announce`
+    webhook: Option<String>,
+}
+
+/// Settings of the relay which can be reloaded while it's running
+#[derive(Debug)]
+pub struct RelayConfig {
+    /// How long a passphrase stays valid if no receiver connects
+    pub session_ttl: Duration,
+
+    /// Maximum number of messages per minute and address (optional)
+    pub rate_limit: Option<u32>,
+
+    /// Maximum number of registered sessions (optional)
+    pub max_sessions: Option<usize>,
+
+    /// How long senders are asked to wait if `max_sessions` is reached
+    pub busy_retry_after: Duration,
+
+    /// Relays the sessions are forwarded to, by passphrase.
+    /// If set, the relay is a front relay and doesn't store sessions itself
+    pub shards: Vec<SocketAddr>,
+
+    /// Key the session tickets are signed with, random unless derived from `ticket_secret`
+    pub ticket_key: TicketKey,
+
+    /// Whether receivers need a session ticket to pick up files
+    pub require_tickets: bool,
+
+    /// Where session events of senders which opted in are POSTed to (optional)
+    pub webhook: Option<Webhook>,
+
+    /// Salt of the hashed passphrases in blind mode (`serve --blind`), kept across reloads
+    pub blind_salt: Option<BlindSalt>,
+
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+    auth_tokens: HashSet<String>,
+    admin_tokens: HashSet<String>,
+    trusted_fronts: Vec<IpNetwork>,
+    random_ticket_key: bool,
+}
+
+impl RelayConfig {
+    /// Creates a configuration which only sets the session TTL and lets everyone in.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_ttl` - How long a passphrase stays valid if no receiver connects.
+    pub fn new(session_ttl: Duration) -> Self {
+        RelayConfig {
+            session_ttl,
+            rate_limit: None,
+            max_sessions: None,
+            busy_retry_after: DEFAULT_BUSY_RETRY_AFTER,
+            shards: Vec::new(),
+            ticket_key: TicketKey::random(),
+            require_tickets: false,
+            webhook: None,
+            blind_salt: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            auth_tokens: HashSet::new(),
+            admin_tokens: HashSet::new(),
+            trusted_fronts: Vec::new(),
+            random_ticket_key: true,
+        }
+    }
+
+    /// Reads the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the JSON configuration file.
+    /// * `session_ttl` - The TTL used if the file doesn't set one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidRelayConfig` if the file can't be read or contains invalid values.
+    pub fn load(path: &Path, session_ttl: Duration) -> Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| NudgeError::InvalidRelayConfig(format!("{}: {}", path.display(), e));
+        let contents = fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        Self::parse(&contents, session_ttl).map_err(|e| match e {
+            NudgeError::InvalidRelayConfig(reason) => invalid(&reason),
+            e => invalid(&e),
+        })
+    }
+
+    /// Parses the contents of a configuration file, see `load`.
+    fn parse(contents: &str, session_ttl: Duration) -> Result<Self> {
+        let file: RelayConfigFile = serde_json::from_str(contents)
+            .map_err(|e| NudgeError::InvalidRelayConfig(e.to_string()))?;
+        let parse_networks = |networks: &[String]| {
+            networks.iter().map(|network| network.parse()).collect::<Result<Vec<IpNetwork>>>()
+        };
+
+        Ok(RelayConfig {
+            session_ttl: match file.session_ttl {
+                Some(ttl) => parse_duration(&ttl)?,
+                None => session_ttl,
+            },
+            rate_limit: file.rate_limit,
+            max_sessions: file.max_sessions,
+            busy_retry_after: match file.busy_retry_after {
+                Some(retry_after) => parse_duration(&retry_after)?,
+                None => DEFAULT_BUSY_RETRY_AFTER,
+            },
+            shards: file.shards.iter().map(|shard| resolve_shard(shard)).collect::<Result<_>>()?,
+            ticket_key: file.ticket_secret.as_deref().map_or_else(TicketKey::random, TicketKey::from_secret),
+            require_tickets: file.require_tickets,
+            webhook: file.webhook.as_deref().map(Webhook::parse).transpose()?,
+            blind_salt: None,
+            allow: parse_networks(&file.allow)?,
+            deny: parse_networks(&file.deny)?,
+            auth_tokens: file.auth_tokens.into_iter().collect(),
+            admin_tokens: file.admin_tokens.into_iter().collect(),
+            trusted_fronts: parse_networks(&file.trusted_fronts)?,
+            random_ticket_key: file.ticket_secret.is_none(),
+        })
+    }
+
+    /// Keeps the random ticket key of the configuration this one replaces,
+    /// so reloading doesn't invalidate the tickets handed out so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `previous` - The configuration used until now.
+    pub fn inherit_ticket_key(&mut self, previous: RelayConfig) {
+        if self.random_ticket_key && previous.random_ticket_key {
+            self.ticket_key = previous.ticket_key;
+        }
+    }
+
+    /// Returns the key the session of a passphrase is stored under.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::BlindPassphraseRequired` if the relay is blind and the client sent the passphrase itself.
+    pub fn session_key(&self, passphrase: &Passphrase<'static>) -> Result<Passphrase<'static>> {
+        match &self.blind_salt {
+            Some(salt) => salt.session_key(passphrase),
+            None => Ok(passphrase.clone()),
+        }
+    }
+
+    /// Checks whether the address may use the relay.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::AddressNotAllowed` if the address is denied or not on the allow list.
+    pub fn check_address(&self, ip: IpAddr) -> Result<()> {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip));
+        if !allowed || self.deny.iter().any(|network| network.contains(ip)) {
+            return Err(NudgeError::AddressNotAllowed);
+        }
+        Ok(())
+    }
+
+    /// Checks whether a sender may register a passphrase.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidRelayToken` if the relay requires a token and the sender didn't present a known one.
+    pub fn check_token(&self, token: Option<&str>) -> Result<()> {
+        if self.auth_tokens.is_empty() || token.is_some_and(|token| self.auth_tokens.contains(token)) {
+            Ok(())
+        } else {
+            Err(NudgeError::InvalidRelayToken)
+        }
+    }
+
+    /// Checks whether an operator may read the session statistics.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::AdminApiDisabled` if the relay has no admin tokens,
+    /// or `NudgeError::InvalidAdminToken` if the token isn't one of them.
+    pub fn check_admin_token(&self, token: &str) -> Result<()> {
+        if self.admin_tokens.is_empty() {
+            Err(NudgeError::AdminApiDisabled)
+        } else if self.admin_tokens.contains(token) {
+            Ok(())
+        } else {
+            Err(NudgeError::InvalidAdminToken)
+        }
+    }
+
+    /// Checks whether the address belongs to a front relay which may forward messages of its clients.
+    pub fn is_trusted_front(&self, ip: IpAddr) -> bool {
+        self.trusted_fronts.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Resolves the address of a shard, e.g. "shard-1.internal:4000".
+fn resolve_shard(shard: &str) -> Result<SocketAddr> {
+    shard.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| NudgeError::InvalidRelayConfig(format!("cannot resolve shard: {}", shard)))
+}
+
+/// An address or a network in CIDR notation, e.g. "192.168.0.0/16"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Checks whether the address is part of the network.
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = NudgeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || NudgeError::InvalidRelayConfig(format!("invalid address or network: {}", s));
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse().map_err(|_| invalid())?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+        Ok(IpNetwork { addr: addr.to_canonical(), prefix_len })
+    }
+}
+
+/// Counts the messages per address, in windows of a minute
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Start of the current window (unix millis) and number of messages in it, per address
+    windows: HashMap<IpAddr, (u64, u32)>,
+}
+
+impl RateLimiter {
+    /// Counts a message and checks whether the address exceeded the limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The address the message came from.
+    /// * `limit` - Maximum number of messages per minute.
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::RateLimited` if the address sent too many messages in the current window.
+    pub fn check(&mut self, ip: IpAddr, limit: u32, now: u64) -> Result<()> {
+        let (window_start, count) = self.windows.entry(ip).or_insert((now, 0));
+        if now.saturating_sub(*window_start) >= RATE_LIMIT_WINDOW_MILLIS {
+            *window_start = now;
+            *count = 0;
+        }
+        *count = count.saturating_add(1);
+        if *count > limit {
+            return Err(NudgeError::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Forgets addresses whose window has ended.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in unix millis.
+    pub fn prune(&mut self, now: u64) {
+        self.windows.retain(|_, (window_start, _)| now.saturating_sub(*window_start) < RATE_LIMIT_WINDOW_MILLIS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::ticket::TicketPayload;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = RelayConfig::parse(r#"{"session_ttl": "30m", "rate_limit": 10}"#, Duration::from_secs(3600)).unwrap();
+        assert_eq!(config.session_ttl, Duration::from_secs(30 * 60));
+        assert_eq!(config.rate_limit, Some(10));
+
+        let config = RelayConfig::parse("{}", Duration::from_secs(3600)).unwrap();
+        assert_eq!(config.session_ttl, Duration::from_secs(3600));
+        assert_eq!(config.rate_limit, None);
+        assert_eq!(config.max_sessions, None);
+        assert_eq!(config.busy_retry_after, DEFAULT_BUSY_RETRY_AFTER);
+
+        let config = RelayConfig::parse(r#"{"max_sessions": 5, "busy_retry_after": "1m"}"#, Duration::ZERO).unwrap();
+        assert_eq!(config.max_sessions, Some(5));
+        assert_eq!(config.busy_retry_after, Duration::from_secs(60));
+        assert!(!config.require_tickets);
+
+        let config = RelayConfig::parse(r#"{"ticket_secret": "s3cr3t", "require_tickets": true}"#, Duration::ZERO).unwrap();
+        assert!(!config.random_ticket_key);
+        assert!(config.require_tickets);
+
+        assert!(RelayConfig::parse(r#"{"session_tll": "30m"}"#, Duration::ZERO).is_err());
+        assert!(RelayConfig::parse(r#"{"deny": ["10.0.0.0/33"]}"#, Duration::ZERO).is_err());
+        assert!(RelayConfig::parse(r#"{"shards": ["10.0.0.1"]}"#, Duration::ZERO).is_err());
+
+        let config = RelayConfig::parse(r#"{"webhook": "http://chat.internal/hooks/nudge"}"#, Duration::ZERO).unwrap();
+        assert!(config.webhook.is_some());
+        assert!(RelayConfig::parse(r#"{"webhook": "https://chat.internal/hooks/nudge"}"#, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_inherit_ticket_key() {
+        let previous = RelayConfig::new(Duration::ZERO);
+        let ticket = previous.ticket_key.sign(&TicketPayload {
+            passphrase: Passphrase::from("correct-horse-battery".to_string()),
+            relay: "relay.example:4000".to_string(),
+            expires_at: 1000,
+            file_name: "report.pdf".to_string(),
+            file_size: 1,
+        }).unwrap();
+
+        // a random key survives the reload
+        let mut config = RelayConfig::parse("{}", Duration::ZERO).unwrap();
+        config.inherit_ticket_key(previous);
+        assert!(config.ticket_key.verify(&ticket, 0).is_ok());
+
+        // a configured secret replaces it
+        let previous = config;
+        let mut config = RelayConfig::parse(r#"{"ticket_secret": "s3cr3t"}"#, Duration::ZERO).unwrap();
+        config.inherit_ticket_key(previous);
+        assert!(config.ticket_key.verify(&ticket, 0).is_err());
+    }
+
+    #[test]
+    fn test_sharding() {
+        let config = RelayConfig::parse(
+            r#"{"shards": ["10.0.0.1:4000", "[::1]:4001"], "trusted_fronts": ["10.0.0.0/24"]}"#, Duration::ZERO,
+        ).unwrap();
+        assert_eq!(config.shards, vec!["10.0.0.1:4000".parse().unwrap(), "[::1]:4001".parse().unwrap()]);
+        assert!(config.is_trusted_front("10.0.0.5".parse().unwrap()));
+        assert!(!config.is_trusted_front("10.0.1.5".parse().unwrap()));
+        assert!(!RelayConfig::new(Duration::ZERO).is_trusted_front("10.0.0.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_check_address() {
+        let config = RelayConfig::parse(
+            r#"{"allow": ["10.0.0.0/8", "::1"], "deny": ["10.0.0.13"]}"#, Duration::ZERO,
+        ).unwrap();
+        assert!(config.check_address("10.1.2.3".parse().unwrap()).is_ok());
+        assert!(config.check_address("::ffff:10.1.2.3".parse().unwrap()).is_ok());
+        assert!(config.check_address("::1".parse().unwrap()).is_ok());
+        assert!(config.check_address("10.0.0.13".parse().unwrap()).is_err());
+        assert!(config.check_address("192.168.0.1".parse().unwrap()).is_err());
+
+        let config = RelayConfig::new(Duration::ZERO);
+        assert!(config.check_address("192.168.0.1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let everyone: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everyone.contains("1.2.3.4".parse().unwrap()));
+        assert!(!everyone.contains("::2".parse().unwrap()));
+
+        let network: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8::1".parse().unwrap()));
+        assert!(!network.contains("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_check_token() {
+        assert!(RelayConfig::new(Duration::ZERO).check_token(None).is_ok());
+
+        let config = RelayConfig::parse(r#"{"auth_tokens": ["s3cr3t"]}"#, Duration::ZERO).unwrap();
+        assert!(config.check_token(Some("s3cr3t")).is_ok());
+        assert!(config.check_token(Some("guess")).is_err());
+        assert!(config.check_token(None).is_err());
+    }
+
+    #[test]
+    fn test_check_admin_token() {
+        let config = RelayConfig::parse(r#"{"admin_tokens": ["4dm1n"]}"#, Duration::ZERO).unwrap();
+        assert!(config.check_admin_token("4dm1n").is_ok());
+        assert!(matches!(config.check_admin_token("guess"), Err(NudgeError::InvalidAdminToken)));
+        assert!(matches!(
+            RelayConfig::new(Duration::ZERO).check_admin_token("4dm1n"),
+            Err(NudgeError::AdminApiDisabled)
+        ));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.check(ip, 2, 0).is_ok());
+        assert!(limiter.check(ip, 2, 1).is_ok());
+        assert!(limiter.check(ip, 2, 2).is_err());
+        assert!(limiter.check(ip, 2, RATE_LIMIT_WINDOW_MILLIS).is_ok());
+
+        limiter.prune(3 * RATE_LIMIT_WINDOW_MILLIS);
+        assert!(limiter.windows.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::error::{NudgeError, Result};
+
+/// Responses telling the peers about each other, held back by `--simulate-match-delay-ms`
+const MATCH_PREFIXES: [&str; 2] = ["X2S_SCON", "X2R_ASC"];
+
+/// Drops messages with a prefix, e.g. `X2R_ASC` (always) or `S2X_RP:2` (the first two)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DropRule {
+    prefix: String,
+
+    /// How many messages are dropped (`None` for all)
+    count: Option<u32>,
+}
+
+impl DropRule {
+    /// Parses a rule given as `<PREFIX>` or `<PREFIX>:<COUNT>`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidSimulation` if the prefix is empty or the count isn't a number.
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = || NudgeError::InvalidSimulation(format!("invalid drop rule {}, expected PREFIX or PREFIX:COUNT", input));
+        let (prefix, count) = match input.split_once(':') {
+            Some((prefix, count)) => (prefix, Some(count.parse().map_err(|_| invalid())?)),
+            None => (input, None),
+        };
+        if prefix.is_empty() {
+            return Err(invalid());
+        }
+        Ok(DropRule { prefix: prefix.to_string(), count })
+    }
+}
+
+/// Misbehavior of a relay in CI, so the retries and fallbacks of clients can be exercised reproducibly.
+/// Nothing is simulated by default
+#[derive(Debug, Default)]
+pub struct RelaySimulation {
+    /// Shift of the ports of the addresses the peers are told about, like a symmetric NAT
+    /// allocating a new port for every destination
+    port_shift: i32,
+
+    /// How long the responses matching the peers are held back
+    match_delay: Duration,
+
+    /// Messages from and to clients which are dropped, with the number dropped so far
+    drop_rules: Vec<(DropRule, Cell<u32>)>,
+}
+
+impl RelaySimulation {
+    /// Creates the simulation selected with the `--simulate-*` options of `serve`.
+    ///
+    /// # Arguments
+    ///
+    /// * `port_shift` - Shift of the ports of the addresses the peers are told about.
+    /// * `match_delay` - How long the responses matching the peers are held back.
+    /// * `drop_rules` - Messages from and to clients which are dropped.
+    pub fn new(port_shift: i32, match_delay: Duration, drop_rules: &[DropRule]) -> Self {
+        RelaySimulation {
+            port_shift,
+            match_delay,
+            drop_rules: drop_rules.iter().map(|rule| (rule.clone(), Cell::new(0))).collect(),
+        }
+    }
+
+    /// Returns whether anything is simulated.
+    pub fn is_active(&self) -> bool {
+        self.port_shift != 0 || !self.match_delay.is_zero() || !self.drop_rules.is_empty()
+    }
+
+    /// Returns the address the peers are told about instead of the observed one.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address the relay observed.
+    pub fn mangle(&self, addr: &SocketAddr) -> SocketAddr {
+        let mut mangled = *addr;
+        mangled.set_port((addr.port() as i32 + self.port_shift).rem_euclid(0x10000) as u16);
+        mangled
+    }
+
+    /// Checks whether a message (received or about to be sent) is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message, starting with its prefix.
+    pub fn drops(&self, message: &str) -> bool {
+        let prefix = message.split_whitespace().next();
+        self.drop_rules.iter().any(|(rule, dropped)| {
+            let drops = prefix == Some(rule.prefix.as_str()) && rule.count.is_none_or(|count| dropped.get() < count);
+            if drops {
+                dropped.set(dropped.get() + 1);
+            }
+            drops
+        })
+    }
+
+    /// Returns how long a response is held back before it's sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The response, starting with its prefix.
+    pub fn delay_of(&self, response: &str) -> Duration {
+        match response.split_whitespace().next() {
+            Some(prefix) if MATCH_PREFIXES.contains(&prefix) => self.match_delay,
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_rules() {
+        let rules = [DropRule::parse("S2X_RP:2").unwrap(), DropRule::parse("X2R_ASC").unwrap()];
+        let simulation = RelaySimulation::new(0, Duration::ZERO, &rules);
+        assert!(simulation.drops("S2X_RP {}"));
+        assert!(simulation.drops("S2X_RP {}"));
+        assert!(!simulation.drops("S2X_RP {}"));
+        assert!(simulation.drops("X2R_ASC {}\n"));
+        assert!(simulation.drops("X2R_ASC {}\n"));
+        assert!(!simulation.drops("R2X_RFI {}"));
+
+        assert!(DropRule::parse("").is_err());
+        assert!(DropRule::parse("S2X_RP:x").is_err());
+    }
+
+    #[test]
+    fn test_mangle() {
+        let addr: SocketAddr = "192.0.2.1:65535".parse().unwrap();
+        let simulation = RelaySimulation::new(1, Duration::ZERO, &[]);
+        assert_eq!(simulation.mangle(&addr), "192.0.2.1:0".parse().unwrap());
+        assert_eq!(RelaySimulation::default().mangle(&addr), addr);
+    }
+
+    #[test]
+    fn test_delay_of() {
+        let simulation = RelaySimulation::new(0, Duration::from_secs(1), &[]);
+        assert_eq!(simulation.delay_of("X2S_SCON {}\n"), Duration::from_secs(1));
+        assert_eq!(simulation.delay_of("X2R_AFI {}\n"), Duration::ZERO);
+    }
+}
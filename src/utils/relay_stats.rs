@@ -0,0 +1,165 @@
+//! Statistics of the sessions of a relay, served to its operators with `nudge relay-stats`,
+//! e.g. to learn how long passphrases typically wait before a receiver claims them.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::models::{FileInfo, WaitPercentiles, X2AStatisticsMessage};
+use crate::utils::passphrase::Passphrase;
+
+/// Number of recently claimed sessions whose wait is kept for the percentiles
+const MAX_WAIT_SAMPLES: usize = 1024;
+
+/// Counts what happened to the sessions of a relay since it started
+#[derive(Debug)]
+pub struct RelayStatistics {
+    /// When the relay started (unix millis)
+    started_at: u64,
+
+    registered: u64,
+    matched: u64,
+    expired: u64,
+    lookups: u64,
+
+    /// Milliseconds the recently claimed sessions waited for their receiver, the oldest first
+    waits: VecDeque<u64>,
+}
+
+impl RelayStatistics {
+    /// Starts counting.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in unix millis.
+    pub fn new(now: u64) -> Self {
+        RelayStatistics {
+            started_at: now,
+            registered: 0,
+            matched: 0,
+            expired: 0,
+            lookups: 0,
+            waits: VecDeque::new(),
+        }
+    }
+
+    /// Counts a newly registered session (not the retries of its registration).
+    pub fn record_registration(&mut self) {
+        self.registered += 1;
+    }
+
+    /// Counts a lookup of a registered passphrase, also kept by the session itself.
+    pub fn record_lookup(&mut self, file_info: &mut FileInfo) {
+        self.lookups += 1;
+        file_info.lookups += 1;
+    }
+
+    /// Counts a session claimed by a receiver, remembering how long it waited.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_info` - The session, whose claim time is set.
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Returns
+    ///
+    /// `u64` - Milliseconds the session waited for the receiver.
+    pub fn record_match(&mut self, file_info: &mut FileInfo, now: u64) -> u64 {
+        let wait = now.saturating_sub(file_info.created_at);
+        file_info.matched_at = Some(now);
+        self.matched += 1;
+        if self.waits.len() == MAX_WAIT_SAMPLES {
+            self.waits.pop_front();
+        }
+        self.waits.push_back(wait);
+        wait
+    }
+
+    /// Counts a session the relay forgets, if no receiver ever claimed it.
+    pub fn record_removal(&mut self, file_info: &FileInfo) {
+        if file_info.matched_at.is_none() {
+            self.expired += 1;
+        }
+    }
+
+    /// Returns the statistics, together with the sessions registered right now.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_map` - The sessions registered right now.
+    /// * `now` - The current time in unix millis.
+    pub fn report(&self, client_map: &BTreeMap<Passphrase<'static>, FileInfo>, now: u64) -> X2AStatisticsMessage {
+        let waiting: Vec<&FileInfo> = client_map.values().filter(|file_info| file_info.matched_at.is_none()).collect();
+        X2AStatisticsMessage {
+            uptime_secs: now.saturating_sub(self.started_at) / 1000,
+            registered: self.registered,
+            matched: self.matched,
+            expired: self.expired,
+            lookups: self.lookups,
+            active: client_map.len() as u64,
+            waiting: waiting.len() as u64,
+            oldest_waiting_secs: waiting.iter()
+                .map(|file_info| now.saturating_sub(file_info.created_at) / 1000)
+                .max(),
+            wait_millis: percentiles(&self.waits),
+        }
+    }
+}
+
+/// Returns the median, 90th percentile and maximum of the waits, or `None` if there are none.
+fn percentiles(waits: &VecDeque<u64>) -> Option<WaitPercentiles> {
+    let mut sorted: Vec<u64> = waits.iter().copied().collect();
+    sorted.sort_unstable();
+    let max = *sorted.last()?;
+    let percentile = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+    Some(WaitPercentiles { p50: percentile(50), p90: percentile(90), max })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_info(created_at: u64) -> FileInfo {
+        serde_json::from_value(serde_json::json!({
+            "file_size": 1,
+            "file_name": "a",
+            "file_hash": null,
+            "sender_host": null,
+            "created_at": created_at,
+            "sender_addr": "127.0.0.1:1",
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_report() {
+        let mut statistics = RelayStatistics::new(0);
+        let mut client_map = BTreeMap::new();
+        for (index, created_at) in [1_000, 2_000, 5_000].into_iter().enumerate() {
+            statistics.record_registration();
+            client_map.insert(Passphrase::from(format!("session-{}", index)), file_info(created_at));
+        }
+        let claimed = client_map.get_mut(&Passphrase::from("session-0")).unwrap();
+        statistics.record_lookup(claimed);
+        statistics.record_lookup(claimed);
+        assert_eq!(statistics.record_match(claimed, 4_000), 3_000);
+        assert_eq!(claimed.lookups, 2);
+
+        let report = statistics.report(&client_map, 11_000);
+        assert_eq!(report.uptime_secs, 11);
+        assert_eq!((report.registered, report.matched, report.lookups), (3, 1, 2));
+        assert_eq!((report.active, report.waiting), (3, 2));
+        assert_eq!(report.oldest_waiting_secs, Some(9));
+        assert_eq!(report.wait_millis, Some(WaitPercentiles { p50: 3_000, p90: 3_000, max: 3_000 }));
+
+        // claimed sessions don't count as expired when they're forgotten
+        for file_info in client_map.values() {
+            statistics.record_removal(file_info);
+        }
+        assert_eq!(statistics.report(&BTreeMap::new(), 11_000).expired, 2);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        assert_eq!(percentiles(&VecDeque::new()), None);
+        let waits: VecDeque<u64> = (1..=100).rev().collect();
+        assert_eq!(percentiles(&waits), Some(WaitPercentiles { p50: 50, p90: 90, max: 100 }));
+    }
+}
@@ -1,92 +1,553 @@
-use std::collections::HashMap;
-use std::net::UdpSocket;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::error::{NudgeError, Result};
+use crate::utils::capture::PacketCapture;
 use crate::utils::current_unix_millis;
+use crate::utils::encryption::{FrameCipher, SEAL_OVERHEAD};
+use crate::utils::memory::MemoryProfile;
+use crate::utils::pacing::PacketPacer;
+use crate::utils::platform::{enable_txtime, grow_receive_buffer, receive_buffer_size, send_at};
+use crate::utils::profiling::{span, Stage};
+use crate::utils::sanitize::sanitize;
 
+/// Minimum time between two resend requests for the same packet
+const RESEND_REQUEST_INTERVAL_MS: u64 = 100;
+
+/// Minimum time before resending the same packets again for a repeated resend request
+const GO_BACK_INTERVAL_MS: u64 = 1000;
+
+/// Largest frame on the wire: the 3 byte header and up to 0xfffc bytes of data
+const MAX_FRAME_SIZE: usize = 0xfffc + 3;
+
+/// Size of the socket receive buffer requested, so bursts of the sender fit while we're busy
+const RECEIVE_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Gap between two receive attempts (usually at most a second apart) after which we assume the system slept
+const SLEEP_DETECTION_THRESHOLD_MS: u64 = 15_000;
+
+/// Minimum time between two resume requests after waking up
+const RESUME_INTERVAL_MS: u64 = 500;
+
+/// Number of resume requests sent after waking up, before relying on the regular retransmissions only
+const RESUME_ATTEMPTS: u32 = 120;
+
+/// Most sealed packets kept while the encryption hasn't started yet, later ones are dropped and sent again
+const MAX_EARLY_SEALED: usize = 64;
+
+/// Number of packet IDs before the next expected one which are recognized as duplicates (half of the ID space),
+/// the IDs after it are taken as packets which overtook a missing one
+pub const DEFAULT_DEDUP_WINDOW: u16 = 0x8000;
+
+/// Largest dedup window, leaves room for the packets in flight ahead of the next expected one
+pub const MAX_DEDUP_WINDOW: u16 = 0xf000;
+
+/// Most data packets received in order which are acknowledged together (`get --ack-every`)
+pub const DEFAULT_ACK_EVERY: u16 = 8;
+
+/// Most data packets arriving ahead of a missing one which are kept until it arrives (`get --reorder-buffer`)
+pub const DEFAULT_REORDER_BUFFER: u16 = 64;
+
+/// Longest an acknowledgment is held back for the packets following
+const ACK_DELAY: Duration = Duration::from_millis(2);
+
+/// Go-backs in a row to the same packet after which a large packet is taken as too large for the path
+/// (MTU black hole): the peer keeps asking for it, so smaller packets get through, but it never arrives
+const BLACK_HOLE_GO_BACKS: u32 = 4;
+
+/// How long `send` and `get` wait for the first packet of the peer after connecting (`--peer-timeout`).
+/// Punching through the NATs takes a few seconds at most, so a silent peer means the connection failed
+pub const DEFAULT_PEER_TIMEOUT: &str = "20s";
+
+/// Irregular datagrams of a session, shown in the statistics at the end of a transfer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DatagramStatistics {
+    /// Data packets received again, e.g. resent because the acknowledgment was late
+    pub duplicates: u64,
+
+    /// Data packets which overtook a missing one, and are dropped until it was resent
+    pub reordered: u64,
+
+    /// Data packets we resent
+    pub retransmitted: u64,
+}
+
+impl Display for DatagramStatistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} duplicate, {} out of order, {} retransmitted",
+            self.duplicates, self.reordered, self.retransmitted
+        )
+    }
+}
+
+/// Where the receiving side of a session stands, to tell where a stalled transfer hangs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiveState {
+    /// Data packets received in order, the sequence number of the next expected one
+    pub received_packets: u64,
+
+    /// When the last packet of the peer arrived (unix millis), `None` if none arrived yet
+    pub last_packet_at: Option<u64>,
+
+    /// Resend requests sent for missing packets
+    pub resend_requests: u64,
+
+    pub statistics: DatagramStatistics,
+}
+
+/// An acknowledgment held back, so it covers the packets following as well
+#[derive(Debug, Clone, Copy)]
+struct PendingAck {
+    /// The last data packet received in order
+    packet_id: u16,
+
+    /// Packets received in order since the last acknowledgment
+    count: u16,
+
+    /// When the first of them arrived
+    since: Instant,
+}
+
+/// Type of a packet, the third byte of its header. The values are part of the protocol (and of the layout
+/// dissectors of `--capture` files rely on), new types are only ever appended.
 #[derive(Ord, Eq, PartialOrd, PartialEq)]
 enum PacketType {
-    Write,
-    Acknowledgment,
-    ResendRequest,
-    EndSession,
+    Write = 0,
+    Acknowledgment = 1,
+    ResendRequest = 2,
+    EndSession = 3,
+    Abort = 4,
+    Resume = 5,
+    ResumeAcknowledgment = 6,
+    /// A data packet of an encrypted data stream, its type is sealed along with the data (see `encryption`)
+    Sealed = 7,
 }
 
 /// Handles reliable data transmission over UDP with manual acknowledgments and retransmissions.
 /// Heavily inspired by SafeReadWrite from https://github.com/TudbuT/qft/blob/master/src/main.rs
+///
+/// Both peers may read and write on the same socket: data arriving while waiting for
+/// acknowledgments is queued for the next `read`, and acknowledgments arriving while reading
+/// are applied to the packets waiting for retransmission.
+///
+/// Acknowledgments carry the receiver's window, i.e. how many more packets it can take
+/// (2 bytes after the header). The sender keeps at most that many packets unacknowledged,
+/// so a fast sender can't overrun a slow receiver. Peers which don't advertise a window
+/// are limited by `max_in_flight` only.
+///
+/// Data packets are only acknowledged in order, so an acknowledgment covers the packet and all before it.
+/// The receiver holds acknowledgments back for up to `ACK_DELAY` and acknowledges up to `ack_every` packets
+/// at once, which saves most of the datagrams on the reverse path (e.g. a DSL uplink). Senders which only
+/// forget the acknowledged packet itself keep the others until they wait for the latest one, which is
+/// acknowledged at the latest `ACK_DELAY` after it arrived.
+///
+/// Data packets which overtook a missing one are kept in the reorder buffer (up to `reorder_buffer` of them)
+/// and delivered once it arrives, so reordering on the path (e.g. over several links) costs no retransmission.
+/// A gap which outlasts a resend request is taken as loss: the buffer is dropped, since the peer goes back
+/// and sends the packets again anyway, possibly split into smaller ones.
+///
+/// Byte streams (e.g. a file) can fall back to smaller packets: once the peer asked for the same large packet
+/// `BLACK_HOLE_GO_BACKS` times in a row, the packets from it on are split up, renumbered and sent again,
+/// and later writes are split the same way. The peer dropped the packets after the missing one, so it takes
+/// the smaller packets in their place.
+///
+/// After the system slept (e.g. a closed laptop lid), the connection is re-validated:
+/// resume requests carrying the number of packets received so far are sent until the peer
+/// answers with its own count, which also re-opens the NAT mappings. Both sides then continue
+/// from the last packet the other side received.
+///
+/// Once the data stream is encrypted (`start_encryption`), data packets are sealed when they're written and kept
+/// sealed for retransmissions. Received data packets are only taken sealed from then on, the others are dropped.
+/// Acknowledgments and other control packets carry no data and stay readable.
 pub struct ReliableUdpSocket {
     socket: UdpSocket,
     last_transmitted: HashMap<u16, Vec<u8>>,
     sent_packets_count: u64,
     received_packets_count: u64,
+    max_in_flight: usize,
+    last_resend_request: Option<(u16, u64)>,
+    last_go_back: Option<(u16, u64)>,
+    received_queue: VecDeque<(u8, Vec<u8>)>,
+    frame_buffer: Vec<u8>,
+    is_catching_up: bool,
+    delay: u64,
+    /// Size of the socket receive buffer in bytes (if known)
+    receive_buffer: Option<usize>,
+    /// Largest data frame received so far, to estimate how many fit into the receive buffer
+    largest_frame: usize,
+    /// Window the peer advertised in its last acknowledgment
+    peer_window: Option<u16>,
+    /// Time of the last receive attempt (unix millis), to detect that the system slept
+    last_receive_attempt: u64,
+    /// Resume requests left to send until the peer answers
+    resume_attempts_left: u32,
+    /// Time of the last resume request (unix millis)
+    last_resume_request: u64,
+    /// Number of packet IDs before the next expected one which are recognized as duplicates
+    dedup_window: u16,
+    /// How long to wait for the first packet of the peer (`None` to wait forever)
+    peer_timeout: Option<Duration>,
+    /// Since when we wait for the first packet of the peer (unix millis), `None` once it arrived
+    waiting_since: Option<u64>,
+    /// When the last packet of the peer arrived (unix millis)
+    last_packet_at: Option<u64>,
+    /// Resend requests sent for missing packets
+    resend_requests: u64,
+    /// Spaces the data packets `delay` apart
+    pacer: PacketPacer,
+    /// Most data packets acknowledged together, 1 acknowledges every packet right away
+    ack_every: u16,
+    /// The acknowledgment held back for the packets following (optional)
+    pending_ack: Option<PendingAck>,
+    /// Records the datagrams sent and received, with the local and the peer address (optional)
+    capture: Option<(PacketCapture, SocketAddr, SocketAddr)>,
+    /// Data size of the packets to fall back to if large packets don't get through (optional)
+    fallback_chunk_size: Option<usize>,
+    /// Largest data in a packet once fallen back, `None` while writes are sent as they are
+    chunk_limit: Option<usize>,
+    /// The packet the last go-backs went back to, and how many of them in a row
+    stuck_packet: Option<(u16, u32)>,
+    /// Whether the packets in flight were renumbered since the write started waiting
+    repacked: bool,
+    /// Most data packets kept while they wait for a missing one before them, 0 to drop them
+    reorder_buffer: usize,
+    /// Data packets which overtook the next expected one, by packet ID
+    reordered_packets: HashMap<u16, (u8, Vec<u8>)>,
+    /// Whether the next expected packet is taken as lost, so later packets aren't kept for it
+    gap_is_loss: bool,
+    /// Seals the data packets sent and opens the ones received, once the data stream is encrypted (optional)
+    cipher: Option<FrameCipher>,
+    /// Sealed packets which arrived before the encryption started, opened once it does
+    early_sealed: Vec<Vec<u8>>,
+    statistics: DatagramStatistics,
 }
 
 impl ReliableUdpSocket {
     /// Creates a new instance bound to the provided UDP socket.
     pub fn new(socket: UdpSocket) -> Self {
+        grow_receive_buffer(&socket, RECEIVE_BUFFER_SIZE);
+        let receive_buffer = receive_buffer_size(&socket);
         ReliableUdpSocket {
             socket,
             last_transmitted: HashMap::new(),
             received_packets_count: 0,
             sent_packets_count: 0,
+            max_in_flight: MemoryProfile::DEFAULT.max_in_flight,
+            last_resend_request: None,
+            last_go_back: None,
+            received_queue: VecDeque::new(),
+            frame_buffer: vec![0; MAX_FRAME_SIZE + SEAL_OVERHEAD],
+            is_catching_up: false,
+            delay: 0,
+            receive_buffer,
+            largest_frame: 0,
+            peer_window: None,
+            last_receive_attempt: current_unix_millis(),
+            resume_attempts_left: 0,
+            last_resume_request: 0,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            peer_timeout: None,
+            waiting_since: Some(current_unix_millis()),
+            last_packet_at: None,
+            resend_requests: 0,
+            pacer: PacketPacer::new(false),
+            ack_every: DEFAULT_ACK_EVERY,
+            pending_ack: None,
+            capture: None,
+            fallback_chunk_size: None,
+            chunk_limit: None,
+            stuck_packet: None,
+            repacked: false,
+            reorder_buffer: DEFAULT_REORDER_BUFFER as usize,
+            reordered_packets: HashMap::new(),
+            gap_is_loss: false,
+            cipher: None,
+            early_sealed: Vec::new(),
+            statistics: DatagramStatistics::default(),
+        }
+    }
+
+    /// Limits the number of unacknowledged packets kept for retransmission.
+    /// Once the limit is reached, writes wait for the receiver to catch up.
+    /// Also limits the window advertised to the peer, i.e. the number of received packets buffered.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Sets how many packet IDs before the next expected one are recognized as duplicates (and acknowledged again).
+    /// Packets further back are taken as packets which overtook a missing one, so a smaller window
+    /// tolerates more packets in flight, a larger one later duplicates. Clamped to `1..=MAX_DEDUP_WINDOW`.
+    pub fn with_dedup_window(mut self, dedup_window: u16) -> Self {
+        self.dedup_window = dedup_window.clamp(1, MAX_DEDUP_WINDOW);
+        self
+    }
+
+    /// Gives up if the peer didn't send a single packet within the timeout, e.g. because punching the hole
+    /// through the NATs failed. Once packets arrived, pauses of the peer are waited out as usual.
+    pub fn with_peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = Some(peer_timeout);
+        self
+    }
+
+    /// Sets how many data packets received in order are acknowledged together at most,
+    /// 1 acknowledges every packet right away.
+    pub fn with_ack_every(mut self, ack_every: u16) -> Self {
+        self.ack_every = ack_every.max(1);
+        self
+    }
+
+    /// Sets how many data packets which overtook a missing one are kept until it arrives, 0 drops them and
+    /// relies on the peer sending them again. Bounded by `max_in_flight`.
+    pub fn with_reorder_buffer(mut self, reorder_buffer: u16) -> Self {
+        self.reorder_buffer = reorder_buffer as usize;
+        self
+    }
+
+    /// Returns how many data packets which overtook a missing one are kept at most.
+    pub fn reorder_buffer(&self) -> usize {
+        self.reorder_buffer.min(self.max_in_flight)
+    }
+
+    /// Hands every data packet to the kernel with its departure (SO_TXTIME, Linux), so queueing disciplines
+    /// pacing by it (fq, etf) send it on time. Falls back to waiting for the departure if the socket can't.
+    pub fn with_txtime(mut self, txtime: bool) -> Self {
+        self.pacer = PacketPacer::new(txtime && enable_txtime(&self.socket));
+        self
+    }
+
+    /// Records every datagram sent and received to the capture (`--capture`).
+    /// The socket has to be connected to the peer, whose address the datagrams are recorded with.
+    pub fn with_capture(mut self, capture: Option<PacketCapture>) -> Self {
+        self.capture = capture.and_then(|capture| match (self.socket.local_addr(), self.socket.peer_addr()) {
+            (Ok(local), Ok(peer)) => Some((capture, local, peer)),
+            _ => {
+                warn!(target: "io", "Cannot capture the datagrams of a socket not connected to the peer");
+                None
+            }
+        });
+        self
+    }
+
+    /// Falls back to packets with at most `chunk_size` bytes of data once large packets persistently
+    /// disappear on the way (MTU black hole). The boundaries of the data written aren't kept then,
+    /// so only byte streams may fall back, e.g. a file, not framed messages.
+    pub fn with_chunk_fallback(mut self, chunk_size: usize) -> Self {
+        self.fallback_chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// Encrypts the data stream from the next packet on, in both directions. The peer has to start at the same
+    /// point of the session, e.g. after a message telling so. Sealed packets of the peer which arrived before are
+    /// opened now.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the packets which arrived before can't be acknowledged
+    pub fn start_encryption(&mut self, cipher: FrameCipher) -> Result<()> {
+        debug!(target: "crypto", "Encrypting the data stream from packet {} on", self.sent_packets_count as u16);
+        self.cipher = Some(cipher);
+        for frame in std::mem::take(&mut self.early_sealed) {
+            self.frame_buffer[..frame.len()].copy_from_slice(&frame);
+            self.handle_frame(frame.len())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the largest data in a packet once fallen back to smaller packets, `None` before.
+    pub fn chunk_limit(&self) -> Option<usize> {
+        self.chunk_limit
+    }
+
+    /// Returns the irregular datagrams of the session so far.
+    pub fn statistics(&self) -> DatagramStatistics {
+        self.statistics
+    }
+
+    /// Returns the size of the packets written but not acknowledged yet, including their headers,
+    /// so it's at least the data the peer may not have received.
+    pub fn unacknowledged_bytes(&self) -> u64 {
+        self.last_transmitted.values().map(|packet| packet.len() as u64).sum()
+    }
+
+    /// Returns where the receiving side stands, e.g. to report a stalled transfer.
+    pub fn receive_state(&self) -> ReceiveState {
+        ReceiveState {
+            received_packets: self.received_packets_count,
+            last_packet_at: self.last_packet_at,
+            resend_requests: self.resend_requests,
+            statistics: self.statistics,
         }
     }
 
+    /// Tells the socket the time since the last read was spent by the caller, e.g. blocked writing to a slow pipe,
+    /// so the gap isn't taken for the system sleeping. The sender waits meanwhile, as no acknowledgments arrive.
+    pub fn mark_busy(&mut self) {
+        self.last_receive_attempt = current_unix_millis();
+    }
+
+    /// Keeps the connection to the peer while the caller holds the data back for a while, e.g. outside the window of
+    /// `send --window`: the peer answers a resume request, so mappings of NATs on the way don't expire, and
+    /// its packets are acknowledged (or resent on request) meanwhile.
+    /// Fails with `NudgeError::TransferAborted` once the peer aborted the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - How long the data is held back.
+    pub fn keep_alive(&mut self, duration: Duration) -> Result<()> {
+        self.send_resume_packet(PacketType::Resume);
+        let deadline = Instant::now() + duration;
+        let timeout = self.socket.read_timeout()?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            match self.receive_frame() {
+                Ok(Some((packet_id, packet_type))) => self.handle_control_packet(packet_id, packet_type),
+                Ok(None) => {}
+                Err(NudgeError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                // e.g. the peer is unreachable for now, the next resume request tries again
+                Err(_) => thread::sleep(remaining.min(Duration::from_millis(100))),
+            }
+        }
+        self.socket.set_read_timeout(timeout)?;
+        self.mark_busy();
+        self.check_peer_abort()
+    }
+
     /// Safely writes data to the socket with an optional flush and delay.
+    /// Fails with `NudgeError::TransferAborted` once the peer aborted the session.
     pub fn write_and_flush(&mut self, data: &[u8], should_flush: bool, delay: u64) -> Result<()> {
+        self.check_peer_abort()?;
+        self.delay = delay;
+        if let Some(chunk_limit) = self.chunk_limit.filter(|chunk_limit| data.len() > *chunk_limit) {
+            let mut pieces = data.chunks(chunk_limit).peekable();
+            while let Some(piece) = pieces.next() {
+                self.internal_write(piece, PacketType::Write, should_flush && pieces.peek().is_none(), false, delay)?;
+            }
+            return Ok(());
+        }
         self.internal_write(data, PacketType::Write, should_flush, false, delay)
     }
 
     /// Reads data from the socket, ensuring packet order and requesting retransmissions if necessary.
+    /// Returns the data and its length, which is `0` once the peer ended the session.
     pub fn read(&mut self, buffer: &[u8]) -> Result<(Vec<u8>, usize)> {
         if buffer.len() > 0xfffc {
             return Err(NudgeError::BufferSizeLimitExceeded(buffer.len()));
         }
 
-        let mut packet_buffer = vec![0; buffer.len() + 3];
-        packet_buffer[3..].copy_from_slice(buffer);
-
-        let mut received_data = (Vec::new(), 0);
-        let mut should_retry = true;
-        let mut is_catching_up = false;
-
-        while should_retry {
-            match self.socket.recv(&mut packet_buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read < 3 {
-                        continue;
-                    }
-                    let packet_id = u16::from_be_bytes(
-                        [packet_buffer[0], packet_buffer[1]]
-                    );
-                    self.handle_packet(
-                        packet_id,
-                        &packet_buffer,
-                        &mut received_data,
-                        &mut should_retry,
-                        &mut is_catching_up,
-                        bytes_read,
-                    )?;
+        loop {
+            if let Some(received) = self.pop_received()? {
+                return Ok(received);
+            }
+            match self.receive_frame() {
+                Ok(Some((packet_id, packet_type))) => self.handle_control_packet(packet_id, packet_type),
+                Ok(None) => continue,
+                // Nothing arrived in time, the packet we're waiting for might have been lost
+                Err(_) => {
+                    self.check_peer_timeout()?;
+                    self.request_resend()?
                 }
-                Err(_) => continue,
             }
         }
+    }
+
+    /// Like `read`, but gives up if no data arrived within the timeout, so the caller can write in between.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Option<(Vec<u8>, usize)>>` - The data and its length, or `None` if nothing arrived in time.
+    pub fn try_read(&mut self, buffer: &[u8], timeout: Duration) -> Result<Option<(Vec<u8>, usize)>> {
+        if buffer.len() > 0xfffc {
+            return Err(NudgeError::BufferSizeLimitExceeded(buffer.len()));
+        }
 
-        // Remove the header from the received data
-        packet_buffer.drain(0..3);
+        // a zero timeout is rejected by the socket, so wait at least a millisecond
+        let timeout = timeout.max(Duration::from_millis(1));
+        self.socket.set_read_timeout(Some(timeout))?;
+        let deadline = current_unix_millis() + timeout.as_millis() as u64;
 
-        received_data.0 = packet_buffer;
-        Ok(received_data)
+        loop {
+            if let Some(received) = self.pop_received()? {
+                return Ok(Some(received));
+            }
+            if current_unix_millis() >= deadline {
+                return Ok(None);
+            }
+            match self.receive_frame() {
+                Ok(Some((packet_id, packet_type))) => self.handle_control_packet(packet_id, packet_type),
+                Ok(None) => continue,
+                // The peer may be idle, but its last packet might also have been lost
+                Err(_) => {
+                    self.check_peer_timeout()?;
+                    self.request_resend()?
+                }
+            }
+        }
     }
 
     /// Ends the session, ensuring all data is flushed and the socket is properly closed.
     pub fn end(mut self) -> UdpSocket {
+        debug!(target: "transport", "Ending the session: {}", self.statistics);
         let _ = self.internal_write(&[], PacketType::EndSession, true, true, 3000);
         self.socket
     }
 
+    /// Aborts the session, so the peer knows the data is incomplete instead of assuming it ended regularly.
+    /// The peer's next `read` fails with `NudgeError::TransferAborted`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the session was aborted, shown to the peer.
+    pub fn abort(mut self, reason: &str) -> UdpSocket {
+        // the reason has to fit into a single packet
+        let reason = &reason.as_bytes()[..reason.len().min(1024)];
+        let _ = self.internal_write(reason, PacketType::Abort, true, true, 3000);
+        self.socket
+    }
+
+    /// Fails with `NudgeError::PeerTimeout` if the peer didn't send anything within the peer timeout.
+    fn check_peer_timeout(&self) -> Result<()> {
+        match (self.peer_timeout, self.waiting_since) {
+            (Some(peer_timeout), Some(waiting_since))
+                if current_unix_millis().saturating_sub(waiting_since) >= peer_timeout.as_millis() as u64 => {
+                Err(NudgeError::PeerTimeout(peer_timeout))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if the peer aborted the session, e.g. the receiver while we're still sending.
+    fn check_peer_abort(&self) -> Result<()> {
+        match self.received_queue.iter().find(|(packet_type, _)| *packet_type == PacketType::Abort as u8) {
+            Some((_, reason)) => Err(NudgeError::TransferAborted(sanitize(&String::from_utf8_lossy(reason)))),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the next received data packet from the queue.
+    fn pop_received(&mut self) -> Result<Option<(Vec<u8>, usize)>> {
+        match self.received_queue.pop_front() {
+            Some((packet_type, reason)) if packet_type == PacketType::Abort as u8 => {
+                Err(NudgeError::TransferAborted(sanitize(&String::from_utf8_lossy(&reason))))
+            }
+            Some((_, data)) => {
+                let bytes_read = data.len();
+                Ok(Some((data, bytes_read)))
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Internal method to handle packet writing with retries and error handling.
     fn internal_write(
         &mut self,
@@ -100,47 +561,298 @@ impl ReliableUdpSocket {
             return Err(NudgeError::DataPacketLimitExceeded(data.len()));
         }
 
-        let packet_id = (self.sent_packets_count as u16).to_be_bytes();
-        let packet_index = self.sent_packets_count as u16;
+        let serialize_span = span(Stage::Serialize);
+        let packet_number = self.sent_packets_count;
+        let packet_id = (packet_number as u16).to_be_bytes();
+        let packet_index = packet_number as u16;
         self.sent_packets_count += 1;
 
         let mut data_buffer = Vec::with_capacity(data.len() + 3);
         data_buffer.extend_from_slice(&packet_id);
         data_buffer.push(packet_type as u8);
         data_buffer.extend_from_slice(data);
+        if let Some(cipher) = &mut self.cipher {
+            data_buffer = cipher.seal(&data_buffer, PacketType::Sealed as u8, packet_number);
+        }
+        drop(serialize_span);
 
         // Transmit the packet with retries if not acknowledged
         self.transmit_packet(&data_buffer, packet_index, delay, flush, exit_on_lost)
     }
 
-    /// Handles received packets, managing acknowledgment responses and detecting packet drops.
-    fn handle_packet(
-        &mut self,
-        packet_id: u16,
-        packet_buffer: &[u8],
-        received_data: &mut (Vec<u8>, usize),
-        should_retry: &mut bool,
-        is_catching_up: &mut bool,
-        bytes_read: usize,
-    ) -> Result<()> {
-        if packet_id <= self.received_packets_count as u16 {
-            self.socket.send(
-                &[packet_buffer[0], packet_buffer[1], PacketType::Acknowledgment as u8]
-            )?;
+    /// Receives the next frame from the socket.
+    /// Data frames are acknowledged and queued for `read`, control frames are returned to the caller.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Option<(u16, u8)>>` - The packet id and type of a control frame, or `None` if the frame was consumed.
+    fn recv_frame(&mut self) -> Result<Option<(u16, u8)>> {
+        self.check_for_sleep();
+        let bytes_read = self.socket.recv(&mut self.frame_buffer)?;
+        if let Some((capture, local, peer)) = &self.capture {
+            capture.record(*peer, *local, &self.frame_buffer[..bytes_read]);
+        }
+        if bytes_read < 3 {
+            return Ok(None);
+        }
+        self.waiting_since = None;
+        self.last_packet_at = Some(current_unix_millis());
+        self.handle_frame(bytes_read)
+    }
+
+    /// Handles the frame in `frame_buffer`, see `recv_frame`.
+    fn handle_frame(&mut self, mut bytes_read: usize) -> Result<Option<(u16, u8)>> {
+        let packet_id = u16::from_be_bytes([self.frame_buffer[0], self.frame_buffer[1]]);
+        let is_data = |packet_type: u8| packet_type == PacketType::Write as u8
+            || packet_type == PacketType::EndSession as u8
+            || packet_type == PacketType::Abort as u8;
+        let packet_number = self.received_packet_number(packet_id);
+        match (&self.cipher, self.frame_buffer[2]) {
+            (Some(cipher), x) if x == PacketType::Sealed as u8 => match cipher.open(&mut self.frame_buffer[..bytes_read], packet_number) {
+                Some(length) if length >= 3 => bytes_read = length,
+                _ => {
+                    debug!(target: "crypto", "Dropped packet {}, it doesn't open with the key of the session", packet_id);
+                    return Ok(None);
+                }
+            },
+            // the peer encrypts from a packet on which hasn't been read yet, kept (or sent again) until we're there
+            (None, x) if x == PacketType::Sealed as u8 => {
+                if self.early_sealed.len() < MAX_EARLY_SEALED {
+                    debug!(target: "crypto", "Kept sealed packet {} until the data stream is encrypted", packet_id);
+                    self.early_sealed.push(self.frame_buffer[..bytes_read].to_vec());
+                } else {
+                    debug!(target: "crypto", "Dropped sealed packet {}, the data stream isn't encrypted yet", packet_id);
+                }
+                return Ok(None);
+            }
+            (Some(_), x) if is_data(x) => {
+                debug!(target: "crypto", "Dropped packet {}, the data stream is encrypted", packet_id);
+                return Ok(None);
+            }
+            _ => {}
+        }
+        let packet_type = self.frame_buffer[2];
+        if packet_type == PacketType::Acknowledgment as u8 && bytes_read >= 5 {
+            self.peer_window = Some(u16::from_be_bytes([self.frame_buffer[3], self.frame_buffer[4]]));
+        }
+        if is_data(packet_type) {
+            self.handle_data_packet(packet_id, packet_type, bytes_read)?;
+            return Ok(None);
+        }
+        if packet_type == PacketType::Resume as u8 || packet_type == PacketType::ResumeAcknowledgment as u8 {
+            self.handle_resume_packet(packet_type, bytes_read);
+            return Ok(None);
+        }
+        Ok(Some((packet_id, packet_type)))
+    }
+
+    /// Starts re-validating the connection if the time since the last receive attempt suggests
+    /// the system slept, and repeats the resume request until the peer answers.
+    fn check_for_sleep(&mut self) {
+        let now = current_unix_millis();
+        let gap = now.saturating_sub(self.last_receive_attempt);
+        self.last_receive_attempt = now;
+        if gap > SLEEP_DETECTION_THRESHOLD_MS {
+            warn!(target: "transport", "No activity for {}s, the system probably slept. Re-validating the connection...", gap / 1000);
+            self.resume_attempts_left = RESUME_ATTEMPTS;
+            self.last_resume_request = 0;
+        }
+        if self.resume_attempts_left > 0 && now.saturating_sub(self.last_resume_request) >= RESUME_INTERVAL_MS {
+            self.resume_attempts_left -= 1;
+            self.last_resume_request = now;
+            self.send_resume_packet(PacketType::Resume);
+        }
+    }
+
+    /// Tells the peer how many packets we received, so it continues from there.
+    fn send_resume_packet(&self, packet_type: PacketType) {
+        let mut frame = vec![0, 0, packet_type as u8];
+        frame.extend_from_slice(&self.received_packets_count.to_be_bytes());
+        // the network might not be up again yet, the request is repeated anyway
+        let _ = self.send_frame(&frame);
+    }
+
+    /// Handles a resume request or the answer to ours.
+    fn handle_resume_packet(&mut self, packet_type: u8, bytes_read: usize) {
+        if bytes_read < 11 {
+            return;
         }
-        if packet_id == self.received_packets_count as u16 {
-            *should_retry = false;
+        let mut peer_received = [0u8; 8];
+        peer_received.copy_from_slice(&self.frame_buffer[3..11]);
+        let peer_received = u64::from_be_bytes(peer_received);
+
+        if packet_type == PacketType::Resume as u8 {
+            self.send_resume_packet(PacketType::ResumeAcknowledgment);
+        }
+        if self.resume_attempts_left > 0 {
+            info!(target: "transport", "Connection re-validated, the peer received {} packets so far", peer_received);
+            self.resume_attempts_left = 0;
+        }
+        self.resume_from(peer_received);
+    }
+
+    /// Forgets the packets the peer received and resends the others.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_received` - Number of our packets the peer received.
+    fn resume_from(&mut self, peer_received: u64) {
+        let sent_packets_count = self.sent_packets_count;
+        self.last_transmitted.retain(|packet_id, _| {
+            let distance = (sent_packets_count as u16).wrapping_sub(*packet_id) as u64;
+            sent_packets_count.wrapping_sub(distance) >= peer_received
+        });
+        self.last_go_back = None;
+        let mut start_time = current_unix_millis();
+        self.handle_resend_request(peer_received as u16, self.delay, &mut start_time);
+    }
+
+    /// Returns the number of a data packet of the peer in the session, its ID before wrapping around. IDs up to half
+    /// the ID space before the next expected one are taken as sent before it, the others as sent after it.
+    fn received_packet_number(&self, packet_id: u16) -> u64 {
+        let distance = packet_id.wrapping_sub(self.received_packets_count as u16) as i16;
+        self.received_packets_count.wrapping_add_signed(i64::from(distance))
+    }
+
+    /// Acknowledges a received data packet and queues it if it's the next expected one.
+    /// The IDs wrap around, so whether a packet is a duplicate or overtook a missing one
+    /// depends on its distance to the next expected ID, see `with_dedup_window`.
+    fn handle_data_packet(&mut self, packet_id: u16, packet_type: u8, bytes_read: usize) -> Result<()> {
+        self.largest_frame = self.largest_frame.max(bytes_read);
+        let distance = packet_id.wrapping_sub(self.received_packets_count as u16);
+        if distance == 0 {
+            self.received_packets_count += 1;
+            self.is_catching_up = false;
+            self.gap_is_loss = false;
+            self.received_queue.push_back((packet_type, self.frame_buffer[3..bytes_read].to_vec()));
+            if !self.reordered_packets.is_empty() {
+                return self.deliver_reordered_packets(packet_id);
+            }
+            // the end of the session is acknowledged right away, and so is everything once the window gets small,
+            // the sender waits for it
+            if packet_type == PacketType::Write as u8 && self.advertised_window() > self.ack_every.saturating_mul(2) {
+                let pending = self.pending_ack.get_or_insert(PendingAck { packet_id, count: 0, since: Instant::now() });
+                pending.packet_id = packet_id;
+                pending.count += 1;
+                if pending.count < self.ack_every {
+                    return Ok(());
+                }
+            }
+            self.pending_ack = None;
+        } else if distance > u16::MAX - self.dedup_window {
+            // acknowledged again, our acknowledgment might have been lost
+            self.statistics.duplicates += 1;
+        } else {
+            // this also covers an EndSession overtaking missing packets,
+            // which must not end the session before they were resent
+            self.statistics.reordered += 1;
+            if !self.gap_is_loss && (distance as usize) <= self.reorder_buffer()
+                && self.reordered_packets.len() < self.reorder_buffer()
+            {
+                self.reordered_packets.insert(packet_id, (packet_type, self.frame_buffer[3..bytes_read].to_vec()));
+            }
+            return self.handle_packet_drop(packet_id);
+        }
+        self.send_acknowledgment(packet_id)
+    }
+
+    /// Queues the packets of the reorder buffer which follow the packet which just arrived, and acknowledges
+    /// the last one. Asks for the next missing packet if the buffer still holds packets after it.
+    fn deliver_reordered_packets(&mut self, packet_id: u16) -> Result<()> {
+        let mut last_packet_id = packet_id;
+        while let Some(packet) = self.reordered_packets.remove(&(self.received_packets_count as u16)) {
+            last_packet_id = self.received_packets_count as u16;
             self.received_packets_count += 1;
-            received_data.1 = bytes_read - 3;
-        } else if packet_id > self.received_packets_count as u16 {
-            self.handle_packet_drop(packet_id, is_catching_up)?;
+            self.received_queue.push_back(packet);
         }
-        if packet_buffer[2] == PacketType::EndSession as u8 {
-            *should_retry = false;
+        debug!(target: "transport", "Delivered the reordered packets up to {}", last_packet_id);
+        self.pending_ack = None;
+        self.send_acknowledgment(last_packet_id)?;
+        if !self.reordered_packets.is_empty() {
+            self.is_catching_up = true;
+            self.request_resend()?;
         }
         Ok(())
     }
 
+    /// Acknowledges a data packet, and with it all packets before it.
+    fn send_acknowledgment(&self, packet_id: u16) -> Result<()> {
+        let packet_id = packet_id.to_be_bytes();
+        let window = self.advertised_window().to_be_bytes();
+        self.send_frame(&[packet_id[0], packet_id[1], PacketType::Acknowledgment as u8, window[0], window[1]])?;
+        Ok(())
+    }
+
+    /// Sends the acknowledgment held back, if any.
+    fn send_pending_acknowledgment(&mut self) -> Result<()> {
+        match self.pending_ack.take() {
+            Some(pending) => self.send_acknowledgment(pending.packet_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Receives the next frame like `recv_frame`, but sends the acknowledgment held back once it's due
+    /// instead of blocking past it.
+    fn receive_frame(&mut self) -> Result<Option<(u16, u8)>> {
+        let Some(due) = self.pending_ack.map(|pending| pending.since + ACK_DELAY) else {
+            return self.recv_frame();
+        };
+        let remaining = due.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() {
+            let timeout = self.socket.read_timeout()?;
+            self.socket.set_read_timeout(Some(timeout.map_or(remaining, |timeout| timeout.min(remaining))))?;
+            let result = self.recv_frame();
+            self.socket.set_read_timeout(timeout)?;
+            match result {
+                // nothing arrived meanwhile, so the acknowledgment is due
+                Err(NudgeError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                result => return result,
+            }
+        }
+        self.send_pending_acknowledgment()?;
+        self.recv_frame()
+    }
+
+    /// Forgets the packets an acknowledgment covers: the packet and all sent before it,
+    /// since the peer acknowledges in order. Acknowledgments of packets sent long ago (duplicates) cover nothing.
+    fn acknowledge(&mut self, packet_id: u16) {
+        let latest = (self.sent_packets_count as u16).wrapping_sub(1);
+        let acknowledged_age = latest.wrapping_sub(packet_id);
+        self.last_transmitted.retain(|id, _| latest.wrapping_sub(*id) < acknowledged_age);
+    }
+
+    /// Returns how many more packets we can take: the room left in the receive queue and the reorder buffer,
+    /// bounded by the number of frames fitting into the socket receive buffer.
+    fn advertised_window(&self) -> u16 {
+        let mut window = self.max_in_flight;
+        if let Some(receive_buffer) = self.receive_buffer {
+            window = window.min(receive_buffer / self.largest_frame.max(1));
+        }
+        window.saturating_sub(self.received_queue.len() + self.reordered_packets.len()).min(u16::MAX as usize) as u16
+    }
+
+    /// Returns how many packets may be unacknowledged before writes wait for the receiver.
+    fn send_window(&self) -> usize {
+        match self.peer_window {
+            Some(window) => (window as usize).clamp(1, self.max_in_flight),
+            None => self.max_in_flight,
+        }
+    }
+
+    /// Handles acknowledgments and resend requests for packets we sent.
+    fn handle_control_packet(&mut self, packet_id: u16, packet_type: u8) {
+        match packet_type {
+            x if x == PacketType::Acknowledgment as u8 => {
+                self.acknowledge(packet_id);
+            }
+            x if x == PacketType::ResendRequest as u8 => {
+                let mut start_time = current_unix_millis();
+                self.handle_resend_request(packet_id, self.delay, &mut start_time);
+            }
+            _ => {}
+        }
+    }
+
     /// Transmits a packet with retries if not acknowledged.
     fn transmit_packet(
         &mut self,
@@ -150,68 +862,129 @@ impl ReliableUdpSocket {
         flush: bool,
         exit_on_lost: bool,
     ) -> Result<()> {
+        let pace_span = span(Stage::Pace);
+        let departure = self.pacer.schedule(Instant::now(), Duration::from_micros(delay));
+        self.pacer.wait(departure);
+        drop(pace_span);
+        let send_span = span(Stage::Send);
         loop {
-            match self.socket.send(data_buffer) {
+            match self.send_datagram(data_buffer, departure) {
                 Ok(bytes_sent) => {
                     if bytes_sent == data_buffer.len() {
                         break;
                     }
                 }
-                Err(_) => continue,
+                Err(e) if is_transient(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        drop(send_span);
+        let _ack_wait_span = span(Stage::AckWait);
+        self.last_transmitted.insert(packet_index, data_buffer.to_vec());
+        self.process_pending_control_packets(delay)?;
+        let packet_index = self.follow_repack(packet_index);
+        if !self.last_transmitted.contains_key(&packet_index) {
+            // Already acknowledged while processing the pending control packets
+            return Ok(());
+        }
+
+        // Bound the retransmission buffers by waiting for the receiver to catch up
+        let flush = flush || self.last_transmitted.len() >= self.send_window();
+        self.wait_for_acknowledgment(packet_index, delay, flush, exit_on_lost)
+    }
+
+    /// Processes acknowledgments and resend requests that already arrived, without blocking.
+    fn process_pending_control_packets(&mut self, delay: u64) -> Result<()> {
+        self.socket.set_nonblocking(true)?;
+        let mut start_time = current_unix_millis();
+        while let Ok(frame) = self.recv_frame() {
+            let Some((packet_id, packet_type)) = frame else {
+                continue;
+            };
+            match packet_type {
+                x if x == PacketType::Acknowledgment as u8 => {
+                    self.acknowledge(packet_id);
+                }
+                x if x == PacketType::ResendRequest as u8 => {
+                    self.handle_resend_request(packet_id, delay, &mut start_time);
+                }
+                _ => continue,
             }
-            thread::sleep(Duration::from_micros(delay));
-            self.last_transmitted.insert(packet_index, data_buffer.to_vec());
-            break;
         }
-        self.wait_for_acknowledgment(packet_index, flush, exit_on_lost)
+        self.socket.set_nonblocking(false)?;
+        if self.pending_ack.is_some_and(|pending| pending.since.elapsed() >= ACK_DELAY) {
+            self.send_pending_acknowledgment()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the packet to wait for instead of `packet_index`: the latest one if the packets in flight
+    /// were renumbered meanwhile, since it carries the end of the same data.
+    fn follow_repack(&mut self, packet_index: u16) -> u16 {
+        match std::mem::take(&mut self.repacked) {
+            true => (self.sent_packets_count as u16).wrapping_sub(1),
+            false => packet_index,
+        }
     }
 
     /// Waits for an acknowledgment for the specified packet. Handles timeouts and retransmissions.
     fn wait_for_acknowledgment(
         &mut self,
-        packet_index: u16,
+        mut packet_index: u16,
+        delay: u64,
         flush: bool,
         exit_on_lost: bool,
     ) -> Result<()> {
-        let wait_for_ack = packet_index == 0xffff || flush;
+        if packet_index != 0xffff && !flush {
+            return Ok(());
+        }
         self.socket.set_read_timeout(Some(Duration::from_millis(1000)))?;
 
         let mut start_time = current_unix_millis();
-        let mut buffer = [0; 3];
-        let mut is_catching_up = false;
-
-        while wait_for_ack {
-            match self.socket.recv(&mut buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read != 3 {
-                        continue;
-                    }
 
-                    match buffer[2] {
+        loop {
+            packet_index = self.follow_repack(packet_index);
+            match self.receive_frame() {
+                Ok(None) => {
+                    // the peer won't acknowledge anything after aborting
+                    self.check_peer_abort()?;
+                    // the peer received the packet before we slept, see `resume_from`
+                    if !self.last_transmitted.contains_key(&packet_index) {
+                        self.last_transmitted.clear();
+                        return Ok(());
+                    }
+                }
+                Ok(Some((packet_id, packet_type))) => {
+                    match packet_type {
                         x if x == PacketType::Acknowledgment as u8 => {
-                            let acknowledged_packet_id = u16::from_be_bytes([buffer[0], buffer[1]]);
-                            self.last_transmitted.remove(&acknowledged_packet_id);
-                            if acknowledged_packet_id == packet_index {
+                            self.acknowledge(packet_id);
+                            if packet_id == packet_index {
                                 self.last_transmitted.clear();
                                 return Ok(());
                             }
                         }
                         x if x == PacketType::ResendRequest as u8 => {
-                            let request_packet_id = u16::from_be_bytes([buffer[0], buffer[1]]);
-                            self.handle_resend_request(request_packet_id, &mut is_catching_up);
+                            // The receiver asks for the packet after the one we're waiting for,
+                            // so it got everything (the acknowledgment itself was lost)
+                            if packet_id == packet_index.wrapping_add(1) {
+                                self.last_transmitted.clear();
+                                return Ok(());
+                            }
+                            self.handle_resend_request(packet_id, delay, &mut start_time);
                         }
                         _ => continue,
                     }
                 }
                 Err(_) => {
-                    if current_unix_millis() - start_time > 5000 && exit_on_lost {
-                        println!("WARN: No acknowledgment received within 5 seconds, potential packet loss");
+                    self.check_peer_timeout()?;
+                    if current_unix_millis().saturating_sub(start_time) > 5000 && exit_on_lost {
+                        warn!(target: "transport", "No acknowledgment received within 5 seconds, potential packet loss");
                         break; // Exit if no response and exiting on loss is specified.
                     }
-                    if current_unix_millis() - start_time > 10000 {
-                        println!("WARN: Connection may be disrupted. It's been 10 seconds since the last packet was received. Attempting to resend...");
+                    if current_unix_millis().saturating_sub(start_time) > 10000 {
+                        warn!(target: "transport", "Connection may be disrupted. It's been 10 seconds since the last packet was received. Attempting to resend...");
                         if let Some(data) = self.last_transmitted.get(&packet_index).cloned() {
-                            self.resend_packet(&data, &mut start_time);
+                            self.resend_packet(&data, Instant::now(), &mut start_time);
                             start_time = current_unix_millis();
                         } else {
                             break;
@@ -223,27 +996,142 @@ impl ReliableUdpSocket {
         Ok(())
     }
 
-    /// Handles packet resend requests from the receiver, using the specified packet ID.
-    fn handle_resend_request(&mut self, packet_index: u16, is_catching_up: &mut bool) {
-        *is_catching_up = true;
+    /// Handles packet resend requests from the receiver by resending the requested packet
+    /// and all unacknowledged packets after it (go-back-N).
+    fn handle_resend_request(&mut self, packet_index: u16, delay: u64, start_time: &mut u64) {
+        // Resend requests for the same packet pile up while the resent packets are in flight
+        if let Some((last_index, last_time)) = self.last_go_back {
+            if last_index == packet_index && current_unix_millis().saturating_sub(last_time) < GO_BACK_INTERVAL_MS {
+                return;
+            }
+        }
+
+        let go_backs = match self.stuck_packet {
+            Some((stuck_index, go_backs)) if stuck_index == packet_index => go_backs + 1,
+            _ => 1,
+        };
+        self.stuck_packet = Some((packet_index, go_backs));
+        if go_backs >= BLACK_HOLE_GO_BACKS {
+            if let Some(chunk_size) = self.black_hole_fallback(packet_index) {
+                return self.repack(packet_index, chunk_size, delay, start_time);
+            }
+        }
 
+        debug!(target: "transport", "Peer asked for packet {}, going back", packet_index);
+        let mut index = packet_index;
         // Clone the packet data first to avoid borrowing issues
-        if let Some(packet_data) = self.last_transmitted.get(&packet_index).cloned() {
-            let mut current_time = current_unix_millis();
-            self.resend_packet(&packet_data, &mut current_time);
+        while let Some(packet_data) = self.last_transmitted.get(&index).cloned() {
+            let departure = self.pacer.schedule(Instant::now(), Duration::from_micros(delay));
+            self.pacer.wait(departure);
+            self.resend_packet(&packet_data, departure, start_time);
+            index = index.wrapping_add(1);
+        }
+        self.last_go_back = Some((packet_index, current_unix_millis()));
+    }
+
+    /// Returns the data size to fall back to if the packet the peer keeps asking for is larger,
+    /// and all packets from it on are still kept to be renumbered.
+    fn black_hole_fallback(&self, packet_index: u16) -> Option<usize> {
+        let chunk_size = self.fallback_chunk_size.filter(|_| self.chunk_limit.is_none())?;
+        let packet_size = self.last_transmitted.get(&packet_index)?.len();
+        let overhead = if self.cipher.is_some() { 3 + SEAL_OVERHEAD } else { 3 };
+        let in_flight = (self.sent_packets_count as u16).wrapping_sub(packet_index);
+        let complete = (0..in_flight).all(|offset| self.last_transmitted.contains_key(&packet_index.wrapping_add(offset)));
+        (packet_size > chunk_size + overhead && complete).then_some(chunk_size)
+    }
+
+    /// Splits the packets from the one the peer keeps asking for on into packets with at most `chunk_size` bytes
+    /// of data, and sends them with the IDs from it on. Later writes are split the same way.
+    fn repack(&mut self, packet_index: u16, chunk_size: usize, delay: u64, start_time: &mut u64) {
+        let mut packets = Vec::new();
+        let mut index = packet_index;
+        while let Some(packet_data) = self.last_transmitted.remove(&index) {
+            packets.push(packet_data);
+            index = index.wrapping_add(1);
+        }
+        warn!(
+            target: "transport",
+            "Packet {} ({} bytes) didn't get through after {} attempts, the path seems to drop large datagrams. \
+             Continuing with chunks of {} bytes",
+            packet_index, packets[0].len(), BLACK_HOLE_GO_BACKS, chunk_size
+        );
+        self.chunk_limit = Some(chunk_size);
+        self.repacked = true;
+        self.sent_packets_count -= packets.len() as u64;
+        for (packet_number, packet_data) in (self.sent_packets_count..).zip(packets) {
+            // sealed packets are split up unsealed and sealed again, packets sent before encrypting stay readable
+            let (packet_data, sealed) = match &self.cipher {
+                Some(cipher) if packet_data[2] == PacketType::Sealed as u8 => {
+                    (cipher.open_sent(&packet_data, packet_number).expect("sealed with our key"), true)
+                }
+                _ => (packet_data, false),
+            };
+            let packet_type = packet_data[2];
+            let data = &packet_data[3..];
+            let pieces: Vec<&[u8]> = match packet_type == PacketType::Write as u8 && !data.is_empty() {
+                true => data.chunks(chunk_size).collect(),
+                false => vec![data],
+            };
+            for piece in pieces {
+                let mut data_buffer = Vec::with_capacity(piece.len() + 3);
+                data_buffer.extend_from_slice(&(self.sent_packets_count as u16).to_be_bytes());
+                data_buffer.push(packet_type);
+                data_buffer.extend_from_slice(piece);
+                if let Some(cipher) = self.cipher.as_mut().filter(|_| sealed) {
+                    data_buffer = cipher.seal(&data_buffer, PacketType::Sealed as u8, self.sent_packets_count);
+                }
+                let departure = self.pacer.schedule(Instant::now(), Duration::from_micros(delay));
+                self.pacer.wait(departure);
+                self.resend_packet(&data_buffer, departure, start_time);
+                self.last_transmitted.insert(self.sent_packets_count as u16, data_buffer);
+                self.sent_packets_count += 1;
+            }
+        }
+        // the resend requests for the packet still on their way are answered by the new packets
+        self.last_go_back = Some((packet_index, current_unix_millis()));
+        self.stuck_packet = None;
+    }
+
+    /// Hands a data packet to the socket, to leave at its departure if the kernel paces by it (SO_TXTIME).
+    fn send_datagram(&self, data: &[u8], departure: Instant) -> std::io::Result<usize> {
+        if !self.pacer.txtime() {
+            return self.send_frame(data);
+        }
+        let bytes_sent = send_at(&self.socket, data, departure)?;
+        self.record_sent(data);
+        Ok(bytes_sent)
+    }
+
+    /// Sends a frame right away.
+    fn send_frame(&self, data: &[u8]) -> std::io::Result<usize> {
+        let bytes_sent = self.socket.send(data)?;
+        self.record_sent(data);
+        Ok(bytes_sent)
+    }
+
+    /// Records a frame sent to the capture, if any.
+    fn record_sent(&self, data: &[u8]) {
+        if let Some((capture, local, peer)) = &self.capture {
+            capture.record(*local, *peer, data);
         }
     }
 
     /// Resends a packet and resets the start time for response waiting.
-    fn resend_packet(&mut self, packet_data: &[u8], start_time: &mut u64) {
+    fn resend_packet(&mut self, packet_data: &[u8], departure: Instant, start_time: &mut u64) {
+        self.statistics.retransmitted += 1;
         loop {
-            match self.socket.send(packet_data) {
+            match self.send_datagram(packet_data, departure) {
                 Ok(bytes_sent) => {
                     if bytes_sent == packet_data.len() {
                         break; // Break if packet is sent successfully
                     }
                 }
-                Err(_) => continue, // Retry on send error
+                Err(e) if is_transient(&e) => continue, // Retry on send error
+                Err(e) => {
+                    // the packet stays unacknowledged, the next go-back or the peer timeout deals with it
+                    warn!(target: "transport", "Cannot resend a packet: {}", e);
+                    break;
+                }
             }
             thread::sleep(Duration::from_millis(4)); // Minimal delay between retries
         }
@@ -251,21 +1139,53 @@ impl ReliableUdpSocket {
     }
 
     /// Detects and handles the event of packet drop based on the ID discrepancies.
-    fn handle_packet_drop(&mut self, packet_id: u16, is_catching_up: &mut bool) -> Result<()> {
-        if !*is_catching_up {
-            println!(
-                "WARN: A packet was dropped: received ID {} is more recent than the expected ID {}",
+    fn handle_packet_drop(&mut self, packet_id: u16) -> Result<()> {
+        if !self.is_catching_up {
+            warn!(
+                target: "transport",
+                "A packet was dropped: received ID {} is more recent than the expected ID {}",
                 packet_id, self.received_packets_count
             );
-            *is_catching_up = true;
+            self.is_catching_up = true;
         }
-        // Request resend for the missing packet
-        let expected_packet_id = (self.received_packets_count as u16).to_be_bytes();
-        self.socket.send(&[expected_packet_id[0], expected_packet_id[1], PacketType::ResendRequest as u8])?;
+        self.request_resend()
+    }
+
+    /// Requests a resend of the next expected packet, at most once per `RESEND_REQUEST_INTERVAL_MS`.
+    fn request_resend(&mut self) -> Result<()> {
+        let expected_packet_index = self.received_packets_count as u16;
+        let now = current_unix_millis();
+        if let Some((last_index, last_time)) = self.last_resend_request {
+            if last_index == expected_packet_index && now.saturating_sub(last_time) < RESEND_REQUEST_INTERVAL_MS {
+                return Ok(());
+            }
+            // the gap outlasted the last request, so the packet was lost rather than overtaken
+            if last_index == expected_packet_index && !self.gap_is_loss {
+                self.gap_is_loss = true;
+                self.reordered_packets.clear();
+            }
+        }
+        self.last_resend_request = Some((expected_packet_index, now));
+        self.resend_requests += 1;
+        debug!(target: "transport", "Asking the peer to resend packet {}", expected_packet_index);
+
+        let expected_packet_id = expected_packet_index.to_be_bytes();
+        self.send_frame(&[expected_packet_id[0], expected_packet_id[1], PacketType::ResendRequest as u8])?;
         Ok(())
     }
 }
 
+/// Returns whether sending a datagram may succeed when tried again right away: the socket buffer was full,
+/// the call was interrupted, or an ICMP error caused by an earlier datagram was reported (which clears it).
+/// Anything else, e.g. a send denied by the sandbox, would fail the same way forever.
+fn is_transient(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ENOBUFS) {
+        return true;
+    }
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::ConnectionRefused)
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::UdpSocket;
@@ -278,6 +1198,417 @@ mod tests {
         let reliable_socket = ReliableUdpSocket::new(socket.try_clone().unwrap());
         assert_eq!(reliable_socket.sent_packets_count, 0);
         assert_eq!(reliable_socket.received_packets_count, 0);
+        assert_eq!(reliable_socket.max_in_flight, MemoryProfile::DEFAULT.max_in_flight);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&std::io::Error::from(ErrorKind::WouldBlock)));
+        assert!(is_transient(&std::io::Error::from(ErrorKind::ConnectionRefused)));
+        #[cfg(unix)]
+        assert!(is_transient(&std::io::Error::from_raw_os_error(libc::ENOBUFS)));
+        assert!(!is_transient(&std::io::Error::from(ErrorKind::PermissionDenied)));
+    }
+
+    #[test]
+    fn test_with_max_in_flight() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let reliable_socket = ReliableUdpSocket::new(socket).with_max_in_flight(0);
+        assert_eq!(reliable_socket.max_in_flight, 1);
+    }
+
+    #[test]
+    fn test_read_and_write_in_both_directions() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+        socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        socket_b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut connection = ReliableUdpSocket::new(socket_b);
+            let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+            assert_eq!(&data[..bytes_read], b"ping");
+            connection.write_and_flush(b"pong", true, 0).unwrap();
+            connection.end();
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        connection.write_and_flush(b"ping", true, 0).unwrap();
+        let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+        assert_eq!(&data[..bytes_read], b"pong");
+        assert_eq!(connection.read(&[0; 64]).unwrap().1, 0);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+        socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        socket_b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut connection = ReliableUdpSocket::new(socket_b);
+            // acknowledged while the other side holds its data back
+            let started = Instant::now();
+            connection.write_and_flush(b"ready", true, 0).unwrap();
+            assert!(started.elapsed() < Duration::from_millis(400));
+            let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+            assert_eq!(&data[..bytes_read], b"data");
+            assert_eq!(connection.read(&[0; 64]).unwrap().1, 0);
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        connection.keep_alive(Duration::from_millis(500)).unwrap();
+        let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+        assert_eq!(&data[..bytes_read], b"ready");
+        connection.write_and_flush(b"data", true, 0).unwrap();
+        connection.end();
+        peer.join().unwrap();
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn test_read_and_write_encrypted() {
+        use crate::utils::encryption::{exchange_for_test, generate_salt};
+        use crate::utils::passphrase::Passphrase;
+
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+        socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        socket_b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let passphrase = Passphrase::from("beaver-canoe-tulip".to_string());
+        let (sender_cipher, receiver_cipher) = exchange_for_test(&passphrase, &generate_salt());
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+
+        let peer = thread::spawn(move || {
+            let mut connection = ReliableUdpSocket::new(socket_b);
+            connection.start_encryption(receiver_cipher).unwrap();
+            let mut received = Vec::new();
+            loop {
+                let (chunk, bytes_read) = connection.read(&[0; 64]).unwrap();
+                if bytes_read == 0 {
+                    break;
+                }
+                received.extend_from_slice(&chunk[..bytes_read]);
+                if received.len() == expected.len() {
+                    connection.write_and_flush(b"done", true, 0).unwrap();
+                }
+            }
+            assert_eq!(received, expected);
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        connection.start_encryption(sender_cipher).unwrap();
+        connection.write_and_flush(&data, true, 0).unwrap();
+        let (reply, bytes_read) = connection.read(&[0; 64]).unwrap();
+        assert_eq!(&reply[..bytes_read], b"done");
+        connection.end();
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_received_packet_number() {
+        let mut connection = ReliableUdpSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        connection.received_packets_count = 0x1_0005;
+        assert_eq!(connection.received_packet_number(5), 0x1_0005);
+        // duplicates and packets which overtook missing ones
+        assert_eq!(connection.received_packet_number(3), 0x1_0003);
+        assert_eq!(connection.received_packet_number(0xfffe), 0xfffe);
+        assert_eq!(connection.received_packet_number(0x10), 0x1_0010);
+    }
+
+    #[test]
+    fn test_peer_timeout() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(silent_peer.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let mut connection = ReliableUdpSocket::new(socket_a).with_peer_timeout(Duration::from_millis(300));
+        assert!(matches!(connection.read(&[0; 64]), Err(NudgeError::PeerTimeout(_))));
+        assert!(matches!(connection.write_and_flush(b"ping", true, 0), Err(NudgeError::PeerTimeout(_))));
+    }
+
+    #[test]
+    fn test_flow_control_window() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket).with_max_in_flight(8);
+        reliable_socket.receive_buffer = Some(1000);
+        reliable_socket.largest_frame = 200;
+        assert_eq!(reliable_socket.advertised_window(), 5);
+        reliable_socket.received_queue.push_back((PacketType::Write as u8, vec![0; 197]));
+        assert_eq!(reliable_socket.advertised_window(), 4);
+
+        // the peer's window limits the packets in flight, but never below one
+        assert_eq!(reliable_socket.send_window(), 8);
+        reliable_socket.peer_window = Some(3);
+        assert_eq!(reliable_socket.send_window(), 3);
+        reliable_socket.peer_window = Some(0);
+        assert_eq!(reliable_socket.send_window(), 1);
+        reliable_socket.peer_window = Some(u16::MAX);
+        assert_eq!(reliable_socket.send_window(), 8);
+    }
+
+    #[test]
+    fn test_acknowledgments_advertise_window() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+        socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        socket_b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut connection = ReliableUdpSocket::new(socket_b).with_max_in_flight(16);
+            for _ in 0..3 {
+                connection.read(&[0; 64]).unwrap();
+            }
+            connection.end();
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        for _ in 0..3 {
+            connection.write_and_flush(b"data", true, 0).unwrap();
+        }
+        assert!(connection.peer_window.is_some_and(|window| (1..=16).contains(&window)));
+        assert_eq!(connection.read(&[0; 64]).unwrap().1, 0);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_duplicates_and_reordered_packets() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(socket.local_addr().unwrap()).unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket).with_dedup_window(16).with_reorder_buffer(0);
+        reliable_socket.frame_buffer[..4].copy_from_slice(&[0, 0, PacketType::Write as u8, 42]);
+        // the IDs wrapped around, 65535 was received before
+        reliable_socket.received_packets_count = 65536;
+
+        reliable_socket.handle_data_packet(65535, PacketType::Write as u8, 4).unwrap();
+        reliable_socket.handle_data_packet(1, PacketType::Write as u8, 4).unwrap();
+        reliable_socket.handle_data_packet(0, PacketType::Write as u8, 4).unwrap();
+        // too far back for the window, so it's taken as overtaking the missing packet 1
+        reliable_socket.handle_data_packet(65000, PacketType::Write as u8, 4).unwrap();
+
+        assert_eq!(reliable_socket.received_queue.len(), 1);
+        assert_eq!(reliable_socket.received_packets_count, 65537);
+        assert_eq!(
+            reliable_socket.statistics(),
+            DatagramStatistics { duplicates: 1, reordered: 2, retransmitted: 0 }
+        );
+        // both missing packets were asked for
+        assert_eq!(reliable_socket.receive_state().resend_requests, 2);
+    }
+
+    #[test]
+    fn test_reorder_buffer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(socket.local_addr().unwrap()).unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket).with_max_in_flight(64).with_reorder_buffer(4);
+        assert_eq!(reliable_socket.reorder_buffer(), 4);
+        let receive = |socket: &mut ReliableUdpSocket, packet_id: u16| {
+            socket.frame_buffer[..4].copy_from_slice(&[0, 0, PacketType::Write as u8, packet_id as u8]);
+            socket.handle_data_packet(packet_id, PacketType::Write as u8, 4).unwrap();
+        };
+
+        // 2 and 3 overtook 1, 9 is too far ahead to be kept
+        receive(&mut reliable_socket, 0);
+        receive(&mut reliable_socket, 2);
+        receive(&mut reliable_socket, 3);
+        receive(&mut reliable_socket, 9);
+        assert_eq!(reliable_socket.reordered_packets.len(), 2);
+        receive(&mut reliable_socket, 1);
+        assert_eq!(reliable_socket.received_packets_count, 4);
+        let data: Vec<u8> = reliable_socket.received_queue.iter().map(|(_, data)| data[0]).collect();
+        assert_eq!(data, vec![0, 1, 2, 3]);
+        assert!(reliable_socket.reordered_packets.is_empty());
+
+        // once the gap outlasts a resend request, the packet is taken as lost
+        receive(&mut reliable_socket, 5);
+        assert_eq!(reliable_socket.reordered_packets.len(), 1);
+        reliable_socket.last_resend_request = Some((4, 0));
+        receive(&mut reliable_socket, 6);
+        assert!(reliable_socket.reordered_packets.is_empty());
+        assert!(reliable_socket.gap_is_loss);
+        receive(&mut reliable_socket, 4);
+        assert!(!reliable_socket.gap_is_loss);
+        assert_eq!(reliable_socket.received_packets_count, 5);
+    }
+
+    #[test]
+    fn test_acknowledge() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket);
+        // packets 65534 to 65537 are unacknowledged, their IDs wrapped around
+        reliable_socket.sent_packets_count = 65538;
+        for packet_id in [65534u16, 65535, 0, 1] {
+            reliable_socket.last_transmitted.insert(packet_id, vec![0, 0, PacketType::Write as u8]);
+        }
+        // a late acknowledgment of a packet sent long ago covers nothing
+        reliable_socket.acknowledge(60000);
+        assert_eq!(reliable_socket.last_transmitted.len(), 4);
+        reliable_socket.acknowledge(0);
+        let remaining: Vec<u16> = reliable_socket.last_transmitted.keys().copied().collect();
+        assert_eq!(remaining, vec![1]);
+    }
+
+    #[test]
+    fn test_delayed_acknowledgments() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(socket.local_addr().unwrap()).unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket).with_max_in_flight(64).with_ack_every(4);
+        reliable_socket.frame_buffer[..4].copy_from_slice(&[0, 0, PacketType::Write as u8, 42]);
+        for packet_id in 0..3 {
+            reliable_socket.handle_data_packet(packet_id, PacketType::Write as u8, 4).unwrap();
+        }
+        assert!(reliable_socket.pending_ack.is_some_and(|pending| pending.packet_id == 2 && pending.count == 3));
+        // the fourth packet sends the acknowledgment for all of them
+        reliable_socket.handle_data_packet(3, PacketType::Write as u8, 4).unwrap();
+        assert!(reliable_socket.pending_ack.is_none());
+        reliable_socket.handle_data_packet(4, PacketType::Write as u8, 4).unwrap();
+        // the end of the session is acknowledged right away
+        reliable_socket.handle_data_packet(5, PacketType::EndSession as u8, 3).unwrap();
+        assert!(reliable_socket.pending_ack.is_none());
+
+        let mut frame = [0u8; 8];
+        let acknowledged: Vec<u16> = (0..2)
+            .map(|_| {
+                reliable_socket.socket.recv(&mut frame).unwrap();
+                assert_eq!(frame[2], PacketType::Acknowledgment as u8);
+                u16::from_be_bytes([frame[0], frame[1]])
+            })
+            .collect();
+        assert_eq!(acknowledged, vec![3, 5]);
+    }
+
+    #[test]
+    fn test_chunk_fallback() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(peer.local_addr().unwrap()).unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket).with_max_in_flight(64).with_chunk_fallback(4);
+        for data in [b"0123456789", b"abcdefghij"] {
+            reliable_socket.write_and_flush(data, false, 0).unwrap();
+        }
+        assert_eq!(reliable_socket.chunk_limit(), None);
+
+        // the peer keeps asking for the first packet, which never arrives
+        let mut start_time = current_unix_millis();
+        for _ in 0..BLACK_HOLE_GO_BACKS {
+            reliable_socket.last_go_back = None;
+            reliable_socket.handle_resend_request(0, 0, &mut start_time);
+        }
+        assert_eq!(reliable_socket.chunk_limit(), Some(4));
+        assert_eq!(reliable_socket.follow_repack(1), 5);
+        reliable_socket.write_and_flush(b"klmnop", false, 0).unwrap();
+
+        assert_eq!(reliable_socket.sent_packets_count, 8);
+        let data: Vec<u8> = (0..8u16)
+            .flat_map(|packet_id| {
+                let packet_data = &reliable_socket.last_transmitted[&packet_id];
+                assert_eq!(&packet_data[..3], &[0, packet_id as u8, PacketType::Write as u8]);
+                assert!(packet_data.len() <= 4 + 3);
+                packet_data[3..].to_vec()
+            })
+            .collect();
+        assert_eq!(data, b"0123456789abcdefghijklmnop");
+    }
+
+    #[test]
+    fn test_resume_from() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(socket.local_addr().unwrap()).unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket);
+        // packets 65534, 65535, 65536 and 65537 are unacknowledged, their IDs wrapped around
+        reliable_socket.sent_packets_count = 65538;
+        for packet_id in [65534u16, 65535, 0, 1] {
+            reliable_socket.last_transmitted.insert(packet_id, vec![0, 0, PacketType::Write as u8]);
+        }
+        reliable_socket.resume_from(65536);
+        let mut remaining: Vec<u16> = reliable_socket.last_transmitted.keys().copied().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_resume_after_sleep() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+        socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        socket_b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut connection = ReliableUdpSocket::new(socket_b);
+            for expected in [b"before", b"after!"] {
+                let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+                assert_eq!(&data[..bytes_read], expected);
+            }
+            connection.end();
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        connection.write_and_flush(b"before", true, 0).unwrap();
+        // pretend the system slept for a minute
+        connection.last_receive_attempt -= 60_000;
+        connection.write_and_flush(b"after!", true, 0).unwrap();
+        assert_eq!(connection.read(&[0; 64]).unwrap().1, 0);
+        // the peer may answer the resume request after ending the session
+        connection.try_read(&[0; 64], Duration::from_millis(200)).unwrap();
+        assert_eq!(connection.resume_attempts_left, 0);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_abort() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+        socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        socket_b.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let peer = thread::spawn(move || {
+            let mut connection = ReliableUdpSocket::new(socket_b);
+            connection.write_and_flush(b"partial", false, 0).unwrap();
+            connection.abort("disk on fire");
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        assert_eq!(connection.read(&[0; 64]).unwrap().1, 7);
+        let result = connection.read(&[0; 64]);
+        assert!(matches!(result, Err(NudgeError::TransferAborted(reason)) if reason == "disk on fire"));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_after_peer_abort() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(socket.local_addr().unwrap()).unwrap();
+        let mut connection = ReliableUdpSocket::new(socket);
+        connection.received_queue.push_back((PacketType::Abort as u8, b"pipe closed".to_vec()));
+        let result = connection.write_and_flush(b"data", false, 0);
+        assert!(matches!(result, Err(NudgeError::TransferAborted(reason)) if reason == "pipe closed"));
+    }
+
+    #[test]
+    fn test_try_read_times_out() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        let received = connection.try_read(&[0; 64], Duration::from_millis(10)).unwrap();
+        assert!(received.is_none());
     }
 
     #[test]
@@ -289,4 +1620,74 @@ mod tests {
         let result = reliable_socket.internal_write(&large_data, PacketType::Write, false, false, 10);
         assert!(matches!(result, Err(NudgeError::DataPacketLimitExceeded(_))));
     }
+
+    /// Receives the frames arriving at a plain socket until none arrive for a while.
+    fn receive_frames(socket: &UdpSocket) -> Vec<(u16, u8)> {
+        let mut frames = Vec::new();
+        let mut buffer = [0; 64];
+        while let Ok(bytes_read) = socket.recv(&mut buffer) {
+            if bytes_read >= 3 {
+                frames.push((u16::from_be_bytes([buffer[0], buffer[1]]), buffer[2]));
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn test_resend_request_goes_back() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(peer.local_addr().unwrap()).unwrap();
+        peer.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        connection.write_and_flush(b"one", false, 0).unwrap();
+        connection.write_and_flush(b"two", false, 0).unwrap();
+        assert_eq!(receive_frames(&peer), vec![(0, PacketType::Write as u8), (1, PacketType::Write as u8)]);
+
+        // the peer lost the first packet, so it's resent along with all packets after it
+        peer.send(&[0, 0, PacketType::ResendRequest as u8]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        connection.write_and_flush(b"three", false, 0).unwrap();
+        let mut ids: Vec<u16> = receive_frames(&peer).into_iter().map(|(packet_id, _)| packet_id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_end_session_waits_for_missing_packets() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket_a.connect(peer.local_addr().unwrap()).unwrap();
+        peer.connect(socket_a.local_addr().unwrap()).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let frames: [&[u8]; 3] = [&[0, 0, 0, b'a'], &[0, 1, 0, b'b'], &[0, 2, PacketType::EndSession as u8]];
+
+        let sender = thread::spawn(move || {
+            // the first packet is lost, the end of the session overtakes it
+            peer.send(frames[1]).unwrap();
+            peer.send(frames[2]).unwrap();
+            let mut buffer = [0; 64];
+            loop {
+                let bytes_read = peer.recv(&mut buffer).unwrap_or(0);
+                if bytes_read >= 3 && buffer[..3] == [0, 0, PacketType::ResendRequest as u8] {
+                    break;
+                }
+            }
+            for frame in frames {
+                peer.send(frame).unwrap();
+            }
+        });
+
+        let mut connection = ReliableUdpSocket::new(socket_a);
+        let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+        assert_eq!(&data[..bytes_read], b"a");
+        let (data, bytes_read) = connection.read(&[0; 64]).unwrap();
+        assert_eq!(&data[..bytes_read], b"b");
+        assert_eq!(connection.read(&[0; 64]).unwrap().1, 0);
+        sender.join().unwrap();
+    }
 }
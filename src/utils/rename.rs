@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{NudgeError, Result};
+
+/// Default pattern for renamed downloads, like browsers do, e.g. "report (1).pdf"
+pub const DEFAULT_RENAME_PATTERN: &str = "{stem} ({n}).{ext}";
+
+/// How many names are tried before giving up
+const MAX_ATTEMPTS: u32 = 10_000;
+
+/// Pattern for the names of downloads whose file already exists, e.g. "{stem} ({n}).{ext}".
+///
+/// Variables: `{name}`, `{stem}`, `{ext}` (without the dot) and the counter `{n}`.
+/// If the file has no extension, the dot in front of `{ext}` is left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenamePattern(String);
+
+impl RenamePattern {
+    /// Parses a pattern.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidRenamePattern` if the pattern uses an unknown variable,
+    /// doesn't contain `{n}` or contains a path separator.
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = |reason: &str| NudgeError::InvalidRenamePattern(format!("{}: {}", input, reason));
+        if input.contains(['/', '\\']) {
+            return Err(invalid("must not contain directories"));
+        }
+        let mut rest = input;
+        let mut has_counter = false;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| invalid("unbalanced {"))? + start;
+            match &rest[start + 1..end] {
+                "n" => has_counter = true,
+                "name" | "stem" | "ext" => {}
+                name => return Err(invalid(&format!("unknown variable {{{}}}", name))),
+            }
+            rest = &rest[end + 1..];
+        }
+        if !has_counter {
+            return Err(invalid("must contain the counter {n}"));
+        }
+        Ok(RenamePattern(input.to_string()))
+    }
+
+    /// Returns the path itself if no file exists there yet,
+    /// otherwise the first path in the same directory named by the pattern which doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if all names up to the maximum counter are taken.
+    pub fn unique_path(&self, path: &Path) -> Result<PathBuf> {
+        self.unique_path_with(path, |candidate| candidate.exists())
+    }
+
+    fn unique_path_with(&self, path: &Path, exists: impl Fn(&Path) -> bool) -> Result<PathBuf> {
+        if !exists(path) {
+            return Ok(path.to_path_buf());
+        }
+        let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        (1..=MAX_ATTEMPTS)
+            .map(|n| path.with_file_name(self.file_name(&file_name, n)))
+            .find(|candidate| !exists(candidate))
+            .ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} and {} renamed copies of it already exist", path.display(), MAX_ATTEMPTS),
+            ).into())
+    }
+
+    /// Expands the pattern for a file name and counter.
+    fn file_name(&self, file_name: &str, n: u32) -> String {
+        let (stem, ext) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+            _ => (file_name, ""),
+        };
+        let mut expanded = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').expect("checked by parse") + start;
+            expanded.push_str(&rest[..start]);
+            match &rest[start + 1..end] {
+                "n" => expanded.push_str(&n.to_string()),
+                "name" => expanded.push_str(file_name),
+                "stem" => expanded.push_str(stem),
+                _ if ext.is_empty() => {
+                    // "{stem} ({n}).{ext}" shouldn't leave a trailing dot
+                    if expanded.ends_with('.') {
+                        expanded.pop();
+                    }
+                }
+                _ => expanded.push_str(ext),
+            }
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(RenamePattern::parse(DEFAULT_RENAME_PATTERN).is_ok());
+        assert!(RenamePattern::parse("{name}.{n}").is_ok());
+        assert!(RenamePattern::parse("{stem}.{ext}").is_err());
+        assert!(RenamePattern::parse("{stem}-{count}").is_err());
+        assert!(RenamePattern::parse("{stem}-{n").is_err());
+        assert!(RenamePattern::parse("../{name}.{n}").is_err());
+    }
+
+    #[test]
+    fn test_file_name() {
+        let pattern = RenamePattern::parse(DEFAULT_RENAME_PATTERN).unwrap();
+        assert_eq!(pattern.file_name("report.pdf", 1), "report (1).pdf");
+        assert_eq!(pattern.file_name("a.tar.gz", 2), "a.tar (2).gz");
+        assert_eq!(pattern.file_name("README", 3), "README (3)");
+        assert_eq!(pattern.file_name(".bashrc", 1), ".bashrc (1)");
+        assert_eq!(RenamePattern::parse("{name}.{n}").unwrap().file_name("report.pdf", 1), "report.pdf.1");
+    }
+
+    #[test]
+    fn test_unique_path() {
+        let pattern = RenamePattern::parse(DEFAULT_RENAME_PATTERN).unwrap();
+        let taken = [PathBuf::from("dl/report.pdf"), PathBuf::from("dl/report (1).pdf")];
+        let exists = |path: &Path| taken.iter().any(|taken| taken == path);
+        assert_eq!(pattern.unique_path_with(Path::new("dl/other.pdf"), exists).unwrap(), PathBuf::from("dl/other.pdf"));
+        assert_eq!(pattern.unique_path_with(Path::new("dl/report.pdf"), exists).unwrap(), PathBuf::from("dl/report (2).pdf"));
+        assert!(pattern.unique_path_with(Path::new("dl/report.pdf"), |_| true).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::error::{NudgeError, Result};
+use crate::models::*;
+use crate::utils::{AnonymousString, new_registration_id};
+use crate::utils::blind::{generate_passphrase, relay_passphrase};
+use crate::utils::encode_hex;
+use crate::utils::encryption::{self, generate_salt};
+use crate::utils::passphrase::Passphrase;
+use crate::utils::port_mapping::mapped_addr;
+use crate::utils::resolver::RelayBinding;
+use crate::utils::serialize::{receive_and_parse_and_expect, receive_expected, relay_timeout, request, serialize_and_send};
+use crate::utils::socket::connect_to_peer;
+use crate::utils::ui::{is_quiet, style, success_mark};
+
+/// Longest pause between two heartbeats while watching the relay
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pause between two heartbeats while an offer waits for a receiver, short enough to keep the NAT mapping open
+const OFFER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(25);
+
+/// Result of pairing with a peer running the same command
+pub struct Pairing {
+    /// Display name or hostname of the peer (optional)
+    pub peer_host: AnonymousString,
+
+    /// Whether we registered the passphrase, i.e. reached the relay before the peer
+    pub is_host: bool,
+
+    /// The passphrase as given or generated, the key of the data stream is derived from it
+    pub passphrase: Passphrase<'static>,
+
+    /// Salt of the key exchange (hex), chosen by the joining side (`None` if it can't encrypt)
+    pub encryption_salt: Option<String>,
+
+    /// Whether the joining side agrees on the key with a key exchange
+    pub key_exchange: bool,
+}
+
+/// Returns the passphrase given as argument or stored in a file.
+///
+/// # Arguments
+///
+/// * `code` - The passphrase passed as argument (optional).
+/// * `code_from_file` - Path of a file containing the passphrase (optional).
+///
+/// # Returns
+///
+/// `Result<Option<Passphrase>>` - `None` if neither was given, so the relay generates one.
+pub fn code_from_args(code: Option<&str>, code_from_file: Option<&str>) -> Result<Option<Passphrase<'static>>> {
+    match (code, code_from_file) {
+        (Some(code), _) => Ok(Some(Passphrase::from(code.to_string()))),
+        (None, Some(path)) => Ok(Some(Passphrase::read_from(BufReader::new(File::open(path)?))?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Pairs two peers running the same command (e.g. `nudge exchange`) via the relay.
+///
+/// With a passphrase, the session is joined if the peer already registered it, and registered otherwise.
+/// Without a passphrase, the relay generates one which is printed for the peer.
+/// The joining side sends the salt of the key exchange along, if it can encrypt.
+/// Afterward, the socket is connected to the peer.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay.
+/// * `passphrase` - The passphrase shared with the peer (optional).
+/// * `identity` - Display name or hostname shown to the peer.
+/// * `kind` - What the session is used for.
+/// * `size` - Number of bytes announced to the relay when registering.
+/// * `relay_token` - Token presented to relays which require one for registering (optional).
+/// * `blind` - Whether only a hash of the passphrase is sent to the relay (`--blind`).
+pub fn pair(
+    socket: &UdpSocket,
+    passphrase: Option<Passphrase<'static>>,
+    identity: &AnonymousString,
+    kind: SessionKind,
+    size: u64,
+    relay_token: Option<&str>,
+    blind: bool,
+) -> Result<Pairing> {
+    let Some(passphrase) = passphrase else {
+        // blind relays can't generate passphrases, they never see them
+        let passphrase = if blind { Some(generate_passphrase()?) } else { None };
+        return host_session(socket, passphrase, identity, kind, size, relay_token, blind);
+    };
+
+    if let Some(pairing) = join_session(socket, &passphrase, identity, kind, blind)? {
+        return Ok(pairing);
+    }
+    match host_session(socket, Some(passphrase.clone()), identity, kind, size, relay_token, blind) {
+        // The peer registered the passphrase just before us
+        Err(NudgeError::ServerError(e)) if e.contains(&NudgeError::PassphraseInUse.to_string()) => {
+            join_session(socket, &passphrase, identity, kind, blind)?.ok_or(NudgeError::PassphraseNotFound)
+        }
+        result => result,
+    }
+}
+
+/// Joins a session registered by the peer.
+///
+/// # Returns
+///
+/// `Result<Option<Pairing>>` - The pairing with the peer, or `None` if nobody registered the passphrase yet.
+fn join_session(
+    socket: &UdpSocket,
+    passphrase: &Passphrase<'static>,
+    identity: &AnonymousString,
+    kind: SessionKind,
+    blind: bool,
+) -> Result<Option<Pairing>> {
+    let relay_passphrase = relay_passphrase(passphrase, blind);
+    let file_info: FileInfo = match request(socket, "R2X_RFI", &R2XRequestFileInfoMessage {
+        passphrase: relay_passphrase.clone(),
+        ticket: None,
+    }, "X2R_AFI") {
+        Err(NudgeError::ServerError(e)) if e.contains(&NudgeError::PassphraseNotFound.to_string()) => {
+            debug!(target: "relay_client", "Passphrase not registered yet, starting the session");
+            return Ok(None);
+        }
+        result => result?,
+    };
+    debug!(target: "relay_client", "Received FileInfo: {:?}", file_info);
+    if file_info.kind != kind {
+        return Err(NudgeError::WrongSessionKind(file_info.kind));
+    }
+
+    let sender_addr = mapped_addr(file_info.sender_addr, file_info.sender_mapped_port);
+    let encryption_salt = encryption::is_supported().then(|| encode_hex(&generate_salt()));
+    let current_addr = request_sender_connection(socket, &R2XRequestSenderConnectionMessage {
+        passphrase: relay_passphrase,
+        file_hash: file_info.file_hash.clone(),
+        receiver_host: identity.clone(),
+        mapped_port: None,
+        preview: false,
+        xattrs: false,
+        sparse: false,
+        manifest: false,
+        compress: false,
+        zstd: false,
+        resume_offset: None,
+        encryption_salt: encryption_salt.clone(),
+        key_exchange: encryption_salt.is_some(),
+        max_chunk_size: None,
+        ticket: None,
+        identity: None,
+    })?;
+    let sender_addr = current_addr.unwrap_or(sender_addr);
+
+    status!(
+        "{} Connecting to peer {} ({})...",
+        style("[~]").bold().yellow(),
+        style(&file_info.sender_host).cyan(),
+        style(&sender_addr).dim()
+    );
+    connect_to_peer(socket, sender_addr)?;
+    Ok(Some(Pairing {
+        peer_host: file_info.sender_host,
+        is_host: false,
+        passphrase: passphrase.clone(),
+        key_exchange: encryption_salt.is_some(),
+        encryption_salt,
+    }))
+}
+
+/// Registers the session at the relay and waits for the peer to join.
+///
+/// # Returns
+///
+/// `Result<Pairing>` - The pairing with the peer.
+fn host_session(
+    socket: &UdpSocket,
+    passphrase: Option<Passphrase<'static>>,
+    identity: &AnonymousString,
+    kind: SessionKind,
+    size: u64,
+    relay_token: Option<&str>,
+    blind: bool,
+) -> Result<Pairing> {
+    let mut passphrase_message: X2SPassphraseProvidedMessage = request(socket, "S2X_RP", &S2XRequestPassphraseMessage {
+        file_size: size,
+        file_name: String::new(),
+        file_hash: AnonymousString(None),
+        sender_host: identity.clone(),
+        passphrase: passphrase.as_ref().map(|passphrase| relay_passphrase(passphrase, blind)),
+        expire_secs: None,
+        kind,
+        relay_token: relay_token.map(str::to_string),
+        registration_id: Some(new_registration_id()),
+        mapped_port: None,
+        previewable: false,
+        file_mode: None,
+        sparse: false,
+        directory: false,
+        file_count: None,
+        announce: false,
+        on_match: MatchPolicy::Remove,
+        offer_only: false,
+        compress: false,
+        hash_algorithm: None,
+        streaming: false,
+        schedule: None,
+        resume: false,
+    }, "X2S_PPM")?;
+    // the relay answers with the hash, the peer needs the passphrase
+    if let Some(passphrase) = passphrase.filter(|_| blind) {
+        passphrase_message.passphrase = passphrase;
+    }
+    if is_quiet() {
+        // print only the passphrase so scripts can pick it up
+        report!("{}", passphrase_message.passphrase);
+    } else {
+        report!(
+            "{} Passphrase: {} (run `{}` on the other machine)",
+            success_mark(),
+            style(&passphrase_message.passphrase).cyan(),
+            kind.join_command(&passphrase_message.passphrase.to_string())
+        );
+    }
+
+    debug!(target: "relay_client", "Waiting for the peer to join...");
+    let expires_in = passphrase_message.expires_in_secs.map(Duration::from_secs);
+    let conn_req = wait_for_connection_request(socket, expires_in)?;
+    let receiver_addr = mapped_addr(conn_req.receiver_addr, conn_req.receiver_mapped_port);
+
+    status!(
+        "{} Connecting to peer {} ({})...",
+        style("[~]").bold().yellow(),
+        style(&conn_req.receiver_host).cyan(),
+        style(&receiver_addr).dim()
+    );
+    connect_to_peer(socket, receiver_addr)?;
+    Ok(Pairing {
+        peer_host: conn_req.receiver_host,
+        is_host: true,
+        passphrase: passphrase_message.passphrase,
+        encryption_salt: conn_req.encryption_salt,
+        key_exchange: conn_req.key_exchange,
+    })
+}
+
+/// Asks the relay to send our address to the sender and waits for the confirmation.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay.
+/// * `message` - The request, containing the passphrase.
+///
+/// # Returns
+///
+/// `Result<Option<SocketAddr>>` - The current address of the sender (its mapped port if any), if the relay tells.
+pub fn request_sender_connection(socket: &UdpSocket, message: &R2XRequestSenderConnectionMessage) -> Result<Option<SocketAddr>> {
+    match request::<X2RSenderConnectionAcceptedMessage>(socket, "R2X_RSC", message, "X2R_ASC") {
+        // Older relays don't confirm, but answer a retry with an error since the first request consumed the passphrase
+        Err(NudgeError::ServerError(e)) if e.contains(&NudgeError::PassphraseNotFound.to_string()) => {
+            debug!(target: "relay_client", "Relay didn't confirm the connection request, assuming it was accepted");
+            Ok(None)
+        }
+        result => {
+            let accepted = result?;
+            Ok(accepted.sender_addr.map(|addr| mapped_addr(addr, accepted.sender_mapped_port)))
+        }
+    }
+}
+
+/// Waits for the relay to send the address of the receiver
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay
+/// * `expires_in` - Time until the passphrase expires (optional)
+///
+/// # Errors
+///
+/// Returns `NudgeError::SessionExpired` if no receiver connected before the passphrase expired
+pub fn wait_for_connection_request(
+    socket: &UdpSocket,
+    expires_in: Option<Duration>,
+) -> Result<X2SSenderConnectToReceiverMessage> {
+    // a zero timeout is rejected by the socket, so wait at least a millisecond
+    socket.set_read_timeout(expires_in.map(|expires_in| expires_in.max(Duration::from_millis(1))))?;
+    // skips late responses to retried registrations
+    let conn_req = match receive_expected(socket, "X2S_SCON") {
+        Err(NudgeError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Err(NudgeError::SessionExpired);
+        }
+        result => result?,
+    };
+    socket.set_read_timeout(None)?;
+    Ok(conn_req)
+}
+
+/// Waits for a receiver to ask for an offer (`send --offer-only`), sending heartbeats meanwhile.
+/// The relay passes the request on right away and repeats it in its answers to the heartbeats,
+/// so a sender which missed it (e.g. a laptop which slept) learns of it once it's back.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay
+/// * `passphrase` - The passphrase of the session, as sent to the relay
+/// * `expires_in` - Time until the passphrase expires (optional)
+///
+/// # Returns
+///
+/// `Result<AnonymousString>` - The display name or hostname of the receiver.
+///
+/// # Errors
+///
+/// Returns `NudgeError::SessionExpired` if no receiver asked before the passphrase expired,
+/// or the error of the relay if it doesn't know the session anymore
+pub fn wait_for_offer_request(
+    socket: &UdpSocket,
+    passphrase: &Passphrase<'static>,
+    expires_in: Option<Duration>,
+) -> Result<AnonymousString> {
+    let started = Instant::now();
+    let mut next_heartbeat = started;
+    loop {
+        let remaining = expires_in.map(|expires_in| expires_in.saturating_sub(started.elapsed()));
+        if remaining == Some(Duration::ZERO) {
+            return Err(NudgeError::SessionExpired);
+        }
+        if Instant::now() >= next_heartbeat {
+            serialize_and_send(socket, "S2X_HB", &S2XHeartbeatMessage { passphrase: passphrase.clone() })?;
+            next_heartbeat = Instant::now() + OFFER_HEARTBEAT_INTERVAL;
+        }
+        let wait = next_heartbeat.saturating_duration_since(Instant::now());
+        let wait = remaining.map_or(wait, |remaining| wait.min(remaining));
+        socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+        match receive_and_parse_and_expect::<X2SHeartbeatMessage>(socket, "X2S_HB") {
+            Ok(X2SHeartbeatMessage { requested: true, receiver_host }) => {
+                socket.set_read_timeout(None)?;
+                return Ok(receiver_host.unwrap_or(AnonymousString(None)));
+            }
+            Ok(_) => {}
+            // the relay may be unreachable for a while, e.g. while the sender is offline
+            Err(NudgeError::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused) => {}
+            Err(NudgeError::ReceiveExpectationNotMet(..)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Asks the relay to wake the sender of an offer (`send --offer-only`), which then comes online.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay
+/// * `message` - The request, containing the passphrase
+///
+/// # Errors
+///
+/// Returns `NudgeError::SenderOffline` if the sender isn't online yet, the relay asked it to come online
+pub fn wake_sender(socket: &UdpSocket, message: &R2XWakeSenderMessage) -> Result<()> {
+    match request::<X2RSenderOnlineMessage>(socket, "R2X_WAKE", message, "X2R_ONL") {
+        Err(NudgeError::ServerError(e)) if e.contains(&NudgeError::SenderOffline.to_string()) => {
+            Err(NudgeError::SenderOffline)
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+/// Waits for the relay to send the address of the receiver like `wait_for_connection_request`, sending heartbeats
+/// meanwhile to notice a relay which stopped responding.
+/// Any answer of the relay (even an error of relays not knowing heartbeats) shows that it's still there.
+/// The heartbeats also keep the way through the NATs open once the socket follows the relay to a new address.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the relay
+/// * `passphrase` - The passphrase of the session, as sent to the relay
+/// * `expires_in` - Time until the passphrase expires (optional)
+/// * `binding` - Follows the relay's host name to a new address (optional), looked up right away
+///   if the relay stops responding
+/// * `fail_over` - Whether to give up on a relay which stopped responding, to move to a backup relay.
+///   Otherwise the heartbeats continue until the relay is back
+///
+/// # Errors
+///
+/// Returns `NudgeError::SessionExpired` if no receiver connected before the passphrase expired,
+/// or `NudgeError::RelayUnresponsive` if the relay didn't answer within the relay timeout
+pub fn watch_for_connection_request(
+    socket: &UdpSocket,
+    passphrase: &Passphrase<'static>,
+    expires_in: Option<Duration>,
+    mut binding: Option<&mut RelayBinding>,
+    fail_over: bool,
+) -> Result<X2SSenderConnectToReceiverMessage> {
+    let started = Instant::now();
+    let timeout = relay_timeout();
+    // a few heartbeats fit into the relay timeout, so a lost one doesn't count as a lost relay
+    let interval = HEARTBEAT_INTERVAL.min(timeout / 3).max(Duration::from_millis(1));
+    let mut last_answer = Instant::now();
+    loop {
+        if expires_in.is_some_and(|expires_in| started.elapsed() >= expires_in) {
+            return Err(NudgeError::SessionExpired);
+        }
+        if last_answer.elapsed() >= timeout {
+            // a relay on a dynamic IP address may have moved
+            let moved = match binding.as_deref_mut() {
+                Some(binding) => follow_relay(socket, binding, true)?,
+                None => false,
+            };
+            if fail_over && !moved {
+                return Err(NudgeError::RelayUnresponsive(timeout));
+            }
+            last_answer = Instant::now();
+        }
+        let wait = expires_in.map_or(interval, |expires_in| interval.min(expires_in.saturating_sub(started.elapsed())));
+        socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+        match receive_and_parse_and_expect::<X2SSenderConnectToReceiverMessage>(socket, "X2S_SCON") {
+            Ok(conn_req) => {
+                socket.set_read_timeout(None)?;
+                return Ok(conn_req);
+            }
+            Err(NudgeError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if let Some(binding) = binding.as_deref_mut() {
+                    follow_relay(socket, binding, false)?;
+                }
+                serialize_and_send(socket, "S2X_HB", &S2XHeartbeatMessage { passphrase: passphrase.clone() })?;
+            }
+            // unreachable relays are reported by some systems when sending, the timeout decides
+            Err(NudgeError::Io(e)) if e.kind() == ErrorKind::ConnectionRefused => {}
+            Err(e @ (NudgeError::ServerError(_) | NudgeError::ReceiveExpectationNotMet(..))) => {
+                debug!(target: "relay_client", "Relay answered the heartbeat: {}", e);
+                last_answer = Instant::now();
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Connects the socket to the new address of the relay, if its host name resolves to another one.
+///
+/// # Returns
+///
+/// `Result<bool>` - Whether the relay moved.
+fn follow_relay(socket: &UdpSocket, binding: &mut RelayBinding, force: bool) -> Result<bool> {
+    let Some(addr) = binding.rebind(socket, force)? else {
+        return Ok(false);
+    };
+    status!(
+        "{} The relay {} moved to {}, following it",
+        style("[~]").bold().yellow(),
+        binding.address(),
+        style(addr).dim()
+    );
+    Ok(true)
+}
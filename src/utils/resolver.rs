@@ -0,0 +1,401 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::error::{NudgeError, Result};
+
+/// How often long-running clients look up the relay host name again (`--relay-recheck`)
+pub const DEFAULT_RELAY_RECHECK: &str = "5m";
+
+/// Timeout of a DNS-over-HTTPS query
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS record types queried over HTTPS, IPv4 first
+const DOH_RECORD_TYPES: [(&str, u16); 2] = [("A", 1), ("AAAA", 28)];
+
+/// Resolves the host names of relays
+pub trait Resolver {
+    /// Looks up the addresses of a host name.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host name, e.g. `relay-1.nudge.d2a.io`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::ResolveFailed` if the host name can't be resolved.
+    fn lookup(&self, host: &str) -> Result<Vec<IpAddr>>;
+}
+
+/// Resolves host names with the resolver of the operating system
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let addrs = (host, 0).to_socket_addrs()
+            .map_err(|e| NudgeError::ResolveFailed(host.to_string(), e.to_string()))?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Resolves host names with DNS-over-HTTPS (`--doh`), for networks tampering with DNS.
+/// Queries use the JSON API (`application/dns-json`) offered by e.g. Cloudflare and Google.
+/// nudge has no TLS stack of its own, so the requests are made by `curl`, which has to be installed
+pub struct DohResolver {
+    url: String,
+}
+
+/// Response of the JSON API, e.g. `{"Status": 0, "Answer": [{"type": 1, "data": "192.0.2.1"}]}`
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u16,
+
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+impl DohResolver {
+    /// Creates a resolver querying the DoH server at a URL, e.g. `https://1.1.1.1/dns-query`.
+    /// Use a URL with an IP address, otherwise the system resolver looks up the DoH server.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::ResolveFailed` if the URL doesn't start with `https://`.
+    pub fn new(url: &str) -> Result<Self> {
+        if !url.starts_with("https://") || url.len() == "https://".len() {
+            return Err(NudgeError::ResolveFailed(url.to_string(), "expected an https:// URL".to_string()));
+        }
+        Ok(DohResolver { url: url.to_string() })
+    }
+
+    /// Returns the URL querying a record of a host name.
+    fn query_url(&self, host: &str, record_type: &str) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        format!("{}{}name={}&type={}", self.url, separator, host, record_type)
+    }
+
+    /// Queries a record of a host name.
+    fn query(&self, host: &str, record_type: &str) -> Result<Vec<u8>> {
+        let failed = |reason: String| NudgeError::ResolveFailed(host.to_string(), reason);
+        let output = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--proto", "=https"])
+            .arg("--max-time").arg(DOH_TIMEOUT.as_secs().to_string())
+            .arg("--header").arg("accept: application/dns-json")
+            .arg(self.query_url(host, record_type))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| failed(format!("cannot run curl for DNS-over-HTTPS: {}", e)))?;
+        if !output.status.success() {
+            return Err(failed(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl Resolver for DohResolver {
+    fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        // the host name ends up in the query string
+        if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) {
+            return Err(NudgeError::ResolveFailed(host.to_string(), "invalid host name".to_string()));
+        }
+        let addrs = merge_lookups(DOH_RECORD_TYPES.iter().map(|&(name, record_type)| {
+            let response = self.query(host, name)?;
+            parse_doh_response(&response, record_type)
+                .map_err(|reason| NudgeError::ResolveFailed(host.to_string(), reason))
+        }))?;
+        debug!(target: "relay_client", "Resolved {} via DNS-over-HTTPS: {:?}", host, addrs);
+        Ok(addrs)
+    }
+}
+
+/// Merges the addresses of the record types. A record type which failed counts as empty
+/// if another one succeeded, e.g. when the DoH server chokes on AAAA queries.
+///
+/// # Errors
+///
+/// Returns the last error if all record types failed.
+fn merge_lookups(lookups: impl Iterator<Item = Result<Vec<IpAddr>>>) -> Result<Vec<IpAddr>> {
+    let mut addrs = Vec::new();
+    let mut failure = None;
+    let mut succeeded = false;
+    for lookup in lookups {
+        match lookup {
+            Ok(found) => {
+                succeeded = true;
+                addrs.extend(found);
+            }
+            Err(e) => {
+                debug!(target: "relay_client", "DNS-over-HTTPS query failed: {}", e);
+                failure = Some(e);
+            }
+        }
+    }
+    match failure {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(addrs),
+    }
+}
+
+/// Checks that `curl`, which makes the DNS-over-HTTPS queries, can be run.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ResolveFailed` if `curl` isn't installed (or not in `PATH`).
+fn check_curl(url: &str) -> Result<()> {
+    let status = Command::new("curl").arg("--version")
+        .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(NudgeError::ResolveFailed(url.to_string(), format!("curl --version failed with {}", status))),
+        Err(e) => Err(NudgeError::ResolveFailed(
+            url.to_string(), format!("DNS-over-HTTPS needs curl, which cannot be run: {}", e),
+        )),
+    }
+}
+
+/// Extracts the addresses of a record type from a response of the JSON API, skipping e.g. CNAMEs.
+fn parse_doh_response(response: &[u8], record_type: u16) -> std::result::Result<Vec<IpAddr>, String> {
+    let response: DohResponse = serde_json::from_slice(response)
+        .map_err(|e| format!("invalid DNS-over-HTTPS response: {}", e))?;
+    match response.status {
+        0 => {}
+        3 => return Ok(Vec::new()), // NXDOMAIN
+        status => return Err(format!("DNS-over-HTTPS server answered with status {}", status)),
+    }
+    Ok(response.answer.iter()
+        .filter(|answer| answer.record_type == record_type)
+        .filter_map(|answer| answer.data.parse().ok())
+        .collect())
+}
+
+/// Returns the resolver selected with `--doh`.
+///
+/// # Arguments
+///
+/// * `doh` - URL of the DoH server, or `None` for the system resolver.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ResolveFailed` if the URL is invalid or `curl` is missing.
+pub fn resolver(doh: Option<&str>) -> Result<Box<dyn Resolver>> {
+    Ok(match doh {
+        Some(url) => {
+            let resolver = DohResolver::new(url)?;
+            check_curl(url)?;
+            Box::new(resolver)
+        }
+        None => Box::new(SystemResolver),
+    })
+}
+
+/// Resolves an address like `relay.example:4000` or `[::1]:4000`. IP addresses are used as they are.
+///
+/// # Arguments
+///
+/// * `resolver` - The resolver looking up host names.
+/// * `address` - The address, as `<host>:<port>`.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ResolveFailed` if the address is invalid or the host name can't be resolved.
+pub fn resolve_address(resolver: &dyn Resolver, address: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let failed = |reason: &str| NudgeError::ResolveFailed(address.to_string(), reason.to_string());
+    let (host, port) = address.rsplit_once(':').ok_or_else(|| failed("expected <host>:<port>"))?;
+    let port: u16 = port.parse().map_err(|_| failed("invalid port"))?;
+    let ip = resolver.lookup(host)?.into_iter().next().ok_or_else(|| failed("no addresses found"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// The address a socket talking to the relay is connected to, kept up to date with the relay's host name,
+/// so a relay on a dynamic IP address (e.g. hosted at home) is followed when its DNS record changes
+pub struct RelayBinding {
+    resolver: Box<dyn Resolver>,
+
+    /// The relay as `<host>:<port>`
+    address: String,
+    addr: SocketAddr,
+
+    /// Time between two lookups
+    interval: Duration,
+    checked_at: Instant,
+}
+
+impl RelayBinding {
+    /// Starts following the host name of the relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - The resolver looking up the host name.
+    /// * `address` - The relay as `<host>:<port>`.
+    /// * `addr` - The address the socket is connected to.
+    /// * `interval` - Time between two lookups.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// `Option<RelayBinding>` - `None` if there's nothing to follow: the relay is given as IP address,
+    /// or the interval is zero.
+    pub fn new(resolver: Box<dyn Resolver>, address: &str, addr: SocketAddr, interval: Duration, now: Instant) -> Option<Self> {
+        if interval.is_zero() || address.parse::<SocketAddr>().is_ok() {
+            return None;
+        }
+        Some(RelayBinding { resolver, address: address.to_string(), addr, interval, checked_at: now })
+    }
+
+    /// Looks up the host name again if it's due, or right away if forced (e.g. because the relay stopped answering).
+    /// A failed lookup keeps the address, the relay may be reachable anyway.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time.
+    /// * `force` - Whether to look up the host name even if the interval didn't pass yet.
+    ///
+    /// # Returns
+    ///
+    /// `Option<SocketAddr>` - The new address of the relay, if it changed.
+    pub fn check(&mut self, now: Instant, force: bool) -> Option<SocketAddr> {
+        if !force && now.duration_since(self.checked_at) < self.interval {
+            return None;
+        }
+        self.checked_at = now;
+        match resolve_address(self.resolver.as_ref(), &self.address) {
+            Ok(addr) if addr != self.addr => {
+                self.addr = addr;
+                Some(addr)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                debug!(target: "relay_client", "Looking up the relay again failed, keeping {}: {}", self.addr, e);
+                None
+            }
+        }
+    }
+
+    /// Like `check`, and connects the socket to the new address of the relay if it changed.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Option<SocketAddr>>` - The new address the socket was connected to, if the relay moved.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the socket can't be connected to the new address.
+    pub fn rebind(&mut self, socket: &UdpSocket, force: bool) -> Result<Option<SocketAddr>> {
+        let Some(addr) = self.check(Instant::now(), force) else {
+            return Ok(None);
+        };
+        debug!(target: "relay_client", "Connecting to the new address of the relay {}: {}", self.address, addr);
+        socket.connect(addr)?;
+        Ok(Some(addr))
+    }
+
+    /// Returns the relay as `<host>:<port>`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct StaticResolver(Vec<IpAddr>);
+
+    impl Resolver for StaticResolver {
+        fn lookup(&self, _: &str) -> Result<Vec<IpAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    struct ChangingResolver(Rc<RefCell<Vec<IpAddr>>>);
+
+    impl Resolver for ChangingResolver {
+        fn lookup(&self, _: &str) -> Result<Vec<IpAddr>> {
+            Ok(self.0.borrow().clone())
+        }
+    }
+
+    #[test]
+    fn test_relay_binding() {
+        let ips = Rc::new(RefCell::new(vec!["192.0.2.1".parse().unwrap()]));
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        assert!(RelayBinding::new(Box::new(StaticResolver(vec![])), "192.0.2.1:4000", addr, minute, start).is_none());
+        assert!(RelayBinding::new(Box::new(StaticResolver(vec![])), "relay.example:4000", addr, Duration::ZERO, start).is_none());
+
+        let mut binding = RelayBinding::new(Box::new(ChangingResolver(ips.clone())), "relay.example:4000", addr, minute, start).unwrap();
+        assert_eq!(binding.check(start + minute, false), None);
+        *ips.borrow_mut() = vec!["192.0.2.2".parse().unwrap()];
+        // not due yet, unless forced
+        assert_eq!(binding.check(start + minute + Duration::from_secs(1), false), None);
+        assert_eq!(binding.check(start + minute + Duration::from_secs(1), true), Some("192.0.2.2:4000".parse().unwrap()));
+        assert_eq!(binding.check(start + minute * 3, false), None);
+        // failed lookups keep the address
+        ips.borrow_mut().clear();
+        assert_eq!(binding.check(start + minute * 5, false), None);
+        *ips.borrow_mut() = vec!["192.0.2.3".parse().unwrap()];
+        assert_eq!(binding.check(start + minute * 7, false), Some("192.0.2.3:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_resolve_address() {
+        let resolver = StaticResolver(vec!["192.0.2.1".parse().unwrap()]);
+        assert_eq!(resolve_address(&resolver, "relay.example:4000").unwrap(), "192.0.2.1:4000".parse().unwrap());
+        assert_eq!(resolve_address(&resolver, "[::1]:80").unwrap(), "[::1]:80".parse().unwrap());
+        assert!(resolve_address(&resolver, "relay.example").is_err());
+        assert!(resolve_address(&resolver, "relay.example:x").is_err());
+        assert!(resolve_address(&StaticResolver(vec![]), "relay.example:4000").is_err());
+    }
+
+    #[test]
+    fn test_parse_doh_response() {
+        let response = br#"{"Status":0,"Answer":[
+            {"name":"relay.example","type":5,"TTL":60,"data":"edge.example."},
+            {"name":"edge.example","type":1,"TTL":60,"data":"192.0.2.1"}]}"#;
+        assert_eq!(parse_doh_response(response, 1).unwrap(), vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert!(parse_doh_response(response, 28).unwrap().is_empty());
+        assert!(parse_doh_response(br#"{"Status":3}"#, 1).unwrap().is_empty());
+        assert!(parse_doh_response(br#"{"Status":2}"#, 1).is_err());
+        assert!(parse_doh_response(b"<html>", 1).is_err());
+    }
+
+    #[test]
+    fn test_doh_resolver() {
+        assert!(DohResolver::new("http://1.1.1.1/dns-query").is_err());
+        let resolver = DohResolver::new("https://1.1.1.1/dns-query").unwrap();
+        assert_eq!(resolver.query_url("relay.example", "A"), "https://1.1.1.1/dns-query?name=relay.example&type=A");
+        let resolver = DohResolver::new("https://dns.example/resolve?ct=json").unwrap();
+        assert_eq!(resolver.query_url("relay.example", "AAAA"), "https://dns.example/resolve?ct=json&name=relay.example&type=AAAA");
+        assert!(resolver.lookup("relay.example&type=TXT").is_err());
+    }
+
+    #[test]
+    fn test_merge_lookups() {
+        let failed = || Err(NudgeError::ResolveFailed("relay.example".to_string(), "HTTP 502".to_string()));
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(merge_lookups([Ok(vec![v4]), Ok(vec![v6])].into_iter()).unwrap(), vec![v4, v6]);
+        assert_eq!(merge_lookups([Ok(vec![v4]), failed()].into_iter()).unwrap(), vec![v4]);
+        assert_eq!(merge_lookups([failed(), Ok(vec![v6])].into_iter()).unwrap(), vec![v6]);
+        assert!(merge_lookups([Ok(vec![]), failed()].into_iter()).unwrap().is_empty());
+        assert!(merge_lookups([failed(), failed()].into_iter()).is_err());
+    }
+}
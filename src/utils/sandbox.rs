@@ -0,0 +1,347 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{NudgeError, Result};
+
+/// Files a sandboxed receiver may still read: time zones for log and manifest timestamps
+const READABLE_PATHS: [&str; 2] = ["/etc/localtime", "/usr/share/zoneinfo"];
+
+/// Terminals a sandboxed receiver may still open, to ask for confirmation after a preview
+const TERMINAL_PATHS: [&str; 2] = ["/dev/tty", "/dev/null"];
+
+/// What the sandbox could restrict on this system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxStatus {
+    /// Version of the Landlock ABI restricting the file system, or `None` if the kernel doesn't support Landlock
+    pub landlock_abi: Option<u32>,
+}
+
+/// Restricts the process once it's connected to the peer, so a malicious peer exploiting it
+/// can neither reach anyone else nor touch files other than the ones being transferred:
+///
+/// * seccomp: no new sockets or connections, and datagrams only to the address the socket is connected to
+///   (the peer), no executing other programs
+/// * Landlock (Linux 5.13+): files can only be created and written below the given directories,
+///   and only the given files can be read besides them
+///
+/// The restrictions can't be lifted for the rest of the process. Threads which are already running
+/// keep their file system access, the network restrictions apply to all of them.
+///
+/// # Arguments
+///
+/// * `writable_dirs` - Directories the receiver writes to (e.g. of the output file and the manifest).
+///   Directories which don't exist yet are created first, rather than allowing their nearest existing ancestor
+///   (which could be `/` or the home directory).
+/// * `readable_files` - Files the sender reads (e.g. the file being sent).
+///
+/// # Errors
+///
+/// Returns `NudgeError::SandboxFailed` if the system doesn't support seccomp, a writable directory can't be created
+/// or a restriction can't be applied.
+pub fn enter(writable_dirs: &[&Path], readable_files: &[&Path]) -> Result<SandboxStatus> {
+    let writable_dirs = writable_dirs.iter().map(|dir| writable_dir(dir)).collect::<Result<Vec<_>>>()?;
+    debug!(
+        "Sandboxing the process, writable directories: {:?}, readable files: {:?}",
+        writable_dirs, readable_files
+    );
+    imp::enter(&writable_dirs, readable_files)
+}
+
+/// Creates the directory (and its parents) if it doesn't exist yet, so only the directory itself is allowed.
+/// An empty path (the parent of a relative file name) is the working directory.
+///
+/// # Errors
+///
+/// Returns `NudgeError::SandboxFailed` if the directory can't be created.
+fn writable_dir(dir: &Path) -> Result<PathBuf> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    fs::create_dir_all(dir)
+        .map_err(|e| NudgeError::SandboxFailed(format!("cannot create {}: {}", dir.display(), e)))?;
+    Ok(dir.to_path_buf())
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod imp {
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::{Path, PathBuf};
+
+    use libc::{c_long, sock_filter, sock_fprog};
+
+    use super::{SandboxStatus, READABLE_PATHS, TERMINAL_PATHS};
+    use crate::error::{NudgeError, Result};
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Offsets into `struct seccomp_data`
+    const SECCOMP_DATA_NR: u32 = 0;
+    const SECCOMP_DATA_ARCH: u32 = 4;
+    const SECCOMP_DATA_ARGS: u32 = 16;
+
+    /// System calls of the x32 ABI have this bit set, they'd bypass the checks of the x86_64 numbers
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// System calls a connected receiver never needs
+    const DENIED_SYSCALLS: [c_long; 14] = [
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_sendmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_writev,
+        // io_uring performs network and file operations without passing seccomp
+        libc::SYS_io_uring_setup,
+    ];
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// Everything up to `MAKE_SYM`, as handled by the first version of the ABI
+    const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+    const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+    #[repr(C)]
+    struct LandlockRulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct LandlockPathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub fn enter(writable_dirs: &[PathBuf], readable_files: &[&Path]) -> Result<SandboxStatus> {
+        // required to restrict ourselves without privileges, and keeps setuid programs from lifting the restrictions
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(failed("cannot set no_new_privs", io::Error::last_os_error()));
+        }
+
+        let landlock_abi = landlock_abi();
+        if let Some(abi) = landlock_abi {
+            restrict_file_system(writable_dirs, readable_files, abi)?;
+        }
+        restrict_system_calls()?;
+        Ok(SandboxStatus { landlock_abi })
+    }
+
+    fn failed(what: &str, e: io::Error) -> NudgeError {
+        NudgeError::SandboxFailed(format!("{}: {}", what, e))
+    }
+
+    /// Returns the version of the Landlock ABI, or `None` if the kernel doesn't support (or disabled) Landlock.
+    fn landlock_abi() -> Option<u32> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<LandlockRulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        (abi > 0).then_some(abi as u32)
+    }
+
+    /// Returns the file system access the ABI version knows about, all of which is denied unless allowed by a rule.
+    fn handled_access(abi: u32) -> u64 {
+        let mut access = ACCESS_FS_V1;
+        if abi >= 2 {
+            access |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            access |= ACCESS_FS_TRUNCATE;
+        }
+        if abi >= 5 {
+            access |= ACCESS_FS_IOCTL_DEV;
+        }
+        access
+    }
+
+    fn restrict_file_system(writable_dirs: &[PathBuf], readable_files: &[&Path], abi: u32) -> Result<()> {
+        let handled_access = handled_access(abi);
+        let attr = LandlockRulesetAttr { handled_access_fs: handled_access };
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const LandlockRulesetAttr,
+                size_of::<LandlockRulesetAttr>(),
+                0u32,
+            )
+        };
+        if ruleset < 0 {
+            return Err(failed("cannot create the Landlock ruleset", io::Error::last_os_error()));
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+        for dir in writable_dirs {
+            allow(&ruleset, dir, handled_access & !ACCESS_FS_EXECUTE)?;
+        }
+        for file in readable_files {
+            allow(&ruleset, file, ACCESS_FS_READ_FILE)?;
+        }
+        for path in READABLE_PATHS.iter().map(Path::new).filter(|path| path.exists()) {
+            let access = if path.is_dir() { ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR } else { ACCESS_FS_READ_FILE };
+            allow(&ruleset, path, access)?;
+        }
+        for path in TERMINAL_PATHS.iter().map(Path::new).filter(|path| path.exists()) {
+            allow(&ruleset, path, ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE)?;
+        }
+
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) } != 0 {
+            return Err(failed("cannot enforce the Landlock ruleset", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Allows the access to the path and everything below it.
+    fn allow(ruleset: &OwnedFd, path: &Path, allowed_access: u64) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|e| failed(&format!("cannot open {}", path.display()), e))?;
+        let attr = LandlockPathBeneathAttr { allowed_access, parent_fd: file.as_raw_fd() };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const LandlockPathBeneathAttr,
+                0u32,
+            )
+        };
+        if result != 0 {
+            return Err(failed(&format!("cannot allow access to {}", path.display()), io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn restrict_system_calls() -> Result<()> {
+        let filter = seccomp_filter();
+        let program = sock_fprog { len: filter.len() as u16, filter: filter.as_ptr() as *mut sock_filter };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program as *const sock_fprog,
+            )
+        };
+        if result != 0 {
+            return Err(failed("cannot install the seccomp filter", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn statement(code: u32, k: u32) -> sock_filter {
+        sock_filter { code: code as u16, jt: 0, jf: 0, k }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter { code: code as u16, jt, jf, k }
+    }
+
+    /// Builds the BPF program denying `DENIED_SYSCALLS` and `sendto` with a destination address.
+    pub(super) fn seccomp_filter() -> Vec<sock_filter> {
+        let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+        let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+        let jump_equal = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+        let ret = libc::BPF_RET | libc::BPF_K;
+        // the upper and lower half of the `dest_addr` argument of `sendto`
+        let dest_addr = SECCOMP_DATA_ARGS + 4 * 8;
+
+        let mut filter = vec![
+            // system call numbers of other architectures mean something else
+            statement(load, SECCOMP_DATA_ARCH),
+            jump(jump_equal, AUDIT_ARCH, 1, 0),
+            statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(load, SECCOMP_DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+            statement(ret, deny),
+        ]);
+        for syscall in DENIED_SYSCALLS {
+            filter.extend([
+                jump(jump_equal, syscall as u32, 0, 1),
+                statement(ret, deny),
+            ]);
+        }
+        // datagrams may only go to the connected peer
+        filter.extend([
+            jump(jump_equal, libc::SYS_sendto as u32, 0, 6),
+            statement(load, dest_addr),
+            jump(jump_equal, 0, 0, 3),
+            statement(load, dest_addr + 4),
+            jump(jump_equal, 0, 0, 1),
+            statement(ret, libc::SECCOMP_RET_ALLOW),
+            statement(ret, deny),
+            statement(ret, libc::SECCOMP_RET_ALLOW),
+        ]);
+        filter
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod imp {
+    use std::path::{Path, PathBuf};
+
+    use super::SandboxStatus;
+    use crate::error::{NudgeError, Result};
+
+    pub fn enter(_: &[PathBuf], _: &[&Path]) -> Result<SandboxStatus> {
+        Err(NudgeError::SandboxFailed("only supported on Linux (x86_64 and aarch64)".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writable_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(writable_dir(dir.path()).unwrap(), dir.path());
+        let missing = dir.path().join("2024/alice");
+        assert_eq!(writable_dir(&missing).unwrap(), missing);
+        assert!(missing.is_dir());
+        assert_eq!(writable_dir(Path::new("")).unwrap(), Path::new("."));
+
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(matches!(writable_dir(&file.join("sub")), Err(NudgeError::SandboxFailed(_))));
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn test_seccomp_filter() {
+        let filter = imp::seccomp_filter();
+        assert!(filter.len() < libc::BPF_MAXINSNS as usize);
+        // every path ends in a return
+        assert_eq!(filter.last().unwrap().code as u32, libc::BPF_RET | libc::BPF_K);
+        for (index, instruction) in filter.iter().enumerate().filter(|(_, instruction)| instruction.code & 0x07 == libc::BPF_JMP as u16) {
+            let target = index + 1 + instruction.jt.max(instruction.jf) as usize;
+            assert!(target < filter.len(), "jump of instruction {} leaves the program", index);
+        }
+    }
+}
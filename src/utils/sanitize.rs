@@ -0,0 +1,54 @@
+/// Longest text from a peer shown, longer text is shortened in the middle so both ends (e.g. the extension) stay visible
+pub const MAX_DISPLAY_CHARS: usize = 100;
+
+/// Controls of the direction of text, which let e.g. "invoice\u{202e}fdp.exe" read as "invoiceexe.pdf"
+const BIDI_CONTROLS: [char; 12] = [
+    '\u{061c}', '\u{200e}', '\u{200f}', '\u{202a}', '\u{202b}', '\u{202c}',
+    '\u{202d}', '\u{202e}', '\u{2066}', '\u{2067}', '\u{2068}', '\u{2069}',
+];
+
+/// Makes text sent by a peer (file names, hostnames, abort reasons) safe to print to the terminal:
+/// control characters, which could inject escape sequences, and bidi controls are replaced,
+/// and text longer than `MAX_DISPLAY_CHARS` is shortened in the middle.
+///
+/// # Arguments
+///
+/// * `text` - The text as sent by the peer.
+pub fn sanitize(text: &str) -> String {
+    let chars: Vec<char> = text.chars()
+        .map(|c| if c.is_control() || BIDI_CONTROLS.contains(&c) { char::REPLACEMENT_CHARACTER } else { c })
+        .collect();
+    if chars.len() <= MAX_DISPLAY_CHARS {
+        return chars.into_iter().collect();
+    }
+    let head = (MAX_DISPLAY_CHARS - 1) / 2;
+    let tail = MAX_DISPLAY_CHARS - 1 - head;
+    chars[..head].iter()
+        .chain(std::iter::once(&'…'))
+        .chain(&chars[chars.len() - tail..])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_controls() {
+        assert_eq!(sanitize("report.pdf"), "report.pdf");
+        assert_eq!(sanitize("grüße 📄.txt"), "grüße 📄.txt");
+        assert_eq!(sanitize("evil\x1b]0;title\x07.txt"), "evil\u{fffd}]0;title\u{fffd}.txt");
+        assert_eq!(sanitize("line\nbreak\r"), "line\u{fffd}break\u{fffd}");
+        assert_eq!(sanitize("invoice\u{202e}fdp.exe"), "invoice\u{fffd}fdp.exe");
+        assert_eq!(sanitize("\u{9b}31m"), "\u{fffd}31m");
+    }
+
+    #[test]
+    fn test_sanitize_length() {
+        let long = format!("{}.exe", "a".repeat(300));
+        let sanitized = sanitize(&long);
+        assert_eq!(sanitized.chars().count(), MAX_DISPLAY_CHARS);
+        assert!(sanitized.starts_with("aaa") && sanitized.ends_with("aaa.exe") && sanitized.contains('…'));
+        assert_eq!(sanitize(&"b".repeat(MAX_DISPLAY_CHARS)), "b".repeat(MAX_DISPLAY_CHARS));
+    }
+}
@@ -0,0 +1,231 @@
+//! Time-of-day schedule of `send --start-at` and `send --window`, so large transfers run during off-peak hours
+//! of metered or shared connections. Times are in the local time of the sender.
+
+use std::fmt;
+use std::time::Duration;
+
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::error::{NudgeError, Result};
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// A time of day on the 24-hour clock, in minutes after midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    fn secs(self) -> u32 {
+        u32::from(self.0) * 60
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// The hours in which data is sent, may span midnight like "22:00-06:00"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    start: TimeOfDay,
+    end: TimeOfDay,
+}
+
+impl Window {
+    /// Returns whether the time is within the window, which includes its start but not its end.
+    ///
+    /// # Arguments
+    ///
+    /// * `secs_of_day` - The time of day in seconds after midnight.
+    fn contains(&self, secs_of_day: u32) -> bool {
+        let (start, end) = (self.start.secs(), self.end.secs());
+        match start < end {
+            true => (start..end).contains(&secs_of_day),
+            false => secs_of_day >= start || secs_of_day < end,
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "between {} and {}", self.start, self.end)
+    }
+}
+
+/// Parses a time of day like "02:00" or "23:30" (24-hour clock).
+///
+/// # Arguments
+///
+/// * `input` - The time to parse.
+///
+/// # Returns
+///
+/// * `Ok(TimeOfDay)` - The parsed time.
+/// * `Err(NudgeError::InvalidSchedule)` - If the input isn't a time of day.
+pub fn parse_time_of_day(input: &str) -> Result<TimeOfDay> {
+    let invalid = || NudgeError::InvalidSchedule(input.to_string());
+    let (hours, minutes) = input.trim().split_once(':').ok_or_else(invalid)?;
+    let parse = |digits: &str, lengths: std::ops::RangeInclusive<usize>, limit: u16| {
+        Some(digits)
+            .filter(|digits| lengths.contains(&digits.len()) && digits.bytes().all(|c| c.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<u16>().ok())
+            .filter(|value| *value < limit)
+            .ok_or_else(invalid)
+    };
+    Ok(TimeOfDay(parse(hours, 1..=2, 24)? * 60 + parse(minutes, 2..=2, 60)?))
+}
+
+/// Parses a window of the day like "01:00-06:00", or "22:00-06:00" over midnight.
+///
+/// # Arguments
+///
+/// * `input` - The window to parse.
+///
+/// # Returns
+///
+/// * `Ok(Window)` - The parsed window.
+/// * `Err(NudgeError::InvalidSchedule)` - If the input isn't two times of day, or both are the same.
+pub fn parse_window(input: &str) -> Result<Window> {
+    let (start, end) = input.split_once('-').ok_or_else(|| NudgeError::InvalidSchedule(input.to_string()))?;
+    let window = Window { start: parse_time_of_day(start)?, end: parse_time_of_day(end)? };
+    // an empty window would never send, and a whole day needs no window
+    match window.start == window.end {
+        true => Err(NudgeError::InvalidSchedule(input.to_string())),
+        false => Ok(window),
+    }
+}
+
+/// When the sender sends data (`--start-at`, `--window`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    start_at: Option<TimeOfDay>,
+    window: Option<Window>,
+
+    /// Offset of the local time the times are in
+    offset: UtcOffset,
+}
+
+impl Schedule {
+    /// Creates the schedule of the options.
+    ///
+    /// # Arguments
+    ///
+    /// * `start_at` - When to start sending (optional).
+    /// * `window` - The hours in which data is sent (optional).
+    /// * `offset` - Offset of the local time, determined while the process has a single thread.
+    ///
+    /// # Returns
+    ///
+    /// `Option<Schedule>` - The schedule, `None` if data is sent at any time.
+    pub fn new(start_at: Option<TimeOfDay>, window: Option<Window>, offset: UtcOffset) -> Option<Self> {
+        (start_at.is_some() || window.is_some()).then_some(Schedule { start_at, window, offset })
+    }
+
+    /// Returns how long to wait until `--start-at`, zero without it.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time.
+    pub fn until_start(&self, now: OffsetDateTime) -> Duration {
+        let secs_of_day = self.secs_of_day(now);
+        let secs = self.start_at.map_or(0, |start_at| (start_at.secs() + SECS_PER_DAY - secs_of_day) % SECS_PER_DAY);
+        Duration::from_secs(u64::from(secs))
+    }
+
+    /// Returns how long to wait until the window opens, zero within the window or without one.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time.
+    pub fn until_window(&self, now: OffsetDateTime) -> Duration {
+        let secs_of_day = self.secs_of_day(now);
+        let secs = match self.window {
+            Some(window) if !window.contains(secs_of_day) => (window.start.secs() + SECS_PER_DAY - secs_of_day) % SECS_PER_DAY,
+            _ => 0,
+        };
+        Duration::from_secs(u64::from(secs))
+    }
+
+    fn secs_of_day(&self, now: OffsetDateTime) -> u32 {
+        let (hours, minutes, seconds) = now.to_offset(self.offset).to_hms();
+        u32::from(hours) * 60 * 60 + u32::from(minutes) * 60 + u32::from(seconds)
+    }
+}
+
+/// Describes the schedule like "from 02:00, between 01:00 and 06:00"
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.start_at, self.window) {
+            (Some(start_at), Some(window)) => write!(f, "from {}, {}", start_at, window),
+            (Some(start_at), None) => write!(f, "from {}", start_at),
+            (None, Some(window)) => write!(f, "{}", window),
+            (None, None) => write!(f, "at any time"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the time of the day in UTC
+    fn at(hours: i64, minutes: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(19_000 * 86_400 + hours * 3600 + minutes * 60).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time_of_day() {
+        assert_eq!(parse_time_of_day("02:00").unwrap(), TimeOfDay(120));
+        assert_eq!(parse_time_of_day("2:05").unwrap(), TimeOfDay(125));
+        assert_eq!(parse_time_of_day("23:59").unwrap().to_string(), "23:59");
+        for input in ["", "24:00", "12:60", "12", "12:5", "-1:00", "+1:00", "012:00", "ab:cd"] {
+            assert!(parse_time_of_day(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let window = parse_window("01:00-06:00").unwrap();
+        assert_eq!(window.to_string(), "between 01:00 and 06:00");
+        assert!(window.contains(60 * 60));
+        assert!(!window.contains(6 * 60 * 60));
+        assert!(parse_window("01:00-01:00").is_err());
+        assert!(parse_window("01:00").is_err());
+
+        let overnight = parse_window("22:00-06:00").unwrap();
+        assert!(overnight.contains(23 * 60 * 60));
+        assert!(overnight.contains(5 * 60 * 60));
+        assert!(!overnight.contains(12 * 60 * 60));
+    }
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(Schedule::new(None, None, UtcOffset::UTC), None);
+
+        let schedule = Schedule::new(parse_time_of_day("02:00").ok(), None, UtcOffset::UTC).unwrap();
+        assert_eq!(schedule.until_start(at(1, 30)), Duration::from_secs(30 * 60));
+        assert_eq!(schedule.until_start(at(2, 0)), Duration::ZERO);
+        // the next day
+        assert_eq!(schedule.until_start(at(2, 30)), Duration::from_secs(23 * 60 * 60 + 30 * 60));
+        assert_eq!(schedule.until_window(at(12, 0)), Duration::ZERO);
+
+        let schedule = Schedule::new(None, parse_window("22:00-06:00").ok(), UtcOffset::UTC).unwrap();
+        assert_eq!(schedule.until_start(at(12, 0)), Duration::ZERO);
+        assert_eq!(schedule.until_window(at(23, 0)), Duration::ZERO);
+        assert_eq!(schedule.until_window(at(6, 0)), Duration::from_secs(16 * 60 * 60));
+        assert_eq!(schedule.to_string(), "between 22:00 and 06:00");
+    }
+
+    #[test]
+    fn test_schedule_local_time() {
+        let offset = UtcOffset::from_hms(2, 0, 0).unwrap();
+        let schedule = Schedule::new(parse_time_of_day("02:00").ok(), parse_window("01:00-06:00").ok(), offset).unwrap();
+        // 00:00 UTC is 02:00 local time
+        assert_eq!(schedule.until_start(at(0, 0)), Duration::ZERO);
+        assert_eq!(schedule.until_window(at(0, 0)), Duration::ZERO);
+        assert_eq!(schedule.until_window(at(4, 0)), Duration::from_secs(19 * 60 * 60));
+        assert_eq!(schedule.to_string(), "from 02:00, between 01:00 and 06:00");
+    }
+}
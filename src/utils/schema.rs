@@ -0,0 +1,477 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use serde_json::{json, Map, Value};
+
+use crate::error::{NudgeError, Result};
+
+/// Strings fed to the visitors of string fields. If a type rejects one (e.g. `SocketAddr` parsing it),
+/// the field is traced again with the next one
+const SAMPLE_STRINGS: [&str; 2] = ["", "0.0.0.0:0"];
+
+/// How often the tracing is repeated to find values every field accepts, before giving up
+const MAX_TRACE_RUNS: usize = 64;
+
+/// Derives the JSON Schema of a message from its `Deserialize` implementation.
+///
+/// The message is deserialized from a tracer which records every type the implementation asks for,
+/// so the schema follows the serde attributes (renames, skipped fields, defaults) of the model.
+/// Fields whose value the tracer can't make up (e.g. a public key) are only traced if they're optional.
+///
+/// # Errors
+///
+/// Returns `NudgeError::SchemaError` if the model uses types the tracer doesn't support (e.g. untagged enums).
+pub fn schema_of<T: DeserializeOwned>() -> Result<Value> {
+    let state = RefCell::new(TraceState::default());
+    let failed = |e: TraceError| NudgeError::SchemaError(std::any::type_name::<T>().to_string(), e.to_string());
+
+    // find values every field accepts
+    let mut runs = 0;
+    while let Err(e) = trace::<T>(&state).1 {
+        runs += 1;
+        if runs == MAX_TRACE_RUNS || !state.borrow_mut().retry_differently() {
+            return Err(failed(e));
+        }
+    }
+
+    // leave out one field at a time to find out which are required
+    let fields = state.borrow().fields.clone();
+    for (path, field) in fields {
+        state.borrow_mut().omitted = Some((path.clone(), field));
+        if let Err(TraceError::MissingField(missing)) = trace::<T>(&state).1 {
+            if missing == field {
+                state.borrow_mut().required.insert((path, field));
+            }
+        }
+    }
+    state.borrow_mut().omitted = None;
+
+    let (schema, result) = trace::<T>(&state);
+    result.map_err(failed)?;
+    Ok(schema)
+}
+
+/// Runs the `Deserialize` implementation of the message once against the tracer.
+fn trace<T: DeserializeOwned>(state: &RefCell<TraceState>) -> (Value, std::result::Result<T, TraceError>) {
+    state.borrow_mut().begin_run();
+    let mut schema = Value::Null;
+    let result = T::deserialize(Tracer { state, path: String::new(), schema: &mut schema });
+    (schema, result)
+}
+
+/// What the tracer learned about the message in earlier runs
+#[derive(Debug, Default)]
+struct TraceState {
+    /// Index into `SAMPLE_STRINGS` per string field
+    samples: HashMap<String, usize>,
+
+    /// Optional fields traced as `None`, since no sample was accepted as their value
+    none_options: HashSet<String>,
+
+    /// Schemas of the values of `none_options`, recorded while they were still traced
+    option_schemas: HashMap<String, Value>,
+
+    /// Fields of the structs in the message, as (path of the struct, name of the field)
+    fields: Vec<(String, &'static str)>,
+
+    /// Fields which the message can't be deserialized without
+    required: HashSet<(String, &'static str)>,
+
+    /// Field left out of the message in this run
+    omitted: Option<(String, &'static str)>,
+
+    /// The last string field traced in this run
+    last_string: Option<String>,
+
+    /// String field whose sample was rejected in this run
+    failed_string: Option<String>,
+
+    /// Innermost optional field whose value was rejected in this run
+    failed_option: Option<String>,
+}
+
+impl TraceState {
+    fn begin_run(&mut self) {
+        self.fields.clear();
+        self.last_string = None;
+        self.failed_string = None;
+        self.failed_option = None;
+    }
+
+    /// Changes the values of the fields which made the last run fail.
+    ///
+    /// # Returns
+    ///
+    /// `bool` - Whether there is something left to try.
+    fn retry_differently(&mut self) -> bool {
+        if let Some(path) = self.failed_string.take() {
+            let sample = self.samples.entry(path).or_default();
+            if *sample + 1 < SAMPLE_STRINGS.len() {
+                *sample += 1;
+                return true;
+            }
+        }
+        match self.failed_option.take() {
+            Some(path) => self.none_options.insert(path),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum TraceError {
+    MissingField(&'static str),
+    Custom(String),
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::MissingField(field) => write!(f, "missing field `{}`", field),
+            TraceError::Custom(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: Display>(message: T) -> Self {
+        TraceError::Custom(message.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        TraceError::MissingField(field)
+    }
+}
+
+/// Deserializer answering every request with a sample value, recording the schema of the requested type
+struct Tracer<'a> {
+    state: &'a RefCell<TraceState>,
+
+    /// Path of the value in the message, e.g. "/extents/items/offset"
+    path: String,
+
+    /// Where the schema of the value is recorded
+    schema: &'a mut Value,
+}
+
+impl Tracer<'_> {
+    fn unsigned<'de, V: Visitor<'de>>(self, visitor: V, max: Option<u64>) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = match max {
+            Some(max) => json!({"type": "integer", "minimum": 0, "maximum": max}),
+            None => json!({"type": "integer", "minimum": 0}),
+        };
+        visitor.visit_u64(0)
+    }
+
+    fn string<'de, V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "string"});
+        let sample = self.state.borrow().samples.get(&self.path).copied().unwrap_or_default();
+        self.state.borrow_mut().last_string = Some(self.path.clone());
+        let result = visitor.visit_str(SAMPLE_STRINGS[sample]);
+        if result.is_err() {
+            self.state.borrow_mut().failed_string.get_or_insert(self.path);
+        }
+        result
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Tracer<'_> {
+    type Error = TraceError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> std::result::Result<V::Value, TraceError> {
+        Err(TraceError::Custom(format!("cannot trace the self-describing value at {}", self.path)))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "boolean"});
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "integer"});
+        visitor.visit_i64(0)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.unsigned(visitor, Some(u8::MAX as u64))
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.unsigned(visitor, Some(u16::MAX as u64))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.unsigned(visitor, Some(u32::MAX as u64))
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.unsigned(visitor, None)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "number"});
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "string", "minLength": 1, "maxLength": 1});
+        visitor.visit_char(' ')
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.string(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        if self.state.borrow().none_options.contains(&self.path) {
+            let inner = self.state.borrow().option_schemas.get(&self.path).cloned().unwrap_or(json!({}));
+            *self.schema = json!({"anyOf": [inner, {"type": "null"}]});
+            return visitor.visit_none();
+        }
+
+        let mut inner = Value::Null;
+        let result = visitor.visit_some(Tracer { state: self.state, path: self.path.clone(), schema: &mut inner });
+        if result.is_err() {
+            let mut state = self.state.borrow_mut();
+            state.option_schemas.insert(self.path.clone(), inner.clone());
+            state.failed_option.get_or_insert(self.path);
+        }
+        *self.schema = json!({"anyOf": [inner, {"type": "null"}]});
+        result
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "null"});
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, TraceError> {
+        let mut items = Value::Null;
+        let result = visitor.visit_seq(SampleSeq {
+            state: self.state,
+            path: format!("{}/items", self.path),
+            items: Some(&mut items),
+        });
+        *self.schema = json!({"type": "array", "items": items});
+        result
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, TraceError> {
+        let (omitted, required) = {
+            let mut state = self.state.borrow_mut();
+            state.fields.extend(fields.iter().map(|field| (self.path.clone(), *field)));
+            let omitted = state.omitted.as_ref()
+                .filter(|(path, _)| *path == self.path)
+                .map(|(_, field)| *field);
+            let required: Vec<&str> = fields.iter()
+                .filter(|field| state.required.contains(&(self.path.clone(), **field)))
+                .copied()
+                .collect();
+            (omitted, required)
+        };
+
+        let mut properties = Map::new();
+        let result = visitor.visit_map(SampleStruct {
+            state: self.state,
+            path: &self.path,
+            fields: fields.iter().filter(|field| Some(**field) != omitted).copied().collect(),
+            current: None,
+            properties: &mut properties,
+        });
+        *self.schema = json!({"title": name, "type": "object", "properties": properties, "required": required});
+        result
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "string", "enum": variants});
+        let variant = variants.first().ok_or_else(|| TraceError::Custom(format!("empty enum at {}", self.path)))?;
+        visitor.visit_enum(UnitVariant(variant))
+    }
+
+    forward_to_deserialize_any! {
+        bytes byte_buf tuple tuple_struct map identifier ignored_any
+    }
+}
+
+/// Sequence holding a single sample item
+struct SampleSeq<'a> {
+    state: &'a RefCell<TraceState>,
+    path: String,
+    items: Option<&'a mut Value>,
+}
+
+impl<'de> SeqAccess<'de> for SampleSeq<'_> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> std::result::Result<Option<T::Value>, TraceError> {
+        match self.items.take() {
+            Some(schema) => seed.deserialize(Tracer { state: self.state, path: self.path.clone(), schema }).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Map holding a sample value for every field of a struct
+struct SampleStruct<'a> {
+    state: &'a RefCell<TraceState>,
+    path: &'a str,
+    fields: Vec<&'static str>,
+    current: Option<&'static str>,
+    properties: &'a mut Map<String, Value>,
+}
+
+impl<'de> MapAccess<'de> for SampleStruct<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> std::result::Result<Option<K::Value>, TraceError> {
+        if self.fields.is_empty() {
+            return Ok(None);
+        }
+        let field = self.fields.remove(0);
+        self.current = Some(field);
+        seed.deserialize(IntoDeserializer::<TraceError>::into_deserializer(field)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> std::result::Result<V::Value, TraceError> {
+        let field = self.current.take().expect("values are requested after their key");
+        let path = format!("{}/{}", self.path, field);
+        let schema = self.properties.entry(field).or_insert(Value::Null);
+        let result = seed.deserialize(Tracer { state: self.state, path, schema });
+        if result.is_err() {
+            // the string was accepted, but not the value parsed from it (e.g. `#[serde(try_from = "String")]`)
+            let mut state = self.state.borrow_mut();
+            if state.failed_string.is_none() && state.failed_option.is_none() {
+                state.failed_string = state.last_string.clone();
+            }
+        }
+        result
+    }
+}
+
+/// Chooses the first variant of a unit-only enum
+struct UnitVariant(&'static str);
+
+impl<'de> EnumAccess<'de> for UnitVariant {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> std::result::Result<(V::Value, Self), TraceError> {
+        let variant = seed.deserialize(IntoDeserializer::<TraceError>::into_deserializer(self.0))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for UnitVariant {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> std::result::Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, _: T) -> std::result::Result<T::Value, TraceError> {
+        Err(TraceError::Custom(format!("cannot trace the data of variant {}", self.0)))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, _: V) -> std::result::Result<V::Value, TraceError> {
+        Err(TraceError::Custom(format!("cannot trace the data of variant {}", self.0)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], _: V) -> std::result::Result<V::Value, TraceError> {
+        Err(TraceError::Custom(format!("cannot trace the data of variant {}", self.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use serde::Deserialize;
+
+    use crate::models::{FileInfo, R2XRequestSenderConnectionMessage};
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Sample {
+        count: u16,
+        #[serde(rename = "addr")]
+        address: SocketAddr,
+        #[serde(default)]
+        names: Vec<String>,
+        #[serde(skip)]
+        internal: bool,
+    }
+
+    #[test]
+    fn test_schema_of() {
+        let schema = schema_of::<Sample>().unwrap();
+        assert_eq!(schema, json!({
+            "title": "Sample",
+            "type": "object",
+            "properties": {
+                "count": {"type": "integer", "minimum": 0, "maximum": 65535},
+                "addr": {"type": "string"},
+                "names": {"type": "array", "items": {"type": "string"}},
+            },
+            "required": ["count", "addr"],
+        }));
+    }
+
+    #[test]
+    fn test_schema_of_models() {
+        let schema = schema_of::<FileInfo>().unwrap();
+        assert_eq!(schema["properties"]["kind"]["enum"], json!(["file", "exchange", "pipe", "forward"]));
+        assert_eq!(schema["properties"]["sender_host"], json!({"anyOf": [{"type": "string"}, {"type": "null"}]}));
+        assert!(schema["properties"]["sender_geo"]["anyOf"][0]["properties"]["asn"].is_object());
+        assert!(schema["properties"].get("receiver_addr").is_none());
+        assert_eq!(schema["required"], json!(["file_size", "file_name", "file_hash", "sender_host", "created_at", "sender_addr"]));
+
+        // public keys can't be made up, but the field is optional
+        let schema = schema_of::<R2XRequestSenderConnectionMessage>().unwrap();
+        assert_eq!(schema["properties"]["identity"], json!({"anyOf": [{"type": "string"}, {"type": "null"}]}));
+    }
+}
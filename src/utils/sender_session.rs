@@ -0,0 +1,216 @@
+//! Sessions of `send --resume-session <NAME>`, recorded in the configuration directory (`send-sessions/`).
+//! A session keeps the registration ID, so a restarted sender takes over the session at the relay with the same
+//! passphrase, the hash of the file, so it isn't hashed again, and how much of the file the receiver acknowledged.
+//! The record is removed once the receiver got the whole file.
+
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::utils::hash_cache::FileIdentity;
+use crate::utils::platform::config_dir;
+use crate::utils::{encode_hex, new_registration_id};
+
+/// Name of the directory in the configuration directory holding the sessions
+const SESSIONS_DIR_NAME: &str = "send-sessions";
+
+/// How often the acknowledged bytes are recorded while sending, in milliseconds
+const SAVE_INTERVAL_MILLIS: u64 = 1000;
+
+/// Content of a session record
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SessionState {
+    /// ID the session is registered with at the relay
+    registration_id: String,
+
+    /// The file sent, the hash and the progress only apply to the same file
+    file: Option<FileIdentity>,
+    file_hash: Option<String>,
+
+    /// Bytes of the file the receiver acknowledged, from its beginning
+    acknowledged: u64,
+}
+
+/// Records a session of the sender, the last progress is recorded when this is dropped
+/// before the receiver got the whole file.
+#[derive(Debug)]
+pub struct SenderSession {
+    path: PathBuf,
+    state: SessionState,
+
+    /// When the progress was recorded last (unix millis)
+    saved_at: u64,
+    complete: bool,
+}
+
+impl SenderSession {
+    /// Opens the session in the configuration directory, see `config_dir`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the session (`--resume-session`).
+    /// * `file` - Identity of the file sent (optional, e.g. not for directories).
+    ///
+    /// # Returns
+    ///
+    /// `Option<SenderSession>` - The session, or `None` if there is no configuration directory.
+    pub fn open(name: &str, file: Option<FileIdentity>) -> Option<Self> {
+        config_dir().map(|dir| Self::load(dir.join(SESSIONS_DIR_NAME).join(session_file_name(name)), file))
+    }
+
+    /// Loads the session from a file. A missing or corrupt file starts a new session, a session of another
+    /// file keeps its registration ID but starts over.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the session file.
+    /// * `file` - Identity of the file sent (optional).
+    pub fn load(path: PathBuf, file: Option<FileIdentity>) -> Self {
+        let recorded: Option<SessionState> = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+                debug!(target: "io", "Ignoring corrupt session {}: {}", path.display(), e);
+            }).ok(),
+            Err(_) => None,
+        };
+        let state = match recorded {
+            // without an identity, the file might have changed since
+            Some(state) if file.is_some() && state.file == file => state,
+            Some(state) => SessionState { file, file_hash: None, acknowledged: 0, ..state },
+            None => SessionState { registration_id: new_registration_id(), file, file_hash: None, acknowledged: 0 },
+        };
+        SenderSession { path, state, saved_at: 0, complete: false }
+    }
+
+    /// Returns the ID the session is registered with at the relay.
+    pub fn registration_id(&self) -> &str {
+        &self.state.registration_id
+    }
+
+    /// Returns the hash of the file recorded before, `None` if it wasn't or the file changed since.
+    pub fn file_hash(&self) -> Option<&str> {
+        self.state.file_hash.as_deref()
+    }
+
+    /// Returns the bytes of the file the receiver acknowledged before.
+    pub fn acknowledged(&self) -> u64 {
+        self.state.acknowledged
+    }
+
+    /// Records the session with the hash of the file, before the first receiver connects.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_hash` - The hash of the file (optional).
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the session can't be written.
+    pub fn start(&mut self, file_hash: Option<String>, now: u64) -> Result<()> {
+        self.state.file_hash = file_hash;
+        self.saved_at = now;
+        self.save()
+    }
+
+    /// Notes the bytes of the file the receiver acknowledged, recorded at most every `SAVE_INTERVAL_MILLIS`.
+    /// Failing to record is logged, not fatal to the transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `acknowledged` - Bytes of the file the receiver acknowledged, from its beginning.
+    /// * `now` - The current time in unix millis.
+    pub fn record(&mut self, acknowledged: u64, now: u64) {
+        self.state.acknowledged = acknowledged;
+        if now.saturating_sub(self.saved_at) < SAVE_INTERVAL_MILLIS {
+            return;
+        }
+        self.saved_at = now;
+        if let Err(e) = self.save() {
+            warn!(target: "io", "Cannot record the progress in {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Removes the session, the receiver got the whole file and there's nothing left to resume.
+    pub fn complete(&mut self) {
+        self.complete = true;
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(target: "io", "Cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_string(&self.state)?)?;
+        Ok(())
+    }
+}
+
+impl Drop for SenderSession {
+    fn drop(&mut self) {
+        if self.complete {
+            return;
+        }
+        match self.save() {
+            Ok(()) => debug!(target: "io", "Recorded {} acknowledged bytes in {}", self.state.acknowledged, self.path.display()),
+            Err(e) => warn!(target: "io", "Cannot record the progress in {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Returns the name of the file of a session, derived from the hash of its name so any name is a valid file name.
+fn session_file_name(name: &str) -> String {
+    format!("{}.json", encode_hex(&blake3::hash(name.as_bytes()).as_bytes()[..8]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity_of(path: &std::path::Path) -> Option<FileIdentity> {
+        FileIdentity::of(path, &fs::metadata(path).unwrap())
+    }
+
+    #[test]
+    fn test_resume_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("report.pdf");
+        fs::write(&file, [0; 100]).unwrap();
+        let path = dir.path().join("sessions").join(session_file_name("backup"));
+
+        let mut session = SenderSession::load(path.clone(), identity_of(&file));
+        let registration_id = session.registration_id().to_string();
+        assert_eq!(session.acknowledged(), 0);
+        session.start(Some("abc".to_string()), 0).unwrap();
+        session.record(10, 500);
+        session.record(40, 1000);
+        assert_eq!(SenderSession::load(path.clone(), identity_of(&file)).acknowledged(), 40);
+        session.record(60, 1200);
+        drop(session);
+
+        let session = SenderSession::load(path.clone(), identity_of(&file));
+        assert_eq!(session.registration_id(), registration_id);
+        assert_eq!(session.file_hash(), Some("abc"));
+        assert_eq!(session.acknowledged(), 60);
+        drop(session);
+
+        // another file keeps the registration ID, but starts over
+        let other = dir.path().join("other.pdf");
+        fs::write(&other, [0; 10]).unwrap();
+        let mut session = SenderSession::load(path.clone(), identity_of(&other));
+        assert_eq!(session.registration_id(), registration_id);
+        assert_eq!((session.file_hash(), session.acknowledged()), (None, 0));
+        session.complete();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_session_file_name() {
+        assert_eq!(session_file_name("backup"), session_file_name("backup"));
+        assert_ne!(session_file_name("backup"), session_file_name("backup2"));
+        assert_eq!(session_file_name("../../etc/passwd").len(), 16 + ".json".len());
+    }
+}
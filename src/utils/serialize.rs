@@ -1,8 +1,41 @@
+use rand::{Rng, thread_rng};
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
+use std::io::ErrorKind;
 use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::error::{NudgeError, Result};
+use crate::models::RelayBusyMessage;
+
+/// How long a request is sent to the relay again before giving up (`--relay-timeout`)
+pub const DEFAULT_RELAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+static RELAY_TIMEOUT_MILLIS: AtomicU64 = AtomicU64::new(DEFAULT_RELAY_TIMEOUT.as_millis() as u64);
+
+/// How long to wait for the first response of the relay, doubled for every retry
+const RELAY_INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest time to wait for a response of the relay
+const RELAY_MAX_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How often a request is sent again after the relay said it's busy
+const RELAY_BUSY_ATTEMPTS: u32 = 5;
+
+/// Longest pause a busy relay can ask for
+const RELAY_MAX_BUSY_PAUSE: Duration = Duration::from_secs(5 * 60);
+
+/// Sets how long requests to the relay are sent again before giving up (`--relay-timeout`), for the whole process.
+pub fn set_relay_timeout(timeout: Duration) {
+    RELAY_TIMEOUT_MILLIS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Returns how long requests to the relay are sent again before giving up.
+pub fn relay_timeout() -> Duration {
+    Duration::from_millis(RELAY_TIMEOUT_MILLIS.load(Ordering::Relaxed))
+}
 
 /// Serializes the given data and sends it over the provided UDP socket with the specified prefix.
 ///
@@ -46,6 +79,10 @@ pub fn receive_and_parse_and_expect<T>(connection: &UdpSocket, expected_prefix:
     if message_trimmed.starts_with("ERROR ") {
         return Err(NudgeError::ServerError(message_trimmed.to_string()));
     }
+    if let Some(payload) = message_trimmed.strip_prefix("BUSY ") {
+        let busy: RelayBusyMessage = serde_json::from_str(payload.trim())?;
+        return Err(NudgeError::RelayBusy(busy.retry_after_secs));
+    }
 
     let prefix = message_trimmed.split_whitespace().next().unwrap_or_default();
     if prefix != expected_prefix {
@@ -58,3 +95,195 @@ pub fn receive_and_parse_and_expect<T>(connection: &UdpSocket, expected_prefix:
     let part = message_trimmed[prefix.len()..].trim();
     Ok(serde_json::from_str(part)?)
 }
+
+/// Like `receive_and_parse_and_expect`, but skips messages with another prefix,
+/// e.g. a late response to a request which was retried.
+///
+/// # Arguments
+///
+/// * `connection` - A reference to the `UdpSocket` used for receiving the data.
+/// * `expected_prefix` - The expected prefix of the received message.
+///
+/// # Errors
+///
+/// Returns `NudgeError` if receiving the message fails, if the message contains an error or if deserialization fails.
+pub fn receive_expected<T>(connection: &UdpSocket, expected_prefix: &str) -> Result<T>
+    where
+        T: DeserializeOwned
+{
+    loop {
+        match receive_and_parse_and_expect(connection, expected_prefix) {
+            Err(NudgeError::ReceiveExpectationNotMet(expected, received)) => {
+                debug!(target: "relay_client", "Ignoring {} while waiting for {} (duplicate response?)", received, expected);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends a request to the relay and waits for the response.
+/// Since packets can be lost, the request is sent again with an exponential backoff if the relay doesn't respond,
+/// until the relay timeout (see `set_relay_timeout`) is used up. If the relay is busy, the request is sent again after the pause the relay asked for (plus some jitter).
+///
+/// # Arguments
+///
+/// * `connection` - A reference to the `UdpSocket` connected to the relay.
+/// * `prefix` - The prefix of the request.
+/// * `data` - The payload of the request.
+/// * `expected_prefix` - The prefix of the response.
+///
+/// # Errors
+///
+/// Returns `NudgeError::RelayTimeout` if the relay didn't respond in time,
+/// `NudgeError::RelayBusy` if it stayed busy, or the errors of `serialize_and_send` and `receive_expected`.
+pub fn request<T>(connection: &UdpSocket, prefix: &str, data: &impl Serialize, expected_prefix: &str) -> Result<T>
+    where
+        T: DeserializeOwned
+{
+    request_within(connection, prefix, data, expected_prefix, relay_timeout())
+}
+
+/// Like `request`, but gives up after the given time instead of the relay timeout of the process.
+fn request_within<T>(
+    connection: &UdpSocket,
+    prefix: &str,
+    data: &impl Serialize,
+    expected_prefix: &str,
+    relay_timeout: Duration,
+) -> Result<T>
+    where
+        T: DeserializeOwned
+{
+    let mut timeout = RELAY_INITIAL_TIMEOUT;
+    let mut deadline = Instant::now() + relay_timeout;
+    let mut attempt = 1;
+    let mut busy_attempt = 0;
+    let response = loop {
+        // a zero timeout is rejected by the socket, so wait at least a millisecond
+        let remaining = deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+        if let Err(e) = serialize_and_send(connection, prefix, data)
+            .and_then(|_| Ok(connection.set_read_timeout(Some(timeout.min(remaining)))?))
+        {
+            break Err(e);
+        }
+        match receive_expected(connection, expected_prefix) {
+            Err(NudgeError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if Instant::now() >= deadline {
+                    break Err(NudgeError::RelayTimeout(relay_timeout));
+                }
+                warn!(target: "relay_client", "No response from the relay, sending {} again (attempt {})", prefix, attempt + 1);
+                attempt += 1;
+                timeout = (timeout * 2).min(RELAY_MAX_TIMEOUT);
+            }
+            Err(NudgeError::RelayBusy(retry_after_secs)) if busy_attempt < RELAY_BUSY_ATTEMPTS => {
+                busy_attempt += 1;
+                let pause = busy_pause(retry_after_secs);
+                warn!(target: "relay_client", "The relay is busy, sending {} again in {:?} ({}/{})", prefix, pause, busy_attempt, RELAY_BUSY_ATTEMPTS);
+                thread::sleep(pause);
+                // the relay answered, so the time waiting for it starts over
+                deadline = Instant::now() + relay_timeout;
+            }
+            result => break result,
+        }
+    };
+    connection.set_read_timeout(None)?;
+    response
+}
+
+/// Returns how long to wait before asking a busy relay again.
+/// The pause is stretched by up to 50 % at random, so the turned away senders don't all return at once.
+fn busy_pause(retry_after_secs: u64) -> Duration {
+    Duration::from_secs(retry_after_secs)
+        .min(RELAY_MAX_BUSY_PAUSE)
+        .mul_f64(thread_rng().gen_range(1.0..1.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Ping {
+        id: u32,
+    }
+
+    #[test]
+    fn test_request_retries_and_skips_duplicates() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(relay.local_addr().unwrap()).unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            // drop the first request, answer the second one after a stale message
+            relay.recv_from(&mut buffer).unwrap();
+            let (_, addr) = relay.recv_from(&mut buffer).unwrap();
+            relay.send_to(b"X2S_PPM {}", addr).unwrap();
+            relay.send_to(b"PONG {\"id\":1}", addr).unwrap();
+        });
+
+        let response: Ping = request(&client, "PING", &Ping { id: 1 }, "PONG").unwrap();
+        assert_eq!(response, Ping { id: 1 });
+        assert_eq!(client.read_timeout().unwrap(), None);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_retries_busy_relay() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(relay.local_addr().unwrap()).unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            let (_, addr) = relay.recv_from(&mut buffer).unwrap();
+            relay.send_to(b"BUSY {\"retry_after_secs\":0}\n", addr).unwrap();
+            let (_, addr) = relay.recv_from(&mut buffer).unwrap();
+            relay.send_to(b"PONG {\"id\":1}", addr).unwrap();
+        });
+
+        let response: Ping = request(&client, "PING", &Ping { id: 1 }, "PONG").unwrap();
+        assert_eq!(response, Ping { id: 1 });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_times_out() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(relay.local_addr().unwrap()).unwrap();
+
+        let start = Instant::now();
+        let response: Result<Ping> = request_within(&client, "PING", &Ping { id: 1 }, "PONG", Duration::from_millis(300));
+        assert!(matches!(response, Err(NudgeError::RelayTimeout(timeout)) if timeout == Duration::from_millis(300)));
+        assert!(start.elapsed() < RELAY_INITIAL_TIMEOUT);
+        drop(relay);
+    }
+
+    #[test]
+    fn test_busy_pause() {
+        assert_eq!(busy_pause(0), Duration::ZERO);
+        let pause = busy_pause(10);
+        assert!(pause >= Duration::from_secs(10) && pause < Duration::from_secs(15));
+        assert!(busy_pause(u64::MAX) < RELAY_MAX_BUSY_PAUSE * 2);
+    }
+
+    #[test]
+    fn test_request_returns_server_error() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(relay.local_addr().unwrap()).unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            let (_, addr) = relay.recv_from(&mut buffer).unwrap();
+            relay.send_to(b"ERROR Passphrase not found\n", addr).unwrap();
+        });
+
+        let response: Result<Ping> = request(&client, "PING", &Ping { id: 1 }, "PONG");
+        assert!(matches!(response, Err(NudgeError::ServerError(_))));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+//! State files next to the output of `get`, naming the session and the receiver writing it.
+//! Unlike the lock on the output file itself, they work on shared filesystems (NFS, SMB), where two receivers
+//! redeeming the same passphrase (or the copies of `send --copies`) could otherwise write the same file at once.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::get_hostname;
+use crate::utils::passphrase::Passphrase;
+use crate::utils::platform::process_alive;
+
+/// Appended to the name of the output file, e.g. `report.pdf.nudge-lock`
+pub const LOCK_SUFFIX: &str = ".nudge-lock";
+
+/// Context of the session hash, so the state file doesn't reveal the passphrase
+const SESSION_CONTEXT: &str = "nudge-ngx 2024 session lock";
+
+/// Content of a state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LockState {
+    /// Hash of the passphrase the file is received with
+    session: String,
+
+    /// Host and process of the receiver
+    host: String,
+    pid: u32,
+
+    /// When the receiver started writing (unix millis)
+    started_at: u64,
+}
+
+impl LockState {
+    /// Describes the receiver holding the lock, e.g. "laptop (pid 4242)"
+    fn holder(&self) -> String {
+        format!("{} (pid {})", self.host, self.pid)
+    }
+
+    /// Whether the receiver is known to be gone: it ran on this host and its process doesn't exist anymore.
+    fn is_stale(&self, host: &str) -> bool {
+        self.host == host && process_alive(self.pid) == Some(false)
+    }
+}
+
+/// Claims the output of a session while it's received, the state file is removed when this is dropped.
+#[derive(Debug)]
+pub struct SessionLock {
+    path: PathBuf,
+}
+
+impl SessionLock {
+    /// Creates the state file next to the output, taking over state files of receivers which crashed on this host.
+    ///
+    /// # Arguments
+    ///
+    /// * `out_file_name` - Path of the output file.
+    /// * `passphrase` - The passphrase the file is received with.
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::DuplicateSession` if another receiver writes the file for the same session,
+    /// `NudgeError::FileLocked` if it writes the file for another session, or `NudgeError::Io` if the state file
+    /// can't be created.
+    pub fn acquire(out_file_name: &Path, passphrase: &Passphrase, now: u64) -> Result<Self> {
+        let path = lock_path(out_file_name);
+        let host = get_hostname().unwrap_or_default();
+        let state = LockState {
+            session: session_hash(passphrase),
+            host: host.clone(),
+            pid: std::process::id(),
+            started_at: now,
+        };
+
+        // a stale state file is taken over once, a second conflict means someone else took it over meanwhile
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let written = file.write_all(serde_json::to_string(&state)?.as_bytes());
+                    let lock = SessionLock { path };
+                    written?;
+                    debug!(target: "io", "Created state file {}", lock.path.display());
+                    return Ok(lock);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let holder = match fs::read_to_string(&path).ok().and_then(|json| serde_json::from_str::<LockState>(&json).ok()) {
+                Some(holder) => holder,
+                // the holder might still be writing it
+                None => return Err(NudgeError::FileLocked(out_file_name.display().to_string())),
+            };
+            if holder.is_stale(&host) {
+                warn!(target: "io", "Removing the state file of {}, which is gone", holder.holder());
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if holder.session == state.session {
+                return Err(NudgeError::DuplicateSession(
+                    out_file_name.display().to_string(),
+                    holder.holder(),
+                    path.display().to_string(),
+                ));
+            }
+            return Err(NudgeError::FileLocked(out_file_name.display().to_string()));
+        }
+        Err(NudgeError::FileLocked(out_file_name.display().to_string()))
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(target: "io", "Cannot remove the state file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Returns the path of the state file of an output file.
+pub fn lock_path(out_file_name: &Path) -> PathBuf {
+    let mut name = out_file_name.file_name().unwrap_or_default().to_os_string();
+    name.push(LOCK_SUFFIX);
+    out_file_name.with_file_name(name)
+}
+
+/// Returns the hash identifying the session in the state file.
+fn session_hash(passphrase: &Passphrase) -> String {
+    blake3::Hash::from(blake3::derive_key(SESSION_CONTEXT, passphrase.0.as_bytes())).to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("report.pdf");
+        let passphrase = Passphrase::from("correct-horse-battery");
+
+        let lock = SessionLock::acquire(&out, &passphrase, 1).unwrap();
+        let state = fs::read_to_string(dir.path().join("report.pdf.nudge-lock")).unwrap();
+        assert!(!state.contains("horse"));
+        assert!(matches!(
+            SessionLock::acquire(&out, &passphrase, 2),
+            Err(NudgeError::DuplicateSession(..))
+        ));
+        assert!(matches!(
+            SessionLock::acquire(&out, &Passphrase::from("other"), 2),
+            Err(NudgeError::FileLocked(_))
+        ));
+
+        drop(lock);
+        assert!(!lock_path(&out).exists());
+        SessionLock::acquire(&out, &passphrase, 3).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_acquire_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("report.pdf");
+        let passphrase = Passphrase::from("correct-horse-battery");
+        let mut crashed = LockState {
+            session: session_hash(&passphrase),
+            host: get_hostname().unwrap_or_default(),
+            pid: i32::MAX as u32,
+            started_at: 1,
+        };
+        fs::write(lock_path(&out), serde_json::to_string(&crashed).unwrap()).unwrap();
+        let lock = SessionLock::acquire(&out, &passphrase, 2).unwrap();
+        drop(lock);
+
+        // receivers on other hosts can't be checked
+        crashed.host = "elsewhere".to_string();
+        fs::write(lock_path(&out), serde_json::to_string(&crashed).unwrap()).unwrap();
+        assert!(matches!(
+            SessionLock::acquire(&out, &passphrase, 2),
+            Err(NudgeError::DuplicateSession(..))
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+
+use crate::error::{NudgeError, Result};
+use crate::models::S2XRequestPassphraseMessage;
+use crate::utils::passphrase::{Passphrase, PassphraseGenerator};
+
+/// Prefix of messages passed between a front relay and its shards,
+/// followed by the address of the client and the client's message (or the response to it)
+const FORWARD_PREFIX: &str = "FWD ";
+
+/// How long the front relay remembers the passphrase it generated for a registration
+const GENERATED_PASSPHRASE_LIFETIME_MILLIS: u64 = 60 * 1000;
+
+/// Passphrases the front relay generated for registrations,
+/// so retries of a registration get the same passphrase and reach the same shard
+#[derive(Debug, Default)]
+pub struct GeneratedPassphrases {
+    /// Passphrase and time of generation (unix millis), by sender address and registration ID
+    by_registration: HashMap<(SocketAddr, String), (Passphrase<'static>, u64)>,
+}
+
+impl GeneratedPassphrases {
+    /// Returns the passphrase generated for the registration before, or generates a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_addr` - Address of the sender.
+    /// * `registration_id` - ID of the registration (optional, older clients don't send one).
+    /// * `passphrase_generator` - Generator for new passphrases.
+    /// * `now` - The current time in unix millis.
+    pub fn get_or_generate(
+        &mut self,
+        sender_addr: &SocketAddr,
+        registration_id: Option<&str>,
+        passphrase_generator: &PassphraseGenerator,
+        now: u64,
+    ) -> Result<Passphrase<'static>> {
+        let Some(registration_id) = registration_id else {
+            return passphrase_generator.generate().ok_or(NudgeError::PassphraseGenerationError);
+        };
+        let key = (*sender_addr, registration_id.to_string());
+        if let Some((passphrase, _)) = self.by_registration.get(&key) {
+            return Ok(passphrase.clone());
+        }
+        let passphrase = passphrase_generator.generate().ok_or(NudgeError::PassphraseGenerationError)?;
+        self.by_registration.insert(key, (passphrase.clone(), now));
+        Ok(passphrase)
+    }
+
+    /// Forgets passphrases generated too long ago to be retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in unix millis.
+    pub fn prune(&mut self, now: u64) {
+        self.by_registration.retain(|_, (_, generated_at)| {
+            now.saturating_sub(*generated_at) < GENERATED_PASSPHRASE_LIFETIME_MILLIS
+        });
+    }
+}
+
+/// Any client message which refers to a passphrase
+#[derive(Deserialize)]
+struct PassphraseMessage {
+    passphrase: Passphrase<'static>,
+}
+
+/// Wraps a message for the other side of the front relay / shard connection.
+///
+/// # Arguments
+///
+/// * `client_addr` - Address of the client the message came from (or goes to).
+/// * `message` - The message of (or for) the client.
+pub fn wrap_forwarded(client_addr: &SocketAddr, message: &[u8]) -> Vec<u8> {
+    let mut wrapped = format!("{}{} ", FORWARD_PREFIX, client_addr).into_bytes();
+    wrapped.extend_from_slice(message);
+    wrapped
+}
+
+/// Unwraps a message created by `wrap_forwarded`.
+///
+/// # Returns
+///
+/// `Option<(SocketAddr, &str)>` - The address of the client and its message, or `None` if the message isn't wrapped.
+pub fn unwrap_forwarded(message: &str) -> Option<(SocketAddr, &str)> {
+    let (client_addr, message) = message.strip_prefix(FORWARD_PREFIX)?.split_once(' ')?;
+    Some((client_addr.parse().ok()?, message))
+}
+
+/// Returns the shard responsible for a passphrase.
+/// The same passphrase always maps to the same shard, as long as the shard map doesn't change.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase of the session.
+/// * `shards` - The shard map.
+pub fn shard_for(passphrase: &Passphrase, shards: &[SocketAddr]) -> Option<SocketAddr> {
+    if shards.is_empty() {
+        return None;
+    }
+    let hash = blake3::hash(passphrase.0.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&hash.as_bytes()[..8]);
+    Some(shards[(u64::from_le_bytes(prefix) % shards.len() as u64) as usize])
+}
+
+/// Prepares a client message to be forwarded to its shard.
+///
+/// Registrations without a passphrase get a generated one here,
+/// since the shard can only be chosen once the passphrase is known.
+///
+/// # Arguments
+///
+/// * `message` - The message of the client, e.g. `R2X_RFI {...}`.
+/// * `client_addr` - Address of the client.
+/// * `passphrase_generator` - Generator for registrations without a passphrase.
+/// * `generated` - Passphrases generated for earlier attempts of registrations.
+/// * `now` - The current time in unix millis.
+///
+/// # Returns
+///
+/// `Result<(Passphrase, String)>` - The passphrase the message refers to and the message to forward.
+pub fn route_message(
+    message: &str,
+    client_addr: &SocketAddr,
+    passphrase_generator: &PassphraseGenerator,
+    generated: &mut GeneratedPassphrases,
+    now: u64,
+) -> Result<(Passphrase<'static>, String)> {
+    let (command, payload) = message.split_once(' ').ok_or(NudgeError::UnknownCommand)?;
+    match command {
+        "S2X_RP" => {
+            let mut request: S2XRequestPassphraseMessage = serde_json::from_str(payload)?;
+            let passphrase = match &request.passphrase {
+                Some(passphrase) => passphrase.clone(),
+                None => generated.get_or_generate(
+                    client_addr, request.registration_id.as_deref(), passphrase_generator, now,
+                )?,
+            };
+            request.passphrase = Some(passphrase.clone());
+            Ok((passphrase, format!("{} {}", command, serde_json::to_string(&request)?)))
+        }
+        "R2X_RFI" | "R2X_RSC" | "R2X_WAKE" | "S2X_RT" | "S2X_HB" | "S2X_RDY" => {
+            let request: PassphraseMessage = serde_json::from_str(payload)?;
+            Ok((request.passphrase, message.to_string()))
+        }
+        _ => Err(NudgeError::UnknownCommand),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let client_addr: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let wrapped = wrap_forwarded(&client_addr, b"R2X_RFI {\"passphrase\": \"a b\"}");
+        let wrapped = String::from_utf8(wrapped).unwrap();
+        assert_eq!(unwrap_forwarded(&wrapped), Some((client_addr, "R2X_RFI {\"passphrase\": \"a b\"}")));
+
+        assert_eq!(unwrap_forwarded("R2X_RFI {}"), None);
+        assert_eq!(unwrap_forwarded("FWD nonsense R2X_RFI {}"), None);
+    }
+
+    #[test]
+    fn test_shard_for() {
+        let shards: Vec<SocketAddr> = vec!["10.0.0.1:4000".parse().unwrap(), "10.0.0.2:4000".parse().unwrap()];
+        let passphrase = Passphrase::from("correct-horse-battery");
+        assert_eq!(shard_for(&passphrase, &shards), shard_for(&passphrase.clone(), &shards));
+        assert!(shards.contains(&shard_for(&passphrase, &shards).unwrap()));
+        assert_eq!(shard_for(&passphrase, &[]), None);
+
+        // passphrases are spread over all shards
+        let hits = (0..100)
+            .map(|i| shard_for(&Passphrase::from(format!("passphrase-{}", i)), &shards).unwrap())
+            .filter(|shard| *shard == shards[0])
+            .count();
+        assert!(hits > 0 && hits < 100);
+    }
+
+    #[test]
+    fn test_route_message() {
+        let generator = PassphraseGenerator::new().unwrap();
+        let mut generated = GeneratedPassphrases::default();
+        let client_addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut route = |message: &str| route_message(message, &client_addr, &generator, &mut generated, 0);
+
+        let (passphrase, message) = route(r#"R2X_RFI {"passphrase":"correct-horse"}"#).unwrap();
+        assert_eq!(passphrase, Passphrase::from("correct-horse"));
+        assert_eq!(message, r#"R2X_RFI {"passphrase":"correct-horse"}"#);
+
+        // waking the sender of an offer goes to the shard the offer is registered at
+        let wake = r#"R2X_WAKE {"passphrase":"correct-horse","receiver_host":null}"#;
+        assert_eq!(route(wake).unwrap(), (Passphrase::from("correct-horse"), wake.to_string()));
+
+        let registration = r#"S2X_RP {"file_size":1,"file_name":"a","file_hash":null,"sender_host":null}"#;
+        let (passphrase, message) = route(registration).unwrap();
+        let (_, payload) = message.split_once(' ').unwrap();
+        let request: S2XRequestPassphraseMessage = serde_json::from_str(payload).unwrap();
+        assert_eq!(request.passphrase, Some(passphrase));
+
+        assert!(route("X2S_PPM {}").is_err());
+    }
+
+    #[test]
+    fn test_generated_passphrases() {
+        let generator = PassphraseGenerator::new().unwrap();
+        let mut generated = GeneratedPassphrases::default();
+        let sender_addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let other_addr: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+
+        let first = generated.get_or_generate(&sender_addr, Some("abc"), &generator, 0).unwrap();
+        assert_eq!(generated.get_or_generate(&sender_addr, Some("abc"), &generator, 1).unwrap(), first);
+        assert_eq!(generated.by_registration.len(), 1);
+        generated.get_or_generate(&other_addr, Some("abc"), &generator, 1).unwrap();
+        generated.get_or_generate(&sender_addr, None, &generator, 1).unwrap();
+        assert_eq!(generated.by_registration.len(), 2);
+
+        generated.prune(GENERATED_PASSPHRASE_LIFETIME_MILLIS + 1);
+        assert!(generated.by_registration.is_empty());
+    }
+}
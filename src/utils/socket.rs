@@ -1,9 +1,10 @@
-use std::net::UdpSocket;
+use std::net::{SocketAddr, UdpSocket};
 use std::time::Duration;
 use std::thread;
 
 use crate::error::Result;
 use crate::utils::current_unix_millis;
+use crate::utils::tunnel;
 
 /// Synchronizes the thread to the next boundary of the specified interval in milliseconds.
 ///
@@ -30,7 +31,7 @@ fn send_packets(socket: &UdpSocket, count: usize, interval_ms: u64) -> Result<()
     for _ in 0..count {
         let start_time = current_unix_millis();
         let _ = socket.send(&[0]);
-        let elapsed = current_unix_millis() - start_time;
+        let elapsed = current_unix_millis().saturating_sub(start_time);
         thread::sleep(Duration::from_millis((interval_ms as i64 - elapsed as i64).max(0) as u64));
     }
     Ok(())
@@ -45,18 +46,21 @@ fn send_packets(socket: &UdpSocket, count: usize, interval_ms: u64) -> Result<()
 ///
 /// # Returns
 ///
-/// * `Result<()>` - An `Ok` result when the condition is met, or an error if a socket operation fails.
-fn wait_for_condition<F>(socket: &UdpSocket, condition: F) -> Result<()>
+/// * `Result<bool>` - Whether anything was received before the condition was met (or the read timed out),
+///   or an error if a socket operation fails.
+fn wait_for_condition<F>(socket: &UdpSocket, condition: F) -> Result<bool>
     where
         F: Fn(usize) -> bool,
 {
     let mut buffer = [0; 2];
+    let mut received_any = false;
     while let Ok(received) = socket.recv(&mut buffer) {
+        received_any = true;
         if !condition(received) {
             break;
         }
     }
-    Ok(())
+    Ok(received_any)
 }
 
 /// Initializes a UDP socket by setting timeouts, synchronizing to a boundary,
@@ -68,8 +72,8 @@ fn wait_for_condition<F>(socket: &UdpSocket, condition: F) -> Result<()>
 ///
 /// # Returns
 ///
-/// * `Result<()>` - An `Ok` result if the initialization succeeds, or an error otherwise.
-pub fn init_socket(socket: &UdpSocket) -> Result<()> {
+/// * `Result<bool>` - Whether the peer answered, or an error if the initialization fails.
+pub fn init_socket(socket: &UdpSocket) -> Result<bool> {
     // Set socket read and write timeouts
     socket.set_read_timeout(Some(Duration::from_secs(1)))?;
     socket.set_write_timeout(Some(Duration::from_secs(1)))?;
@@ -81,12 +85,35 @@ pub fn init_socket(socket: &UdpSocket) -> Result<()> {
     send_packets(socket, 40, 50)?;
 
     // Wait for the connection to be established
-    wait_for_condition(socket, |received| received == 1)?;
+    let answered = wait_for_condition(socket, |received| received == 1)?;
     socket.send(&[0, 0])?;
     socket.send(&[0, 0])?;
 
     wait_for_condition(socket, |received| received != 2)?;
     wait_for_condition(socket, |received| received == 2)?;
 
+    Ok(answered)
+}
+
+/// Connects the socket to the peer and discards the relay messages which arrived before,
+/// e.g. late responses to retried requests, so they aren't mistaken for packets of the peer.
+///
+/// # Arguments
+///
+/// * `socket` - A reference to the `UdpSocket`.
+/// * `peer_addr` - The address of the peer.
+pub fn connect_to_peer(socket: &UdpSocket, peer_addr: SocketAddr) -> Result<()> {
+    let peer_addr = tunnel::route_to_peer(socket.local_addr()?.port(), peer_addr)?;
+    socket.connect(peer_addr)?;
+    socket.set_nonblocking(true)?;
+    let mut buffer = [0u8; 1024];
+    while let Ok((_, from)) = socket.peek_from(&mut buffer) {
+        if from == peer_addr {
+            break;
+        }
+        debug!(target: "transport", "Discarding message from {}", from);
+        let _ = socket.recv_from(&mut buffer);
+    }
+    socket.set_nonblocking(false)?;
     Ok(())
-}
\ No newline at end of file
+}
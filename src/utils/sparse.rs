@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::error::Result;
+use crate::models::Extent;
+
+/// Maximum number of extents sent to the receiver, so the map fits into a single packet
+const MAX_EXTENTS: usize = 1024;
+
+/// Holes smaller than this are sent as data, skipping them isn't worth an extent
+const MIN_HOLE_SIZE: u64 = 64 * 1024;
+
+/// Finds the regions of a file which contain data, the regions between them are holes which read as zeros.
+/// The position of the file is reset to the start.
+///
+/// # Arguments
+///
+/// * `file` - The file to examine.
+/// * `file_size` - Size of the file in bytes.
+///
+/// # Returns
+///
+/// `Result<Option<Vec<Extent>>>` - The data regions, or `None` if the file has no holes worth skipping
+/// (or the platform or file system can't tell).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn data_extents(file: &mut File, file_size: u64) -> Result<Option<Vec<Extent>>> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < file_size {
+        let Ok(start) = libc::off_t::try_from(offset) else {
+            // offsets this large can't be passed to lseek on this platform
+            file.rewind()?;
+            return Ok(None);
+        };
+        // SAFETY: lseek only moves the position of the file, which is reset below
+        let data = unsafe { libc::lseek(fd, start, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                // no more data until the end of the file
+                Some(libc::ENXIO) => break,
+                // the file system doesn't support seeking for data
+                Some(libc::EINVAL) => {
+                    file.rewind()?;
+                    return Ok(None);
+                }
+                _ => return Err(e.into()),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(file_size));
+        if hole > data {
+            extents.push(Extent { offset: data, len: hole - data });
+        }
+        offset = hole.max(data + 1);
+    }
+    file.rewind()?;
+
+    let extents = coalesce(extents, MIN_HOLE_SIZE, MAX_EXTENTS);
+    if extents == [Extent { offset: 0, len: file_size }] {
+        return Ok(None);
+    }
+    Ok(Some(extents))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn data_extents(_: &mut File, _: u64) -> Result<Option<Vec<Extent>>> {
+    Ok(None)
+}
+
+/// Merges extents separated by small holes, doubling the minimum hole size until at most `max_extents` remain.
+fn coalesce(extents: Vec<Extent>, min_hole_size: u64, max_extents: usize) -> Vec<Extent> {
+    let mut min_hole_size = min_hole_size;
+    let mut extents = extents;
+    loop {
+        let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+        for extent in extents {
+            match merged.last_mut() {
+                Some(last) if extent.offset - (last.offset + last.len) < min_hole_size => {
+                    last.len = extent.offset + extent.len - last.offset;
+                }
+                _ => merged.push(extent),
+            }
+        }
+        if merged.len() <= max_extents {
+            return merged;
+        }
+        extents = merged;
+        min_hole_size = min_hole_size.saturating_mul(2);
+    }
+}
+
+/// Returns the number of bytes in the extents, i.e. the data actually transferred.
+pub fn data_size(extents: &[Extent]) -> u64 {
+    extents.iter().map(|extent| extent.len).sum()
+}
+
+/// Reads only the data regions of a file, one after another
+pub struct SparseReader<'a, R: Read + Seek> {
+    inner: &'a mut R,
+    extents: &'a [Extent],
+
+    /// Index of the current extent
+    index: usize,
+
+    /// Bytes read of the current extent
+    read_in_extent: u64,
+}
+
+impl<'a, R: Read + Seek> SparseReader<'a, R> {
+    pub fn new(inner: &'a mut R, extents: &'a [Extent]) -> Self {
+        SparseReader { inner, extents, index: 0, read_in_extent: 0 }
+    }
+}
+
+impl<R: Read + Seek> Read for SparseReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let Some(extent) = self.extents.get(self.index) else {
+                return Ok(0);
+            };
+            let remaining = extent.len - self.read_in_extent;
+            if remaining == 0 {
+                self.index += 1;
+                self.read_in_extent = 0;
+                continue;
+            }
+            if self.read_in_extent == 0 {
+                self.inner.seek(SeekFrom::Start(extent.offset))?;
+            }
+            let max = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let bytes_read = self.inner.read(&mut buf[..max])?;
+            if bytes_read == 0 && max > 0 {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "file shrank during the transfer"));
+            }
+            self.read_in_extent += bytes_read as u64;
+            return Ok(bytes_read);
+        }
+    }
+}
+
+/// Writes the data received for the extents at their offsets, leaving the holes in between untouched
+pub struct SparseWriter<'a, W: Write + Seek> {
+    inner: &'a mut W,
+    extents: Vec<Extent>,
+
+    /// Index of the current extent
+    index: usize,
+
+    /// Bytes written to the current extent
+    written_in_extent: u64,
+}
+
+impl<'a, W: Write + Seek> SparseWriter<'a, W> {
+    pub fn new(inner: &'a mut W, extents: Vec<Extent>) -> Self {
+        SparseWriter { inner, extents, index: 0, written_in_extent: 0 }
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let Some(extent) = self.extents.get(self.index) else {
+                return Err(std::io::Error::new(ErrorKind::InvalidData, "sender sent more data than announced"));
+            };
+            let remaining = extent.len - self.written_in_extent;
+            if remaining == 0 {
+                self.index += 1;
+                self.written_in_extent = 0;
+                continue;
+            }
+            if self.written_in_extent == 0 {
+                self.inner.seek(SeekFrom::Start(extent.offset))?;
+            }
+            let max = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let written = self.inner.write(&buf[..max])?;
+            self.written_in_extent += written as u64;
+            return Ok(written);
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn extent(offset: u64, len: u64) -> Extent {
+        Extent { offset, len }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let extents = vec![extent(0, 10), extent(15, 5), extent(100, 10), extent(1000, 1)];
+        assert_eq!(coalesce(extents.clone(), 1, 10), extents);
+        assert_eq!(coalesce(extents.clone(), 10, 10), vec![extent(0, 20), extent(100, 10), extent(1000, 1)]);
+        assert_eq!(coalesce(extents.clone(), 1, 2), vec![extent(0, 110), extent(1000, 1)]);
+        assert_eq!(coalesce(Vec::new(), 1, 1), Vec::new());
+    }
+
+    #[test]
+    fn test_sparse_read_write() {
+        let source: Vec<u8> = (0..100u8).map(|i| if (10..30).contains(&i) || i >= 90 { i } else { 0 }).collect();
+        let extents = vec![extent(10, 20), extent(90, 10)];
+        assert_eq!(data_size(&extents), 30);
+
+        let mut reader = Cursor::new(source.clone());
+        let mut stream = Vec::new();
+        let mut buffer = [0; 7];
+        let mut sparse_reader = SparseReader::new(&mut reader, &extents);
+        loop {
+            let bytes_read = sparse_reader.read(&mut buffer).unwrap();
+            if bytes_read == 0 {
+                break;
+            }
+            stream.extend_from_slice(&buffer[..bytes_read]);
+        }
+        assert_eq!(stream.len(), 30);
+
+        let mut target = Cursor::new(vec![0u8; 100]);
+        let mut writer = SparseWriter::new(&mut target, extents);
+        for chunk in stream.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert!(writer.write_all(&[1]).is_err());
+        assert_eq!(target.into_inner(), source);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_data_extents() {
+        let mut file = tempfile::tempfile_in(".").unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        assert_eq!(data_extents(&mut file, 4096).unwrap(), None);
+
+        file.set_len(16 << 20).unwrap();
+        file.seek(SeekFrom::Start(8 << 20)).unwrap();
+        file.write_all(&[2; 4096]).unwrap();
+        file.sync_all().unwrap();
+        match data_extents(&mut file, 16 << 20).unwrap() {
+            // the file system may not support holes, nothing to skip then
+            None => {}
+            Some(extents) => {
+                assert_eq!(extents.len(), 2);
+                assert_eq!(extents[0].offset, 0);
+                assert_eq!(extents[1].offset, 8 << 20);
+                assert!(data_size(&extents) < 1 << 20);
+            }
+        }
+        assert_eq!(file.stream_position().unwrap(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::ui::Phase;
+
+/// Summary of a finished transfer
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferSummary {
+    /// Where the file was written to
+    pub path: PathBuf,
+
+    /// Number of bytes transferred
+    pub size: u64,
+
+    /// Hash of the file, if it was checked
+    pub hash: Option<String>,
+
+    /// Duration of the transfer in milliseconds
+    pub duration_millis: u64,
+
+    /// Time spent in each phase, in the order they were entered
+    pub phases: Vec<PhaseDuration>,
+}
+
+/// Time a transfer spent in one of its phases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseDuration {
+    pub phase: Phase,
+    pub duration_millis: u64,
+}
+
+/// Measures the phases of a transfer with the monotonic clock,
+/// so the wall clock changing mid-transfer (e.g. NTP adjusting it) doesn't distort the durations
+#[derive(Debug, Default)]
+pub struct PhaseTimer {
+    /// The phase the transfer is in and when it was entered
+    current: Option<(Phase, Instant)>,
+
+    /// The phases left so far
+    finished: Vec<PhaseDuration>,
+}
+
+impl PhaseTimer {
+    /// Ends the current phase and starts measuring the given one.
+    /// Time spent in a phase entered again is added to its earlier duration.
+    pub fn enter(&mut self, phase: Phase) {
+        let previous = self.current.map(|(previous, _)| previous);
+        debug!("Phase: {} -> {}", previous.map_or("start", |previous| previous.name()), phase.name());
+        debug_assert!(phase.can_follow(previous), "{:?} can't follow {:?}", phase, previous);
+        if let Some(duration) = self.current_duration() {
+            self.add(duration);
+        }
+        self.current = Some((phase, Instant::now()));
+    }
+
+    /// Returns the time spent in each phase so far, including the current one.
+    pub fn durations(&self) -> Vec<PhaseDuration> {
+        let mut timer = PhaseTimer { current: None, finished: self.finished.clone() };
+        if let Some(duration) = self.current_duration() {
+            timer.add(duration);
+        }
+        timer.finished
+    }
+
+    fn current_duration(&self) -> Option<PhaseDuration> {
+        self.current.map(|(phase, entered)| PhaseDuration {
+            phase,
+            duration_millis: entered.elapsed().as_millis() as u64,
+        })
+    }
+
+    fn add(&mut self, duration: PhaseDuration) {
+        match self.finished.iter_mut().find(|finished| finished.phase == duration.phase) {
+            Some(finished) => finished.duration_millis += duration.duration_millis,
+            None => self.finished.push(duration),
+        }
+    }
+}
+
+/// Formats the phase durations for humans, e.g. `looking_up 0.120s, transferring 1.254s`.
+pub fn format_phase_durations(phases: &[PhaseDuration]) -> String {
+    phases.iter()
+        .map(|phase| format!("{} {:.3}s", phase.phase.name(), phase.duration_millis as f64 / 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl TransferSummary {
+    /// Returns the duration of the transfer in seconds.
+    pub fn seconds(&self) -> f64 {
+        self.duration_millis as f64 / 1000.0
+    }
+
+    /// Returns the average speed of the transfer in bytes per second.
+    pub fn bytes_per_second(&self) -> u64 {
+        if self.duration_millis == 0 {
+            return self.size;
+        }
+        (self.size as u128 * 1000 / self.duration_millis as u128) as u64
+    }
+
+    /// Formats the summary as a single tab separated line for scripts:
+    /// `<path>\t<size>\t<hash or ->\t<seconds>\t<bytes per second>`
+    pub fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{:.3}\t{}",
+            self.path.display(),
+            self.size,
+            self.hash.as_deref().unwrap_or("-"),
+            self.seconds(),
+            self.bytes_per_second()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(hash: Option<&str>, duration_millis: u64) -> TransferSummary {
+        TransferSummary {
+            path: PathBuf::from("/tmp/file.bin"),
+            size: 2000,
+            hash: hash.map(str::to_string),
+            duration_millis,
+            phases: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_bytes_per_second() {
+        assert_eq!(summary(None, 500).bytes_per_second(), 4000);
+        assert_eq!(summary(None, 0).bytes_per_second(), 2000);
+    }
+
+    #[test]
+    fn test_to_line() {
+        assert_eq!(summary(Some("abc"), 1500).to_line(), "/tmp/file.bin\t2000\tabc\t1.500\t1333");
+        assert_eq!(summary(None, 1000).to_line(), "/tmp/file.bin\t2000\t-\t1.000\t2000");
+    }
+
+    #[test]
+    fn test_phase_timer() {
+        let mut timer = PhaseTimer::default();
+        assert!(timer.durations().is_empty());
+        timer.enter(Phase::Connecting);
+        timer.enter(Phase::Transferring);
+        timer.enter(Phase::Connecting);
+        let phases: Vec<Phase> = timer.durations().iter().map(|duration| duration.phase).collect();
+        assert_eq!(phases, vec![Phase::Connecting, Phase::Transferring]);
+    }
+
+    #[test]
+    fn test_format_phase_durations() {
+        let phases = [
+            PhaseDuration { phase: Phase::LookingUp, duration_millis: 120 },
+            PhaseDuration { phase: Phase::Transferring, duration_millis: 1254 },
+        ];
+        assert_eq!(format_phase_durations(&phases), "looking_up 0.120s, transferring 1.254s");
+        assert_eq!(format_phase_durations(&[]), "");
+    }
+}
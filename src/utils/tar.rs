@@ -0,0 +1,605 @@
+//! Streams a directory as a tar archive (`send <DIRECTORY>`) and unpacks it while it's received.
+//! The archive is built from the files on disk as it's read, and its size is known upfront, so the
+//! transfer looks like the one of a single file. Entries are written in the GNU format: names longer than
+//! the 100 bytes of the header are stored in a preceding 'L' entry, sizes beyond 8 GiB in base-256.
+//! Only regular files and directories are archived, symlinks and special files are skipped.
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Cursor, ErrorKind, Read, Take, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::platform::{file_mode, set_file_mode};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Bytes of the name field of a header
+const NAME_LEN: usize = 100;
+
+/// Longest name accepted in an 'L' entry
+const MAX_LONG_NAME: u64 = 64 * 1024;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_LONG_NAME: u8 = b'L';
+
+/// Name of the header of an 'L' entry, the real name follows as its data
+const LONG_NAME_ENTRY: &[u8] = b"././@LongLink";
+
+/// Magic and version of GNU headers
+const GNU_MAGIC: &[u8] = b"ustar  \0";
+
+/// Magic of POSIX headers, which split long names into a prefix and the name
+const USTAR_MAGIC: &[u8] = b"ustar\0";
+
+/// Permissions of entries on platforms without unix permissions
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const DEFAULT_DIRECTORY_MODE: u32 = 0o755;
+
+#[derive(Debug)]
+enum EntryKind {
+    Directory,
+    File(u64),
+}
+
+/// A file or directory in the archive
+#[derive(Debug)]
+struct ArchiveEntry {
+    path: PathBuf,
+
+    /// The name in the archive, starting with the name of the directory sent
+    name: String,
+    kind: EntryKind,
+    mode: u32,
+    mtime: u64,
+}
+
+impl ArchiveEntry {
+    fn new(path: PathBuf, name: String, metadata: &Metadata) -> Self {
+        let (kind, default_mode) = if metadata.is_dir() {
+            (EntryKind::Directory, DEFAULT_DIRECTORY_MODE)
+        } else {
+            (EntryKind::File(metadata.len()), DEFAULT_FILE_MODE)
+        };
+        let mtime = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_secs());
+        ArchiveEntry { path, name, kind, mode: file_mode(metadata).unwrap_or(default_mode), mtime }
+    }
+
+    /// The headers of the entry, including an 'L' entry for a long name.
+    fn headers(&self) -> Vec<u8> {
+        let (name, kind, size) = match self.kind {
+            EntryKind::Directory => (format!("{}/", self.name), TYPE_DIRECTORY, 0),
+            EntryKind::File(size) => (self.name.clone(), TYPE_FILE, size),
+        };
+        let mut headers = Vec::with_capacity(BLOCK_SIZE);
+        if name.len() > NAME_LEN {
+            let long_name = name.len() as u64 + 1;
+            headers.extend_from_slice(&header(LONG_NAME_ENTRY, TYPE_LONG_NAME, long_name, DEFAULT_FILE_MODE, 0));
+            headers.extend_from_slice(name.as_bytes());
+            headers.resize(headers.len() + 1 + padding(long_name), 0);
+        }
+        headers.extend_from_slice(&header(name.as_bytes(), kind, size, self.mode, self.mtime));
+        headers
+    }
+
+    /// Bytes of the entry in the archive, headers and padded data.
+    fn archived_size(&self) -> u64 {
+        let data_size = match self.kind {
+            EntryKind::Directory => 0,
+            EntryKind::File(size) => size + padding(size) as u64,
+        };
+        self.headers().len() as u64 + data_size
+    }
+}
+
+/// A directory to be sent as a tar archive
+#[derive(Debug)]
+pub struct DirectoryArchive {
+    name: String,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl DirectoryArchive {
+    /// Collects the files and directories below the directory, in the order they're archived.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory to send.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a directory can't be listed.
+    pub fn scan(root: &Path) -> Result<Self> {
+        // "." or "dir/.." have no name of their own
+        let name = match root.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => fs::canonicalize(root)?.file_name()
+                .map_or_else(|| "directory".to_string(), |name| name.to_string_lossy().to_string()),
+        };
+        let mut entries = vec![ArchiveEntry::new(root.to_path_buf(), name.clone(), &fs::metadata(root)?)];
+        scan_directory(root, &name, &mut entries)?;
+        Ok(DirectoryArchive { name, entries })
+    }
+
+    /// Returns the name of the directory.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of files in the archive, directories not counted.
+    pub fn file_count(&self) -> usize {
+        self.entries.iter().filter(|entry| matches!(entry.kind, EntryKind::File(_))).count()
+    }
+
+    /// Returns the size of the archive in bytes, including the two empty blocks ending it.
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(ArchiveEntry::archived_size).sum::<u64>() + 2 * BLOCK_SIZE as u64
+    }
+
+    /// Returns a reader building the archive from the files on disk.
+    pub fn reader(&self) -> ArchiveReader<'_> {
+        ArchiveReader { entries: self.entries.iter(), pending: Cursor::default(), file: None, finished: false }
+    }
+}
+
+/// Adds the entries below the directory, sorted by name so the archive (and its hash) doesn't depend on the
+/// order the file system lists them in.
+fn scan_directory(path: &Path, name: &str, entries: &mut Vec<ArchiveEntry>) -> Result<()> {
+    let mut children = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let child_path = child.path();
+        let child_name = format!("{}/{}", name, child.file_name().to_string_lossy());
+        let metadata = fs::symlink_metadata(&child_path)?;
+        if metadata.is_dir() {
+            entries.push(ArchiveEntry::new(child_path.clone(), child_name.clone(), &metadata));
+            scan_directory(&child_path, &child_name, entries)?;
+        } else if metadata.is_file() {
+            entries.push(ArchiveEntry::new(child_path, child_name, &metadata));
+        } else {
+            warn!(target: "io", "Skipping {}, only files and directories are sent", child_path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Reads the archive of a directory, opening the files one after another
+pub struct ArchiveReader<'a> {
+    entries: std::slice::Iter<'a, ArchiveEntry>,
+
+    /// Headers or padding not read yet
+    pending: Cursor<Vec<u8>>,
+
+    /// The file being read and its entry (optional)
+    file: Option<(Take<File>, &'a ArchiveEntry)>,
+
+    /// Whether the empty blocks ending the archive were queued
+    finished: bool,
+}
+
+impl Read for ArchiveReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.pending.read(buffer)?;
+            if read > 0 || buffer.is_empty() {
+                return Ok(read);
+            }
+            if let Some((file, entry)) = &mut self.file {
+                let read = file.read(buffer)?;
+                if read > 0 {
+                    return Ok(read);
+                }
+                // the size is in the header already, a file growing meanwhile is cut off
+                if file.limit() > 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("{} shrank while it was sent", entry.path.display()),
+                    ));
+                }
+                let size = match entry.kind {
+                    EntryKind::File(size) => size,
+                    EntryKind::Directory => 0,
+                };
+                self.pending = Cursor::new(vec![0; padding(size)]);
+                self.file = None;
+                continue;
+            }
+            match self.entries.next() {
+                Some(entry) => {
+                    self.pending = Cursor::new(entry.headers());
+                    if let EntryKind::File(size) = entry.kind {
+                        self.file = Some((File::open(&entry.path)?.take(size), entry));
+                    }
+                }
+                None if !self.finished => {
+                    self.pending = Cursor::new(vec![0; 2 * BLOCK_SIZE]);
+                    self.finished = true;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Where the unpacker is in the archive
+#[derive(Debug)]
+enum UnpackState {
+    Header,
+    LongName { name: Vec<u8>, remaining: u64, padding: usize },
+    /// Data of an entry, written to the file unless the entry is skipped
+    Data { file: Option<File>, remaining: u64, padding: usize },
+    Padding(usize),
+    /// The empty blocks ending the archive were reached, anything after is ignored
+    End,
+}
+
+/// Unpacks an archive into a directory as it's written
+#[derive(Debug)]
+pub struct ArchiveUnpacker {
+    root: PathBuf,
+
+    /// Permissions of all files, `None` to apply the ones in the archive
+    mode: Option<u32>,
+
+    /// The header being received
+    header: Vec<u8>,
+
+    /// The name of the next entry from an 'L' entry (optional)
+    long_name: Option<Vec<u8>>,
+    state: UnpackState,
+    files: u64,
+}
+
+impl ArchiveUnpacker {
+    /// Creates an unpacker. The directory sent is unpacked as `root`, whatever its name in the archive.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory to unpack into, created by the first entry.
+    /// * `mode` - Permissions of all files, `None` to apply the ones in the archive (both restricted by the umask).
+    pub fn new(root: &Path, mode: Option<u32>) -> Self {
+        ArchiveUnpacker {
+            root: root.to_path_buf(),
+            mode,
+            header: Vec::with_capacity(BLOCK_SIZE),
+            long_name: None,
+            state: UnpackState::Header,
+            files: 0,
+        }
+    }
+
+    /// Checks the archive was received completely.
+    ///
+    /// # Returns
+    ///
+    /// `Result<u64>` - The number of files unpacked.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidArchive` if the archive ended before the blocks ending it.
+    pub fn finish(&self) -> Result<u64> {
+        match self.state {
+            UnpackState::End => Ok(self.files),
+            _ => Err(NudgeError::InvalidArchive("it ended early".to_string())),
+        }
+    }
+
+    /// Consumes the start of the data, up to the end of the current header, name, data or padding.
+    fn consume<'b>(&mut self, data: &'b [u8]) -> io::Result<&'b [u8]> {
+        let consumed = match &mut self.state {
+            UnpackState::Header => {
+                let take = (BLOCK_SIZE - self.header.len()).min(data.len());
+                self.header.extend_from_slice(&data[..take]);
+                if self.header.len() == BLOCK_SIZE {
+                    let header = std::mem::replace(&mut self.header, Vec::with_capacity(BLOCK_SIZE));
+                    self.state = self.start_entry(&header)?;
+                }
+                take
+            }
+            UnpackState::LongName { name, remaining, padding } => {
+                let take = (*remaining).min(data.len() as u64) as usize;
+                name.extend_from_slice(&data[..take]);
+                *remaining -= take as u64;
+                if *remaining == 0 {
+                    let mut name = std::mem::take(name);
+                    name.truncate(name.iter().position(|&byte| byte == 0).unwrap_or(name.len()));
+                    self.long_name = Some(name);
+                    self.state = UnpackState::Padding(*padding);
+                }
+                take
+            }
+            UnpackState::Data { file, remaining, padding } => {
+                let take = (*remaining).min(data.len() as u64) as usize;
+                if let Some(file) = file {
+                    file.write_all(&data[..take])?;
+                }
+                *remaining -= take as u64;
+                if *remaining == 0 {
+                    self.state = UnpackState::Padding(*padding);
+                }
+                take
+            }
+            UnpackState::Padding(padding) => {
+                let take = (*padding).min(data.len());
+                *padding -= take;
+                take
+            }
+            UnpackState::End => data.len(),
+        };
+        if matches!(self.state, UnpackState::Padding(0)) {
+            self.state = UnpackState::Header;
+        }
+        Ok(&data[consumed..])
+    }
+
+    /// Creates the directory or file of a header.
+    ///
+    /// # Returns
+    ///
+    /// `io::Result<UnpackState>` - What follows the header.
+    fn start_entry(&mut self, header: &[u8]) -> io::Result<UnpackState> {
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(UnpackState::End);
+        }
+        if parse_number(&header[148..156])? != u64::from(checksum(header)) {
+            return Err(invalid_archive("a header is corrupted"));
+        }
+        let size = parse_number(&header[124..136])?;
+        let padding = padding(size);
+        let kind = header[156];
+        if kind == TYPE_LONG_NAME {
+            if size > MAX_LONG_NAME {
+                return Err(invalid_archive("a name is too long"));
+            }
+            return Ok(UnpackState::LongName { name: Vec::new(), remaining: size, padding });
+        }
+
+        let name = match self.long_name.take() {
+            Some(name) => name,
+            None => header_name(header),
+        };
+        let name = String::from_utf8_lossy(&name).to_string();
+        let path = self.target(&name)?;
+        let file = match kind {
+            TYPE_DIRECTORY => {
+                fs::create_dir_all(&path)?;
+                None
+            }
+            // '\0' is a regular file in old archives, '7' a contiguous one
+            TYPE_FILE | 0 | b'7' if path != self.root => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let file = File::create(&path)?;
+                let mode = match self.mode {
+                    Some(mode) => mode,
+                    None => parse_number(&header[100..108])? as u32,
+                };
+                set_file_mode(&file, mode).map_err(io::Error::other)?;
+                self.files += 1;
+                Some(file)
+            }
+            _ => {
+                warn!(target: "io", "Skipping {}, only files and directories are unpacked", name);
+                None
+            }
+        };
+        Ok(match size {
+            0 => UnpackState::Header,
+            _ => UnpackState::Data { file, remaining: size, padding },
+        })
+    }
+
+    /// Returns where an entry is unpacked to: below the root, with the name of the directory sent replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::UnsafePath` if the name would leave the root (absolute, "..")
+    /// or passes through a symlink.
+    fn target(&self, name: &str) -> io::Result<PathBuf> {
+        let unsafe_path = || io::Error::other(NudgeError::UnsafePath(name.to_string()));
+        if name.starts_with('/') {
+            return Err(unsafe_path());
+        }
+        let mut path = self.root.clone();
+        for part in name.split('/').skip(1).filter(|part| !part.is_empty() && *part != ".") {
+            let mut components = Path::new(part).components();
+            if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+                return Err(unsafe_path());
+            }
+            path.push(part);
+            // an earlier entry can't be a symlink, but the root may already hold one
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                return Err(unsafe_path());
+            }
+        }
+        Ok(path)
+    }
+}
+
+impl Write for ArchiveUnpacker {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut data = buffer;
+        while !data.is_empty() {
+            data = self.consume(data)?;
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn invalid_archive(reason: &str) -> io::Error {
+    io::Error::other(NudgeError::InvalidArchive(reason.to_string()))
+}
+
+/// Bytes of zeros filling up data to the next block.
+fn padding(size: u64) -> usize {
+    ((BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64) as usize
+}
+
+/// Creates a header of the GNU format.
+fn header(name: &[u8], kind: u8, size: u64, mode: u32, mtime: u64) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    let name = &name[..name.len().min(NAME_LEN)];
+    header[..name.len()].copy_from_slice(name);
+    write_octal(&mut header[100..108], u64::from(mode));
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_size(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..265].copy_from_slice(GNU_MAGIC);
+    // six digits, a NUL and a space
+    let checksum = checksum(&header);
+    write_octal(&mut header[148..155], u64::from(checksum));
+    header[155] = b' ';
+    header
+}
+
+/// The name of a header, joined with the prefix of POSIX headers.
+fn header_name(header: &[u8]) -> Vec<u8> {
+    let field = |field: &[u8]| field[..field.iter().position(|&byte| byte == 0).unwrap_or(field.len())].to_vec();
+    let name = field(&header[..NAME_LEN]);
+    let prefix = field(&header[345..500]);
+    if &header[257..263] != USTAR_MAGIC || prefix.is_empty() {
+        return name;
+    }
+    [prefix, name].join(&b'/')
+}
+
+/// Sums up the bytes of the header, with the checksum field counted as spaces.
+fn checksum(header: &[u8]) -> u32 {
+    header.iter().enumerate()
+        .map(|(index, &byte)| if (148..156).contains(&index) { u32::from(b' ') } else { u32::from(byte) })
+        .sum()
+}
+
+/// Writes a number as zero-padded octal digits followed by a NUL, capped at the largest number fitting.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let value = value.min(8u64.pow(digits as u32) - 1);
+    field[..digits].copy_from_slice(format!("{:0digits$o}", value, digits = digits).as_bytes());
+    field[digits] = 0;
+}
+
+/// Writes a size as octal digits, or in base-256 if it doesn't fit (GNU).
+fn write_size(field: &mut [u8], size: u64) {
+    if size < 8u64.pow(field.len() as u32 - 1) {
+        write_octal(field, size);
+        return;
+    }
+    field.fill(0);
+    field[0] = 0x80;
+    let start = field.len() - 8;
+    field[start..].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Parses a number written by `write_octal` or `write_size`, padded with spaces or NULs.
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return [field[0] & 0x7f].iter().chain(&field[1..]).try_fold(0u64, |number, &byte| {
+            number.checked_mul(256).map(|number| number + u64::from(byte))
+        }).ok_or_else(|| invalid_archive("a size is too large"));
+    }
+    let digits = std::str::from_utf8(field).ok()
+        .map(|digits| digits.trim_matches(|c: char| c == '\0' || c == ' '))
+        .ok_or_else(|| invalid_archive("a number is malformed"))?;
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid_archive("a number is malformed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unpacks the archive in pieces of the given size.
+    fn unpack(archive: &[u8], root: &Path, piece: usize) -> Result<u64> {
+        let mut unpacker = ArchiveUnpacker::new(root, None);
+        for data in archive.chunks(piece) {
+            unpacker.write_all(data)?;
+        }
+        unpacker.finish()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let source = tempfile::tempdir_in(".").unwrap();
+        let root = source.path().join("project");
+        let long_name = "n".repeat(120);
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("README.md"), b"hello").unwrap();
+        fs::write(root.join("src/nested/data.bin"), vec![7; 1000]).unwrap();
+        fs::write(root.join("src").join(&long_name), b"long").unwrap();
+        fs::write(root.join("empty.txt"), b"").unwrap();
+
+        let archive = DirectoryArchive::scan(&root).unwrap();
+        assert_eq!(archive.name(), "project");
+        assert_eq!(archive.file_count(), 4);
+        let mut data = Vec::new();
+        archive.reader().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, archive.size());
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+
+        // the name of the directory sent is replaced by the root
+        let target = tempfile::tempdir_in(".").unwrap();
+        let unpacked = target.path().join("received");
+        assert_eq!(unpack(&data, &unpacked, 700).unwrap(), 4);
+        assert_eq!(fs::read(unpacked.join("README.md")).unwrap(), b"hello");
+        assert_eq!(fs::read(unpacked.join("src/nested/data.bin")).unwrap(), vec![7; 1000]);
+        assert_eq!(fs::read(unpacked.join("src").join(&long_name)).unwrap(), b"long");
+        assert_eq!(fs::read(unpacked.join("empty.txt")).unwrap(), b"");
+        assert!(unpacked.join("empty").is_dir());
+    }
+
+    #[test]
+    fn test_truncated_archive() {
+        let source = tempfile::tempdir_in(".").unwrap();
+        fs::write(source.path().join("file"), vec![1; 600]).unwrap();
+        let archive = DirectoryArchive::scan(source.path()).unwrap();
+        let mut data = Vec::new();
+        archive.reader().read_to_end(&mut data).unwrap();
+
+        let target = tempfile::tempdir_in(".").unwrap();
+        let result = unpack(&data[..data.len() - 2 * BLOCK_SIZE], &target.path().join("out"), 512);
+        assert!(matches!(result, Err(NudgeError::InvalidArchive(_))));
+    }
+
+    #[test]
+    fn test_rejects_unsafe_paths() {
+        let target = tempfile::tempdir_in(".").unwrap();
+        for name in ["root/../escaped", "/etc/escaped"] {
+            let mut unpacker = ArchiveUnpacker::new(&target.path().join("out"), None);
+            let result = unpacker.write_all(&header(name.as_bytes(), TYPE_FILE, 0, 0o644, 0));
+            assert!(result.is_err(), "{} should be rejected", name);
+        }
+        assert!(!target.path().join("escaped").exists());
+
+        let mut corrupted = header(b"root/file", TYPE_FILE, 0, 0o644, 0);
+        corrupted[0] = b'R';
+        let mut unpacker = ArchiveUnpacker::new(&target.path().join("out"), None);
+        assert!(unpacker.write_all(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_numbers() {
+        let mut field = [0; 12];
+        write_size(&mut field, 0o644);
+        assert_eq!(&field, b"00000000644\0");
+        assert_eq!(parse_number(&field).unwrap(), 0o644);
+        // beyond 8 GiB
+        write_size(&mut field, 10 << 30);
+        assert_eq!(field[0], 0x80);
+        assert_eq!(parse_number(&field).unwrap(), 10 << 30);
+        assert_eq!(parse_number(b"  644 \0").unwrap(), 0o644);
+        assert!(parse_number(b"64x\0").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::{Debug, Formatter};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::passphrase::Passphrase;
+use crate::utils::{decode_hex, deterministic, encode_hex};
+
+/// Prefix of tickets, names the version of the format
+const TICKET_PREFIX: &str = "nudge1.";
+
+/// Context of the key derivation, so the configured secret can't be used to forge anything else
+const KEY_CONTEXT: &str = "nudge-ngx 2024 relay session tickets";
+
+/// What a session ticket grants: picking up the file of a session at a relay, until the session expires
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TicketPayload {
+    /// Passphrase of the session
+    pub passphrase: Passphrase<'static>,
+
+    /// Relay the session is registered at, as "host:port"
+    pub relay: String,
+
+    /// Timestamp after which the relay forgets the session (unix millis)
+    pub expires_at: u64,
+
+    /// Name of the file
+    pub file_name: String,
+
+    /// Size of the file in bytes
+    pub file_size: u64,
+}
+
+/// Key the relay signs its tickets with
+pub struct TicketKey([u8; 32]);
+
+impl TicketKey {
+    /// Creates a random key, tickets signed with it are only valid until the relay restarts.
+    pub fn random() -> Self {
+        TicketKey(deterministic::rng().gen())
+    }
+
+    /// Derives the key from a secret, so tickets stay valid across restarts (and shards sharing the secret).
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The `ticket_secret` of the relay configuration.
+    pub fn from_secret(secret: &str) -> Self {
+        TicketKey(blake3::derive_key(KEY_CONTEXT, secret.as_bytes()))
+    }
+
+    /// Signs the payload.
+    ///
+    /// # Returns
+    ///
+    /// `Result<String>` - The ticket, e.g. `nudge1.<payload>.<signature>`.
+    pub fn sign(&self, payload: &TicketPayload) -> Result<String> {
+        let payload = serde_json::to_vec(payload)?;
+        let mac = blake3::keyed_hash(&self.0, &payload);
+        Ok(format!("{}{}.{}", TICKET_PREFIX, encode_hex(&payload), mac.to_hex()))
+    }
+
+    /// Checks the signature and lifetime of a ticket.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticket` - The ticket presented by a receiver.
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidTicket` if the ticket is malformed, wasn't signed with this key or has expired.
+    pub fn verify(&self, ticket: &str, now: u64) -> Result<TicketPayload> {
+        let (payload_bytes, mac) = split(ticket)?;
+        let mac = blake3::Hash::from_hex(mac).map_err(|_| invalid("malformed signature"))?;
+        // comparing blake3 hashes takes constant time
+        if blake3::keyed_hash(&self.0, &payload_bytes) != mac {
+            return Err(invalid("not issued by this relay"));
+        }
+        let payload: TicketPayload = serde_json::from_slice(&payload_bytes).map_err(|_| invalid("malformed payload"))?;
+        if payload.expires_at <= now {
+            return Err(invalid("expired"));
+        }
+        Ok(payload)
+    }
+}
+
+impl Debug for TicketKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TicketKey(..)")
+    }
+}
+
+/// Reads the payload of a ticket without checking its signature, which only the relay can do.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidTicket` if the ticket is malformed.
+pub fn decode_ticket(ticket: &str) -> Result<TicketPayload> {
+    let (payload_bytes, _) = split(ticket)?;
+    serde_json::from_slice(&payload_bytes).map_err(|_| invalid("malformed payload"))
+}
+
+/// Splits a ticket into the payload and the hex encoded signature.
+fn split(ticket: &str) -> Result<(Vec<u8>, &str)> {
+    let (payload, mac) = ticket.trim()
+        .strip_prefix(TICKET_PREFIX)
+        .and_then(|ticket| ticket.split_once('.'))
+        .ok_or_else(|| invalid("not a nudge ticket"))?;
+    let payload = decode_hex(payload).ok_or_else(|| invalid("malformed payload"))?;
+    Ok((payload, mac))
+}
+
+fn invalid(reason: &str) -> NudgeError {
+    NudgeError::InvalidTicket(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(expires_at: u64) -> TicketPayload {
+        TicketPayload {
+            passphrase: Passphrase::from("correct-horse-battery".to_string()),
+            relay: "relay.example:4000".to_string(),
+            expires_at,
+            file_name: "report.pdf".to_string(),
+            file_size: 1234,
+        }
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let key = TicketKey::from_secret("s3cr3t");
+        let ticket = key.sign(&payload(1000)).unwrap();
+        assert!(ticket.starts_with(TICKET_PREFIX));
+        assert_eq!(key.verify(&ticket, 999).unwrap(), payload(1000));
+        assert_eq!(key.verify(&format!(" {}\n", ticket), 999).unwrap(), payload(1000));
+        assert_eq!(decode_ticket(&ticket).unwrap(), payload(1000));
+
+        // the same secret results in the same key
+        assert!(TicketKey::from_secret("s3cr3t").verify(&ticket, 999).is_ok());
+        assert!(matches!(key.verify(&ticket, 1000), Err(NudgeError::InvalidTicket(_))));
+        assert!(TicketKey::from_secret("other").verify(&ticket, 999).is_err());
+        assert!(TicketKey::random().verify(&ticket, 999).is_err());
+    }
+
+    #[test]
+    fn test_tampered_ticket() {
+        let key = TicketKey::random();
+        let ticket = key.sign(&payload(1000)).unwrap();
+        let (_, mac) = ticket.rsplit_once('.').unwrap();
+        let forged = format!(
+            "{}{}.{}", TICKET_PREFIX, encode_hex(&serde_json::to_vec(&payload(u64::MAX)).unwrap()), mac,
+        );
+        assert!(decode_ticket(&forged).is_ok());
+        assert!(key.verify(&forged, 999).is_err());
+
+        assert!(decode_ticket("correct-horse-battery").is_err());
+        assert!(decode_ticket("nudge1.zz.00").is_err());
+        assert!(key.verify(&format!("{}.nothex", ticket.rsplit_once('.').unwrap().0), 999).is_err());
+    }
+}
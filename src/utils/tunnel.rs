@@ -0,0 +1,534 @@
+//! Tunnels the datagrams of a client through a TCP connection to the relay (`--tunnel`), for networks which
+//! block UDP, e.g. guest Wi-Fi only letting web traffic through.
+//!
+//! The relay (`serve --tunnel`) listens for TCP on its UDP port and gives each tunneled client a UDP socket of its
+//! own (an allocation), which talks to the relay and the peer in place of the client. The peer sees the allocation
+//! like any other address. On the client, a bridge of two local UDP sockets stands in for the relay and the peer,
+//! so the transfer itself runs unchanged, just slower. Every socket of the client gets a tunnel (and so an
+//! allocation) of its own, e.g. the sessions of `send --copies` or `get --batch`, and a socket connecting to another
+//! relay (e.g. `--backup-relay` or a link naming one) gets a new tunnel to that relay.
+//!
+//! Frames are a channel (1 byte), the length of the payload (2 bytes, big endian) and the payload. Datagrams of the
+//! relay travel on `CHANNEL_RELAY`, datagrams of the peer on `CHANNEL_PEER`. `CHANNEL_CONNECT` tells the relay the
+//! address of the peer, which has to be one the relay introduced to the client.
+//!
+//! Clients which can't send datagrams at all, e.g. browsers, connect with a WebSocket instead (`serve --websocket`)
+//! and get an allocation as well. Messages of the relay travel as text messages, the others as binary messages
+//! starting with their channel.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::Shutdown;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::utils::websocket::{Message, WebSocket};
+
+const CHANNEL_RELAY: u8 = 0;
+const CHANNEL_PEER: u8 = 1;
+const CHANNEL_CONNECT: u8 = 2;
+
+/// Largest datagram a frame carries
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// How often the allocation checks whether the client closed the tunnel
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the relay waits for the first bytes of a tunnel or the handshake of a WebSocket
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The tunnels of this process, by the local port of the socket using them. Not by its address, which turns from
+/// the unspecified one into the loopback address once the socket is connected to the tunnel
+static TUNNELS: LazyLock<Mutex<HashMap<u16, Tunnel>>> = LazyLock::new(Mutex::default);
+
+/// Writes a frame to the tunnel.
+fn write_frame(stream: &mut impl Write, channel: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(3 + payload.len());
+    frame.push(channel);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+/// Reads a frame from the tunnel.
+///
+/// # Returns
+///
+/// `std::io::Result<Option<(u8, Vec<u8>)>>` - The channel and the payload, `None` once the tunnel was closed.
+fn read_frame(stream: &mut impl Read) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0; 3];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut payload = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+/// The client side of the tunnel: local UDP sockets standing in for the relay and the peer
+struct Tunnel {
+    stream: Arc<Mutex<TcpStream>>,
+
+    /// The relay the tunnel leads to
+    relay: SocketAddr,
+
+    /// Tells the bridge to stop once the socket moved on to another relay
+    closed: Arc<AtomicBool>,
+
+    /// Local address standing in for the relay
+    relay_side: SocketAddr,
+
+    /// Local address standing in for the peer
+    peer_side: SocketAddr,
+}
+
+impl Tunnel {
+    /// Closes the tunnel, the relay drops the allocation and the bridge stops.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+/// Opens a tunnel to the relay for a socket, or returns the one opened for it before.
+/// A tunnel of the socket to another relay is closed.
+///
+/// # Arguments
+///
+/// * `client` - The local port of the socket using the tunnel.
+/// * `relay` - The address of the relay, which listens for TCP on its UDP port.
+///
+/// # Returns
+///
+/// `Result<SocketAddr>` - The local address to send the messages for the relay to.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the relay doesn't accept the TCP connection
+pub fn open(client: u16, relay: SocketAddr) -> Result<SocketAddr> {
+    let mut tunnels = TUNNELS.lock().unwrap();
+    match tunnels.get(&client) {
+        Some(tunnel) if tunnel.relay == relay => return Ok(tunnel.relay_side),
+        Some(tunnel) => tunnel.close(),
+        None => {}
+    }
+    let stream = TcpStream::connect_timeout(&relay, Duration::from_secs(10))?;
+    stream.set_nodelay(true)?;
+    let relay_socket = UdpSocket::bind("127.0.0.1:0")?;
+    let peer_socket = UdpSocket::bind("127.0.0.1:0")?;
+    let tunnel = Tunnel {
+        stream: Arc::new(Mutex::new(stream.try_clone()?)),
+        relay,
+        closed: Arc::new(AtomicBool::new(false)),
+        relay_side: relay_socket.local_addr()?,
+        peer_side: peer_socket.local_addr()?,
+    };
+    debug!(
+        target: "relay_client",
+        "Tunneling port {} through TCP to {} (relay via {}, peer via {})", client, relay, tunnel.relay_side, tunnel.peer_side
+    );
+
+    // the socket of the client, learned from its datagrams
+    let client_addr = Arc::new(Mutex::new(None));
+    for (socket, channel) in [(&relay_socket, CHANNEL_RELAY), (&peer_socket, CHANNEL_PEER)] {
+        let (socket, stream, client_addr, closed) =
+            (socket.try_clone()?, tunnel.stream.clone(), client_addr.clone(), tunnel.closed.clone());
+        socket.set_read_timeout(Some(CLOSE_POLL_INTERVAL))?;
+        thread::spawn(move || bridge_outgoing(socket, channel, stream, client_addr, closed));
+    }
+    let closed = tunnel.closed.clone();
+    thread::spawn(move || bridge_incoming(stream, relay_socket, peer_socket, client_addr, closed));
+
+    let relay_side = tunnel.relay_side;
+    tunnels.insert(client, tunnel);
+    Ok(relay_side)
+}
+
+/// Routes the datagrams of a socket for the peer through its tunnel, if it has one.
+///
+/// # Arguments
+///
+/// * `client` - The local port of the socket.
+/// * `peer_addr` - The address the relay introduced the peer with.
+///
+/// # Returns
+///
+/// `Result<SocketAddr>` - The address to send the datagrams for the peer to: a local one with a tunnel, or the
+/// peer's own address without.
+pub fn route_to_peer(client: u16, peer_addr: SocketAddr) -> Result<SocketAddr> {
+    let tunnels = TUNNELS.lock().unwrap();
+    let Some(tunnel) = tunnels.get(&client) else {
+        return Ok(peer_addr);
+    };
+    debug!(target: "relay_client", "Tunneling the datagrams for {} through the relay", peer_addr);
+    let mut stream = tunnel.stream.lock().unwrap();
+    write_frame(&mut *stream, CHANNEL_CONNECT, peer_addr.to_string().as_bytes())?;
+    Ok(tunnel.peer_side)
+}
+
+/// Passes the datagrams the client sends to a local socket on to the tunnel, until the tunnel is closed.
+fn bridge_outgoing(
+    socket: UdpSocket,
+    channel: u8,
+    stream: Arc<Mutex<TcpStream>>,
+    client: Arc<Mutex<Option<SocketAddr>>>,
+    closed: Arc<AtomicBool>,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    while !closed.load(Ordering::Relaxed) {
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(_) => return,
+        };
+        *client.lock().unwrap() = Some(from);
+        if let Err(e) = write_frame(&mut *stream.lock().unwrap(), channel, &buffer[..length]) {
+            warn!(target: "relay_client", "The tunnel to the relay broke: {}", e);
+            return;
+        }
+    }
+}
+
+/// Passes the datagrams arriving through the tunnel on to the client, from the socket standing in for the sender.
+fn bridge_incoming(
+    mut stream: TcpStream,
+    relay_socket: UdpSocket,
+    peer_socket: UdpSocket,
+    client: Arc<Mutex<Option<SocketAddr>>>,
+    closed: Arc<AtomicBool>,
+) {
+    loop {
+        let (channel, payload) = match read_frame(&mut stream) {
+            Ok(Some(frame)) => frame,
+            // closed by us, the socket moved on to another relay
+            _ if closed.load(Ordering::Relaxed) => return,
+            Ok(None) => {
+                warn!(target: "relay_client", "The relay closed the tunnel");
+                return;
+            }
+            Err(e) => {
+                warn!(target: "relay_client", "The tunnel to the relay broke: {}", e);
+                return;
+            }
+        };
+        let socket = match channel {
+            CHANNEL_RELAY => &relay_socket,
+            CHANNEL_PEER => &peer_socket,
+            _ => continue,
+        };
+        if let Some(client) = *client.lock().unwrap() {
+            let _ = socket.send_to(&payload, client);
+        }
+    }
+}
+
+/// The allocations of tunneled clients, by the address of the allocation
+#[derive(Debug, Clone, Default)]
+pub struct Allocations(Arc<Mutex<HashMap<SocketAddr, IpAddr>>>);
+
+impl Allocations {
+    /// Returns the address of the client an allocation tunnels for, so the relay treats the messages of the
+    /// allocation (rate limits, allow and deny lists) as the client's.
+    pub fn client_of(&self, allocation: &SocketAddr) -> Option<IpAddr> {
+        self.0.lock().unwrap().get(allocation).copied()
+    }
+}
+
+/// Which connections the relay accepts on its TCP listener
+#[derive(Debug, Clone, Copy)]
+pub struct Listeners {
+    /// Tunnels of the CLI (`serve --tunnel`)
+    pub tunnel: bool,
+
+    /// WebSockets, e.g. of browsers (`serve --websocket`)
+    pub websocket: bool,
+}
+
+/// Accepts tunnels and WebSockets of clients and runs them, on the relay. Both share the TCP port, a WebSocket
+/// starts with an HTTP request, a tunnel with the channel of its first frame.
+///
+/// # Arguments
+///
+/// * `listener` - Listens for TCP on the port of the relay.
+/// * `relay_port` - The UDP port of the relay.
+/// * `allocations` - The allocations, shared with the relay.
+/// * `listeners` - Which connections are accepted.
+pub fn serve(listener: TcpListener, relay_port: u16, allocations: Allocations, listeners: Listeners) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Cannot accept a tunnel: {}", e);
+                continue;
+            }
+        };
+        let allocations = allocations.clone();
+        thread::spawn(move || {
+            let client = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            if let Err(e) = accept(stream, relay_port, &allocations, listeners) {
+                warn!("({}) Tunnel failed: {}", client, e);
+            }
+        });
+    }
+}
+
+/// Tells a tunnel from a WebSocket and runs its allocation.
+fn accept(stream: TcpStream, relay_port: u16, allocations: &Allocations, listeners: Listeners) -> Result<()> {
+    // clients which connect without saying anything don't hold a thread forever
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut start = [0; 4];
+    let peeked = stream.peek(&mut start)?;
+    if start[..peeked].starts_with(b"GET") && listeners.websocket {
+        let websocket = match WebSocket::accept(stream.try_clone()?) {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                debug!("({}) Refused a WebSocket: {}", stream.peer_addr()?, e);
+                return Ok(());
+            }
+        };
+        stream.set_read_timeout(None)?;
+        run_allocation(websocket, &stream, relay_port, allocations)
+    } else if peeked > 0 && start[0] <= CHANNEL_CONNECT && listeners.tunnel {
+        stream.set_read_timeout(None)?;
+        run_allocation(stream.try_clone()?, &stream, relay_port, allocations)
+    } else {
+        debug!("({}) Closed a connection which is neither a tunnel nor a WebSocket", stream.peer_addr()?);
+        Ok(())
+    }
+}
+
+/// The connection of a tunneled client on the relay, carrying datagrams along with their channel
+trait TunnelConnection: Send + Sized + 'static {
+    /// Reads the next datagram, `None` once the client closed the connection.
+    fn receive(&mut self) -> std::io::Result<Option<(u8, Vec<u8>)>>;
+
+    /// Sends a datagram to the client.
+    fn send(&mut self, channel: u8, payload: &[u8]) -> std::io::Result<()>;
+
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    /// Closes the connection, waking up threads reading from it.
+    fn close(&self);
+}
+
+impl TunnelConnection for TcpStream {
+    fn receive(&mut self) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+        read_frame(self)
+    }
+
+    fn send(&mut self, channel: u8, payload: &[u8]) -> std::io::Result<()> {
+        write_frame(self, channel, payload)
+    }
+
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Messages of the relay travel as text, everything else as binary messages starting with the channel
+impl TunnelConnection for WebSocket {
+    fn receive(&mut self) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+        Ok(match self.read_message()? {
+            Some(Message::Text(text)) => Some((CHANNEL_RELAY, text.into_bytes())),
+            // an empty message names no channel, and is dropped like frames of unknown channels
+            Some(Message::Binary(data)) => Some(data.split_first().map_or((u8::MAX, Vec::new()), |(channel, payload)| (*channel, payload.to_vec()))),
+            None => None,
+        })
+    }
+
+    fn send(&mut self, channel: u8, payload: &[u8]) -> std::io::Result<()> {
+        match std::str::from_utf8(payload) {
+            Ok(text) if channel == CHANNEL_RELAY => self.write_message(&Message::Text(text.to_string())),
+            _ => self.write_message(&Message::Binary([&[channel][..], payload].concat())),
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Self> {
+        WebSocket::try_clone(self)
+    }
+
+    fn close(&self) {
+        self.shutdown();
+    }
+}
+
+/// Addresses the relay introduced the client to, and the peer the client talks to
+#[derive(Debug, Default)]
+struct Introductions {
+    /// Only datagrams to these hosts are passed on, so the relay doesn't send datagrams to arbitrary addresses
+    hosts: HashSet<IpAddr>,
+    peer: Option<SocketAddr>,
+}
+
+/// Runs the allocation of a tunneled client until it closes the tunnel.
+///
+/// # Arguments
+///
+/// * `connection` - The tunnel or WebSocket of the client.
+/// * `stream` - The TCP connection underneath, naming the client and the address it reached the relay at.
+/// * `relay_port` - The UDP port of the relay.
+/// * `allocations` - The allocations, shared with the relay.
+fn run_allocation(connection: impl TunnelConnection, stream: &TcpStream, relay_port: u16, allocations: &Allocations) -> Result<()> {
+    let client = stream.peer_addr()?;
+    // bound to the address the client reached, so peers reach the allocation there as well
+    let local_ip = stream.local_addr()?.ip();
+    let relay = SocketAddr::new(local_ip, relay_port);
+    let allocation = UdpSocket::bind(SocketAddr::new(local_ip, 0))?;
+    allocation.set_read_timeout(Some(CLOSE_POLL_INTERVAL))?;
+    stream.set_nodelay(true)?;
+    let allocation_addr = allocation.local_addr()?;
+    allocations.0.lock().unwrap().insert(allocation_addr, client.ip());
+    info!("({}) Opened a tunnel, allocated {}", client, allocation_addr);
+
+    let introductions = Arc::new(Mutex::new(Introductions::default()));
+    let closed = Arc::new(AtomicBool::new(false));
+    let receiver = {
+        let (allocation, introductions, mut connection) = (allocation.try_clone()?, introductions.clone(), connection.try_clone()?);
+        let closed = closed.clone();
+        thread::spawn(move || pass_to_client(&allocation, relay, &introductions, &mut connection, &closed))
+    };
+    let result = pass_from_client(connection, &allocation, relay, &introductions);
+    closed.store(true, Ordering::Relaxed);
+
+    allocations.0.lock().unwrap().remove(&allocation_addr);
+    let _ = receiver.join();
+    info!("({}) Closed the tunnel", client);
+    result
+}
+
+/// Sends the datagrams of the client to the relay or its peer.
+fn pass_from_client(
+    mut connection: impl TunnelConnection,
+    allocation: &UdpSocket,
+    relay: SocketAddr,
+    introductions: &Mutex<Introductions>,
+) -> Result<()> {
+    let result = loop {
+        let (channel, payload) = match connection.receive() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e.into()),
+        };
+        match channel {
+            CHANNEL_RELAY => {
+                allocation.send_to(&payload, relay)?;
+            }
+            CHANNEL_PEER => {
+                if let Some(peer) = introductions.lock().unwrap().peer {
+                    let _ = allocation.send_to(&payload, peer);
+                }
+            }
+            CHANNEL_CONNECT => {
+                let peer = String::from_utf8_lossy(&payload).parse::<SocketAddr>().ok();
+                let mut introductions = introductions.lock().unwrap();
+                match peer {
+                    Some(peer) if introductions.hosts.contains(&peer.ip()) => introductions.peer = Some(peer),
+                    _ => warn!("({}) Refused to tunnel to {}, the relay didn't introduce it", relay, String::from_utf8_lossy(&payload)),
+                }
+            }
+            _ => {}
+        }
+    };
+    connection.close();
+    result
+}
+
+/// Passes the datagrams of the relay and the peer on to the client, until the tunnel was closed.
+fn pass_to_client(
+    allocation: &UdpSocket,
+    relay: SocketAddr,
+    introductions: &Mutex<Introductions>,
+    connection: &mut impl TunnelConnection,
+    closed: &AtomicBool,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    while !closed.load(Ordering::Relaxed) {
+        let (length, from) = match allocation.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => continue,
+            Err(_) => return,
+        };
+        let channel = if from == relay {
+            introductions.lock().unwrap().hosts.extend(introduced_hosts(&buffer[..length]));
+            CHANNEL_RELAY
+        } else if introductions.lock().unwrap().peer == Some(from) {
+            CHANNEL_PEER
+        } else {
+            continue;
+        };
+        if connection.send(channel, &buffer[..length]).is_err() {
+            return;
+        }
+    }
+}
+
+/// Collects the hosts of the peer addresses in a message of the relay (`sender_addr`, `receiver_addr`).
+fn introduced_hosts(message: &[u8]) -> Vec<IpAddr> {
+    let message = String::from_utf8_lossy(message);
+    let Some((_, json)) = message.split_once(' ') else {
+        return Vec::new();
+    };
+    let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(json.trim()) else {
+        return Vec::new();
+    };
+    fields.iter()
+        .filter(|(name, _)| name.ends_with("_addr"))
+        .filter_map(|(_, value)| value.as_str()?.parse::<SocketAddr>().ok())
+        .map(|addr| addr.ip())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut tunnel = Vec::new();
+        write_frame(&mut tunnel, CHANNEL_PEER, b"data").unwrap();
+        write_frame(&mut tunnel, CHANNEL_RELAY, b"").unwrap();
+        let mut reader = &tunnel[..];
+        assert_eq!(read_frame(&mut reader).unwrap(), Some((CHANNEL_PEER, b"data".to_vec())));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some((CHANNEL_RELAY, Vec::new())));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_introduced_hosts() {
+        let message = b"X2R_ASC {\"sender_addr\":\"203.0.113.7:4000\",\"sender_mapped_port\":null}\n";
+        assert_eq!(introduced_hosts(message), ["203.0.113.7".parse::<IpAddr>().unwrap()]);
+        assert!(introduced_hosts(b"ERROR Passphrase not found\n").is_empty());
+        assert!(introduced_hosts(b"X2S_PPM {\"passphrase\":\"a-b-c\"}\n").is_empty());
+    }
+
+    #[test]
+    fn test_tunnel_per_socket_and_relay() {
+        let relay_a = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay_b = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (a, b) = (relay_a.local_addr().unwrap(), relay_b.local_addr().unwrap());
+        let (first, second) = (65001, 65002);
+
+        let relay_side = open(first, a).unwrap();
+        assert_eq!(open(first, a).unwrap(), relay_side);
+        // another socket gets an allocation of its own
+        assert_ne!(open(second, a).unwrap(), relay_side);
+        let (mut first_stream, _) = relay_a.accept().unwrap();
+        relay_a.accept().unwrap();
+
+        // a socket moving on to another relay doesn't stay with the first one
+        assert_ne!(open(first, b).unwrap(), relay_side);
+        relay_b.accept().unwrap();
+        first_stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(first_stream.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
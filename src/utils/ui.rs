@@ -0,0 +1,647 @@
+//! Console output helpers.
+//!
+//! With the `ui` feature enabled, output is colored and transfers show their phase and a progress bar.
+//! Without it, the same functions print plain text, so the rest of the code base
+//! doesn't need to care which one is compiled in.
+
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+#[cfg(feature = "ui")]
+use std::sync::Mutex;
+#[cfg(feature = "ui")]
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::utils::summary::{PhaseDuration, PhaseTimer};
+#[cfg(feature = "ui")]
+use crate::utils::deterministic;
+#[cfg(feature = "ui")]
+use crate::utils::units::Units;
+
+#[cfg(feature = "ui")]
+pub use console::style;
+#[cfg(feature = "ui")]
+pub use indicatif::ProgressBar;
+
+#[cfg(feature = "ui")]
+use dialoguer::{Confirm, Password, theme::ColorfulTheme};
+#[cfg(feature = "ui")]
+use indicatif::{MultiProgress, ProgressStyle};
+
+/// Amount of output, selected by `-q` and `-v`/`-vv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors and the results (passphrase, output path)
+    Quiet = 0,
+    /// Status messages and progress bars
+    Normal = 1,
+    /// Debug logs
+    Verbose = 2,
+    /// Trace logs
+    Trace = 3,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+
+static EMOJI: AtomicBool = AtomicBool::new(true);
+
+/// Progress display of the running transfer, which has to be cleared while other output is printed
+#[cfg(feature = "ui")]
+static ACTIVE_PROGRESS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// How often the phase spinner is redrawn
+#[cfg(feature = "ui")]
+const SPINNER_TICK: Duration = Duration::from_millis(100);
+
+/// How often progress bars are redrawn, independent of how often (and from which thread) the position changes
+#[cfg(feature = "ui")]
+const PROGRESS_TICK: Duration = Duration::from_millis(100);
+
+impl Verbosity {
+    /// Returns the verbosity selected by the command line flags.
+    ///
+    /// # Arguments
+    ///
+    /// * `quiet` - Whether `-q` was passed.
+    /// * `verbose` - How often `-v` was passed.
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Trace,
+        }
+    }
+
+    /// Returns the log level for this verbosity.
+    pub fn log_level(&self) -> tracing::Level {
+        match self {
+            Verbosity::Quiet => tracing::Level::ERROR,
+            Verbosity::Normal => tracing::Level::INFO,
+            Verbosity::Verbose => tracing::Level::DEBUG,
+            Verbosity::Trace => tracing::Level::TRACE,
+        }
+    }
+
+    /// Sets the verbosity for the whole process.
+    pub fn apply(self) {
+        VERBOSITY.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// Returns the verbosity of the process.
+    pub fn current() -> Self {
+        match VERBOSITY.load(Ordering::Relaxed) {
+            0 => Verbosity::Quiet,
+            1 => Verbosity::Normal,
+            2 => Verbosity::Verbose,
+            _ => Verbosity::Trace,
+        }
+    }
+}
+
+/// Disables colors and other styling on stdout and stderr.
+#[cfg(feature = "ui")]
+pub fn disable_colors() {
+    console::set_colors_enabled(false);
+    console::set_colors_enabled_stderr(false);
+}
+
+/// Disables colors, there are none without the `ui` feature.
+#[cfg(not(feature = "ui"))]
+pub fn disable_colors() {}
+
+/// Returns whether the `NO_COLOR` environment variable asks for output without colors (see <https://no-color.org>).
+pub fn is_no_color_env() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Replaces emoji and other symbols in the output with ASCII, e.g. `[✔]` with `[+]`.
+pub fn disable_emoji() {
+    EMOJI.store(false, Ordering::Relaxed);
+}
+
+/// Returns whether emoji and other symbols may be printed.
+pub fn is_emoji_enabled() -> bool {
+    EMOJI.load(Ordering::Relaxed)
+}
+
+/// Returns the marker of a successful step, `[✔]` (`[+]` with `--no-emoji`).
+pub fn success_mark() -> impl Display {
+    style(if is_emoji_enabled() { "[✔]" } else { "[+]" }).bold().green()
+}
+
+/// Returns the marker of a failed step, `[✗]` (`[x]` with `--no-emoji`).
+pub fn failure_mark() -> impl Display {
+    style(if is_emoji_enabled() { "[✗]" } else { "[x]" }).bold().red()
+}
+
+/// Returns the message a finished progress bar is left with.
+pub fn complete_message() -> &'static str {
+    if is_emoji_enabled() { "Transfer complete! 🎉" } else { "Transfer complete!" }
+}
+
+/// Returns whether status output should be suppressed.
+pub fn is_quiet() -> bool {
+    Verbosity::current() == Verbosity::Quiet
+}
+
+/// Moves all messages to stderr, for commands that write data to stdout (e.g. `nudge pipe`).
+pub fn reserve_stdout_for_data() {
+    STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether stdout carries data instead of messages.
+pub fn is_stdout_data() -> bool {
+    STDOUT_IS_DATA.load(Ordering::Relaxed)
+}
+
+/// Runs `f` with the progress display of the running transfer hidden, so printed lines don't tear it apart.
+#[cfg(feature = "ui")]
+pub fn suspend_progress<R>(f: impl FnOnce() -> R) -> R {
+    let active = ACTIVE_PROGRESS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match active {
+        Some(multi) => multi.suspend(f),
+        None => f(),
+    }
+}
+
+/// Runs `f`, there is no progress display to hide without the `ui` feature.
+#[cfg(not(feature = "ui"))]
+pub fn suspend_progress<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Prints a result (e.g. the passphrase) to stdout, or to stderr if stdout carries data.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        let line = format!($($arg)*);
+        $crate::utils::ui::suspend_progress(|| {
+            if $crate::utils::ui::is_stdout_data() {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+        })
+    };
+}
+
+/// Prints a status line to stdout (or stderr if stdout carries data), unless `-q` was passed.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::utils::ui::is_quiet() {
+            report!($($arg)*);
+        }
+    };
+}
+
+/// Rings the terminal bell (on stderr, so it doesn't end up in piped output), even with `-q`.
+pub fn ring_bell() {
+    use std::io::Write;
+
+    suspend_progress(|| {
+        let mut stderr = std::io::stderr();
+        let _ = stderr.write_all(b"\x07").and_then(|_| stderr.flush());
+    });
+}
+
+/// Creates a customized theme for prompts.
+///
+/// # Returns
+///
+/// `ColorfulTheme` - A theme with customized prompt, success, and error prefixes.
+#[cfg(feature = "ui")]
+pub fn question_theme() -> ColorfulTheme {
+    let theme = ColorfulTheme {
+        prompt_prefix: style("[?]".to_string()).for_stderr().dim(),
+        success_prefix: style("[✔]".to_string()).for_stderr().bold().green(),
+        error_prefix: style("[✗]".to_string()).for_stderr().bold().red(),
+        ..ColorfulTheme::default()
+    };
+    if is_emoji_enabled() {
+        return theme;
+    }
+    ColorfulTheme {
+        prompt_suffix: style(">".to_string()).for_stderr().black().bright(),
+        success_prefix: style("[+]".to_string()).for_stderr().bold().green(),
+        success_suffix: style("-".to_string()).for_stderr().black().bright(),
+        error_prefix: style("[x]".to_string()).for_stderr().bold().red(),
+        ..theme
+    }
+}
+
+/// Asks the user a yes/no question.
+///
+/// # Arguments
+///
+/// * `prompt` - The question to ask.
+///
+/// # Returns
+///
+/// `Result<bool>` - `true` if the user confirmed.
+#[cfg(feature = "ui")]
+pub fn confirm(prompt: &str) -> Result<bool> {
+    suspend_progress(|| Ok(Confirm::with_theme(&question_theme())
+        .with_prompt(prompt)
+        .interact()
+        .map_err(|dialoguer::Error::IO(e)| e)?))
+}
+
+/// Asks the user a yes/no question.
+///
+/// # Arguments
+///
+/// * `prompt` - The question to ask.
+///
+/// # Returns
+///
+/// `Result<bool>` - `true` if the user answered with "y" or "yes".
+#[cfg(not(feature = "ui"))]
+pub fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+
+    eprint!("[?] {} [y/n] ", prompt);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Asks the user for a secret without echoing it.
+///
+/// # Arguments
+///
+/// * `prompt` - The prompt to show.
+/// * `confirm` - Whether the secret has to be entered twice (e.g. when choosing a new one).
+///
+/// # Returns
+///
+/// `Result<String>` - The entered secret.
+#[cfg(feature = "ui")]
+pub fn password(prompt: &str, confirm: bool) -> Result<String> {
+    suspend_progress(|| {
+        let theme = question_theme();
+        let mut input = Password::with_theme(&theme).with_prompt(prompt);
+        if confirm {
+            input = input.with_confirmation("Repeat to confirm", "The entries don't match");
+        }
+        Ok(input.interact().map_err(|dialoguer::Error::IO(e)| e)?)
+    })
+}
+
+/// Asks the user for a secret. Without the `ui` feature the input is echoed,
+/// prefer passing secrets via the environment on such builds.
+///
+/// # Arguments
+///
+/// * `prompt` - The prompt to show.
+/// * `confirm` - Whether the secret has to be entered twice (e.g. when choosing a new one).
+///
+/// # Returns
+///
+/// `Result<String>` - The entered secret.
+#[cfg(not(feature = "ui"))]
+pub fn password(prompt: &str, confirm: bool) -> Result<String> {
+    use std::io::Write;
+
+    let read = |prompt: &str| -> Result<String> {
+        eprint!("[?] {}: ", prompt);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+    };
+    let secret = read(prompt)?;
+    if confirm && read("Repeat to confirm")? != secret {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The entries don't match").into());
+    }
+    Ok(secret)
+}
+
+/// Creates a new progress bar with a specified length and custom style.
+///
+/// # Arguments
+///
+/// * `length` - The total length of the progress bar.
+///
+/// # Returns
+///
+/// `ProgressBar` - A progress bar configured with a custom style and prefix, redrawn every `PROGRESS_TICK`.
+/// Setting its position is cheap and thread-safe, so it can be set after every chunk.
+#[cfg(feature = "ui")]
+pub fn new_downloader_progressbar(len: u64) -> ProgressBar {
+    let template = match Units::current() {
+        Units::Decimal => "{prefix:.orange} {elapsed_precise} :: |{wide_bar:.white/dim}| :: {decimal_bytes}/{decimal_total_bytes}",
+        Units::Binary => "{prefix:.orange} {elapsed_precise} :: |{wide_bar:.white/dim}| :: {binary_bytes}/{binary_total_bytes}",
+    };
+    show_progressbar(ProgressBar::new(len), template)
+}
+
+/// Creates a progress display for a data stream of unknown length (`send -`), counting the bytes and their rate.
+///
+/// # Returns
+///
+/// `ProgressBar` - A progress display like `new_downloader_progressbar`, without a total.
+#[cfg(feature = "ui")]
+pub fn new_stream_progressbar() -> ProgressBar {
+    let template = match Units::current() {
+        Units::Decimal => "{prefix:.orange} {elapsed_precise} :: {spinner} :: {decimal_bytes} ({decimal_bytes_per_sec})",
+        Units::Binary => "{prefix:.orange} {elapsed_precise} :: {spinner} :: {binary_bytes} ({binary_bytes_per_sec})",
+    };
+    show_progressbar(ProgressBar::new_spinner(), template)
+}
+
+#[cfg(feature = "ui")]
+fn show_progressbar(progress_bar: ProgressBar, template: &str) -> ProgressBar {
+    if is_quiet() || deterministic::is_enabled() {
+        return ProgressBar::hidden();
+    }
+    let progress_bar = progress_bar.with_prefix("[>]");
+    progress_bar.set_style(ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars(if is_emoji_enabled() { "█ :" } else { "#>-" }));
+    // shown below the phase of the transfer, if one is displayed
+    let progress_bar = match ACTIVE_PROGRESS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(multi) => multi.add(progress_bar),
+        None => progress_bar,
+    };
+    progress_bar.enable_steady_tick(PROGRESS_TICK);
+    progress_bar
+}
+
+/// Creates a progress bar that doesn't display anything.
+#[cfg(not(feature = "ui"))]
+pub fn new_downloader_progressbar(_: u64) -> ProgressBar {
+    ProgressBar
+}
+
+/// Creates a progress display for a data stream that doesn't display anything.
+#[cfg(not(feature = "ui"))]
+pub fn new_stream_progressbar() -> ProgressBar {
+    ProgressBar
+}
+
+/// Stand-in for `indicatif::ProgressBar` when the `ui` feature is disabled
+#[cfg(not(feature = "ui"))]
+pub struct ProgressBar;
+
+#[cfg(not(feature = "ui"))]
+impl ProgressBar {
+    pub fn set_position(&self, _: u64) {}
+
+    pub fn finish_with_message(&self, _: &'static str) {}
+}
+
+/// A phase of a transfer, shown so users can tell what a seemingly hanging transfer waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// The sender hashes the file
+    Hashing,
+    /// The sender registers the file at the relay
+    Registering,
+    /// The receiver looks up the passphrase at the relay
+    LookingUp,
+    /// The sender waits for a receiver to claim the passphrase
+    WaitingForPeer,
+    /// The peers punch a hole through their NATs
+    Connecting,
+    /// The file is transferred
+    Transferring,
+    /// The receiver checks the hash of the file
+    Verifying,
+}
+
+/// Phases of `nudge send`
+pub const SENDER_PHASES: &[Phase] = &[
+    Phase::Hashing, Phase::Registering, Phase::WaitingForPeer, Phase::Connecting, Phase::Transferring,
+];
+
+/// Phases of `nudge get`
+pub const RECEIVER_PHASES: &[Phase] = &[
+    Phase::LookingUp, Phase::Connecting, Phase::Transferring, Phase::Verifying,
+];
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Hashing => "Hashing the file",
+            Phase::Registering => "Registering at the relay",
+            Phase::LookingUp => "Looking up the passphrase",
+            Phase::WaitingForPeer => "Waiting for the receiver",
+            Phase::Connecting => "Connecting to the peer",
+            Phase::Transferring => "Transferring",
+            Phase::Verifying => "Verifying the hash",
+        })
+    }
+}
+
+impl Phase {
+    /// Returns the name of the phase in the manifest, e.g. `waiting_for_peer`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Hashing => "hashing",
+            Phase::Registering => "registering",
+            Phase::LookingUp => "looking_up",
+            Phase::WaitingForPeer => "waiting_for_peer",
+            Phase::Connecting => "connecting",
+            Phase::Transferring => "transferring",
+            Phase::Verifying => "verifying",
+        }
+    }
+
+    /// Returns whether a transfer may enter this phase after the previous one (`None` if it's the first phase).
+    /// Besides moving on, a transfer may go back to (re)connecting, e.g. to retry after the connection broke.
+    pub fn can_follow(&self, previous: Option<Phase>) -> bool {
+        let Some(previous) = previous else {
+            return true;
+        };
+        matches!(
+            (previous, self),
+            (Phase::Hashing, Phase::Registering)
+                | (Phase::Registering, Phase::WaitingForPeer)
+                // a sender registers again at a backup relay
+                | (Phase::WaitingForPeer, Phase::Registering)
+                | (Phase::LookingUp, Phase::Connecting)
+                // a group skips connecting, its receivers connect on threads of their own
+                | (Phase::WaitingForPeer, Phase::Connecting | Phase::Transferring)
+                | (Phase::Connecting, Phase::Transferring)
+                | (Phase::Transferring, Phase::Verifying)
+                | (Phase::Connecting | Phase::Transferring, Phase::LookingUp | Phase::WaitingForPeer | Phase::Connecting)
+        )
+    }
+}
+
+/// Returns the step of a phase, e.g. `(2, 5)` for the second of five phases.
+fn phase_step(phases: &[Phase], phase: Phase) -> (usize, usize) {
+    let index = phases.iter().position(|candidate| *candidate == phase).unwrap_or(0);
+    (index + 1, phases.len())
+}
+
+/// Shows the phase a transfer is in with a spinner, e.g. `⠋ [3/5] Waiting for the receiver 00:00:12`.
+/// The progress bar of the transfer is shown below it. The display is cleared when dropped
+#[cfg(feature = "ui")]
+pub struct PhaseProgress {
+    phases: &'static [Phase],
+    spinner: ProgressBar,
+    timer: RefCell<PhaseTimer>,
+
+    /// Whether the spinner is the display of the running transfer (see `hidden`)
+    shown: bool,
+}
+
+#[cfg(feature = "ui")]
+impl PhaseProgress {
+    /// Starts showing the phases, unless `-q` or `-v` was passed (logs would tear the display apart).
+    ///
+    /// # Arguments
+    ///
+    /// * `phases` - The phases of the transfer in their order, e.g. `SENDER_PHASES`.
+    pub fn new(phases: &'static [Phase]) -> Self {
+        if Verbosity::current() != Verbosity::Normal || deterministic::is_enabled() {
+            return PhaseProgress::hidden(phases);
+        }
+        let multi = MultiProgress::new();
+        let spinner = multi.add(ProgressBar::new(phases.len() as u64));
+        let mut style = ProgressStyle::with_template("{spinner:.yellow} [{pos}/{len}] {msg} {elapsed_precise:.dim}").unwrap();
+        if !is_emoji_enabled() {
+            style = style.tick_chars("|/-\\ ");
+        }
+        spinner.set_style(style);
+        spinner.enable_steady_tick(SPINNER_TICK);
+        *ACTIVE_PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = Some(multi);
+        PhaseProgress { phases, spinner, timer: RefCell::default(), shown: true }
+    }
+
+    /// Only times the phases, e.g. of one of several transfers shown by a single spinner.
+    pub fn hidden(phases: &'static [Phase]) -> Self {
+        PhaseProgress { phases, spinner: ProgressBar::hidden(), timer: RefCell::default(), shown: false }
+    }
+
+    /// Shows that the transfer entered the next phase, restarting the elapsed time.
+    pub fn enter(&self, phase: Phase) {
+        let (step, _) = phase_step(self.phases, phase);
+        self.spinner.set_position(step as u64);
+        self.spinner.set_message(phase.to_string());
+        self.spinner.reset_elapsed();
+        self.timer.borrow_mut().enter(phase);
+    }
+
+    /// Returns the time spent in each phase so far.
+    pub fn durations(&self) -> Vec<PhaseDuration> {
+        self.timer.borrow().durations()
+    }
+}
+
+#[cfg(feature = "ui")]
+impl Drop for PhaseProgress {
+    fn drop(&mut self) {
+        self.spinner.finish_and_clear();
+        if self.shown {
+            *ACTIVE_PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+    }
+}
+
+/// Prints the phase a transfer enters, e.g. `[~] [3/5] Waiting for the receiver...`
+#[cfg(not(feature = "ui"))]
+pub struct PhaseProgress {
+    phases: &'static [Phase],
+    timer: RefCell<PhaseTimer>,
+    shown: bool,
+}
+
+#[cfg(not(feature = "ui"))]
+impl PhaseProgress {
+    pub fn new(phases: &'static [Phase]) -> Self {
+        PhaseProgress { phases, timer: RefCell::default(), shown: true }
+    }
+
+    pub fn hidden(phases: &'static [Phase]) -> Self {
+        PhaseProgress { phases, timer: RefCell::default(), shown: false }
+    }
+
+    pub fn enter(&self, phase: Phase) {
+        if self.shown {
+            let (step, len) = phase_step(self.phases, phase);
+            status!("[~] [{}/{}] {}...", step, len, phase);
+        }
+        self.timer.borrow_mut().enter(phase);
+    }
+
+    pub fn durations(&self) -> Vec<PhaseDuration> {
+        self.timer.borrow().durations()
+    }
+}
+
+/// Stand-in for `console::StyledObject` when the `ui` feature is disabled
+#[cfg(not(feature = "ui"))]
+pub struct StyledObject<D>(D);
+
+#[cfg(not(feature = "ui"))]
+impl<D> StyledObject<D> {
+    pub fn bold(self) -> Self { self }
+    pub fn dim(self) -> Self { self }
+    pub fn red(self) -> Self { self }
+    pub fn green(self) -> Self { self }
+    pub fn yellow(self) -> Self { self }
+    pub fn cyan(self) -> Self { self }
+}
+
+#[cfg(not(feature = "ui"))]
+impl<D: std::fmt::Display> std::fmt::Display for StyledObject<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Wraps a value for (unstyled) display.
+#[cfg(not(feature = "ui"))]
+pub fn style<D>(val: D) -> StyledObject<D> {
+    StyledObject(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_from_flags() {
+        assert_eq!(Verbosity::from_flags(true, 2), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_flags(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(false, 1), Verbosity::Verbose);
+        assert_eq!(Verbosity::from_flags(false, 5), Verbosity::Trace);
+    }
+
+    #[test]
+    fn test_phase_step() {
+        assert_eq!(phase_step(SENDER_PHASES, Phase::Hashing), (1, 5));
+        assert_eq!(phase_step(SENDER_PHASES, Phase::WaitingForPeer), (3, 5));
+        assert_eq!(phase_step(RECEIVER_PHASES, Phase::Verifying), (4, 4));
+    }
+
+    #[test]
+    fn test_phase_can_follow() {
+        for phases in [SENDER_PHASES, RECEIVER_PHASES] {
+            assert!(phases[0].can_follow(None));
+            for pair in phases.windows(2) {
+                assert!(pair[1].can_follow(Some(pair[0])), "{:?} -> {:?}", pair[0], pair[1]);
+            }
+        }
+        assert!(Phase::Connecting.can_follow(Some(Phase::Transferring)));
+        assert!(Phase::Registering.can_follow(Some(Phase::WaitingForPeer)));
+        assert!(!Phase::Verifying.can_follow(Some(Phase::Connecting)));
+        assert!(!Phase::Hashing.can_follow(Some(Phase::Transferring)));
+    }
+
+    #[test]
+    fn test_verbosity_log_level() {
+        assert_eq!(Verbosity::Quiet.log_level(), tracing::Level::ERROR);
+        assert_eq!(Verbosity::Trace.log_level(), tracing::Level::TRACE);
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use clap::ValueEnum;
+use humansize::{format_size, BINARY, DECIMAL};
+
+use crate::error::{NudgeError, Result};
+
+/// Units sizes are shown in, selected with `--units`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Units {
+    /// Powers of 1000: kB, MB, GB (like macOS, disk vendors and most download tools)
+    Decimal = 0,
+    /// Powers of 1024: KiB, MiB, GiB (like `ls -h`, `du` and Windows, which labels them KB, MB, GB)
+    Binary = 1,
+}
+
+static UNITS: AtomicU8 = AtomicU8::new(Units::Decimal as u8);
+
+impl Units {
+    /// Sets the units for the whole process.
+    pub fn apply(self) {
+        UNITS.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// Returns the units of the process.
+    pub fn current() -> Self {
+        match UNITS.load(Ordering::Relaxed) {
+            0 => Units::Decimal,
+            _ => Units::Binary,
+        }
+    }
+
+    /// Formats a size in these units, e.g. "1.50 MB" or "1.43 MiB".
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size in bytes.
+    pub fn format(self, size: u64) -> String {
+        match self {
+            Units::Decimal => format_size(size, DECIMAL),
+            Units::Binary => format_size(size, BINARY),
+        }
+    }
+}
+
+/// Formats a size in the units selected with `--units`.
+///
+/// # Arguments
+///
+/// * `size` - The size in bytes.
+pub fn format_bytes(size: impl Into<u64>) -> String {
+    Units::current().format(size.into())
+}
+
+/// Parses a size like "500", "50MB" or "1GiB" into bytes.
+///
+/// # Arguments
+///
+/// * `input` - The size to parse, a number without unit is interpreted as bytes.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidSize` if the input has no number, an unknown unit or overflows.
+pub fn parse_size(input: &str) -> Result<u64> {
+    let invalid = || NudgeError::InvalidSize(input.to_string());
+    let input = input.trim();
+    let (number, unit) = input.split_at(input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len()));
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "tb" => 1000 * 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Units::Decimal.format(1_500_000), "1.50 MB");
+        assert_eq!(Units::Binary.format(1_500_000), "1.43 MiB");
+        assert_eq!(Units::Decimal.format(999), "999 B");
+        assert_eq!(Units::Binary.format(4096), "4 KiB");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500").unwrap(), 500);
+        assert_eq!(parse_size("50MB").unwrap(), 50_000_000);
+        assert_eq!(parse_size("1 GiB").unwrap(), 1 << 30);
+        assert_eq!(parse_size("32GiB").unwrap(), 32 << 30);
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+        assert!(parse_size("99999999TiB").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Reports a receive which stopped making progress while it's still waiting, so a transfer that "froze at 43%"
+//! comes with the last packet received and the retransmissions so far, instead of just hanging until it's killed.
+
+use std::time::Duration;
+
+/// How long `get` waits without data before reporting where the transfer stands (`--stall-report`)
+pub const DEFAULT_STALL_REPORT: &str = "10s";
+
+/// Tells when a stalled receive is due for another report
+#[derive(Debug)]
+pub struct StallWatchdog {
+    /// Time between two reports, `None` to never report
+    interval: Option<Duration>,
+
+    /// When data arrived last (unix millis)
+    last_progress: u64,
+
+    /// Reports since then
+    reports: u32,
+}
+
+impl StallWatchdog {
+    /// Starts watching.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - Time without progress before the first report, and between the following ones.
+    ///   `Duration::ZERO` turns the reports off.
+    /// * `now` - The current time in unix millis.
+    pub fn new(interval: Duration, now: u64) -> Self {
+        StallWatchdog {
+            interval: Some(interval).filter(|interval| !interval.is_zero()),
+            last_progress: now,
+            reports: 0,
+        }
+    }
+
+    /// Notes that data arrived, the next report is due a full interval later.
+    pub fn progressed(&mut self, now: u64) {
+        self.last_progress = now;
+        self.reports = 0;
+    }
+
+    /// Returns whether another report is due.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in unix millis.
+    ///
+    /// # Returns
+    ///
+    /// `Option<Duration>` - How long the receive stalls, if a report is due.
+    pub fn check(&mut self, now: u64) -> Option<Duration> {
+        let interval = self.interval?.as_millis() as u64;
+        let stalled = now.saturating_sub(self.last_progress);
+        if stalled < interval * (u64::from(self.reports) + 1) {
+            return None;
+        }
+        self.reports += 1;
+        Some(Duration::from_millis(stalled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut watchdog = StallWatchdog::new(Duration::from_secs(10), 1_000);
+        assert_eq!(watchdog.check(5_000), None);
+        assert_eq!(watchdog.check(11_000), Some(Duration::from_secs(10)));
+        // once per interval
+        assert_eq!(watchdog.check(12_000), None);
+        assert_eq!(watchdog.check(21_500), Some(Duration::from_millis(20_500)));
+
+        watchdog.progressed(22_000);
+        assert_eq!(watchdog.check(31_000), None);
+        assert_eq!(watchdog.check(32_000), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_check_disabled() {
+        let mut watchdog = StallWatchdog::new(Duration::ZERO, 0);
+        assert_eq!(watchdog.check(u64::MAX), None);
+    }
+}
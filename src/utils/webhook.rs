@@ -0,0 +1,303 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::error::{NudgeError, Result};
+use crate::models::{FileInfo, SessionKind};
+use crate::utils::passphrase::Passphrase;
+use crate::utils::AnonymousString;
+
+/// Time a request to the webhook may take in total, from connecting until the end of the response
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Events waiting for the webhook, further events are dropped until the webhook catches up
+const WEBHOOK_QUEUE_LENGTH: usize = 64;
+
+/// What happened to a session the sender asked to announce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEventKind {
+    /// The sender registered the session, receivers can join it now
+    Registered,
+
+    /// A receiver joined the session
+    Matched,
+
+    /// A receiver asked the sender of an offer (`send --offer-only`) to come online
+    Requested,
+}
+
+/// Body POSTed to the webhook, e.g.
+///
+/// ```json
+/// {
+///   "event": "registered",
+///   "passphrase": "correct-horse-battery",
+///   "kind": "file",
+///   "file_name": "report.pdf",
+///   "file_size": 1024,
+///   "sender_host": "Alice's laptop",
+///   "expires_at": 1718000000000
+/// }
+/// ```
+#[derive(Debug, Serialize)]
+pub struct WebhookEvent {
+    pub event: WebhookEventKind,
+    pub passphrase: Passphrase<'static>,
+    pub kind: SessionKind,
+    pub file_name: String,
+    pub file_size: u64,
+    pub sender_host: AnonymousString,
+
+    /// Timestamp after which the relay forgets the session (unix millis)
+    pub expires_at: u64,
+
+    /// Display name or hostname of the receiver (matched sessions only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receiver_host: Option<AnonymousString>,
+}
+
+impl WebhookEvent {
+    /// Describes a session for the webhook.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - What happened to the session.
+    /// * `passphrase` - The passphrase of the session.
+    /// * `file_info` - The session.
+    /// * `receiver_host` - The receiver which joined the session (matched sessions only).
+    pub fn new(
+        event: WebhookEventKind,
+        passphrase: &Passphrase<'static>,
+        file_info: &FileInfo,
+        receiver_host: Option<AnonymousString>,
+    ) -> Self {
+        WebhookEvent {
+            event,
+            passphrase: passphrase.clone(),
+            kind: file_info.kind,
+            file_name: file_info.file_name.clone(),
+            file_size: file_info.file_size,
+            sender_host: file_info.sender_host.clone(),
+            expires_at: file_info.expires_at,
+            receiver_host,
+        }
+    }
+}
+
+/// Where the webhook is reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct WebhookTarget {
+    /// Host (with port) the webhook is reached at
+    host: String,
+
+    /// Path of the webhook on the host
+    path: String,
+}
+
+/// URL the relay POSTs session events to, for senders which opted in with `send --announce`.
+///
+/// The events are posted one after another by a single worker thread, which ends once the webhook is dropped
+/// (e.g. when the configuration is reloaded) and the queued events are posted.
+#[derive(Debug)]
+pub struct Webhook {
+    target: WebhookTarget,
+
+    /// Queue of the worker posting the events
+    queue: SyncSender<WebhookEvent>,
+}
+
+impl Webhook {
+    /// Parses the URL of the webhook, e.g. "http://chat.internal:8080/hooks/nudge", and starts its worker.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidRelayConfig` if it's not a plain `http://` URL.
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = || NudgeError::InvalidRelayConfig(format!("invalid webhook URL (only http:// is supported): {}", url));
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let host = if host.contains(':') && !host.ends_with(']') { host.to_string() } else { format!("{}:80", host) };
+        let target = WebhookTarget { host, path: path.to_string() };
+
+        let (queue, events) = mpsc::sync_channel(WEBHOOK_QUEUE_LENGTH);
+        let worker = target.clone();
+        thread::Builder::new().name("webhook".to_string()).spawn(move || worker.run(events))?;
+        Ok(Webhook { target, queue })
+    }
+
+    /// Queues the event for the worker, failures are only logged.
+    ///
+    /// # Returns
+    ///
+    /// Whether the event was queued, it's dropped if the webhook is too far behind.
+    pub fn notify(&self, event: WebhookEvent) -> bool {
+        match self.queue.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(event)) => {
+                warn!(
+                    "Dropping {:?} event for the webhook at {}{}, {} events are still waiting",
+                    event.event, self.target.host, self.target.path, WEBHOOK_QUEUE_LENGTH
+                );
+                false
+            }
+            Err(TrySendError::Disconnected(event)) => {
+                warn!("Dropping {:?} event, the worker of the webhook has stopped", event.event);
+                false
+            }
+        }
+    }
+}
+
+impl WebhookTarget {
+    /// Posts the queued events until the webhook is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The queue of the webhook.
+    fn run(self, events: Receiver<WebhookEvent>) {
+        for event in events {
+            if let Err(e) = self.post(&event) {
+                warn!("Cannot notify the webhook at {}{} about {:?}: {}", self.host, self.path, event.event, e);
+            }
+        }
+    }
+
+    /// Posts the event as JSON and waits for the response, at most `WEBHOOK_TIMEOUT` in total.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::WebhookFailed` if the webhook can't be reached, doesn't answer in time
+    /// or doesn't answer with a 2xx status.
+    fn post(&self, event: &WebhookEvent) -> Result<()> {
+        let failed = |e: &dyn std::fmt::Display| NudgeError::WebhookFailed(e.to_string());
+        let deadline = Instant::now() + WEBHOOK_TIMEOUT;
+        let remaining = || deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())
+            .ok_or_else(|| failed(&"timed out"));
+
+        let body = serde_json::to_string(event)?;
+        let addr = self.host.to_socket_addrs().map_err(|e| failed(&e))?.next()
+            .ok_or_else(|| failed(&format!("cannot resolve {}", self.host)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, remaining()?).map_err(|e| failed(&e))?;
+        stream.set_write_timeout(Some(remaining()?))?;
+
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            self.path, self.host, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).map_err(|e| failed(&e))?;
+
+        // a webhook trickling its response must not hold up the worker beyond the deadline
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+        loop {
+            stream.set_read_timeout(Some(remaining()?))?;
+            match stream.read(&mut buffer).map_err(|e| failed(&e))? {
+                0 => break,
+                len => response.extend_from_slice(&buffer[..len]),
+            }
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status: u16 = response.split_whitespace().nth(1).and_then(|status| status.parse().ok())
+            .ok_or_else(|| failed(&"invalid HTTP response"))?;
+        if !(200..300).contains(&status) {
+            return Err(failed(&format!("HTTP status {}", status)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn event() -> WebhookEvent {
+        WebhookEvent {
+            event: WebhookEventKind::Registered,
+            passphrase: Passphrase::from("correct-horse-battery".to_string()),
+            kind: SessionKind::File,
+            file_name: "report.pdf".to_string(),
+            file_size: 1024,
+            sender_host: AnonymousString(None),
+            expires_at: 1000,
+            receiver_host: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let webhook = Webhook::parse("http://chat.internal:8080/hooks/nudge").unwrap();
+        assert_eq!(webhook.target.host, "chat.internal:8080");
+        assert_eq!(webhook.target.path, "/hooks/nudge");
+
+        let webhook = Webhook::parse("http://10.0.0.1").unwrap();
+        assert_eq!(webhook.target.host, "10.0.0.1:80");
+        assert_eq!(webhook.target.path, "/");
+        assert_eq!(Webhook::parse("http://[::1]/hook").unwrap().target.host, "[::1]:80");
+
+        assert!(Webhook::parse("https://chat.internal/hook").is_err());
+        assert!(Webhook::parse("http:///hook").is_err());
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let json = serde_json::to_string(&event()).unwrap();
+        assert!(json.starts_with(r#"{"event":"registered","passphrase":"correct-horse-battery","kind":"file""#));
+        assert!(!json.contains("receiver_host"));
+    }
+
+    #[test]
+    fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = Webhook::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !request.ends_with(b"}") {
+                let len = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..len]);
+            }
+            stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        webhook.target.post(&event()).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.0\r\n"));
+        assert!(request.ends_with(r#""expires_at":1000}"#));
+    }
+
+    #[test]
+    fn test_notify_drops_overflow() {
+        // accepts connections (in the backlog) but never answers, so the worker is stuck on the first event
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = Webhook::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+
+        let started = Instant::now();
+        let queued = (0..WEBHOOK_QUEUE_LENGTH * 2).filter(|_| webhook.notify(event())).count();
+        assert!(started.elapsed() < WEBHOOK_TIMEOUT);
+        assert!((WEBHOOK_QUEUE_LENGTH..=WEBHOOK_QUEUE_LENGTH + 1).contains(&queued));
+    }
+
+    #[test]
+    fn test_post_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = Webhook::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let started = Instant::now();
+        assert!(matches!(webhook.target.post(&event()), Err(NudgeError::WebhookFailed(_))));
+        assert!(started.elapsed() < WEBHOOK_TIMEOUT + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,333 @@
+//! The server side of WebSocket (RFC 6455), just enough for browsers to talk to the relay (`serve --websocket`),
+//! see `tunnel`. Messages are read whole, fragmented ones are joined, pings answered and extensions not offered.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+/// Appended to the key of the client to prove the server speaks WebSocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message taken from a client, a datagram and its channel
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 + 1;
+
+/// Largest request of the opening handshake
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// A message of a WebSocket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A WebSocket accepted from a client. Clones share the writing side, so frames of several threads don't interleave.
+pub struct WebSocket {
+    reader: TcpStream,
+    writer: Arc<Mutex<TcpStream>>,
+}
+
+impl WebSocket {
+    /// Runs the opening handshake with a client which connected.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection of the client, which sent an HTTP upgrade request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the request isn't a WebSocket upgrade, which the client is told.
+    pub fn accept(mut stream: TcpStream) -> std::io::Result<Self> {
+        let request = read_handshake(&stream)?;
+        let Some(key) = header(&request, "sec-websocket-key").filter(|_| is_upgrade(&request)) else {
+            stream.write_all(b"HTTP/1.1 426 Upgrade Required\r\nUpgrade: websocket\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")?;
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "not a WebSocket upgrade request"));
+        };
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes())?;
+        Ok(WebSocket { writer: Arc::new(Mutex::new(stream.try_clone()?)), reader: stream })
+    }
+
+    /// Reads the next message, answering pings and joining fragments on the way.
+    ///
+    /// # Returns
+    ///
+    /// `std::io::Result<Option<Message>>` - The message, `None` once the client closed the WebSocket.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the client breaks the protocol, e.g. sends unmasked frames or too
+    /// large messages.
+    pub fn read_message(&mut self) -> std::io::Result<Option<Message>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let Some((fin, opcode, payload)) = read_frame(&mut self.reader)? else {
+                return Ok(None);
+            };
+            match opcode {
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let _ = self.write_frame(OPCODE_CLOSE, payload.get(..2).unwrap_or_default());
+                    return Ok(None);
+                }
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => message = Some((opcode, payload)),
+                OPCODE_CONTINUATION if message.is_some() => {
+                    let (_, data) = message.as_mut().expect("checked above");
+                    if data.len() + payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(invalid_data("message too large"));
+                    }
+                    data.extend_from_slice(&payload);
+                }
+                _ => return Err(invalid_data("unexpected frame")),
+            }
+            if fin && !matches!(opcode, OPCODE_PING | OPCODE_PONG) {
+                if let Some((opcode, data)) = message.take() {
+                    return Ok(Some(match opcode {
+                        OPCODE_TEXT => Message::Text(String::from_utf8(data).map_err(|_| invalid_data("text isn't UTF-8"))?),
+                        _ => Message::Binary(data),
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Sends a message to the client.
+    pub fn write_message(&self, message: &Message) -> std::io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OPCODE_BINARY, data),
+        }
+    }
+
+    /// Sends a frame to the client, unmasked as frames of servers are.
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.writer.lock().unwrap().write_all(&frame)
+    }
+
+    /// Returns another handle of the WebSocket, e.g. for a thread writing while this one reads.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(WebSocket { reader: self.reader.try_clone()?, writer: self.writer.clone() })
+    }
+
+    /// Closes the connection, waking up threads reading from it.
+    pub fn shutdown(&self) {
+        let _ = self.reader.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn invalid_data(reason: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Reads the HTTP request of the opening handshake, up to the empty line.
+fn read_handshake(stream: &TcpStream) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream.take(MAX_HANDSHAKE_SIZE as u64));
+    let mut request = String::new();
+    loop {
+        let length = reader.read_line(&mut request)?;
+        if length == 0 {
+            return Err(invalid_data("incomplete handshake"));
+        }
+        if request.ends_with("\r\n\r\n") || request.ends_with("\n\n") {
+            return Ok(request);
+        }
+    }
+}
+
+/// Returns the value of a header of the request, regardless of the case of its name.
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Returns whether the request asks to switch to WebSocket.
+fn is_upgrade(request: &str) -> bool {
+    request.starts_with("GET ")
+        && header(request, "upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        && header(request, "connection").is_some_and(|connection| {
+            connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+}
+
+/// Derives the `Sec-WebSocket-Accept` answering the key of the client.
+fn accept_key(key: &str) -> String {
+    encode_base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// Reads a frame of the client.
+///
+/// # Returns
+///
+/// `std::io::Result<Option<(bool, u8, Vec<u8>)>>` - Whether it's the last frame of the message, the opcode and the
+/// unmasked payload, `None` if the connection was closed.
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Option<(bool, u8, Vec<u8>)>> {
+    let mut header = [0; 2];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let (fin, opcode) = (header[0] & 0x80 != 0, header[0] & 0x0f);
+    // clients have to mask their frames
+    if header[1] & 0x80 == 0 {
+        return Err(invalid_data("unmasked frame"));
+    }
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as usize
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            usize::try_from(u64::from_be_bytes(length)).unwrap_or(usize::MAX)
+        }
+        length => length as usize,
+    };
+    if length > MAX_MESSAGE_SIZE {
+        return Err(invalid_data("message too large"));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// SHA-1 (RFC 3174), only used for the handshake, which asks for it.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+/// Encodes bytes as base64 (RFC 4648) with padding.
+fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(crate::utils::encode_hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(crate::utils::encode_hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        // longer than a block
+        let long = "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(crate::utils::encode_hex(&sha1(long.as_bytes())), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_handshake() {
+        // the example of RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        let request = "GET /chat HTTP/1.1\r\nHost: relay.example\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert!(is_upgrade(request));
+        assert_eq!(header(request, "SEC-WEBSOCKET-KEY"), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!is_upgrade("GET / HTTP/1.1\r\nHost: relay.example\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_read_frame() {
+        // "Hello" masked, the example of RFC 6455
+        let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(read_frame(&mut &frame[..]).unwrap(), Some((true, OPCODE_TEXT, b"Hello".to_vec())));
+        assert_eq!(read_frame(&mut &[][..]).unwrap(), None);
+        // servers don't take unmasked frames
+        let unmasked = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(read_frame(&mut &unmasked[..]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs::File;
+
+use crate::error::{NudgeError, Result};
+use crate::models::ExtendedAttribute;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::utils::{decode_hex, encode_hex};
+
+/// Largest total size of the attribute values carried to the receiver,
+/// so they fit into a single packet even though the values are hex encoded
+const MAX_ATTRIBUTES_SIZE: usize = 24 * 1024;
+
+/// Only attributes of the user namespace are carried,
+/// the others (security, trusted, system) are tied to the host or need privileges to set
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CARRIED_NAMESPACE: &str = "user.";
+
+/// Explains why extended attributes can't be carried on this platform.
+///
+/// # Returns
+///
+/// `Option<&str>` - The reason, or `None` if they are supported.
+pub fn unsupported_reason() -> Option<&'static str> {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        None
+    } else if cfg!(windows) {
+        Some("NTFS alternate data streams and extended attributes aren't transferred, only the file content")
+    } else {
+        Some("Extended attributes aren't supported on this platform")
+    }
+}
+
+/// Reads the extended attributes of a file which are carried to the receiver.
+/// Attributes which would exceed the size limit are skipped.
+///
+/// # Arguments
+///
+/// * `file` - The file to read the attributes of.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the attributes can't be listed.
+/// File systems without extended attributes have none, which isn't an error.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn read_attributes(file: &File) -> Result<Vec<ExtendedAttribute>> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let names = match query(|buffer, len| unsafe { libc::flistxattr(fd, buffer, len) }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut attributes = Vec::new();
+    let mut total_size = 0;
+    for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty()) {
+        let Ok(name) = std::str::from_utf8(name) else { continue };
+        if !name.starts_with(CARRIED_NAMESPACE) {
+            continue;
+        }
+        let c_name = std::ffi::CString::new(name).expect("names are NUL separated");
+        let value = query(|buffer, len| unsafe {
+            libc::fgetxattr(fd, c_name.as_ptr(), buffer as *mut libc::c_void, len)
+        })?;
+        total_size += name.len() + value.len();
+        if total_size > MAX_ATTRIBUTES_SIZE {
+            warn!(target: "io", "Skipping extended attribute {}, the attributes are too large to carry", name);
+            continue;
+        }
+        attributes.push(ExtendedAttribute { name: name.to_string(), value: encode_hex(&value) });
+    }
+    Ok(attributes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn read_attributes(_: &File) -> Result<Vec<ExtendedAttribute>> {
+    Ok(Vec::new())
+}
+
+/// Runs a query which fills a buffer (e.g. `flistxattr`), asking for the needed size first.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn query(mut call: impl FnMut(*mut libc::c_char, usize) -> libc::ssize_t) -> std::io::Result<Vec<u8>> {
+    loop {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; len as usize];
+        let read = call(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len());
+        if read >= 0 {
+            buffer.truncate(read as usize);
+            return Ok(buffer);
+        }
+        // the attributes grew in the meantime, ask again
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+/// Sets extended attributes received from the sender on a file.
+/// Attributes which can't be set (e.g. not supported by the file system) are skipped with a warning.
+///
+/// # Arguments
+///
+/// * `file` - The downloaded file.
+/// * `attributes` - The attributes of the sender's file.
+///
+/// # Returns
+///
+/// `Result<usize>` - The number of attributes set.
+///
+/// # Errors
+///
+/// Returns `NudgeError::ExchangeProtocolError` if the sender sent an attribute outside of the carried namespace
+/// or a value which isn't hex encoded.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn write_attributes(file: &File, attributes: &[ExtendedAttribute]) -> Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut written = 0;
+    for attribute in attributes {
+        let invalid = || NudgeError::ExchangeProtocolError(format!("invalid extended attribute: {}", attribute.name));
+        if !attribute.name.starts_with(CARRIED_NAMESPACE) {
+            return Err(invalid());
+        }
+        let name = std::ffi::CString::new(attribute.name.as_str()).map_err(|_| invalid())?;
+        let value = decode_hex(&attribute.value).ok_or_else(invalid)?;
+        let result = unsafe {
+            libc::fsetxattr(file.as_raw_fd(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+        };
+        if result == 0 {
+            written += 1;
+        } else {
+            warn!(target: "io", "Cannot set extended attribute {}: {}", attribute.name, std::io::Error::last_os_error());
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn write_attributes(_: &File, _: &[ExtendedAttribute]) -> Result<usize> {
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_read_write_attributes() {
+        let source = tempfile::NamedTempFile::new_in(".").unwrap();
+        let target = tempfile::NamedTempFile::new_in(".").unwrap();
+        let attributes = vec![ExtendedAttribute { name: "user.nudge.test".to_string(), value: encode_hex(b"hello") }];
+        if write_attributes(source.as_file(), &attributes).unwrap() == 0 {
+            // the file system of the working directory doesn't support user attributes
+            return;
+        }
+
+        let read = read_attributes(source.as_file()).unwrap();
+        assert_eq!(read, attributes);
+        assert_eq!(write_attributes(target.as_file(), &read).unwrap(), 1);
+        assert_eq!(read_attributes(target.as_file()).unwrap(), attributes);
+
+        let foreign = vec![ExtendedAttribute { name: "security.selinux".to_string(), value: String::new() }];
+        assert!(write_attributes(target.as_file(), &foreign).is_err());
+    }
+}